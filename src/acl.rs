@@ -0,0 +1,86 @@
+//! Client access control (`--allow-client`, `--deny-client`)
+//!
+//! A tenant answers the clients in its allowed subnets, or everyone when it
+//! has none, except those in its denied subnets: a deny carves exceptions out
+//! of the allowed ranges. Other clients are answered REFUSED, or get nothing
+//! at all under `--deny-action drop`, which keeps the server from answering
+//! (and so from being used to reflect traffic at) spoofed sources.
+
+use std::net::IpAddr;
+
+use clap::ValueEnum;
+
+use crate::geoip::Subnet;
+
+/// What a client that may not query gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DenyAction {
+    /// A REFUSED response
+    #[default]
+    Refuse,
+    /// No response
+    Drop,
+}
+
+/// Who may query a tenant
+#[derive(Debug, Clone, Default)]
+pub struct ClientAcl {
+    allow: Vec<Subnet>,
+    deny: Vec<Subnet>,
+    pub action: DenyAction,
+}
+
+impl ClientAcl {
+    pub fn new(allow: Vec<Subnet>, deny: Vec<Subnet>, action: DenyAction) -> Self {
+        Self {
+            allow,
+            deny,
+            action,
+        }
+    }
+
+    /// Whether `ip` may query
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets see IPv4 clients as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        (self.allow.is_empty() || self.allow.iter().any(|subnet| subnet.contains(ip)))
+            && !self.deny.iter().any(|subnet| subnet.contains(ip))
+    }
+
+    /// Whether denied clients' packets are dropped before being parsed
+    pub fn drops(&self, ip: IpAddr) -> bool {
+        self.action == DenyAction::Drop && !self.permits(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits() {
+        let subnets = |cidrs: &[&str]| cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        let everyone = ClientAcl::default();
+        assert!(everyone.permits(ip("203.0.113.7")));
+        assert!(!everyone.drops(ip("203.0.113.7")));
+
+        let lan = ClientAcl::new(
+            subnets(&["10.1.0.0/16", "fd00::/8"]),
+            subnets(&["10.1.99.0/24"]),
+            DenyAction::Drop,
+        );
+        assert!(lan.permits(ip("10.1.2.3")));
+        assert!(lan.permits(ip("::ffff:10.1.2.3")));
+        assert!(lan.permits(ip("fd00::53")));
+        assert!(!lan.permits(ip("10.2.0.1")));
+        assert!(!lan.permits(ip("10.1.99.5")));
+        assert!(lan.drops(ip("::ffff:10.1.99.5")));
+
+        let blocked = ClientAcl::new(Vec::new(), subnets(&["192.0.2.0/24"]), DenyAction::Refuse);
+        assert!(blocked.permits(ip("198.51.100.1")));
+        assert!(!blocked.permits(ip("192.0.2.1")));
+        assert!(!blocked.drops(ip("192.0.2.1")));
+    }
+}
@@ -1,2 +1,6 @@
+pub mod cache_actor;
+pub mod inflight_actor;
+pub mod local_records_actor;
 pub mod messages;
-pub mod query_actor;
\ No newline at end of file
+pub mod query_actor;
+pub mod recursor_actor;
\ No newline at end of file
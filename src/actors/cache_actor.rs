@@ -0,0 +1,728 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+use crate::actors::messages::{CacheKey, CacheMessage, CacheUsage, Cached, Resolution};
+use crate::handlers::cache_handler::{CacheConfig, Eviction};
+use crate::metrics::{self, Metrics};
+use crate::protocol::DnsResourceRecord;
+use crate::registry::RecordType;
+
+use tokio::sync::mpsc;
+
+/// Longest an answer is kept, whatever its TTL says
+pub const MAX_CACHE_TTL: u32 = 24 * 60 * 60;
+
+/// Shards the cache is split into, so concurrent lookups rarely contend
+const SHARDS: usize = 32;
+
+/// Where an entry stands in line for eviction: lowest first
+type Rank = (u64, u64);
+
+/// Keeps upstream answers until their TTLs run out, so repeated lookups
+/// don't go upstream again. Answers hit often are handed out for a refresh
+/// shortly before they expire, so their clients never wait on upstream.
+///
+/// Query tasks look answers up and store them directly, each locking only
+/// the shard its question falls in, and only for as long as the map
+/// operation takes. Every change is reported to the `CacheActor`, which
+/// keeps the eviction order and makes room when the cache is over its size.
+#[derive(Debug)]
+pub struct CacheTable {
+    config: CacheConfig,
+    shards: Box<[Mutex<HashMap<CacheKey, Entry>>]>,
+    hasher: RandomState,
+    // Counts uses, to order entries by how recently they were used
+    clock: AtomicU64,
+    entries: AtomicUsize,
+    // Estimated memory held by the entries
+    bytes: AtomicUsize,
+    // Answers evicted before they expired
+    evictions: AtomicU64,
+    metrics: Arc<Metrics>,
+    // Tells the actor about stores, uses and removals. Unbounded so lookups
+    // never wait on it; it is only ever behind by the changes in flight.
+    changes: mpsc::UnboundedSender<CacheMessage>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    resolution: Resolution,
+    stored: Instant,
+    expires: Instant,
+    hits: u64,
+    // Whether a hit was already told to refresh it
+    prefetching: bool,
+    // When it was last stored or hit, by the table's clock
+    used: u64,
+    // Estimated memory it holds
+    size: usize,
+}
+
+impl Entry {
+    /// Where the entry stands in line for eviction: lowest first
+    fn rank(&self, eviction: Eviction) -> Rank {
+        match eviction {
+            Eviction::Lru => (0, self.used),
+            Eviction::Lfu => (self.hits, self.used),
+        }
+    }
+}
+
+impl CacheTable {
+    /// An empty table, and the receiving end of the changes made to it for
+    /// the actor
+    pub fn new(
+        config: CacheConfig,
+        metrics: Arc<Metrics>,
+    ) -> (Self, mpsc::UnboundedReceiver<CacheMessage>) {
+        let (changes, receiver) = mpsc::unbounded_channel();
+        let table = Self {
+            config,
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            clock: AtomicU64::new(0),
+            entries: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            evictions: AtomicU64::new(0),
+            metrics,
+            changes,
+        };
+        (table, receiver)
+    }
+
+    fn shard(&self, key: &CacheKey) -> MutexGuard<'_, HashMap<CacheKey, Entry>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        // Entries are replaced whole, so a poisoned shard is safe to use
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Report a change to the actor. Sent while the shard is still locked,
+    /// so the actor sees the changes to each entry in the order they were
+    /// made.
+    fn report(&self, change: CacheMessage) {
+        // Only fails once the actor is gone, and with it any need to evict
+        let _ = self.changes.send(change);
+    }
+
+    /// A cached answer with its TTLs counted down by the time it has spent
+    /// in the cache, due a refresh if it is popular and about to expire
+    pub fn get(&self, key: &CacheKey, now: Instant) -> Option<Cached> {
+        let mut shard = self.shard(key);
+        let entry = shard.get_mut(key)?;
+        let left = entry.expires.saturating_duration_since(now);
+        if left.as_secs() == 0 {
+            if let Some(entry) = shard.remove(key) {
+                self.forget(&entry);
+            }
+            self.report(CacheMessage::Removed { key: key.clone() });
+            return None;
+        }
+        entry.hits += 1;
+        entry.used = self.tick();
+        let prefetch = !entry.prefetching
+            && self
+                .config
+                .prefetch
+                .is_some_and(|prefetch| entry.hits >= prefetch.hits && left <= prefetch.window);
+        entry.prefetching |= prefetch;
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let cached = Cached {
+            resolution: aged(entry.resolution.clone(), elapsed),
+            prefetch,
+        };
+        self.report(CacheMessage::Used {
+            key: key.clone(),
+            rank: entry.rank(self.config.eviction),
+        });
+        Some(cached)
+    }
+
+    /// Caches an answer; failures and answers without a TTL are left out,
+    /// and so is an answer bigger than the whole cache may be
+    pub fn insert(&self, key: CacheKey, resolution: Resolution, now: Instant) {
+        let Some(ttl) = cache_ttl(&resolution) else {
+            return;
+        };
+        let size = entry_size(&key, &resolution);
+        if self.config.max_bytes.is_some_and(|max| size > max) {
+            return;
+        }
+        let expires = now + Duration::from_secs(ttl.min(MAX_CACHE_TTL).into());
+        let mut shard = self.shard(&key);
+        // A refreshed answer keeps its popularity
+        let hits = match shard.remove(&key) {
+            Some(entry) => {
+                self.forget(&entry);
+                entry.hits
+            }
+            None => 0,
+        };
+        let entry = Entry {
+            resolution,
+            stored: now,
+            expires,
+            hits,
+            prefetching: false,
+            used: self.tick(),
+            size,
+        };
+        let rank = entry.rank(self.config.eviction);
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        shard.insert(key.clone(), entry);
+        self.report(CacheMessage::Stored { key, rank });
+    }
+
+    /// The answers that haven't expired, aged as `get` ages them
+    pub fn snapshot(&self, now: Instant) -> Vec<(CacheKey, Resolution)> {
+        let mut answers = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            answers.extend(
+                shard
+                    .iter()
+                    .filter(|(_, entry)| entry.expires.saturating_duration_since(now).as_secs() > 0)
+                    .map(|(key, entry)| {
+                        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+                        (key.clone(), aged(entry.resolution.clone(), elapsed))
+                    }),
+            );
+        }
+        answers
+    }
+
+    /// The answers cached for a name, of one type or all, aged as `get`
+    /// ages them. Unlike `get` this is no use of the answers: it doesn't
+    /// count as a hit or move them in line for eviction.
+    pub fn find(
+        &self,
+        name: &str,
+        qtype: Option<RecordType>,
+        now: Instant,
+    ) -> Vec<(CacheKey, Resolution)> {
+        let mut answers = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            answers.extend(
+                shard
+                    .iter()
+                    .filter(|(key, entry)| {
+                        &*key.name == name
+                            && qtype.is_none_or(|qtype| key.qtype == qtype)
+                            && entry.expires.saturating_duration_since(now).as_secs() > 0
+                    })
+                    .map(|(key, entry)| {
+                        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+                        (key.clone(), aged(entry.resolution.clone(), elapsed))
+                    }),
+            );
+        }
+        answers
+    }
+
+    pub fn usage(&self) -> CacheUsage {
+        CacheUsage {
+            entries: self.entries.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            config: self.config,
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the cache holds more answers, or more memory, than it may
+    fn is_over(&self) -> bool {
+        self.entries.load(Ordering::Relaxed) > self.config.max_entries
+            || self
+                .config
+                .max_bytes
+                .is_some_and(|max| self.bytes.load(Ordering::Relaxed) > max)
+    }
+
+    /// Drop an entry to make room; one that had expired isn't counted
+    fn evict(&self, key: &CacheKey, now: Instant) {
+        let Some(entry) = self.shard(key).remove(key) else {
+            return;
+        };
+        self.forget(&entry);
+        if entry.expires > now {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            metrics::incr(&self.metrics.cache_evictions);
+        }
+    }
+
+    /// Take a removed entry off the counts
+    fn forget(&self, entry: &Entry) {
+        self.entries.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(entry.size, Ordering::Relaxed);
+    }
+}
+
+/// Keeps the cache's entries in the order they are evicted in, least
+/// recently or least frequently used first as configured, and evicts from
+/// the front whenever the table has grown past its size.
+pub struct CacheActor {
+    // The changes made to the table
+    receiver: mpsc::UnboundedReceiver<CacheMessage>,
+    // Weak, so the actor stops once the table's handles are all gone
+    table: Weak<CacheTable>,
+    // The entries in the order they are evicted in, by `Entry::rank`
+    order: BTreeMap<Rank, CacheKey>,
+    // Where each entry stands in `order`
+    ranks: HashMap<CacheKey, Rank>,
+}
+
+impl CacheActor {
+    // Constructor for the actor
+    pub fn new(receiver: mpsc::UnboundedReceiver<CacheMessage>, table: &Arc<CacheTable>) -> Self {
+        Self {
+            receiver,
+            table: Arc::downgrade(table),
+            order: BTreeMap::new(),
+            ranks: HashMap::new(),
+        }
+    }
+
+    // Run the actor
+    pub async fn run(&mut self) {
+        while let Some(msg) = self.receiver.recv().await {
+            self.handle_message(msg, Instant::now());
+        }
+    }
+
+    // Handle a message
+    fn handle_message(&mut self, msg: CacheMessage, now: Instant) {
+        match msg {
+            CacheMessage::Stored { key, rank } => {
+                self.place(key.clone(), rank);
+                self.make_room(&key, now);
+            }
+            // A use reported after a later one has no news
+            CacheMessage::Used { key, rank } => {
+                if self.ranks.get(&key).is_some_and(|&current| current < rank) {
+                    self.place(key, rank);
+                }
+            }
+            CacheMessage::Removed { key } => {
+                if let Some(rank) = self.ranks.remove(&key) {
+                    self.order.remove(&rank);
+                }
+            }
+        }
+    }
+
+    /// Move an entry to its new place in line
+    fn place(&mut self, key: CacheKey, rank: Rank) {
+        if let Some(previous) = self.ranks.insert(key.clone(), rank) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(rank, key);
+    }
+
+    /// Evict the entries first in line until the table is within its size,
+    /// sparing the one just stored, which a least frequently used order puts
+    /// first
+    fn make_room(&mut self, stored: &CacheKey, now: Instant) {
+        let Some(table) = self.table.upgrade() else {
+            return;
+        };
+        while table.is_over() {
+            let Some(rank) = self
+                .order
+                .iter()
+                .find(|(_, key)| *key != stored)
+                .map(|(rank, _)| *rank)
+            else {
+                break;
+            };
+            if let Some(key) = self.order.remove(&rank) {
+                self.ranks.remove(&key);
+                table.evict(&key, now);
+            }
+        }
+    }
+}
+
+/// Roughly the memory an entry holds: its key, its records and their
+/// names and RDATA, and its place in the map and the eviction order
+fn entry_size(key: &CacheKey, resolution: &Resolution) -> usize {
+    let record_size = |record: &DnsResourceRecord| {
+        size_of::<DnsResourceRecord>() + record.name.len() + record.rdata.len()
+    };
+    let records = match resolution {
+        Resolution::Records(records) => records.iter().map(record_size).sum(),
+        Resolution::Negative { soa, .. } => soa.iter().map(record_size).sum(),
+        Resolution::Failed(_) => 0,
+    };
+    2 * size_of::<CacheKey>() + key.name.len() + size_of::<Entry>() + 16 + records
+}
+
+/// How long an answer may be cached: records for the least of their TTLs
+/// and negative answers for their SOA's (RFC 2308). Failures and negative
+/// answers without an SOA aren't cached.
+pub fn cache_ttl(resolution: &Resolution) -> Option<u32> {
+    let ttl = match resolution {
+        Resolution::Records(records) => records.iter().map(|record| record.ttl).min()?,
+        Resolution::Negative { soa: Some(soa), .. } => soa.ttl,
+        Resolution::Negative { soa: None, .. } | Resolution::Failed(_) => return None,
+    };
+    Some(ttl).filter(|&ttl| ttl > 0)
+}
+
+/// Take `elapsed` seconds off every TTL in an answer
+fn aged(resolution: Resolution, elapsed: u32) -> Resolution {
+    let age = |record: DnsResourceRecord| DnsResourceRecord {
+        ttl: record.ttl.saturating_sub(elapsed),
+        ..record
+    };
+    match resolution {
+        Resolution::Records(records) => Resolution::Records(records.into_iter().map(age).collect()),
+        Resolution::Negative { rcode, soa } => Resolution::Negative {
+            rcode,
+            soa: soa.map(age),
+        },
+        Resolution::Failed(rcode) => Resolution::Failed(rcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::cache_handler::Prefetch;
+    use crate::registry::{Rcode, RecordClass, RecordType};
+    use crate::response_builder::SoaRecord;
+    use std::sync::atomic::Ordering;
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey {
+            name: Arc::from(name),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+            route: None,
+        }
+    }
+
+    fn addresses(ttl: u32) -> Resolution {
+        Resolution::Records(vec![DnsResourceRecord::new(
+            "example.com".into(),
+            RecordType::A,
+            RecordClass::IN,
+            ttl,
+            vec![192, 0, 2, 1],
+        )])
+    }
+
+    /// A table with its actor, which is handed the table's changes as soon
+    /// as they are made
+    struct Cache {
+        table: Arc<CacheTable>,
+        actor: CacheActor,
+    }
+
+    impl Cache {
+        fn get(&mut self, key: &CacheKey, now: Instant) -> Option<Cached> {
+            let cached = self.table.get(key, now);
+            self.settle(now);
+            cached
+        }
+
+        fn insert(&mut self, key: CacheKey, resolution: Resolution, now: Instant) {
+            self.table.insert(key, resolution, now);
+            self.settle(now);
+        }
+
+        fn settle(&mut self, now: Instant) {
+            while let Ok(msg) = self.actor.receiver.try_recv() {
+                self.actor.handle_message(msg, now);
+            }
+        }
+
+        fn entries(&self) -> usize {
+            self.table.usage().entries
+        }
+    }
+
+    fn actor_with(config: CacheConfig) -> Cache {
+        let (table, receiver) = CacheTable::new(config, Arc::new(Metrics::new(1)));
+        let table = Arc::new(table);
+        let actor = CacheActor::new(receiver, &table);
+        Cache { table, actor }
+    }
+
+    fn actor(max_entries: usize) -> Cache {
+        actor_with(CacheConfig {
+            max_entries,
+            ..CacheConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_ttl_counts_down() {
+        let mut cache = actor(10);
+        let start = Instant::now();
+        cache.insert(key("example.com"), addresses(300), start);
+
+        let Some(Cached {
+            resolution: Resolution::Records(records),
+            ..
+        }) = cache.get(&key("example.com"), start + Duration::from_secs(100))
+        else {
+            panic!("expected a cached answer");
+        };
+        assert_eq!(records[0].ttl, 200);
+        assert!(cache.get(&key("example.org"), start).is_none());
+
+        // Expired answers are gone
+        assert!(cache
+            .get(&key("example.com"), start + Duration::from_secs(300))
+            .is_none());
+        assert_eq!(cache.entries(), 0);
+        assert!(cache.actor.ranks.is_empty() && cache.actor.order.is_empty());
+    }
+
+    #[test]
+    fn test_records_age_separately() {
+        let mut cache = actor(10);
+        let start = Instant::now();
+        let Resolution::Records(mut records) = addresses(300) else {
+            unreachable!()
+        };
+        // A long-lived CNAME in front of a short-lived address
+        records.insert(
+            0,
+            DnsResourceRecord::new(
+                "www.example.com".into(),
+                RecordType::CNAME,
+                RecordClass::IN,
+                3600,
+                crate::response_builder::encode_name("example.com"),
+            ),
+        );
+        cache.insert(key("www.example.com"), Resolution::Records(records), start);
+
+        let Some(Cached {
+            resolution: Resolution::Records(records),
+            ..
+        }) = cache.get(&key("www.example.com"), start + Duration::from_secs(100))
+        else {
+            panic!("expected a cached answer");
+        };
+        let ttls: Vec<u32> = records.iter().map(|record| record.ttl).collect();
+        assert_eq!(ttls, vec![3500, 200]);
+        // ...and the answer goes when its shortest TTL runs out
+        assert!(cache
+            .get(&key("www.example.com"), start + Duration::from_secs(300))
+            .is_none());
+    }
+
+    #[test]
+    fn test_what_is_cached() {
+        let mut cache = actor(10);
+        let now = Instant::now();
+        let soa = SoaRecord {
+            zone: "example.com".into(),
+            mname: "ns1.example.com".into(),
+            rname: "hostmaster.example.com".into(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 60,
+        }
+        .to_resource_record(60);
+
+        cache.insert(
+            key("missing.example.com"),
+            Resolution::Negative {
+                rcode: Rcode::NXDOMAIN,
+                soa: Some(soa),
+            },
+            now,
+        );
+        cache.insert(
+            key("nodata.example.com"),
+            Resolution::Negative {
+                rcode: Rcode::NOERROR,
+                soa: None,
+            },
+            now,
+        );
+        cache.insert(
+            key("broken.example.com"),
+            Resolution::Failed(Rcode::SERVFAIL),
+            now,
+        );
+        cache.insert(key("zero.example.com"), addresses(0), now);
+
+        assert!(matches!(
+            cache.get(&key("missing.example.com"), now + Duration::from_secs(20)),
+            Some(Cached { resolution: Resolution::Negative { rcode: Rcode::NXDOMAIN, soa: Some(soa) }, .. }) if soa.ttl == 40
+        ));
+        assert_eq!(cache.entries(), 1);
+    }
+
+    #[test]
+    fn test_find() {
+        let mut cache = actor(10);
+        let start = Instant::now();
+        cache.insert(key("example.com"), addresses(300), start);
+        cache.insert(
+            CacheKey {
+                route: Some(Arc::from("vpn")),
+                ..key("example.com")
+            },
+            addresses(300),
+            start,
+        );
+        cache.insert(key("example.org"), addresses(300), start);
+
+        let later = start + Duration::from_secs(100);
+        let found = cache.table.find("example.com", None, later);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|(_, resolution)| matches!(
+            resolution,
+            Resolution::Records(records) if records[0].ttl == 200
+        )));
+        assert!(cache
+            .table
+            .find("example.com", Some(RecordType::MX), later)
+            .is_empty());
+
+        // Looking isn't a hit, so it doesn't keep an answer from eviction
+        assert_eq!(cache.actor.ranks[&key("example.com")], (0, 1));
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut cache = actor(2);
+        let start = Instant::now();
+        cache.insert(key("a.example"), addresses(100), start);
+        cache.insert(key("b.example"), addresses(100), start);
+        assert!(cache.get(&key("a.example"), start).is_some());
+
+        cache.insert(key("c.example"), addresses(100), start);
+        assert!(cache.get(&key("b.example"), start).is_none());
+        assert!(cache.get(&key("a.example"), start).is_some());
+        assert!(cache.get(&key("c.example"), start).is_some());
+        assert_eq!(cache.table.usage().evictions, 1);
+        assert_eq!(
+            cache.table.metrics.cache_evictions.load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(cache.table.snapshot(start).len(), 2);
+
+        // Answers that had expired anyway don't count as evicted
+        let later = start + Duration::from_secs(100);
+        cache.insert(key("d.example"), addresses(100), later);
+        cache.insert(key("e.example"), addresses(100), later);
+        assert_eq!(cache.table.usage().evictions, 1);
+    }
+
+    #[test]
+    fn test_uses_reported_out_of_order() {
+        let mut cache = actor(2);
+        let start = Instant::now();
+        cache.insert(key("a.example"), addresses(100), start);
+        cache.insert(key("b.example"), addresses(100), start);
+
+        // Two lookups of a, whose reports reach the actor the other way round
+        assert!(cache.table.get(&key("a.example"), start).is_some());
+        assert!(cache.table.get(&key("a.example"), start).is_some());
+        let mut changes = Vec::new();
+        while let Ok(msg) = cache.actor.receiver.try_recv() {
+            changes.push(msg);
+        }
+        for msg in changes.into_iter().rev() {
+            cache.actor.handle_message(msg, start);
+        }
+        assert_eq!(cache.actor.ranks[&key("a.example")], (0, 4));
+        assert_eq!(cache.actor.order.len(), 2);
+
+        // b is still the one evicted
+        cache.insert(key("c.example"), addresses(100), start);
+        assert!(cache.get(&key("b.example"), start).is_none());
+        assert!(cache.get(&key("a.example"), start).is_some());
+    }
+
+    #[test]
+    fn test_least_frequently_used_is_evicted() {
+        let mut cache = actor_with(CacheConfig {
+            max_entries: 2,
+            eviction: Eviction::Lfu,
+            ..CacheConfig::default()
+        });
+        let start = Instant::now();
+        cache.insert(key("a.example"), addresses(100), start);
+        cache.insert(key("b.example"), addresses(100), start);
+        for _ in 0..3 {
+            cache.get(&key("a.example"), start);
+        }
+        cache.get(&key("b.example"), start);
+
+        // a was used less recently but more often
+        cache.insert(key("c.example"), addresses(100), start);
+        assert!(cache.get(&key("a.example"), start).is_some());
+        assert!(cache.get(&key("b.example"), start).is_none());
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let size = entry_size(&key("a.example"), &addresses(100));
+        let mut cache = actor_with(CacheConfig {
+            max_bytes: Some(size * 3),
+            ..CacheConfig::default()
+        });
+        let start = Instant::now();
+        for name in ["a.example", "b.example", "c.example", "d.example"] {
+            cache.insert(key(name), addresses(100), start);
+        }
+        let usage = cache.table.usage();
+        assert_eq!(usage.entries, 3);
+        assert_eq!(usage.bytes, size * 3);
+        assert_eq!(usage.evictions, 1);
+        assert!(cache.get(&key("a.example"), start).is_none());
+
+        // Replacing an answer doesn't count it twice
+        cache.insert(key("d.example"), addresses(100), start);
+        assert_eq!(cache.table.usage().bytes, size * 3);
+    }
+
+    #[test]
+    fn test_popular_answers_are_prefetched() {
+        let mut cache = actor_with(CacheConfig {
+            prefetch: Some(Prefetch {
+                hits: 2,
+                window: Duration::from_secs(10),
+            }),
+            ..CacheConfig::default()
+        });
+        let start = Instant::now();
+        cache.insert(key("example.com"), addresses(60), start);
+        cache.insert(key("example.org"), addresses(60), start);
+
+        let prefetch = |cache: &mut Cache, name, at| cache.get(&key(name), at).unwrap().prefetch;
+        // Not while it has long to go, however popular
+        assert!(!prefetch(&mut cache, "example.com", start));
+        assert!(!prefetch(&mut cache, "example.com", start));
+        // Close to expiring, one hit is told to refresh it
+        let late = start + Duration::from_secs(55);
+        assert!(prefetch(&mut cache, "example.com", late));
+        assert!(!prefetch(&mut cache, "example.com", late));
+        // Unpopular answers are left to expire
+        assert!(!prefetch(&mut cache, "example.org", late));
+
+        // The refreshed answer isn't due again until it nears its own expiry
+        cache.insert(key("example.com"), addresses(60), late);
+        assert!(!prefetch(&mut cache, "example.com", late));
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::actors::messages::{CacheKey, InflightMessage, Joined, Resolution};
+
+use tokio::sync::{mpsc, oneshot};
+
+/// Longest a lookup is waited on; later arrivals lead a fresh one. Upstream
+/// lookups time out well before this, so only a lost leader gets here.
+pub const MAX_INFLIGHT: Duration = Duration::from_secs(30);
+
+/// Tracks the upstream lookups in flight so that concurrent queries for the
+/// same question share one instead of each going upstream
+pub struct InflightActor {
+    // The receiver for incoming messages
+    receiver: mpsc::Receiver<InflightMessage>,
+    entries: HashMap<CacheKey, Entry>,
+}
+
+struct Entry {
+    started: Instant,
+    // Everyone waiting on the leader
+    followers: Vec<oneshot::Sender<Resolution>>,
+}
+
+impl InflightActor {
+    // Constructor for the actor
+    pub fn new(receiver: mpsc::Receiver<InflightMessage>) -> Self {
+        Self {
+            receiver,
+            entries: HashMap::new(),
+        }
+    }
+
+    // Run the actor
+    pub async fn run(&mut self) {
+        while let Some(msg) = self.receiver.recv().await {
+            self.handle_message(msg, Instant::now());
+        }
+    }
+
+    // Handle a message
+    fn handle_message(&mut self, msg: InflightMessage, now: Instant) {
+        match msg {
+            InflightMessage::Join { key, respond_to } => {
+                let _ = respond_to.send(self.join(key, now));
+            }
+            InflightMessage::Finish { key, resolution } => self.finish(&key, resolution),
+        }
+    }
+
+    fn join(&mut self, key: CacheKey, now: Instant) -> Joined {
+        match self.entries.get_mut(&key) {
+            Some(entry) if now.duration_since(entry.started) < MAX_INFLIGHT => {
+                let (send, recv) = oneshot::channel();
+                entry.followers.push(send);
+                Joined::Follower(recv)
+            }
+            // Replacing a stale entry drops its followers' senders, and they
+            // go upstream themselves
+            _ => {
+                self.entries.insert(
+                    key,
+                    Entry {
+                        started: now,
+                        followers: Vec::new(),
+                    },
+                );
+                Joined::Leader
+            }
+        }
+    }
+
+    fn finish(&mut self, key: &CacheKey, resolution: Resolution) {
+        let Some(entry) = self.entries.remove(key) else {
+            return;
+        };
+        for follower in entry.followers {
+            let _ = follower.send(resolution.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{Rcode, RecordClass, RecordType};
+    use std::sync::Arc;
+
+    fn key(qtype: RecordType) -> CacheKey {
+        CacheKey {
+            name: Arc::from("example.com"),
+            qtype,
+            qclass: RecordClass::IN,
+            route: None,
+        }
+    }
+
+    fn actor() -> InflightActor {
+        let (_sender, receiver) = mpsc::channel(1);
+        InflightActor::new(receiver)
+    }
+
+    #[test]
+    fn test_followers_share_the_outcome() {
+        let mut inflight = actor();
+        let now = Instant::now();
+
+        assert!(matches!(
+            inflight.join(key(RecordType::A), now),
+            Joined::Leader
+        ));
+        let Joined::Follower(mut first) = inflight.join(key(RecordType::A), now) else {
+            panic!("expected to follow");
+        };
+        let Joined::Follower(mut second) = inflight.join(key(RecordType::A), now) else {
+            panic!("expected to follow");
+        };
+        // Another type is another lookup
+        assert!(matches!(
+            inflight.join(key(RecordType::AAAA), now),
+            Joined::Leader
+        ));
+
+        inflight.finish(&key(RecordType::A), Resolution::Failed(Rcode::SERVFAIL));
+        for follower in [&mut first, &mut second] {
+            assert!(matches!(
+                follower.try_recv(),
+                Ok(Resolution::Failed(Rcode::SERVFAIL))
+            ));
+        }
+        // The next query goes upstream again
+        assert!(matches!(
+            inflight.join(key(RecordType::A), now),
+            Joined::Leader
+        ));
+    }
+
+    #[test]
+    fn test_lost_leader_is_replaced() {
+        let mut inflight = actor();
+        let start = Instant::now();
+
+        assert!(matches!(
+            inflight.join(key(RecordType::A), start),
+            Joined::Leader
+        ));
+        let Joined::Follower(mut follower) = inflight.join(key(RecordType::A), start) else {
+            panic!("expected to follow");
+        };
+
+        let later = start + MAX_INFLIGHT;
+        assert!(matches!(
+            inflight.join(key(RecordType::A), later),
+            Joined::Leader
+        ));
+        assert!(matches!(
+            follower.try_recv(),
+            Err(oneshot::error::TryRecvError::Closed)
+        ));
+    }
+}
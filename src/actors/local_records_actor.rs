@@ -0,0 +1,1078 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::SystemTime;
+
+use crate::actors::messages::LocalRecordsMessage;
+use crate::errors::LocalRecordError;
+use crate::geoip::{ClientLocation, GeoTag};
+use crate::health::CheckTarget;
+use crate::local_records::{
+    diff_records, is_in_zone, normalize_name, ImportDiff, LocalRecord, RecordData, VersionInfo,
+    HISTORY_LEN,
+};
+use crate::registry::RecordType;
+use crate::reverse::parse_reverse_name;
+
+use rand::distr::weighted::WeightedIndex;
+use rand::distr::Distribution;
+use rand::Rng;
+use tokio::sync::mpsc;
+
+/// Longest CNAME chain followed within the local records
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The records and health state lookups need. Query tasks read it directly,
+/// without going through the actor: the actor is the only writer and
+/// publishes a new snapshot after every change.
+#[derive(Debug, Default, Clone)]
+pub struct RecordTable {
+    // Records keyed by their normalized (lowercase, no trailing dot) name
+    records: HashMap<String, Vec<LocalRecord>>,
+    // Health-checked targets currently failing
+    down: HashSet<CheckTarget>,
+    // The A and AAAA records by address, for synthesizing PTR records
+    addresses: HashMap<IpAddr, Vec<LocalRecord>>,
+    // Where each round-robin pool (a name's records of one type) is in its
+    // turns, carried across snapshots while the pool is there
+    turns: HashMap<(String, RecordType), Arc<Mutex<PoolTurns>>>,
+}
+
+/// The current weights of smooth weighted round-robin: each record of a
+/// pool, by its data, and its current weight
+type PoolTurns = Vec<(RecordData, i64)>;
+
+impl RecordTable {
+    /// Records of the requested type, or the name's CNAME if it has one.
+    /// Location-tagged records are chosen by `for_location`, failed-over ones
+    /// by `for_health`, and among round-robin records one is picked in turn by
+    /// `pick_in_turn`, among weighted records one at random by `pick_weighted`.
+    pub fn lookup(
+        &self,
+        name: &str,
+        qtype: RecordType,
+        client: &ClientLocation,
+    ) -> Vec<LocalRecord> {
+        let mut rng = rand::rng();
+        let mut answers = Vec::new();
+        let mut name = normalize_name(name);
+
+        // Follow CNAMEs through the local table so that e.g. an RFC 2317
+        // delegation CNAME and the PTR it points at come back together
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(entry) = self.records.get(&name) else {
+                break;
+            };
+
+            let matching = self.select(
+                for_health(for_location(entry, qtype, client), &self.down),
+                &mut rng,
+            );
+            if !matching.is_empty() {
+                answers.extend(matching.into_iter().cloned());
+                break;
+            }
+
+            let cnames = self.select(
+                for_health(for_location(entry, RecordType::CNAME, client), &self.down),
+                &mut rng,
+            );
+            let Some(cname) = cnames.into_iter().next() else {
+                break;
+            };
+            answers.push(cname.clone());
+            match &cname.data {
+                RecordData::Cname(target) => name = target.clone(),
+                _ => break,
+            }
+        }
+
+        answers
+    }
+
+    /// The records of a pool to answer with
+    fn select<'a>(
+        &self,
+        records: Vec<&'a LocalRecord>,
+        rng: &mut impl Rng,
+    ) -> Vec<&'a LocalRecord> {
+        if records.iter().any(|record| record.round_robin) {
+            pick_in_turn(records, &self.turns)
+        } else {
+            pick_weighted(records, rng)
+        }
+    }
+
+    /// PTR records for a reverse name, pointing at the names whose A or AAAA
+    /// records hold its address, each with the TTL of that record
+    pub fn synthesize_ptr(&self, name: &str) -> Vec<LocalRecord> {
+        let Some(addr) = parse_reverse_name(name) else {
+            return Vec::new();
+        };
+        let mut ptrs: Vec<LocalRecord> = Vec::new();
+        for record in self.addresses.get(&addr).into_iter().flatten() {
+            let data = RecordData::Ptr(record.name.clone());
+            if !ptrs.iter().any(|ptr| ptr.data == data) {
+                ptrs.push(LocalRecord::new(name, record.ttl, data));
+            }
+        }
+        ptrs
+    }
+
+    /// Rebuild the round-robin pools from the records, each keeping its
+    /// place in its turns from the last snapshot
+    fn index_turns(&mut self) {
+        let mut turns = HashMap::new();
+        for record in self.records.values().flatten() {
+            if record.round_robin {
+                let key = (record.name.clone(), record.rtype());
+                let pool = self.turns.get(&key).cloned().unwrap_or_default();
+                turns.entry(key).or_insert(pool);
+            }
+        }
+        self.turns = turns;
+    }
+
+    /// Rebuild the index of addresses from the records
+    fn index_addresses(&mut self) {
+        self.addresses.clear();
+        for record in self.records.values().flatten() {
+            let addr = match record.data {
+                RecordData::A(addr) => IpAddr::V4(addr),
+                RecordData::Aaaa(addr) => IpAddr::V6(addr),
+                _ => continue,
+            };
+            self.addresses.entry(addr).or_default().push(record.clone());
+        }
+        for records in self.addresses.values_mut() {
+            records.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+    }
+
+    /// The types of the records at a name, whatever their location or health
+    pub fn types(&self, name: &str) -> Vec<RecordType> {
+        let mut types: Vec<RecordType> = self
+            .records
+            .get(&normalize_name(name))
+            .into_iter()
+            .flatten()
+            .map(LocalRecord::rtype)
+            .collect();
+        types.sort_unstable();
+        types.dedup();
+        types
+    }
+
+    /// Whether a name has records, or is an empty non-terminal with records
+    /// below it
+    pub fn exists(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+        self.records.contains_key(&name)
+            || self.records.keys().any(|owner| is_in_zone(owner, &name))
+    }
+}
+
+/// Where the actor publishes the current table. The lock is only held to
+/// swap or clone the `Arc`, so readers never wait on a change being made.
+#[derive(Debug, Default)]
+pub struct PublishedTable(RwLock<Arc<RecordTable>>);
+
+impl PublishedTable {
+    /// The current snapshot
+    pub fn load(&self) -> Arc<RecordTable> {
+        // Swapping an Arc can't leave the table half-updated, so a poisoned
+        // lock is safe to use
+        Arc::clone(&self.0.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn store(&self, table: Arc<RecordTable>) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = table;
+    }
+}
+
+/// Owns the table of locally defined records and serializes changes to it
+pub struct LocalRecordsActor {
+    // The receiver for incoming messages
+    receiver: mpsc::Receiver<LocalRecordsMessage>,
+    // The current table, changed copy-on-write since readers may hold it
+    table: Arc<RecordTable>,
+    published: Arc<PublishedTable>,
+    // The most recent versions of the table, oldest first; the last one is current
+    history: VecDeque<Version>,
+}
+
+/// A snapshot of the table in the history
+struct Version {
+    info: VersionInfo,
+    records: Vec<LocalRecord>,
+}
+
+impl LocalRecordsActor {
+    // Constructor for the actor
+    pub fn new(
+        receiver: mpsc::Receiver<LocalRecordsMessage>,
+        published: Arc<PublishedTable>,
+    ) -> Self {
+        Self {
+            receiver,
+            table: published.load(),
+            published,
+            history: VecDeque::from([Version {
+                info: VersionInfo {
+                    serial: 0,
+                    time: SystemTime::now(),
+                    change: "start".to_string(),
+                    records: 0,
+                },
+                records: Vec::new(),
+            }]),
+        }
+    }
+
+    // Run the actor
+    pub async fn run(&mut self) {
+        while let Some(msg) = self.receiver.recv().await {
+            self.handle_message(msg);
+        }
+    }
+
+    /// The table, for changing; copied first if a snapshot of it is in use
+    fn table_mut(&mut self) -> &mut RecordTable {
+        Arc::make_mut(&mut self.table)
+    }
+
+    // Handle a message
+    fn handle_message(&mut self, msg: LocalRecordsMessage) {
+        match msg {
+            LocalRecordsMessage::Add { record, respond_to } => {
+                let exists = self
+                    .table
+                    .records
+                    .get(&record.name)
+                    .is_some_and(|entry| entry.contains(&record));
+                let added = if exists {
+                    false
+                } else {
+                    let change = format!("add {}", record);
+                    let records = &mut self.table_mut().records;
+                    records.entry(record.name.clone()).or_default().push(record);
+                    self.commit(change);
+                    true
+                };
+                let _ = respond_to.send(added);
+            }
+            LocalRecordsMessage::Remove {
+                name,
+                rtype,
+                respond_to,
+            } => {
+                let name = normalize_name(&name);
+                let mut removed = 0;
+                if self.table.records.contains_key(&name) {
+                    let records = &mut self.table_mut().records;
+                    let entry = records.get_mut(&name).expect("checked above");
+                    let before = entry.len();
+                    entry.retain(|record| rtype.is_some_and(|rtype| record.rtype() != rtype));
+                    removed = before - entry.len();
+                    if entry.is_empty() {
+                        records.remove(&name);
+                    }
+                }
+                if removed > 0 {
+                    self.commit(match rtype {
+                        Some(rtype) => format!("remove {} {}", name, rtype),
+                        None => format!("remove {}", name),
+                    });
+                }
+                let _ = respond_to.send(removed);
+            }
+            LocalRecordsMessage::List { respond_to } => {
+                let _ = respond_to.send(self.all_records());
+            }
+            LocalRecordsMessage::Import {
+                records,
+                replace,
+                dry_run,
+                respond_to,
+            } => {
+                let diff = self.import(records, replace, dry_run);
+                if !dry_run && diff.is_changed() {
+                    self.commit(format!(
+                        "import ({} added, {} removed)",
+                        diff.added.len(),
+                        diff.removed.len()
+                    ));
+                }
+                let _ = respond_to.send(diff);
+            }
+            LocalRecordsMessage::ReplaceZone {
+                zone,
+                records,
+                change,
+                respond_to,
+            } => {
+                let diff = self.replace_zone(&zone, records);
+                if diff.is_changed() {
+                    self.commit(change);
+                }
+                let _ = respond_to.send(diff);
+            }
+            LocalRecordsMessage::Versions { respond_to } => {
+                let versions = self
+                    .history
+                    .iter()
+                    .map(|version| version.info.clone())
+                    .collect();
+                let _ = respond_to.send(versions);
+            }
+            LocalRecordsMessage::Diff {
+                from,
+                to,
+                zone,
+                respond_to,
+            } => {
+                let _ = respond_to.send(self.diff_versions(from, to, zone.as_deref()));
+            }
+            LocalRecordsMessage::Rollback {
+                serial,
+                zone,
+                respond_to,
+            } => {
+                let _ = respond_to.send(self.rollback(serial, zone.as_deref()));
+            }
+            LocalRecordsMessage::SetHealth { target, healthy } => {
+                if self.table.down.contains(&target) == healthy {
+                    let down = &mut self.table_mut().down;
+                    if healthy {
+                        down.remove(&target);
+                    } else {
+                        down.insert(target);
+                    }
+                    self.publish();
+                }
+            }
+        }
+    }
+
+    /// Every record, sorted by name and type
+    fn all_records(&self) -> Vec<LocalRecord> {
+        let mut all: Vec<LocalRecord> = self.table.records.values().flatten().cloned().collect();
+        all.sort_by(|a, b| a.name.cmp(&b.name).then(a.rtype().cmp(&b.rtype())));
+        all
+    }
+
+    /// Work out (and unless `dry_run`, apply) the changes an import makes
+    fn import(&mut self, records: Vec<LocalRecord>, replace: bool, dry_run: bool) -> ImportDiff {
+        let mut diff = ImportDiff::default();
+        let mut incoming: Vec<LocalRecord> = Vec::with_capacity(records.len());
+
+        for record in records {
+            if incoming.contains(&record) {
+                continue;
+            }
+            let exists = self
+                .table
+                .records
+                .get(&record.name)
+                .is_some_and(|entry| entry.contains(&record));
+            if exists {
+                diff.unchanged += 1;
+            } else {
+                diff.added.push(record.clone());
+            }
+            incoming.push(record);
+        }
+
+        if replace {
+            diff.removed = self
+                .all_records()
+                .into_iter()
+                .filter(|record| !incoming.contains(record))
+                .collect();
+        }
+
+        if !dry_run && (replace || !diff.added.is_empty()) {
+            let records = &mut self.table_mut().records;
+            if replace {
+                records.clear();
+                for record in incoming {
+                    records.entry(record.name.clone()).or_default().push(record);
+                }
+            } else {
+                for record in diff.added.iter().cloned() {
+                    records.entry(record.name.clone()).or_default().push(record);
+                }
+            }
+        }
+
+        diff
+    }
+
+    /// Make the records at or below `zone` exactly `records`, leaving the
+    /// rest of the table alone. Records outside the zone are ignored.
+    fn replace_zone(&mut self, zone: &str, records: Vec<LocalRecord>) -> ImportDiff {
+        let zone = normalize_name(zone);
+        let mut incoming: Vec<LocalRecord> = Vec::with_capacity(records.len());
+        for record in records {
+            if is_in_zone(&record.name, &zone) && !incoming.contains(&record) {
+                incoming.push(record);
+            }
+        }
+
+        let current: Vec<LocalRecord> = self
+            .table
+            .records
+            .iter()
+            .filter(|(name, _)| is_in_zone(name, &zone))
+            .flat_map(|(_, entry)| entry.iter().cloned())
+            .collect();
+
+        let diff = diff_records(&current, &incoming);
+        if diff.is_changed() {
+            let records = &mut self.table_mut().records;
+            records.retain(|name, _| !is_in_zone(name, &zone));
+            for record in incoming {
+                records.entry(record.name.clone()).or_default().push(record);
+            }
+        }
+        diff
+    }
+
+    /// Make the current table the one lookups see
+    fn publish(&self) {
+        self.published.store(Arc::clone(&self.table));
+    }
+
+    /// Record the current table as a new version, forgetting the oldest one
+    /// once the history is full, and publish it. Every change to the records
+    /// ends here, before the change is acknowledged.
+    fn commit(&mut self, change: String) {
+        self.table_mut().index_addresses();
+        self.table_mut().index_turns();
+        self.publish();
+        let records = self.all_records();
+        let serial = self
+            .history
+            .back()
+            .map_or(0, |version| version.info.serial + 1);
+        self.history.push_back(Version {
+            info: VersionInfo {
+                serial,
+                time: SystemTime::now(),
+                change,
+                records: records.len(),
+            },
+            records,
+        });
+        while self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+    }
+
+    fn version(&self, serial: u32) -> Result<&Version, LocalRecordError> {
+        self.history
+            .iter()
+            .find(|version| version.info.serial == serial)
+            .ok_or(LocalRecordError::UnknownVersion(serial))
+    }
+
+    /// What changed from one version to another (or to the current table)
+    fn diff_versions(
+        &self,
+        from: u32,
+        to: Option<u32>,
+        zone: Option<&str>,
+    ) -> Result<ImportDiff, LocalRecordError> {
+        let from = &self.version(from)?.records;
+        let to = match to {
+            Some(to) => self.version(to)?.records.clone(),
+            None => self.all_records(),
+        };
+        let in_scope = |records: &[LocalRecord]| -> Vec<LocalRecord> {
+            match zone.map(normalize_name) {
+                Some(zone) => records
+                    .iter()
+                    .filter(|record| is_in_zone(&record.name, &zone))
+                    .cloned()
+                    .collect(),
+                None => records.to_vec(),
+            }
+        };
+        Ok(diff_records(&in_scope(from), &in_scope(&to)))
+    }
+
+    /// Bring back the records of an earlier version, all of them or only
+    /// those at or below `zone`. The result is a new version, so a rollback
+    /// can itself be rolled back.
+    fn rollback(
+        &mut self,
+        serial: u32,
+        zone: Option<&str>,
+    ) -> Result<ImportDiff, LocalRecordError> {
+        let target = &self.version(serial)?.records;
+        let current = self.all_records();
+        let restored: Vec<LocalRecord> = match zone.map(normalize_name) {
+            Some(zone) => current
+                .iter()
+                .filter(|record| !is_in_zone(&record.name, &zone))
+                .chain(
+                    target
+                        .iter()
+                        .filter(|record| is_in_zone(&record.name, &zone)),
+                )
+                .cloned()
+                .collect(),
+            None => target.clone(),
+        };
+
+        let diff = diff_records(&current, &restored);
+        if !diff.is_changed() {
+            return Ok(diff);
+        }
+        let records = &mut self.table_mut().records;
+        records.clear();
+        for record in restored {
+            records.entry(record.name.clone()).or_default().push(record);
+        }
+        self.commit(match zone {
+            Some(zone) => format!("rollback of {} to {}", normalize_name(zone), serial),
+            None => format!("rollback to {}", serial),
+        });
+        Ok(diff)
+    }
+}
+
+/// Failover: the healthy primaries, or while there are none the backups.
+/// Primaries without a health check always count as healthy. If nothing is
+/// left, everything is returned rather than no answer at all.
+fn for_health<'a>(
+    records: Vec<&'a LocalRecord>,
+    down: &HashSet<CheckTarget>,
+) -> Vec<&'a LocalRecord> {
+    let is_down = |record: &LocalRecord| {
+        record
+            .check_target()
+            .is_some_and(|target| down.contains(&target))
+    };
+
+    let primaries: Vec<&LocalRecord> = records
+        .iter()
+        .copied()
+        .filter(|record| !record.backup && !is_down(record))
+        .collect();
+    if !primaries.is_empty() {
+        return primaries;
+    }
+    let backups: Vec<&LocalRecord> = records
+        .iter()
+        .copied()
+        .filter(|record| record.backup && !is_down(record))
+        .collect();
+    if !backups.is_empty() {
+        return backups;
+    }
+    records
+}
+
+/// The records of type `rtype` meant for a client: those tagged with its
+/// site, failing that its country, then its continent, then the untagged
+/// ones. If every record is tagged for somewhere else they are all returned,
+/// so a name never goes unanswered because of where the client is.
+fn for_location<'a>(
+    records: &'a [LocalRecord],
+    rtype: RecordType,
+    client: &ClientLocation,
+) -> Vec<&'a LocalRecord> {
+    let of_type = records.iter().filter(|record| record.rtype() == rtype);
+    let best = of_type
+        .clone()
+        .map(|record| location_rank(record, client))
+        .max();
+    of_type
+        .filter(|record| Some(location_rank(record, client)) == best)
+        .collect()
+}
+
+/// Traffic splitting: when any of the records carries a weight, answer with
+/// just one of them, chosen with probability proportional to its weight
+/// (records without a weight count as 1, `weight=0` takes a record out of
+/// rotation). Unweighted record sets are returned whole.
+fn pick_weighted<'a>(records: Vec<&'a LocalRecord>, rng: &mut impl Rng) -> Vec<&'a LocalRecord> {
+    if records.iter().all(|record| record.weight.is_none()) {
+        return records;
+    }
+    let weights = records.iter().map(|record| record.weight.unwrap_or(1));
+    match WeightedIndex::new(weights) {
+        Ok(index) => vec![records[index.sample(rng)]],
+        // All weights zero: better to answer with everything than nothing
+        Err(_) => records,
+    }
+}
+
+/// Round-robin pools: answer with one record at a time, each in turn and as
+/// often as its weight says (records without a weight count as 1, `weight=0`
+/// takes a record out of rotation), by smooth weighted round-robin, which
+/// spreads a heavy record's turns out instead of bunching them.
+fn pick_in_turn<'a>(
+    records: Vec<&'a LocalRecord>,
+    turns: &HashMap<(String, RecordType), Arc<Mutex<PoolTurns>>>,
+) -> Vec<&'a LocalRecord> {
+    let Some(pool) = records
+        .first()
+        .and_then(|first| turns.get(&(first.name.clone(), first.rtype())))
+    else {
+        return records;
+    };
+    let mut current = pool.lock().unwrap_or_else(PoisonError::into_inner);
+    // Records that left the pool, withdrawn or failed over, lose their place
+    current.retain(|(data, _)| records.iter().any(|record| record.data == *data));
+
+    let mut total = 0;
+    let mut best: Option<(usize, usize)> = None;
+    for (index, record) in records.iter().enumerate() {
+        let weight = i64::from(record.weight.unwrap_or(1));
+        if weight == 0 {
+            continue;
+        }
+        total += weight;
+        let slot = match current.iter().position(|(data, _)| *data == record.data) {
+            Some(slot) => slot,
+            None => {
+                current.push((record.data.clone(), 0));
+                current.len() - 1
+            }
+        };
+        current[slot].1 += weight;
+        if best.is_none_or(|(_, best)| current[slot].1 > current[best].1) {
+            best = Some((index, slot));
+        }
+    }
+    match best {
+        Some((index, slot)) => {
+            current[slot].1 -= total;
+            vec![records[index]]
+        }
+        // All weights zero: better to answer with everything than nothing
+        None => records,
+    }
+}
+
+/// How closely a record's location fits the client, higher is closer
+fn location_rank(record: &LocalRecord, client: &ClientLocation) -> u8 {
+    match &record.location {
+        Some(tag @ GeoTag::Site(_)) if tag.matches(client) => 4,
+        Some(tag @ GeoTag::Country(_)) if tag.matches(client) => 3,
+        Some(tag @ GeoTag::Continent(_)) if tag.matches(client) => 2,
+        None => 1,
+        Some(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthCheck;
+    use crate::registry::RecordType;
+    use crate::reverse::ClasslessBlock;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn test_lookup_follows_local_cnames() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = LocalRecordsActor::new(receiver, Arc::default());
+
+        let block: ClasslessBlock = "192.168.1.64/26".parse().unwrap();
+        let mut records = block.delegation_cnames(3600);
+        records.push(
+            "65.64/26.1.168.192.in-addr.arpa PTR host.example.com"
+                .parse()
+                .unwrap(),
+        );
+        actor.import(records, false, false);
+
+        let answers = actor.table.lookup(
+            "65.1.168.192.in-addr.arpa.",
+            RecordType::PTR,
+            &ClientLocation::default(),
+        );
+        let answers: Vec<String> = answers.iter().map(|record| record.to_string()).collect();
+        assert_eq!(
+            answers,
+            vec![
+                "65.1.168.192.in-addr.arpa CNAME 65.64/26.1.168.192.in-addr.arpa 3600",
+                "65.64/26.1.168.192.in-addr.arpa PTR host.example.com 300",
+            ]
+        );
+
+        // A CNAME query gets just the CNAME; a CNAME to a name that isn't
+        // local is returned on its own for the client to chase
+        assert_eq!(
+            actor
+                .table
+                .lookup(
+                    "66.1.168.192.in-addr.arpa",
+                    RecordType::CNAME,
+                    &ClientLocation::default()
+                )
+                .len(),
+            1
+        );
+        assert_eq!(
+            actor
+                .table
+                .lookup(
+                    "66.1.168.192.in-addr.arpa",
+                    RecordType::PTR,
+                    &ClientLocation::default()
+                )
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_synthesize_ptr() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = LocalRecordsActor::new(receiver, Arc::default());
+        let records = [
+            "nas.lan A 192.168.1.10 600",
+            "files.lan A 192.168.1.10",
+            "nas.lan AAAA fd00::10",
+            "printer.lan CNAME nas.lan",
+        ]
+        .map(|record| record.parse().unwrap())
+        .to_vec();
+        actor.import(records, false, false);
+        actor.commit("import".to_string());
+
+        let ptrs = |table: &RecordTable, name: &str| -> Vec<String> {
+            table
+                .synthesize_ptr(name)
+                .iter()
+                .map(|record| record.to_string())
+                .collect()
+        };
+        assert_eq!(
+            ptrs(&actor.table, "10.1.168.192.in-addr.arpa."),
+            vec![
+                "10.1.168.192.in-addr.arpa PTR files.lan 300",
+                "10.1.168.192.in-addr.arpa PTR nas.lan 600",
+            ]
+        );
+        assert_eq!(
+            ptrs(
+                &actor.table,
+                "0.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa"
+            )
+            .len(),
+            1
+        );
+        assert!(ptrs(&actor.table, "11.1.168.192.in-addr.arpa").is_empty());
+        assert!(ptrs(&actor.table, "nas.lan").is_empty());
+
+        // The index follows changes to the records
+        actor.replace_zone("lan", Vec::new());
+        actor.commit("clear".to_string());
+        assert!(ptrs(&actor.table, "10.1.168.192.in-addr.arpa").is_empty());
+    }
+
+    #[test]
+    fn test_exists() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = LocalRecordsActor::new(receiver, Arc::default());
+        actor.import(
+            vec!["nas.office.lan A 192.168.1.10".parse().unwrap()],
+            false,
+            false,
+        );
+        assert!(actor.table.exists("nas.office.lan"));
+        // An empty non-terminal exists; names beside or below it don't
+        assert!(actor.table.exists("Office.lan."));
+        assert!(!actor.table.exists("printer.office.lan"));
+        assert!(!actor.table.exists("a.nas.office.lan"));
+        assert!(!actor.table.exists("ffice.lan"));
+    }
+
+    #[test]
+    fn test_lookup_by_location() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = LocalRecordsActor::new(receiver, Arc::default());
+        let records = [
+            "www.lan A 10.0.0.1",
+            "www.lan A 10.0.1.1 continent=EU",
+            "www.lan A 10.0.1.2 country=DE",
+            "eu-only.lan A 10.0.2.1 continent=EU",
+            "nas.lan A 10.1.0.1 site=home",
+            "nas.lan A 10.2.0.1 site=office",
+        ];
+        actor.import(
+            records.iter().map(|s| s.parse().unwrap()).collect(),
+            false,
+            false,
+        );
+
+        let client = |country: &str, continent: &str| ClientLocation {
+            country: Some(country.to_string()),
+            continent: Some(continent.to_string()),
+            site: None,
+        };
+        let answer = |name: &str, client: &ClientLocation| -> Vec<String> {
+            actor
+                .table
+                .lookup(name, RecordType::A, client)
+                .iter()
+                .map(|record| record.data.to_string())
+                .collect()
+        };
+
+        assert_eq!(answer("www.lan", &client("DE", "EU")), ["10.0.1.2"]);
+        assert_eq!(answer("www.lan", &client("FR", "EU")), ["10.0.1.1"]);
+        assert_eq!(answer("www.lan", &client("US", "NA")), ["10.0.0.1"]);
+        assert_eq!(answer("www.lan", &ClientLocation::default()), ["10.0.0.1"]);
+        // Tagged for elsewhere only: still answered
+        assert_eq!(answer("eu-only.lan", &client("US", "NA")), ["10.0.2.1"]);
+
+        // Site steering, falling back to every site's records when the
+        // client has no site
+        let at_office = ClientLocation {
+            site: Some("office".to_string()),
+            ..client("DE", "EU")
+        };
+        assert_eq!(answer("nas.lan", &at_office), ["10.2.0.1"]);
+        assert_eq!(
+            answer("nas.lan", &client("DE", "EU")),
+            ["10.1.0.1", "10.2.0.1"]
+        );
+    }
+
+    #[test]
+    fn test_weighted_selection() {
+        let records: Vec<LocalRecord> = [
+            "www.lan A 10.0.0.1 weight=90",
+            "www.lan A 10.0.0.2 weight=10",
+            "www.lan A 10.0.0.3 weight=0",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let mut canary = 0;
+        for _ in 0..1000 {
+            let picked = pick_weighted(records.iter().collect(), &mut rng);
+            assert_eq!(picked.len(), 1);
+            match picked[0].data.to_string().as_str() {
+                "10.0.0.1" => {}
+                "10.0.0.2" => canary += 1,
+                other => panic!("weight 0 record {} picked", other),
+            }
+        }
+        assert!((50..150).contains(&canary), "canary got {}/1000", canary);
+
+        // Without weights every record is returned
+        let unweighted: Vec<LocalRecord> = ["www.lan A 10.0.0.1", "www.lan A 10.0.0.2"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(
+            pick_weighted(unweighted.iter().collect(), &mut rng).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_failover() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = LocalRecordsActor::new(receiver, Arc::default());
+        let records = [
+            "app.lan A 10.0.0.1 check=443",
+            "app.lan A 10.0.0.2 role=backup",
+        ];
+        actor.import(
+            records.iter().map(|s| s.parse().unwrap()).collect(),
+            false,
+            false,
+        );
+        let answer = |actor: &mut LocalRecordsActor| -> Vec<String> {
+            actor
+                .table
+                .lookup("app.lan", RecordType::A, &ClientLocation::default())
+                .iter()
+                .map(|record| record.data.to_string())
+                .collect()
+        };
+
+        assert_eq!(answer(&mut actor), ["10.0.0.1"]);
+        let primary = CheckTarget {
+            ip: "10.0.0.1".parse().unwrap(),
+            check: HealthCheck::Tcp(443),
+        };
+        actor.handle_message(LocalRecordsMessage::SetHealth {
+            target: primary.clone(),
+            healthy: false,
+        });
+        assert_eq!(answer(&mut actor), ["10.0.0.2"]);
+        actor.handle_message(LocalRecordsMessage::SetHealth {
+            target: primary,
+            healthy: true,
+        });
+        assert_eq!(answer(&mut actor), ["10.0.0.1"]);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = LocalRecordsActor::new(receiver, Arc::default());
+        let records = [
+            "pool.lan A 10.0.0.1 select=round-robin weight=2 check=http:80/health",
+            "pool.lan A 10.0.0.2",
+            "pool.lan A 10.0.0.3 weight=0",
+        ];
+        actor.import(
+            records.iter().map(|s| s.parse().unwrap()).collect(),
+            false,
+            false,
+        );
+        actor.commit("import".to_string());
+        let turns = |actor: &LocalRecordsActor, n: usize| -> Vec<String> {
+            (0..n)
+                .flat_map(|_| {
+                    actor
+                        .table
+                        .lookup("pool.lan", RecordType::A, &ClientLocation::default())
+                })
+                .map(|record| record.data.to_string())
+                .collect()
+        };
+
+        // Two turns in three for the heavier record, spread out
+        assert_eq!(
+            turns(&actor, 6),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.1", "10.0.0.1", "10.0.0.2", "10.0.0.1"]
+        );
+        // Only healthy members take turns, across snapshots of the table
+        actor.handle_message(LocalRecordsMessage::SetHealth {
+            target: CheckTarget {
+                ip: "10.0.0.1".parse().unwrap(),
+                check: "http:80/health".parse().unwrap(),
+            },
+            healthy: false,
+        });
+        assert_eq!(turns(&actor, 2), ["10.0.0.2", "10.0.0.2"]);
+
+        // A pool that is gone takes its turns with it
+        actor.import(vec!["pool.lan A 10.0.0.4".parse().unwrap()], true, false);
+        actor.commit("import".to_string());
+        assert!(actor.table.turns.is_empty());
+    }
+
+    #[test]
+    fn test_replace_zone() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = LocalRecordsActor::new(receiver, Arc::default());
+        let parse = |records: &[&str]| -> Vec<LocalRecord> {
+            records.iter().map(|s| s.parse().unwrap()).collect()
+        };
+        actor.import(
+            parse(&[
+                "example.com A 192.0.2.1",
+                "www.example.com A 192.0.2.2",
+                "nas.lan A 10.0.0.1",
+            ]),
+            false,
+            false,
+        );
+
+        let diff = actor.replace_zone(
+            "Example.com.",
+            parse(&[
+                "example.com A 192.0.2.1",
+                "mail.example.com A 192.0.2.3",
+                "outside.org A 192.0.2.9",
+            ]),
+        );
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.added, parse(&["mail.example.com A 192.0.2.3"]));
+        assert_eq!(diff.removed, parse(&["www.example.com A 192.0.2.2"]));
+
+        let names: Vec<String> = actor
+            .all_records()
+            .into_iter()
+            .map(|record| record.name)
+            .collect();
+        assert_eq!(names, ["example.com", "mail.example.com", "nas.lan"]);
+
+        // An empty zone drops everything in it
+        assert_eq!(
+            actor.replace_zone("example.com", Vec::new()).removed.len(),
+            2
+        );
+        assert_eq!(actor.all_records().len(), 1);
+    }
+
+    #[test]
+    fn test_versions_and_rollback() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = LocalRecordsActor::new(receiver, Arc::default());
+        let add = |actor: &mut LocalRecordsActor, record: &str| {
+            let (respond_to, _) = oneshot::channel();
+            actor.handle_message(LocalRecordsMessage::Add {
+                record: record.parse().unwrap(),
+                respond_to,
+            });
+        };
+        add(&mut actor, "www.example.com A 192.0.2.1");
+        add(&mut actor, "nas.lan A 10.0.0.1");
+        // Adding a record that is already there is not a new version
+        add(&mut actor, "nas.lan A 10.0.0.1");
+        actor.replace_zone("example.com", Vec::new());
+        actor.commit("transfer of example.com".to_string());
+        add(&mut actor, "printer.lan A 10.0.0.2");
+
+        let serials: Vec<u32> = actor.history.iter().map(|v| v.info.serial).collect();
+        assert_eq!(serials, [0, 1, 2, 3, 4]);
+
+        let diff = actor.diff_versions(2, None, Some("example.com")).unwrap();
+        assert_eq!(diff.removed.len(), 1);
+        assert!(diff.added.is_empty());
+        assert!(matches!(
+            actor.diff_versions(9, None, None),
+            Err(LocalRecordError::UnknownVersion(9))
+        ));
+
+        // Rolling back one zone leaves the rest of the table alone
+        let diff = actor.rollback(2, Some("example.com.")).unwrap();
+        assert_eq!(diff.added.len(), 1);
+        let names: Vec<String> = actor
+            .all_records()
+            .into_iter()
+            .map(|record| record.name)
+            .collect();
+        assert_eq!(names, ["nas.lan", "printer.lan", "www.example.com"]);
+        assert_eq!(actor.history.back().unwrap().info.serial, 5);
+
+        actor.rollback(0, None).unwrap();
+        assert!(actor.all_records().is_empty());
+
+        for i in 0..HISTORY_LEN {
+            add(&mut actor, &format!("host{}.lan A 10.0.1.1", i));
+        }
+        assert_eq!(actor.history.len(), HISTORY_LEN);
+        assert!(actor.version(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lookups_see_published_changes() {
+        let handle = crate::handlers::local_records_handler::LocalRecordsHandle::new();
+        let lookup = || handle.lookup("nas.lan", RecordType::A, &ClientLocation::default());
+        assert!(lookup().is_empty());
+
+        handle.add("nas.lan A 10.0.0.1".parse().unwrap()).await;
+        assert_eq!(lookup().len(), 1);
+
+        handle.remove("nas.lan".to_string(), None).await;
+        assert!(lookup().is_empty());
+    }
+}
@@ -1,7 +1,34 @@
-use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 use tokio::sync::oneshot;
 
+use crate::errors::LocalRecordError;
+use crate::handlers::cache_handler::CacheConfig;
+use crate::health::CheckTarget;
+use crate::local_records::{ImportDiff, LocalRecord, VersionInfo};
+use crate::protocol::DnsResourceRecord;
+use crate::registry::{Rcode, RecordClass, RecordType};
+
+/// Outcome of resolving a name upstream
+#[derive(Debug, Clone)]
+pub enum Resolution {
+    /// The upstream's answer records, CNAMEs leading to the name asked for
+    /// included
+    Records(Vec<DnsResourceRecord>),
+    /// The upstream answered that there is nothing to return: NXDOMAIN, or
+    /// NOERROR with no records (NODATA). Carries the zone's SOA when the
+    /// upstream sent one, with its TTL set to the negative caching TTL.
+    Negative {
+        rcode: Rcode,
+        soa: Option<DnsResourceRecord>,
+    },
+    /// The lookup itself failed (timeout, SERVFAIL, no upstream reachable,
+    /// ...), with the response code to give the client: the upstream's, or
+    /// SERVFAIL when there was none
+    Failed(Rcode),
+}
+
 /// The ActorMessage enum defines the kind of messages we can send to the actor.
 /// By using an enum, we can have many different message types,
 /// and each message type can have its own set of arguments.
@@ -9,9 +36,160 @@ use tokio::sync::oneshot;
 /// which is a message passing channel that allows sending exactly one message.
 #[derive(Debug)]
 pub enum QueryActorMessage {
-    /// Resolve a DNS name to an IPv4 address.
+    /// Resolve a DNS name to its records of one type.
     Resolve {
+        name: Arc<str>,
+        qtype: RecordType,
+        respond_to: oneshot::Sender<Resolution>,
+    },
+}
+
+/// Messages understood by the LocalRecordsActor.
+#[derive(Debug)]
+pub enum LocalRecordsMessage {
+    /// Add a record, returning false if an identical record already exists.
+    Add {
+        record: LocalRecord,
+        respond_to: oneshot::Sender<bool>,
+    },
+    /// Remove all records for a name, optionally restricted to one type.
+    /// Returns the number of records removed.
+    Remove {
         name: String,
-        respond_to: oneshot::Sender<Option<Vec<IpAddr>>>,
+        rtype: Option<RecordType>,
+        respond_to: oneshot::Sender<usize>,
+    },
+    /// List every record currently held.
+    List {
+        respond_to: oneshot::Sender<Vec<LocalRecord>>,
+    },
+    /// Merge a set of records into the table (or replace it entirely),
+    /// returning the differences. With `dry_run` nothing is changed.
+    Import {
+        records: Vec<LocalRecord>,
+        replace: bool,
+        dry_run: bool,
+        respond_to: oneshot::Sender<ImportDiff>,
+    },
+    /// Replace every record at or below `zone` with `records`, returning the
+    /// differences. Used to load transferred zones and zone files; `change`
+    /// describes the replacement in the version history.
+    ReplaceZone {
+        zone: String,
+        records: Vec<LocalRecord>,
+        change: String,
+        respond_to: oneshot::Sender<ImportDiff>,
+    },
+    /// List the versions of the table kept in the history, oldest first.
+    Versions {
+        respond_to: oneshot::Sender<Vec<VersionInfo>>,
+    },
+    /// Compare two versions (`to` defaults to the current table), optionally
+    /// only at or below a zone.
+    Diff {
+        from: u32,
+        to: Option<u32>,
+        zone: Option<String>,
+        respond_to: oneshot::Sender<Result<ImportDiff, LocalRecordError>>,
+    },
+    /// Restore a version (optionally only at or below a zone) as a new
+    /// version, returning the changes made.
+    Rollback {
+        serial: u32,
+        zone: Option<String>,
+        respond_to: oneshot::Sender<Result<ImportDiff, LocalRecordError>>,
+    },
+    /// Mark a health-checked target up or down.
+    SetHealth { target: CheckTarget, healthy: bool },
+}
+
+/// Identifies a query for duplicate suppression: a retransmission repeats the
+/// client, the transaction ID and the question.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QueryKey {
+    pub client: SocketAddr,
+    pub id: u16,
+    /// Lowercased question name, interned
+    pub name: Arc<str>,
+    pub qtype: RecordType,
+}
+
+/// Whether a query was already seen within the suppression window
+#[derive(Debug, PartialEq)]
+pub enum Duplicate {
+    /// First time seen; resolve it
+    New,
+    /// Still being resolved; the pending response answers this copy too
+    InFlight,
+    /// Already answered; carries the encoded response to send again
+    Answered(Vec<u8>),
+}
+
+/// Identifies a cached answer: the question, and the route that answered it
+/// since routes may resolve the same name differently
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// Lowercased question name, interned
+    pub name: Arc<str>,
+    pub qtype: RecordType,
+    pub qclass: RecordClass,
+    /// The `--route` that resolved it, if any
+    pub route: Option<Arc<str>>,
+}
+
+/// An answer found in the cache
+#[derive(Debug)]
+pub struct Cached {
+    pub resolution: Resolution,
+    /// Whether the answer is popular and close enough to expiring that it
+    /// should be looked up again now; only ever set for one of its hits
+    pub prefetch: bool,
+}
+
+/// Changes to the cache table, reported to the CacheActor for keeping the
+/// eviction order. A rank is where the entry now stands in line.
+#[derive(Debug)]
+pub enum CacheMessage {
+    /// An answer was stored, or stored again.
+    Stored { key: CacheKey, rank: (u64, u64) },
+    /// An answer was looked up.
+    Used { key: CacheKey, rank: (u64, u64) },
+    /// An answer expired and was taken out.
+    Removed { key: CacheKey },
+}
+
+/// How full a cache is, and how it was set up
+#[derive(Debug, Clone, Copy)]
+pub struct CacheUsage {
+    pub entries: usize,
+    /// Estimated memory held by the entries
+    pub bytes: usize,
+    pub config: CacheConfig,
+    /// Answers evicted before they expired
+    pub evictions: u64,
+}
+
+/// Whether a lookup joined one already on its way upstream
+#[derive(Debug)]
+pub enum Joined {
+    /// None was; resolve it and report the outcome with `Finish`
+    Leader,
+    /// One was; its outcome arrives here. An error means the leader gave up
+    /// and the lookup is the follower's to make.
+    Follower(oneshot::Receiver<Resolution>),
+}
+
+/// Messages understood by the InflightActor.
+#[derive(Debug)]
+pub enum InflightMessage {
+    /// Join the lookup in flight for a question, or lead a new one.
+    Join {
+        key: CacheKey,
+        respond_to: oneshot::Sender<Joined>,
+    },
+    /// Hand the outcome of a led lookup to everyone who joined it.
+    Finish {
+        key: CacheKey,
+        resolution: Resolution,
     },
 }
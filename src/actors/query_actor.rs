@@ -1,36 +1,57 @@
-use std::net::IpAddr;
-
 // Import necessary modules and types
-use crate::actors::messages::QueryActorMessage;
+use crate::actors::messages::{QueryActorMessage, Resolution};
+use crate::hedge::HedgedResolver;
+use crate::names::{escape_label, name_to_labels};
+use crate::parsers::ROOT_NAME;
+use crate::protocol::DnsResourceRecord;
+use crate::registry::{Rcode, RecordClass, RecordType};
+use crate::response_builder::SoaRecord;
 
 use hickory_resolver::{
-    lookup_ip::LookupIp, name_server::TokioConnectionProvider, ResolveError, Resolver,
+    lookup::Lookup,
+    proto::{
+        op::ResponseCode,
+        rr::rdata::SOA,
+        rr::{RData, Record},
+        serialize::binary::{BinEncodable, BinEncoder},
+        ProtoError, ProtoErrorKind,
+    },
+    Name, ResolveError,
 };
-use tokio::sync::mpsc;
-use tracing::error;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, error};
+
+/// Largest response code the 4-bit header field holds; extended ones need
+/// EDNS, which isn't spoken to clients
+const MAX_HEADER_RCODE: u16 = 15;
+
+/// A queue of queries that several actors take work from
+pub type SharedReceiver = Arc<Mutex<mpsc::Receiver<QueryActorMessage>>>;
 
 /// Resolves DNS queries by acting as an actor that processes incoming messages
 pub struct QueryActor {
-    // The receiver for incoming messages
-    receiver: mpsc::Receiver<QueryActorMessage>,
+    // The receiver for incoming messages, shared with the rest of the pool
+    receiver: SharedReceiver,
     // The resolver used to resolve DNS queries
-    resolver: Resolver<TokioConnectionProvider>,
+    resolver: HedgedResolver,
 }
 
 impl QueryActor {
     // Constructor for the actor
-    pub fn new(
-        receiver: mpsc::Receiver<QueryActorMessage>,
-        resolver: Resolver<TokioConnectionProvider>,
-    ) -> Self {
+    pub fn new(receiver: SharedReceiver, resolver: HedgedResolver) -> Self {
         // Return a new actor with the given receiver and an empty key-value hash map
         Self { receiver, resolver }
     }
 
     // Run the actor
     pub async fn run(&mut self) {
-        // Continuously receive messages and handle them
-        while let Some(msg) = self.receiver.recv().await {
+        // Take the next message whenever idle; the lock is let go before the
+        // lookup, so the other actors keep taking messages meanwhile
+        loop {
+            let Some(msg) = self.receiver.lock().await.recv().await else {
+                break;
+            };
             self.handle_message(msg).await;
         }
     }
@@ -38,30 +59,352 @@ impl QueryActor {
     // Handle a message
     async fn handle_message(&self, msg: QueryActorMessage) {
         match msg {
-            QueryActorMessage::Resolve { name, respond_to } => {
-                let lookup_result: Result<LookupIp, ResolveError> =
-                    self.resolver.lookup_ip(&name).await;
-                match lookup_result {
+            QueryActorMessage::Resolve {
+                name,
+                qtype,
+                respond_to,
+            } => {
+                // Build the name from its raw label bytes: parsing text would apply
+                // IDNA rules and reject binary labels. The result is always absolute
+                // (no search domains), and the root has no labels at all.
+                let lookup_result: Result<Lookup, ResolveError> =
+                    match Name::from_labels(name_to_labels(&name)) {
+                        Ok(fqdn) => self.resolver.lookup(fqdn, qtype.0.into()).await,
+                        Err(e) => Err(e.into()),
+                    };
+                let resolution = match lookup_result {
                     Ok(lookup) => {
-                        // Collect all IP addresses (both IPv4 and IPv6) from the lookup.
-                        // When you call resolver.lookup_ip(&name), the returned LookupIp type is not a simple collection of data.
-                        // It's an iterator that is tied to the lifetime of the resolver and the name it was called with.
-                        // We need to collect the IP addresses into a Vec<IpAddr>.
-                        let ips: Vec<IpAddr> = lookup.iter().collect();
-
-                        if !ips.is_empty() {
-                            let _ = respond_to.send(Some(ips));
+                        // The lookup holds the CNAMEs followed on the way, then
+                        // the records asked for
+                        let records: Vec<DnsResourceRecord> =
+                            chain_order(lookup.query().name(), lookup.records())
+                                .into_iter()
+                                .filter_map(|record| match resource_record(record) {
+                                    Ok(record) => Some(record),
+                                    Err(e) => {
+                                        error!(
+                                            "Dropped a {} record for {}: {}",
+                                            record.record_type(),
+                                            name,
+                                            e
+                                        );
+                                        None
+                                    }
+                                })
+                                .collect();
+
+                        if !records.is_empty() {
+                            Resolution::Records(records)
                         } else {
-                            // If the lookup was successful but returned no IPs
-                            let _ = respond_to.send(None);
+                            // If the lookup was successful but returned no records
+                            Resolution::Negative {
+                                rcode: Rcode::NOERROR,
+                                soa: None,
+                            }
                         }
                     }
-                    Err(e) => {
-                        error!("DNS lookup failed for {}: {}", name, e);
-                        let _ = respond_to.send(None);
-                    }
-                }
+                    Err(e) => negative_resolution(&e).unwrap_or_else(|| {
+                        error!("DNS lookup failed for {} {}: {}", name, qtype, e);
+                        Resolution::Failed(failure_rcode(&e))
+                    }),
+                };
+                let _ = respond_to.send(resolution);
             }
         }
     }
 }
+
+/// The records of a lookup with the CNAMEs leading from `name` first, in
+/// the order they are followed, then the rest as they came. Clients reading
+/// the answer section top to bottom then meet each alias before its target.
+fn chain_order<'a>(name: &Name, records: &'a [Record]) -> Vec<&'a Record> {
+    let mut rest: Vec<&Record> = records.iter().collect();
+    let mut ordered = Vec::with_capacity(records.len());
+    let mut owner = name.clone();
+    // Each CNAME is taken once, so a loop in the chain ends it
+    while let Some(index) = rest
+        .iter()
+        .position(|record| record.name() == &owner && matches!(record.data(), RData::CNAME(_)))
+    {
+        let record = rest.remove(index);
+        if let RData::CNAME(target) = record.data() {
+            owner = target.0.clone();
+        }
+        ordered.push(record);
+    }
+    ordered.extend(rest);
+    ordered
+}
+
+/// Convert one of hickory's records into ours, its RDATA in wire form with
+/// any names written out in full
+fn resource_record(record: &Record) -> Result<DnsResourceRecord, ProtoError> {
+    let mut rdata = Vec::new();
+    let mut encoder = BinEncoder::new(&mut rdata);
+    // Canonical names aren't compressed, which a pointer into a buffer of
+    // its own would need
+    encoder.set_canonical_names(true);
+    record.data().emit(&mut encoder)?;
+
+    Ok(DnsResourceRecord::new(
+        labels_to_name(record.name()),
+        RecordType(record.record_type().into()),
+        RecordClass(record.dns_class().into()),
+        record.ttl(),
+        rdata,
+    ))
+}
+
+/// A name in the parser's text form: escaped labels without the trailing
+/// dot, and "." for the root
+fn labels_to_name(name: &Name) -> String {
+    if name.is_root() {
+        return ROOT_NAME.to_string();
+    }
+    name.iter().map(escape_label).collect::<Vec<_>>().join(".")
+}
+
+/// Turn a "no records" error into a negative answer, keeping the upstream SOA.
+/// Only NXDOMAIN and NODATA are negative answers; other response codes say
+/// the lookup failed.
+fn negative_resolution(error: &ResolveError) -> Option<Resolution> {
+    let ProtoErrorKind::NoRecordsFound {
+        soa,
+        negative_ttl,
+        response_code: response_code @ (ResponseCode::NXDomain | ResponseCode::NoError),
+        ..
+    } = error.proto()?.kind()
+    else {
+        return None;
+    };
+
+    debug!("Negative answer from upstream: {}", response_code);
+    Some(Resolution::Negative {
+        rcode: Rcode(u16::from(*response_code)),
+        soa: soa
+            .as_deref()
+            .map(|soa| soa_resource_record(soa, *negative_ttl)),
+    })
+}
+
+/// The response code to fail a lookup with: the upstream's, when it sent
+/// one that fits the header, or SERVFAIL
+fn failure_rcode(error: &ResolveError) -> Rcode {
+    match error.proto().map(ProtoError::kind) {
+        Some(ProtoErrorKind::NoRecordsFound { response_code, .. })
+            if u16::from(*response_code) <= MAX_HEADER_RCODE =>
+        {
+            Rcode(u16::from(*response_code))
+        }
+        _ => Rcode::SERVFAIL,
+    }
+}
+
+/// Convert hickory's SOA record into ours
+fn soa_resource_record(record: &Record<SOA>, negative_ttl: Option<u32>) -> DnsResourceRecord {
+    let soa = record.data();
+    let name = |name: &Name| name.to_string().trim_end_matches('.').to_string();
+
+    // RFC 2308: the negative TTL is the lesser of the SOA TTL and its MINIMUM field
+    let ttl = negative_ttl.unwrap_or_else(|| record.ttl().min(soa.minimum()));
+
+    SoaRecord {
+        zone: name(record.name()),
+        mname: name(soa.mname()),
+        rname: name(soa.rname()),
+        serial: soa.serial(),
+        refresh: soa.refresh() as u32,
+        retry: soa.retry() as u32,
+        expire: soa.expire() as u32,
+        minimum: soa.minimum(),
+    }
+    .to_resource_record(ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry;
+    use crate::response_builder::{encode_name, NaptrRecord};
+    use hickory_resolver::proto::op::Query;
+    use hickory_resolver::proto::rr::rdata::{A, CNAME, MX, NAPTR};
+    use hickory_resolver::proto::rr::RecordType;
+    use hickory_resolver::proto::ProtoError;
+    use std::str::FromStr;
+
+    fn nx_error(soa: Option<Record<SOA>>, negative_ttl: Option<u32>) -> ResolveError {
+        let query = Query::query(
+            Name::from_str("missing.example.com.").unwrap(),
+            RecordType::A,
+        );
+        ProtoError::nx_error(
+            Box::new(query),
+            soa.map(Box::new),
+            None,
+            negative_ttl,
+            ResponseCode::NXDomain,
+            true,
+            None,
+        )
+        .into()
+    }
+
+    #[test]
+    fn test_negative_resolution_keeps_soa() {
+        let soa = SOA::new(
+            Name::from_str("ns1.example.com.").unwrap(),
+            Name::from_str("hostmaster.example.com.").unwrap(),
+            2024010101,
+            7200,
+            3600,
+            1209600,
+            300,
+        );
+        let record = Record::from_rdata(Name::from_str("example.com.").unwrap(), 3600, soa);
+
+        let Some(Resolution::Negative { rcode, soa }) =
+            negative_resolution(&nx_error(Some(record), None))
+        else {
+            panic!("expected a negative resolution");
+        };
+        assert_eq!(rcode, Rcode::NXDOMAIN);
+
+        let soa = soa.expect("SOA should be kept");
+        assert_eq!(soa.name, "example.com");
+        assert_eq!(soa.rtype, registry::RecordType::SOA);
+        // The lesser of the record TTL (3600) and MINIMUM (300)
+        assert_eq!(soa.ttl, 300);
+        // MINIMUM is the last field of the rdata
+        assert_eq!(&soa.rdata[soa.rdata.len() - 4..], &300u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_resource_record() {
+        let mx = Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            300,
+            RData::MX(MX::new(10, Name::from_str("mail.example.com.").unwrap())),
+        );
+        let record = resource_record(&mx).unwrap();
+        assert_eq!(record.name, "example.com");
+        assert_eq!(record.rtype, registry::RecordType::MX);
+        assert_eq!(record.ttl, 300);
+        // The exchange is written out in full, never as a pointer
+        let mut rdata = vec![0, 10];
+        rdata.extend(encode_name("mail.example.com"));
+        assert_eq!(record.rdata, rdata);
+
+        // Text fields pass through as they are, the replacement uncompressed
+        let naptr = Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            300,
+            RData::NAPTR(NAPTR::new(
+                100,
+                10,
+                b"S".to_vec().into(),
+                b"SIP+D2U".to_vec().into(),
+                Box::default(),
+                Name::from_str("_sip._udp.example.com.").unwrap(),
+            )),
+        );
+        let record = resource_record(&naptr).unwrap();
+        let expected = NaptrRecord {
+            name: "example.com".to_string(),
+            order: 100,
+            preference: 10,
+            flags: "S".to_string(),
+            services: "SIP+D2U".to_string(),
+            regexp: String::new(),
+            replacement: "_sip._udp.example.com".to_string(),
+        };
+        assert_eq!(record.rdata, expected.to_resource_record(300).rdata);
+        assert_eq!(NaptrRecord::from_resource_record(&record), Some(expected));
+
+        assert_eq!(labels_to_name(&Name::root()), ".");
+        assert_eq!(
+            labels_to_name(&Name::from_labels([&b"a.b"[..], b"c"]).unwrap()),
+            "a\\.b.c"
+        );
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let name = |name: &str| Name::from_str(name).unwrap();
+        let cname = |owner: &str, target: &str| {
+            Record::from_rdata(name(owner), 300, RData::CNAME(CNAME(name(target))))
+        };
+        let a = Record::from_rdata(
+            name("edge.cdn.example."),
+            60,
+            RData::A(A::new(192, 0, 2, 1)),
+        );
+        let records = [
+            a.clone(),
+            cname("cdn.example.", "edge.cdn.example."),
+            cname("WWW.example.com.", "cdn.example."),
+        ];
+
+        let owners: Vec<String> = chain_order(&name("www.example.com."), &records)
+            .iter()
+            .map(|record| record.name().to_string())
+            .collect();
+        assert_eq!(
+            owners,
+            ["www.example.com.", "cdn.example.", "edge.cdn.example."]
+        );
+
+        // A looping chain still returns every record once
+        let looped = [
+            cname("a.example.", "b.example."),
+            cname("b.example.", "a.example."),
+        ];
+        assert_eq!(chain_order(&name("a.example."), &looped).len(), 2);
+        assert_eq!(chain_order(&name("x.example."), &[a]).len(), 1);
+    }
+
+    #[test]
+    fn test_negative_resolution_without_soa() {
+        assert!(matches!(
+            negative_resolution(&nx_error(None, None)),
+            Some(Resolution::Negative {
+                rcode: Rcode::NXDOMAIN,
+                soa: None
+            })
+        ));
+        let timeout: ResolveError = ProtoError::from(ProtoErrorKind::Timeout).into();
+        assert!(negative_resolution(&timeout).is_none());
+    }
+
+    #[test]
+    fn test_failure_rcode() {
+        let upstream_error = |code| -> ResolveError {
+            ProtoError::from(ProtoErrorKind::NoRecordsFound {
+                query: Box::new(Query::default()),
+                soa: None,
+                ns: None,
+                negative_ttl: None,
+                response_code: code,
+                trusted: false,
+                authorities: None,
+            })
+            .into()
+        };
+
+        // The upstream's own failure is passed on, and isn't a negative answer
+        let refused = upstream_error(ResponseCode::Refused);
+        assert!(negative_resolution(&refused).is_none());
+        assert_eq!(failure_rcode(&refused), Rcode::REFUSED);
+        assert_eq!(
+            failure_rcode(&upstream_error(ResponseCode::ServFail)),
+            Rcode::SERVFAIL
+        );
+        // ...unless it doesn't fit the header
+        assert_eq!(
+            failure_rcode(&upstream_error(ResponseCode::BADVERS)),
+            Rcode::SERVFAIL
+        );
+        // No answer at all is a server failure
+        let timeout: ResolveError = ProtoError::from(ProtoErrorKind::Timeout).into();
+        assert_eq!(failure_rcode(&timeout), Rcode::SERVFAIL);
+    }
+}
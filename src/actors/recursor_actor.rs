@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use crate::actors::messages::QueryActorMessage;
+use crate::actors::query_actor::SharedReceiver;
+use crate::recursor::Recursor;
+
+/// Resolves queries from the root down (`--recursive`), taking them from the
+/// same kind of shared queue as the QueryActors
+pub struct RecursorActor {
+    // The receiver for incoming messages, shared with the rest of the pool
+    receiver: SharedReceiver,
+    // Shared by the pool, with the zone cuts it remembers
+    recursor: Arc<Recursor>,
+}
+
+impl RecursorActor {
+    // Constructor for the actor
+    pub fn new(receiver: SharedReceiver, recursor: Arc<Recursor>) -> Self {
+        Self { receiver, recursor }
+    }
+
+    // Run the actor
+    pub async fn run(&mut self) {
+        // As with the QueryActors, the lock is let go before the lookup
+        loop {
+            let Some(msg) = self.receiver.lock().await.recv().await else {
+                break;
+            };
+            match msg {
+                QueryActorMessage::Resolve {
+                    name,
+                    qtype,
+                    respond_to,
+                } => {
+                    let resolution = self.recursor.resolve(&name, qtype).await;
+                    let _ = respond_to.send(resolution);
+                }
+            }
+        }
+    }
+}
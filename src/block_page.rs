@@ -0,0 +1,155 @@
+//! Sending blocked names to a block page (`--block-page`)
+//!
+//! Instead of NXDOMAIN, which browsers show as a connection error, A and
+//! AAAA queries for blocked names can be answered with the address of a web
+//! server explaining the block. A family without a block page address gets
+//! an empty answer rather than NXDOMAIN, so a client asking for both still
+//! connects to the other: an NXDOMAIN for the AAAA query would tell it the
+//! name doesn't exist at all. HTTPS and SVCB queries get an empty answer
+//! too, so browsers fall back to the A and AAAA records instead of
+//! connecting to the endpoints the real ones would advertise. Other types
+//! keep the blocklist's answer.
+//!
+//! With `--redirect-nxdomain`, upstream NXDOMAIN answers to A, AAAA, HTTPS
+//! and SVCB queries are sent to the block page the same way, so a mistyped
+//! name lands on a search or help page. Names answered locally keep their
+//! NXDOMAIN, as do queries with the DO bit set, whose validators would
+//! reject the rewritten answer anyway. The cache keeps the upstream's real
+//! answer; only the client's response is rewritten.
+//!
+//! The block page applies to everything answered as blocked, by the
+//! blocklist, a `--script` or a `--plugin` alike. `stats` counts the
+//! rewritten answers as `queries_redirected`.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use futures::future::BoxFuture;
+use tracing::debug;
+
+use crate::blocklist::SINKHOLE_TTL;
+use crate::metrics;
+use crate::names::DisplayName;
+use crate::pipeline::{Answer, Middleware, Next, Request};
+use crate::protocol::DnsResourceRecord;
+use crate::query_log::Disposition;
+use crate::registry::{Rcode, RecordType};
+
+/// The middleware layer sending blocked names to the block page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockPage {
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    /// Whether upstream NXDOMAIN answers go there too
+    nxdomain: bool,
+}
+
+impl BlockPage {
+    /// The block page at `addresses`, at most one of each family
+    pub fn new(addresses: &[IpAddr], nxdomain: bool) -> anyhow::Result<Self> {
+        let mut page = BlockPage {
+            ipv4: None,
+            ipv6: None,
+            nxdomain,
+        };
+        for address in addresses {
+            match address.to_canonical() {
+                IpAddr::V4(ip) if page.ipv4.is_none() => page.ipv4 = Some(ip),
+                IpAddr::V6(ip) if page.ipv6.is_none() => page.ipv6 = Some(ip),
+                _ => anyhow::bail!(
+                    "--block-page takes one address of each family at most, got {} too",
+                    address
+                ),
+            }
+        }
+        Ok(page)
+    }
+
+    /// Whether `answer` is sent to the block page
+    fn redirects(&self, request: &Request<'_>, answer: &Answer) -> bool {
+        let redirected_type = matches!(
+            request.question.qtype,
+            RecordType::A | RecordType::AAAA | RecordType::HTTPS | RecordType::SVCB
+        );
+        let nxdomain = || {
+            self.nxdomain
+                && answer.rcode == Rcode::NXDOMAIN
+                && matches!(
+                    answer.disposition,
+                    Disposition::Forwarded | Disposition::Cached
+                )
+                && !request.dnssec_ok
+        };
+        redirected_type && (answer.disposition == Disposition::Blocked || nxdomain())
+    }
+
+    /// The block page's records for the question, possibly none
+    fn records(&self, request: &Request<'_>) -> Vec<DnsResourceRecord> {
+        let question = request.question;
+        let rdata = match question.qtype {
+            RecordType::A => self.ipv4.map(|ip| ip.octets().to_vec()),
+            RecordType::AAAA => self.ipv6.map(|ip| ip.octets().to_vec()),
+            _ => None,
+        };
+        rdata
+            .map(|rdata| {
+                DnsResourceRecord::new(
+                    question.name.clone(),
+                    question.qtype,
+                    question.qclass,
+                    SINKHOLE_TTL,
+                    rdata,
+                )
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+impl Middleware for BlockPage {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        Box::pin(async move {
+            let answer = next.run(request).await;
+            if !self.redirects(request, &answer) {
+                return answer;
+            }
+            debug!(
+                "Sent {} to the block page",
+                DisplayName(&request.question.name)
+            );
+            metrics::incr(&request.ctx.metrics.queries_redirected);
+            let mut redirected = Answer::new(answer.disposition);
+            redirected.answers = self.records(request);
+            redirected
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_page_addresses() {
+        let page = BlockPage::new(
+            &[
+                "192.0.2.80".parse().unwrap(),
+                "2001:db8::80".parse().unwrap(),
+            ],
+            false,
+        )
+        .unwrap();
+        assert_eq!(page.ipv4, Some(Ipv4Addr::new(192, 0, 2, 80)));
+        assert_eq!(page.ipv6, Some("2001:db8::80".parse().unwrap()));
+
+        // An IPv4-mapped address is an IPv4 one
+        let page = BlockPage::new(&["::ffff:192.0.2.80".parse().unwrap()], true).unwrap();
+        assert_eq!(page.ipv4, Some(Ipv4Addr::new(192, 0, 2, 80)));
+        assert_eq!(page.ipv6, None);
+
+        assert!(BlockPage::new(
+            &["192.0.2.80".parse().unwrap(), "192.0.2.81".parse().unwrap()],
+            false
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,588 @@
+//! Ad and tracker blocking (`--blocklist`)
+//!
+//! Blocklists are domain list files in the same formats as route lists: one
+//! domain per line, or hosts-file lines (`0.0.0.0 ads.example`), so the
+//! common published lists work as they are. A listed domain is blocked along
+//! with everything under it. Blocked queries are answered NXDOMAIN, or with
+//! the unspecified address (`0.0.0.0`, `::`) under `--block-mode null`, which
+//! some clients retry less eagerly; other types then get an empty answer.
+//!
+//! Local records are answered before the blocklist, so adding one for a
+//! blocked name lets it through. So does the allowlist (`--allow`, or `allowlist
+//! add` on the control socket), without answering for the name: `example.com`
+//! lets that name alone through, `*.example.com` everything under it.
+//!
+//! A list can also be an `http://` or `https://` URL. Those are downloaded in
+//! the background once the server is up, since fetching them may need the
+//! server itself to resolve the list's host, and again every refresh interval
+//! along with the files. The new blocklist is built on the side and swapped in
+//! whole, so queries never wait on a reload; if any list can't be had, the
+//! current blocklist stays and the reload is retried a minute later.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use tracing::{info, warn};
+
+use crate::errors::LocalRecordError;
+use crate::local_records::{normalize_name, validate_name};
+use crate::protocol::DnsResourceRecord;
+use crate::registry::{RecordClass, RecordType};
+use crate::routing::{in_domains, parse_domains};
+
+/// TTL of the null answers, short so that unblocking takes effect quickly
+pub const SINKHOLE_TTL: u32 = 60;
+
+/// Default time between reloads of blocklists with remote lists
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Time before a failed reload is tried again
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest a download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest list downloaded; the big published lists are a few megabytes
+const MAX_LIST_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where a list of blocked domains comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlocklistSource {
+    File(PathBuf),
+    /// Downloaded over HTTP(S)
+    Url(String),
+}
+
+impl BlocklistSource {
+    pub fn is_remote(&self) -> bool {
+        matches!(self, BlocklistSource::Url(_))
+    }
+
+    fn read(&self) -> anyhow::Result<String> {
+        match self {
+            BlocklistSource::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("could not read blocklist {}", path.display())),
+            // ureq's errors name the URL
+            BlocklistSource::Url(url) => fetch(url).context("could not download blocklist"),
+        }
+    }
+}
+
+impl fmt::Display for BlocklistSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlocklistSource::File(path) => write!(f, "{}", path.display()),
+            BlocklistSource::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// `http://` and `https://` URLs are downloaded; anything else is a file
+impl FromStr for BlocklistSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let remote = ["http://", "https://"]
+            .iter()
+            .find_map(|scheme| s.strip_prefix(scheme));
+        match remote {
+            Some("") => Err(format!("blocklist URL '{}' has no host", s)),
+            Some(_) => Ok(BlocklistSource::Url(s.to_string())),
+            None if s.is_empty() => Err("empty blocklist path".into()),
+            None => Ok(BlocklistSource::File(PathBuf::from(s))),
+        }
+    }
+}
+
+/// Download a list, blocking until it's in
+fn fetch(url: &str) -> anyhow::Result<String> {
+    let agent = ureq::AgentBuilder::new().timeout(FETCH_TIMEOUT).build();
+    let response = agent.get(url).call()?;
+    let mut contents = String::new();
+    response
+        .into_reader()
+        .take(MAX_LIST_SIZE)
+        .read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// How blocked queries are answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum BlockMode {
+    /// NXDOMAIN
+    #[default]
+    Nxdomain,
+    /// 0.0.0.0 for A, :: for AAAA and no records for other types
+    Null,
+}
+
+/// What a blocked query gets
+#[derive(Debug)]
+pub enum Blocked {
+    NxDomain,
+    /// Answered with these records, possibly none
+    Answer(Vec<DnsResourceRecord>),
+}
+
+/// The blocked domains of a tenant
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    domains: HashSet<String>,
+    mode: BlockMode,
+}
+
+impl Blocklist {
+    /// Read the domains listed in `sources`, blocking while remote lists are
+    /// downloaded
+    pub fn load<'a>(
+        sources: impl IntoIterator<Item = &'a BlocklistSource>,
+        mode: BlockMode,
+    ) -> anyhow::Result<Blocklist> {
+        let mut domains = HashSet::new();
+        for source in sources {
+            domains.extend(parse_domains(&source.read()?));
+        }
+        Ok(Blocklist { domains, mode })
+    }
+
+    /// How many domains are blocked
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// The answer to a query for a blocked name, or None when it isn't blocked
+    pub fn check(&self, name: &str, qtype: RecordType, qclass: RecordClass) -> Option<Blocked> {
+        self.blocks(name).then(|| self.answer(name, qtype, qclass))
+    }
+
+    /// Whether a name, or a domain it is under, is listed
+    pub fn blocks(&self, name: &str) -> bool {
+        !self.domains.is_empty() && in_domains(&self.domains, name)
+    }
+
+    /// The answer to a query for a name blocked in this blocklist's mode
+    fn answer(&self, name: &str, qtype: RecordType, qclass: RecordClass) -> Blocked {
+        let rdata = match (self.mode, qtype) {
+            (BlockMode::Nxdomain, _) => return Blocked::NxDomain,
+            (BlockMode::Null, RecordType::A) => Ipv4Addr::UNSPECIFIED.octets().to_vec(),
+            (BlockMode::Null, RecordType::AAAA) => Ipv6Addr::UNSPECIFIED.octets().to_vec(),
+            (BlockMode::Null, _) => return Blocked::Answer(Vec::new()),
+        };
+        Blocked::Answer(vec![DnsResourceRecord::new(
+            name.to_string(),
+            qtype,
+            qclass,
+            SINKHOLE_TTL,
+            rdata,
+        )])
+    }
+}
+
+/// A name let through the blocklist: `example.com` for that name alone,
+/// `*.example.com` for everything under it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AllowRule {
+    domain: String,
+    wildcard: bool,
+}
+
+impl fmt::Display for AllowRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.wildcard {
+            write!(f, "*.")?;
+        }
+        write!(f, "{}", self.domain)
+    }
+}
+
+impl FromStr for AllowRule {
+    type Err = LocalRecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (domain, wildcard) = match s.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (s, false),
+        };
+        validate_name(domain)?;
+        Ok(AllowRule {
+            domain: normalize_name(domain),
+            wildcard,
+        })
+    }
+}
+
+/// Exceptions to the blocklist
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    exact: HashSet<String>,
+    wildcard: HashSet<String>,
+}
+
+impl Allowlist {
+    pub fn new(rules: &[AllowRule]) -> Self {
+        let mut allowlist = Allowlist::default();
+        for rule in rules {
+            allowlist.insert(rule.clone());
+        }
+        allowlist
+    }
+
+    fn insert(&mut self, rule: AllowRule) -> bool {
+        if rule.wildcard {
+            self.wildcard.insert(rule.domain)
+        } else {
+            self.exact.insert(rule.domain)
+        }
+    }
+
+    fn remove(&mut self, rule: &AllowRule) -> bool {
+        if rule.wildcard {
+            self.wildcard.remove(&rule.domain)
+        } else {
+            self.exact.remove(&rule.domain)
+        }
+    }
+
+    /// Whether a rule lets `name` through
+    pub fn allows(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+        self.exact.contains(&name)
+            || name
+                .split_once('.')
+                .is_some_and(|(_, parent)| in_domains(&self.wildcard, parent))
+    }
+
+    /// The rules, sorted
+    pub fn rules(&self) -> Vec<AllowRule> {
+        let exact = self.exact.iter().map(|domain| (domain, false));
+        let wildcard = self.wildcard.iter().map(|domain| (domain, true));
+        let mut rules: Vec<AllowRule> = exact
+            .chain(wildcard)
+            .map(|(domain, wildcard)| AllowRule {
+                domain: domain.clone(),
+                wildcard,
+            })
+            .collect();
+        rules.sort();
+        rules
+    }
+}
+
+/// Where the current blocklist and allowlist are published. The locks are
+/// only held to swap or clone an `Arc`, so queries never wait on a reload or
+/// an allowlist change.
+#[derive(Debug, Default)]
+pub struct PublishedBlocklist {
+    blocklist: RwLock<Arc<Blocklist>>,
+    allowlist: RwLock<Arc<Allowlist>>,
+    // Domains blocked on the control socket, kept across reloads
+    blocked: RwLock<Arc<HashSet<String>>>,
+    // Where the blocklist is reloaded from
+    sources: Vec<BlocklistSource>,
+    mode: BlockMode,
+}
+
+impl PublishedBlocklist {
+    pub fn new(blocklist: Blocklist, allowlist: Allowlist) -> Self {
+        Self {
+            mode: blocklist.mode,
+            blocklist: RwLock::new(Arc::new(blocklist)),
+            allowlist: RwLock::new(Arc::new(allowlist)),
+            blocked: RwLock::default(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// Reload the blocklist from `sources`, which it was loaded from, or
+    /// its files at least
+    pub fn with_sources(self, sources: Vec<BlocklistSource>) -> Self {
+        Self { sources, ..self }
+    }
+
+    /// Whether there are lists to reload
+    pub fn has_sources(&self) -> bool {
+        !self.sources.is_empty()
+    }
+
+    /// Read every list again, downloading the remote ones, and swap the new
+    /// blocklist in whole if all of them load; returns how many domains it
+    /// blocks. Queries go on being checked against the current one meanwhile.
+    pub async fn reload(&self) -> anyhow::Result<usize> {
+        let (sources, mode) = (self.sources.clone(), self.mode);
+        let blocklist =
+            tokio::task::spawn_blocking(move || Blocklist::load(sources.iter(), mode)).await??;
+        let domains = blocklist.len();
+        self.store(blocklist);
+        Ok(domains)
+    }
+
+    /// The answer to a query for a blocked name, or None when it isn't
+    /// blocked or the allowlist lets it through
+    pub fn check(&self, name: &str, qtype: RecordType, qclass: RecordClass) -> Option<Blocked> {
+        let blocklist = read(&self.blocklist);
+        let blocked = match blocklist.check(name, qtype, qclass) {
+            Some(blocked) => blocked,
+            None if self.is_blocked(name) => blocklist.answer(name, qtype, qclass),
+            None => return None,
+        };
+        (!read(&self.allowlist).allows(name)).then_some(blocked)
+    }
+
+    /// Whether the blocklists (leaving out the allowlist and the domains
+    /// blocked on the control socket) block a name
+    pub fn is_listed(&self, name: &str) -> bool {
+        read(&self.blocklist).blocks(name)
+    }
+
+    /// Whether a domain blocked on the control socket blocks a name
+    fn is_blocked(&self, name: &str) -> bool {
+        let blocked = read(&self.blocked);
+        !blocked.is_empty() && in_domains(&blocked, name)
+    }
+
+    /// The domains blocked on the control socket, sorted
+    pub fn blocked(&self) -> Vec<String> {
+        let mut domains: Vec<String> = read(&self.blocked).iter().cloned().collect();
+        domains.sort();
+        domains
+    }
+
+    /// Block a domain and everything under it, returning whether it's new
+    pub fn block(&self, domain: &str) -> bool {
+        self.change_blocked(|blocked| blocked.insert(normalize_name(domain)))
+    }
+
+    /// Lift a block made with `block`, returning whether there was one
+    pub fn unblock(&self, domain: &str) -> bool {
+        self.change_blocked(|blocked| blocked.remove(&normalize_name(domain)))
+    }
+
+    fn change_blocked(&self, change: impl FnOnce(&mut HashSet<String>) -> bool) -> bool {
+        // Held across the change so that concurrent changes aren't lost
+        let mut published = self.blocked.write().unwrap_or_else(PoisonError::into_inner);
+        let mut blocked = HashSet::clone(&published);
+        let changed = change(&mut blocked);
+        if changed {
+            *published = Arc::new(blocked);
+        }
+        changed
+    }
+
+    /// The current allowlist
+    pub fn allowlist(&self) -> Arc<Allowlist> {
+        read(&self.allowlist)
+    }
+
+    /// Add an allowlist rule, returning whether it's new
+    pub fn allow(&self, rule: AllowRule) -> bool {
+        self.change_allowlist(|allowlist| allowlist.insert(rule))
+    }
+
+    /// Remove an allowlist rule, returning whether it was there
+    pub fn disallow(&self, rule: &AllowRule) -> bool {
+        self.change_allowlist(|allowlist| allowlist.remove(rule))
+    }
+
+    fn change_allowlist(&self, change: impl FnOnce(&mut Allowlist) -> bool) -> bool {
+        // Held across the change so that concurrent changes aren't lost
+        let mut published = self
+            .allowlist
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut allowlist = Allowlist::clone(&published);
+        let changed = change(&mut allowlist);
+        if changed {
+            *published = Arc::new(allowlist);
+        }
+        changed
+    }
+
+    fn store(&self, blocklist: Blocklist) {
+        *self
+            .blocklist
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(blocklist);
+    }
+}
+
+/// Clone the published `Arc`. Swapping an `Arc` can't leave a list
+/// half-updated, so a poisoned lock is safe to use.
+fn read<T>(lock: &RwLock<Arc<T>>) -> Arc<T> {
+    Arc::clone(&lock.read().unwrap_or_else(PoisonError::into_inner))
+}
+
+/// Reload the blocklist from all its sources now and then every `interval`,
+/// publishing each one that loads in full
+pub async fn refresh_blocklist(interval: Duration, published: Arc<PublishedBlocklist>) {
+    let mut delay = Duration::ZERO;
+    loop {
+        tokio::time::sleep(delay).await;
+        delay = match published.reload().await {
+            Ok(domains) => {
+                info!("Blocklist reloaded ({} domains)", domains);
+                interval
+            }
+            Err(e) => {
+                warn!("Keeping the current blocklist: {:#}", e);
+                RETRY_INTERVAL.min(interval)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocklist(mode: BlockMode) -> Blocklist {
+        Blocklist {
+            domains: ["ads.example".to_string()].into(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn test_nxdomain_mode() {
+        let blocklist = blocklist(BlockMode::Nxdomain);
+        assert!(matches!(
+            blocklist.check("tracker.ADS.example.", RecordType::A, RecordClass::IN),
+            Some(Blocked::NxDomain)
+        ));
+        assert!(matches!(
+            blocklist.check("ads.example", RecordType::MX, RecordClass::IN),
+            Some(Blocked::NxDomain)
+        ));
+        assert!(blocklist
+            .check("notads.example", RecordType::A, RecordClass::IN)
+            .is_none());
+    }
+
+    #[test]
+    fn test_null_mode() {
+        let blocklist = blocklist(BlockMode::Null);
+        let Some(Blocked::Answer(records)) =
+            blocklist.check("ads.example", RecordType::AAAA, RecordClass::IN)
+        else {
+            panic!("expected a null answer");
+        };
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].rdata, vec![0; 16]);
+        assert_eq!(records[0].ttl, SINKHOLE_TTL);
+
+        assert!(matches!(
+            blocklist.check("ads.example", RecordType::TXT, RecordClass::IN),
+            Some(Blocked::Answer(records)) if records.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let published = PublishedBlocklist::new(
+            blocklist(BlockMode::Nxdomain),
+            Allowlist::new(&["cdn.ads.example".parse().unwrap()]),
+        );
+        let blocked = |name| {
+            published
+                .check(name, RecordType::A, RecordClass::IN)
+                .is_some()
+        };
+        assert!(!blocked("CDN.ads.example."));
+        assert!(blocked("x.cdn.ads.example"));
+        assert!(blocked("ads.example"));
+
+        let wildcard: AllowRule = "*.Tracker.ADS.example".parse().unwrap();
+        assert_eq!(wildcard.to_string(), "*.tracker.ads.example");
+        assert!(published.allow(wildcard.clone()));
+        assert!(!published.allow(wildcard.clone()));
+        assert!(!blocked("a.b.tracker.ads.example"));
+        assert!(blocked("tracker.ads.example"));
+        assert_eq!(
+            published.allowlist().rules(),
+            vec!["cdn.ads.example".parse().unwrap(), wildcard.clone()]
+        );
+
+        assert!(published.disallow(&wildcard));
+        assert!(blocked("a.b.tracker.ads.example"));
+        assert!("*.".parse::<AllowRule>().is_err());
+    }
+
+    #[test]
+    fn test_runtime_blocks() {
+        let published = PublishedBlocklist::new(blocklist(BlockMode::Null), Allowlist::default());
+        let check = |name| published.check(name, RecordType::A, RecordClass::IN);
+        assert!(check("social.example").is_none());
+
+        assert!(published.block("Social.Example."));
+        assert!(!published.block("social.example"));
+        // Answered in the blocklist's mode, along with everything under it
+        assert!(matches!(
+            check("www.social.example"),
+            Some(Blocked::Answer(records)) if records[0].rdata == [0; 4]
+        ));
+        assert_eq!(published.blocked(), ["social.example"]);
+        assert!(!published.is_listed("social.example"));
+
+        // Kept when the lists are reloaded, and the allowlist still wins
+        published.store(blocklist(BlockMode::Null));
+        assert!(check("social.example").is_some());
+        published.allow("cdn.social.example".parse().unwrap());
+        assert!(check("cdn.social.example").is_none());
+
+        assert!(published.unblock("social.example"));
+        assert!(!published.unblock("social.example"));
+        assert!(check("social.example").is_none());
+        // Listed domains aren't lifted by unblock
+        assert!(!published.unblock("ads.example"));
+        assert!(published.is_listed("ads.example"));
+    }
+
+    #[test]
+    fn test_remote_list() {
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ads.txt", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            let body = "# ads\n0.0.0.0 ads.example\ntracker.example\n";
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        let source: BlocklistSource = url.parse().unwrap();
+        assert!(source.is_remote());
+        assert_eq!(source.to_string(), url);
+        let blocklist = Blocklist::load([&source], BlockMode::Nxdomain).unwrap();
+        server.join().unwrap();
+        assert_eq!(blocklist.len(), 2);
+        assert!(blocklist
+            .check("x.tracker.example", RecordType::A, RecordClass::IN)
+            .is_some());
+
+        assert_eq!(
+            "/etc/dns/ads.txt".parse::<BlocklistSource>(),
+            Ok(BlocklistSource::File("/etc/dns/ads.txt".into()))
+        );
+        assert!("https://".parse::<BlocklistSource>().is_err());
+    }
+}
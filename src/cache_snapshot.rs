@@ -0,0 +1,331 @@
+//! Keeping the cache across restarts (`--cache-snapshot`)
+//!
+//! The default tenant's cache is written to a file every
+//! `--cache-snapshot-interval` seconds and when the server is stopped with
+//! SIGINT or SIGTERM, and read back when it starts. The file records when it
+//! was written, so the TTLs of its answers are counted down by the time the
+//! server was away, and answers that expired meanwhile are left out. A
+//! restarted server thus answers its clients' usual names from the cache
+//! instead of sending them all upstream at once.
+//!
+//! The file is JSON: the time it was written, in seconds since the Unix
+//! epoch, and each answer with its question, route and records, RDATA in
+//! base64. It is written to a temporary file first and moved into place, so
+//! a crash while writing leaves the last snapshot intact.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info};
+
+use crate::actors::messages::{CacheKey, Resolution};
+use crate::handlers::cache_handler::CacheHandle;
+use crate::protocol::DnsResourceRecord;
+
+/// Seconds between snapshots unless `--cache-snapshot-interval` says otherwise
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 300;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    /// When it was written, in seconds since the Unix epoch
+    saved: u64,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    route: Option<String>,
+    #[serde(flatten)]
+    answer: Answer,
+}
+
+/// A cached answer in the form it is stored in, in a snapshot or a shared
+/// cache (see `shared_cache.rs`)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "answer", rename_all = "lowercase")]
+pub enum Answer {
+    Records { records: Vec<Record> },
+    Negative { rcode: u16, soa: Record },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    name: String,
+    rtype: u16,
+    rclass: u16,
+    ttl: u32,
+    rdata: String,
+}
+
+impl From<&DnsResourceRecord> for Record {
+    fn from(record: &DnsResourceRecord) -> Self {
+        Record {
+            name: record.name.clone(),
+            rtype: record.rtype.into(),
+            rclass: record.rclass.into(),
+            ttl: record.ttl,
+            rdata: BASE64.encode(&record.rdata),
+        }
+    }
+}
+
+impl Record {
+    /// The record, its TTL counted down by `elapsed` seconds; none once it
+    /// has run out
+    fn restore(&self, elapsed: u64) -> anyhow::Result<Option<DnsResourceRecord>> {
+        let ttl = u64::from(self.ttl).saturating_sub(elapsed);
+        if ttl == 0 {
+            return Ok(None);
+        }
+        let rdata = BASE64
+            .decode(&self.rdata)
+            .with_context(|| format!("invalid RDATA for {}", self.name))?;
+        Ok(Some(DnsResourceRecord::new(
+            self.name.clone(),
+            self.rtype.into(),
+            self.rclass.into(),
+            ttl as u32,
+            rdata,
+        )))
+    }
+}
+
+impl Answer {
+    /// A cached answer as stored; failures aren't cached, and neither are
+    /// negative answers without an SOA
+    pub fn new(resolution: &Resolution) -> Option<Answer> {
+        Some(match resolution {
+            Resolution::Records(records) => Answer::Records {
+                records: records.iter().map(Record::from).collect(),
+            },
+            Resolution::Negative {
+                rcode,
+                soa: Some(soa),
+            } => Answer::Negative {
+                rcode: (*rcode).into(),
+                soa: soa.into(),
+            },
+            Resolution::Negative { soa: None, .. } | Resolution::Failed(_) => return None,
+        })
+    }
+
+    /// The answer `elapsed` seconds after it was stored; none when any of
+    /// its records has expired since, as the cache would have dropped it
+    pub fn restore(&self, elapsed: u64) -> anyhow::Result<Option<Resolution>> {
+        Ok(Some(match self {
+            Answer::Records { records } => {
+                let mut restored = Vec::with_capacity(records.len());
+                for record in records {
+                    match record.restore(elapsed)? {
+                        Some(record) => restored.push(record),
+                        None => return Ok(None),
+                    }
+                }
+                Resolution::Records(restored)
+            }
+            Answer::Negative { rcode, soa } => match soa.restore(elapsed)? {
+                Some(soa) => Resolution::Negative {
+                    rcode: (*rcode).into(),
+                    soa: Some(soa),
+                },
+                None => return Ok(None),
+            },
+        }))
+    }
+}
+
+impl Entry {
+    fn new(key: &CacheKey, resolution: &Resolution) -> Option<Entry> {
+        Some(Entry {
+            name: key.name.to_string(),
+            qtype: key.qtype.into(),
+            qclass: key.qclass.into(),
+            route: key.route.as_deref().map(str::to_string),
+            answer: Answer::new(resolution)?,
+        })
+    }
+
+    /// The answer `elapsed` seconds after it was written, with its question
+    fn restore(&self, elapsed: u64) -> anyhow::Result<Option<(CacheKey, Resolution)>> {
+        let Some(resolution) = self.answer.restore(elapsed)? else {
+            return Ok(None);
+        };
+        let key = CacheKey {
+            name: Arc::from(self.name.as_str()),
+            qtype: self.qtype.into(),
+            qclass: self.qclass.into(),
+            route: self.route.as_deref().map(Arc::from),
+        };
+        Ok(Some((key, resolution)))
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Write what `cache` holds to `path`, returning how many answers
+pub async fn save(path: &Path, cache: &CacheHandle) -> anyhow::Result<usize> {
+    let entries: Vec<Entry> = cache
+        .snapshot()
+        .iter()
+        .filter_map(|(key, resolution)| Entry::new(key, resolution))
+        .collect();
+    let count = entries.len();
+    let snapshot = Snapshot {
+        saved: unix_now(),
+        entries,
+    };
+    let json = serde_json::to_vec(&snapshot)?;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    let partial = PathBuf::from(partial);
+    tokio::fs::write(&partial, json)
+        .await
+        .with_context(|| format!("could not write {}", partial.display()))?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .with_context(|| format!("could not replace {}", path.display()))?;
+    Ok(count)
+}
+
+/// Fill `cache` from the snapshot at `path`, if there is one, returning how
+/// many answers were still good
+pub async fn load(path: &Path, cache: &CacheHandle) -> anyhow::Result<usize> {
+    let json = match tokio::fs::read(path).await {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("could not read {}", path.display())),
+    };
+    let snapshot: Snapshot = serde_json::from_slice(&json)
+        .with_context(|| format!("invalid cache snapshot {}", path.display()))?;
+    let elapsed = unix_now().saturating_sub(snapshot.saved);
+    let mut restored = 0;
+    for entry in &snapshot.entries {
+        if let Some((key, resolution)) = entry.restore(elapsed)? {
+            cache.insert(key, resolution).await;
+            restored += 1;
+        }
+    }
+    Ok(restored)
+}
+
+/// Write a snapshot of `cache` to `path` every `interval`
+pub async fn run_snapshots(path: PathBuf, interval: Duration, cache: CacheHandle) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is immediate, and the cache was only just loaded
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        match save(&path, &cache).await {
+            Ok(count) => debug!("Cache snapshot written ({} answers)", count),
+            Err(e) => error!("Could not write the cache snapshot: {:#}", e),
+        }
+    }
+}
+
+/// Fill `cache` from `path`, logging rather than failing on a bad snapshot:
+/// the server runs fine without one
+pub async fn restore(path: &Path, cache: &CacheHandle) {
+    match load(path, cache).await {
+        Ok(0) => {}
+        Ok(count) => info!("Restored {} cached answers from {}", count, path.display()),
+        Err(e) => error!("Could not restore the cache snapshot: {:#}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::cache_handler::CacheConfig;
+    use crate::metrics::Metrics;
+    use crate::registry::{Rcode, RecordClass, RecordType};
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey {
+            name: Arc::from(name),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+            route: Some(Arc::from("lan")),
+        }
+    }
+
+    fn record(name: &str, ttl: u32) -> DnsResourceRecord {
+        DnsResourceRecord::new(
+            name.to_string(),
+            RecordType::A,
+            RecordClass::IN,
+            ttl,
+            vec![192, 0, 2, 1],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_survives_restart() {
+        let path = std::env::temp_dir().join(format!("dns-cache-{}.json", std::process::id()));
+        let cache = CacheHandle::new(CacheConfig::default(), Arc::new(Metrics::new(1)));
+        cache
+            .insert(
+                key("short.example"),
+                Resolution::Records(vec![record("short.example", 5)]),
+            )
+            .await;
+        cache
+            .insert(
+                key("long.example"),
+                Resolution::Records(vec![record("long.example", 300)]),
+            )
+            .await;
+        cache
+            .insert(
+                key("missing.example"),
+                Resolution::Negative {
+                    rcode: Rcode::NXDOMAIN,
+                    soa: Some(record("example", 60)),
+                },
+            )
+            .await;
+        assert_eq!(save(&path, &cache).await.unwrap(), 3);
+
+        // As if the server had been away for 30 seconds
+        let json = std::fs::read(&path).unwrap();
+        let mut snapshot: Snapshot = serde_json::from_slice(&json).unwrap();
+        snapshot.saved -= 30;
+        std::fs::write(&path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let restarted = CacheHandle::new(CacheConfig::default(), Arc::new(Metrics::new(1)));
+        assert_eq!(load(&path, &restarted).await.unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        let Some(cached) = restarted.get(key("long.example")).await else {
+            panic!("expected the answer to be restored");
+        };
+        let Resolution::Records(records) = cached.resolution else {
+            panic!("expected records");
+        };
+        assert!((265..=270).contains(&records[0].ttl));
+        assert_eq!(records[0].rdata, [192, 0, 2, 1]);
+        assert!(matches!(
+            restarted.get(key("missing.example")).await,
+            Some(cached) if matches!(cached.resolution, Resolution::Negative { rcode: Rcode::NXDOMAIN, .. })
+        ));
+        assert!(restarted.get(key("short.example")).await.is_none());
+
+        // No snapshot yet is no error
+        assert_eq!(load(&path, &restarted).await.unwrap(), 0);
+    }
+}
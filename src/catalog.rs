@@ -0,0 +1,461 @@
+//! Catalog zones (RFC 9432)
+//!
+//! A catalog zone lists the member zones a primary wants its secondaries to
+//! serve: each member is a PTR record `<id>.zones.<catalog>` pointing at the
+//! member's name. With `--catalog <catalog>@<primary>` the server transfers
+//! the catalog (AXFR over TCP) and then every member zone from the same
+//! primary, loading their records as local records. Zones added to the
+//! catalog are picked up at the next refresh and zones removed from it are
+//! dropped, so no configuration change is needed on this side.
+//!
+//! The catalog's SOA refresh and retry timers drive the polling: every
+//! refresh the SOA serials of the catalog and its members are checked, and a
+//! zone is transferred again when its serial changed. Only record types local
+//! records can hold (A, AAAA, CNAME, MX, TXT, PTR) are served; NOTIFY and
+//! zone expiry are not implemented, so the last good copy of a zone is served
+//! until the primary can be reached again. A member zone's transfer replaces
+//! every local record at or below its name. Dynamic updates for member zones
+//! are forwarded to the primary (see `update.rs`).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::Encoder;
+use tracing::{info, warn};
+
+use crate::codec::DnsCodec;
+use crate::errors::ZoneTransferError;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::local_records::{normalize_name, LocalRecord, RecordData};
+use crate::parsers::parse_dns_packet;
+use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion, DnsResourceRecord};
+use crate::registry::{Opcode, Rcode, RecordClass, RecordType};
+use crate::response_builder::SoaRecord;
+use crate::tcp::{read_message, write_message};
+use crate::update::Primaries;
+
+/// Longest a single SOA query or zone transfer may take
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bounds on the SOA timers, so a zone can neither hammer the primary nor be
+/// forgotten for weeks
+const MIN_REFRESH: Duration = Duration::from_secs(10);
+const MAX_REFRESH: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Schema version of RFC 9432 catalogs
+const CATALOG_VERSION: &str = "2";
+
+/// A catalog zone and the primary serving it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogSource {
+    pub zone: String,
+    pub primary: SocketAddr,
+}
+
+impl fmt::Display for CatalogSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.zone, self.primary)
+    }
+}
+
+/// Parses `<catalog zone>@<ip>[:<port>]`; the port defaults to 53
+impl FromStr for CatalogSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected <catalog zone>@<ip>[:<port>], got '{}'", s);
+
+        let (zone, primary) = s.split_once('@').ok_or_else(invalid)?;
+        let zone = normalize_name(zone);
+        if zone.is_empty() || zone.split('.').any(str::is_empty) {
+            return Err(invalid());
+        }
+        let primary = match primary.parse::<SocketAddr>() {
+            Ok(primary) => primary,
+            Err(_) => SocketAddr::new(primary.parse::<IpAddr>().map_err(|_| invalid())?, 53),
+        };
+        Ok(Self { zone, primary })
+    }
+}
+
+/// Keep the member zones of a catalog provisioned, forever
+pub async fn run_catalog(
+    source: CatalogSource,
+    local_records: LocalRecordsHandle,
+    primaries: Arc<Primaries>,
+) {
+    let mut consumer = CatalogConsumer {
+        source,
+        local_records,
+        primaries,
+        serial: None,
+        retry: MIN_REFRESH,
+        members: BTreeMap::new(),
+    };
+
+    loop {
+        let wait = match consumer.refresh().await {
+            Ok(refresh) => refresh,
+            Err(e) => {
+                warn!("Catalog {}: {}", consumer.source, e);
+                consumer.retry
+            }
+        };
+        sleep(wait).await;
+    }
+}
+
+/// What a secondary knows about one catalog
+struct CatalogConsumer {
+    source: CatalogSource,
+    local_records: LocalRecordsHandle,
+    // Where the member zones' updates are forwarded
+    primaries: Arc<Primaries>,
+    // Serial of the catalog's last transfer
+    serial: Option<u32>,
+    // How soon to try again after a failure, from the catalog's SOA
+    retry: Duration,
+    // Member zones by name, with the serial of their last transfer
+    members: BTreeMap<String, Option<u32>>,
+}
+
+impl CatalogConsumer {
+    /// Bring the member list and every member zone up to date, returning the
+    /// time until the next refresh
+    async fn refresh(&mut self) -> Result<Duration, ZoneTransferError> {
+        let primary = self.source.primary;
+        let soa = query_soa(primary, &self.source.zone).await?;
+        self.retry = soa_timer(soa.retry);
+
+        if self.serial != Some(soa.serial) {
+            let records = transfer(primary, &self.source.zone).await?;
+            let members = catalog_members(&self.source.zone, &records)?;
+
+            let removed: Vec<String> = self
+                .members
+                .keys()
+                .filter(|zone| !members.contains(zone))
+                .cloned()
+                .collect();
+            for zone in removed {
+                let diff = self
+                    .local_records
+                    .replace_zone(
+                        zone.clone(),
+                        Vec::new(),
+                        format!("removal of {} from catalog {}", zone, self.source.zone),
+                    )
+                    .await;
+                info!(
+                    "Catalog {}: member zone {} removed ({} records dropped)",
+                    self.source,
+                    zone,
+                    diff.removed.len()
+                );
+                self.primaries.forget(&zone);
+                self.members.remove(&zone);
+            }
+            for zone in members {
+                if !self.members.contains_key(&zone) {
+                    info!("Catalog {}: new member zone {}", self.source, zone);
+                    self.primaries.learn(&zone, primary);
+                    self.members.insert(zone, None);
+                }
+            }
+            self.serial = Some(soa.serial);
+        }
+
+        for (zone, serial) in self.members.iter_mut() {
+            if let Err(e) = refresh_member(primary, zone, serial, &self.local_records).await {
+                warn!("Catalog {}: member zone {}: {}", self.source, zone, e);
+            }
+        }
+
+        Ok(soa_timer(soa.refresh))
+    }
+}
+
+/// Transfer a member zone again if its serial changed, and serve the result
+async fn refresh_member(
+    primary: SocketAddr,
+    zone: &str,
+    serial: &mut Option<u32>,
+    local_records: &LocalRecordsHandle,
+) -> Result<(), ZoneTransferError> {
+    let soa = query_soa(primary, zone).await?;
+    if *serial == Some(soa.serial) {
+        return Ok(());
+    }
+
+    let records = transfer(primary, zone).await?;
+    // Leave out the SOA that opens and closes the transfer
+    let records = &records[1..records.len() - 1];
+    let total = records.len();
+    let local: Vec<LocalRecord> = records
+        .iter()
+        .filter_map(|record| LocalRecord::from_resource_record(record).ok())
+        .collect();
+    let skipped = total - local.len();
+
+    let diff = local_records
+        .replace_zone(zone.to_string(), local, format!("transfer of {}", zone))
+        .await;
+    info!(
+        "Transferred {} (serial {}) from {}: {} added, {} removed, {} unchanged, {} not servable as local records",
+        zone,
+        soa.serial,
+        primary,
+        diff.added.len(),
+        diff.removed.len(),
+        diff.unchanged,
+        skipped
+    );
+    *serial = Some(soa.serial);
+    Ok(())
+}
+
+/// Member zones listed in a transferred catalog, in order of their IDs
+pub fn catalog_members(
+    catalog: &str,
+    records: &[DnsResourceRecord],
+) -> Result<Vec<String>, ZoneTransferError> {
+    let catalog = normalize_name(catalog);
+    let version_name = format!("version.{}", catalog);
+    let zones_suffix = format!(".zones.{}", catalog);
+
+    let mut version = None;
+    let mut by_id: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for record in records {
+        let name = normalize_name(&record.name);
+        match LocalRecord::from_resource_record(record).map(|record| record.data) {
+            Ok(RecordData::Txt(text)) if name == version_name => version = Some(text),
+            Ok(RecordData::Ptr(member)) => {
+                // Properties live below the member ID (e.g. group.<id>.zones);
+                // only the ID's own PTR names a member
+                if let Some(id) = name.strip_suffix(&zones_suffix) {
+                    if !id.contains('.') {
+                        by_id.entry(id.to_string()).or_default().push(member);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match version.as_deref() {
+        Some(CATALOG_VERSION) => {}
+        Some(other) => {
+            return Err(ZoneTransferError::InvalidCatalog(format!(
+                "unsupported schema version {}",
+                other
+            )))
+        }
+        None => {
+            return Err(ZoneTransferError::InvalidCatalog(format!(
+                "no {} TXT record",
+                version_name
+            )))
+        }
+    }
+
+    let mut members = Vec::new();
+    for (id, zones) in by_id {
+        // A member ID must have exactly one PTR record (RFC 9432 §4.1)
+        match &zones[..] {
+            [zone] if zone.is_empty() || *zone == catalog => {}
+            [zone] => {
+                if !members.contains(zone) {
+                    members.push(zone.clone());
+                }
+            }
+            _ => warn!(
+                "Catalog {}: ignoring member ID {} with {} PTR records",
+                catalog,
+                id,
+                zones.len()
+            ),
+        }
+    }
+    Ok(members)
+}
+
+/// The SOA record of a zone, straight from the primary
+async fn query_soa(primary: SocketAddr, zone: &str) -> Result<SoaRecord, ZoneTransferError> {
+    query(primary, zone, RecordType::SOA)
+        .await?
+        .iter()
+        .find(|record| normalize_name(&record.name) == zone)
+        .and_then(SoaRecord::from_resource_record)
+        .ok_or_else(|| ZoneTransferError::MissingSoa(zone.to_string()))
+}
+
+/// Every record of a zone, by AXFR. The SOA comes first and last.
+async fn transfer(
+    primary: SocketAddr,
+    zone: &str,
+) -> Result<Vec<DnsResourceRecord>, ZoneTransferError> {
+    let records = query(primary, zone, RecordType::AXFR).await?;
+    if records.first().map(|record| record.rtype) != Some(RecordType::SOA) {
+        return Err(ZoneTransferError::MissingSoa(zone.to_string()));
+    }
+    if records.len() < 2 || records.last().map(|record| record.rtype) != Some(RecordType::SOA) {
+        return Err(ZoneTransferError::Malformed(
+            "transfer ended before the closing SOA".to_string(),
+        ));
+    }
+    Ok(records)
+}
+
+/// Ask the primary over TCP, returning the answer records. An AXFR answer
+/// may span several messages and ends with the zone's SOA.
+async fn query(
+    primary: SocketAddr,
+    zone: &str,
+    qtype: RecordType,
+) -> Result<Vec<DnsResourceRecord>, ZoneTransferError> {
+    timeout(TRANSFER_TIMEOUT, async {
+        let mut stream = TcpStream::connect(primary).await?;
+        let id: u16 = rand::random();
+
+        let mut message = BytesMut::new();
+        DnsCodec::new()
+            .encode(query_packet(id, zone, qtype), &mut message)
+            .map_err(|e| ZoneTransferError::Malformed(e.to_string()))?;
+        write_message(&mut stream, &message).await?;
+
+        let mut records = Vec::new();
+        loop {
+            let message = read_message(&mut stream).await?;
+            let (_, packet) = parse_dns_packet(&message).map_err(|_| {
+                ZoneTransferError::Malformed(format!("unparseable {}-byte message", message.len()))
+            })?;
+            if packet.header.id != id {
+                return Err(ZoneTransferError::Malformed(format!(
+                    "answer to query {} instead of {}",
+                    packet.header.id, id
+                )));
+            }
+            if packet.header.rcode != Rcode::NOERROR {
+                return Err(ZoneTransferError::Rcode(packet.header.rcode));
+            }
+            if packet.answers.is_empty() {
+                break;
+            }
+            records.extend(packet.answers);
+
+            let complete = records.len() > 1
+                && records.last().map(|record| record.rtype) == Some(RecordType::SOA);
+            if qtype != RecordType::AXFR || complete {
+                break;
+            }
+        }
+        Ok(records)
+    })
+    .await
+    .map_err(|_| ZoneTransferError::Timeout)?
+}
+
+/// A non-recursive query for `zone`
+fn query_packet(id: u16, zone: &str, qtype: RecordType) -> DnsPacket {
+    DnsPacket {
+        header: DnsPacketHeader {
+            id,
+            qr: false,
+            opcode: Opcode::QUERY,
+            aa: false,
+            tc: false,
+            rd: false,
+            ra: false,
+            z: 0,
+            rcode: Rcode::NOERROR,
+            qdcount: 1,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        },
+        questions: vec![DnsQuestion {
+            name: zone.to_string(),
+            qtype,
+            qclass: RecordClass::IN,
+        }],
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+    }
+}
+
+/// An SOA timer in seconds, within sensible bounds
+fn soa_timer(seconds: u32) -> Duration {
+    Duration::from_secs(seconds.into()).clamp(MIN_REFRESH, MAX_REFRESH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(line: &str) -> DnsResourceRecord {
+        line.parse::<LocalRecord>().unwrap().to_resource_record()
+    }
+
+    #[test]
+    fn test_catalog_members() {
+        let mut records = vec![
+            record("version.catalog.example TXT 2"),
+            record("a1.zones.catalog.example PTR example.com"),
+            record("b2.zones.catalog.example PTR Example.NET."),
+            // A property, not a member
+            record("group.a1.zones.catalog.example PTR other.org"),
+            // Two PTR records for one ID make the member invalid
+            record("c3.zones.catalog.example PTR one.org"),
+            record("c3.zones.catalog.example PTR two.org"),
+        ];
+        assert_eq!(
+            catalog_members("catalog.example.", &records).unwrap(),
+            ["example.com", "example.net"]
+        );
+
+        records[0] = record("version.catalog.example TXT 1");
+        assert!(matches!(
+            catalog_members("catalog.example", &records),
+            Err(ZoneTransferError::InvalidCatalog(_))
+        ));
+        assert!(catalog_members("catalog.example", &records[1..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_catalog_source() {
+        let source: CatalogSource = "Catalog.Example.@192.0.2.1".parse().unwrap();
+        assert_eq!(source.zone, "catalog.example");
+        assert_eq!(source.primary, "192.0.2.1:53".parse().unwrap());
+        assert_eq!(source.to_string(), "catalog.example@192.0.2.1:53");
+        assert_eq!(
+            "catalog.example@[2001:db8::1]:5353"
+                .parse::<CatalogSource>()
+                .unwrap()
+                .primary,
+            "[2001:db8::1]:5353".parse().unwrap()
+        );
+
+        for invalid in [
+            "catalog.example",
+            "@192.0.2.1",
+            "catalog..example@192.0.2.1",
+        ] {
+            assert!(invalid.parse::<CatalogSource>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_soa_timer() {
+        assert_eq!(soa_timer(3600), Duration::from_secs(3600));
+        assert_eq!(soa_timer(0), MIN_REFRESH);
+        assert_eq!(soa_timer(u32::MAX), MAX_REFRESH);
+    }
+}
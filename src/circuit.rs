@@ -0,0 +1,187 @@
+//! Circuit breaking for upstreams
+//!
+//! Each upstream of a group keeps the outcomes of its recent lookups and
+//! health probes. When at least half of the last few have failed, by error or
+//! by timeout, its circuit opens: the upstream is taken out of rotation and
+//! the rest of its group answers instead. Once the backoff is over the next
+//! probe decides; an answer closes the circuit again and puts the upstream
+//! back, a failure keeps it open for twice as long, up to a limit.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Recent outcomes kept per upstream
+const WINDOW: usize = 20;
+/// Outcomes needed before the failure rate counts for anything
+const MIN_OUTCOMES: usize = 5;
+/// How long an upstream is first kept out of rotation
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+/// The longest it is kept out at a time
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How a circuit changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Out of rotation for the backoff, `failures` of the last `outcomes`
+    /// having failed
+    Opened {
+        failures: usize,
+        outcomes: usize,
+        backoff: Duration,
+    },
+    /// Kept out for another, longer backoff after a failed probe
+    Extended { backoff: Duration },
+    /// Back in rotation
+    Closed,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Recent outcomes, true for an answer; only kept while closed
+    outcomes: VecDeque<bool>,
+    /// Until when the circuit is open
+    open_until: Option<Instant>,
+    /// How long it is opened for next
+    backoff: Duration,
+}
+
+/// One upstream's circuit
+#[derive(Debug)]
+pub struct Breaker {
+    state: Mutex<State>,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker {
+            state: Mutex::new(State {
+                outcomes: VecDeque::with_capacity(WINDOW),
+                open_until: None,
+                backoff: INITIAL_BACKOFF,
+            }),
+        }
+    }
+}
+
+impl Breaker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether the upstream is in rotation
+    pub fn is_closed(&self) -> bool {
+        self.state().open_until.is_none()
+    }
+
+    /// Whether the upstream is due a probe: always while in rotation, and
+    /// once its backoff is over while out
+    pub fn probe_due(&self, now: Instant) -> bool {
+        !matches!(self.state().open_until, Some(until) if now < until)
+    }
+
+    /// Count a lookup's outcome. Lookups still under way when the circuit
+    /// opened don't count.
+    pub fn record(&self, answered: bool, now: Instant) -> Option<Change> {
+        let mut state = self.state();
+        if state.open_until.is_some() {
+            return None;
+        }
+        if state.outcomes.len() == WINDOW {
+            state.outcomes.pop_front();
+        }
+        state.outcomes.push_back(answered);
+        let outcomes = state.outcomes.len();
+        let failures = state.outcomes.iter().filter(|&&answered| !answered).count();
+        if outcomes < MIN_OUTCOMES || failures * 2 < outcomes {
+            return None;
+        }
+        state.outcomes.clear();
+        let backoff = state.backoff;
+        state.open_until = Some(now + backoff);
+        Some(Change::Opened {
+            failures,
+            outcomes,
+            backoff,
+        })
+    }
+
+    /// Count a probe's outcome: like a lookup's while in rotation; once the
+    /// backoff is over, an answer closes the circuit and a failure opens it
+    /// again for longer
+    pub fn probed(&self, answered: bool, now: Instant) -> Option<Change> {
+        let mut state = self.state();
+        match state.open_until {
+            None => {
+                drop(state);
+                self.record(answered, now)
+            }
+            Some(until) if now < until => None,
+            Some(_) if answered => {
+                state.open_until = None;
+                state.backoff = INITIAL_BACKOFF;
+                Some(Change::Closed)
+            }
+            Some(_) => {
+                state.backoff = (state.backoff * 2).min(MAX_BACKOFF);
+                let backoff = state.backoff;
+                state.open_until = Some(now + backoff);
+                Some(Change::Extended { backoff })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failing_upstream_is_taken_out() {
+        let breaker = Breaker::new();
+        let now = Instant::now();
+
+        // Occasional failures are tolerated
+        for answered in [true, false, true, true, false, true, true] {
+            assert_eq!(breaker.record(answered, now), None);
+        }
+        assert!(breaker.is_closed());
+
+        // Until half the recent outcomes are failures
+        for _ in 0..2 {
+            assert_eq!(breaker.record(false, now), None);
+        }
+        assert_eq!(
+            breaker.record(false, now),
+            Some(Change::Opened {
+                failures: 5,
+                outcomes: 10,
+                backoff: INITIAL_BACKOFF
+            })
+        );
+        assert!(!breaker.is_closed());
+        assert_eq!(breaker.record(false, now), None);
+        assert!(!breaker.probe_due(now));
+
+        // A failed probe after the backoff keeps it out for longer
+        let later = now + INITIAL_BACKOFF;
+        assert!(breaker.probe_due(later));
+        assert_eq!(
+            breaker.probed(false, later),
+            Some(Change::Extended {
+                backoff: INITIAL_BACKOFF * 2
+            })
+        );
+        assert!(!breaker.probe_due(later + INITIAL_BACKOFF));
+
+        // An answer puts it back
+        let recovered = later + INITIAL_BACKOFF * 2;
+        assert_eq!(breaker.probed(true, recovered), Some(Change::Closed));
+        assert!(breaker.is_closed());
+        assert_eq!(breaker.record(false, recovered), None);
+    }
+}
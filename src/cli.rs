@@ -1,13 +1,560 @@
-use clap::Parser;
-use std::net::SocketAddr;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::Level;
+
+use crate::acl::DenyAction;
+use crate::blocklist::{AllowRule, BlockMode, BlocklistSource, DEFAULT_REFRESH_INTERVAL};
+use crate::cache_snapshot::DEFAULT_SNAPSHOT_INTERVAL;
+use crate::catalog::CatalogSource;
+use crate::config;
+use crate::control_tls;
+use crate::geoip::{SiteRule, Subnet};
+use crate::groups::{ClientName, Group};
+use crate::handlers::cache_handler::{
+    CacheConfig, Eviction, Prefetch, DEFAULT_CACHE_SIZE, DEFAULT_PREFETCH_HITS,
+    DEFAULT_PREFETCH_WINDOW,
+};
+use crate::handlers::query_handler::DEFAULT_RESOLVER_WORKERS;
+use crate::health::{DEFAULT_CHECK_INTERVAL, DEFAULT_DAMPING};
+use crate::hedge::Hedge;
+use crate::hostname::HostnamePolicy;
+use crate::identity::DdrEndpoint;
+use crate::local_records::{LocalRecord, RecordFormat};
+use crate::query_log::{QueryLogFormat, DEFAULT_RETENTION_DAYS};
+use crate::quota::Quota;
+use crate::retry::UpstreamPolicy;
+use crate::reverse::ReverseZone;
+use crate::routing::RouteRule;
+use crate::rrset_order::RrsetOrder;
+use crate::service::ServiceKind;
+use crate::tenant::{Tenant, DEFAULT_LISTEN_ADDR, DEFAULT_TENANT};
+use crate::ttl::TtlLimits;
+use crate::tunnel::Webhook;
+use crate::update::UpdateForward;
+use crate::upstream::Upstream;
+use crate::view::View;
+use crate::zone_file::ZoneFile;
+
+/// Default address of the control socket, used by `ctl` when none is given
+pub const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:2054";
 
 #[derive(Parser, Debug)]
 #[command(name = "rust-dns")]
 #[command(about = "A DNS server written in Rust", long_about = None)]
 pub struct Args {
-    /// Resolver, where <address> will be of the form <ip>:<port>
-    #[arg(short, long, value_parser = parse_socket_addr)]
-    pub resolver: Option<SocketAddr>,
+    /// Read options from a TOML file, keyed by their long names (e.g. cache-size = 50000);
+    /// options also given on the command line take the command line's values
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Resolver, as <ip>:<port>, tried in the order given; repeatable. Append
+    /// @<source ip> or @<interface> (Linux) to send its queries from that address
+    /// or through that interface. Defaults to the name servers configured in the
+    /// operating system
+    #[arg(short, long = "resolver", value_name = "ADDRESS")]
+    pub resolvers: Vec<Upstream>,
+
+    /// Race the first resolvers: each is asked after the one before has had
+    /// <STAGGER> milliseconds to answer, and the first answer wins. Given as
+    /// <STAGGER>[/<RESOLVERS>]; two resolvers race unless given
+    #[arg(long, value_name = "STAGGER")]
+    pub hedge: Option<Hedge>,
+
+    /// Timeouts and retries for upstream lookups, as comma-separated settings:
+    /// timeout=<ms>, connect-timeout=<ms>, attempts=<n>, backoff=<ms> and
+    /// retry-on=timeout|servfail|timeout+servfail|none. Applies to every
+    /// upstream unless upstream=<ip>:<port> is given; repeatable
+    #[arg(long = "upstream-policy", value_name = "POLICY")]
+    pub upstream_policies: Vec<UpstreamPolicy>,
+
+    /// Don't read the OS resolver configuration; forward to 8.8.8.8:53 unless --resolver is given
+    #[arg(long)]
+    pub no_system_resolvers: bool,
+
+    /// Send query names upstream as they are, instead of with their letters in random case
+    /// (0x20), for upstreams that don't echo the case of the question
+    #[arg(long)]
+    pub no_case_randomization: bool,
+
+    /// Resolve names from the root servers down, following referrals, instead of
+    /// forwarding them to resolvers (default tenant only)
+    #[arg(long, conflicts_with_all = ["resolvers", "transparent"])]
+    pub recursive: bool,
+
+    /// Address the default tenant receives queries on over UDP and TCP, as <ip>:<port>
+    #[arg(long, default_value_t = DEFAULT_LISTEN_ADDR, value_parser = parse_socket_addr)]
+    pub listen: SocketAddr,
+
+    /// Enable the control socket on <ip>:<port> (e.g. 127.0.0.1:2054)
+    #[arg(long, value_parser = parse_socket_addr)]
+    pub control: Option<SocketAddr>,
+
+    /// Serve every control socket over TLS with this certificate chain (PEM),
+    /// only letting in clients with a certificate from --control-client-ca
+    #[arg(long, value_name = "PATH", requires_all = ["control_tls_key", "control_client_ca"])]
+    pub control_tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of --control-tls-cert
+    #[arg(long, value_name = "PATH", requires = "control_tls_cert")]
+    pub control_tls_key: Option<PathBuf>,
+
+    /// CA bundle (PEM) the certificates of control clients must be issued by
+    #[arg(long, value_name = "PATH", requires = "control_tls_cert")]
+    pub control_client_ca: Option<PathBuf>,
+
+    /// Only let the holder of a certificate issued for this DNS name on the
+    /// default tenant's control socket; repeatable. Any certificate from
+    /// --control-client-ca is let in when none is given
+    #[arg(
+        long = "control-client",
+        value_name = "NAME",
+        requires = "control_tls_cert",
+        value_parser = control_tls::parse_client_name
+    )]
+    pub control_clients: Vec<String>,
+
+    /// How query names are validated: liberal resolves any name, strict answers
+    /// names that are not valid hostnames with FORMERR
+    #[arg(long, value_enum, default_value_t = HostnamePolicy::Liberal)]
+    pub hostname_policy: HostnamePolicy,
+
+    /// MaxMind GeoIP database (.mmdb) used to pick location-tagged local records
+    /// by client address
+    #[arg(long)]
+    pub geoip_db: Option<PathBuf>,
+
+    /// Map a client subnet to a site, as <site>=<cidr> (e.g. lab=192.168.1.0/24); repeatable.
+    /// Local records tagged site=<site> are preferred for the site's clients
+    #[arg(long = "site", value_name = "SITE=CIDR")]
+    pub sites: Vec<SiteRule>,
+
+    /// Only answer clients in this subnet (CIDR); repeatable. Everyone is answered when
+    /// none are given
+    #[arg(long = "allow-client", value_name = "CIDR")]
+    pub allowed_clients: Vec<Subnet>,
+
+    /// Don't answer clients in this subnet (CIDR), even when in an allowed one; repeatable
+    #[arg(long = "deny-client", value_name = "CIDR")]
+    pub denied_clients: Vec<Subnet>,
+
+    /// What clients that may not query get: refuse (a REFUSED response) or drop (nothing)
+    #[arg(long, value_enum, default_value_t = DenyAction::Refuse)]
+    pub deny_action: DenyAction,
+
+    /// Queries a second each client may send, with bursts of up to BURST (the rate by
+    /// default); packets over the quota are turned away before they are parsed
+    #[arg(long, value_name = "QPS[/BURST]")]
+    pub client_quota: Option<Quota>,
+
+    /// What clients over their --client-quota get: refuse (a REFUSED response) or drop
+    /// (nothing)
+    #[arg(long, value_enum, default_value_t = DenyAction::Refuse, requires = "client_quota")]
+    pub quota_action: DenyAction,
+
+    /// Seconds between health checks of failover records (those with check=)
+    #[arg(long, default_value_t = DEFAULT_CHECK_INTERVAL.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    pub health_check_interval: u64,
+
+    /// Consecutive agreeing health checks needed before a record fails over or back
+    #[arg(long, default_value_t = DEFAULT_DAMPING, value_parser = clap::value_parser!(u32).range(1..))]
+    pub health_check_damping: u32,
+
+    /// Serve the member zones of a catalog zone (RFC 9432) as a secondary, transferring
+    /// them from the primary, as <catalog zone>@<ip>[:<port>]; repeatable
+    #[arg(long = "catalog", value_name = "ZONE@PRIMARY")]
+    pub catalogs: Vec<CatalogSource>,
+
+    /// Serve a zone from a JSON or CSV records file, as <zone>=<path>; the file is
+    /// reloaded whenever it changes; repeatable
+    #[arg(long = "zone-file", value_name = "ZONE=PATH")]
+    pub zone_files: Vec<ZoneFile>,
+
+    /// Serve the zone of a key file written by `dnssec keygen` signed, with RRSIG and NSEC
+    /// records made as queries come in; repeatable, one file per key (default tenant only)
+    #[arg(long = "dnssec-key", value_name = "PATH")]
+    pub dnssec_keys: Vec<PathBuf>,
+
+    /// Answer a local record before any forwarding, given as on the control socket:
+    /// "<name> <type> <value> [ttl] [options]", e.g. "nas.lan A 192.168.1.10";
+    /// repeatable
+    #[arg(long = "record", value_name = "RECORD")]
+    pub records: Vec<LocalRecord>,
+
+    /// Answer the names in a hosts file (/etc/hosts format) with their addresses
+    /// before any forwarding; repeatable
+    #[arg(long = "hosts-file", value_name = "PATH")]
+    pub hosts_files: Vec<PathBuf>,
+
+    /// Answer PTR queries for the addresses of local A and AAAA records with the
+    /// records' names, so reverse lookups of LAN hosts work (default tenant only)
+    #[arg(long)]
+    pub synthesize_ptr: bool,
+
+    /// Answer the reverse zone of a network locally, as <ip>/<prefix> on an octet
+    /// (IPv4) or nibble (IPv6) boundary: names nothing local answers get NXDOMAIN
+    /// instead of being forwarded; repeatable (default tenant only)
+    #[arg(long = "reverse-zone", value_name = "CIDR")]
+    pub reverse_zones: Vec<ReverseZone>,
+
+    /// Forward dynamic updates (RFC 2136) for a zone to its primary, as
+    /// <zone>@<ip>[:<port>], instead of refusing them; repeatable. Member zones of
+    /// a --catalog forward to the catalog's primary without this
+    #[arg(long = "update-forward", value_name = "ZONE@PRIMARY")]
+    pub update_forwards: Vec<UpdateForward>,
+
+    /// Serve an additional, isolated tenant with its own listener, records, upstream,
+    /// counters and control socket; repeatable. Given as comma-separated settings:
+    /// name=NAME,listen=IP:PORT[,control=IP:PORT][,upstream=IP:PORT[@SOURCE|@INTERFACE]...][,allow=CIDR...][,deny=CIDR...][,deny-action=refuse|drop][,quota=QPS[/BURST]][,quota-action=refuse|drop][,token-file=PATH][,catalog=ZONE@PRIMARY...][,zone-file=ZONE=PATH...][,update-forward=ZONE@PRIMARY...]
+    #[arg(long = "tenant", value_name = "SETTINGS")]
+    pub tenants: Vec<Tenant>,
+
+    /// Threads running the async runtime. Defaults to one per CPU core
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub worker_threads: Option<u16>,
+
+    /// Most threads kept for blocking work such as file reads. Defaults to 512
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub max_blocking_threads: Option<u16>,
+
+    /// Upstream lookups each tenant (each shard, with --shards) runs at once
+    #[arg(long, default_value_t = DEFAULT_RESOLVER_WORKERS, value_parser = clap::value_parser!(u16).range(1..))]
+    pub resolver_workers: u16,
+
+    /// Upstream answers each tenant caches until their TTLs run out; a full
+    /// cache evicts answers to make room. 0 turns the cache off
+    #[arg(long, default_value_t = DEFAULT_CACHE_SIZE)]
+    pub cache_size: usize,
+
+    /// Most memory, in megabytes, each cache's answers may take (estimated);
+    /// unlimited unless given
+    #[arg(long, value_name = "MEGABYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub cache_memory: Option<u64>,
+
+    /// Which answers a full cache evicts first: the least recently used, or the
+    /// least frequently used
+    #[arg(long, value_enum, default_value_t = Eviction::Lru)]
+    pub cache_eviction: Eviction,
+
+    /// Cache hits that make an answer popular: popular answers are looked up
+    /// again shortly before they expire, so clients don't wait on upstream
+    /// for them; 0 turns prefetching off
+    #[arg(long, default_value_t = DEFAULT_PREFETCH_HITS)]
+    pub prefetch_hits: u64,
+
+    /// Seconds before a popular answer expires in which a hit refreshes it
+    #[arg(long, default_value_t = DEFAULT_PREFETCH_WINDOW, value_parser = clap::value_parser!(u64).range(1..))]
+    pub prefetch_window: u64,
+
+    /// Keep the cache in this file across restarts: written periodically and on
+    /// SIGINT or SIGTERM, read back with TTLs counted down on start (default
+    /// tenant only)
+    #[arg(long, value_name = "PATH")]
+    pub cache_snapshot: Option<PathBuf>,
+
+    /// Seconds between cache snapshots
+    #[arg(long, default_value_t = DEFAULT_SNAPSHOT_INTERVAL, value_parser = clap::value_parser!(u64).range(1..))]
+    pub cache_snapshot_interval: u64,
+
+    /// Share cached answers with other instances through this store, such as
+    /// redis://10.0.0.5:6379/0: local misses are looked up there and answers
+    /// written there, and the local cache carries on alone while it is
+    /// unavailable (builds with the redis feature only)
+    #[arg(long, value_name = "URL")]
+    pub shared_cache: Option<String>,
+
+    /// Least TTL, in seconds, upstream records are answered and cached with;
+    /// shorter TTLs are raised to it
+    #[arg(long, default_value_t = 0)]
+    pub min_ttl: u32,
+
+    /// Greatest TTL, in seconds, upstream records are answered and cached
+    /// with; longer TTLs are cut to it
+    #[arg(long)]
+    pub max_ttl: Option<u32>,
+
+    /// Order of the records of an RRset in answers: fixed, cyclic (rotated by one
+    /// every response) or random (shuffled every response)
+    #[arg(long, value_enum, default_value_t = RrsetOrder::Fixed)]
+    pub rrset_order: RrsetOrder,
+
+    /// Receive sockets per tenant, bound to the same address with SO_REUSEPORT
+    /// (Unix only); 0 for one per runtime worker thread. Each shard has its own
+    /// resolver workers and duplicate tracking
+    #[arg(long, default_value_t = 1)]
+    pub shards: u16,
+
+    /// Serve UDP through io_uring, submitting receives and responses in batches
+    /// (Linux builds with the io-uring feature only)
+    #[arg(long)]
+    pub io_uring: bool,
+
+    /// Print one human-friendly, colorized line per query to stdout (default tenant only)
+    #[arg(long)]
+    pub pretty_query_log: bool,
+
+    /// Append one line per query to a file, or to stdout when given as - (default tenant only)
+    #[arg(long, value_name = "PATH")]
+    pub query_log_file: Option<PathBuf>,
+
+    /// Format of --query-log-file: text, or json for one JSON object per line
+    #[arg(long, value_enum, default_value_t = QueryLogFormat::Text)]
+    pub query_log_format: QueryLogFormat,
+
+    /// Also keep the query log in an SQLite database, created if it doesn't exist (default
+    /// tenant only, builds with the sqlite feature only)
+    #[arg(long, value_name = "PATH")]
+    pub query_log_db: Option<PathBuf>,
+
+    /// Days --query-log-db keeps queries for
+    #[arg(long, value_name = "DAYS", default_value_t = DEFAULT_RETENTION_DAYS, value_parser = clap::value_parser!(u64).range(1..))]
+    pub query_log_retention: u64,
+
+    /// Route matching queries to upstreams of their own, or answer them NXDOMAIN; repeatable,
+    /// tried in order (default tenant only). Given as comma-separated settings:
+    /// name=TAG[,client=CIDR...][,domain=DOMAIN...][,domains=PATH...][,qtype=TYPE...],to=IP:PORT...|to=blackhole
+    #[arg(long = "route", value_name = "SETTINGS")]
+    pub routes: Vec<RouteRule>,
+
+    /// Answer the clients of some subnets from records, zones and upstreams of their own
+    /// (split horizon); repeatable, tried in order (default tenant only). Given as
+    /// comma-separated settings:
+    /// name=NAME,client=CIDR...[,upstream=IP:PORT...][,record=RECORD...][,hosts=PATH...][,zone-file=ZONE=PATH...]
+    #[arg(long = "view", value_name = "SETTINGS")]
+    pub views: Vec<View>,
+
+    /// Give a group of clients a blocklist, rewrites, upstreams and query logging of their
+    /// own; repeatable, tried in order (default tenant only). Given as comma-separated
+    /// settings: name=NAME,client=CIDR|IP|MAC|HOSTNAME...[,blocklist=PATH|URL...|blocklist=none]
+    /// [,rewrite=RECORD...][,upstream=IP:PORT...][,log=all|failures|none]
+    #[arg(long = "group", value_name = "SETTINGS")]
+    pub groups: Vec<Group>,
+
+    /// Give a client's MAC address or hostname an address, for --group to name it by;
+    /// repeatable (default tenant only)
+    #[arg(long = "client", value_name = "MAC|HOSTNAME=IP")]
+    pub client_names: Vec<ClientName>,
+
+    /// Block the domains listed in a file or at an http(s):// URL (one per line, or
+    /// hosts-file lines), and everything under them; repeatable (default tenant only)
+    #[arg(long = "blocklist", value_name = "PATH|URL")]
+    pub blocklists: Vec<BlocklistSource>,
+
+    /// Seconds between reloads of the blocklists, when some are downloaded
+    #[arg(long, default_value_t = DEFAULT_REFRESH_INTERVAL.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    pub blocklist_refresh_interval: u64,
+
+    /// Let a name through the blocklist: example.com for that name alone, *.example.com
+    /// for everything under it; repeatable (default tenant only)
+    #[arg(long = "allow", value_name = "DOMAIN")]
+    pub allowed: Vec<AllowRule>,
+
+    /// How blocked queries are answered: nxdomain, or null for 0.0.0.0 and ::
+    #[arg(long, value_enum, default_value_t = BlockMode::Nxdomain)]
+    pub block_mode: BlockMode,
+
+    /// Answer A and AAAA queries for blocked names with the address of this block page
+    /// instead, and their HTTPS and SVCB queries with no records; one IPv4 and one IPv6
+    /// address at most (default tenant only)
+    #[arg(long = "block-page", value_name = "IP")]
+    pub block_pages: Vec<IpAddr>,
+
+    /// Send upstream NXDOMAIN answers to A, AAAA, HTTPS and SVCB queries to the
+    /// --block-page too
+    #[arg(long, requires = "block_pages")]
+    pub redirect_nxdomain: bool,
+
+    /// A Rhai script deciding on questions and answers: it may block, refuse or answer a
+    /// question, send it to a --route, or rewrite the answer; reloaded when it changes
+    /// (default tenant only)
+    #[arg(long, value_name = "PATH")]
+    pub script: Option<PathBuf>,
+
+    /// A WebAssembly plugin deciding on questions and answers as --script does, run
+    /// sandboxed; repeatable, each asked in turn ahead of the script (default tenant
+    /// only, builds with the wasm feature only)
+    #[arg(long = "plugin", value_name = "PATH")]
+    pub plugins: Vec<PathBuf>,
+
+    /// Score clients for DNS tunneling (many unique, long or random-looking names under
+    /// one domain, heavy TXT/NULL use) and log an alert for each offending client
+    /// (default tenant only)
+    #[arg(long)]
+    pub detect_tunnels: bool,
+
+    /// Also post each tunneling alert as JSON to this http(s):// URL
+    #[arg(long, value_name = "URL", requires = "detect_tunnels")]
+    pub tunnel_webhook: Option<Webhook>,
+
+    /// Queries a second a client alerted on as a tunnel gets for the next ten minutes;
+    /// the rest are answered REFUSED
+    #[arg(long, value_name = "QPS", requires = "detect_tunnels", value_parser = clap::value_parser!(u32).range(1..))]
+    pub tunnel_rate_limit: Option<u32>,
+
+    /// Answer mDNS queries on 224.0.0.251 port 5353 for the .local names among the
+    /// local records (default tenant only)
+    #[arg(long)]
+    pub mdns: bool,
+
+    /// Answer questions for .local names with a one-shot mDNS query on the LAN, so
+    /// clients on other VLANs can resolve its devices (default tenant only)
+    #[arg(long)]
+    pub mdns_proxy: bool,
+
+    /// The IPv4 address of an interface --mdns and --mdns-proxy use; repeatable.
+    /// Defaults to the default interface
+    #[arg(long = "mdns-interface", value_name = "IP")]
+    pub mdns_interfaces: Vec<Ipv4Addr>,
+
+    /// Relay queries to the upstream as they are, rewriting only the ID, and relay the
+    /// responses back untouched instead of answering from local records
+    #[arg(long)]
+    pub transparent: bool,
+
+    /// The server's own hostname, answered with its addresses (default tenant only)
+    #[arg(long, value_name = "NAME")]
+    pub server_name: Option<String>,
+
+    /// An address the server is reached at, answered for --server-name and hinted in
+    /// DDR records; repeatable. Defaults to the listen address unless it is a wildcard
+    #[arg(long = "advertise", value_name = "IP", requires = "server_name")]
+    pub advertised: Vec<IpAddr>,
+
+    /// Advertise an encrypted endpoint at --server-name through DDR (_dns.resolver.arpa
+    /// SVCB records); repeatable. Given as comma-separated settings:
+    /// alpn=dot|doq|h2|h3...[,port=PORT][,dohpath=TEMPLATE][,priority=N]
+    #[arg(long = "ddr", value_name = "SETTINGS", requires = "server_name")]
+    pub ddr: Vec<DdrEndpoint>,
+
+    /// Most detailed log messages shown: error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL", default_value_t = Level::INFO)]
+    pub log_level: Level,
+
+    /// Run in the background, detached from the terminal (Unix only)
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// With --daemon, write the process ID to this file
+    #[arg(long, requires = "daemon")]
+    pub pid_file: Option<PathBuf>,
+
+    /// With --daemon, append log output to this file instead of discarding it
+    #[arg(long, requires = "daemon")]
+    pub log_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Open an interactive shell on a running server's control socket
+    Ctl {
+        /// Control socket address of the running server
+        #[arg(long, default_value = DEFAULT_CONTROL_ADDR, value_parser = parse_socket_addr)]
+        connect: SocketAddr,
+
+        /// Run a single command instead of starting an interactive shell
+        command: Vec<String>,
+    },
+    /// Print a systemd unit or launchd plist that runs the server with the given options
+    GenerateService {
+        /// Service manager to generate a definition for
+        #[arg(value_enum)]
+        kind: ServiceKind,
+
+        /// Write to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Check the packet parser, encoder and query pipeline against directories of
+    /// test vectors (JSON files with a packet in hex and the expected results)
+    Conformance {
+        /// Directories holding the vectors, one *.json file each
+        #[arg(required = true)]
+        dirs: Vec<PathBuf>,
+    },
+    /// Export or import the local records of a running server
+    Records {
+        #[command(subcommand)]
+        action: RecordsAction,
+    },
+    /// Generate DNSSEC keys for --dnssec-key and print the records publishing them
+    Dnssec {
+        #[command(subcommand)]
+        action: DnssecAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DnssecAction {
+    /// Generate an ECDSA P-256 key for a zone, and print its DNSKEY and DS records to stderr
+    Keygen {
+        /// The zone the key signs
+        zone: String,
+
+        /// Make a key signing key, which signs only the DNSKEY records; a zone with a
+        /// single key uses it for everything
+        #[arg(long)]
+        ksk: bool,
+
+        /// Write the key file here instead of to stdout; an existing file is not overwritten
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print the DNSKEY record of key files, and the DS record to publish in the parent zone
+    Ds {
+        /// Key files written by keygen
+        #[arg(required = true)]
+        keys: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RecordsAction {
+    /// Write all local records as JSON or CSV
+    Export {
+        /// Control socket address of the running server
+        #[arg(long, default_value = DEFAULT_CONTROL_ADDR, value_parser = parse_socket_addr)]
+        connect: SocketAddr,
+
+        /// Output format: json or csv
+        #[arg(long, default_value = "json", value_parser = parse_record_format)]
+        format: RecordFormat,
+
+        /// Write to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Load local records from a JSON or CSV file
+    Import {
+        /// File to import; the format is taken from its extension unless --format is given
+        file: PathBuf,
+
+        /// Control socket address of the running server
+        #[arg(long, default_value = DEFAULT_CONTROL_ADDR, value_parser = parse_socket_addr)]
+        connect: SocketAddr,
+
+        /// Input format: json or csv
+        #[arg(long, value_parser = parse_record_format)]
+        format: Option<RecordFormat>,
+
+        /// Show what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Remove existing records that are not in the file
+        #[arg(long)]
+        replace: bool,
+    },
+}
+
+fn parse_record_format(s: &str) -> Result<RecordFormat, String> {
+    s.parse()
+        .map_err(|e: crate::errors::LocalRecordError| e.to_string())
 }
 
 fn parse_socket_addr(s: &str) -> Result<SocketAddr, String> {
@@ -20,10 +567,439 @@ fn parse_socket_addr(s: &str) -> Result<SocketAddr, String> {
 }
 
 impl Args {
+    /// The options of a command line giving none
+    pub fn defaults() -> Self {
+        Self::parse_from([env!("CARGO_PKG_NAME")])
+    }
+
+    /// Parse the command line, filling in what it leaves out from the
+    /// `--config` file when one is given
     pub fn parse_args() -> Self {
-        Self::parse()
+        let argv: Vec<OsString> = std::env::args_os().collect();
+        // A lenient first pass, only to find the file and what the command line sets
+        let mut command = Self::command().ignore_errors(true);
+        let matches = command
+            .try_get_matches_from_mut(&argv)
+            .unwrap_or_else(|e| e.exit());
+        let Some(path) = matches.get_one::<PathBuf>("config") else {
+            return Self::parse_from(argv);
+        };
+        let flags = config::load(path, &command, &matches).unwrap_or_else(|e| {
+            command
+                .error(ErrorKind::Io, format!("{}: {}", path.display(), e))
+                .exit()
+        });
+
+        // The file's flags go ahead of the command line's, before any subcommand
+        let mut combined = argv;
+        let rest = combined.split_off(combined.len().min(1));
+        combined.extend(flags);
+        combined.extend(rest);
+        Self::parse_from(combined)
+    }
+    pub fn use_system_resolvers(&self) -> bool {
+        !self.no_system_resolvers
+    }
+
+    /// Whether upstream query names are sent in random case
+    pub fn case_randomization(&self) -> bool {
+        !self.no_case_randomization
+    }
+
+    /// The server options as command-line flags, for embedding in service definitions.
+    /// Daemonization flags are left out since service managers supervise the process directly.
+    pub fn server_flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        for resolver in &self.resolvers {
+            flags.extend(["--resolver".to_string(), resolver.to_string()]);
+        }
+        if let Some(hedge) = self.hedge {
+            flags.extend(["--hedge".to_string(), hedge.to_string()]);
+        }
+        for policy in &self.upstream_policies {
+            flags.extend(["--upstream-policy".to_string(), policy.to_string()]);
+        }
+        if self.no_system_resolvers {
+            flags.push("--no-system-resolvers".to_string());
+        }
+        if self.no_case_randomization {
+            flags.push("--no-case-randomization".to_string());
+        }
+        if self.recursive {
+            flags.push("--recursive".to_string());
+        }
+        if self.listen != DEFAULT_LISTEN_ADDR {
+            flags.extend(["--listen".to_string(), self.listen.to_string()]);
+        }
+        if let Some(control) = self.control {
+            flags.extend(["--control".to_string(), control.to_string()]);
+        }
+        if let Some(path) = &self.control_tls_cert {
+            flags.extend(["--control-tls-cert".to_string(), path.display().to_string()]);
+        }
+        if let Some(path) = &self.control_tls_key {
+            flags.extend(["--control-tls-key".to_string(), path.display().to_string()]);
+        }
+        if let Some(path) = &self.control_client_ca {
+            flags.extend([
+                "--control-client-ca".to_string(),
+                path.display().to_string(),
+            ]);
+        }
+        for name in &self.control_clients {
+            flags.extend(["--control-client".to_string(), name.clone()]);
+        }
+        if self.hostname_policy != HostnamePolicy::Liberal {
+            flags.push("--hostname-policy".to_string());
+            flags.push("strict".to_string());
+        }
+        if let Some(geoip_db) = &self.geoip_db {
+            flags.extend(["--geoip-db".to_string(), geoip_db.display().to_string()]);
+        }
+        for site in &self.sites {
+            flags.extend(["--site".to_string(), site.to_string()]);
+        }
+        for subnet in &self.allowed_clients {
+            flags.extend(["--allow-client".to_string(), subnet.to_string()]);
+        }
+        for subnet in &self.denied_clients {
+            flags.extend(["--deny-client".to_string(), subnet.to_string()]);
+        }
+        if self.deny_action != DenyAction::Refuse {
+            flags.extend(["--deny-action".to_string(), "drop".to_string()]);
+        }
+        if let Some(quota) = self.client_quota {
+            flags.extend(["--client-quota".to_string(), quota.to_string()]);
+        }
+        if self.quota_action != DenyAction::Refuse {
+            flags.extend(["--quota-action".to_string(), "drop".to_string()]);
+        }
+        if self.health_check_interval != DEFAULT_CHECK_INTERVAL.as_secs() {
+            flags.extend([
+                "--health-check-interval".to_string(),
+                self.health_check_interval.to_string(),
+            ]);
+        }
+        if self.health_check_damping != DEFAULT_DAMPING {
+            flags.extend([
+                "--health-check-damping".to_string(),
+                self.health_check_damping.to_string(),
+            ]);
+        }
+        for catalog in &self.catalogs {
+            flags.extend(["--catalog".to_string(), catalog.to_string()]);
+        }
+        for zone_file in &self.zone_files {
+            flags.extend(["--zone-file".to_string(), zone_file.to_string()]);
+        }
+        for key in &self.dnssec_keys {
+            flags.extend(["--dnssec-key".to_string(), key.display().to_string()]);
+        }
+        for record in &self.records {
+            flags.extend(["--record".to_string(), record.to_string()]);
+        }
+        for path in &self.hosts_files {
+            flags.extend(["--hosts-file".to_string(), path.display().to_string()]);
+        }
+        if self.synthesize_ptr {
+            flags.push("--synthesize-ptr".to_string());
+        }
+        for zone in &self.reverse_zones {
+            flags.extend(["--reverse-zone".to_string(), zone.to_string()]);
+        }
+        for forward in &self.update_forwards {
+            flags.extend(["--update-forward".to_string(), forward.to_string()]);
+        }
+        for tenant in &self.tenants {
+            flags.extend(["--tenant".to_string(), tenant.to_string()]);
+        }
+        if let Some(threads) = self.worker_threads {
+            flags.extend(["--worker-threads".to_string(), threads.to_string()]);
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            flags.extend(["--max-blocking-threads".to_string(), threads.to_string()]);
+        }
+        if self.resolver_workers != DEFAULT_RESOLVER_WORKERS {
+            flags.extend([
+                "--resolver-workers".to_string(),
+                self.resolver_workers.to_string(),
+            ]);
+        }
+        if self.cache_size != DEFAULT_CACHE_SIZE {
+            flags.extend(["--cache-size".to_string(), self.cache_size.to_string()]);
+        }
+        if let Some(path) = &self.cache_snapshot {
+            flags.extend(["--cache-snapshot".to_string(), path.display().to_string()]);
+        }
+        if self.cache_snapshot_interval != DEFAULT_SNAPSHOT_INTERVAL {
+            flags.extend([
+                "--cache-snapshot-interval".to_string(),
+                self.cache_snapshot_interval.to_string(),
+            ]);
+        }
+        if let Some(url) = &self.shared_cache {
+            flags.extend(["--shared-cache".to_string(), url.clone()]);
+        }
+        if let Some(megabytes) = self.cache_memory {
+            flags.extend(["--cache-memory".to_string(), megabytes.to_string()]);
+        }
+        if self.cache_eviction != Eviction::Lru {
+            flags.extend([
+                "--cache-eviction".to_string(),
+                self.cache_eviction.to_string(),
+            ]);
+        }
+        if self.prefetch_hits != DEFAULT_PREFETCH_HITS {
+            flags.extend([
+                "--prefetch-hits".to_string(),
+                self.prefetch_hits.to_string(),
+            ]);
+        }
+        if self.prefetch_window != DEFAULT_PREFETCH_WINDOW {
+            flags.extend([
+                "--prefetch-window".to_string(),
+                self.prefetch_window.to_string(),
+            ]);
+        }
+        if self.min_ttl != 0 {
+            flags.extend(["--min-ttl".to_string(), self.min_ttl.to_string()]);
+        }
+        if let Some(max_ttl) = self.max_ttl {
+            flags.extend(["--max-ttl".to_string(), max_ttl.to_string()]);
+        }
+        if self.rrset_order != RrsetOrder::Fixed {
+            flags.extend(["--rrset-order".to_string(), self.rrset_order.to_string()]);
+        }
+        if self.shards != 1 {
+            flags.extend(["--shards".to_string(), self.shards.to_string()]);
+        }
+        if self.io_uring {
+            flags.push("--io-uring".to_string());
+        }
+        if self.pretty_query_log {
+            flags.push("--pretty-query-log".to_string());
+        }
+        if let Some(path) = &self.query_log_file {
+            flags.extend(["--query-log-file".to_string(), path.display().to_string()]);
+        }
+        if self.query_log_format != QueryLogFormat::Text {
+            flags.extend(["--query-log-format".to_string(), "json".to_string()]);
+        }
+        if let Some(path) = &self.query_log_db {
+            flags.extend(["--query-log-db".to_string(), path.display().to_string()]);
+        }
+        if self.query_log_retention != DEFAULT_RETENTION_DAYS {
+            flags.extend([
+                "--query-log-retention".to_string(),
+                self.query_log_retention.to_string(),
+            ]);
+        }
+        for route in &self.routes {
+            flags.extend(["--route".to_string(), route.to_string()]);
+        }
+        for view in &self.views {
+            flags.extend(["--view".to_string(), view.to_string()]);
+        }
+        for group in &self.groups {
+            flags.extend(["--group".to_string(), group.to_string()]);
+        }
+        for name in &self.client_names {
+            flags.extend(["--client".to_string(), name.to_string()]);
+        }
+        for source in &self.blocklists {
+            flags.extend(["--blocklist".to_string(), source.to_string()]);
+        }
+        if self.blocklist_refresh_interval != DEFAULT_REFRESH_INTERVAL.as_secs() {
+            flags.extend([
+                "--blocklist-refresh-interval".to_string(),
+                self.blocklist_refresh_interval.to_string(),
+            ]);
+        }
+        for rule in &self.allowed {
+            flags.extend(["--allow".to_string(), rule.to_string()]);
+        }
+        for ip in &self.block_pages {
+            flags.extend(["--block-page".to_string(), ip.to_string()]);
+        }
+        if self.redirect_nxdomain {
+            flags.push("--redirect-nxdomain".to_string());
+        }
+        if let Some(path) = &self.script {
+            flags.extend(["--script".to_string(), path.display().to_string()]);
+        }
+        for path in &self.plugins {
+            flags.extend(["--plugin".to_string(), path.display().to_string()]);
+        }
+        if self.detect_tunnels {
+            flags.push("--detect-tunnels".to_string());
+        }
+        if let Some(webhook) = &self.tunnel_webhook {
+            flags.extend(["--tunnel-webhook".to_string(), webhook.to_string()]);
+        }
+        if let Some(rate) = self.tunnel_rate_limit {
+            flags.extend(["--tunnel-rate-limit".to_string(), rate.to_string()]);
+        }
+        if self.mdns {
+            flags.push("--mdns".to_string());
+        }
+        if self.mdns_proxy {
+            flags.push("--mdns-proxy".to_string());
+        }
+        for interface in &self.mdns_interfaces {
+            flags.extend(["--mdns-interface".to_string(), interface.to_string()]);
+        }
+        if self.block_mode != BlockMode::Nxdomain {
+            flags.extend(["--block-mode".to_string(), "null".to_string()]);
+        }
+        if self.transparent {
+            flags.push("--transparent".to_string());
+        }
+        if let Some(name) = &self.server_name {
+            flags.extend(["--server-name".to_string(), name.clone()]);
+        }
+        for ip in &self.advertised {
+            flags.extend(["--advertise".to_string(), ip.to_string()]);
+        }
+        for endpoint in &self.ddr {
+            flags.extend(["--ddr".to_string(), endpoint.to_string()]);
+        }
+        if self.log_level != Level::INFO {
+            flags.extend(["--log-level".to_string(), self.log_level.to_string()]);
+        }
+        flags
+    }
+    /// Receive sockets per tenant, with 0 (one per worker thread) worked out
+    pub fn shards(&self) -> usize {
+        match self.shards {
+            0 if cfg!(unix) => self.worker_threads.map_or_else(
+                || std::thread::available_parallelism().map_or(1, usize::from),
+                usize::from,
+            ),
+            0 => 1,
+            shards => shards.into(),
+        }
+    }
+
+    pub fn ttl_limits(&self) -> TtlLimits {
+        TtlLimits {
+            min: self.min_ttl,
+            max: self.max_ttl.unwrap_or(u32::MAX),
+        }
+    }
+    pub fn control(&self) -> Option<SocketAddr> {
+        self.control
+    }
+    pub fn health_check_interval(&self) -> Duration {
+        Duration::from_secs(self.health_check_interval)
     }
-    pub fn resolver(&self) -> Option<SocketAddr> {
-        self.resolver
+    pub fn query_log_retention(&self) -> Duration {
+        Duration::from_secs(self.query_log_retention * 24 * 60 * 60)
+    }
+    pub fn blocklist_refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.blocklist_refresh_interval)
+    }
+    pub fn cache_snapshot_interval(&self) -> Duration {
+        Duration::from_secs(self.cache_snapshot_interval)
+    }
+    /// How big each cache may grow, what it evicts, and when its answers
+    /// are refreshed before they expire
+    pub fn cache_config(&self) -> CacheConfig {
+        CacheConfig {
+            max_entries: self.cache_size,
+            max_bytes: self
+                .cache_memory
+                .map(|megabytes| usize::try_from(megabytes << 20).unwrap_or(usize::MAX)),
+            eviction: self.cache_eviction,
+            prefetch: (self.prefetch_hits > 0).then(|| Prefetch {
+                hits: self.prefetch_hits,
+                window: Duration::from_secs(self.prefetch_window),
+            }),
+        }
+    }
+
+    /// Every tenant to serve: the default one described by the top-level
+    /// options, followed by those given with `--tenant`
+    pub fn all_tenants(&self) -> Vec<Tenant> {
+        let default = Tenant {
+            name: DEFAULT_TENANT.to_string(),
+            listen: self.listen,
+            control: self.control(),
+            upstreams: self.resolvers.clone(),
+            hedge: self.hedge,
+            allow: self.allowed_clients.clone(),
+            deny: self.denied_clients.clone(),
+            deny_action: self.deny_action,
+            quota: self.client_quota,
+            quota_action: self.quota_action,
+            token_file: None,
+            control_clients: self.control_clients.clone(),
+            catalogs: self.catalogs.clone(),
+            zone_files: self.zone_files.clone(),
+            update_forwards: self.update_forwards.clone(),
+        };
+        std::iter::once(default)
+            .chain(self.tenants.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::parse_from(std::iter::once(env!("CARGO_PKG_NAME")).chain(flags.iter().copied()))
+    }
+
+    #[test]
+    fn test_runtime_sizing() {
+        let defaults = args(&[]);
+        assert_eq!(defaults.worker_threads, None);
+        assert_eq!(defaults.max_blocking_threads, None);
+        assert_eq!(defaults.resolver_workers, DEFAULT_RESOLVER_WORKERS);
+        assert!(!defaults
+            .server_flags()
+            .iter()
+            .any(|flag| flag.ends_with("-threads") || flag == "--resolver-workers"));
+
+        let flags = [
+            "--worker-threads",
+            "4",
+            "--max-blocking-threads",
+            "64",
+            "--resolver-workers",
+            "32",
+        ];
+        let sized = args(&flags);
+        assert_eq!(sized.worker_threads, Some(4));
+        assert_eq!(sized.max_blocking_threads, Some(64));
+        assert_eq!(sized.resolver_workers, 32);
+        assert!(sized
+            .server_flags()
+            .windows(flags.len())
+            .any(|window| window == flags));
+
+        for flag in [
+            "--worker-threads",
+            "--max-blocking-threads",
+            "--resolver-workers",
+        ] {
+            assert!(Args::try_parse_from([env!("CARGO_PKG_NAME"), flag, "0"]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_shards() {
+        assert_eq!(args(&[]).shards(), 1);
+        assert_eq!(args(&["--shards", "4"]).shards(), 4);
+        // 0 is one per worker thread, which is one per core unless given
+        let expected = if cfg!(unix) { 3 } else { 1 };
+        assert_eq!(
+            args(&["--shards", "0", "--worker-threads", "3"]).shards(),
+            expected
+        );
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        let expected = if cfg!(unix) { cores } else { 1 };
+        assert_eq!(args(&["--shards", "0"]).shards(), expected);
     }
 }
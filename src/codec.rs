@@ -1,15 +1,20 @@
 //! DNS packet codec for tokio_util
 //!
 //! This module provides Decoder and Encoder implementations for DNS packets,
-//! allowing integration with tokio's framed streams and UDP handling.
+//! allowing integration with tokio's framed streams and UDP handling. The
+//! codec keeps no state, so the listeners call the functions behind it
+//! directly: `decode_packet` parses straight from the received datagram and
+//! `encode_packet` writes a packet it only borrows.
 
 use bytes::{BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 use tracing::{debug, error};
 
 use crate::errors::DnsCodecError;
+use crate::names::{escape_label, name_to_labels};
 use crate::parsers::parse_dns_packet;
-use crate::protocol::DnsPacket;
+use crate::protocol::{DnsPacket, DnsPacketHeader, DnsResourceRecord};
+use crate::registry::RecordType;
 
 /// DNS packet codec for use with tokio_util framed streams
 #[derive(Debug, Default)]
@@ -27,57 +32,13 @@ impl Decoder for DnsCodec {
     type Error = DnsCodecError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // debug!("DnsCodec::decode called with {} bytes", src.len());
-
-        // DNS packets need at least 12 bytes for the header
-        if src.len() < 12 {
-            debug!("Insufficient bytes for DNS header: {} < 12", src.len());
-            return Ok(None);
-        }
-
-        // For UDP DNS packets, we expect complete packets in each datagram
-        // Convert BytesMut to &[u8] for nom parsing
-        let input_bytes = src.as_ref();
-
-        // debug!(
-        //     "Attempting to parse DNS packet of {} bytes",
-        //     input_bytes.len()
-        // );
-
-        // Use our existing nom parser
-        match parse_dns_packet(input_bytes) {
-            Ok((remaining, packet)) => {
-                let consumed = input_bytes.len() - remaining.len();
-                // debug!(
-                //     "Successfully parsed DNS packet, consumed {} bytes",
-                //     consumed
-                // );
-
+        match decode_prefix(src)? {
+            Some((packet, consumed)) => {
                 // Remove the consumed bytes from the buffer
                 let _ = src.split_to(consumed);
-
                 Ok(Some(packet))
             }
-            Err(nom::Err::Incomplete(needed)) => {
-                debug!("Incomplete DNS packet, need more data: {:?}", needed);
-
-                let needed_bytes = match needed {
-                    nom::Needed::Size(n) => n.get(),
-                    nom::Needed::Unknown => 64, // Reasonable default for DNS
-                };
-
-                Err(DnsCodecError::IncompletePacket {
-                    needed: needed_bytes,
-                    available: src.len(),
-                })
-            }
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-                error!("DNS parsing error: {:?}", e);
-
-                // Convert nom error to our error type
-                let error_msg = format!("nom parsing failed: {:?}", e);
-                Err(DnsCodecError::NomError(error_msg))
-            }
+            None => Ok(None),
         }
     }
 }
@@ -86,163 +47,292 @@ impl Encoder<DnsPacket> for DnsCodec {
     type Error = DnsCodecError;
 
     fn encode(&mut self, item: DnsPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        debug!("DnsCodec::encode called for packet ID {}", item.header.id);
+        encode_packet(&item, dst)
+    }
+}
 
-        // Create a corrected header with the actual question and answer counts
-        let mut corrected_header = item.header;
-        corrected_header.qdcount = item.questions.len() as u16;
-        corrected_header.ancount = item.answers.len() as u16;
+/// Decode a DNS message, such as a UDP datagram, without copying it first.
+/// Returns `None` when it's too short to hold a header.
+pub fn decode_packet(src: &[u8]) -> Result<Option<DnsPacket>, DnsCodecError> {
+    Ok(decode_prefix(src)?.map(|(packet, _)| packet))
+}
 
-        // Encode DNS packet header (12 bytes) with corrected counts
-        self.encode_header(&corrected_header, dst);
+/// Decode the DNS message at the start of `src`, and how many bytes it took
+fn decode_prefix(src: &[u8]) -> Result<Option<(DnsPacket, usize)>, DnsCodecError> {
+    // DNS packets need at least 12 bytes for the header
+    if src.len() < 12 {
+        debug!("Insufficient bytes for DNS header: {} < 12", src.len());
+        return Ok(None);
+    }
+
+    // For UDP DNS packets, we expect complete packets in each datagram
+    match parse_dns_packet(src) {
+        Ok((remaining, packet)) => Ok(Some((packet, src.len() - remaining.len()))),
+        Err(nom::Err::Incomplete(needed)) => {
+            debug!("Incomplete DNS packet, need more data: {:?}", needed);
+
+            let needed_bytes = match needed {
+                nom::Needed::Size(n) => n.get(),
+                nom::Needed::Unknown => 64, // Reasonable default for DNS
+            };
+
+            Err(DnsCodecError::IncompletePacket {
+                needed: needed_bytes,
+                available: src.len(),
+            })
+        }
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+            error!("DNS parsing error: {}", e);
+            Err(DnsCodecError::Malformed(e))
+        }
+    }
+}
 
-        // Encode the questions
-        for question in &item.questions {
-            // Encode the question name using DNS label format
-            self.encode_domain_name(&question.name, dst)?;
+/// Encode a DNS packet, with section counts taken from the sections rather
+/// than the header
+pub fn encode_packet(item: &DnsPacket, dst: &mut BytesMut) -> Result<(), DnsCodecError> {
+    debug!("Encoding DNS packet ID {}", item.header.id);
 
-            // Encode the question type (2 bytes)
-            dst.put_u16(question.qtype);
+    // Create a corrected header with the actual section counts
+    let mut corrected_header = item.header;
+    corrected_header.qdcount = item.questions.len() as u16;
+    corrected_header.ancount = item.answers.len() as u16;
+    corrected_header.nscount = item.authorities.len() as u16;
+    corrected_header.arcount = item.additionals.len() as u16;
 
-            // Encode the question class (2 bytes)
-            dst.put_u16(question.qclass);
-        }
+    // Encode DNS packet header (12 bytes) with corrected counts
+    encode_header(&corrected_header, dst);
 
-        // Encode the answers
-        for answer in &item.answers {
-            // Encode the answer name using DNS label format
-            self.encode_domain_name(&answer.name, dst)?;
+    // Encode the questions
+    for question in &item.questions {
+        // Encode the question name using DNS label format
+        encode_domain_name(&question.name, dst)?;
 
-            // Encode the answer type (2 bytes)
-            dst.put_u16(answer.rtype);
+        // Encode the question type (2 bytes)
+        dst.put_u16(question.qtype.0);
 
-            // Encode the answer class (2 bytes)
-            dst.put_u16(answer.rclass);
+        // Encode the question class (2 bytes)
+        dst.put_u16(question.qclass.0);
+    }
 
-            // Encode the TTL (4 bytes)
-            dst.put_u32(answer.ttl);
+    // Encode the answers, then the authority and additional records
+    for record in item
+        .answers
+        .iter()
+        .chain(&item.authorities)
+        .chain(&item.additionals)
+    {
+        encode_resource_record(record, dst)?;
+    }
 
-            // Encode the data length (2 bytes)
-            dst.put_u16(answer.rdata.len() as u16);
+    Ok(())
+}
 
-            // Encode the data
-            dst.put_slice(&answer.rdata);
+/// Encode a response for a UDP client that takes at most `limit` bytes.
+/// Additional records other than OPT are only extra help, so they go
+/// first, without setting TC. A response still too large keeps its
+/// questions, OPT record and as many answers as fit, drops the rest, and
+/// has TC set so the client knows to ask again over TCP (RFC 2181 §9).
+/// Returns whether it was truncated.
+pub fn encode_truncated(
+    mut item: DnsPacket,
+    limit: usize,
+    dst: &mut BytesMut,
+) -> Result<bool, DnsCodecError> {
+    let start = dst.len();
+    encode_packet(&item, dst)?;
+    if dst.len() - start <= limit {
+        return Ok(false);
+    }
+    dst.truncate(start);
+
+    if item
+        .additionals
+        .iter()
+        .any(|record| record.rtype != RecordType::OPT)
+    {
+        item.additionals
+            .retain(|record| record.rtype == RecordType::OPT);
+        encode_packet(&item, dst)?;
+        if dst.len() - start <= limit {
+            return Ok(false);
         }
+        dst.truncate(start);
+    }
 
-        // debug!(
-        //     "Successfully encoded DNS packet, total size: {} bytes",
-        //     dst.len()
-        // );
-        Ok(())
+    let answers = std::mem::take(&mut item.answers);
+    item.authorities.clear();
+    item.additionals
+        .retain(|record| record.rtype == RecordType::OPT);
+    item.header.tc = true;
+
+    // Names aren't compressed, so each record adds its own length
+    let mut len = encoded_len(&item);
+    for answer in answers {
+        len += record_len(&answer);
+        if len > limit {
+            break;
+        }
+        item.answers.push(answer);
     }
+    encode_packet(&item, dst)?;
+    Ok(true)
 }
 
-impl DnsCodec {
-    /// Encode a DNS domain name using label format
-    /// Domain names are encoded as a sequence of labels, each prefixed by its length,
-    /// terminated by a null byte (0)
-    fn encode_domain_name(
-        &self,
-        domain_name: &str,
-        dst: &mut BytesMut,
-    ) -> Result<(), DnsCodecError> {
-        // Split the domain name by dots to get individual labels
-        let labels: Vec<&str> = domain_name.split('.').collect();
-
-        // Calculate total space needed: sum of (1 byte length + label bytes) + 1 null terminator
-        let total_space: usize = labels.iter().map(|label| 1 + label.len()).sum::<usize>() + 1;
-        dst.reserve(total_space);
-
-        // Encode each label
-        for label in labels {
-            // Check label length (DNS labels must be <= 63 bytes)
-            if label.len() > 63 {
-                return Err(DnsCodecError::InvalidDomainName(format!(
-                    "Label '{}' exceeds maximum length of 63 bytes",
-                    label
-                )));
-            }
-
-            // Skip empty labels (e.g., from trailing dots)
-            if label.is_empty() {
-                continue;
-            }
+/// Length of a packet on the wire, once it has been encoded successfully
+fn encoded_len(packet: &DnsPacket) -> usize {
+    let questions: usize = packet
+        .questions
+        .iter()
+        .map(|question| name_len(&question.name) + 4)
+        .sum();
+    let records: usize = packet
+        .answers
+        .iter()
+        .chain(&packet.authorities)
+        .chain(&packet.additionals)
+        .map(record_len)
+        .sum();
+    12 + questions + records
+}
 
-            // Encode length byte followed by label content
-            dst.put_u8(label.len() as u8);
-            dst.put_slice(label.as_bytes());
+/// Encode a DNS domain name using label format
+/// Domain names are encoded as a sequence of labels, each prefixed by its length,
+/// terminated by a null byte (0). Escapes in the name (`\.`, `\DDD`) are
+/// decoded back into the raw label bytes.
+fn encode_domain_name(domain_name: &str, dst: &mut BytesMut) -> Result<(), DnsCodecError> {
+    // The root name has no labels and is just the null terminator
+    let labels = name_to_labels(domain_name);
+
+    // Calculate total space needed: sum of (1 byte length + label bytes) + 1 null terminator
+    let total_space: usize = labels.iter().map(|label| 1 + label.len()).sum::<usize>() + 1;
+    dst.reserve(total_space);
+
+    // Encode each label
+    for label in labels {
+        // Check label length (DNS labels must be <= 63 bytes)
+        if label.len() > 63 {
+            return Err(DnsCodecError::InvalidDomainName(format!(
+                "Label '{}' exceeds maximum length of 63 bytes",
+                escape_label(&label)
+            )));
         }
 
-        // Null terminator
-        dst.put_u8(0);
-
-        Ok(())
+        // Encode length byte followed by label content
+        dst.put_u8(label.len() as u8);
+        dst.put_slice(&label);
     }
 
-    /// Encode DNS packet header into the destination buffer
-    fn encode_header(&self, header: &crate::protocol::DnsPacketHeader, dst: &mut BytesMut) {
-        // Ensure we have enough space (12 bytes for header)
-        dst.reserve(12);
+    // Null terminator
+    dst.put_u8(0);
 
-        // ID (16 bits)
-        dst.put_u16(header.id);
+    Ok(())
+}
 
-        // Flags (16 bits total)
-        let mut flags: u16 = 0;
+/// Encode a resource record (answer or authority section)
+fn encode_resource_record(
+    record: &DnsResourceRecord,
+    dst: &mut BytesMut,
+) -> Result<(), DnsCodecError> {
+    // Encode the record name using DNS label format
+    encode_domain_name(&record.name, dst)?;
 
-        // QR (1 bit) - bit 15
-        if header.qr {
-            flags |= 0x8000;
-        }
+    // Encode the record type (2 bytes)
+    dst.put_u16(record.rtype.0);
 
-        // OPCODE (4 bits) - bits 14-11
-        flags |= ((header.opcode as u16) & 0x0F) << 11;
+    // Encode the record class (2 bytes)
+    dst.put_u16(record.rclass.0);
 
-        // AA (1 bit) - bit 10
-        if header.aa {
-            flags |= 0x0400;
-        }
+    // Encode the TTL (4 bytes)
+    dst.put_u32(record.ttl);
 
-        // TC (1 bit) - bit 9
-        if header.tc {
-            flags |= 0x0200;
-        }
+    // Encode the data length (2 bytes)
+    dst.put_u16(record.rdata.len() as u16);
 
-        // RD (1 bit) - bit 8
-        if header.rd {
-            flags |= 0x0100;
-        }
+    // Encode the data
+    dst.put_slice(&record.rdata);
 
-        // RA (1 bit) - bit 7
-        if header.ra {
-            flags |= 0x0080;
-        }
+    Ok(())
+}
 
-        // Z (3 bits) - bits 6-4 (reserved, should be 0)
-        flags |= ((header.z as u16) & 0x07) << 4;
+/// Encode DNS packet header into the destination buffer
+fn encode_header(header: &DnsPacketHeader, dst: &mut BytesMut) {
+    // Ensure we have enough space (12 bytes for header)
+    dst.reserve(12);
 
-        // RCODE (4 bits) - bits 3-0
-        flags |= (header.rcode as u16) & 0x0F;
+    // ID (16 bits)
+    dst.put_u16(header.id);
 
-        dst.put_u16(flags);
+    // Flags (16 bits total)
+    let mut flags: u16 = 0;
 
-        // Question count (16 bits)
-        dst.put_u16(header.qdcount);
+    // QR (1 bit) - bit 15
+    if header.qr {
+        flags |= 0x8000;
+    }
 
-        // Answer count (16 bits)
-        dst.put_u16(header.ancount);
+    // OPCODE (4 bits) - bits 14-11
+    flags |= (u16::from(header.opcode.0) & 0x0F) << 11;
 
-        // Authority count (16 bits)
-        dst.put_u16(header.nscount);
+    // AA (1 bit) - bit 10
+    if header.aa {
+        flags |= 0x0400;
+    }
 
-        // Additional count (16 bits)
-        dst.put_u16(header.arcount);
+    // TC (1 bit) - bit 9
+    if header.tc {
+        flags |= 0x0200;
     }
+
+    // RD (1 bit) - bit 8
+    if header.rd {
+        flags |= 0x0100;
+    }
+
+    // RA (1 bit) - bit 7
+    if header.ra {
+        flags |= 0x0080;
+    }
+
+    // Z (3 bits) - bits 6-4 (reserved, should be 0)
+    flags |= ((header.z as u16) & 0x07) << 4;
+
+    // RCODE (4 bits) - bits 3-0
+    flags |= header.rcode.0 & 0x0F;
+
+    dst.put_u16(flags);
+
+    // Question count (16 bits)
+    dst.put_u16(header.qdcount);
+
+    // Answer count (16 bits)
+    dst.put_u16(header.ancount);
+
+    // Authority count (16 bits)
+    dst.put_u16(header.nscount);
+
+    // Additional count (16 bits)
+    dst.put_u16(header.arcount);
 }
 
+/// Length of a name on the wire
+fn name_len(name: &str) -> usize {
+    name_to_labels(name)
+        .iter()
+        .map(|label| 1 + label.len())
+        .sum::<usize>()
+        + 1
+}
+
+/// Length of a record on the wire: its name, TYPE, CLASS, TTL, RDLENGTH
+/// and RDATA
+fn record_len(record: &DnsResourceRecord) -> usize {
+    name_len(&record.name) + 10 + record.rdata.len()
+}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::registry::{Opcode, Rcode, RecordClass, RecordType};
     use bytes::BytesMut;
 
     #[test]
@@ -274,14 +364,14 @@ mod tests {
 
         let header = DnsPacketHeader {
             id: 0x1234,
-            qr: true,  // Response
-            opcode: 0, // QUERY
+            qr: true, // Response
+            opcode: Opcode::QUERY,
             aa: true,  // Authoritative
             tc: false, // Not truncated
             rd: true,  // Recursion desired
             ra: true,  // Recursion available
             z: 0,      // Reserved
-            rcode: 0,  // NOERROR
+            rcode: Rcode::NOERROR,
             qdcount: 1,
             ancount: 1,
             nscount: 0,
@@ -292,6 +382,8 @@ mod tests {
             header,
             questions: vec![], // Empty questions for this test
             answers: vec![],   // Empty answers for this test
+            authorities: vec![],
+            additionals: vec![],
         };
 
         let result = codec.encode(packet, &mut buf);
@@ -328,14 +420,14 @@ mod tests {
 
         let header = DnsPacketHeader {
             id: 0x1234,
-            qr: false,  // Query
-            opcode: 0,  // QUERY
-            aa: false,  // Not authoritative
-            tc: false,  // Not truncated
-            rd: true,   // Recursion desired
-            ra: false,  // Recursion not available
-            z: 0,       // Reserved
-            rcode: 0,   // NOERROR
+            qr: false, // Query
+            opcode: Opcode::QUERY,
+            aa: false, // Not authoritative
+            tc: false, // Not truncated
+            rd: true,  // Recursion desired
+            ra: false, // Recursion not available
+            z: 0,      // Reserved
+            rcode: Rcode::NOERROR,
             qdcount: 1, // One question
             ancount: 0,
             nscount: 0,
@@ -344,14 +436,16 @@ mod tests {
 
         let question = DnsQuestion {
             name: "google.com".to_string(),
-            qtype: 1,  // A record
-            qclass: 1, // IN class
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
         };
 
         let packet = DnsPacket {
             header,
             questions: vec![question],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         let result = codec.encode(packet, &mut buf);
@@ -396,11 +490,10 @@ mod tests {
 
     #[test]
     fn test_dns_codec_encode_domain_name_edge_cases() {
-        let codec = DnsCodec::new();
         let mut buf = BytesMut::new();
 
         // Test simple domain
-        let result = codec.encode_domain_name("example.com", &mut buf);
+        let result = encode_domain_name("example.com", &mut buf);
         assert!(result.is_ok());
 
         let expected = vec![
@@ -412,7 +505,7 @@ mod tests {
 
         // Test domain with trailing dot (should be handled correctly)
         buf.clear();
-        let result = codec.encode_domain_name("test.org.", &mut buf);
+        let result = encode_domain_name("test.org.", &mut buf);
         assert!(result.is_ok());
 
         let expected = vec![
@@ -421,6 +514,73 @@ mod tests {
             0,    // null terminator
         ];
         assert_eq!(buf.as_ref(), &expected[..]);
+
+        // The root name is a single zero byte
+        for root in [".", ""] {
+            buf.clear();
+            encode_domain_name(root, &mut buf).unwrap();
+            assert_eq!(buf.as_ref(), &[0]);
+        }
+    }
+
+    #[test]
+    fn test_dns_codec_round_trip_binary_labels() {
+        let mut codec = DnsCodec::new();
+        let mut wire = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        // "_sip", "a.b", "\xff\x00" and "com"
+        wire.extend_from_slice(b"\x04_sip\x03a.b\x02\xff\x00\x03com\x00\x00\x21\x00\x01");
+
+        let packet = codec
+            .decode(&mut BytesMut::from(&wire[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.questions[0].name, "_sip.a\\.b.\\255\\000.com");
+
+        // Encoding restores the original bytes
+        let mut buf = BytesMut::new();
+        codec.encode(packet, &mut buf).unwrap();
+        assert_eq!(&buf[..], &wire[..]);
+    }
+
+    #[test]
+    fn test_dns_codec_round_trip_root_question() {
+        use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion};
+
+        let mut codec = DnsCodec::new();
+        let packet = DnsPacket {
+            header: DnsPacketHeader {
+                id: 0x4321,
+                qr: false,
+                opcode: Opcode::QUERY,
+                aa: false,
+                tc: false,
+                rd: true,
+                ra: false,
+                z: 0,
+                rcode: Rcode::NOERROR,
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![DnsQuestion {
+                name: ".".to_string(),
+                qtype: RecordType::NS,
+                qclass: RecordClass::IN,
+            }],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+
+        let mut buf = BytesMut::new();
+        codec.encode(packet, &mut buf).unwrap();
+        // Header, then the root name, QTYPE and QCLASS
+        assert_eq!(&buf[12..], &[0, 0, 2, 0, 1]);
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.questions[0].name, ".");
+        assert_eq!(decoded.questions[0].qtype, RecordType::NS);
     }
 
     #[test]
@@ -434,13 +594,13 @@ mod tests {
             header: DnsPacketHeader {
                 id: 0x1234,
                 qr: false,
-                opcode: 0,
+                opcode: Opcode::QUERY,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: false,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NOERROR,
                 qdcount: 1, // One question
                 ancount: 0,
                 nscount: 0,
@@ -448,10 +608,12 @@ mod tests {
             },
             questions: vec![DnsQuestion {
                 name: "example.com".to_string(),
-                qtype: 1,  // A record
-                qclass: 1, // IN class
+                qtype: RecordType::A,
+                qclass: RecordClass::IN,
             }],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         // Encode the packet
@@ -501,13 +663,13 @@ mod tests {
             header: DnsPacketHeader {
                 id: 0x5678,
                 qr: false,
-                opcode: 0,
+                opcode: Opcode::QUERY,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: false,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NOERROR,
                 qdcount: 2, // Two questions
                 ancount: 0,
                 nscount: 0,
@@ -516,16 +678,18 @@ mod tests {
             questions: vec![
                 DnsQuestion {
                     name: "example.com".to_string(),
-                    qtype: 1,  // A record
-                    qclass: 1, // IN class
+                    qtype: RecordType::A,
+                    qclass: RecordClass::IN,
                 },
                 DnsQuestion {
                     name: "test.org".to_string(),
-                    qtype: 28, // AAAA record
-                    qclass: 1, // IN class
+                    qtype: RecordType::AAAA,
+                    qclass: RecordClass::IN,
                 },
             ],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         // Encode the packet
@@ -575,13 +739,13 @@ mod tests {
             header: DnsPacketHeader {
                 id: 0x1234,
                 qr: false,
-                opcode: 0,
+                opcode: Opcode::QUERY,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: false,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NOERROR,
                 qdcount: 99, // Incorrect count - should be corrected to 3
                 ancount: 0,
                 nscount: 0,
@@ -590,21 +754,23 @@ mod tests {
             questions: vec![
                 DnsQuestion {
                     name: "example.com".to_string(),
-                    qtype: 1,
-                    qclass: 1,
+                    qtype: RecordType::A,
+                    qclass: RecordClass::IN,
                 },
                 DnsQuestion {
                     name: "test.org".to_string(),
-                    qtype: 28,
-                    qclass: 1,
+                    qtype: RecordType::AAAA,
+                    qclass: RecordClass::IN,
                 },
                 DnsQuestion {
                     name: "foo.bar".to_string(),
-                    qtype: 1,
-                    qclass: 1,
+                    qtype: RecordType::A,
+                    qclass: RecordClass::IN,
                 },
             ],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         // Encode the packet
@@ -641,14 +807,14 @@ mod tests {
 
         let header = DnsPacketHeader {
             id: 0x5678,
-            qr: true,   // Response
-            opcode: 0,  // QUERY
-            aa: true,   // Authoritative
-            tc: false,  // Not truncated
-            rd: true,   // Recursion desired
-            ra: true,   // Recursion available
-            z: 0,       // Reserved
-            rcode: 0,   // NOERROR
+            qr: true, // Response
+            opcode: Opcode::QUERY,
+            aa: true,  // Authoritative
+            tc: false, // Not truncated
+            rd: true,  // Recursion desired
+            ra: true,  // Recursion available
+            z: 0,      // Reserved
+            rcode: Rcode::NOERROR,
             qdcount: 1, // One question
             ancount: 1, // One answer
             nscount: 0,
@@ -657,14 +823,14 @@ mod tests {
 
         let question = DnsQuestion {
             name: "example.com".to_string(),
-            qtype: 1,  // A record
-            qclass: 1, // IN class
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
         };
 
         let answer = DnsResourceRecord::new(
             "example.com".to_string(),
-            crate::response_builder::DNS_TYPE_A,
-            crate::response_builder::DNS_CLASS_IN,
+            RecordType::A,
+            RecordClass::IN,
             300,
             vec![192, 168, 1, 1],
         );
@@ -673,6 +839,8 @@ mod tests {
             header,
             questions: vec![question],
             answers: vec![answer],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         let result = codec.encode(packet, &mut buf);
@@ -736,4 +904,162 @@ mod tests {
         // Total expected length: 12 (header) + 17 (question) + 27 (answer) = 56
         assert_eq!(bytes.len(), 56);
     }
+
+    #[test]
+    fn test_dns_codec_encode_with_authority() {
+        use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion, DnsResourceRecord};
+
+        let mut codec = DnsCodec::new();
+        let mut buf = BytesMut::new();
+
+        let header = DnsPacketHeader {
+            id: 0x9abc,
+            qr: true,
+            opcode: Opcode::QUERY,
+            aa: false,
+            tc: false,
+            rd: true,
+            ra: true,
+            z: 0,
+            rcode: Rcode::NXDOMAIN,
+            qdcount: 1,
+            ancount: 0,
+            nscount: 0, // Corrected by the encoder
+            arcount: 0,
+        };
+
+        let question = DnsQuestion {
+            name: "missing.example.com".to_string(),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+        };
+
+        // Opaque SOA rdata; only its length matters here
+        let soa = DnsResourceRecord::new(
+            "example.com".to_string(),
+            RecordType::SOA,
+            RecordClass::IN,
+            900,
+            vec![0; 22],
+        );
+
+        let packet = DnsPacket {
+            header,
+            questions: vec![question],
+            answers: vec![],
+            authorities: vec![soa],
+            additionals: vec![],
+        };
+
+        codec.encode(packet, &mut buf).unwrap();
+        let bytes = buf.as_ref();
+
+        // ANCOUNT = 0, NSCOUNT = 1
+        assert_eq!(&bytes[6..10], &[0, 0, 0, 1]);
+
+        // The authority record follows the question directly
+        // "missing.example.com" = 21 bytes + QTYPE/QCLASS = 25
+        let authority_start = 12 + 25;
+        assert_eq!(bytes[authority_start], 7);
+        assert_eq!(&bytes[authority_start + 1..authority_start + 8], b"example");
+
+        // 12 (header) + 25 (question) + 13 (name) + 10 (fixed fields) + 22 (rdata)
+        assert_eq!(bytes.len(), 82);
+    }
+
+    #[test]
+    fn test_dns_codec_encode_truncated() {
+        use crate::edns::Edns;
+        use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion, DnsResourceRecord};
+
+        let header = DnsPacketHeader {
+            id: 0x4242,
+            qr: true,
+            opcode: Opcode::QUERY,
+            aa: false,
+            tc: false,
+            rd: true,
+            ra: false,
+            z: 0,
+            rcode: Rcode::NOERROR,
+            qdcount: 1,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        };
+        // 12 (header) + 17 (question) = 29 bytes, and 100 TXT records of 127
+        // bytes each (13 name, 10 fixed fields, 104 rdata)
+        let answer = DnsResourceRecord::new(
+            "example.com".to_string(),
+            RecordType::TXT,
+            RecordClass::IN,
+            300,
+            [&[103][..], &[b'x'; 103]].concat(),
+        );
+        let opt = Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+        .to_record();
+        let packet = DnsPacket {
+            header,
+            questions: vec![DnsQuestion {
+                name: "example.com".to_string(),
+                qtype: RecordType::TXT,
+                qclass: RecordClass::IN,
+            }],
+            answers: vec![answer; 100],
+            authorities: vec![],
+            additionals: vec![],
+        };
+
+        // Everything fits: nothing changes
+        let mut buf = BytesMut::new();
+        assert!(!encode_truncated(packet.clone(), 65535, &mut buf).unwrap());
+        assert_eq!(buf.len(), 29 + 100 * 127);
+        assert_eq!(encoded_len(&packet), buf.len());
+
+        // 512 bytes hold three answers, and TC is set
+        let mut buf = BytesMut::new();
+        assert!(encode_truncated(packet.clone(), 512, &mut buf).unwrap());
+        assert_eq!(buf.len(), 29 + 3 * 127);
+        assert_eq!(buf[2] & 0x02, 0x02);
+        assert_eq!(&buf[6..8], &[0, 3]);
+
+        // The OPT record (11 bytes) is kept, at the expense of an answer
+        let packet = DnsPacket {
+            additionals: vec![opt.clone()],
+            ..packet
+        };
+        let mut buf = BytesMut::new();
+        assert!(encode_truncated(packet.clone(), 512, &mut buf).unwrap());
+        assert_eq!(buf.len(), 29 + 11 + 3 * 127);
+        assert_eq!(&buf[6..12], &[0, 3, 0, 0, 0, 1]);
+
+        // Other additional records (27 bytes each) are dropped first, and
+        // losing them alone doesn't set TC
+        let glue = DnsResourceRecord::new(
+            "example.com".to_string(),
+            RecordType::A,
+            RecordClass::IN,
+            300,
+            vec![192, 0, 2, 1],
+        );
+        let packet = DnsPacket {
+            answers: packet.answers[..3].to_vec(),
+            additionals: vec![glue, opt],
+            ..packet
+        };
+        let mut buf = BytesMut::new();
+        assert!(!encode_truncated(packet.clone(), 440, &mut buf).unwrap());
+        assert_eq!(buf.len(), 29 + 11 + 3 * 127);
+        assert_eq!(buf[2] & 0x02, 0);
+        assert_eq!(&buf[10..12], &[0, 1]);
+        let mut buf = BytesMut::new();
+        assert!(!encode_truncated(packet, 1232, &mut buf).unwrap());
+        assert_eq!(&buf[10..12], &[0, 2]);
+    }
 }
@@ -0,0 +1,166 @@
+//! `--config`: server options from a TOML file
+//!
+//! Each top-level key is the long name of a command-line option, with its
+//! value as it would be given there; options that repeat take an array, and
+//! switches a boolean. Underscores may stand in for dashes:
+//!
+//! ```toml
+//! resolver = ["1.1.1.1:53", "9.9.9.9:53"]
+//! listen = "0.0.0.0:53"
+//! cache-size = 50000
+//! log-level = "warn"
+//! route = ["name=ads,domains=/etc/dns/ads.txt,to=blackhole"]
+//! no-system-resolvers = true
+//! ```
+//!
+//! The file's settings are turned into flags and parsed with the command
+//! line, so they are checked the same way. An option given on the command
+//! line wins: the file's value for it is left out altogether, so a repeatable
+//! option takes the command line's values rather than adding to the file's.
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use toml::{Table, Value};
+
+use crate::errors::ConfigError;
+
+/// The options of a config file as command-line flags, leaving out those
+/// `matches` got from the command line
+pub fn load(
+    path: &Path,
+    command: &Command,
+    matches: &ArgMatches,
+) -> Result<Vec<OsString>, ConfigError> {
+    let contents = std::fs::read_to_string(path)?;
+    flags(&contents.parse()?, command, matches)
+}
+
+fn flags(
+    table: &Table,
+    command: &Command,
+    matches: &ArgMatches,
+) -> Result<Vec<OsString>, ConfigError> {
+    let mut flags = Vec::new();
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()) && configurable(arg))
+            .ok_or_else(|| ConfigError::UnknownSetting(key.clone()))?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        let flag = format!("--{}", long);
+        // Switches are given by their presence alone
+        if !arg.get_action().takes_values() {
+            match value {
+                Value::Boolean(true) => flags.push(flag.into()),
+                Value::Boolean(false) => {}
+                _ => return Err(ConfigError::UnsupportedValue(key.clone())),
+            }
+            continue;
+        }
+
+        let values = match value {
+            Value::Array(values) if matches!(arg.get_action(), ArgAction::Append) => {
+                values.iter().collect()
+            }
+            Value::Array(_) => return Err(ConfigError::NotRepeatable(key.clone())),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                Value::Integer(n) => n.to_string(),
+                Value::Float(n) => n.to_string(),
+                Value::Boolean(b) => b.to_string(),
+                _ => return Err(ConfigError::UnsupportedValue(key.clone())),
+            };
+            flags.push(flag.clone().into());
+            flags.push(value.into());
+        }
+    }
+    Ok(flags)
+}
+
+/// Whether an option can be set from a file: not `--config` itself, nor
+/// `--help` and the like
+fn configurable(arg: &Arg) -> bool {
+    arg.get_id() != "config"
+        && !matches!(
+            arg.get_action(),
+            ArgAction::Help | ArgAction::HelpShort | ArgAction::HelpLong | ArgAction::Version
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Args;
+    use clap::CommandFactory;
+
+    fn flags_for(config: &str, cli: &[&str]) -> Result<Vec<String>, ConfigError> {
+        let command = Args::command();
+        let matches = command
+            .clone()
+            .try_get_matches_from(std::iter::once("dns-server").chain(cli.iter().copied()))
+            .unwrap();
+        let flags = flags(&config.parse().unwrap(), &command, &matches)?;
+        Ok(flags
+            .into_iter()
+            .map(|flag| flag.into_string().unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn test_config_flags() {
+        let config = r#"
+            resolver = ["1.1.1.1:53", "9.9.9.9:53"]
+            cache_size = 500
+            no-system-resolvers = true
+            transparent = false
+        "#;
+        assert_eq!(
+            flags_for(config, &[]).unwrap(),
+            [
+                "--cache-size",
+                "500",
+                "--no-system-resolvers",
+                "--resolver",
+                "1.1.1.1:53",
+                "--resolver",
+                "9.9.9.9:53"
+            ]
+        );
+
+        // The command line replaces the file's values, lists included
+        assert_eq!(
+            flags_for(config, &["--cache-size", "10", "-r", "8.8.8.8:53"]).unwrap(),
+            ["--no-system-resolvers"]
+        );
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert!(matches!(
+            flags_for("bogus = 1", &[]),
+            Err(ConfigError::UnknownSetting(key)) if key == "bogus"
+        ));
+        assert!(matches!(
+            flags_for("config = '/etc/other.toml'", &[]),
+            Err(ConfigError::UnknownSetting(_))
+        ));
+        assert!(matches!(
+            flags_for("listen = ['127.0.0.1:53', '[::1]:53']", &[]),
+            Err(ConfigError::NotRepeatable(_))
+        ));
+        assert!(matches!(
+            flags_for("daemon = 'yes'", &[]),
+            Err(ConfigError::UnsupportedValue(_))
+        ));
+    }
+}
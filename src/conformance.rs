@@ -0,0 +1,427 @@
+//! `dns-server conformance`: checks the wire format handling against test vectors
+//!
+//! A vector is a JSON file holding a packet as a client sent it and what the
+//! server should make of it:
+//!
+//! ```json
+//! {
+//!   "description": "A query answered from a local record",
+//!   "query": "1234 0100 0001 0000 0000 0000 076578616d706c6503636f6d00 0001 0001",
+//!   "parsed": { "rd": true, "questions": [{ "name": "example.com", "type": "A" }] },
+//!   "round_trip": true,
+//!   "records": ["example.com A 192.0.2.1 300"],
+//!   "response": "1234 8100 0001 0001 ...",
+//!   "response_parsed": { "rcode": "NOERROR", "answers": [{ "data": "192.0.2.1" }] }
+//! }
+//! ```
+//!
+//! Only `query` is required. Hex may be spaced out freely. `parsed` and
+//! `response_parsed` are compared field by field, so a vector only spells out
+//! what it is about; `rejected` expects the parser to refuse the query and
+//! `no_response` expects the server to stay silent. Queries are answered by
+//! the real query pipeline, with the given local records, the given
+//! `server_name` if any, and no upstream, so names without local records
+//! fail to resolve.
+//!
+//! Every `*.json` file of each directory is a vector; the bundled ones live
+//! in `conformance/`.
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context};
+use bytes::BytesMut;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::UdpSocket;
+
+use crate::codec;
+use crate::geoip::Locator;
+use crate::handlers::cache_handler::{CacheConfig, CacheHandle};
+use crate::handlers::dedup_handler::DedupHandle;
+use crate::handlers::inflight_handler::InflightHandle;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::handlers::query_handler::QueryActorHandle;
+use crate::hedge::HedgedResolver;
+use crate::hostname::HostnamePolicy;
+use crate::identity::Identity;
+use crate::local_records::{LocalRecord, RecordData};
+use crate::metrics::Metrics;
+use crate::parsers::parse_dns_packet;
+use crate::pipeline::Pipeline;
+use crate::processor::{process_dns_query, QueryContext, Responder, DUPLICATE_WINDOW};
+use crate::protocol::{DnsPacket, DnsResourceRecord};
+use crate::query_log::QueryLog;
+use crate::ttl::TtlLimits;
+use crate::udp::UdpSender;
+
+/// How long to wait for a response that has already been sent over loopback
+const RESPONSE_WAIT: Duration = Duration::from_millis(200);
+
+/// One test vector, as read from its file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Vector {
+    /// What the vector is about; shown when it fails
+    #[serde(default)]
+    description: String,
+    /// The packet as received, in hex
+    query: String,
+    /// Expected parse of the query
+    parsed: Option<Value>,
+    /// The parser must refuse the query
+    #[serde(default)]
+    rejected: bool,
+    /// Encoding the parsed query gives back the exact bytes received
+    #[serde(default)]
+    round_trip: bool,
+    /// Local records served while answering, as given to `add`
+    #[serde(default)]
+    records: Vec<String>,
+    /// The server's own name, as given to `--server-name`
+    server_name: Option<String>,
+    /// Expected response, in hex
+    response: Option<String>,
+    /// Expected parse of the response
+    response_parsed: Option<Value>,
+    /// The server must not answer at all
+    #[serde(default)]
+    no_response: bool,
+}
+
+/// Run the vectors of every directory, printing a line per vector. Fails if
+/// any vector does.
+pub async fn run(dirs: &[PathBuf]) -> anyhow::Result<()> {
+    let mut total = 0;
+    let mut failed = 0;
+    for dir in dirs {
+        for path in vector_files(dir)? {
+            total += 1;
+            match check_file(&path).await {
+                Ok(()) => println!("ok    {}", path.display()),
+                Err(e) => {
+                    failed += 1;
+                    println!("FAIL  {}: {:#}", path.display(), e);
+                }
+            }
+        }
+    }
+    println!("{} passed, {} failed", total - failed, failed);
+    ensure!(failed == 0, "{} of {} vectors failed", failed, total);
+    Ok(())
+}
+
+/// The vector files of a directory, in name order
+fn vector_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("could not read vector directory {}", dir.display()))?;
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+async fn check_file(path: &Path) -> anyhow::Result<()> {
+    let text = tokio::fs::read_to_string(path).await?;
+    let vector: Vector = serde_json::from_str(&text).context("invalid vector")?;
+    check_vector(&vector).await.with_context(|| {
+        if vector.description.is_empty() {
+            "mismatch".to_string()
+        } else {
+            vector.description.clone()
+        }
+    })
+}
+
+async fn check_vector(vector: &Vector) -> anyhow::Result<()> {
+    let query = decode_hex(&vector.query).context("query")?;
+
+    // The parser, as the listener runs it
+    let parsed = codec::decode_packet(&query);
+    match (parsed, vector.rejected) {
+        (Ok(Some(_)), true) => bail!("the query was parsed but should be rejected"),
+        (Ok(Some(packet)), false) => {
+            if let Some(expected) = &vector.parsed {
+                compare(expected, &packet_view(&packet), "parsed")?;
+            }
+            // The encoder, on the same packet
+            if vector.round_trip {
+                let mut encoded = BytesMut::new();
+                codec::encode_packet(&packet, &mut encoded)?;
+                ensure!(
+                    encoded[..] == query[..],
+                    "re-encoded query differs:\n  expected {}\n  got      {}",
+                    encode_hex(&query),
+                    encode_hex(&encoded)
+                );
+            }
+        }
+        (Ok(None), false) => bail!("the query is shorter than a header"),
+        (Ok(None) | Err(_), true) => {}
+        (Err(e), false) => bail!("the query was rejected: {}", e),
+    }
+
+    // The query pipeline, answering from a socket of its own
+    let response = answer(&query, &vector.records, vector.server_name.as_deref()).await?;
+    if vector.no_response {
+        ensure!(response.is_none(), "the query was answered");
+        return Ok(());
+    }
+    if vector.response.is_none() && vector.response_parsed.is_none() {
+        return Ok(());
+    }
+    let Some(response) = response else {
+        bail!("no response");
+    };
+    if let Some(expected) = &vector.response {
+        let expected = decode_hex(expected).context("response")?;
+        ensure!(
+            response == expected,
+            "response differs:\n  expected {}\n  got      {}",
+            encode_hex(&expected),
+            encode_hex(&response)
+        );
+    }
+    if let Some(expected) = &vector.response_parsed {
+        let packet = match parse_dns_packet(&response) {
+            Ok((_, packet)) => packet,
+            Err(e) => bail!("the response does not parse: {}", e),
+        };
+        compare(expected, &packet_view(&packet), "response")?;
+    }
+    Ok(())
+}
+
+/// Send a query through the query pipeline of a fresh, upstream-less
+/// server holding `records` and named `server_name`, returning the
+/// response if there is one
+async fn answer(
+    query: &[u8],
+    records: &[String],
+    server_name: Option<&str>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let local_records = LocalRecordsHandle::new();
+    for record in records {
+        let record: LocalRecord = record
+            .parse()
+            .with_context(|| format!("record {:?}", record))?;
+        local_records.add(record).await;
+    }
+    let metrics = Arc::new(Metrics::new(1));
+    // No upstreams at all
+    let resolver = HedgedResolver::new(&[], None, &[], false, Arc::clone(&metrics));
+    let context = QueryContext {
+        query_handle: QueryActorHandle::new(resolver, 1, Arc::clone(&metrics)),
+        local_records,
+        query_log: QueryLog::new(),
+        metrics: Arc::clone(&metrics),
+        dedup: DedupHandle::new(DUPLICATE_WINDOW),
+        cache: CacheHandle::new(
+            CacheConfig {
+                max_entries: 0,
+                ..CacheConfig::default()
+            },
+            metrics,
+        ),
+        inflight: InflightHandle::new(),
+        names: Arc::default(),
+        hostname_policy: HostnamePolicy::Liberal,
+        ttl_limits: TtlLimits::default(),
+        locator: Arc::new(Locator::default()),
+        acl: Arc::default(),
+        quotas: None,
+        shard: 0,
+        proxy: None,
+        router: Arc::default(),
+        blocklist: Arc::default(),
+        identity: Arc::new(Identity::new(
+            server_name.unwrap_or_default(),
+            Vec::new(),
+            Vec::new(),
+        )),
+        primaries: Arc::default(),
+        dnssec: Arc::default(),
+        reverse: Arc::default(),
+        zones: Arc::default(),
+        groups: Arc::default(),
+        pipeline: Pipeline::default(),
+    };
+
+    let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    process_dns_query(
+        query.to_vec(),
+        client.local_addr()?,
+        context,
+        Responder::Udp(UdpSender::Tokio(server)),
+    )
+    .await;
+
+    // A response, if any, was sent before processing returned
+    let mut buf = vec![0; 65535];
+    match tokio::time::timeout(RESPONSE_WAIT, client.recv(&mut buf)).await {
+        Ok(len) => {
+            buf.truncate(len?);
+            Ok(Some(buf))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// A packet as the JSON structure vectors compare against
+fn packet_view(packet: &DnsPacket) -> Value {
+    let header = &packet.header;
+    let questions: Vec<Value> = packet
+        .questions
+        .iter()
+        .map(|question| {
+            json!({
+                "name": question.name,
+                "type": question.qtype,
+                "class": question.qclass,
+            })
+        })
+        .collect();
+    json!({
+        "id": header.id,
+        "qr": header.qr,
+        "opcode": header.opcode,
+        "aa": header.aa,
+        "tc": header.tc,
+        "rd": header.rd,
+        "ra": header.ra,
+        "z": header.z,
+        "rcode": header.rcode,
+        "qdcount": header.qdcount,
+        "ancount": header.ancount,
+        "nscount": header.nscount,
+        "arcount": header.arcount,
+        "questions": questions,
+        "answers": packet.answers.iter().map(record_view).collect::<Vec<_>>(),
+        "authorities": packet.authorities.iter().map(record_view).collect::<Vec<_>>(),
+        "additionals": packet.additionals.iter().map(record_view).collect::<Vec<_>>(),
+    })
+}
+
+/// A record with its data in presentation form, or in hex for types local
+/// records can't hold
+fn record_view(record: &DnsResourceRecord) -> Value {
+    let data = match RecordData::from_rdata(record.rtype, &record.rdata) {
+        Ok(data) => data.to_string(),
+        Err(_) => encode_hex(&record.rdata),
+    };
+    json!({
+        "name": record.name,
+        "type": record.rtype,
+        "class": record.rclass,
+        "ttl": record.ttl,
+        "data": data,
+    })
+}
+
+/// Check that `actual` has every field of `expected`, with the same value.
+/// Arrays must have the same length and match element by element.
+fn compare(expected: &Value, actual: &Value, path: &str) -> anyhow::Result<()> {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            for (key, expected) in expected {
+                let path = format!("{}.{}", path, key);
+                let Some(actual) = actual.get(key) else {
+                    bail!("{}: no such field", path);
+                };
+                compare(expected, actual, &path)?;
+            }
+            Ok(())
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            ensure!(
+                expected.len() == actual.len(),
+                "{}: expected {} entries, got {}",
+                path,
+                expected.len(),
+                actual.len()
+            );
+            for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+                compare(expected, actual, &format!("{}[{}]", path, index))?;
+            }
+            Ok(())
+        }
+        _ => {
+            ensure!(
+                expected == actual,
+                "{}: expected {}, got {}",
+                path,
+                expected,
+                actual
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Bytes from hex digits, ignoring whitespace
+fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    ensure!(digits.len().is_multiple_of(2), "odd number of hex digits");
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)?;
+            u8::from_str_radix(pair, 16).with_context(|| format!("invalid hex {:?}", pair))
+        })
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_checks_given_fields_only() {
+        let actual = json!({"id": 1, "rd": true, "questions": [{"name": "a.", "type": "A"}]});
+        assert!(compare(&json!({"rd": true}), &actual, "parsed").is_ok());
+        assert!(compare(&json!({"questions": [{"type": "A"}]}), &actual, "parsed").is_ok());
+
+        let error = compare(&json!({"questions": [{"type": "MX"}]}), &actual, "parsed")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, r#"parsed.questions[0].type: expected "MX", got "A""#);
+        assert!(compare(&json!({"questions": []}), &actual, "parsed").is_err());
+        assert!(compare(&json!({"ttl": 1}), &actual, "parsed").is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(decode_hex("12 3a\n ff").unwrap(), vec![0x12, 0x3a, 0xff]);
+        assert!(decode_hex("123").is_err());
+        assert!(decode_hex("zz").is_err());
+        assert_eq!(encode_hex(&[0, 0xab]), "00ab");
+    }
+
+    #[tokio::test]
+    async fn test_bundled_vectors() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("conformance");
+        for path in vector_files(&dir).unwrap() {
+            if let Err(e) = check_file(&path).await {
+                panic!("{}: {:#}", path.display(), e);
+            }
+        }
+    }
+}
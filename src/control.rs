@@ -0,0 +1,1144 @@
+//! Control socket
+//!
+//! A line-based admin protocol served over TCP (normally bound to localhost).
+//! Each request is a single line; each response is zero or more lines followed
+//! by an empty line. `dns-server ctl` provides an interactive shell on top of it,
+//! but any line-oriented client (e.g. `nc`) works as well.
+//!
+//! A tenant with an admin token only accepts commands on a connection that
+//! has sent `auth <token>` first; see `tenant`. The socket can also be served
+//! over TLS, taking client certificates instead; see `control_tls`.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info};
+
+use crate::actors::messages::{CacheKey, Resolution};
+use crate::blocklist::{AllowRule, PublishedBlocklist};
+use crate::control_tls::ControlTls;
+use crate::errors::{ControlError, LocalRecordError};
+use crate::handlers::cache_handler::CacheHandle;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::local_records::{
+    export_records, import_records, normalize_name, parse_record_type, validate_name, LocalRecord,
+    RecordFormat, DEFAULT_LOCAL_TTL,
+};
+use crate::metrics::Metrics;
+use crate::profiling::{write_profile, Profile, MAX_CPU_SECONDS};
+use crate::query_log::{answer_summary, render_pretty, QueryLog};
+use crate::quota::ClientQuotas;
+use crate::registry::RecordType;
+use crate::reload::Reloader;
+use crate::reverse::ClasslessBlock;
+use crate::stats::{QueryStats, TopKind, DEFAULT_TOP};
+
+const HELP: &str = "\
+help                              show this help
+auth <token>                      authenticate, if the server requires a token
+records                           list local records
+add <name> <type> <value> [ttl] [options]
+                                  add a local record (A, AAAA, CNAME, MX, TXT, PTR);
+                                  options: country=XX, continent=XX or site=NAME
+                                  (location-aware answers),
+                                  weight=N (traffic split), select=round-robin
+                                  (answers in turn), check=<port>,
+                                  check=http:<port>[/path] and role=backup
+                                  (failover)
+remove <name> [type]              remove local records for a name
+export [json|csv]                 dump local records (default json)
+import [--dry-run] [--replace] <json>
+                                  load records from a JSON array, showing the diff
+classless <network>/<len> [ttl]   add RFC 2317 CNAMEs delegating reverse DNS for a
+                                  sub-/24 block (e.g. 192.168.1.64/26)
+versions                          list recent versions of the local records
+diff <from> [to] [zone]           compare two versions (to defaults to the current one),
+                                  optionally only at or below a zone
+rollback <version> [zone]         restore a version, or only one zone of it
+allowlist [add|remove <domain>]   list the names let through the blocklist, or
+                                  change them (example.com, or *.example.com for
+                                  everything under it)
+profile cpu <seconds> <path>      write a CPU profile (flamegraph if path ends in .svg,
+                                  pprof otherwise) on the server's host
+profile heap <path>               write a heap profile on the server's host
+reload-blocklists                 read the blocklists again and swap them in
+reload-zone <zone>                read the zone's file again and swap it in
+tail [--color]                    stream one line per query until an empty line is sent
+cache                             show how full the cache is and what it has evicted
+cache <name> [type]               show the answers cached for a name, with their TTLs
+                                  counted down
+block [<domain>]                  list the domains blocked here, or block one and
+                                  everything under it until unblocked
+unblock <domain>                  lift a block made with block
+stats                             show server counters
+top <domains|blocked|clients> [n] show the most queried names, blocked names or
+                                  clients of the last hour (default 10)
+rates                             show queries per second and the share blocked over
+                                  the last 10 seconds, minute, 5 minutes and hour
+quit                              close the connection";
+
+/// Handles to the subsystems the control socket can operate on
+#[derive(Clone, Debug)]
+pub struct ControlContext {
+    pub local_records: LocalRecordsHandle,
+    pub query_log: QueryLog,
+    pub metrics: Arc<Metrics>,
+    pub blocklist: Arc<PublishedBlocklist>,
+    pub cache: CacheHandle,
+    /// The tenant's query statistics, for `top` and `rates`
+    pub stats: Arc<QueryStats>,
+    /// Each client's quota counters, if the tenant sets quotas
+    pub quotas: Option<Arc<ClientQuotas>>,
+    /// Token connections must present before any other command, if set
+    pub token: Option<Arc<str>>,
+    /// The tenant's blocklists and zone files, for reloading
+    pub reloader: Reloader,
+    /// The certificates connections must present instead, if the socket
+    /// speaks TLS
+    pub tls: Option<ControlTls>,
+}
+
+/// A parsed control command
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    Help,
+    Auth(String),
+    Records,
+    Add(LocalRecord),
+    Remove {
+        name: String,
+        rtype: Option<RecordType>,
+    },
+    Export(RecordFormat),
+    Import {
+        records: Vec<LocalRecord>,
+        replace: bool,
+        dry_run: bool,
+    },
+    Classless {
+        block: ClasslessBlock,
+        ttl: u32,
+    },
+    Versions,
+    Diff {
+        from: u32,
+        to: Option<u32>,
+        zone: Option<String>,
+    },
+    Rollback {
+        serial: u32,
+        zone: Option<String>,
+    },
+    Allowlist,
+    Allow(AllowRule),
+    Disallow(AllowRule),
+    ReloadBlocklists,
+    ReloadZone(String),
+    Tail {
+        color: bool,
+    },
+    Profile {
+        profile: Profile,
+        path: PathBuf,
+    },
+    Cache,
+    CacheLookup {
+        name: String,
+        rtype: Option<RecordType>,
+    },
+    Blocked,
+    Block(String),
+    Unblock(String),
+    Stats,
+    Top {
+        kind: TopKind,
+        count: usize,
+    },
+    Rates,
+    Quit,
+}
+
+impl ControlCommand {
+    /// Parse a single command line
+    pub fn parse(line: &str) -> Result<Self, ControlError> {
+        let line = line.trim();
+        let (verb, args) = match line.split_once(char::is_whitespace) {
+            Some((verb, args)) => (verb, args.trim()),
+            None => (line, ""),
+        };
+
+        match verb.to_ascii_lowercase().as_str() {
+            "help" | "?" => Ok(ControlCommand::Help),
+            "auth" => match args {
+                "" => Err(ControlError::Usage("auth <token>")),
+                token => Ok(ControlCommand::Auth(token.to_string())),
+            },
+            "records" | "list" => Ok(ControlCommand::Records),
+            "add" => {
+                if args.is_empty() {
+                    return Err(ControlError::Usage("add <name> <type> <value> [ttl]"));
+                }
+                Ok(ControlCommand::Add(args.parse()?))
+            }
+            "remove" | "rm" => {
+                let mut parts = args.split_whitespace();
+                let name = parts
+                    .next()
+                    .ok_or(ControlError::Usage("remove <name> [type]"))?;
+                let rtype = parts.next().map(parse_record_type).transpose()?;
+                if parts.next().is_some() {
+                    return Err(ControlError::Usage("remove <name> [type]"));
+                }
+                Ok(ControlCommand::Remove {
+                    name: name.to_string(),
+                    rtype,
+                })
+            }
+            "export" => {
+                let format = match args {
+                    "" => RecordFormat::Json,
+                    format => format.parse()?,
+                };
+                Ok(ControlCommand::Export(format))
+            }
+            "import" => {
+                const USAGE: &str = "import [--dry-run] [--replace] <json>";
+                let mut replace = false;
+                let mut dry_run = false;
+                let mut payload = args;
+                while let Some(flag) = payload.strip_prefix("--") {
+                    let (flag, rest) = flag.split_once(char::is_whitespace).unwrap_or((flag, ""));
+                    match flag {
+                        "dry-run" => dry_run = true,
+                        "replace" => replace = true,
+                        _ => return Err(ControlError::Usage(USAGE)),
+                    }
+                    payload = rest.trim_start();
+                }
+                if payload.is_empty() {
+                    return Err(ControlError::Usage(USAGE));
+                }
+                Ok(ControlCommand::Import {
+                    records: import_records(payload, RecordFormat::Json)?,
+                    replace,
+                    dry_run,
+                })
+            }
+            "classless" => {
+                const USAGE: &str = "classless <network>/<len> [ttl]";
+                let mut parts = args.split_whitespace();
+                let block = parts.next().ok_or(ControlError::Usage(USAGE))?.parse()?;
+                let ttl = match parts.next() {
+                    Some(ttl) => ttl
+                        .parse()
+                        .map_err(|_| LocalRecordError::InvalidValue(ttl.to_string()))?,
+                    None => DEFAULT_LOCAL_TTL,
+                };
+                if parts.next().is_some() {
+                    return Err(ControlError::Usage(USAGE));
+                }
+                Ok(ControlCommand::Classless { block, ttl })
+            }
+            "versions" => Ok(ControlCommand::Versions),
+            "diff" => {
+                const USAGE: &str = "diff <from> [to] [zone]";
+                let mut parts = args.split_whitespace();
+                let from = parse_serial(parts.next().ok_or(ControlError::Usage(USAGE))?)?;
+                let mut next = parts.next();
+                let to = match next.map(str::parse::<u32>) {
+                    Some(Ok(to)) => {
+                        next = parts.next();
+                        Some(to)
+                    }
+                    _ => None,
+                };
+                let zone = next.map(str::to_string);
+                if parts.next().is_some() {
+                    return Err(ControlError::Usage(USAGE));
+                }
+                Ok(ControlCommand::Diff { from, to, zone })
+            }
+            "rollback" => {
+                const USAGE: &str = "rollback <version> [zone]";
+                let mut parts = args.split_whitespace();
+                let serial = parse_serial(parts.next().ok_or(ControlError::Usage(USAGE))?)?;
+                let zone = parts.next().map(str::to_string);
+                if parts.next().is_some() {
+                    return Err(ControlError::Usage(USAGE));
+                }
+                Ok(ControlCommand::Rollback { serial, zone })
+            }
+            "allowlist" => {
+                const USAGE: &str = "allowlist [add|remove <domain>]";
+                let parts: Vec<&str> = args.split_whitespace().collect();
+                match parts[..] {
+                    [] => Ok(ControlCommand::Allowlist),
+                    ["add", rule] => Ok(ControlCommand::Allow(rule.parse()?)),
+                    ["remove" | "rm", rule] => Ok(ControlCommand::Disallow(rule.parse()?)),
+                    _ => Err(ControlError::Usage(USAGE)),
+                }
+            }
+            "reload-blocklists" => Ok(ControlCommand::ReloadBlocklists),
+            "reload-zone" => match args {
+                zone if !zone.is_empty() && !zone.contains(char::is_whitespace) => {
+                    validate_name(zone)?;
+                    Ok(ControlCommand::ReloadZone(normalize_name(zone)))
+                }
+                _ => Err(ControlError::Usage("reload-zone <zone>")),
+            },
+            "tail" => match args {
+                "" => Ok(ControlCommand::Tail { color: false }),
+                "--color" => Ok(ControlCommand::Tail { color: true }),
+                _ => Err(ControlError::Usage("tail [--color]")),
+            },
+            "profile" => {
+                const USAGE: &str = "profile cpu <seconds> <path> | profile heap <path>";
+                let parts: Vec<&str> = args.split_whitespace().collect();
+                let (profile, path) = match parts[..] {
+                    ["cpu", seconds, path] => {
+                        let seconds = seconds
+                            .parse()
+                            .ok()
+                            .filter(|seconds| (1..=MAX_CPU_SECONDS).contains(seconds))
+                            .ok_or_else(|| {
+                                LocalRecordError::InvalidValue(format!(
+                                    "seconds '{}' (1 to {})",
+                                    seconds, MAX_CPU_SECONDS
+                                ))
+                            })?;
+                        (Profile::Cpu { seconds }, path)
+                    }
+                    ["heap", path] => (Profile::Heap, path),
+                    _ => return Err(ControlError::Usage(USAGE)),
+                };
+                Ok(ControlCommand::Profile {
+                    profile,
+                    path: PathBuf::from(path),
+                })
+            }
+            "cache" => {
+                const USAGE: &str = "cache [<name> [type]]";
+                let parts: Vec<&str> = args.split_whitespace().collect();
+                let (name, rtype) = match parts[..] {
+                    [] => return Ok(ControlCommand::Cache),
+                    [name] => (name, None),
+                    [name, rtype] => (name, Some(parse_record_type(rtype)?)),
+                    _ => return Err(ControlError::Usage(USAGE)),
+                };
+                validate_name(name)?;
+                Ok(ControlCommand::CacheLookup {
+                    name: normalize_name(name),
+                    rtype,
+                })
+            }
+            "block" => match args {
+                "" => Ok(ControlCommand::Blocked),
+                domain if !domain.contains(char::is_whitespace) => {
+                    validate_name(domain)?;
+                    Ok(ControlCommand::Block(normalize_name(domain)))
+                }
+                _ => Err(ControlError::Usage("block [<domain>]")),
+            },
+            "unblock" => match args {
+                domain if !domain.is_empty() && !domain.contains(char::is_whitespace) => {
+                    validate_name(domain)?;
+                    Ok(ControlCommand::Unblock(normalize_name(domain)))
+                }
+                _ => Err(ControlError::Usage("unblock <domain>")),
+            },
+            "stats" => Ok(ControlCommand::Stats),
+            "top" => {
+                const USAGE: &str = "top <domains|blocked|clients> [n]";
+                let parts: Vec<&str> = args.split_whitespace().collect();
+                let (kind, count) = match parts[..] {
+                    [kind] => (kind, None),
+                    [kind, count] => (kind, Some(count)),
+                    _ => return Err(ControlError::Usage(USAGE)),
+                };
+                let kind = kind.parse().map_err(LocalRecordError::InvalidValue)?;
+                let count = match count {
+                    Some(count) => {
+                        count
+                            .parse()
+                            .ok()
+                            .filter(|count| *count > 0)
+                            .ok_or_else(|| {
+                                LocalRecordError::InvalidValue(format!("count '{}'", count))
+                            })?
+                    }
+                    None => DEFAULT_TOP,
+                };
+                Ok(ControlCommand::Top { kind, count })
+            }
+            "rates" => Ok(ControlCommand::Rates),
+            "quit" | "exit" => Ok(ControlCommand::Quit),
+            other => Err(ControlError::UnknownCommand(other.to_string())),
+        }
+    }
+}
+
+fn parse_serial(s: &str) -> Result<u32, ControlError> {
+    s.parse()
+        .map_err(|_| LocalRecordError::InvalidValue(format!("version '{}'", s)).into())
+}
+
+/// Accept control connections until the listener fails
+pub async fn run_control_server(addr: SocketAddr, ctx: ControlContext) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Control socket listening on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            debug!("Control connection from {}", peer);
+            if let Err(e) = accept(stream, ctx).await {
+                error!("Control connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Serve a connection, after the TLS handshake if the socket speaks TLS
+async fn accept(stream: TcpStream, ctx: ControlContext) -> std::io::Result<()> {
+    let Some(tls) = &ctx.tls else {
+        let authenticated = ctx.token.is_none();
+        return handle_connection(stream, ctx, authenticated).await;
+    };
+    let mut stream = tls.acceptor().accept(stream).await?;
+    let certificate = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certificates| certificates.first());
+    if !tls.admits(certificate) {
+        let response = format!("error: {}\n\n", ControlError::CertificateNotAllowed);
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            ControlError::CertificateNotAllowed.to_string(),
+        ));
+    }
+    match handle_connection(stream, ctx, true).await {
+        // Clients that hang up without ending the TLS session are done too
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+        result => result,
+    }
+}
+
+async fn handle_connection<S>(
+    stream: S,
+    ctx: ControlContext,
+    mut authenticated: bool,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match ControlCommand::parse(&line) {
+            Ok(ControlCommand::Quit) => break,
+            Ok(ControlCommand::Auth(token)) => {
+                let response = match &ctx.token {
+                    Some(expected) if !token_matches(expected, &token) => {
+                        format!("error: {}\n", ControlError::InvalidToken)
+                    }
+                    _ => {
+                        authenticated = true;
+                        "ok: authenticated\n".to_string()
+                    }
+                };
+                writer.write_all(response.as_bytes()).await?
+            }
+            Ok(ControlCommand::Help) => execute(ControlCommand::Help, &ctx, &mut writer).await?,
+            Ok(_) if !authenticated => {
+                writer
+                    .write_all(format!("error: {}\n", ControlError::AuthRequired).as_bytes())
+                    .await?
+            }
+            Ok(ControlCommand::Tail { color }) => {
+                tail(&ctx, color, &mut lines, &mut writer).await?
+            }
+            Ok(command) => execute(command, &ctx, &mut writer).await?,
+            Err(e) => {
+                writer
+                    .write_all(format!("error: {}\n", e).as_bytes())
+                    .await?
+            }
+        }
+
+        // An empty line marks the end of the response
+        writer.write_all(b"\n").await?;
+        writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+/// Compare tokens in time independent of where they differ
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn execute<W>(
+    command: ControlCommand,
+    ctx: &ControlContext,
+    out: &mut W,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let response = match command {
+        ControlCommand::Help => format!("{}\n", HELP),
+        ControlCommand::Records => {
+            let records = ctx.local_records.list().await;
+            if records.is_empty() {
+                "no local records\n".to_string()
+            } else {
+                records
+                    .iter()
+                    .map(|record| format!("{}\n", record))
+                    .collect()
+            }
+        }
+        ControlCommand::Add(record) => {
+            let line = record.to_string();
+            if ctx.local_records.add(record).await {
+                info!("Control: added local record {}", line);
+                format!("ok: added {}\n", line)
+            } else {
+                format!("ok: {} already present\n", line)
+            }
+        }
+        ControlCommand::Remove { name, rtype } => {
+            let removed = ctx.local_records.remove(name.clone(), rtype).await;
+            info!("Control: removed {} local record(s) for {}", removed, name);
+            format!("ok: removed {} record(s)\n", removed)
+        }
+        ControlCommand::Export(format) => {
+            let records = ctx.local_records.list().await;
+            export_records(&records, format)
+        }
+        ControlCommand::Import {
+            records,
+            replace,
+            dry_run,
+        } => {
+            let diff = ctx.local_records.import(records, replace, dry_run).await;
+            if dry_run {
+                format!("{}\ndry run: no changes made\n", diff)
+            } else {
+                info!(
+                    "Control: imported local records ({} added, {} removed)",
+                    diff.added.len(),
+                    diff.removed.len()
+                );
+                format!("{}\n", diff)
+            }
+        }
+        ControlCommand::Classless { block, ttl } => {
+            let diff = ctx
+                .local_records
+                .import(block.delegation_cnames(ttl), false, false)
+                .await;
+            info!(
+                "Control: delegated reverse DNS for {} ({} CNAMEs added)",
+                block,
+                diff.added.len()
+            );
+            format!(
+                "ok: {} CNAME(s) added for {}; serve its PTR records under {}\n",
+                diff.added.len(),
+                block,
+                block.zone_name()
+            )
+        }
+        ControlCommand::Versions => ctx
+            .local_records
+            .versions()
+            .await
+            .iter()
+            .map(|version| format!("{}\n", version))
+            .collect(),
+        ControlCommand::Diff { from, to, zone } => {
+            match ctx.local_records.diff(from, to, zone).await {
+                Ok(diff) => format!("{}\n", diff),
+                Err(e) => format!("error: {}\n", ControlError::from(e)),
+            }
+        }
+        ControlCommand::Rollback { serial, zone } => {
+            match ctx.local_records.rollback(serial, zone.clone()).await {
+                Ok(diff) => {
+                    info!(
+                        "Control: rolled back {} to version {} ({} added, {} removed)",
+                        zone.as_deref().unwrap_or("local records"),
+                        serial,
+                        diff.added.len(),
+                        diff.removed.len()
+                    );
+                    format!("{}\nok: restored version {}\n", diff, serial)
+                }
+                Err(e) => format!("error: {}\n", ControlError::from(e)),
+            }
+        }
+        ControlCommand::Allowlist => {
+            let rules = ctx.blocklist.allowlist().rules();
+            if rules.is_empty() {
+                "allowlist is empty\n".to_string()
+            } else {
+                rules.iter().map(|rule| format!("{}\n", rule)).collect()
+            }
+        }
+        ControlCommand::Allow(rule) => {
+            let line = rule.to_string();
+            if ctx.blocklist.allow(rule) {
+                info!("Control: allowed {}", line);
+                format!("ok: allowed {}\n", line)
+            } else {
+                format!("ok: {} already allowed\n", line)
+            }
+        }
+        ControlCommand::Disallow(rule) => {
+            if ctx.blocklist.disallow(&rule) {
+                info!("Control: removed {} from the allowlist", rule);
+                format!("ok: removed {}\n", rule)
+            } else {
+                format!("ok: {} wasn't allowed\n", rule)
+            }
+        }
+        ControlCommand::ReloadBlocklists => {
+            if !ctx.reloader.has_blocklists() {
+                "no blocklists to reload\n".to_string()
+            } else {
+                let results = ctx.reloader.reload_blocklists().await;
+                results
+                    .iter()
+                    .map(|(name, result)| match result {
+                        Ok(domains) => format!("ok: reloaded {} ({} domains)\n", name, domains),
+                        Err(e) => format!("error: kept the current {}: {:#}\n", name, e),
+                    })
+                    .collect()
+            }
+        }
+        ControlCommand::ReloadZone(zone) => {
+            let results = ctx.reloader.reload_zone(&zone).await;
+            if results.is_empty() {
+                format!("error: no zone file holds {}\n", zone)
+            } else {
+                results
+                    .iter()
+                    .map(|(file, result)| match result {
+                        Ok(diff) => format!(
+                            "ok: reloaded {} from {}: {} added, {} removed, {} unchanged\n",
+                            file.zone,
+                            file.path.display(),
+                            diff.added.len(),
+                            diff.removed.len(),
+                            diff.unchanged
+                        ),
+                        Err(e) => format!(
+                            "error: kept the current {}, {} not loaded: {}\n",
+                            file.zone,
+                            file.path.display(),
+                            e
+                        ),
+                    })
+                    .collect()
+            }
+        }
+        ControlCommand::Profile { profile, path } => {
+            info!(
+                "Control: writing {:?} profile to {}",
+                profile,
+                path.display()
+            );
+            match write_profile(profile, path.clone()).await {
+                Ok(written) => format!("ok: wrote {} to {}\n", written, path.display()),
+                Err(e) => format!("error: {}\n", e),
+            }
+        }
+        ControlCommand::Cache => match ctx.cache.usage() {
+            Some(usage) => {
+                let max_bytes = usage
+                    .config
+                    .max_bytes
+                    .map_or("unlimited".to_string(), |max| max.to_string());
+                format!(
+                    "entries {}\nmax_entries {}\nbytes {}\nmax_bytes {}\neviction {}\nevictions {}\n",
+                    usage.entries,
+                    usage.config.max_entries,
+                    usage.bytes,
+                    max_bytes,
+                    usage.config.eviction,
+                    usage.evictions
+                )
+            }
+            None => "cache is off\n".to_string(),
+        },
+        ControlCommand::CacheLookup { name, rtype } => {
+            let mut answers = ctx.cache.find(&name, rtype);
+            answers.sort_by_key(|(key, _)| (key.qtype.to_string(), key.route.clone()));
+            if answers.is_empty() {
+                format!("{} is not cached\n", name)
+            } else {
+                answers
+                    .iter()
+                    .map(|(key, resolution)| render_cached(key, resolution))
+                    .collect()
+            }
+        }
+        ControlCommand::Blocked => {
+            let domains = ctx.blocklist.blocked();
+            if domains.is_empty() {
+                "no domains blocked here\n".to_string()
+            } else {
+                domains
+                    .iter()
+                    .map(|domain| format!("{}\n", domain))
+                    .collect()
+            }
+        }
+        ControlCommand::Block(domain) => {
+            if ctx.blocklist.block(&domain) {
+                info!("Control: blocked {}", domain);
+                format!("ok: blocked {}\n", domain)
+            } else {
+                format!("ok: {} already blocked\n", domain)
+            }
+        }
+        ControlCommand::Unblock(domain) => {
+            let unblocked = ctx.blocklist.unblock(&domain);
+            if unblocked {
+                info!("Control: unblocked {}", domain);
+            }
+            match (unblocked, ctx.blocklist.is_listed(&domain)) {
+                (true, false) => format!("ok: unblocked {}\n", domain),
+                (false, false) => format!("ok: {} wasn't blocked here\n", domain),
+                // Only the allowlist overrides the lists
+                (_, true) => format!(
+                    "ok: {} is still blocked by the blocklists; allowlist add {} lets it through\n",
+                    domain, domain
+                ),
+            }
+        }
+        ControlCommand::Stats => {
+            let mut stats: String = ctx
+                .metrics
+                .snapshot()
+                .into_iter()
+                .map(|(name, value)| format!("{} {}\n", name, value))
+                .collect();
+            stats.push_str(&format!("query_log_dropped {}\n", ctx.query_log.dropped()));
+            for (shard, queries) in ctx.metrics.shard_snapshot().into_iter().enumerate() {
+                stats.push_str(&format!("shard{}_queries {}\n", shard, queries));
+            }
+            for (client, counts) in ctx.quotas.iter().flat_map(|quotas| quotas.snapshot()) {
+                stats.push_str(&format!(
+                    "client_{}_queries {}\nclient_{}_over_quota {}\n",
+                    client, counts.queries, client, counts.over_quota
+                ));
+            }
+            stats
+        }
+        ControlCommand::Top { kind, count } => {
+            let top = ctx.stats.top(kind, count);
+            if top.is_empty() {
+                format!("no {} in the last hour\n", kind)
+            } else {
+                top.iter()
+                    .map(|(name, queries)| format!("{} {}\n", queries, name))
+                    .collect()
+            }
+        }
+        ControlCommand::Rates => ctx
+            .stats
+            .rates()
+            .into_iter()
+            .map(|(name, value)| format!("{} {:.2}\n", name, value))
+            .collect(),
+        // Handled by the connection loop
+        ControlCommand::Auth(_) | ControlCommand::Tail { .. } | ControlCommand::Quit => {
+            String::new()
+        }
+    };
+
+    out.write_all(response.as_bytes()).await
+}
+
+/// One cached answer: its question and route, then its records or the
+/// negative answer with its SOA's TTL
+fn render_cached(key: &CacheKey, resolution: &Resolution) -> String {
+    let mut lines = match &key.route {
+        Some(route) => format!("{} {} (route {})\n", key.name, key.qtype, route),
+        None => format!("{} {}\n", key.name, key.qtype),
+    };
+    match resolution {
+        Resolution::Records(records) => {
+            for record in records {
+                lines.push_str(&format!(
+                    "  {} {} {}\n",
+                    record.name,
+                    record.ttl,
+                    answer_summary(record)
+                ));
+            }
+        }
+        Resolution::Negative { rcode, soa } => {
+            let ttl = soa.as_ref().map_or(0, |soa| soa.ttl);
+            lines.push_str(&format!("  {} (negative, {} left)\n", rcode, ttl));
+        }
+        Resolution::Failed(rcode) => lines.push_str(&format!("  {}\n", rcode)),
+    }
+    lines
+}
+
+/// Stream query events to the client until it sends a line or disconnects
+async fn tail<R, W>(
+    ctx: &ControlContext,
+    color: bool,
+    lines: &mut Lines<R>,
+    out: &mut W,
+) -> std::io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut events = ctx.query_log.subscribe();
+    out.write_all(b"tailing queries (send an empty line to stop)\n")
+        .await?;
+    out.flush().await?;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let line = match event {
+                    Ok(event) => format!("{}\n", render_pretty(&event, color)),
+                    Err(RecvError::Lagged(missed)) => {
+                        format!("... {} queries not shown (output too slow)\n", missed)
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                out.write_all(line.as_bytes()).await?;
+                out.flush().await?;
+            }
+            line = lines.next_line() => {
+                if line?.is_none() {
+                    // The client went away; nothing left to write to
+                    return Ok(());
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::RecordType;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(ControlCommand::parse("help").unwrap(), ControlCommand::Help);
+        assert_eq!(
+            ControlCommand::parse("  RECORDS ").unwrap(),
+            ControlCommand::Records
+        );
+        assert_eq!(
+            ControlCommand::parse("remove nas.lan aaaa").unwrap(),
+            ControlCommand::Remove {
+                name: "nas.lan".to_string(),
+                rtype: Some(RecordType::AAAA),
+            }
+        );
+        assert!(matches!(
+            ControlCommand::parse("add nas.lan A 10.0.0.2").unwrap(),
+            ControlCommand::Add(_)
+        ));
+        assert_eq!(
+            ControlCommand::parse("cache").unwrap(),
+            ControlCommand::Cache
+        );
+        assert_eq!(
+            ControlCommand::parse("cache Example.COM. mx").unwrap(),
+            ControlCommand::CacheLookup {
+                name: "example.com".to_string(),
+                rtype: Some(RecordType::MX),
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("block").unwrap(),
+            ControlCommand::Blocked
+        );
+        assert_eq!(
+            ControlCommand::parse("block Ads.example").unwrap(),
+            ControlCommand::Block("ads.example".to_string())
+        );
+        assert_eq!(
+            ControlCommand::parse("unblock ads.example.").unwrap(),
+            ControlCommand::Unblock("ads.example".to_string())
+        );
+        assert_eq!(
+            ControlCommand::parse("tail --color").unwrap(),
+            ControlCommand::Tail { color: true }
+        );
+        assert_eq!(
+            ControlCommand::parse("classless 192.168.1.64/26 3600").unwrap(),
+            ControlCommand::Classless {
+                block: "192.168.1.64/26".parse().unwrap(),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("diff 3 example.com").unwrap(),
+            ControlCommand::Diff {
+                from: 3,
+                to: None,
+                zone: Some("example.com".to_string()),
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("diff 3 5").unwrap(),
+            ControlCommand::Diff {
+                from: 3,
+                to: Some(5),
+                zone: None,
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("rollback 2").unwrap(),
+            ControlCommand::Rollback {
+                serial: 2,
+                zone: None,
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("profile cpu 30 /tmp/cpu.svg").unwrap(),
+            ControlCommand::Profile {
+                profile: Profile::Cpu { seconds: 30 },
+                path: PathBuf::from("/tmp/cpu.svg"),
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("allowlist add *.cdn.example").unwrap(),
+            ControlCommand::Allow("*.cdn.example".parse().unwrap())
+        );
+        assert_eq!(
+            ControlCommand::parse("allowlist").unwrap(),
+            ControlCommand::Allowlist
+        );
+        assert_eq!(
+            ControlCommand::parse("auth s3cret").unwrap(),
+            ControlCommand::Auth("s3cret".to_string())
+        );
+        assert_eq!(
+            ControlCommand::parse("stats").unwrap(),
+            ControlCommand::Stats
+        );
+        assert_eq!(
+            ControlCommand::parse("cache").unwrap(),
+            ControlCommand::Cache
+        );
+        assert_eq!(
+            ControlCommand::parse("top blocked").unwrap(),
+            ControlCommand::Top {
+                kind: TopKind::Blocked,
+                count: DEFAULT_TOP
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("top clients 25").unwrap(),
+            ControlCommand::Top {
+                kind: TopKind::Clients,
+                count: 25
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("rates").unwrap(),
+            ControlCommand::Rates
+        );
+        assert_eq!(
+            ControlCommand::parse("export csv").unwrap(),
+            ControlCommand::Export(RecordFormat::Csv)
+        );
+        assert_eq!(
+            ControlCommand::parse("reload-blocklists").unwrap(),
+            ControlCommand::ReloadBlocklists
+        );
+        assert_eq!(
+            ControlCommand::parse("reload-zone Lab.Example.com.").unwrap(),
+            ControlCommand::ReloadZone("lab.example.com".to_string())
+        );
+        match ControlCommand::parse(
+            r#"import --dry-run [{"name":"nas.lan","type":"A","value":"10.0.0.2"}]"#,
+        )
+        .unwrap()
+        {
+            ControlCommand::Import {
+                records,
+                replace,
+                dry_run,
+            } => {
+                assert_eq!(records.len(), 1);
+                assert!(!replace);
+                assert!(dry_run);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[cfg(all(unix, feature = "profiling"))]
+    #[tokio::test]
+    async fn test_profile_command_writes_profile() {
+        let path = std::env::temp_dir().join(format!("dns-profile-{}.pb", std::process::id()));
+        let command = ControlCommand::parse(&format!("profile cpu 1 {}", path.display())).unwrap();
+        let ControlCommand::Profile { profile, path } = command else {
+            panic!("not a profile command: {:?}", command);
+        };
+        assert_eq!(profile, Profile::Cpu { seconds: 1 });
+        assert_eq!(
+            write_profile(profile, path.clone()).await.unwrap(),
+            "1s CPU profile (pprof)"
+        );
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            ControlCommand::parse("frobnicate"),
+            Err(ControlError::UnknownCommand(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("add"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("add nas.lan A nope"),
+            Err(ControlError::InvalidRecord(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("import --force []"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("reload-zone"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("tail --follow"),
+            Err(ControlError::Usage("tail [--color]"))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("rollback"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("rollback latest"),
+            Err(ControlError::InvalidRecord(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("profile cpu 0 /tmp/cpu.pb"),
+            Err(ControlError::InvalidRecord(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("profile heap"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("auth"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("top"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("cache example.com A extra"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("cache example.com NOPE"),
+            Err(ControlError::InvalidRecord(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("unblock"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("block a b"),
+            Err(ControlError::Usage(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("block bad..name"),
+            Err(ControlError::InvalidRecord(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("top names"),
+            Err(ControlError::InvalidRecord(_))
+        ));
+        assert!(matches!(
+            ControlCommand::parse("top domains 0"),
+            Err(ControlError::InvalidRecord(_))
+        ));
+    }
+
+    #[test]
+    fn test_render_cached() {
+        use crate::protocol::DnsResourceRecord;
+        use crate::registry::{Rcode, RecordClass};
+
+        let key = |route: Option<&str>| CacheKey {
+            name: "example.com".into(),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+            route: route.map(Into::into),
+        };
+        let records = Resolution::Records(vec![DnsResourceRecord::new(
+            "example.com".to_string(),
+            RecordType::A,
+            RecordClass::IN,
+            240,
+            vec![192, 0, 2, 1],
+        )]);
+        assert_eq!(
+            render_cached(&key(None), &records),
+            "example.com A\n  example.com 240 A 192.0.2.1\n"
+        );
+        let negative = Resolution::Negative {
+            rcode: Rcode::NXDOMAIN,
+            soa: None,
+        };
+        assert_eq!(
+            render_cached(&key(Some("vpn")), &negative),
+            "example.com A (route vpn)\n  NXDOMAIN (negative, 0 left)\n"
+        );
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("s3cret", "s3cret"));
+        assert!(!token_matches("s3cret", "s3cre"));
+        assert!(!token_matches("s3cret", "s3creT"));
+    }
+}
@@ -0,0 +1,253 @@
+//! `dns-server ctl`: an interactive shell for the control socket
+//!
+//! If the server requires a token, set it in `DNS_CTL_TOKEN`; every
+//! connection then authenticates before sending its first command. If its
+//! control socket speaks TLS, set `DNS_CTL_CERT`, `DNS_CTL_KEY` and
+//! `DNS_CTL_CA` to the client certificate, its key and the CA bundle the
+//! server's certificate is checked against.
+
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines, ReadHalf, Stdin,
+    WriteHalf,
+};
+use tokio::net::TcpStream;
+
+use crate::control_tls::{self, CA_ENV, CERT_ENV, KEY_ENV};
+use crate::local_records::{import_records, RecordFormat, RecordRow};
+
+const PROMPT: &str = "dns> ";
+
+/// Environment variable holding the control socket token
+pub const TOKEN_ENV: &str = "DNS_CTL_TOKEN";
+
+/// A connection to the control socket, in TLS or not
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Connection for S {}
+
+type Responses = Lines<BufReader<ReadHalf<Box<dyn Connection>>>>;
+type Requests = WriteHalf<Box<dyn Connection>>;
+
+/// Connect to the control socket, over TLS if a client certificate is set,
+/// authenticating if a token is set
+async fn connect(addr: SocketAddr) -> anyhow::Result<(Responses, Requests)> {
+    let stream: Box<dyn Connection> = match (
+        std::env::var_os(CERT_ENV),
+        std::env::var_os(KEY_ENV),
+        std::env::var_os(CA_ENV),
+    ) {
+        (Some(cert), Some(key), Some(ca)) => {
+            Box::new(control_tls::connect(addr, cert.as_ref(), key.as_ref(), ca.as_ref()).await?)
+        }
+        (None, None, None) => Box::new(
+            TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("could not connect to control socket at {}", addr))?,
+        ),
+        _ => anyhow::bail!(
+            "{}, {} and {} must be set together",
+            CERT_ENV,
+            KEY_ENV,
+            CA_ENV
+        ),
+    };
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut responses = BufReader::new(reader).lines();
+
+    if let Ok(token) = std::env::var(TOKEN_ENV) {
+        writer
+            .write_all(format!("auth {}\n", token).as_bytes())
+            .await?;
+        let lines = read_response(&mut responses).await?;
+        if let Some(error) = lines.first().and_then(|line| line.strip_prefix("error: ")) {
+            anyhow::bail!(
+                "control socket at {} rejected {}: {}",
+                addr,
+                TOKEN_ENV,
+                error
+            );
+        }
+    }
+    Ok((responses, writer))
+}
+
+/// Response lines up to the terminating empty line
+async fn read_response(responses: &mut Responses) -> anyhow::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    while let Some(line) = responses.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// Run a single command if one is given, otherwise start an interactive session
+pub async fn run(addr: SocketAddr, command: Vec<String>) -> anyhow::Result<()> {
+    let (mut responses, mut writer) = connect(addr).await?;
+
+    if !command.is_empty() {
+        let line = with_color_hint(&command.join(" "));
+        writer.write_all(format!("{}\n", line).as_bytes()).await?;
+        print_response(&mut responses).await?;
+        return Ok(());
+    }
+
+    println!(
+        "Connected to {} (type 'help' for commands, 'quit' to exit)",
+        addr
+    );
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+
+    loop {
+        stdout.write_all(PROMPT.as_bytes()).await?;
+        stdout.flush().await?;
+
+        let Some(line) = stdin.next_line().await? else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if matches!(line, "quit" | "exit") {
+            break;
+        }
+
+        let line = with_color_hint(line);
+        writer.write_all(format!("{}\n", line).as_bytes()).await?;
+        let open = if line.starts_with("tail") {
+            println!("(press Enter to stop)");
+            print_stream(&mut responses, &mut stdin, &mut writer).await?
+        } else {
+            print_response(&mut responses).await?
+        };
+        if !open {
+            println!("Control socket closed the connection");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print response lines up to the terminating empty line.
+/// Returns false if the server closed the connection.
+async fn print_response(responses: &mut Responses) -> anyhow::Result<bool> {
+    while let Some(line) = responses.next_line().await? {
+        if line.is_empty() {
+            return Ok(true);
+        }
+        println!("{}", line);
+    }
+    Ok(false)
+}
+
+/// Print a streaming response (`tail`) until the user presses Enter.
+/// Returns false if the server closed the connection.
+async fn print_stream(
+    responses: &mut Responses,
+    stdin: &mut Lines<BufReader<Stdin>>,
+    writer: &mut Requests,
+) -> anyhow::Result<bool> {
+    let mut stop_sent = false;
+    loop {
+        tokio::select! {
+            line = responses.next_line() => match line? {
+                Some(line) if line.is_empty() => return Ok(true),
+                Some(line) => println!("{}", line),
+                None => return Ok(false),
+            },
+            _ = stdin.next_line(), if !stop_sent => {
+                // Any line tells the server to stop; it then ends the response
+                writer.write_all(b"\n").await?;
+                stop_sent = true;
+            }
+        }
+    }
+}
+
+/// Ask for colored `tail` output when it is going to a terminal
+fn with_color_hint(line: &str) -> String {
+    if line.trim() == "tail" && std::io::stdout().is_terminal() {
+        "tail --color".to_string()
+    } else {
+        line.to_string()
+    }
+}
+
+/// Send a single command and collect the response lines
+async fn request(addr: SocketAddr, line: &str) -> anyhow::Result<Vec<String>> {
+    let (mut responses, mut writer) = connect(addr).await?;
+
+    writer.write_all(format!("{}\n", line).as_bytes()).await?;
+    let lines = read_response(&mut responses).await?;
+
+    if let Some(error) = lines.first().and_then(|line| line.strip_prefix("error: ")) {
+        anyhow::bail!("{}", error);
+    }
+    Ok(lines)
+}
+
+/// `records export`: fetch the server's local records and write them out
+pub async fn export_records(
+    addr: SocketAddr,
+    format: RecordFormat,
+    output: Option<&Path>,
+) -> anyhow::Result<()> {
+    let verb = match format {
+        RecordFormat::Json => "export json",
+        RecordFormat::Csv => "export csv",
+    };
+    let mut document = request(addr, verb).await?.join("\n");
+    document.push('\n');
+
+    match output {
+        Some(path) => tokio::fs::write(path, document)
+            .await
+            .with_context(|| format!("could not write {}", path.display()))?,
+        None => print!("{}", document),
+    }
+    Ok(())
+}
+
+/// `records import`: validate a file locally, then send it to the server
+pub async fn import_records_file(
+    addr: SocketAddr,
+    file: &Path,
+    format: Option<RecordFormat>,
+    dry_run: bool,
+    replace: bool,
+) -> anyhow::Result<()> {
+    let format = format.unwrap_or_else(|| RecordFormat::for_path(file));
+
+    let input = tokio::fs::read_to_string(file)
+        .await
+        .with_context(|| format!("could not read {}", file.display()))?;
+    let records = import_records(&input, format)
+        .with_context(|| format!("invalid records in {}", file.display()))?;
+
+    // The control socket takes the records as a single line of JSON
+    let rows: Vec<RecordRow> = records.iter().map(RecordRow::from).collect();
+    let mut line = String::from("import");
+    if dry_run {
+        line.push_str(" --dry-run");
+    }
+    if replace {
+        line.push_str(" --replace");
+    }
+    line.push(' ');
+    line.push_str(&serde_json::to_string(&rows)?);
+
+    for line in request(addr, &line).await? {
+        println!("{}", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,577 @@
+//! DNSSEC signing of locally served zones (`--dnssec-key`)
+//!
+//! A zone with keys is served signed. Its keys are ECDSA P-256 (algorithm 13,
+//! RFC 6605) kept in key files written by `dnssec keygen`: a key signing key
+//! (KSK, flags 257) signs the DNSKEY RRset, a zone signing key (ZSK, flags
+//! 256) everything else, and a zone with a single key uses it for both.
+//!
+//! Signatures are made online, as queries come in, for clients that set the
+//! DO bit, so local records can change at any time without a re-sign. A
+//! signed zone is answered in full: the apex has its DNSKEY RRset and a SOA,
+//! and a name without records of the type asked is answered NODATA with an
+//! NSEC record covering only the name itself (minimally covering NSEC, RFC
+//! 4470), which keeps the zone from being walked. Names that don't exist get
+//! the same NODATA answer, so no NSEC chain of the whole zone is needed.
+//!
+//! `dnssec ds` prints a key's DNSKEY record and the DS record to publish in
+//! the parent zone; the server also logs them for every key it loads.
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::DnssecAction;
+use crate::errors::DnssecError;
+use crate::local_records::{is_in_zone, normalize_name};
+use crate::names::name_to_labels;
+use crate::protocol::DnsResourceRecord;
+use crate::registry::{RecordClass, RecordType};
+use crate::response_builder::{encode_name, SoaRecord, LOCAL_NEGATIVE_TTL};
+
+/// ECDSA Curve P-256 with SHA-256, the one algorithm keys are made with
+pub const ALGORITHM: u8 = 13;
+/// DNSKEY flags of a zone signing key: the Zone Key bit
+pub const ZSK_FLAGS: u16 = 256;
+/// DNSKEY flags of a key signing key: the Zone Key and Secure Entry Point bits
+pub const KSK_FLAGS: u16 = 257;
+/// The Secure Entry Point bit of the DNSKEY flags
+const SEP: u16 = 1;
+/// The DNSKEY protocol field, always 3 (RFC 4034 §2.1.2)
+const PROTOCOL: u8 = 3;
+/// DS digest type SHA-256 (RFC 4509)
+const DIGEST_SHA256: u8 = 2;
+/// TTL of the DNSKEY and SOA records
+const ZONE_TTL: u32 = 3600;
+/// The SOA minimum, and so the TTL of negative answers and their NSEC records
+const NEGATIVE_TTL: u32 = LOCAL_NEGATIVE_TTL;
+/// How far back signatures are valid from, for validators with slow clocks
+const INCEPTION_SKEW: u32 = 3600;
+/// How long signatures are valid for
+const VALIDITY: u32 = 7 * 86400;
+
+/// A key as stored on disk, written by `dnssec keygen`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyFile {
+    pub zone: String,
+    /// DNSKEY flags: 257 for a KSK, 256 for a ZSK
+    pub flags: u16,
+    pub algorithm: u8,
+    /// The private key as a base64 PKCS#8 document
+    pub private_key: String,
+}
+
+impl KeyFile {
+    /// A new key for `zone`
+    pub fn generate(zone: &str, ksk: bool) -> Result<Self, DnssecError> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .map_err(|_| DnssecError::KeyGeneration)?;
+        Ok(Self {
+            zone: normalize_name(zone),
+            flags: if ksk { KSK_FLAGS } else { ZSK_FLAGS },
+            algorithm: ALGORITHM,
+            private_key: BASE64.encode(pkcs8.as_ref()),
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self, DnssecError> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+/// A key loaded for signing
+#[derive(Debug)]
+pub struct SigningKey {
+    pub flags: u16,
+    pub tag: u16,
+    key_pair: EcdsaKeyPair,
+    /// The DNSKEY RDATA publishing the key
+    dnskey: Vec<u8>,
+}
+
+impl SigningKey {
+    pub fn new(file: &KeyFile) -> Result<Self, DnssecError> {
+        if file.algorithm != ALGORITHM {
+            return Err(DnssecError::UnsupportedAlgorithm(file.algorithm));
+        }
+        let pkcs8 = BASE64
+            .decode(file.private_key.trim())
+            .map_err(|e| DnssecError::InvalidKey(e.to_string()))?;
+        let key_pair = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &pkcs8,
+            &SystemRandom::new(),
+        )
+        .map_err(|e| DnssecError::InvalidKey(e.to_string()))?;
+
+        // The public key is the curve point without its uncompressed-form
+        // prefix byte (RFC 6605 §4)
+        let mut dnskey = file.flags.to_be_bytes().to_vec();
+        dnskey.extend([PROTOCOL, ALGORITHM]);
+        dnskey.extend(&key_pair.public_key().as_ref()[1..]);
+        Ok(Self {
+            flags: file.flags,
+            tag: key_tag(&dnskey),
+            key_pair,
+            dnskey,
+        })
+    }
+
+    /// Whether this is a key signing key
+    pub fn is_ksk(&self) -> bool {
+        self.flags & SEP != 0
+    }
+
+    pub fn dnskey_record(&self, zone: &str) -> DnsResourceRecord {
+        DnsResourceRecord::new(
+            zone.to_string(),
+            RecordType::DNSKEY,
+            RecordClass::IN,
+            ZONE_TTL,
+            self.dnskey.clone(),
+        )
+    }
+
+    /// The DNSKEY and DS records to publish for the key, in presentation format
+    pub fn publication(&self, zone: &str) -> String {
+        let digest = ds_digest(zone, &self.dnskey);
+        let hex = digest.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02X}", byte);
+            hex
+        });
+        format!(
+            "{zone}. {ZONE_TTL} IN DNSKEY {} {PROTOCOL} {ALGORITHM} {}\n\
+             {zone}. {ZONE_TTL} IN DS {} {ALGORITHM} {DIGEST_SHA256} {}\n",
+            self.flags,
+            BASE64.encode(&self.dnskey[4..]),
+            self.tag,
+            hex
+        )
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.key_pair
+            .sign(&SystemRandom::new(), data)
+            .map(|signature| signature.as_ref().to_vec())
+            .unwrap_or_default()
+    }
+}
+
+/// What a signed zone answers for a name without local records of the type
+#[derive(Debug, Default)]
+pub struct ZoneAnswer {
+    pub answers: Vec<DnsResourceRecord>,
+    pub authority: Vec<DnsResourceRecord>,
+}
+
+/// A zone served signed, and its keys
+#[derive(Debug)]
+pub struct SignedZone {
+    pub zone: String,
+    pub keys: Vec<SigningKey>,
+    soa: SoaRecord,
+}
+
+impl SignedZone {
+    pub fn new(zone: String, keys: Vec<SigningKey>) -> Self {
+        let soa = SoaRecord::local(&zone, unix_time());
+        Self { zone, keys, soa }
+    }
+
+    /// RRSIG records for the RRsets of an answer that are in the zone
+    pub fn sign_answers(&self, records: &[DnsResourceRecord]) -> Vec<DnsResourceRecord> {
+        let mut rrsigs = Vec::new();
+        let mut rest = records;
+        while let Some(first) = rest.first() {
+            let len = rest
+                .iter()
+                .take_while(|record| record.rtype == first.rtype && record.name == first.name)
+                .count();
+            let (rrset, tail) = rest.split_at(len);
+            if is_in_zone(&normalize_name(&first.name), &self.zone) {
+                rrsigs.extend(self.sign(rrset));
+            }
+            rest = tail;
+        }
+        rrsigs
+    }
+
+    /// The answer for `name`, which has local records of `types` but none
+    /// of `qtype`: the keys or SOA at the apex, NODATA otherwise
+    pub fn answer(
+        &self,
+        name: &str,
+        qtype: RecordType,
+        types: &[RecordType],
+        dnssec_ok: bool,
+    ) -> ZoneAnswer {
+        let name = normalize_name(name);
+        let apex = name == self.zone;
+        let mut answer = ZoneAnswer::default();
+
+        let apex_records = match qtype {
+            RecordType::DNSKEY if apex => self
+                .keys
+                .iter()
+                .map(|key| key.dnskey_record(&self.zone))
+                .collect(),
+            RecordType::SOA if apex => vec![self.soa.to_resource_record(ZONE_TTL)],
+            _ => Vec::new(),
+        };
+        if !apex_records.is_empty() {
+            if dnssec_ok {
+                let rrsigs = self.sign(&apex_records);
+                answer.answers.extend(apex_records);
+                answer.answers.extend(rrsigs);
+            } else {
+                answer.answers = apex_records;
+            }
+            return answer;
+        }
+
+        let soa = self.soa.to_resource_record(NEGATIVE_TTL);
+        if dnssec_ok {
+            let nsec = self.nsec(&name, types, apex);
+            let soa_rrsigs = self.sign(std::slice::from_ref(&soa));
+            let nsec_rrsigs = self.sign(std::slice::from_ref(&nsec));
+            answer.authority.push(soa);
+            answer.authority.extend(soa_rrsigs);
+            answer.authority.push(nsec);
+            answer.authority.extend(nsec_rrsigs);
+        } else {
+            answer.authority.push(soa);
+        }
+        answer
+    }
+
+    /// An NSEC record denying every type `name` doesn't have, whose next
+    /// name is the one right after it, so it covers no other name
+    fn nsec(&self, name: &str, types: &[RecordType], apex: bool) -> DnsResourceRecord {
+        let mut present: BTreeSet<u16> = types.iter().map(|rtype| rtype.0).collect();
+        present.extend([RecordType::RRSIG.0, RecordType::NSEC.0]);
+        if apex {
+            present.extend([RecordType::SOA.0, RecordType::DNSKEY.0]);
+        }
+        let mut rdata = encode_name(&format!("\\000.{}", name));
+        rdata.extend(type_bitmap(&present));
+        DnsResourceRecord::new(
+            name.to_string(),
+            RecordType::NSEC,
+            RecordClass::IN,
+            NEGATIVE_TTL,
+            rdata,
+        )
+    }
+
+    /// RRSIGs over an RRset, by the key signing keys for the DNSKEY RRset
+    /// and the zone signing keys for everything else
+    fn sign(&self, rrset: &[DnsResourceRecord]) -> Vec<DnsResourceRecord> {
+        let Some(first) = rrset.first() else {
+            return Vec::new();
+        };
+        let owner = normalize_name(&first.name);
+        let ttl = rrset.iter().map(|record| record.ttl).min().unwrap_or(0);
+        let labels = name_to_labels(&owner);
+        let wildcard = labels.first().is_some_and(|label| label == b"*");
+        let now = unix_time();
+
+        // The RRs in canonical form and order (RFC 4034 §6.2, §6.3)
+        let owner_wire = encode_name(&owner);
+        let mut rdatas: Vec<Vec<u8>> = rrset
+            .iter()
+            .map(|record| canonical_rdata(record.rtype, &record.rdata))
+            .collect();
+        rdatas.sort();
+        rdatas.dedup();
+
+        let key_signing = first.rtype == RecordType::DNSKEY;
+        let has_role = self.keys.iter().any(|key| key.is_ksk() == key_signing);
+        self.keys
+            .iter()
+            .filter(|key| !has_role || key.is_ksk() == key_signing)
+            .map(|key| {
+                let mut rdata = first.rtype.0.to_be_bytes().to_vec();
+                rdata.push(ALGORITHM);
+                rdata.push((labels.len() - usize::from(wildcard)) as u8);
+                rdata.extend(ttl.to_be_bytes());
+                rdata.extend(now.wrapping_add(VALIDITY).to_be_bytes());
+                rdata.extend(now.wrapping_sub(INCEPTION_SKEW).to_be_bytes());
+                rdata.extend(key.tag.to_be_bytes());
+                rdata.extend(encode_name(&self.zone));
+
+                let mut signed = rdata.clone();
+                for data in &rdatas {
+                    signed.extend(&owner_wire);
+                    signed.extend(first.rtype.0.to_be_bytes());
+                    signed.extend(RecordClass::IN.0.to_be_bytes());
+                    signed.extend(ttl.to_be_bytes());
+                    signed.extend((data.len() as u16).to_be_bytes());
+                    signed.extend(data);
+                }
+                rdata.extend(key.sign(&signed));
+
+                DnsResourceRecord::new(
+                    first.name.clone(),
+                    RecordType::RRSIG,
+                    RecordClass::IN,
+                    ttl,
+                    rdata,
+                )
+            })
+            .collect()
+    }
+}
+
+/// The zones served signed
+#[derive(Debug, Default)]
+pub struct SignedZones(Vec<SignedZone>);
+
+impl SignedZones {
+    /// Load key files, grouping the keys by zone
+    pub fn load(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut zones: Vec<SignedZone> = Vec::new();
+        for path in paths {
+            let load = || -> Result<_, DnssecError> {
+                let file = KeyFile::read(path)?;
+                Ok((normalize_name(&file.zone), SigningKey::new(&file)?))
+            };
+            let (zone, key) =
+                load().with_context(|| format!("could not load DNSSEC key {}", path.display()))?;
+            match zones.iter_mut().find(|signed| signed.zone == zone) {
+                Some(signed) => signed.keys.push(key),
+                None => zones.push(SignedZone::new(zone, vec![key])),
+            }
+        }
+        Ok(Self(zones))
+    }
+
+    /// The signed zone `name` is in, the closest one if zones are nested
+    pub fn find(&self, name: &str) -> Option<&SignedZone> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let name = normalize_name(name);
+        self.0
+            .iter()
+            .filter(|signed| is_in_zone(&name, &signed.zone))
+            .max_by_key(|signed| signed.zone.len())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SignedZone> {
+        self.0.iter()
+    }
+}
+
+/// Run a `dnssec` subcommand
+pub fn run(action: &DnssecAction) -> anyhow::Result<()> {
+    match action {
+        DnssecAction::Keygen { zone, ksk, output } => {
+            let file = KeyFile::generate(zone, *ksk)?;
+            let json = serde_json::to_string_pretty(&file)? + "\n";
+            match output {
+                Some(path) => write_private(path, &json)
+                    .with_context(|| format!("could not write {}", path.display()))?,
+                None => print!("{}", json),
+            }
+            let key = SigningKey::new(&file)?;
+            eprint!("{}", key.publication(&file.zone));
+        }
+        DnssecAction::Ds { keys } => {
+            for path in keys {
+                let file = KeyFile::read(path)?;
+                print!("{}", SigningKey::new(&file)?.publication(&file.zone));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write a file only its owner can read
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// The key tag of a DNSKEY RDATA (RFC 4034 Appendix B)
+pub fn key_tag(dnskey: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for (i, byte) in dnskey.iter().enumerate() {
+        sum += if i % 2 == 0 {
+            u32::from(*byte) << 8
+        } else {
+            u32::from(*byte)
+        };
+    }
+    sum += sum >> 16;
+    sum as u16
+}
+
+/// The SHA-256 DS digest of a zone's DNSKEY RDATA (RFC 4509)
+pub fn ds_digest(zone: &str, dnskey: &[u8]) -> Vec<u8> {
+    let mut data = encode_name(&normalize_name(zone));
+    data.extend(dnskey);
+    digest::digest(&digest::SHA256, &data).as_ref().to_vec()
+}
+
+/// RDATA with its names lowercased, as signed (RFC 4034 §6.2). Names are
+/// uncompressed, and no length byte falls in the ASCII capitals.
+fn canonical_rdata(rtype: RecordType, rdata: &[u8]) -> Vec<u8> {
+    match rtype {
+        RecordType::NS | RecordType::CNAME | RecordType::PTR => rdata.to_ascii_lowercase(),
+        RecordType::MX if rdata.len() > 2 => {
+            let mut data = rdata[..2].to_vec();
+            data.extend(rdata[2..].to_ascii_lowercase());
+            data
+        }
+        _ => rdata.to_vec(),
+    }
+}
+
+/// The NSEC type bit maps field for a set of types (RFC 4034 §4.1.2)
+fn type_bitmap(types: &BTreeSet<u16>) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut window = types.iter().peekable();
+    while let Some(&&first) = window.peek() {
+        let block = (first >> 8) as u8;
+        let mut bitmap = [0u8; 32];
+        let mut len = 0;
+        while let Some(&&rtype) = window.peek() {
+            if (rtype >> 8) as u8 != block {
+                break;
+            }
+            let low = (rtype & 0xff) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            len = low / 8 + 1;
+            window.next();
+        }
+        data.push(block);
+        data.push(len as u8);
+        data.extend(&bitmap[..len]);
+    }
+    data
+}
+
+/// Seconds since the epoch, in the 32-bit serial arithmetic RRSIG times use
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    #[test]
+    fn test_key_tag_and_ds() {
+        let mut dnskey = KSK_FLAGS.to_be_bytes().to_vec();
+        dnskey.extend([PROTOCOL, ALGORITHM]);
+        dnskey.extend(0..64);
+
+        assert_eq!(key_tag(&dnskey), 59409);
+        assert_eq!(
+            ds_digest("Example.NET.", &dnskey),
+            [
+                0x15, 0xa6, 0x17, 0xb2, 0x5a, 0x0b, 0xf5, 0x8e, 0xf7, 0xad, 0x15, 0x0f, 0xae, 0x6e,
+                0x89, 0xe9, 0xbb, 0xd4, 0xe0, 0x46, 0x86, 0x4f, 0xd9, 0xe5, 0xd9, 0x35, 0x7b, 0x79,
+                0xf0, 0x83, 0xfb, 0x97
+            ]
+        );
+    }
+
+    #[test]
+    fn test_signed_answers() {
+        let ksk = SigningKey::new(&KeyFile::generate("example.com", true).unwrap()).unwrap();
+        let zsk = SigningKey::new(&KeyFile::generate("example.com", false).unwrap()).unwrap();
+        let (ksk_tag, zsk_tag) = (ksk.tag, zsk.tag);
+        let zsk_public = zsk.dnskey[4..].to_vec();
+        let zones = SignedZones(vec![SignedZone::new(
+            "example.com".to_string(),
+            vec![ksk, zsk],
+        )]);
+        let zone = zones.find("WWW.Example.com.").unwrap();
+        assert!(zones.find("example.org").is_none());
+
+        // An answer's RRset verifies against the ZSK
+        let record = |ip: [u8; 4]| {
+            DnsResourceRecord::new(
+                "www.example.com".to_string(),
+                RecordType::A,
+                RecordClass::IN,
+                300,
+                ip.to_vec(),
+            )
+        };
+        let rrset = [record([192, 0, 2, 2]), record([192, 0, 2, 1])];
+        let rrsigs = zone.sign_answers(&rrset);
+        assert_eq!(rrsigs.len(), 1);
+        let rdata = &rrsigs[0].rdata;
+        assert_eq!(&rdata[..4], [0, 1, ALGORITHM, 3]);
+        assert_eq!(&rdata[16..18], zsk_tag.to_be_bytes());
+        let signer = encode_name("example.com");
+        let (fields, signature) = rdata.split_at(18 + signer.len());
+        let mut signed = fields.to_vec();
+        for ip in [[192, 0, 2, 1], [192, 0, 2, 2]] {
+            signed.extend(encode_name("www.example.com"));
+            signed.extend([0, 1, 0, 1, 0, 0, 1, 44, 0, 4]);
+            signed.extend(ip);
+        }
+        let mut point = vec![4];
+        point.extend(zsk_public);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+            .verify(&signed, signature)
+            .unwrap();
+
+        // The DNSKEY RRset is signed by the KSK
+        let keys = zone.answer("example.com", RecordType::DNSKEY, &[], true);
+        assert_eq!(keys.answers.len(), 3);
+        assert_eq!(keys.answers[2].rtype, RecordType::RRSIG);
+        assert_eq!(&keys.answers[2].rdata[16..18], ksk_tag.to_be_bytes());
+
+        // NODATA denies the other types at the name, and only the name
+        let nodata = zone.answer("www.example.com", RecordType::MX, &[RecordType::A], true);
+        assert!(nodata.answers.is_empty());
+        let types: Vec<_> = nodata.authority.iter().map(|record| record.rtype).collect();
+        assert_eq!(
+            types,
+            [
+                RecordType::SOA,
+                RecordType::RRSIG,
+                RecordType::NSEC,
+                RecordType::RRSIG
+            ]
+        );
+        let mut nsec = encode_name("\\000.www.example.com");
+        nsec.extend([0, 6, 0x40, 0, 0, 0, 0, 0x03]);
+        assert_eq!(nodata.authority[2].rdata, nsec);
+
+        // Without DO only the SOA is sent
+        let plain = zone.answer("nope.example.com", RecordType::A, &[], false);
+        assert_eq!(plain.authority.len(), 1);
+    }
+
+    #[test]
+    fn test_type_bitmap() {
+        let types = BTreeSet::from([1, 15, 46, 47, 257]);
+        assert_eq!(
+            type_bitmap(&types),
+            [0, 6, 0x40, 0x01, 0, 0, 0, 0x03, 1, 1, 0x40]
+        );
+    }
+}
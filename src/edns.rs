@@ -0,0 +1,330 @@
+//! EDNS(0): the OPT pseudo-record (RFC 6891)
+//!
+//! A client that sends an OPT record in the additional section says how
+//! large a UDP response it can take, whether it wants DNSSEC records (the DO
+//! bit) and which extensions it speaks (options). Its response carries an
+//! OPT record of the server's own, advertising what the server takes and
+//! holding the upper bits of extended response codes such as BADVERS.
+//!
+//! Only version 0 exists; queries for a later version are answered BADVERS.
+//! A query with more than one OPT record, or a malformed one, is answered
+//! FORMERR.
+//!
+//! Resolvers may say which network the client they ask for is on (EDNS
+//! Client Subnet, RFC 7871). With `--geoip-db` or `--site`, location-tagged
+//! records are picked for that network instead of for the resolver, and the
+//! option comes back with the scope the answer holds for.
+
+use std::net::IpAddr;
+
+use crate::errors::EdnsError;
+use crate::parsers::ROOT_NAME;
+use crate::protocol::{DnsPacket, DnsResourceRecord};
+use crate::registry::{RecordClass, RecordType};
+
+/// UDP payload size advertised to clients, the size that avoids IP
+/// fragmentation on nearly every path (DNS Flag Day 2020)
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+/// The EDNS version spoken
+pub const EDNS_VERSION: u8 = 0;
+/// What every client takes over UDP: all of it without EDNS (RFC 1035
+/// §4.2.1), and at least it with EDNS, whatever it says (RFC 6891 §6.2.3)
+pub const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+/// The DO bit, in the flags half of the TTL
+const DNSSEC_OK: u32 = 0x8000;
+/// The option code of EDNS Client Subnet
+pub const CLIENT_SUBNET: u16 = 8;
+
+/// An option of an OPT record: its code and raw data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The network of the client a resolver asks for (RFC 7871 §6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    /// The network's address, zero past the source prefix
+    pub addr: IpAddr,
+    /// How many leading bits of the address the resolver gave
+    pub source_prefix: u8,
+    /// How many leading bits the answer depends on, in responses
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// Read an option's data: the family, the two prefixes and as many
+    /// bytes of address as the source prefix takes, the bits past it zero
+    fn parse(data: &[u8]) -> Result<Self, EdnsError> {
+        let [family_hi, family_lo, source_prefix, scope_prefix, address @ ..] = data else {
+            return Err(EdnsError::BadClientSubnet);
+        };
+        let (source_prefix, scope_prefix) = (*source_prefix, *scope_prefix);
+        let max_prefix = match u16::from_be_bytes([*family_hi, *family_lo]) {
+            1 => 32,
+            2 => 128,
+            _ => return Err(EdnsError::BadClientSubnet),
+        };
+        let stray_bits = match source_prefix % 8 {
+            0 => 0,
+            bits => 0xff >> bits,
+        };
+        if source_prefix > max_prefix
+            || scope_prefix > max_prefix
+            || address.len() != usize::from(source_prefix.div_ceil(8))
+            || address.last().is_some_and(|last| last & stray_bits != 0)
+        {
+            return Err(EdnsError::BadClientSubnet);
+        }
+        let mut octets = [0; 16];
+        octets[..address.len()].copy_from_slice(address);
+        let addr = if max_prefix == 32 {
+            IpAddr::from([octets[0], octets[1], octets[2], octets[3]])
+        } else {
+            IpAddr::from(octets)
+        };
+        Ok(ClientSubnet {
+            addr,
+            source_prefix,
+            scope_prefix,
+        })
+    }
+
+    /// The option answering this one, for an answer depending on the first
+    /// `scope_prefix` bits of the address
+    pub fn reply(&self, scope_prefix: u8) -> EdnsOption {
+        let (family, octets): (u16, Vec<u8>) = match self.addr {
+            IpAddr::V4(addr) => (1, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2, addr.octets().to_vec()),
+        };
+        let mut data = family.to_be_bytes().to_vec();
+        data.extend([self.source_prefix, scope_prefix]);
+        data.extend(&octets[..usize::from(self.source_prefix.div_ceil(8))]);
+        EdnsOption {
+            code: CLIENT_SUBNET,
+            data,
+        }
+    }
+}
+
+/// The contents of an OPT record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    /// Largest UDP response the sender takes
+    pub udp_payload_size: u16,
+    /// Upper 8 bits of the 12-bit response code
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The sender wants DNSSEC records
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    /// The EDNS of a message: none without an OPT record, an error when
+    /// there are several or the one there is malformed
+    pub fn from_packet(packet: &DnsPacket) -> Result<Option<Self>, EdnsError> {
+        let mut opts = packet
+            .additionals
+            .iter()
+            .filter(|record| record.rtype == RecordType::OPT);
+        let Some(record) = opts.next() else {
+            return Ok(None);
+        };
+        if opts.next().is_some() {
+            return Err(EdnsError::Duplicate);
+        }
+        Self::from_record(record).map(Some)
+    }
+
+    fn from_record(record: &DnsResourceRecord) -> Result<Self, EdnsError> {
+        if record.name != ROOT_NAME {
+            return Err(EdnsError::NotRoot(record.name.clone()));
+        }
+        let mut options = Vec::new();
+        let mut rdata = &record.rdata[..];
+        while !rdata.is_empty() {
+            let [code_hi, code_lo, len_hi, len_lo, rest @ ..] = rdata else {
+                return Err(EdnsError::TruncatedOption);
+            };
+            let len = u16::from_be_bytes([*len_hi, *len_lo]) as usize;
+            if rest.len() < len {
+                return Err(EdnsError::TruncatedOption);
+            }
+            let option = EdnsOption {
+                code: u16::from_be_bytes([*code_hi, *code_lo]),
+                data: rest[..len].to_vec(),
+            };
+            if option.code == CLIENT_SUBNET {
+                ClientSubnet::parse(&option.data)?;
+            }
+            options.push(option);
+            rdata = &rest[len..];
+        }
+
+        let [extended_rcode, version, ..] = record.ttl.to_be_bytes();
+        Ok(Edns {
+            udp_payload_size: record.rclass.0.max(MIN_UDP_PAYLOAD_SIZE),
+            extended_rcode,
+            version,
+            dnssec_ok: record.ttl & DNSSEC_OK != 0,
+            options,
+        })
+    }
+
+    /// The client subnet the sender gave; a source prefix of 0 asks for it
+    /// to be left unused
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.options
+            .iter()
+            .filter(|option| option.code == CLIENT_SUBNET)
+            .find_map(|option| ClientSubnet::parse(&option.data).ok())
+    }
+
+    /// The OPT record to answer this one with: the server's payload size and
+    /// version, and the DO bit copied from the query (RFC 3225 §3)
+    pub fn reply(&self) -> Self {
+        Edns {
+            udp_payload_size: UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: EDNS_VERSION,
+            dnssec_ok: self.dnssec_ok,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn to_record(&self) -> DnsResourceRecord {
+        let mut ttl = u32::from_be_bytes([self.extended_rcode, self.version, 0, 0]);
+        if self.dnssec_ok {
+            ttl |= DNSSEC_OK;
+        }
+        let mut rdata = Vec::new();
+        for option in &self.options {
+            rdata.extend(option.code.to_be_bytes());
+            rdata.extend((option.data.len() as u16).to_be_bytes());
+            rdata.extend(&option.data);
+        }
+        DnsResourceRecord::new(
+            ROOT_NAME.to_string(),
+            RecordType::OPT,
+            RecordClass(self.udp_payload_size),
+            ttl,
+            rdata,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::parse_dns_packet;
+
+    fn query(additional: &[u8], arcount: u8) -> DnsPacket {
+        let mut message = vec![0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, arcount];
+        message.extend([3, b'c', b'o', b'm', 0, 0, 1, 0, 1]);
+        message.extend(additional);
+        parse_dns_packet(&message).unwrap().1
+    }
+
+    #[test]
+    fn test_parse_opt() {
+        // 4096 bytes, DO set, a cookie option
+        let packet = query(
+            &[
+                0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 12, 0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8,
+            ],
+            1,
+        );
+        let edns = Edns::from_packet(&packet).unwrap().unwrap();
+        assert_eq!(edns.udp_payload_size, 4096);
+        assert_eq!(edns.version, 0);
+        assert!(edns.dnssec_ok);
+        assert_eq!(
+            edns.options,
+            vec![EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8]
+            }]
+        );
+        assert_eq!(Edns::from_record(&edns.to_record()).unwrap(), edns);
+
+        let reply = edns.reply();
+        assert_eq!(reply.udp_payload_size, UDP_PAYLOAD_SIZE);
+        assert!(reply.dnssec_ok);
+        assert!(reply.options.is_empty());
+
+        // Sizes below 512 count as 512
+        let packet = query(&[0, 0, 41, 0, 100, 0, 1, 0, 0, 0, 0], 1);
+        let edns = Edns::from_packet(&packet).unwrap().unwrap();
+        assert_eq!(edns.udp_payload_size, 512);
+        assert_eq!(edns.version, 1);
+
+        assert_eq!(Edns::from_packet(&query(&[], 0)), Ok(None));
+    }
+
+    #[test]
+    fn test_client_subnet() {
+        // 192.0.2.0/24
+        let packet = query(
+            &[
+                0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 11, 0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2,
+            ],
+            1,
+        );
+        let edns = Edns::from_packet(&packet).unwrap().unwrap();
+        let subnet = edns.client_subnet().unwrap();
+        assert_eq!(subnet.addr, "192.0.2.0".parse::<IpAddr>().unwrap());
+        assert_eq!((subnet.source_prefix, subnet.scope_prefix), (24, 0));
+        assert_eq!(
+            subnet.reply(24),
+            EdnsOption {
+                code: CLIENT_SUBNET,
+                data: vec![0, 1, 24, 24, 192, 0, 2]
+            }
+        );
+
+        // 2001:db8::/33, and /0 asking for none to be used
+        let subnet = ClientSubnet::parse(&[0, 2, 33, 0, 0x20, 0x01, 0x0d, 0xb8, 0x80]).unwrap();
+        assert_eq!(subnet.addr, "2001:db8:8000::".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            subnet.reply(33).data,
+            [0, 2, 33, 33, 0x20, 0x01, 0x0d, 0xb8, 0x80]
+        );
+        let subnet = ClientSubnet::parse(&[0, 1, 0, 0]).unwrap();
+        assert_eq!(subnet.reply(0).data, [0, 1, 0, 0]);
+
+        for bad in [
+            &[0, 1, 24][..],
+            &[0, 3, 8, 0, 10],
+            &[0, 1, 33, 0, 10, 0, 0, 0, 0],
+            &[0, 1, 24, 0, 192, 0],
+            &[0, 1, 23, 0, 192, 0, 3],
+        ] {
+            assert_eq!(ClientSubnet::parse(bad), Err(EdnsError::BadClientSubnet));
+        }
+        // A malformed option makes the OPT record malformed
+        let packet = query(
+            &[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 7, 0, 8, 0, 3, 0, 1, 24],
+            1,
+        );
+        assert_eq!(Edns::from_packet(&packet), Err(EdnsError::BadClientSubnet));
+    }
+
+    #[test]
+    fn test_bad_opt() {
+        let opt = [0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            Edns::from_packet(&query(&[opt, opt].concat(), 2)),
+            Err(EdnsError::Duplicate)
+        );
+        assert_eq!(
+            Edns::from_packet(&query(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 3, 0, 10, 0], 1)),
+            Err(EdnsError::TruncatedOption)
+        );
+        assert!(matches!(
+            Edns::from_packet(&query(&[1, b'x', 0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0], 1)),
+            Err(EdnsError::NotRoot(_))
+        ));
+    }
+}
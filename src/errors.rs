@@ -10,8 +10,8 @@ pub enum DnsCodecError {
     // #[error("Invalid packet format: {0}")]
     // InvalidFormat(String),
 
-    #[error("Nom parsing error: {0}")]
-    NomError(String),
+    #[error("Malformed packet: {0}")]
+    Malformed(#[from] PacketError),
 
     #[error("Invalid domain name: {0}")]
     InvalidDomainName(String),
@@ -19,3 +19,212 @@ pub enum DnsCodecError {
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 }
+
+/// Why a DNS message could not be parsed. Besides plain truncation and bad
+/// encodings this covers the limits that keep hostile packets cheap to reject.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PacketError {
+    #[error("bad encoding ({0:?})")]
+    Invalid(nom::error::ErrorKind),
+
+    #[error("compression pointer does not point back")]
+    BadPointer,
+
+    #[error("name longer than 255 bytes")]
+    NameTooLong,
+
+    #[error("more than {0} labels")]
+    TooManyLabels(usize),
+
+    #[error("{count} {section} records cannot fit in the {remaining} bytes left")]
+    CountTooLarge {
+        section: &'static str,
+        count: u16,
+        remaining: usize,
+    },
+
+    #[error("{0}-byte RDATA is over the limit")]
+    RdataTooLong(u16),
+
+    #[error("too much work to parse")]
+    TooComplex,
+}
+
+/// Why a query's OPT record is unusable; the query is answered FORMERR
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum EdnsError {
+    #[error("more than one OPT record")]
+    Duplicate,
+
+    #[error("OPT record owned by {0} instead of the root")]
+    NotRoot(String),
+
+    #[error("OPT options overrun the RDATA")]
+    TruncatedOption,
+
+    #[error("malformed client subnet option")]
+    BadClientSubnet,
+}
+
+/// Errors that can occur while parsing a local record definition
+#[derive(Debug, thiserror::Error)]
+pub enum LocalRecordError {
+    #[error("Missing {0}")]
+    MissingField(&'static str),
+
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+
+    #[error("Invalid domain name: {0}")]
+    InvalidName(String),
+
+    #[error("Unsupported record type: {0}")]
+    UnsupportedType(String),
+
+    #[error("Unexpected trailing input: {0}")]
+    TrailingInput(String),
+
+    #[error("Record {index}: {reason}")]
+    InvalidEntry {
+        index: usize,
+        reason: Box<LocalRecordError>,
+    },
+
+    #[error("Malformed document: {0}")]
+    Malformed(String),
+
+    #[error("Invalid classless block: {0} (expected an aligned IPv4 /25 to /31, e.g. 192.168.1.64/26)")]
+    InvalidBlock(String),
+
+    #[error("No version {0} in the history (see 'versions')")]
+    UnknownVersion(u32),
+}
+
+/// Errors returned for commands received on the control socket
+#[derive(Debug, thiserror::Error)]
+pub enum ControlError {
+    #[error("Unknown command: {0} (try 'help')")]
+    UnknownCommand(String),
+
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+
+    #[error("authentication required (send 'auth <token>')")]
+    AuthRequired,
+
+    #[error("invalid token")]
+    InvalidToken,
+
+    #[error("client certificate not allowed on this control socket")]
+    CertificateNotAllowed,
+
+    #[error(transparent)]
+    InvalidRecord(#[from] LocalRecordError),
+}
+
+/// A mnemonic or number that doesn't name a DNS parameter
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("Unknown {kind}: {value}")]
+    Unknown { kind: &'static str, value: String },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[cfg(not(all(unix, feature = "heap-profiling")))]
+    #[error("this build cannot take that profile (rebuild with --features {0})")]
+    Unavailable(&'static str),
+
+    #[error("profiler failed: {0}")]
+    Profiler(String),
+
+    #[error("could not write the profile: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ZoneTransferError {
+    #[error("could not reach the primary: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("the primary did not answer in time")]
+    Timeout,
+
+    #[error("the primary answered {0}")]
+    Rcode(crate::registry::Rcode),
+
+    #[error("malformed response: {0}")]
+    Malformed(String),
+
+    #[error("no SOA record for {0}")]
+    MissingSoa(String),
+
+    #[error("invalid catalog zone: {0}")]
+    InvalidCatalog(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ZoneFileError {
+    #[error("could not read the file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    InvalidRecord(#[from] LocalRecordError),
+
+    #[error("{0} is not in the zone")]
+    OutOfZone(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("could not read the file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("not valid TOML: {0}")]
+    Malformed(#[from] toml::de::Error),
+
+    #[error("unknown setting '{0}'")]
+    UnknownSetting(String),
+
+    #[error("'{0}' takes a single value")]
+    NotRepeatable(String),
+
+    #[error("'{0}' must be a string, number or boolean")]
+    UnsupportedValue(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecursionError {
+    #[error("no response: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("malformed response: {0}")]
+    Malformed(String),
+
+    #[error("the name servers answered {0}")]
+    Rcode(crate::registry::Rcode),
+
+    #[error("too many referrals for {0}")]
+    TooManyReferrals(String),
+
+    #[error("no address for any name server of {0}")]
+    NoAddresses(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DnssecError {
+    #[error("could not read the key file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("not a valid key file: {0}")]
+    Malformed(#[from] serde_json::Error),
+
+    #[error("invalid private key: {0}")]
+    InvalidKey(String),
+
+    #[error("algorithm {0} is not supported; keys are ECDSA P-256 (13)")]
+    UnsupportedAlgorithm(u8),
+
+    #[error("could not generate a key")]
+    KeyGeneration,
+}
@@ -0,0 +1,328 @@
+//! Client geolocation for location-aware answers (GeoDNS)
+//!
+//! With `--geoip-db` pointing at a MaxMind-format database (GeoLite2 Country
+//! or City, or any MMDB with the same layout), the client address of every
+//! query is looked up in memory. Local records tagged `country=<ISO code>` or
+//! `continent=<code>` are then only given to clients from there; see
+//! `LocalRecordsActor::lookup` for how the answer set is chosen.
+//!
+//! For multi-site setups, `--site <name>=<cidr>` maps client subnets to sites,
+//! and records tagged `site=<name>` are preferred for clients of that site.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use tracing::debug;
+
+use crate::errors::LocalRecordError;
+
+/// An opened GeoIP database
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    /// Load the whole database into memory
+    pub fn open(path: &Path) -> Result<Self, MaxMindDBError> {
+        Ok(Self {
+            reader: Reader::open_readfile(path)?,
+        })
+    }
+
+    /// The database type from its metadata, e.g. `GeoLite2-Country`
+    pub fn database_type(&self) -> &str {
+        &self.reader.metadata.database_type
+    }
+
+    /// Where a client is. Addresses the database doesn't cover (private
+    /// ranges, for one) have an unknown location.
+    pub fn locate(&self, ip: IpAddr) -> ClientLocation {
+        match self.reader.lookup::<geoip2::Country>(ip) {
+            Ok(record) => ClientLocation {
+                country: record
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(str::to_string),
+                continent: record
+                    .continent
+                    .and_then(|continent| continent.code)
+                    .map(str::to_string),
+                site: None,
+            },
+            Err(MaxMindDBError::AddressNotFoundError(_)) => ClientLocation::default(),
+            Err(e) => {
+                debug!("GeoIP lookup for {} failed: {}", ip, e);
+                ClientLocation::default()
+            }
+        }
+    }
+}
+
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("database_type", &self.database_type())
+            .finish()
+    }
+}
+
+/// Works out where a client is, from the GeoIP database and the site map
+#[derive(Debug, Default)]
+pub struct Locator {
+    pub geoip: Option<GeoIp>,
+    pub sites: Vec<SiteRule>,
+}
+
+impl Locator {
+    /// Whether clients are located at all, by database or site
+    pub fn is_active(&self) -> bool {
+        self.geoip.is_some() || !self.sites.is_empty()
+    }
+
+    pub fn locate(&self, ip: IpAddr) -> ClientLocation {
+        let mut location = match &self.geoip {
+            Some(geoip) => geoip.locate(ip),
+            None => ClientLocation::default(),
+        };
+        // The most specific matching subnet wins
+        location.site = self
+            .sites
+            .iter()
+            .filter(|rule| rule.subnet.contains(ip))
+            .max_by_key(|rule| rule.subnet.prefix_len)
+            .map(|rule| rule.site.clone());
+        location
+    }
+}
+
+/// A client's country and continent codes and site, as far as they are known
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientLocation {
+    pub country: Option<String>,
+    pub continent: Option<String>,
+    pub site: Option<String>,
+}
+
+/// An IPv4 or IPv6 network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                mask(u32::from(ip).into(), 32, self.prefix_len)
+                    == mask(u32::from(network).into(), 32, self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                mask(ip.into(), 128, self.prefix_len) == mask(network.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Keep the top `prefix_len` of an address's `bits` bits
+fn mask(address: u128, bits: u8, prefix_len: u8) -> u128 {
+    match bits - prefix_len {
+        0 => address,
+        host_bits if host_bits >= 128 => 0,
+        host_bits => address >> host_bits << host_bits,
+    }
+}
+
+/// The subnet of the address alone, /32 or /128
+impl From<IpAddr> for Subnet {
+    fn from(network: IpAddr) -> Self {
+        let prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        Self {
+            network,
+            prefix_len,
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid subnet '{}', expected e.g. 192.168.1.0/24", s);
+
+        let (network, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return Err(invalid());
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+/// Clients in `subnet` belong to `site`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteRule {
+    pub site: String,
+    pub subnet: Subnet,
+}
+
+impl fmt::Display for SiteRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.site, self.subnet)
+    }
+}
+
+/// Parses `<site>=<cidr>`, as given to `--site`
+impl FromStr for SiteRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (site, subnet) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <site>=<cidr>, got '{}'", s))?;
+        if !is_site_name(site) {
+            return Err(format!("invalid site name '{}'", site));
+        }
+        Ok(Self {
+            site: site.to_ascii_lowercase(),
+            subnet: subnet.parse()?,
+        })
+    }
+}
+
+/// Site names are letters, digits, `-` and `_`
+fn is_site_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The location a local record is meant for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeoTag {
+    /// ISO 3166-1 alpha-2 country code, e.g. `DE`
+    Country(String),
+    /// Continent code as used by MaxMind: AF, AN, AS, EU, NA, OC or SA
+    Continent(String),
+    /// A site from the `--site` map
+    Site(String),
+}
+
+impl GeoTag {
+    /// Whether a client at `location` is in the tagged area
+    pub fn matches(&self, location: &ClientLocation) -> bool {
+        match self {
+            GeoTag::Country(code) => location.country.as_deref() == Some(code.as_str()),
+            GeoTag::Continent(code) => location.continent.as_deref() == Some(code.as_str()),
+            GeoTag::Site(site) => location.site.as_deref() == Some(site.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for GeoTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoTag::Country(code) => write!(f, "country={}", code),
+            GeoTag::Continent(code) => write!(f, "continent={}", code),
+            GeoTag::Site(site) => write!(f, "site={}", site),
+        }
+    }
+}
+
+/// Parses `country=<code>`, `continent=<code>` (codes are two letters) or
+/// `site=<name>`
+impl FromStr for GeoTag {
+    type Err = LocalRecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || LocalRecordError::InvalidValue(s.to_string());
+
+        let (kind, code) = s.split_once('=').ok_or_else(invalid)?;
+        if kind.eq_ignore_ascii_case("site") {
+            if !is_site_name(code) {
+                return Err(invalid());
+            }
+            return Ok(GeoTag::Site(code.to_ascii_lowercase()));
+        }
+        if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+            return Err(invalid());
+        }
+        let code = code.to_ascii_uppercase();
+        match kind.to_ascii_lowercase().as_str() {
+            "country" => Ok(GeoTag::Country(code)),
+            "continent" => Ok(GeoTag::Continent(code)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geo_tag() {
+        let tag: GeoTag = "country=de".parse().unwrap();
+        assert_eq!(tag, GeoTag::Country("DE".to_string()));
+        assert_eq!(tag.to_string(), "country=DE");
+
+        let client = ClientLocation {
+            country: Some("DE".to_string()),
+            continent: Some("EU".to_string()),
+            site: None,
+        };
+        assert!(tag.matches(&client));
+        assert!("continent=EU".parse::<GeoTag>().unwrap().matches(&client));
+        assert!(!"continent=NA".parse::<GeoTag>().unwrap().matches(&client));
+        assert!(!tag.matches(&ClientLocation::default()));
+
+        for invalid in ["DE", "country=DEU", "region=EU", "country=1a"] {
+            assert!(invalid.parse::<GeoTag>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_site_map() {
+        let locator = Locator {
+            geoip: None,
+            sites: ["lab=10.0.0.0/8", "rack2=10.2.0.0/16", "v6=2001:db8::/32"]
+                .iter()
+                .map(|rule| rule.parse().unwrap())
+                .collect(),
+        };
+        let site = |ip: &str| locator.locate(ip.parse().unwrap()).site;
+
+        assert_eq!(site("10.1.2.3").as_deref(), Some("lab"));
+        assert_eq!(site("10.2.0.1").as_deref(), Some("rack2"));
+        assert_eq!(site("2001:db8::1").as_deref(), Some("v6"));
+        assert_eq!(site("192.168.1.1"), None);
+        assert_eq!(site("::ffff:10.1.2.3"), None);
+
+        assert!("0.0.0.0/0"
+            .parse::<Subnet>()
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+        assert!("lab=10.0.0.0/33".parse::<SiteRule>().is_err());
+        assert!("l@b=10.0.0.0/8".parse::<SiteRule>().is_err());
+        assert_eq!(
+            "site=Lab".parse::<GeoTag>().unwrap(),
+            GeoTag::Site("lab".to_string())
+        );
+    }
+}
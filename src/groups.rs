@@ -0,0 +1,450 @@
+//! Client groups with policies of their own (`--group`, `--client`)
+//!
+//! A group gathers clients, by subnet, address, or a MAC address or hostname
+//! mapped to an address with `--client`, and gives them a blocklist,
+//! rewrites, upstreams and query logging of their own. Groups are tried in
+//! the order given and the first holding the client wins; clients in none
+//! get the tenant's settings.
+//!
+//! ```text
+//! --client tablet=192.168.1.41
+//! --client 3c:22:fb:12:34:56=192.168.1.42
+//! --group name=kids,client=tablet,client=3c:22:fb:12:34:56,blocklist=/etc/dns/kids.txt,rewrite=www.youtube.com A 216.239.38.120,upstream=1.1.1.3:53
+//! --group name=servers,client=10.0.0.0/24,blocklist=none,log=failures
+//! ```
+//!
+//! What a group doesn't set is the tenant's: without a `blocklist` its
+//! clients get the tenant's blocklist, and `blocklist=none` blocks nothing
+//! for them. A group's own blocklist replaces the tenant's, though the
+//! tenant's `--allow` rules still let names through it. Rewrites are records
+//! written as for `--record`, answered to the group's clients ahead of the
+//! blocklist. Upstreams resolve the group's questions unless a `--route`
+//! sends them elsewhere, with answers cached apart from the tenant's.
+//! `log=failures` logs only the group's failed queries, and `log=none` none
+//! at all.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::blocklist::{BlocklistSource, PublishedBlocklist};
+use crate::geoip::Subnet;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::local_records::{normalize_name, validate_name, LocalRecord};
+use crate::query_log::Disposition;
+use crate::routing::Route;
+use crate::upstream::Upstream;
+
+/// How a group's clients are told apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientId {
+    /// An address or a subnet
+    Subnet(Subnet),
+    /// A MAC address or hostname, mapped to an address with `--client`
+    Named(String),
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientId::Subnet(subnet) => write!(f, "{}", subnet),
+            ClientId::Named(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// `192.168.1.0/24` or `192.168.1.41` is a subnet; `3c:22:fb:12:34:56` (or
+/// with dashes) a MAC address; anything else a hostname
+impl FromStr for ClientId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('/') {
+            return s.parse().map(ClientId::Subnet);
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(ClientId::Subnet(ip.into()));
+        }
+        if let Some(mac) = parse_mac(s) {
+            return Ok(ClientId::Named(mac));
+        }
+        validate_name(s).map_err(|_| format!("invalid client '{}'", s))?;
+        Ok(ClientId::Named(normalize_name(s)))
+    }
+}
+
+/// A MAC address in lowercase with colons, from colons or dashes
+fn parse_mac(s: &str) -> Option<String> {
+    let octets: Vec<&str> = s.split([':', '-']).collect();
+    let valid = octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.bytes().all(|b| b.is_ascii_hexdigit()));
+    valid.then(|| octets.join(":").to_ascii_lowercase())
+}
+
+/// A MAC address or hostname mapped to a client's address (`--client`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientName {
+    pub name: String,
+    pub address: IpAddr,
+}
+
+impl fmt::Display for ClientName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.address)
+    }
+}
+
+/// Parses `<mac|hostname>=<ip>`
+impl FromStr for ClientName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, address) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <mac|hostname>=<ip>, got '{}'", s))?;
+        let name = match name.parse()? {
+            ClientId::Named(name) => name,
+            ClientId::Subnet(_) => return Err(format!("'{}' is an address already", name)),
+        };
+        let address = address
+            .parse()
+            .map_err(|_| format!("invalid client address '{}'", address))?;
+        Ok(ClientName { name, address })
+    }
+}
+
+/// Which of a group's queries are logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GroupLog {
+    #[default]
+    All,
+    /// Only those that failed
+    Failures,
+    None,
+}
+
+impl GroupLog {
+    /// Whether a query answered as `disposition` is logged
+    pub fn logs(self, disposition: Disposition) -> bool {
+        match self {
+            GroupLog::All => true,
+            GroupLog::Failures => disposition == Disposition::Failed,
+            GroupLog::None => false,
+        }
+    }
+}
+
+impl fmt::Display for GroupLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GroupLog::All => "all",
+            GroupLog::Failures => "failures",
+            GroupLog::None => "none",
+        })
+    }
+}
+
+impl FromStr for GroupLog {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(GroupLog::All),
+            "failures" => Ok(GroupLog::Failures),
+            "none" => Ok(GroupLog::None),
+            _ => Err(format!(
+                "invalid group log '{}', expected all, failures or none",
+                s
+            )),
+        }
+    }
+}
+
+/// A group as given on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub clients: Vec<ClientId>,
+    /// The group's own blocklists; the tenant's when none are given
+    pub blocklists: Vec<BlocklistSource>,
+    /// Whether nothing is blocked for the group (`blocklist=none`)
+    pub unblocked: bool,
+    /// Records answered to the group's clients
+    pub rewrites: Vec<LocalRecord>,
+    /// Upstream resolvers; the tenant's when none are given
+    pub upstreams: Vec<Upstream>,
+    pub log: GroupLog,
+}
+
+impl Group {
+    /// The subnets of the group's clients, with names looked up in `names`
+    pub fn subnets(&self, names: &[ClientName]) -> Result<Vec<Subnet>, String> {
+        let mut subnets = Vec::with_capacity(self.clients.len());
+        for client in &self.clients {
+            match client {
+                ClientId::Subnet(subnet) => subnets.push(*subnet),
+                ClientId::Named(name) => {
+                    let before = subnets.len();
+                    subnets.extend(
+                        names
+                            .iter()
+                            .filter(|mapped| &mapped.name == name)
+                            .map(|mapped| Subnet::from(mapped.address.to_canonical())),
+                    );
+                    if subnets.len() == before {
+                        return Err(format!(
+                            "group {}: no --client gives {} an address",
+                            self.name, name
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(subnets)
+    }
+}
+
+/// Groups can't share a name
+pub fn check_distinct(groups: &[Group]) -> Result<(), String> {
+    for (i, group) in groups.iter().enumerate() {
+        if groups[..i].iter().any(|other| other.name == group.name) {
+            return Err(format!("more than one group is named {}", group.name));
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "name={}", self.name)?;
+        for client in &self.clients {
+            write!(f, ",client={}", client)?;
+        }
+        if self.unblocked {
+            write!(f, ",blocklist=none")?;
+        }
+        for source in &self.blocklists {
+            write!(f, ",blocklist={}", source)?;
+        }
+        for record in &self.rewrites {
+            write!(f, ",rewrite={}", record)?;
+        }
+        for upstream in &self.upstreams {
+            write!(f, ",upstream={}", upstream)?;
+        }
+        if self.log != GroupLog::All {
+            write!(f, ",log={}", self.log)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses comma-separated `key=value` settings: `name` and `client` are
+/// required; `blocklist`, `rewrite`, `upstream` and `log` are optional; all
+/// but `name` and `log` are repeatable
+impl FromStr for Group {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut group = Group {
+            name: String::new(),
+            clients: Vec::new(),
+            blocklists: Vec::new(),
+            unblocked: false,
+            rewrites: Vec::new(),
+            upstreams: Vec::new(),
+            log: GroupLog::All,
+        };
+
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got '{}'", setting))?;
+            match key {
+                "name" => {
+                    if value.is_empty()
+                        || !value
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+                    {
+                        return Err(format!("invalid group name '{}'", value));
+                    }
+                    name = Some(value.to_string());
+                }
+                "client" => group.clients.push(value.parse()?),
+                "blocklist" if value == "none" => group.unblocked = true,
+                "blocklist" => group.blocklists.push(value.parse()?),
+                "rewrite" => group.rewrites.push(
+                    value
+                        .parse()
+                        .map_err(|e| format!("invalid rewrite '{}': {}", value, e))?,
+                ),
+                "upstream" => group.upstreams.push(value.parse()?),
+                "log" => group.log = value.parse()?,
+                _ => return Err(format!("unknown group setting '{}'", key)),
+            }
+        }
+
+        group.name = name.ok_or("group needs a name=<name>")?;
+        if group.clients.is_empty() {
+            return Err(format!("group {} needs a client=<client>", group.name));
+        }
+        if group.unblocked && !group.blocklists.is_empty() {
+            return Err(format!(
+                "group {} has blocklists and blocklist=none",
+                group.name
+            ));
+        }
+        Ok(group)
+    }
+}
+
+/// What a group's clients are blocked by
+#[derive(Debug)]
+pub enum GroupBlocking {
+    /// The tenant's blocklist
+    Tenant,
+    /// The group's own
+    Own(Arc<PublishedBlocklist>),
+    /// Nothing
+    Off,
+}
+
+/// A group ready to answer its clients
+#[derive(Debug)]
+pub struct ClientGroup {
+    pub name: Arc<str>,
+    pub subnets: Vec<Subnet>,
+    pub blocking: GroupBlocking,
+    pub rewrites: LocalRecordsHandle,
+    /// The group's upstreams, as a route of their own
+    pub route: Option<Route>,
+    pub log: GroupLog,
+}
+
+/// A tenant's groups, in order
+#[derive(Debug, Default)]
+pub struct ClientGroups {
+    groups: Vec<ClientGroup>,
+}
+
+impl ClientGroups {
+    pub fn new(groups: Vec<ClientGroup>) -> Self {
+        Self { groups }
+    }
+
+    /// The first group holding `client`, if any
+    pub fn find(&self, client: IpAddr) -> Option<&ClientGroup> {
+        if self.groups.is_empty() {
+            return None;
+        }
+        let client = client.to_canonical();
+        self.groups
+            .iter()
+            .find(|group| group.subnets.iter().any(|subnet| subnet.contains(client)))
+    }
+
+    /// The groups' own blocklists, by group name
+    pub fn blocklists(&self) -> impl Iterator<Item = (&str, &Arc<PublishedBlocklist>)> {
+        self.groups
+            .iter()
+            .filter_map(|group| match &group.blocking {
+                GroupBlocking::Own(blocklist) => Some((&*group.name, blocklist)),
+                GroupBlocking::Tenant | GroupBlocking::Off => None,
+            })
+    }
+
+    /// Whether a query of `client` answered as `disposition` is logged
+    pub fn logs(&self, client: IpAddr, disposition: Disposition) -> bool {
+        self.find(client)
+            .is_none_or(|group| group.log.logs(disposition))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_group() {
+        let spec = "name=kids,client=192.168.1.0/26,client=192.168.1.99,client=3C-22-FB-12-34-56,\
+                    client=Tablet.lan,blocklist=/etc/dns/kids.txt,\
+                    rewrite=www.youtube.com CNAME restrict.youtube.com,upstream=1.1.1.3:53,log=failures";
+        let group: Group = spec.parse().unwrap();
+        assert_eq!(group.name, "kids");
+        assert_eq!(
+            group.clients,
+            vec![
+                ClientId::Subnet("192.168.1.0/26".parse().unwrap()),
+                ClientId::Subnet("192.168.1.99/32".parse().unwrap()),
+                ClientId::Named("3c:22:fb:12:34:56".to_string()),
+                ClientId::Named("tablet.lan".to_string()),
+            ]
+        );
+        assert_eq!(group.blocklists.len(), 1);
+        assert_eq!(group.rewrites[0].name, "www.youtube.com");
+        assert_eq!(group.upstreams.len(), 1);
+        assert_eq!(group.log, GroupLog::Failures);
+        assert_eq!(group.to_string().parse::<Group>().unwrap(), group);
+
+        let open: Group = "name=servers,client=10.0.0.0/24,blocklist=none"
+            .parse()
+            .unwrap();
+        assert!(open.unblocked);
+        assert_eq!(open.to_string().parse::<Group>().unwrap(), open);
+
+        assert!("client=10.0.0.0/8".parse::<Group>().is_err());
+        assert!("name=kids".parse::<Group>().is_err());
+        assert!(
+            "name=kids,client=10.0.0.0/8,blocklist=none,blocklist=/etc/dns/kids.txt"
+                .parse::<Group>()
+                .is_err()
+        );
+        assert!("name=kids,client=10.0.0.0/8,log=some"
+            .parse::<Group>()
+            .is_err());
+        assert!("name=kids,client=10.0.0.0/33".parse::<Group>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_group_clients() {
+        let names: Vec<ClientName> = ["tablet=192.168.1.41", "3c-22-fb-12-34-56=fd00::42"]
+            .iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        assert!("192.168.1.1=192.168.1.41".parse::<ClientName>().is_err());
+        assert!("tablet".parse::<ClientName>().is_err());
+
+        let kids: Group = "name=kids,client=TABLET,client=3c:22:fb:12:34:56"
+            .parse()
+            .unwrap();
+        let office: Group = "name=office,client=192.168.1.0/24".parse().unwrap();
+        let groups = ClientGroups::new(
+            [kids, office]
+                .iter()
+                .map(|group| ClientGroup {
+                    name: group.name.as_str().into(),
+                    subnets: group.subnets(&names).unwrap(),
+                    blocking: GroupBlocking::Tenant,
+                    rewrites: LocalRecordsHandle::new(),
+                    route: None,
+                    log: group.log,
+                })
+                .collect(),
+        );
+        let group = |ip: &str| groups.find(ip.parse().unwrap()).map(|group| &*group.name);
+        // The first group holding the client wins
+        assert_eq!(group("192.168.1.41"), Some("kids"));
+        assert_eq!(group("::ffff:192.168.1.41"), Some("kids"));
+        assert_eq!(group("fd00::42"), Some("kids"));
+        assert_eq!(group("192.168.1.40"), Some("office"));
+        assert_eq!(group("10.0.0.1"), None);
+
+        let unmapped: Group = "name=lab,client=printer".parse().unwrap();
+        assert!(unmapped.subnets(&names).is_err());
+    }
+}
@@ -1 +1,5 @@
+pub mod cache_handler;
+pub mod dedup_handler;
+pub mod inflight_handler;
+pub mod local_records_handler;
 pub mod query_handler;
\ No newline at end of file
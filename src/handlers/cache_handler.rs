@@ -0,0 +1,165 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::actors::{
+    cache_actor::{CacheActor, CacheTable},
+    messages::{CacheKey, CacheUsage, Cached, Resolution},
+};
+use crate::metrics::Metrics;
+use crate::registry::RecordType;
+use crate::shared_cache::SharedCache;
+use clap::ValueEnum;
+
+/// Answers a tenant's cache holds unless `--cache-size` says otherwise
+pub const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+/// Hits that make an answer popular unless `--prefetch-hits` says otherwise
+pub const DEFAULT_PREFETCH_HITS: u64 = 3;
+
+/// Seconds before expiry a popular answer is refreshed in, unless
+/// `--prefetch-window` says otherwise
+pub const DEFAULT_PREFETCH_WINDOW: u64 = 10;
+
+/// When cached answers are looked up again before they expire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefetch {
+    /// Hits an answer needs before it is refreshed
+    pub hits: u64,
+    /// How close to expiring a hit has to come to refresh it
+    pub window: Duration,
+}
+
+/// Which answers a full cache evicts first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Eviction {
+    /// The least recently used
+    #[default]
+    Lru,
+    /// The least often used, the least recently used of those first
+    Lfu,
+}
+
+impl fmt::Display for Eviction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Eviction::Lru => write!(f, "lru"),
+            Eviction::Lfu => write!(f, "lfu"),
+        }
+    }
+}
+
+/// How big a cache may grow and what it does when full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Most answers held; none turns caching off
+    pub max_entries: usize,
+    /// Most memory the answers may hold, estimated, if limited
+    pub max_bytes: Option<usize>,
+    pub eviction: Eviction,
+    /// When popular answers are refreshed; never when None
+    pub prefetch: Option<Prefetch>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: DEFAULT_CACHE_SIZE,
+            max_bytes: None,
+            eviction: Eviction::default(),
+            prefetch: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheHandle {
+    // None when caching is off
+    table: Option<Arc<CacheTable>>,
+    // Consulted on a miss and written through, if any
+    shared: Option<Arc<SharedCache>>,
+}
+
+// Gives you access to the cache table, and through it to the actor.
+impl CacheHandle {
+    /// Starts a cache sized and refreshed as `config` says, counting its
+    /// evictions in `metrics`; room for no answers turns caching off
+    pub fn new(config: CacheConfig, metrics: Arc<Metrics>) -> Self {
+        if config.max_entries == 0 {
+            return Self {
+                table: None,
+                shared: None,
+            };
+        }
+        let (table, receiver) = CacheTable::new(config, metrics);
+        let table = Arc::new(table);
+        let mut actor = CacheActor::new(receiver, &table);
+        tokio::spawn(async move { actor.run().await });
+
+        Self {
+            table: Some(table),
+            shared: None,
+        }
+    }
+
+    /// Backs the cache with one shared between instances: misses are looked
+    /// up there and answers cached are written there too.
+    pub fn with_shared(self, shared: SharedCache) -> Self {
+        Self {
+            shared: Some(Arc::new(shared)),
+            ..self
+        }
+    }
+
+    /// Returns the cached answer for a question, if it hasn't expired, and
+    /// whether it is due a refresh. Reads the table directly, so lookups
+    /// never queue behind each other. A local miss is looked up in the
+    /// shared cache, and an answer found there is cached locally.
+    pub async fn get(&self, key: CacheKey) -> Option<Cached> {
+        let table = self.table.as_ref()?;
+        if let Some(cached) = table.get(&key, Instant::now()) {
+            return Some(cached);
+        }
+        let resolution = self.shared.as_ref()?.get(&key).await?;
+        table.insert(key, resolution.clone(), Instant::now());
+        Some(Cached {
+            resolution,
+            prefetch: false,
+        })
+    }
+
+    /// Every answer still cached, with its TTLs counted down; none when
+    /// caching is off.
+    pub fn snapshot(&self) -> Vec<(CacheKey, Resolution)> {
+        self.table
+            .as_ref()
+            .map_or_else(Vec::new, |table| table.snapshot(Instant::now()))
+    }
+
+    /// The answers cached for a name, of one type or all, under any route;
+    /// looking doesn't count as a hit.
+    pub fn find(&self, name: &str, qtype: Option<RecordType>) -> Vec<(CacheKey, Resolution)> {
+        self.table
+            .as_ref()
+            .map_or_else(Vec::new, |table| table.find(name, qtype, Instant::now()))
+    }
+
+    /// How full the cache is; none when caching is off.
+    pub fn usage(&self) -> Option<CacheUsage> {
+        self.table.as_ref().map(|table| table.usage())
+    }
+
+    /// Caches an answer; failures and answers without a TTL are left out.
+    /// The shared cache, if any, is written in the background.
+    pub async fn insert(&self, key: CacheKey, resolution: Resolution) {
+        let Some(table) = &self.table else {
+            return;
+        };
+        if let Some(shared) = &self.shared {
+            let shared = Arc::clone(shared);
+            let (key, resolution) = (key.clone(), resolution.clone());
+            tokio::spawn(async move { shared.put(&key, &resolution).await });
+        }
+        table.insert(key, resolution, Instant::now());
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::actors::messages::{Duplicate, QueryKey};
+
+/// Locks the queries are spread over, so query tasks rarely wait on each other
+const LOCKS: usize = 16;
+
+/// Remembers recent queries so that retransmissions from stub resolvers
+/// don't each trigger a fresh resolution. Query tasks look their queries up
+/// directly, each locking only the part of the table its query falls in.
+#[derive(Clone, Debug)]
+pub struct DedupHandle {
+    table: Arc<DedupTable>,
+}
+
+#[derive(Debug)]
+struct DedupTable {
+    // How long a query is remembered after it was first seen
+    window: Duration,
+    hasher: RandomState,
+    queries: Box<[Mutex<Queries>]>,
+}
+
+#[derive(Debug)]
+struct Queries {
+    // Recent queries, with the response once it has been sent
+    entries: HashMap<QueryKey, Entry>,
+    // When expired entries were last dropped
+    last_prune: Instant,
+}
+
+impl Default for Queries {
+    fn default() -> Self {
+        Queries {
+            entries: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    seen: Instant,
+    response: Option<Vec<u8>>,
+}
+
+impl DedupHandle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            table: Arc::new(DedupTable {
+                window,
+                hasher: RandomState::new(),
+                queries: (0..LOCKS).map(|_| Mutex::default()).collect(),
+            }),
+        }
+    }
+
+    /// Registers a query, returning whether it repeats one seen within the window.
+    pub fn begin(&self, key: QueryKey) -> Duplicate {
+        self.table.begin(key, Instant::now())
+    }
+
+    /// Stores the encoded response for a query so retransmissions can reuse it.
+    pub fn complete(&self, key: QueryKey, response: Vec<u8>) {
+        if let Some(entry) = self.table.lock(&key).entries.get_mut(&key) {
+            entry.response = Some(response);
+        }
+    }
+
+    /// Forgets a query that got no response, so that a retransmission is
+    /// resolved afresh instead of waiting out the window.
+    pub fn forget(&self, key: &QueryKey) {
+        self.table.lock(key).entries.remove(key);
+    }
+}
+
+impl DedupTable {
+    fn lock(&self, key: &QueryKey) -> MutexGuard<'_, Queries> {
+        let lock = &self.queries[self.hasher.hash_one(key) as usize % LOCKS];
+        // Entries are replaced whole, so a poisoned lock is safe to use
+        lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn begin(&self, key: QueryKey, now: Instant) -> Duplicate {
+        let window = self.window;
+        let mut queries = self.lock(&key);
+        // Drop expired entries at most once per window to keep this cheap
+        if now.duration_since(queries.last_prune) >= window {
+            queries
+                .entries
+                .retain(|_, entry| now.duration_since(entry.seen) < window);
+            queries.last_prune = now;
+        }
+
+        match queries.entries.get(&key) {
+            Some(entry) if now.duration_since(entry.seen) < window => match &entry.response {
+                Some(response) => Duplicate::Answered(response.clone()),
+                None => Duplicate::InFlight,
+            },
+            _ => {
+                queries.entries.insert(
+                    key,
+                    Entry {
+                        seen: now,
+                        response: None,
+                    },
+                );
+                Duplicate::New
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::RecordType;
+
+    fn key(id: u16) -> QueryKey {
+        QueryKey {
+            client: "192.168.1.20:53311".parse().unwrap(),
+            id,
+            name: Arc::from("example.com"),
+            qtype: RecordType::A,
+        }
+    }
+
+    #[test]
+    fn test_duplicates_within_window() {
+        let dedup = DedupHandle::new(Duration::from_secs(2));
+        let table = &dedup.table;
+        let start = Instant::now();
+
+        assert_eq!(table.begin(key(1), start), Duplicate::New);
+        assert_eq!(table.begin(key(1), start), Duplicate::InFlight);
+        // A different transaction ID is a different query
+        assert_eq!(table.begin(key(2), start), Duplicate::New);
+
+        dedup.complete(key(1), vec![1, 2, 3]);
+        assert_eq!(
+            table.begin(key(1), start + Duration::from_secs(1)),
+            Duplicate::Answered(vec![1, 2, 3])
+        );
+
+        // Once the window has passed the query is resolved afresh
+        assert_eq!(
+            table.begin(key(1), start + Duration::from_secs(3)),
+            Duplicate::New
+        );
+    }
+
+    #[test]
+    fn test_forgotten_query_is_new() {
+        let dedup = DedupHandle::new(Duration::from_secs(2));
+
+        assert_eq!(dedup.begin(key(1)), Duplicate::New);
+        assert_eq!(dedup.begin(key(1)), Duplicate::InFlight);
+        dedup.forget(&key(1));
+        assert_eq!(dedup.begin(key(1)), Duplicate::New);
+    }
+}
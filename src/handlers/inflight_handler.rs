@@ -0,0 +1,44 @@
+use tokio::sync::{mpsc, oneshot};
+
+use crate::actors::{
+    inflight_actor::InflightActor,
+    messages::{CacheKey, InflightMessage, Joined, Resolution},
+};
+
+#[derive(Clone, Debug)]
+pub struct InflightHandle {
+    sender: mpsc::Sender<InflightMessage>,
+}
+
+// Gives you access to the underlying actor.
+impl InflightHandle {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(64);
+        let mut actor = InflightActor::new(receiver);
+        tokio::spawn(async move { actor.run().await });
+
+        Self { sender }
+    }
+
+    /// Joins the lookup in flight for a question, or makes the caller lead one.
+    pub async fn join(&self, key: CacheKey) -> Joined {
+        let (send, recv) = oneshot::channel();
+        let msg = InflightMessage::Join {
+            key,
+            respond_to: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below.
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Hands the outcome of a led lookup to the queries that joined it.
+    pub async fn finish(&self, key: CacheKey, resolution: Resolution) {
+        let _ = self
+            .sender
+            .send(InflightMessage::Finish { key, resolution })
+            .await;
+    }
+}
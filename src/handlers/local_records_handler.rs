@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::actors::local_records_actor::{LocalRecordsActor, PublishedTable};
+use crate::actors::messages::LocalRecordsMessage;
+use crate::errors::LocalRecordError;
+use crate::geoip::ClientLocation;
+use crate::health::CheckTarget;
+use crate::local_records::{ImportDiff, LocalRecord, VersionInfo};
+use crate::registry::RecordType;
+
+#[derive(Clone, Debug)]
+pub struct LocalRecordsHandle {
+    sender: mpsc::Sender<LocalRecordsMessage>,
+    // The actor's latest table, read directly by lookups
+    table: Arc<PublishedTable>,
+}
+
+// Gives you access to the underlying actor.
+impl LocalRecordsHandle {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(32);
+        let table = Arc::new(PublishedTable::default());
+        let mut actor = LocalRecordsActor::new(receiver, Arc::clone(&table));
+        tokio::spawn(async move { actor.run().await });
+
+        Self { sender, table }
+    }
+
+    /// Returns the local records answering the given name and type for a
+    /// client at the given location. Reads the latest published table
+    /// directly, so lookups never queue behind each other or behind changes.
+    pub fn lookup(
+        &self,
+        name: &str,
+        qtype: RecordType,
+        client: &ClientLocation,
+    ) -> Vec<LocalRecord> {
+        self.table.load().lookup(name, qtype, client)
+    }
+
+    /// PTR records for a reverse name made from the local addresses
+    pub fn synthesize_ptr(&self, name: &str) -> Vec<LocalRecord> {
+        self.table.load().synthesize_ptr(name)
+    }
+
+    /// The types of the records at a name, for denying the others
+    pub fn types(&self, name: &str) -> Vec<RecordType> {
+        self.table.load().types(name)
+    }
+
+    /// Whether a name exists in the local records, for telling NODATA from
+    /// NXDOMAIN
+    pub fn exists(&self, name: &str) -> bool {
+        self.table.load().exists(name)
+    }
+
+    /// Adds a record. Returns false if the record was already present.
+    pub async fn add(&self, record: LocalRecord) -> bool {
+        let (send, recv) = oneshot::channel();
+        let msg = LocalRecordsMessage::Add {
+            record,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Removes the records for a name (optionally only one type), returning how many were removed.
+    pub async fn remove(&self, name: String, rtype: Option<RecordType>) -> usize {
+        let (send, recv) = oneshot::channel();
+        let msg = LocalRecordsMessage::Remove {
+            name,
+            rtype,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Returns all local records, sorted by name.
+    pub async fn list(&self) -> Vec<LocalRecord> {
+        let (send, recv) = oneshot::channel();
+        let msg = LocalRecordsMessage::List { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Imports records, merging them in or replacing the whole table.
+    /// With `dry_run` the table is left untouched and only the diff is returned.
+    pub async fn import(
+        &self,
+        records: Vec<LocalRecord>,
+        replace: bool,
+        dry_run: bool,
+    ) -> ImportDiff {
+        let (send, recv) = oneshot::channel();
+        let msg = LocalRecordsMessage::Import {
+            records,
+            replace,
+            dry_run,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Replaces everything at or below a zone with the given records, e.g. a
+    /// freshly transferred copy of the zone. `change` is how the new version
+    /// is described in the history.
+    pub async fn replace_zone(
+        &self,
+        zone: String,
+        records: Vec<LocalRecord>,
+        change: String,
+    ) -> ImportDiff {
+        let (send, recv) = oneshot::channel();
+        let msg = LocalRecordsMessage::ReplaceZone {
+            zone,
+            records,
+            change,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Returns the versions kept in the history, oldest first.
+    pub async fn versions(&self) -> Vec<VersionInfo> {
+        let (send, recv) = oneshot::channel();
+        let msg = LocalRecordsMessage::Versions { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Compares two versions, or a version with the current records when
+    /// `to` is None, optionally only at or below a zone.
+    pub async fn diff(
+        &self,
+        from: u32,
+        to: Option<u32>,
+        zone: Option<String>,
+    ) -> Result<ImportDiff, LocalRecordError> {
+        let (send, recv) = oneshot::channel();
+        let msg = LocalRecordsMessage::Diff {
+            from,
+            to,
+            zone,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Restores an earlier version, optionally only at or below a zone.
+    pub async fn rollback(
+        &self,
+        serial: u32,
+        zone: Option<String>,
+    ) -> Result<ImportDiff, LocalRecordError> {
+        let (send, recv) = oneshot::channel();
+        let msg = LocalRecordsMessage::Rollback {
+            serial,
+            zone,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Marks a health-checked target up or down, for failover.
+    pub async fn set_health(&self, target: CheckTarget, healthy: bool) {
+        let _ = self
+            .sender
+            .send(LocalRecordsMessage::SetHealth { target, healthy })
+            .await;
+    }
+}
@@ -1,33 +1,78 @@
-use std::net::IpAddr;
+use std::sync::Arc;
 
-use hickory_resolver::Resolver;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, Mutex};
 // pub mod actors;
 
-use hickory_resolver::name_server::TokioConnectionProvider;
+use crate::actors::{
+    messages::{QueryActorMessage, Resolution},
+    query_actor::QueryActor,
+    recursor_actor::RecursorActor,
+};
+use crate::hedge::HedgedResolver;
+use crate::metrics::Metrics;
+use crate::recursor::Recursor;
+use crate::registry::{Rcode, RecordType};
+use crate::supervisor::supervise;
 
-use crate::actors::{messages::QueryActorMessage, query_actor::QueryActor};
+/// Query actors started per tenant unless `--resolver-workers` says otherwise
+pub const DEFAULT_RESOLVER_WORKERS: u16 = 16;
 
+/// Queries waiting for an actor, per actor in the pool
+const QUEUE_PER_WORKER: usize = 8;
+
+/// A pool of query actors working off one queue
 #[derive(Clone, Debug)]
 pub struct QueryActorHandle {
     sender: mpsc::Sender<QueryActorMessage>,
 }
 
-// Gives you access to the underlying actor.
+// Gives you access to the underlying actors.
 impl QueryActorHandle {
-    pub fn new(resolver: Resolver<TokioConnectionProvider>) -> Self {
-        let (sender, receiver) = mpsc::channel(8);
-        let mut actor = QueryActor::new(receiver, resolver);
-        tokio::spawn(async move { actor.run().await });
+    /// Starts `workers` actors sharing the resolver (and its cache). Each
+    /// resolves one name at a time, so this is how many upstream lookups can
+    /// be in flight at once. Whichever actor is free takes the next query, so
+    /// a slow upstream holds up only the actor waiting on it. An actor that
+    /// panics is replaced, and counted in `metrics`.
+    pub fn new(resolver: HedgedResolver, workers: usize, metrics: Arc<Metrics>) -> Self {
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::channel(workers * QUEUE_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers {
+            let (receiver, resolver) = (receiver.clone(), resolver.clone());
+            supervise("query", Arc::clone(&metrics), move || {
+                let mut actor = QueryActor::new(receiver.clone(), resolver.clone());
+                async move { actor.run().await }
+            });
+        }
+
+        Self { sender }
+    }
+
+    /// Starts `workers` actors resolving from the root down with a shared
+    /// recursor (`--recursive`) instead of asking upstream resolvers
+    pub fn recursive(recursor: Arc<Recursor>, workers: usize, metrics: Arc<Metrics>) -> Self {
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::channel(workers * QUEUE_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers {
+            let (receiver, recursor) = (receiver.clone(), Arc::clone(&recursor));
+            supervise("recursor", Arc::clone(&metrics), move || {
+                let mut actor = RecursorActor::new(receiver.clone(), Arc::clone(&recursor));
+                async move { actor.run().await }
+            });
+        }
 
         Self { sender }
     }
 
-    /// Resolves a DNS name to an IPv4 address.
-    pub async fn resolve(&self, name: String) -> Option<Vec<IpAddr>> {
+    /// Resolves a DNS name to its records of `qtype`, or explains why there
+    /// are none. A query lost with an actor that panicked fails with
+    /// SERVFAIL.
+    pub async fn resolve(&self, name: Arc<str>, qtype: RecordType) -> Resolution {
         let (send, recv) = oneshot::channel();
         let msg = QueryActorMessage::Resolve {
             name,
+            qtype,
             respond_to: send,
         };
 
@@ -37,11 +82,83 @@ impl QueryActorHandle {
         let _ = self.sender.send(msg).await;
 
         // this is going back once the msg comes back from the actor.
-        // NOTE: we might get None back, i.e. no value for the given key.
-        if let Some(ips) = recv.await.expect("Actor task has been killed") {
-            Some(ips)
-        } else {
-            None
+        recv.await.unwrap_or(Resolution::Failed(Rcode::SERVFAIL))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    /// An upstream that answers every query with 192.0.2.1, except those for
+    /// names starting with "slow", which it never answers
+    async fn stalling_upstream() -> SocketAddr {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(serve(sock));
+        addr
+    }
+
+    async fn serve(sock: UdpSocket) {
+        let mut buf = [0; 512];
+        loop {
+            let (len, client) = sock.recv_from(&mut buf).await.unwrap();
+            let query = &buf[..len];
+            // Names come in random case
+            if query[13..17].eq_ignore_ascii_case(b"slow") {
+                continue;
+            }
+            let mut response = query.to_vec();
+            response[2] = 0x81;
+            response[3] = 0x80;
+            // One answer and no additional records
+            response[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+            let question_end = 12 + query[12..].iter().position(|&b| b == 0).unwrap() + 5;
+            response.truncate(question_end);
+            response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+            sock.send_to(&response, client).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lost_query_fails() {
+        // A queue no actor serves any more, as when the last one crashed
+        let (sender, _) = mpsc::channel(1);
+        let pool = QueryActorHandle { sender };
+        assert!(matches!(
+            pool.resolve("example.com".into(), RecordType::A).await,
+            Resolution::Failed(Rcode::SERVFAIL)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_slow_lookup_holds_up_one_worker() {
+        let addr = stalling_upstream().await;
+        let metrics = Arc::new(Metrics::new(1));
+        let pool = QueryActorHandle::new(
+            HedgedResolver::new(&[addr.into()], None, &[], true, Arc::clone(&metrics)),
+            2,
+            metrics,
+        );
+
+        let slow = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.resolve("slow.example".into(), RecordType::A).await }
+        });
+        // Every other query goes to the worker that's free
+        for name in ["a.example", "b.example", "c.example"] {
+            let resolution = tokio::time::timeout(
+                Duration::from_secs(2),
+                pool.resolve(name.into(), RecordType::A),
+            )
+            .await
+            .expect("held up behind the slow lookup");
+            assert!(matches!(resolution, Resolution::Records(records) if records.len() == 1));
         }
+        assert!(!slow.is_finished());
+        slow.abort();
     }
 }
@@ -0,0 +1,392 @@
+//! Health checks for failover records and answer pools
+//!
+//! A local A/AAAA record with `check=<port>` is a health-checked primary: every
+//! check interval the server opens a TCP connection to the record's address
+//! on that port. With `check=http:<port>[/path]` it asks the address for the
+//! path over HTTP instead, and only a 2xx or 3xx status passes. While the
+//! check fails the record is withdrawn, and records marked `role=backup` for
+//! the same name are served instead until a primary recovers.
+//!
+//! To keep a flapping target from bouncing answers back and forth, a target
+//! only changes state after `damping` consecutive checks agree.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::future::join_all;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+
+/// Default time between checks of a target
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default number of consecutive agreeing checks before a target changes state
+pub const DEFAULT_DAMPING: u32 = 3;
+
+/// Longest a single check may take
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most of an HTTP response read for its status line
+const STATUS_LINE_LEN: usize = 256;
+
+/// How a record's address is checked
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum HealthCheck {
+    /// A TCP connection to the port opens
+    Tcp(u16),
+    /// A GET of the path on the port answers with a 2xx or 3xx status
+    Http { port: u16, path: String },
+}
+
+impl fmt::Display for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthCheck::Tcp(port) => write!(f, "{}", port),
+            HealthCheck::Http { port, path } => write!(f, "http:{}{}", port, path),
+        }
+    }
+}
+
+/// `<port>` or `http:<port>[/path]`, the path `/` unless given
+impl FromStr for HealthCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid health check '{}' (<port> or http:<port>[/path])",
+                s
+            )
+        };
+        let Some(http) = s.strip_prefix("http:") else {
+            return s.parse().map(HealthCheck::Tcp).map_err(|_| invalid());
+        };
+        let (port, path) = match http.find('/') {
+            Some(slash) => http.split_at(slash),
+            None => (http, "/"),
+        };
+        if path.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(invalid());
+        }
+        Ok(HealthCheck::Http {
+            port: port.parse().map_err(|_| invalid())?,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// TCP checks are written as their port, as they always were, and HTTP
+/// checks in their text form
+impl Serialize for HealthCheck {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            HealthCheck::Tcp(port) => serializer.serialize_u16(*port),
+            HealthCheck::Http { .. } => serializer.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HealthCheck {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Port(u16),
+            Text(String),
+        }
+        match Written::deserialize(deserializer)? {
+            Written::Port(port) => Ok(HealthCheck::Tcp(port)),
+            Written::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// An address checked, and how; records sharing one are checked once
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CheckTarget {
+    pub ip: IpAddr,
+    pub check: HealthCheck,
+}
+
+impl fmt::Display for CheckTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.check {
+            HealthCheck::Tcp(port) => write!(f, "{}", SocketAddr::new(self.ip, *port)),
+            HealthCheck::Http { port, path } => {
+                write!(f, "http://{}{}", SocketAddr::new(self.ip, *port), path)
+            }
+        }
+    }
+}
+
+/// State of one checked target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TargetHealth {
+    healthy: bool,
+    // Consecutive checks disagreeing with `healthy`
+    streak: u32,
+}
+
+/// Damped up/down state of every checked target. Targets start out healthy,
+/// so a freshly added record is served right away.
+#[derive(Debug)]
+pub struct HealthTracker {
+    damping: u32,
+    targets: HashMap<CheckTarget, TargetHealth>,
+}
+
+impl HealthTracker {
+    pub fn new(damping: u32) -> Self {
+        Self {
+            damping: damping.max(1),
+            targets: HashMap::new(),
+        }
+    }
+
+    /// Record a check result, returning the new state if the target flipped
+    pub fn observe(&mut self, target: &CheckTarget, passed: bool) -> Option<bool> {
+        let state = self.targets.entry(target.clone()).or_insert(TargetHealth {
+            healthy: true,
+            streak: 0,
+        });
+        if passed == state.healthy {
+            state.streak = 0;
+            return None;
+        }
+        state.streak += 1;
+        if state.streak < self.damping {
+            return None;
+        }
+        *state = TargetHealth {
+            healthy: passed,
+            streak: 0,
+        };
+        Some(passed)
+    }
+
+    /// Stop tracking targets that are no longer checked, returning those that
+    /// were down so they can be cleared
+    pub fn retain(&mut self, current: &[CheckTarget]) -> Vec<CheckTarget> {
+        let mut cleared = Vec::new();
+        self.targets.retain(|target, state| {
+            let keep = current.contains(target);
+            if !keep && !state.healthy {
+                cleared.push(target.clone());
+            }
+            keep
+        });
+        cleared
+    }
+}
+
+/// Check every target named by a local record, forever, reporting state
+/// changes to the local records actor
+pub async fn run_health_checks(local_records: LocalRecordsHandle, every: Duration, damping: u32) {
+    let mut tracker = HealthTracker::new(damping);
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+
+        let mut targets: Vec<CheckTarget> = local_records
+            .list()
+            .await
+            .iter()
+            .filter_map(|record| record.check_target())
+            .collect();
+        targets.sort();
+        targets.dedup();
+
+        for target in tracker.retain(&targets) {
+            local_records.set_health(target, true).await;
+        }
+
+        let results = join_all(targets.iter().map(check)).await;
+        for (target, passed) in targets.into_iter().zip(results) {
+            match tracker.observe(&target, passed) {
+                Some(true) => {
+                    info!("Health check for {} recovered", target);
+                    local_records.set_health(target, true).await;
+                }
+                Some(false) => {
+                    warn!("Health check for {} failing, failing over", target);
+                    local_records.set_health(target, false).await;
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Whether the target passes its check in time
+async fn check(target: &CheckTarget) -> bool {
+    let passed = match &target.check {
+        HealthCheck::Tcp(port) => timeout(
+            CHECK_TIMEOUT,
+            TcpStream::connect(SocketAddr::new(target.ip, *port)),
+        )
+        .await
+        .is_ok_and(|connected| connected.is_ok()),
+        HealthCheck::Http { port, path } => timeout(
+            CHECK_TIMEOUT,
+            http_status(SocketAddr::new(target.ip, *port), path),
+        )
+        .await
+        .is_ok_and(|status| status.is_ok_and(|status| (200..400).contains(&status))),
+    };
+    if !passed {
+        debug!("Health check for {} failed", target);
+    }
+    passed
+}
+
+/// The status of an HTTP/1.0 GET of `path`, asked of the address itself
+async fn http_status(addr: SocketAddr, path: &str) -> io::Result<u16> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: dns-server\r\nConnection: close\r\n\r\n",
+        path, addr
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // The status line is all that's needed, however much follows
+    let mut head = [0; STATUS_LINE_LEN];
+    let mut len = 0;
+    while len < head.len() && !head[..len].contains(&b'\n') {
+        match stream.read(&mut head[len..]).await? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    parse_status(&head[..len])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an HTTP response"))
+}
+
+/// The status code of an HTTP status line, `HTTP/1.1 200 OK`
+fn parse_status(head: &[u8]) -> Option<u16> {
+    let line = head.split(|&byte| byte == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.split_whitespace();
+    parts
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))?;
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_damping() {
+        let target = &CheckTarget {
+            ip: "192.0.2.1".parse().unwrap(),
+            check: HealthCheck::Tcp(443),
+        };
+        let mut tracker = HealthTracker::new(3);
+
+        // Two failures aren't enough, and a success resets the count
+        assert_eq!(tracker.observe(target, false), None);
+        assert_eq!(tracker.observe(target, false), None);
+        assert_eq!(tracker.observe(target, true), None);
+        assert_eq!(tracker.observe(target, false), None);
+        assert_eq!(tracker.observe(target, false), None);
+        assert_eq!(tracker.observe(target, false), Some(false));
+        assert_eq!(tracker.observe(target, false), None);
+
+        // Recovery is damped the same way
+        assert_eq!(tracker.observe(target, true), None);
+        assert_eq!(tracker.observe(target, true), None);
+        assert_eq!(tracker.observe(target, true), Some(true));
+
+        // A target that goes away while down is reported for clearing
+        for _ in 0..3 {
+            tracker.observe(target, false);
+        }
+        assert_eq!(tracker.retain(&[]), vec![target.clone()]);
+    }
+
+    #[test]
+    fn test_parse_check() {
+        assert_eq!("443".parse(), Ok(HealthCheck::Tcp(443)));
+        let check: HealthCheck = "http:8080/healthz?full=1".parse().unwrap();
+        assert_eq!(
+            check,
+            HealthCheck::Http {
+                port: 8080,
+                path: "/healthz?full=1".to_string()
+            }
+        );
+        assert_eq!(check.to_string(), "http:8080/healthz?full=1");
+        assert_eq!(
+            "http:80".parse::<HealthCheck>().unwrap().to_string(),
+            "http:80/"
+        );
+        for bad in ["https:443", "http:", "http:80healthz", "web"] {
+            assert!(bad.parse::<HealthCheck>().is_err(), "{}", bad);
+        }
+
+        // TCP checks stay numbers in JSON
+        assert_eq!(
+            serde_json::to_string(&HealthCheck::Tcp(443)).unwrap(),
+            "443"
+        );
+        assert_eq!(
+            serde_json::to_string(&check).unwrap(),
+            "\"http:8080/healthz?full=1\""
+        );
+        assert_eq!(
+            serde_json::from_str::<HealthCheck>("443").unwrap(),
+            HealthCheck::Tcp(443)
+        );
+        assert_eq!(
+            serde_json::from_str::<HealthCheck>("\"http:8080/healthz?full=1\"").unwrap(),
+            check
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_check() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = [0; 512];
+                    let len = stream.read(&mut request).await.unwrap();
+                    let status = if request[..len].starts_with(b"GET /healthz ") {
+                        "200 OK"
+                    } else {
+                        "503 Service Unavailable"
+                    };
+                    // Keep the connection open: the status line is enough
+                    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                });
+            }
+        });
+        let target = |path: &str| CheckTarget {
+            ip: addr.ip(),
+            check: HealthCheck::Http {
+                port: addr.port(),
+                path: path.to_string(),
+            },
+        };
+        assert!(check(&target("/healthz")).await);
+        assert!(!check(&target("/")).await);
+
+        assert_eq!(parse_status(b"HTTP/1.0 302 Found\r\n"), Some(302));
+        assert_eq!(parse_status(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+    }
+}
@@ -0,0 +1,519 @@
+//! Racing upstreams against each other (`--hedge`)
+//!
+//! A group of upstreams normally asks them one after the other, so a query
+//! sent to an upstream that is slow to answer waits out its slowness. With
+//! hedging the first few upstreams of the group race: the query goes to the
+//! first, and if no answer has come back after the stagger, to the second
+//! as well, and so on through the racing set. The first answer wins, a
+//! negative one included; an upstream that fails makes way for the next at
+//! once, and the rest of the group is asked in turn as usual.
+//!
+//! Upstreams failing too often are left out of the group's rotation for a
+//! while (see `circuit.rs`), unless all of them are. Every upstream is probed
+//! for the root's SOA every ten seconds, so one that isn't being asked is
+//! still checked, and one left out gets the chance to come back.
+//!
+//! A hedge is given as the stagger in milliseconds and, after a `/`, how
+//! many upstreams race (two unless given). It applies to a group of
+//! upstreams: the default tenant's (`--hedge`), another tenant's, a view's
+//! or a route's (`hedge=`).
+//!
+//! ```text
+//! --resolver 1.1.1.1:53 --resolver 8.8.8.8:53 --hedge 50
+//! --route name=cn,domains=/etc/dns/cn.txt,to=223.5.5.5:53,to=119.29.29.29:53,to=180.76.76.76:53,hedge=30/3
+//! ```
+//!
+//! `stats` counts the requests sent on a stagger as `upstream_hedges`, and
+//! the queries one of them answered first as `upstream_hedge_wins`; the
+//! upstreams left out and put back as `upstream_circuits_opened` and
+//! `upstream_circuits_closed`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use hickory_resolver::lookup::Lookup;
+use hickory_resolver::proto::rr::RecordType;
+use hickory_resolver::proto::{ProtoError, ProtoErrorKind};
+use hickory_resolver::{Name, ResolveError, Resolver};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use crate::circuit::{Breaker, Change};
+use crate::metrics::{self, Metrics};
+use crate::retry::{Failure, RetryPolicy, UpstreamPolicy};
+use crate::upstream::{self, Upstream, UpstreamConnector};
+
+/// Upstreams racing unless the hedge says how many
+const DEFAULT_RACERS: usize = 2;
+
+/// Upstreams of a group that isn't hedged asked at once
+const CONCURRENT_UPSTREAMS: usize = 2;
+
+/// How often the upstreams are probed
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How a group of upstreams is raced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hedge {
+    /// How long an upstream is given before the next is asked too
+    pub stagger: Duration,
+    /// How many of the group's first upstreams race
+    pub racers: usize,
+}
+
+impl fmt::Display for Hedge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.stagger.as_millis(), self.racers)
+    }
+}
+
+/// Parses `<stagger ms>[/<racers>]`
+impl FromStr for Hedge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stagger, racers) = match s.split_once('/') {
+            Some((stagger, racers)) => (stagger, Some(racers)),
+            None => (s, None),
+        };
+        let stagger = stagger
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| format!("invalid hedge stagger '{}' (milliseconds)", stagger))?;
+        let racers = match racers {
+            Some(racers) => racers
+                .parse()
+                .ok()
+                .filter(|&racers| racers >= 2)
+                .ok_or_else(|| format!("invalid hedge racers '{}' (2 or more)", racers))?,
+            None => DEFAULT_RACERS,
+        };
+        Ok(Hedge { stagger, racers })
+    }
+}
+
+/// Resolves through a group of upstreams: in the order given, skipping
+/// those whose circuit is open, and racing the first few
+#[derive(Clone)]
+pub struct HedgedResolver {
+    members: Arc<[Member]>,
+    hedge: Option<Hedge>,
+    metrics: Arc<Metrics>,
+}
+
+/// An upstream of a group
+struct Member {
+    upstream: Upstream,
+    /// Asks this upstream alone
+    resolver: Resolver<UpstreamConnector>,
+    /// Asks it too, for the probes
+    probe: Resolver<UpstreamConnector>,
+    /// Timeouts and retries of its lookups
+    policy: RetryPolicy,
+    breaker: Breaker,
+}
+
+impl HedgedResolver {
+    /// A resolver forwarding to `upstreams` (see [`upstream::resolver`]),
+    /// raced as `hedge` says; without a hedge the first two are asked at
+    /// once. Each upstream is asked under its policy among `policies`. The
+    /// upstreams are probed in the background for as long as the resolver is
+    /// around.
+    pub fn new(
+        upstreams: &[Upstream],
+        hedge: Option<Hedge>,
+        policies: &[UpstreamPolicy],
+        case_randomization: bool,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let members: Arc<[Member]> = upstreams
+            .iter()
+            .map(|upstream| {
+                let policy = RetryPolicy::for_upstream(policies, upstream);
+                let alone = std::slice::from_ref(upstream);
+                Member {
+                    upstream: upstream.clone(),
+                    resolver: upstream::resolver(alone, case_randomization, &policy),
+                    probe: upstream::resolver(alone, case_randomization, &policy),
+                    policy,
+                    breaker: Breaker::new(),
+                }
+            })
+            .collect();
+        if !members.is_empty() {
+            tokio::spawn(probe_upstreams(
+                Arc::downgrade(&members),
+                Arc::clone(&metrics),
+            ));
+        }
+        HedgedResolver {
+            members,
+            hedge,
+            metrics,
+        }
+    }
+
+    /// Look up the records of `rtype` at `name`, taking the first answer
+    /// any upstream gets, or the last one's failure when none gets one
+    pub async fn lookup(&self, name: Name, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        // Upstreams out of rotation are only asked when all of them are
+        let mut order: Vec<usize> = (0..self.members.len())
+            .filter(|&member| self.members[member].breaker.is_closed())
+            .collect();
+        if order.is_empty() {
+            order = (0..self.members.len()).collect();
+        }
+        let mut waiting = order.into_iter();
+        let (racers, stagger) = match self.hedge {
+            Some(hedge) => (hedge.racers, hedge.stagger),
+            None => (CONCURRENT_UPSTREAMS, Duration::ZERO),
+        };
+        let ask = |member, hedged| {
+            ask(
+                Arc::clone(&self.members),
+                Arc::clone(&self.metrics),
+                member,
+                hedged,
+                name.clone(),
+                rtype,
+            )
+        };
+        let mut racing = FuturesUnordered::new();
+        match waiting.next() {
+            Some(first) => racing.push(ask(first, false)),
+            None => return Err("no upstreams to ask".into()),
+        }
+        let mut raced = 1;
+
+        loop {
+            let hedge = async {
+                if raced < racers && waiting.len() > 0 {
+                    tokio::time::sleep(stagger).await
+                } else {
+                    std::future::pending().await
+                }
+            };
+            tokio::select! {
+                Some((member, hedged, result)) = racing.next() => {
+                    let won = answered(&result);
+                    observe(&self.members[member], won, &self.metrics);
+                    if won {
+                        if hedged {
+                            metrics::incr(&self.metrics.upstream_hedge_wins);
+                        }
+                        // The rest run their course in the background, so an
+                        // upstream that never wins still has its failures counted
+                        if !racing.is_empty() {
+                            let (members, metrics) = (Arc::clone(&self.members), Arc::clone(&self.metrics));
+                            tokio::spawn(async move {
+                                while let Some((member, _, result)) = racing.next().await {
+                                    observe(&members[member], answered(&result), &metrics);
+                                }
+                            });
+                        }
+                        return result;
+                    }
+                    // A failed upstream makes way for the next at once
+                    match waiting.next() {
+                        Some(next) => racing.push(ask(next, false)),
+                        None if racing.is_empty() => return result,
+                        None => {}
+                    }
+                }
+                () = hedge => {
+                    if let Some(next) = waiting.next() {
+                        let hedged = self.hedge.is_some();
+                        if hedged {
+                            debug!(
+                                "No answer for {} {} yet; asking {} too",
+                                name, rtype, self.members[next].upstream
+                            );
+                            metrics::incr(&self.metrics.upstream_hedges);
+                        }
+                        racing.push(ask(next, hedged));
+                        raced += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for HedgedResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HedgedResolver")
+            .field(
+                "upstreams",
+                &self
+                    .members
+                    .iter()
+                    .map(|member| &member.upstream)
+                    .collect::<Vec<_>>(),
+            )
+            .field("hedge", &self.hedge)
+            .finish()
+    }
+}
+
+/// One upstream's lookup, by its place in the group, and whether it was
+/// sent on a stagger; attempted again as the upstream's policy allows
+async fn ask(
+    members: Arc<[Member]>,
+    metrics: Arc<Metrics>,
+    member: usize,
+    hedged: bool,
+    name: Name,
+    rtype: RecordType,
+) -> (usize, bool, Result<Lookup, ResolveError>) {
+    let Member {
+        upstream,
+        resolver,
+        policy,
+        ..
+    } = &members[member];
+    let mut attempt = 0;
+    loop {
+        let lookup = resolver.lookup(name.clone(), rtype);
+        let result = match tokio::time::timeout(policy.query_timeout, lookup).await {
+            Ok(result) => result,
+            Err(_) => Err(ProtoError::from(ProtoErrorKind::Timeout).into()),
+        };
+        let failure = result.as_ref().err().and_then(Failure::of);
+        if failure == Some(Failure::Timeout) {
+            metrics::incr(&metrics.upstream_timeouts);
+        }
+        attempt += 1;
+        match failure {
+            Some(failure) if attempt < policy.attempts && policy.retries(failure) => {
+                debug!(
+                    "Upstream {} failed {} {} ({:?}); trying again",
+                    upstream, name, rtype, failure
+                );
+                metrics::incr(&metrics.upstream_retries);
+                tokio::time::sleep(policy.backoff_before(attempt)).await;
+            }
+            _ => return (member, hedged, result),
+        }
+    }
+}
+
+/// Count a lookup's outcome against its upstream's circuit
+fn observe(member: &Member, answered: bool, metrics: &Metrics) {
+    if let Some(change) = member.breaker.record(answered, Instant::now()) {
+        report(&member.upstream, change, metrics);
+    }
+}
+
+/// Ask every upstream of a group for the root's SOA every `PROBE_INTERVAL`,
+/// the ones out of rotation once their backoff is over, until the group's
+/// resolver is gone
+async fn probe_upstreams(members: Weak<[Member]>, metrics: Arc<Metrics>) {
+    let mut ticks = tokio::time::interval(PROBE_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let Some(members) = members.upgrade() else {
+            return;
+        };
+        let due = members
+            .iter()
+            .filter(|member| member.breaker.probe_due(Instant::now()));
+        join_all(due.map(|member| async {
+            let answered = answered(&member.probe.lookup(Name::root(), RecordType::SOA).await);
+            if !answered {
+                debug!("Upstream {} failed its probe", member.upstream);
+            }
+            if let Some(change) = member.breaker.probed(answered, Instant::now()) {
+                report(&member.upstream, change, &metrics);
+            }
+        }))
+        .await;
+    }
+}
+
+/// Log and count a circuit's change
+fn report(upstream: &Upstream, change: Change, metrics: &Metrics) {
+    match change {
+        Change::Opened {
+            failures,
+            outcomes,
+            backoff,
+        } => {
+            warn!(
+                "Upstream {} failed {} of its last {} lookups; out of rotation for {}s",
+                upstream,
+                failures,
+                outcomes,
+                backoff.as_secs()
+            );
+            metrics::incr(&metrics.upstream_circuits_opened);
+        }
+        Change::Extended { backoff } => warn!(
+            "Upstream {} still failing; out of rotation for another {}s",
+            upstream,
+            backoff.as_secs()
+        ),
+        Change::Closed => {
+            info!("Upstream {} answering again; back in rotation", upstream);
+            metrics::incr(&metrics.upstream_circuits_closed);
+        }
+    }
+}
+
+/// Whether a lookup got an answer: records, NXDOMAIN or NODATA
+fn answered(result: &Result<Lookup, ResolveError>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(e) => Failure::of(e).is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::UdpSocket;
+
+    /// An upstream answering every A query with `address` after `delay`
+    async fn upstream(delay: Duration, address: [u8; 4]) -> SocketAddr {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, client) = sock.recv_from(&mut buf).await.unwrap();
+                let mut response = buf[..len].to_vec();
+                let sock = Arc::clone(&sock);
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    response[2] = 0x81;
+                    response[3] = 0x80;
+                    response[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+                    let question_end =
+                        12 + response[12..].iter().position(|&b| b == 0).unwrap() + 5;
+                    response.truncate(question_end);
+                    response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    response.extend_from_slice(&address);
+                    let _ = sock.send_to(&response, client).await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// An upstream answering everything SERVFAIL, and how many queries it got
+    async fn failing_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, client) = sock.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                buf[2] = 0x81;
+                buf[3] = 0x82;
+                let _ = sock.send_to(&buf[..len], client).await;
+            }
+        });
+        (addr, queries)
+    }
+
+    #[test]
+    fn test_parse_hedge() {
+        let hedge: Hedge = "50".parse().unwrap();
+        assert_eq!(hedge.stagger, Duration::from_millis(50));
+        assert_eq!(hedge.racers, 2);
+        let hedge: Hedge = "30/3".parse().unwrap();
+        assert_eq!(hedge.racers, 3);
+        assert_eq!(hedge.to_string().parse::<Hedge>().unwrap(), hedge);
+
+        assert!("".parse::<Hedge>().is_err());
+        assert!("50ms".parse::<Hedge>().is_err());
+        assert!("50/1".parse::<Hedge>().is_err());
+        assert!("50/many".parse::<Hedge>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slow_upstream_is_raced() {
+        let slow = upstream(Duration::from_secs(2), [192, 0, 2, 1]).await;
+        let fast = upstream(Duration::ZERO, [192, 0, 2, 2]).await;
+        let metrics = Arc::new(Metrics::new(1));
+        let hedge = Hedge {
+            stagger: Duration::from_millis(50),
+            racers: 2,
+        };
+        let resolver = HedgedResolver::new(
+            &[slow.into(), fast.into()],
+            Some(hedge),
+            &[],
+            true,
+            Arc::clone(&metrics),
+        );
+
+        let started = Instant::now();
+        let lookup = resolver
+            .lookup(Name::from_ascii("example.com.").unwrap(), RecordType::A)
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let answer = lookup.record_iter().next().unwrap().data().to_string();
+        assert_eq!(answer, "192.0.2.2");
+        assert_eq!(metrics.upstream_hedges.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.upstream_hedge_wins.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failing_upstream_is_left_out() {
+        let (failing, queries) = failing_upstream().await;
+        let working = upstream(Duration::ZERO, [192, 0, 2, 2]).await;
+        let metrics = Arc::new(Metrics::new(1));
+        let resolver = HedgedResolver::new(
+            &[failing.into(), working.into()],
+            None,
+            &[],
+            true,
+            Arc::clone(&metrics),
+        );
+
+        // The second upstream answers what the first fails, until the first
+        // is out of rotation
+        for i in 0..5 {
+            let name = Name::from_ascii(format!("host{}.example.com.", i)).unwrap();
+            assert!(resolver.lookup(name, RecordType::A).await.is_ok());
+        }
+        assert_eq!(metrics.upstream_circuits_opened.load(Ordering::Relaxed), 1);
+        let asked = queries.load(Ordering::Relaxed);
+        let name = Name::from_ascii("example.net.").unwrap();
+        assert!(resolver.lookup(name, RecordType::A).await.is_ok());
+        assert_eq!(queries.load(Ordering::Relaxed), asked);
+    }
+
+    #[tokio::test]
+    async fn test_hung_upstream_times_out() {
+        // Takes queries and never answers them
+        let hung = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let metrics = Arc::new(Metrics::new(1));
+        let policy: UpstreamPolicy = "timeout=100,attempts=3,backoff=10".parse().unwrap();
+        let resolver = HedgedResolver::new(
+            &[hung.local_addr().unwrap().into()],
+            None,
+            &[policy],
+            true,
+            Arc::clone(&metrics),
+        );
+
+        let started = Instant::now();
+        let name = Name::from_ascii("example.com.").unwrap();
+        assert!(resolver.lookup(name, RecordType::A).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(metrics.upstream_timeouts.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.upstream_retries.load(Ordering::Relaxed), 2);
+    }
+}
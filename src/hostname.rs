@@ -0,0 +1,108 @@
+//! Query name validation
+//!
+//! By default any name the parser can read is resolved. With the strict
+//! policy, names that break the hostname rules are answered with FORMERR
+//! instead of being forwarded.
+
+use clap::ValueEnum;
+
+/// Maximum length of a name in text form, without the trailing dot
+const MAX_NAME_LEN: usize = 253;
+/// Maximum length of a single label
+const MAX_LABEL_LEN: usize = 63;
+
+/// How query names are checked before resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum HostnamePolicy {
+    /// Accept any name
+    #[default]
+    Liberal,
+    /// Require letter-digit-hyphen (LDH) labels; `_service` labels are allowed
+    Strict,
+}
+
+impl HostnamePolicy {
+    /// Whether a query for `name` should be resolved
+    pub fn accepts(&self, name: &str) -> bool {
+        match self {
+            HostnamePolicy::Liberal => true,
+            HostnamePolicy::Strict => is_valid_hostname(name),
+        }
+    }
+}
+
+/// Check a name against the hostname rules (RFC 952/1123): labels of 1-63
+/// letters, digits and hyphens, not starting or ending with a hyphen.
+/// Labels starting with an underscore (`_dmarc`, `_sip._tcp`) are accepted as
+/// well since they are how service records are named (RFC 8552).
+/// Under `in-addr.arpa`, `/` is allowed too for RFC 2317 names like
+/// `65.64/26.1.168.192.in-addr.arpa`. The root name (empty) is valid.
+pub fn is_valid_hostname(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        return true;
+    }
+    if name.len() > MAX_NAME_LEN {
+        return false;
+    }
+    let reverse = name.to_ascii_lowercase().ends_with(".in-addr.arpa");
+    name.split('.').all(|label| is_valid_label(label, reverse))
+}
+
+fn is_valid_label(label: &str, allow_slash: bool) -> bool {
+    if label.is_empty() || label.len() > MAX_LABEL_LEN {
+        return false;
+    }
+    let ldh = label.strip_prefix('_').unwrap_or(label);
+    !ldh.is_empty()
+        && !ldh.starts_with('-')
+        && !ldh.ends_with('-')
+        && ldh
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || (allow_slash && b == b'/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_hostnames() {
+        for name in [
+            "example.com",
+            "EXAMPLE.com.",
+            "a-b.c0",
+            "_dmarc.example.com",
+            "_sip._tcp.example.com",
+            "65.64/26.1.168.192.in-addr.arpa",
+            "",
+            &format!("{}.com", "a".repeat(63)),
+        ] {
+            assert!(is_valid_hostname(name), "{:?} should be valid", name);
+        }
+    }
+
+    #[test]
+    fn test_invalid_hostnames() {
+        for name in [
+            "exa mple.com",
+            "-example.com",
+            "example-.com",
+            "example..com",
+            "ex\u{fffd}ample.com",
+            "_.example.com",
+            "under_score.com",
+            "64/26.example.com",
+            &format!("{}.com", "a".repeat(64)),
+            &["abcdefghi"; 26].join("."),
+        ] {
+            assert!(!is_valid_hostname(name), "{:?} should be invalid", name);
+        }
+    }
+
+    #[test]
+    fn test_liberal_accepts_anything() {
+        assert!(HostnamePolicy::Liberal.accepts("exa mple..com"));
+        assert!(!HostnamePolicy::Strict.accepts("exa mple..com"));
+    }
+}
@@ -0,0 +1,108 @@
+//! Hosts files as local records (`--hosts-file`)
+//!
+//! Each line of a hosts file maps an address to one or more names:
+//! `192.168.1.10 nas.lan nas`. Every name gets an A or AAAA record for the
+//! address, with the default local TTL, answered before any upstream
+//! forwarding like any other local record. `#` starts a comment. Lines that
+//! can't be served, such as scoped IPv6 addresses (`fe80::1%eth0`), are
+//! skipped with a warning rather than failing the whole file, so
+//! `/etc/hosts` itself loads as it is.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use tracing::warn;
+
+use crate::errors::LocalRecordError;
+use crate::local_records::{validate_name, LocalRecord, RecordData, DEFAULT_LOCAL_TTL};
+
+/// Read a hosts file into local records, warning about the lines skipped
+pub fn load_hosts_file(path: &Path) -> anyhow::Result<Vec<LocalRecord>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read hosts file {}", path.display()))?;
+    let (records, skipped) = parse_hosts(&contents);
+    for (line, e) in skipped {
+        warn!(
+            "Skipping line {} of hosts file {}: {}",
+            line,
+            path.display(),
+            e
+        );
+    }
+    Ok(records)
+}
+
+/// The records a hosts file describes, and the lines (numbered from 1) that
+/// don't describe any
+pub fn parse_hosts(contents: &str) -> (Vec<LocalRecord>, Vec<(usize, LocalRecordError)>) {
+    let mut records = Vec::new();
+    let mut skipped = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(address) = fields.next() else {
+            continue;
+        };
+        match parse_line(address, fields) {
+            Ok(line_records) => records.extend(line_records),
+            Err(e) => skipped.push((index + 1, e)),
+        }
+    }
+    (records, skipped)
+}
+
+fn parse_line<'a>(
+    address: &str,
+    names: impl Iterator<Item = &'a str>,
+) -> Result<Vec<LocalRecord>, LocalRecordError> {
+    let data = match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => RecordData::A(ip),
+        Ok(IpAddr::V6(ip)) => RecordData::Aaaa(ip),
+        Err(_) => return Err(LocalRecordError::InvalidValue(address.to_string())),
+    };
+    let records = names
+        .map(|name| {
+            validate_name(name)?;
+            Ok(LocalRecord::new(name, DEFAULT_LOCAL_TTL, data.clone()))
+        })
+        .collect::<Result<Vec<_>, LocalRecordError>>()?;
+    if records.is_empty() {
+        return Err(LocalRecordError::MissingField("name"));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hosts() {
+        let hosts = "# home lab\n\
+                     127.0.0.1\tlocalhost\n\
+                     192.168.1.10 NAS.lan nas # the file server\n\
+                     fe80::1%eth0 router.lan\n\
+                     2001:db8::10 nas.lan\n\
+                     192.168.1.11\n";
+        let (records, skipped) = parse_hosts(hosts);
+
+        let records: Vec<String> = records.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            records,
+            vec![
+                "localhost A 127.0.0.1 300",
+                "nas.lan A 192.168.1.10 300",
+                "nas A 192.168.1.10 300",
+                "nas.lan AAAA 2001:db8::10 300",
+            ]
+        );
+        assert!(matches!(
+            &skipped[..],
+            [
+                (4, LocalRecordError::InvalidValue(_)),
+                (6, LocalRecordError::MissingField("name")),
+            ]
+        ));
+    }
+}
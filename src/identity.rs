@@ -0,0 +1,367 @@
+//! The server's own names (`--server-name`, `--ddr`)
+//!
+//! The server answers for its own hostname: A and AAAA queries get the
+//! addresses it is reached at (`--advertise`, or the listen address when it
+//! isn't a wildcard). With `--ddr` it also answers SVCB queries for
+//! `_dns.resolver.arpa`, through which clients discover the encrypted
+//! endpoints serving the same resolver (DDR, RFC 9462) and upgrade to them.
+//! Each endpoint is described by its ALPN protocols, port and, for DoH, URI
+//! template (RFC 9461), with the addresses as hints.
+//!
+//! ```text
+//! --server-name dns.example.net --advertise 192.0.2.53 --advertise 2001:db8::53
+//! --ddr alpn=dot --ddr alpn=h2,alpn=h3,dohpath=/dns-query{?dns}
+//! ```
+//!
+//! The names are answered after local records, so a local record for the
+//! same name wins.
+//!
+//! In the CHAOS class, TXT queries for `id.server` and `hostname.bind` are
+//! answered with the server's name (RFC 4892), so that an operator can tell
+//! which of several servers answered. Nothing else is served in that class.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::local_records::{normalize_name, DEFAULT_LOCAL_TTL};
+use crate::protocol::DnsResourceRecord;
+use crate::registry::{RecordClass, RecordType};
+use crate::response_builder::encode_name;
+
+/// The name clients query to discover designated resolvers (RFC 9462)
+pub const DDR_NAME: &str = "_dns.resolver.arpa";
+
+/// CHAOS names a server's identity is asked for under (RFC 4892 §2)
+const CHAOS_ID_NAMES: [&str; 2] = ["id.server", "hostname.bind"];
+
+/// SvcParamKeys (RFC 9460 §14.3.2, RFC 9461 §5)
+const KEY_ALPN: u16 = 1;
+const KEY_PORT: u16 = 3;
+const KEY_IPV4HINT: u16 = 4;
+const KEY_IPV6HINT: u16 = 6;
+const KEY_DOHPATH: u16 = 7;
+
+/// An encrypted endpoint advertised through DDR
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DdrEndpoint {
+    /// SvcPriority; lower is preferred. Defaults to the order given
+    pub priority: Option<u16>,
+    /// ALPN protocol IDs: `dot`, `doq`, `h2` or `h3`
+    pub alpn: Vec<String>,
+    /// When not the protocol's default
+    pub port: Option<u16>,
+    /// URI template of a DoH endpoint, e.g. `/dns-query{?dns}`
+    pub dohpath: Option<String>,
+}
+
+impl fmt::Display for DdrEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut settings = Vec::new();
+        settings.extend(self.alpn.iter().map(|alpn| format!("alpn={}", alpn)));
+        if let Some(port) = self.port {
+            settings.push(format!("port={}", port));
+        }
+        if let Some(dohpath) = &self.dohpath {
+            settings.push(format!("dohpath={}", dohpath));
+        }
+        if let Some(priority) = self.priority {
+            settings.push(format!("priority={}", priority));
+        }
+        write!(f, "{}", settings.join(","))
+    }
+}
+
+/// Parses comma-separated `key=value` settings: `alpn` (repeatable) is
+/// required; `port`, `dohpath` and `priority` are optional. HTTP endpoints
+/// need a `dohpath`, which only HTTP endpoints may have.
+impl FromStr for DdrEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut endpoint = DdrEndpoint {
+            priority: None,
+            alpn: Vec::new(),
+            port: None,
+            dohpath: None,
+        };
+
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got '{}'", setting))?;
+            match key {
+                "alpn" if matches!(value, "dot" | "doq" | "h2" | "h3") => {
+                    endpoint.alpn.push(value.to_string())
+                }
+                "alpn" => return Err(format!("unknown ALPN '{}' (dot, doq, h2 or h3)", value)),
+                "port" => {
+                    endpoint.port = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid port '{}'", value))?,
+                    )
+                }
+                "dohpath" if value.starts_with('/') && value.contains("{?dns}") => {
+                    endpoint.dohpath = Some(value.to_string())
+                }
+                "dohpath" => {
+                    return Err(format!(
+                        "dohpath '{}' must be a path with a {{?dns}} variable",
+                        value
+                    ))
+                }
+                "priority" => match value.parse() {
+                    Ok(0) | Err(_) => return Err(format!("invalid priority '{}'", value)),
+                    Ok(priority) => endpoint.priority = Some(priority),
+                },
+                _ => return Err(format!("unknown DDR setting '{}'", setting)),
+            }
+        }
+
+        if endpoint.alpn.is_empty() {
+            return Err("DDR endpoint needs an alpn=<protocol>".into());
+        }
+        let http = endpoint.alpn.iter().any(|alpn| alpn.starts_with('h'));
+        match (http, &endpoint.dohpath) {
+            (true, None) => Err("a DoH endpoint (h2, h3) needs a dohpath=<template>".into()),
+            (false, Some(_)) => Err("only DoH endpoints (h2, h3) take a dohpath".into()),
+            _ => Ok(endpoint),
+        }
+    }
+}
+
+/// The names the server answers for itself
+#[derive(Debug, Default)]
+pub struct Identity {
+    /// Normalized; empty when no --server-name was given
+    name: String,
+    addresses: Vec<IpAddr>,
+    ddr: Vec<DdrEndpoint>,
+}
+
+impl Identity {
+    pub fn new(name: &str, addresses: Vec<IpAddr>, ddr: Vec<DdrEndpoint>) -> Self {
+        Identity {
+            name: normalize_name(name),
+            addresses,
+            ddr,
+        }
+    }
+
+    /// The records answering a question about one of the server's own
+    /// names; None when the name isn't one of them. An empty answer means
+    /// the name exists but has nothing of that type.
+    pub fn answer(&self, name: &str, qtype: RecordType) -> Option<Vec<DnsResourceRecord>> {
+        if self.name.is_empty() {
+            return None;
+        }
+        let name = normalize_name(name);
+        if name == self.name {
+            let records = self
+                .addresses
+                .iter()
+                .filter_map(|ip| match (ip, qtype) {
+                    (IpAddr::V4(ip), RecordType::A) => Some(ip.octets().to_vec()),
+                    (IpAddr::V6(ip), RecordType::AAAA) => Some(ip.octets().to_vec()),
+                    _ => None,
+                })
+                .map(|rdata| self.record(&name, qtype, rdata))
+                .collect();
+            return Some(records);
+        }
+        if name == DDR_NAME && !self.ddr.is_empty() {
+            if qtype != RecordType::SVCB {
+                return Some(Vec::new());
+            }
+            let records = self
+                .ddr
+                .iter()
+                .zip(1..)
+                .map(|(endpoint, order)| {
+                    let priority = endpoint.priority.unwrap_or(order);
+                    self.record(&name, qtype, self.svcb_rdata(endpoint, priority))
+                })
+                .collect();
+            return Some(records);
+        }
+        None
+    }
+
+    /// The records answering a CHAOS-class question; None when the name
+    /// isn't one the server identifies itself under, or it has no name
+    pub fn chaos(&self, name: &str, qtype: RecordType) -> Option<Vec<DnsResourceRecord>> {
+        let name = normalize_name(name);
+        if self.name.is_empty() || !CHAOS_ID_NAMES.contains(&name.as_str()) {
+            return None;
+        }
+        if qtype != RecordType::TXT {
+            return Some(Vec::new());
+        }
+        let mut rdata = vec![self.name.len() as u8];
+        rdata.extend(self.name.as_bytes());
+        // Not to be cached: the next answer may come from another server
+        Some(vec![DnsResourceRecord::new(
+            name,
+            qtype,
+            RecordClass::CH,
+            0,
+            rdata,
+        )])
+    }
+
+    fn record(&self, name: &str, rtype: RecordType, rdata: Vec<u8>) -> DnsResourceRecord {
+        DnsResourceRecord::new(
+            name.to_string(),
+            rtype,
+            RecordClass::IN,
+            DEFAULT_LOCAL_TTL,
+            rdata,
+        )
+    }
+
+    /// An SVCB record in ServiceMode pointing at the server's name, its
+    /// parameters in increasing key order as RFC 9460 requires
+    fn svcb_rdata(&self, endpoint: &DdrEndpoint, priority: u16) -> Vec<u8> {
+        let mut rdata = priority.to_be_bytes().to_vec();
+        rdata.extend(encode_name(&self.name));
+
+        let alpn: Vec<u8> = endpoint
+            .alpn
+            .iter()
+            .flat_map(|id| [&[id.len() as u8][..], id.as_bytes()].concat())
+            .collect();
+        push_param(&mut rdata, KEY_ALPN, &alpn);
+        if let Some(port) = endpoint.port {
+            push_param(&mut rdata, KEY_PORT, &port.to_be_bytes());
+        }
+        let (v4, v6): (Vec<IpAddr>, Vec<IpAddr>) =
+            self.addresses.iter().partition(|ip| ip.is_ipv4());
+        for (key, hints) in [(KEY_IPV4HINT, v4), (KEY_IPV6HINT, v6)] {
+            if !hints.is_empty() {
+                let value: Vec<u8> = hints
+                    .iter()
+                    .flat_map(|ip| match ip {
+                        IpAddr::V4(ip) => ip.octets().to_vec(),
+                        IpAddr::V6(ip) => ip.octets().to_vec(),
+                    })
+                    .collect();
+                push_param(&mut rdata, key, &value);
+            }
+        }
+        if let Some(dohpath) = &endpoint.dohpath {
+            push_param(&mut rdata, KEY_DOHPATH, dohpath.as_bytes());
+        }
+        rdata
+    }
+}
+
+fn push_param(rdata: &mut Vec<u8>, key: u16, value: &[u8]) {
+    rdata.extend(key.to_be_bytes());
+    rdata.extend((value.len() as u16).to_be_bytes());
+    rdata.extend(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> Identity {
+        Identity::new(
+            "DNS.example.net.",
+            vec![
+                "192.0.2.53".parse().unwrap(),
+                "2001:db8::53".parse().unwrap(),
+            ],
+            vec![
+                "alpn=dot".parse().unwrap(),
+                "alpn=h2,alpn=h3,dohpath=/dns-query{?dns},port=8443"
+                    .parse()
+                    .unwrap(),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_parse_ddr_endpoint() {
+        let endpoint: DdrEndpoint = "alpn=h2,dohpath=/q{?dns},port=443,priority=5"
+            .parse()
+            .unwrap();
+        assert_eq!(endpoint.alpn, vec!["h2"]);
+        assert_eq!(endpoint.priority, Some(5));
+        assert_eq!(
+            endpoint.to_string().parse::<DdrEndpoint>().unwrap(),
+            endpoint
+        );
+
+        assert!("port=853".parse::<DdrEndpoint>().is_err());
+        assert!("alpn=http/1.1".parse::<DdrEndpoint>().is_err());
+        assert!("alpn=h2".parse::<DdrEndpoint>().is_err());
+        assert!("alpn=h2,dohpath=/dns-query".parse::<DdrEndpoint>().is_err());
+        assert!("alpn=dot,dohpath=/q{?dns}".parse::<DdrEndpoint>().is_err());
+        assert!("alpn=dot,priority=0".parse::<DdrEndpoint>().is_err());
+    }
+
+    #[test]
+    fn test_own_addresses() {
+        let identity = identity();
+        let a = identity.answer("dns.EXAMPLE.net", RecordType::A).unwrap();
+        assert_eq!(a.len(), 1);
+        assert_eq!(a[0].rdata, [192, 0, 2, 53]);
+        let aaaa = identity
+            .answer("dns.example.net.", RecordType::AAAA)
+            .unwrap();
+        assert_eq!(aaaa.len(), 1);
+        assert!(identity
+            .answer("dns.example.net", RecordType::MX)
+            .unwrap()
+            .is_empty());
+        assert!(identity.answer("www.example.net", RecordType::A).is_none());
+        assert!(Identity::default().answer("", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn test_ddr_records() {
+        let identity = identity();
+        let svcb = identity.answer(DDR_NAME, RecordType::SVCB).unwrap();
+        assert_eq!(svcb.len(), 2);
+
+        let target = encode_name("dns.example.net");
+        let dot = &svcb[0].rdata;
+        assert_eq!(dot[..2], [0, 1]);
+        assert_eq!(dot[2..2 + target.len()], target[..]);
+        let params = &dot[2 + target.len()..];
+        assert_eq!(params[..8], [0, 1, 0, 4, 3, b'd', b'o', b't']);
+        assert_eq!(params[8..16], [0, 4, 0, 4, 192, 0, 2, 53]);
+        assert_eq!(params[16..20], [0, 6, 0, 16]);
+        assert_eq!(params.len(), 36);
+
+        let doh = &svcb[1].rdata;
+        assert_eq!(doh[..2], [0, 2]);
+        let params = &doh[2 + target.len()..];
+        assert_eq!(params[..10], [0, 1, 0, 6, 2, b'h', b'2', 2, b'h', b'3']);
+        assert_eq!(params[10..16], [0, 3, 0, 2, 0x20, 0xfb]);
+        assert!(doh.ends_with(b"\x00\x07\x00\x10/dns-query{?dns}"));
+
+        assert!(identity.answer(DDR_NAME, RecordType::A).unwrap().is_empty());
+        let no_ddr = Identity::new("dns.example.net", Vec::new(), Vec::new());
+        assert!(no_ddr.answer(DDR_NAME, RecordType::SVCB).is_none());
+    }
+
+    #[test]
+    fn test_chaos_identity() {
+        let identity = identity();
+        let txt = identity.chaos("ID.SERVER.", RecordType::TXT).unwrap();
+        assert_eq!(txt.len(), 1);
+        assert_eq!(txt[0].rclass, RecordClass::CH);
+        assert_eq!(txt[0].rdata, b"\x0fdns.example.net");
+        assert!(identity
+            .chaos("hostname.bind", RecordType::A)
+            .unwrap()
+            .is_empty());
+        assert!(identity.chaos("version.bind", RecordType::TXT).is_none());
+        assert!(Identity::default()
+            .chaos("id.server", RecordType::TXT)
+            .is_none());
+    }
+}
@@ -0,0 +1,165 @@
+//! A DNS server: forwarding resolver, cache, authoritative local records and
+//! zones, split into tenants and views. The `dns-server` binary is [`run`]
+//! over the command line; [`DnsServer`] runs the same server configured in
+//! code, and the packet types, parsers and [`DnsCodec`] are usable on their
+//! own.
+
+mod acl;
+mod block_page;
+mod blocklist;
+mod cache_snapshot;
+mod catalog;
+mod circuit;
+pub mod cli;
+pub mod codec;
+mod config;
+mod conformance;
+mod control;
+mod control_tls;
+mod ctl;
+mod dnssec;
+mod edns;
+mod errors;
+mod geoip;
+mod groups;
+mod health;
+mod hedge;
+mod hostname;
+mod hosts;
+mod identity;
+mod local_records;
+mod mdns;
+mod metrics;
+mod names;
+pub mod parsers;
+#[cfg(target_os = "linux")]
+mod pktinfo;
+mod pipeline;
+#[cfg(feature = "wasm")]
+mod plugin;
+mod policy;
+mod processor;
+mod profiling;
+pub mod protocol;
+mod proxy;
+#[cfg(feature = "sqlite")]
+mod query_db;
+mod query_log;
+mod quota;
+mod recursor;
+pub mod registry;
+mod reload;
+mod response_builder;
+mod retry;
+mod reverse;
+mod routing;
+mod rrset_order;
+mod script;
+pub mod server;
+mod service;
+mod shared_cache;
+mod stats;
+mod supervisor;
+mod tcp;
+mod tenant;
+mod ttl;
+mod tunnel;
+mod udp;
+mod udp_client;
+mod update;
+mod upstream;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod view;
+mod zone_file;
+
+mod actors;
+mod handlers;
+
+pub use crate::codec::{decode_packet, encode_packet, DnsCodec};
+pub use crate::handlers::cache_handler::{CacheConfig, Eviction, Prefetch};
+pub use crate::local_records::LocalRecord;
+pub use crate::pipeline::{Answer, Middleware, Next, Request};
+pub use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion, DnsResourceRecord};
+pub use crate::query_log::{Disposition, QueryEvent, QueryHook};
+pub use crate::server::{DnsServer, DnsServerBuilder};
+pub use crate::upstream::{Transport, Upstream};
+
+/// Run the `dns-server` command `args` describe: serve, or one of the
+/// subcommands. Starts the async runtime itself, so it is called from a
+/// plain `main`.
+pub fn run(args: cli::Args) -> anyhow::Result<()> {
+    // Service definitions are generated without starting the server
+    if let Some(cli::Command::GenerateService { kind, output }) = &args.command {
+        let exe = std::env::current_exe()?;
+        let definition = service::generate_service(*kind, &exe, &args.server_flags());
+        match output {
+            Some(path) => std::fs::write(path, definition)?,
+            None => print!("{}", definition),
+        }
+        return Ok(());
+    }
+
+    // Keys are generated and printed without starting the server
+    if let Some(cli::Command::Dnssec { action }) = &args.command {
+        return dnssec::run(action);
+    }
+
+    // Forking has to happen before the async runtime starts its threads
+    if args.daemon {
+        service::daemonize(args.pid_file.as_deref(), args.log_file.as_deref())?;
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = args.worker_threads {
+        runtime.worker_threads(threads.into());
+    }
+    if let Some(threads) = args.max_blocking_threads {
+        runtime.max_blocking_threads(threads.into());
+    }
+    runtime.build()?.block_on(run_command(args))
+}
+
+async fn run_command(args: cli::Args) -> anyhow::Result<()> {
+    // Vector results go to stdout, where the server's own logging would bury them
+    if let Some(cli::Command::Conformance { dirs }) = &args.command {
+        return conformance::run(dirs).await;
+    }
+
+    // Initialize tracing subscriber for logging
+    tracing_subscriber::fmt()
+        .with_max_level(args.log_level)
+        .with_thread_ids(true)
+        .with_thread_names(true)
+        .with_file(true)
+        .with_line_number(true)
+        .with_ansi(!args.daemon) // no color codes in log files
+        .init();
+
+    match args.command {
+        Some(cli::Command::Ctl { connect, command }) => return ctl::run(connect, command).await,
+        Some(cli::Command::Records { action }) => {
+            return match action {
+                cli::RecordsAction::Export {
+                    connect,
+                    format,
+                    output,
+                } => ctl::export_records(connect, format, output.as_deref()).await,
+                cli::RecordsAction::Import {
+                    file,
+                    connect,
+                    format,
+                    dry_run,
+                    replace,
+                } => ctl::import_records_file(connect, &file, format, dry_run, replace).await,
+            };
+        }
+        Some(cli::Command::GenerateService { .. })
+        | Some(cli::Command::Conformance { .. })
+        | Some(cli::Command::Dnssec { .. })
+        | None => {}
+    }
+
+    DnsServer::from_args(args).run().await
+}
@@ -0,0 +1,1010 @@
+//! Locally defined resource records
+//!
+//! Local records are answered by the server itself, before any upstream
+//! forwarding takes place. They are managed at runtime through the control
+//! socket (see `control.rs`).
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::LocalRecordError;
+use crate::geoip::GeoTag;
+use crate::health::{CheckTarget, HealthCheck};
+use crate::parsers::parse_domain_name;
+use crate::protocol::DnsResourceRecord;
+use crate::query_log::format_time;
+use crate::registry::{RecordClass, RecordType};
+use crate::response_builder::encode_name;
+
+/// Default TTL for local records when none is given
+pub const DEFAULT_LOCAL_TTL: u32 = 300;
+
+/// How many versions of the record table are kept for `diff` and `rollback`
+pub const HISTORY_LEN: usize = 32;
+
+/// The data carried by a local record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Mx {
+        preference: u16,
+        exchange: String,
+    },
+    Txt(String),
+    Ptr(String),
+    /// Data of any other type, kept as it came and written in the RFC 3597
+    /// generic form (`\# <length> <hex>`)
+    Generic {
+        rtype: RecordType,
+        rdata: Vec<u8>,
+    },
+}
+
+impl RecordData {
+    /// The DNS record type of this data
+    pub fn rtype(&self) -> RecordType {
+        match self {
+            RecordData::A(_) => RecordType::A,
+            RecordData::Aaaa(_) => RecordType::AAAA,
+            RecordData::Cname(_) => RecordType::CNAME,
+            RecordData::Mx { .. } => RecordType::MX,
+            RecordData::Txt(_) => RecordType::TXT,
+            RecordData::Ptr(_) => RecordType::PTR,
+            RecordData::Generic { rtype, .. } => *rtype,
+        }
+    }
+
+    /// Parse the value of a record of type `rtype` from the start of `rest`,
+    /// returning the data and whatever input follows it
+    pub fn parse(rtype: RecordType, rest: &str) -> Result<(Self, &str), LocalRecordError> {
+        if let Some(("\\#", rest)) = split_token(rest) {
+            let (rdata, rest) = parse_generic(rest)?;
+            return Ok((Self::from_rdata(rtype, &rdata)?, rest));
+        }
+        let parsed = match rtype {
+            RecordType::A => {
+                let (value, rest) =
+                    split_token(rest).ok_or(LocalRecordError::MissingField("value"))?;
+                let ip = value
+                    .parse()
+                    .map_err(|_| LocalRecordError::InvalidValue(value.to_string()))?;
+                (RecordData::A(ip), rest)
+            }
+            RecordType::AAAA => {
+                let (value, rest) =
+                    split_token(rest).ok_or(LocalRecordError::MissingField("value"))?;
+                let ip = value
+                    .parse()
+                    .map_err(|_| LocalRecordError::InvalidValue(value.to_string()))?;
+                (RecordData::Aaaa(ip), rest)
+            }
+            RecordType::CNAME => {
+                let (value, rest) =
+                    split_token(rest).ok_or(LocalRecordError::MissingField("value"))?;
+                validate_name(value)?;
+                (RecordData::Cname(normalize_name(value)), rest)
+            }
+            RecordType::PTR => {
+                let (value, rest) =
+                    split_token(rest).ok_or(LocalRecordError::MissingField("value"))?;
+                validate_name(value)?;
+                (RecordData::Ptr(normalize_name(value)), rest)
+            }
+            RecordType::MX => {
+                let (preference, rest) =
+                    split_token(rest).ok_or(LocalRecordError::MissingField("preference"))?;
+                let preference = preference
+                    .parse()
+                    .map_err(|_| LocalRecordError::InvalidValue(preference.to_string()))?;
+                let (exchange, rest) =
+                    split_token(rest).ok_or(LocalRecordError::MissingField("exchange"))?;
+                validate_name(exchange)?;
+                (
+                    RecordData::Mx {
+                        preference,
+                        exchange: normalize_name(exchange),
+                    },
+                    rest,
+                )
+            }
+            RecordType::TXT => {
+                let rest = rest.trim_start();
+                if let Some(quoted) = rest.strip_prefix('"') {
+                    let end = quoted
+                        .find('"')
+                        .ok_or_else(|| LocalRecordError::InvalidValue(rest.to_string()))?;
+                    (
+                        RecordData::Txt(quoted[..end].to_string()),
+                        &quoted[end + 1..],
+                    )
+                } else {
+                    let (value, rest) =
+                        split_token(rest).ok_or(LocalRecordError::MissingField("value"))?;
+                    (RecordData::Txt(value.to_string()), rest)
+                }
+            }
+            // Other types only come in the generic form
+            other => return Err(LocalRecordError::UnsupportedType(other.to_string())),
+        };
+        Ok(parsed)
+    }
+
+    /// The value as a bare string (TXT data is not quoted)
+    pub fn value(&self) -> String {
+        match self {
+            RecordData::Txt(text) => text.clone(),
+            other => other.to_string(),
+        }
+    }
+
+    /// Encode the data into wire-format RDATA
+    pub fn to_rdata(&self) -> Vec<u8> {
+        match self {
+            RecordData::A(ip) => ip.octets().to_vec(),
+            RecordData::Aaaa(ip) => ip.octets().to_vec(),
+            RecordData::Cname(target) | RecordData::Ptr(target) => encode_name(target),
+            RecordData::Mx {
+                preference,
+                exchange,
+            } => {
+                let mut data = preference.to_be_bytes().to_vec();
+                data.extend(encode_name(exchange));
+                data
+            }
+            RecordData::Txt(text) => {
+                // TXT data is a sequence of length-prefixed character strings
+                let mut data = Vec::with_capacity(text.len() + 1);
+                for chunk in text.as_bytes().chunks(255) {
+                    data.push(chunk.len() as u8);
+                    data.extend_from_slice(chunk);
+                }
+                if text.is_empty() {
+                    data.push(0);
+                }
+                data
+            }
+            RecordData::Generic { rdata, .. } => rdata.clone(),
+        }
+    }
+
+    /// Decode wire-format RDATA of type `rtype`. Names in the RDATA must be
+    /// uncompressed, as `parsers::parse_dns_packet` leaves them. Data of types
+    /// without a variant of their own is kept as it is; only meta types (OPT,
+    /// AXFR, ANY and the like) can't be data.
+    pub fn from_rdata(rtype: RecordType, rdata: &[u8]) -> Result<Self, LocalRecordError> {
+        let invalid = || LocalRecordError::InvalidValue(format!("{} RDATA", rtype));
+        let name = |wire: &[u8]| match parse_domain_name(wire, wire) {
+            Ok((_, name)) => Ok(normalize_name(&name)),
+            Err(_) => Err(invalid()),
+        };
+
+        let data = match rtype {
+            RecordType::A => {
+                RecordData::A(<[u8; 4]>::try_from(rdata).map_err(|_| invalid())?.into())
+            }
+            RecordType::AAAA => {
+                RecordData::Aaaa(<[u8; 16]>::try_from(rdata).map_err(|_| invalid())?.into())
+            }
+            RecordType::CNAME => RecordData::Cname(name(rdata)?),
+            RecordType::PTR => RecordData::Ptr(name(rdata)?),
+            RecordType::MX => {
+                let (preference, exchange) = rdata.split_at_checked(2).ok_or_else(invalid)?;
+                RecordData::Mx {
+                    preference: u16::from_be_bytes([preference[0], preference[1]]),
+                    exchange: name(exchange)?,
+                }
+            }
+            RecordType::TXT => {
+                // The character strings are joined, as `to_rdata` splits them
+                let mut text = Vec::with_capacity(rdata.len());
+                let mut rest = rdata;
+                while let Some((&len, tail)) = rest.split_first() {
+                    let (chunk, tail) = tail.split_at_checked(len as usize).ok_or_else(invalid)?;
+                    text.extend_from_slice(chunk);
+                    rest = tail;
+                }
+                RecordData::Txt(String::from_utf8(text).map_err(|_| invalid())?)
+            }
+            other if is_meta_type(other) => {
+                return Err(LocalRecordError::UnsupportedType(other.to_string()))
+            }
+            other => RecordData::Generic {
+                rtype: other,
+                rdata: rdata.to_vec(),
+            },
+        };
+        Ok(data)
+    }
+}
+
+impl fmt::Display for RecordData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordData::A(ip) => write!(f, "{}", ip),
+            RecordData::Aaaa(ip) => write!(f, "{}", ip),
+            RecordData::Cname(target) | RecordData::Ptr(target) => write!(f, "{}", target),
+            RecordData::Mx {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            RecordData::Txt(text) => write!(f, "\"{}\"", text),
+            RecordData::Generic { rdata, .. } => {
+                write!(f, "\\# {}", rdata.len())?;
+                if !rdata.is_empty() {
+                    f.write_str(" ")?;
+                    for byte in rdata {
+                        write!(f, "{:02x}", byte)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parse the rest of the RFC 3597 generic form after `\#`: the length of the
+/// RDATA, then the RDATA in hex, which may be split by whitespace
+fn parse_generic(rest: &str) -> Result<(Vec<u8>, &str), LocalRecordError> {
+    let (len, mut rest) = split_token(rest).ok_or(LocalRecordError::MissingField("length"))?;
+    let len: usize = len
+        .parse()
+        .map_err(|_| LocalRecordError::InvalidValue(len.to_string()))?;
+    let mut rdata = Vec::with_capacity(len);
+    while rdata.len() < len {
+        let (hex, after) = split_token(rest).ok_or(LocalRecordError::MissingField("rdata"))?;
+        let invalid = || LocalRecordError::InvalidValue(hex.to_string());
+        if hex.len() % 2 != 0 || rdata.len() + hex.len() / 2 > len {
+            return Err(invalid());
+        }
+        for pair in hex.as_bytes().chunks(2) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            rdata.push(u8::from_str_radix(pair, 16).map_err(|_| invalid())?);
+        }
+        rest = after;
+    }
+    Ok((rdata, rest))
+}
+
+/// Whether `rtype` is a meta type (RFC 6895 §3.1), which only appears in
+/// queries or as a transport record and never holds data
+fn is_meta_type(rtype: RecordType) -> bool {
+    rtype.0 == 0 || rtype == RecordType::OPT || (128..=255).contains(&rtype.0)
+}
+
+/// A single locally served record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+    /// Only answer clients from this location (needs `--geoip-db`)
+    pub location: Option<GeoTag>,
+    /// Relative share of answers among the name's records of the same type;
+    /// see `LocalRecordsActor::lookup`
+    pub weight: Option<u32>,
+    /// How the record's address is health checked; the record is withdrawn
+    /// while the check fails (see `health.rs`)
+    pub check: Option<HealthCheck>,
+    /// Only served while none of the name's primary records are healthy
+    pub backup: bool,
+    /// Answer the name's records of the same type one at a time, in turn,
+    /// instead of all at once or one at random (`select=round-robin`)
+    pub round_robin: bool,
+}
+
+impl LocalRecord {
+    pub fn new(name: &str, ttl: u32, data: RecordData) -> Self {
+        Self {
+            name: normalize_name(name),
+            ttl,
+            data,
+            location: None,
+            weight: None,
+            check: None,
+            backup: false,
+            round_robin: false,
+        }
+    }
+
+    /// Apply a `key=value` option from the text form of a record
+    fn apply_option(&mut self, option: &str) -> Result<(), LocalRecordError> {
+        let invalid = || LocalRecordError::InvalidValue(option.to_string());
+
+        let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+        match key.to_ascii_lowercase().as_str() {
+            "country" | "continent" | "site" => self.location = Some(option.parse()?),
+            "weight" => self.weight = Some(value.parse().map_err(|_| invalid())?),
+            "check" => {
+                if !matches!(self.data, RecordData::A(_) | RecordData::Aaaa(_)) {
+                    return Err(LocalRecordError::InvalidValue(format!(
+                        "{} (health checks need an A or AAAA record)",
+                        option
+                    )));
+                }
+                self.check = Some(value.parse().map_err(|_| invalid())?);
+            }
+            "role" => match value.to_ascii_lowercase().as_str() {
+                "primary" => self.backup = false,
+                "backup" => self.backup = true,
+                _ => return Err(invalid()),
+            },
+            "select" => match value.to_ascii_lowercase().as_str() {
+                "round-robin" => self.round_robin = true,
+                "random" => self.round_robin = false,
+                _ => return Err(invalid()),
+            },
+            _ => {
+                return Err(LocalRecordError::InvalidValue(format!(
+                    "unknown option '{}'",
+                    option
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// The DNS record type of this record
+    pub fn rtype(&self) -> RecordType {
+        self.data.rtype()
+    }
+
+    /// The address health checked for this record, and how, if it is
+    pub fn check_target(&self) -> Option<CheckTarget> {
+        let ip: IpAddr = match self.data {
+            RecordData::A(ip) => ip.into(),
+            RecordData::Aaaa(ip) => ip.into(),
+            _ => return None,
+        };
+        let check = self.check.clone()?;
+        Some(CheckTarget { ip, check })
+    }
+
+    /// A record received from another server, e.g. in a zone transfer
+    pub fn from_resource_record(record: &DnsResourceRecord) -> Result<Self, LocalRecordError> {
+        if record.rclass != RecordClass::IN {
+            return Err(LocalRecordError::InvalidValue(format!(
+                "class {} of {}",
+                record.rclass, record.name
+            )));
+        }
+        let data = RecordData::from_rdata(record.rtype, &record.rdata)?;
+        Ok(Self::new(&record.name, record.ttl, data))
+    }
+
+    /// Convert into a resource record suitable for an answer section
+    pub fn to_resource_record(&self) -> DnsResourceRecord {
+        DnsResourceRecord::new(
+            self.name.clone(),
+            self.rtype(),
+            RecordClass::IN,
+            self.ttl,
+            self.data.to_rdata(),
+        )
+    }
+}
+
+impl fmt::Display for LocalRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.name,
+            self.rtype(),
+            self.data,
+            self.ttl
+        )?;
+        if let Some(location) = &self.location {
+            write!(f, " {}", location)?;
+        }
+        if let Some(weight) = self.weight {
+            write!(f, " weight={}", weight)?;
+        }
+        if let Some(check) = &self.check {
+            write!(f, " check={}", check)?;
+        }
+        if self.backup {
+            write!(f, " role=backup")?;
+        }
+        if self.round_robin {
+            write!(f, " select=round-robin")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses records of the form `<name> <type> <value> [ttl] [options]`, e.g.
+/// `nas.lan A 192.168.1.10 600` or `lan MX 10 mail.lan`.
+/// TXT values may be quoted to include spaces. Options are `key=value`
+/// tokens: `country=<code>`, `continent=<code>` or `site=<name>` for
+/// location-aware answers, `weight=<n>` for weighted answers,
+/// `select=round-robin` for answers in turn, and `check=<port>`,
+/// `check=http:<port>[/path]` and `role=backup` for failover.
+impl FromStr for LocalRecord {
+    type Err = LocalRecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, rest) = split_token(s).ok_or(LocalRecordError::MissingField("name"))?;
+        let (rtype, rest) = split_token(rest).ok_or(LocalRecordError::MissingField("type"))?;
+
+        let (data, mut rest) = RecordData::parse(parse_record_type(rtype)?, rest)?;
+
+        validate_name(name)?;
+        let mut record = LocalRecord::new(name, DEFAULT_LOCAL_TTL, data);
+
+        // The TTL, if given, comes before any options
+        if let Some((ttl, after)) = split_token(rest).filter(|(token, _)| !token.contains('=')) {
+            record.ttl = ttl
+                .parse()
+                .map_err(|_| LocalRecordError::InvalidValue(ttl.to_string()))?;
+            rest = after;
+        }
+        while let Some((option, after)) = split_token(rest) {
+            if !option.contains('=') {
+                return Err(LocalRecordError::TrailingInput(rest.trim().to_string()));
+            }
+            record.apply_option(option)?;
+            rest = after;
+        }
+
+        Ok(record)
+    }
+}
+
+/// Parse the type of a local record: any but the meta types
+pub fn parse_record_type(s: &str) -> Result<RecordType, LocalRecordError> {
+    match s.parse() {
+        Ok(rtype) if !is_meta_type(rtype) => Ok(rtype),
+        Ok(rtype) => Err(LocalRecordError::UnsupportedType(rtype.to_string())),
+        Err(_) => Err(LocalRecordError::UnsupportedType(s.to_ascii_uppercase())),
+    }
+}
+
+/// Flat representation of a record used for JSON/CSV import and export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordRow {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub rtype: RecordType,
+    pub value: String,
+    #[serde(default)]
+    pub ttl: Option<u32>,
+    /// GeoDNS tag, e.g. `country=DE`
+    #[serde(default)]
+    pub location: Option<String>,
+    /// Weight for weighted answer selection
+    #[serde(default)]
+    pub weight: Option<u32>,
+    /// Health check: a TCP port, or `http:<port>[/path]`
+    #[serde(default)]
+    pub check: Option<HealthCheck>,
+    /// Failover backup record
+    #[serde(default)]
+    pub backup: bool,
+    /// Answered in turn with the name's other records
+    #[serde(default)]
+    pub round_robin: bool,
+}
+
+impl From<&LocalRecord> for RecordRow {
+    fn from(record: &LocalRecord) -> Self {
+        Self {
+            name: record.name.clone(),
+            rtype: record.rtype(),
+            value: record.data.value(),
+            ttl: Some(record.ttl),
+            location: record.location.as_ref().map(GeoTag::to_string),
+            weight: record.weight,
+            check: record.check.clone(),
+            backup: record.backup,
+            round_robin: record.round_robin,
+        }
+    }
+}
+
+impl TryFrom<RecordRow> for LocalRecord {
+    type Error = LocalRecordError;
+
+    fn try_from(row: RecordRow) -> Result<Self, Self::Error> {
+        validate_name(&row.name)?;
+        let data = if row.rtype == RecordType::TXT {
+            // TXT values are taken verbatim, spaces and all
+            RecordData::Txt(row.value)
+        } else {
+            let (data, rest) = RecordData::parse(row.rtype, &row.value)?;
+            if !rest.trim().is_empty() {
+                return Err(LocalRecordError::TrailingInput(rest.trim().to_string()));
+            }
+            data
+        };
+        let mut record = LocalRecord::new(&row.name, row.ttl.unwrap_or(DEFAULT_LOCAL_TTL), data);
+        if let Some(location) = row.location.filter(|location| !location.is_empty()) {
+            record.location = Some(location.parse()?);
+        }
+        record.weight = row.weight;
+        if let Some(check) = row.check {
+            record.apply_option(&format!("check={}", check))?;
+        }
+        record.backup = row.backup;
+        record.round_robin = row.round_robin;
+        Ok(record)
+    }
+}
+
+/// Serialization formats supported for import/export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    Json,
+    Csv,
+}
+
+impl FromStr for RecordFormat {
+    type Err = LocalRecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(RecordFormat::Json),
+            "csv" => Ok(RecordFormat::Csv),
+            other => Err(LocalRecordError::InvalidValue(format!(
+                "unknown format '{}', expected json or csv",
+                other
+            ))),
+        }
+    }
+}
+
+impl RecordFormat {
+    /// The format a file's extension suggests: CSV for `.csv`, otherwise JSON
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => RecordFormat::Csv,
+            _ => RecordFormat::Json,
+        }
+    }
+}
+
+/// Serialize records in the given format
+pub fn export_records(records: &[LocalRecord], format: RecordFormat) -> String {
+    let rows: Vec<RecordRow> = records.iter().map(RecordRow::from).collect();
+    match format {
+        RecordFormat::Json => {
+            serde_json::to_string_pretty(&rows).expect("record rows always serialize") + "\n"
+        }
+        RecordFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for row in &rows {
+                writer.serialize(row).expect("record rows always serialize");
+            }
+            // Write the header even when there are no rows
+            if rows.is_empty() {
+                writer
+                    .write_record([
+                        "name",
+                        "type",
+                        "value",
+                        "ttl",
+                        "location",
+                        "weight",
+                        "check",
+                        "backup",
+                        "round_robin",
+                    ])
+                    .expect("writing to a Vec cannot fail");
+            }
+            let bytes = writer.into_inner().expect("writing to a Vec cannot fail");
+            String::from_utf8(bytes).expect("csv output of UTF-8 rows is UTF-8")
+        }
+    }
+}
+
+/// Parse and validate records in the given format.
+/// Fails on the first invalid record, so nothing is imported from a bad document.
+pub fn import_records(
+    input: &str,
+    format: RecordFormat,
+) -> Result<Vec<LocalRecord>, LocalRecordError> {
+    let rows: Vec<RecordRow> = match format {
+        RecordFormat::Json => {
+            serde_json::from_str(input).map_err(|e| LocalRecordError::Malformed(e.to_string()))?
+        }
+        RecordFormat::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|e| LocalRecordError::Malformed(e.to_string()))?,
+    };
+
+    rows.into_iter()
+        .enumerate()
+        .map(|(index, row)| {
+            LocalRecord::try_from(row).map_err(|e| LocalRecordError::InvalidEntry {
+                index: index + 1,
+                reason: Box::new(e),
+            })
+        })
+        .collect()
+}
+
+/// Changes an import makes (or would make, for a dry run) to the record table
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImportDiff {
+    pub added: Vec<LocalRecord>,
+    pub removed: Vec<LocalRecord>,
+    pub unchanged: usize,
+}
+
+impl ImportDiff {
+    /// Whether anything was added or removed
+    pub fn is_changed(&self) -> bool {
+        !(self.added.is_empty() && self.removed.is_empty())
+    }
+}
+
+impl fmt::Display for ImportDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for record in &self.added {
+            writeln!(f, "+ {}", record)?;
+        }
+        for record in &self.removed {
+            writeln!(f, "- {}", record)?;
+        }
+        write!(
+            f,
+            "{} added, {} removed, {} unchanged",
+            self.added.len(),
+            self.removed.len(),
+            self.unchanged
+        )
+    }
+}
+
+/// What turns the records `from` into the records `to`
+pub fn diff_records(from: &[LocalRecord], to: &[LocalRecord]) -> ImportDiff {
+    let mut diff = ImportDiff::default();
+    for record in to {
+        if from.contains(record) {
+            diff.unchanged += 1;
+        } else {
+            diff.added.push(record.clone());
+        }
+    }
+    diff.removed = from
+        .iter()
+        .filter(|record| !to.contains(record))
+        .cloned()
+        .collect();
+    diff
+}
+
+/// A state of the record table kept in the history, as listed by `versions`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionInfo {
+    /// Increases by one with every change to the table
+    pub serial: u32,
+    pub time: SystemTime,
+    /// What produced this version, e.g. `add nas.lan A 10.0.0.2 300`
+    pub change: String,
+    pub records: usize,
+}
+
+impl fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>5}  {}  {:>5} records  {}",
+            self.serial,
+            format_time(self.time),
+            self.records,
+            self.change
+        )
+    }
+}
+
+/// Lowercase a name and strip its trailing dot so lookups are case-insensitive
+pub fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether a normalized name is `zone` itself or below it
+pub fn is_in_zone(name: &str, zone: &str) -> bool {
+    name.strip_suffix(zone)
+        .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+}
+
+pub fn validate_name(name: &str) -> Result<(), LocalRecordError> {
+    let trimmed = name.trim_end_matches('.');
+    if trimmed.is_empty() || trimmed.len() > 253 {
+        return Err(LocalRecordError::InvalidName(name.to_string()));
+    }
+    if trimmed
+        .split('.')
+        .any(|label| label.is_empty() || label.len() > 63)
+    {
+        return Err(LocalRecordError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Split off the first whitespace-delimited token
+fn split_token(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    if s.is_empty() {
+        return None;
+    }
+    match s.find(char::is_whitespace) {
+        Some(idx) => Some((&s[..idx], &s[idx..])),
+        None => Some((s, "")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let records: Vec<LocalRecord> = [
+            "nas.lan A 192.168.1.10 600",
+            "lan TXT \"a b, c\" 60",
+            "www.lan A 10.0.0.1 60 continent=EU",
+            "canary.lan A 10.0.0.2 60 weight=10",
+            "app.lan A 10.0.0.3 60 check=443",
+            "app.lan A 10.0.0.4 60 role=backup",
+            "api.lan A 10.0.0.5 60 check=http:8080/healthz select=round-robin",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+
+        for format in [RecordFormat::Json, RecordFormat::Csv] {
+            let exported = export_records(&records, format);
+            let imported = import_records(&exported, format).unwrap();
+            assert_eq!(imported, records, "round trip through {:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_import_reports_invalid_entry() {
+        let csv = "name,type,value,ttl\nnas.lan,A,10.0.0.1,\nbad.lan,A,nope,60\n";
+        match import_records(csv, RecordFormat::Csv) {
+            Err(LocalRecordError::InvalidEntry { index, .. }) => assert_eq!(index, 2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let json = r#"[{"name": "nas.lan", "type": "A", "value": "10.0.0.1"}]"#;
+        let imported = import_records(json, RecordFormat::Json).unwrap();
+        assert_eq!(imported[0].ttl, DEFAULT_LOCAL_TTL);
+    }
+
+    #[test]
+    fn test_parse_a_record_with_default_ttl() {
+        let record: LocalRecord = "NAS.lan. A 192.168.1.10".parse().unwrap();
+        assert_eq!(record.name, "nas.lan");
+        assert_eq!(record.ttl, DEFAULT_LOCAL_TTL);
+        assert_eq!(record.data, RecordData::A(Ipv4Addr::new(192, 168, 1, 10)));
+        assert_eq!(record.to_resource_record().rdata, vec![192, 168, 1, 10]);
+    }
+
+    #[test]
+    fn test_parse_mx_and_quoted_txt() {
+        let mx: LocalRecord = "lan MX 10 mail.lan 60".parse().unwrap();
+        assert_eq!(mx.ttl, 60);
+        assert_eq!(
+            mx.data.to_rdata(),
+            vec![0, 10, 4, b'm', b'a', b'i', b'l', 3, b'l', b'a', b'n', 0]
+        );
+
+        let txt: LocalRecord = "lan TXT \"hello world\" 120".parse().unwrap();
+        assert_eq!(txt.data, RecordData::Txt("hello world".to_string()));
+        assert_eq!(txt.ttl, 120);
+        assert_eq!(txt.data.to_rdata()[0], 11);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let record: LocalRecord = "www.lan CNAME nas.lan 42".parse().unwrap();
+        assert_eq!(record.to_string(), "www.lan CNAME nas.lan 42");
+        assert_eq!(record.to_string().parse::<LocalRecord>().unwrap(), record);
+    }
+
+    #[test]
+    fn test_parse_location() {
+        let record: LocalRecord = "www.lan A 10.0.0.1 country=de".parse().unwrap();
+        assert_eq!(record.ttl, DEFAULT_LOCAL_TTL);
+        assert_eq!(record.location, Some(GeoTag::Country("DE".to_string())));
+        assert_eq!(record.to_string(), "www.lan A 10.0.0.1 300 country=DE");
+
+        // Older CSV exports have no location column
+        let csv = "name,type,value,ttl\nnas.lan,A,10.0.0.1,60\n";
+        assert_eq!(
+            import_records(csv, RecordFormat::Csv).unwrap()[0].location,
+            None
+        );
+
+        assert!(matches!(
+            "www.lan A 10.0.0.1 country=DE 60".parse::<LocalRecord>(),
+            Err(LocalRecordError::TrailingInput(_))
+        ));
+        assert!(matches!(
+            "www.lan A 10.0.0.1 60 region=EU".parse::<LocalRecord>(),
+            Err(LocalRecordError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_weight() {
+        let record: LocalRecord = "www.lan A 10.0.0.1 60 weight=90 continent=eu"
+            .parse()
+            .unwrap();
+        assert_eq!(record.weight, Some(90));
+        assert_eq!(
+            record.to_string(),
+            "www.lan A 10.0.0.1 60 continent=EU weight=90"
+        );
+        assert!(matches!(
+            "www.lan A 10.0.0.1 weight=-1".parse::<LocalRecord>(),
+            Err(LocalRecordError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_failover() {
+        let primary: LocalRecord = "app.lan A 10.0.0.3 check=443".parse().unwrap();
+        assert_eq!(primary.check_target().unwrap().to_string(), "10.0.0.3:443");
+        let probed: LocalRecord = "api.lan AAAA fd00::5 check=http:8080/healthz"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            probed.check_target().unwrap().to_string(),
+            "http://[fd00::5]:8080/healthz"
+        );
+        let probed: LocalRecord = "api.lan A 10.0.0.5 60 check=http:80".parse().unwrap();
+        assert_eq!(
+            probed.check,
+            Some(HealthCheck::Http {
+                port: 80,
+                path: "/".to_string()
+            })
+        );
+        assert!("api.lan A 10.0.0.5 check=http:web"
+            .parse::<LocalRecord>()
+            .is_err());
+        assert!(!primary.backup);
+
+        let backup: LocalRecord = "app.lan AAAA ::1 60 role=BACKUP".parse().unwrap();
+        assert!(backup.backup);
+        assert_eq!(backup.to_string(), "app.lan AAAA ::1 60 role=backup");
+
+        assert!(matches!(
+            "app.lan CNAME other.lan check=80".parse::<LocalRecord>(),
+            Err(LocalRecordError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            "app.lan A 10.0.0.3 role=spare".parse::<LocalRecord>(),
+            Err(LocalRecordError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            "nas.lan".parse::<LocalRecord>(),
+            Err(LocalRecordError::MissingField("type"))
+        ));
+        assert!(matches!(
+            "nas.lan A not-an-ip".parse::<LocalRecord>(),
+            Err(LocalRecordError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            "nas.lan SRV 1 2 3 x".parse::<LocalRecord>(),
+            Err(LocalRecordError::UnsupportedType(_))
+        ));
+        assert!(matches!(
+            "nas..lan A 10.0.0.1".parse::<LocalRecord>(),
+            Err(LocalRecordError::InvalidName(_))
+        ));
+    }
+
+    #[test]
+    fn test_generic_records() {
+        let tlsa: LocalRecord = "_443._tcp.www.lan TLSA \\# 7 030101 dead beef 600 weight=2"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            tlsa.data,
+            RecordData::Generic {
+                rtype: RecordType::TLSA,
+                rdata: vec![3, 1, 1, 0xde, 0xad, 0xbe, 0xef],
+            }
+        );
+        assert_eq!((tlsa.ttl, tlsa.weight), (600, Some(2)));
+        assert_eq!(
+            tlsa.to_string(),
+            "_443._tcp.www.lan TLSA \\# 7 030101deadbeef 600 weight=2"
+        );
+        assert_eq!(tlsa.to_string().parse::<LocalRecord>().unwrap(), tlsa);
+
+        let empty: LocalRecord = "lan TYPE65280 \\# 0".parse().unwrap();
+        assert!(empty.to_resource_record().rdata.is_empty());
+        assert_eq!(empty.to_string().parse::<LocalRecord>().unwrap(), empty);
+
+        // Types with a form of their own are decoded into it
+        let a: LocalRecord = "nas.lan A \\# 4 c0a8010a".parse().unwrap();
+        assert_eq!(a.data, RecordData::A(Ipv4Addr::new(192, 168, 1, 10)));
+
+        for line in [
+            "lan TYPE65280 \\# 3 0102",
+            "lan TYPE65280 \\# 1 0102",
+            "lan TYPE65280 \\# 2 01x2",
+            "lan TYPE65280 \\#",
+            "nas.lan A \\# 2 0102",
+            "lan OPT \\# 0",
+            "lan ANY \\# 0",
+        ] {
+            assert!(line.parse::<LocalRecord>().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_resource_record_round_trip() {
+        for line in [
+            "nas.lan A 192.168.1.10 600",
+            "v6.lan AAAA 2001:db8::1 60",
+            "www.lan CNAME nas.lan 60",
+            "lan MX 10 mail.lan 60",
+            "10.1.168.192.in-addr.arpa PTR nas.lan 60",
+            "lan TXT \"v=spf1 -all\" 60",
+        ] {
+            let record: LocalRecord = line.parse().unwrap();
+            let decoded = LocalRecord::from_resource_record(&record.to_resource_record()).unwrap();
+            assert_eq!(decoded, record, "{}", line);
+        }
+
+        // Long TXT data spans several character strings
+        let long = LocalRecord::new("lan", 60, RecordData::Txt("x".repeat(300)));
+        let decoded = LocalRecord::from_resource_record(&long.to_resource_record()).unwrap();
+        assert_eq!(decoded, long);
+
+        let ns = DnsResourceRecord::new(
+            "lan".to_string(),
+            RecordType::NS,
+            RecordClass::IN,
+            60,
+            encode_name("ns.lan"),
+        );
+        let decoded = LocalRecord::from_resource_record(&ns).unwrap();
+        assert_eq!(decoded.to_resource_record().rdata, ns.rdata);
+        let opt = DnsResourceRecord {
+            rtype: RecordType::OPT,
+            ..ns
+        };
+        assert!(matches!(
+            LocalRecord::from_resource_record(&opt),
+            Err(LocalRecordError::UnsupportedType(name)) if name == "OPT"
+        ));
+        let short = DnsResourceRecord::new(
+            "lan".to_string(),
+            RecordType::A,
+            RecordClass::IN,
+            60,
+            vec![10, 0],
+        );
+        assert!(LocalRecord::from_resource_record(&short).is_err());
+    }
+
+    #[test]
+    fn test_is_in_zone() {
+        assert!(is_in_zone("example.com", "example.com"));
+        assert!(is_in_zone("www.example.com", "example.com"));
+        assert!(!is_in_zone("badexample.com", "example.com"));
+        assert!(!is_in_zone("com", "example.com"));
+    }
+}
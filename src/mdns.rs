@@ -0,0 +1,506 @@
+//! Multicast DNS for `.local` names (`--mdns`, `--mdns-proxy`)
+//!
+//! With `--mdns` the server answers mDNS queries (RFC 6762) for the `.local`
+//! names among its local records, on 224.0.0.251 port 5353. It shares the
+//! port with any responder already on the host. Questions asking for a
+//! unicast response (the QU bit) are answered to the asker. So are one-shot
+//! queries sent from a port other than 5353, which get their ID and question
+//! back and TTLs of at most ten seconds. Everything else is answered to the
+//! group. Only names with records are answered: mDNS has no negative answers.
+//!
+//! With `--mdns-proxy` the default tenant answers unicast questions for
+//! `.local` names from the devices on the LAN. Such a question is sent as a
+//! one-shot mDNS query, and the first answer heard within a second is
+//! returned with its TTLs capped at ten seconds. This makes printers and
+//! other devices resolvable from clients on other VLANs, or on wired links
+//! mDNS doesn't reach. A name nobody answers is NXDOMAIN. A name answered
+//! only with other types (or an NSEC record) has no data of the type asked
+//! for. Local records for a name still take precedence, and `.local` names
+//! aren't forwarded upstream either way.
+//!
+//! Both join the group and send on each `--mdns-interface`, given by its IPv4
+//! address, or on the default interface when none is given. Picking the
+//! interface to send on takes Unix; elsewhere the default one is used.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::future::BoxFuture;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, warn};
+
+use crate::codec;
+use crate::geoip::ClientLocation;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::local_records::{is_in_zone, normalize_name};
+use crate::names::DisplayName;
+use crate::pipeline::{Answer, Middleware, Next, Request};
+use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion, DnsResourceRecord};
+use crate::query_log::Disposition;
+use crate::registry::{Opcode, Rcode, RecordClass, RecordType};
+
+/// The mDNS port
+pub const MDNS_PORT: u16 = 5353;
+
+/// The IPv4 mDNS group
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+/// How long the proxy listens for answers
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The longest TTL given to unicast clients (RFC 6762, section 6.7)
+const LEGACY_TTL: u32 = 10;
+
+/// The top bit of the class: the QU bit of a question, and the cache-flush
+/// bit of a record in a response
+const TOP_BIT: u16 = 0x8000;
+
+/// Largest mDNS message (RFC 6762, section 17)
+const MAX_MESSAGE: usize = 9000;
+
+/// Whether a name is under `.local`
+pub fn is_local(name: &str) -> bool {
+    is_in_zone(&normalize_name(name), "local")
+}
+
+/// Where a response goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Destination {
+    /// To the group, on each interface
+    Group,
+    /// Back to the asker
+    Unicast(SocketAddr),
+}
+
+/// The local records answering an mDNS query from `from`, and where to send
+/// them, if it asks for any we have
+fn respond(
+    query: &DnsPacket,
+    from: SocketAddr,
+    local_records: &LocalRecordsHandle,
+) -> Option<(DnsPacket, Destination)> {
+    if query.header.qr || query.header.opcode != Opcode::QUERY {
+        return None;
+    }
+    // One-shot queries come from other ports (RFC 6762, section 6.7)
+    let legacy = from.port() != MDNS_PORT;
+    let mut unicast = legacy;
+    let mut answers = Vec::new();
+    for question in &query.questions {
+        let class = RecordClass(question.qclass.0 & !TOP_BIT);
+        if !is_local(&question.name) || (class != RecordClass::IN && class != RecordClass::ANY) {
+            continue;
+        }
+        let records =
+            local_records.lookup(&question.name, question.qtype, &ClientLocation::default());
+        if records.is_empty() {
+            continue;
+        }
+        unicast |= question.qclass.0 & TOP_BIT != 0;
+        for record in records {
+            let mut answer = record.to_resource_record();
+            if legacy {
+                answer.ttl = answer.ttl.min(LEGACY_TTL);
+            } else {
+                // Our records are the only ones for the name
+                answer.rclass = RecordClass(answer.rclass.0 | TOP_BIT);
+            }
+            answers.push(answer);
+        }
+    }
+    if answers.is_empty() {
+        return None;
+    }
+
+    let response = DnsPacket {
+        header: DnsPacketHeader {
+            id: if legacy { query.header.id } else { 0 },
+            qr: true,
+            opcode: Opcode::QUERY,
+            aa: true,
+            tc: false,
+            rd: false,
+            ra: false,
+            z: 0,
+            rcode: Rcode::NOERROR,
+            qdcount: 0,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        },
+        // One-shot queriers check the question they get back
+        questions: if legacy {
+            query.questions.clone()
+        } else {
+            Vec::new()
+        },
+        answers,
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+    };
+    let destination = if unicast {
+        Destination::Unicast(from)
+    } else {
+        Destination::Group
+    };
+    Some((response, destination))
+}
+
+/// The records of an mDNS response answering `question`, made fit for a
+/// unicast client, and whether the response has records for its name at all
+fn matching(question: &DnsQuestion, response: &DnsPacket) -> (Vec<DnsResourceRecord>, bool) {
+    let name = normalize_name(&question.name);
+    let mut named = false;
+    let mut records = Vec::new();
+    for record in response.answers.iter().chain(&response.additionals) {
+        if normalize_name(&record.name) != name {
+            continue;
+        }
+        named = true;
+        if record.rtype != question.qtype && question.qtype != RecordType::ANY {
+            continue;
+        }
+        let mut record = record.clone();
+        record.rclass = RecordClass(record.rclass.0 & !TOP_BIT);
+        record.ttl = record.ttl.min(LEGACY_TTL);
+        // The question's own spelling, which clients may check
+        record.name.clone_from(&question.name);
+        // Responders on several interfaces send the same records
+        let known = records
+            .iter()
+            .any(|r: &DnsResourceRecord| r.rtype == record.rtype && r.rdata == record.rdata);
+        if !known {
+            records.push(record);
+        }
+    }
+    (records, named)
+}
+
+/// The interfaces to send on; `None` is the default one
+fn send_interfaces(interfaces: &[Ipv4Addr]) -> Vec<Option<Ipv4Addr>> {
+    if interfaces.is_empty() {
+        vec![None]
+    } else {
+        interfaces.iter().copied().map(Some).collect()
+    }
+}
+
+/// Send `packet` to the group on each interface
+async fn send_to_group(sock: &UdpSocket, packet: &[u8], interfaces: &[Ipv4Addr]) -> io::Result<()> {
+    for interface in send_interfaces(interfaces) {
+        if let Some(interface) = interface {
+            set_multicast_interface(sock, interface)?;
+        }
+        sock.send_to(packet, (MDNS_GROUP, MDNS_PORT)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_multicast_interface(sock: &UdpSocket, interface: Ipv4Addr) -> io::Result<()> {
+    socket2::SockRef::from(sock).set_multicast_if_v4(&interface)
+}
+
+#[cfg(not(unix))]
+fn set_multicast_interface(_sock: &UdpSocket, _interface: Ipv4Addr) -> io::Result<()> {
+    Ok(())
+}
+
+/// The mDNS port, shared with other responders on the host
+#[cfg(unix)]
+fn bind_mdns() -> io::Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT));
+    let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_reuse_address(true)?;
+    sock.set_reuse_port(true)?;
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+#[cfg(not(unix))]
+fn bind_mdns() -> io::Result<std::net::UdpSocket> {
+    let sock = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, MDNS_PORT))?;
+    sock.set_nonblocking(true)?;
+    Ok(sock)
+}
+
+/// Answer mDNS queries for the `.local` names among `local_records` until
+/// the socket fails
+pub async fn run_responder(
+    local_records: LocalRecordsHandle,
+    interfaces: Vec<Ipv4Addr>,
+) -> io::Result<()> {
+    let sock = UdpSocket::from_std(bind_mdns()?)?;
+    for interface in send_interfaces(&interfaces) {
+        sock.join_multicast_v4(MDNS_GROUP, interface.unwrap_or(Ipv4Addr::UNSPECIFIED))?;
+    }
+    tracing::info!(
+        "Answering mDNS queries for .local records on port {}",
+        MDNS_PORT
+    );
+
+    let mut buf = vec![0; MAX_MESSAGE];
+    let mut encoded = BytesMut::new();
+    loop {
+        let (len, from) = sock.recv_from(&mut buf).await?;
+        let query = match codec::decode_packet(&buf[..len]) {
+            Ok(Some(query)) => query,
+            _ => continue,
+        };
+        let Some((response, destination)) = respond(&query, from, &local_records) else {
+            continue;
+        };
+        encoded.clear();
+        if let Err(e) = codec::encode_packet(&response, &mut encoded) {
+            warn!("Could not encode an mDNS response: {}", e);
+            continue;
+        }
+        debug!(
+            "Answered an mDNS query from {} with {} records",
+            from,
+            response.answers.len()
+        );
+        let sent = match destination {
+            Destination::Group => send_to_group(&sock, &encoded, &interfaces).await,
+            Destination::Unicast(to) => sock.send_to(&encoded, to).await.map(drop),
+        };
+        if let Err(e) = sent {
+            warn!("Could not send an mDNS response: {}", e);
+        }
+    }
+}
+
+/// Answers unicast questions for `.local` names over mDNS
+#[derive(Debug, Default)]
+pub struct MdnsProxy {
+    interfaces: Vec<Ipv4Addr>,
+}
+
+impl MdnsProxy {
+    /// A proxy querying on `interfaces`, or the default one
+    pub fn new(interfaces: Vec<Ipv4Addr>) -> Self {
+        MdnsProxy { interfaces }
+    }
+
+    /// Ask the LAN for `question` with a one-shot query
+    async fn query(&self, question: &DnsQuestion) -> io::Result<Answer> {
+        let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let id: u16 = rand::random();
+        let query = DnsPacket {
+            header: DnsPacketHeader {
+                id,
+                qr: false,
+                opcode: Opcode::QUERY,
+                aa: false,
+                tc: false,
+                rd: false,
+                ra: false,
+                z: 0,
+                rcode: Rcode::NOERROR,
+                qdcount: 0,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![DnsQuestion {
+                name: question.name.clone(),
+                qtype: question.qtype,
+                qclass: RecordClass::IN,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        };
+        let mut encoded = BytesMut::new();
+        codec::encode_packet(&query, &mut encoded)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        send_to_group(&sock, &encoded, &self.interfaces).await?;
+
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut buf = vec![0; MAX_MESSAGE];
+        let mut named = false;
+        while let Ok(received) = timeout_at(deadline, sock.recv_from(&mut buf)).await {
+            let (len, from) = received?;
+            let response = match codec::decode_packet(&buf[..len]) {
+                Ok(Some(response)) if response.header.qr => response,
+                _ => continue,
+            };
+            let (records, has_name) = matching(question, &response);
+            named |= has_name;
+            if !records.is_empty() {
+                debug!(
+                    "{} answered {} over mDNS ({} records)",
+                    from,
+                    DisplayName(&question.name),
+                    records.len()
+                );
+                let mut answer = Answer::new(Disposition::Forwarded);
+                answer.answers = records;
+                return Ok(answer);
+            }
+        }
+        debug!("No mDNS answer for {}", DisplayName(&question.name));
+        let rcode = if named {
+            Rcode::NOERROR
+        } else {
+            Rcode::NXDOMAIN
+        };
+        Ok(Answer::new(Disposition::Forwarded).with_rcode(rcode))
+    }
+}
+
+impl Middleware for MdnsProxy {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let question = request.question;
+        if !is_local(&question.name) || request.ctx.local_records.exists(&question.name) {
+            return next.run(request);
+        }
+        Box::pin(async move {
+            match self.query(question).await {
+                Ok(answer) => answer,
+                Err(e) => {
+                    warn!(
+                        "Could not ask for {} over mDNS: {}",
+                        DisplayName(&question.name),
+                        e
+                    );
+                    Answer::new(Disposition::Failed).with_rcode(Rcode::SERVFAIL)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_records::LocalRecord;
+
+    fn question(name: &str, qtype: RecordType, qclass: u16) -> DnsQuestion {
+        DnsQuestion {
+            name: name.to_string(),
+            qtype,
+            qclass: RecordClass(qclass),
+        }
+    }
+
+    fn packet(id: u16, questions: Vec<DnsQuestion>, answers: Vec<DnsResourceRecord>) -> DnsPacket {
+        DnsPacket {
+            header: DnsPacketHeader {
+                id,
+                qr: !answers.is_empty(),
+                opcode: Opcode::QUERY,
+                aa: false,
+                tc: false,
+                rd: false,
+                ra: false,
+                z: 0,
+                rcode: Rcode::NOERROR,
+                qdcount: 0,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions,
+            answers,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    fn record(line: &str) -> DnsResourceRecord {
+        line.parse::<LocalRecord>().unwrap().to_resource_record()
+    }
+
+    #[test]
+    fn test_is_local() {
+        assert!(is_local("printer.local"));
+        assert!(is_local("Printer.LOCAL."));
+        assert!(is_local("local"));
+        assert!(!is_local("printer.notlocal"));
+        assert!(!is_local("local.example"));
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let local_records = LocalRecordsHandle::new();
+        local_records
+            .add("nas.local A 192.168.1.5 120".parse().unwrap())
+            .await;
+        local_records
+            .add("www.lan A 192.168.1.6".parse().unwrap())
+            .await;
+        let peer: SocketAddr = "192.168.2.7:5353".parse().unwrap();
+
+        // Answered to the group, with the cache-flush bit
+        let query = packet(0, vec![question("nas.local", RecordType::A, 1)], vec![]);
+        let (response, destination) = respond(&query, peer, &local_records).unwrap();
+        assert_eq!(destination, Destination::Group);
+        assert_eq!(response.header.id, 0);
+        assert!(response.header.qr && response.header.aa);
+        assert!(response.questions.is_empty());
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rclass, RecordClass(0x8001));
+        assert_eq!(response.answers[0].ttl, 120);
+
+        // The QU bit asks for a unicast response
+        let query = packet(
+            0,
+            vec![question("nas.local", RecordType::A, 0x8001)],
+            vec![],
+        );
+        let (_, destination) = respond(&query, peer, &local_records).unwrap();
+        assert_eq!(destination, Destination::Unicast(peer));
+
+        // One-shot queries get their ID and question back, and short TTLs
+        let oneshot: SocketAddr = "192.168.2.7:40001".parse().unwrap();
+        let query = packet(77, vec![question("NAS.local", RecordType::A, 1)], vec![]);
+        let (response, destination) = respond(&query, oneshot, &local_records).unwrap();
+        assert_eq!(destination, Destination::Unicast(oneshot));
+        assert_eq!(response.header.id, 77);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.answers[0].rclass, RecordClass::IN);
+        assert_eq!(response.answers[0].ttl, LEGACY_TTL);
+
+        // Nothing for names we don't have, or outside .local
+        for name in ["tv.local", "www.lan"] {
+            let query = packet(0, vec![question(name, RecordType::A, 1)], vec![]);
+            assert!(respond(&query, peer, &local_records).is_none());
+        }
+        // Nor for responses
+        let response = packet(0, vec![], vec![record("nas.local A 192.168.1.5")]);
+        assert!(respond(&response, peer, &local_records).is_none());
+    }
+
+    #[test]
+    fn test_matching() {
+        let mut answer = record("printer.local A 192.168.1.9 120");
+        answer.rclass = RecordClass(0x8001);
+        let mut response = packet(
+            0,
+            vec![],
+            vec![answer, record("printer.local AAAA fe80::1 120")],
+        );
+        response
+            .additionals
+            .push(record("other.local A 192.168.1.10"));
+
+        let (records, named) = matching(&question("Printer.local", RecordType::A, 1), &response);
+        assert!(named);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, "Printer.local");
+        assert_eq!(records[0].rclass, RecordClass::IN);
+        assert_eq!(records[0].ttl, LEGACY_TTL);
+
+        let (records, named) = matching(&question("printer.local", RecordType::TXT, 1), &response);
+        assert!(records.is_empty() && named);
+        let (records, named) = matching(&question("tv.local", RecordType::A, 1), &response);
+        assert!(records.is_empty() && !named);
+    }
+}
@@ -0,0 +1,216 @@
+//! Server counters
+//!
+//! Plain atomic counters shared by every task through an `Arc<Metrics>`.
+//! Incrementing never blocks or allocates, so the hot path can count freely;
+//! the control socket's `stats` command reads a snapshot.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Query packets received and decoded
+    pub queries: AtomicU64,
+    /// Inbound packets with QR=1 (responses), dropped unanswered
+    pub responses_dropped: AtomicU64,
+    /// Query packets that failed to parse, answered FORMERR
+    pub queries_malformed: AtomicU64,
+    /// Retransmitted queries answered without a new resolution
+    pub duplicates_suppressed: AtomicU64,
+    /// Queries from clients the tenant doesn't serve, answered REFUSED
+    pub queries_refused: AtomicU64,
+    /// Packets from clients the tenant doesn't serve, dropped unanswered
+    /// (`--deny-action drop`)
+    pub queries_dropped: AtomicU64,
+    /// Packets from clients over their `--client-quota`, refused or dropped
+    /// unparsed (see `quota.rs`)
+    pub queries_over_quota: AtomicU64,
+    /// Questions for names on the blocklist
+    pub queries_blocked: AtomicU64,
+    /// Answers sent to the `--block-page` instead (see `block_page.rs`)
+    pub queries_redirected: AtomicU64,
+    /// Clients alerted on as possible DNS tunnels (see `tunnel.rs`)
+    pub tunnel_alerts: AtomicU64,
+    /// Queries of flagged clients over `--tunnel-rate-limit`, answered REFUSED
+    pub queries_rate_limited: AtomicU64,
+    /// Upstream lookups answered from the cache
+    pub cache_hits: AtomicU64,
+    /// Upstream lookups the cache couldn't answer
+    pub cache_misses: AtomicU64,
+    /// Popular cached answers looked up again before they expired
+    pub cache_prefetches: AtomicU64,
+    /// Cached answers dropped before they expired to make room
+    pub cache_evictions: AtomicU64,
+    /// Cache misses answered from the shared cache (see `shared_cache.rs`)
+    pub shared_cache_hits: AtomicU64,
+    /// Requests to the shared cache that failed or timed out
+    pub shared_cache_errors: AtomicU64,
+    /// Calls of a `--script` or `--plugin` policy that failed (see `policy.rs`)
+    pub policy_errors: AtomicU64,
+    /// Cache misses answered by joining the same lookup already under way
+    pub queries_coalesced: AtomicU64,
+    /// Responses cut short to fit the client's UDP limit, with TC set
+    pub responses_truncated: AtomicU64,
+    /// Actors started again after panicking (see `supervisor.rs`)
+    pub actor_restarts: AtomicU64,
+    /// Requests sent to another racing upstream of a hedged group (see `hedge.rs`)
+    pub upstream_hedges: AtomicU64,
+    /// Hedged lookups answered by an upstream other than the first
+    pub upstream_hedge_wins: AtomicU64,
+    /// Upstreams taken out of rotation by their circuit breaker (see `circuit.rs`)
+    pub upstream_circuits_opened: AtomicU64,
+    /// Upstreams put back into rotation after passing a probe
+    pub upstream_circuits_closed: AtomicU64,
+    /// Upstream lookups attempted again under their policy (see `retry.rs`)
+    pub upstream_retries: AtomicU64,
+    /// Upstream lookup attempts that got no answer in time
+    pub upstream_timeouts: AtomicU64,
+    /// Query packets received by each shard (`--shards`), to check the
+    /// kernel spreads clients evenly
+    pub shard_queries: Box<[AtomicU64]>,
+}
+
+impl Metrics {
+    /// Counters for a tenant served by `shards` sockets
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shard_queries: (0..shards).map(|_| AtomicU64::new(0)).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Current value of every counter, by name
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("queries", self.queries.load(Ordering::Relaxed)),
+            (
+                "responses_dropped",
+                self.responses_dropped.load(Ordering::Relaxed),
+            ),
+            (
+                "queries_malformed",
+                self.queries_malformed.load(Ordering::Relaxed),
+            ),
+            (
+                "duplicates_suppressed",
+                self.duplicates_suppressed.load(Ordering::Relaxed),
+            ),
+            (
+                "queries_refused",
+                self.queries_refused.load(Ordering::Relaxed),
+            ),
+            (
+                "queries_dropped",
+                self.queries_dropped.load(Ordering::Relaxed),
+            ),
+            (
+                "queries_over_quota",
+                self.queries_over_quota.load(Ordering::Relaxed),
+            ),
+            (
+                "queries_blocked",
+                self.queries_blocked.load(Ordering::Relaxed),
+            ),
+            (
+                "queries_redirected",
+                self.queries_redirected.load(Ordering::Relaxed),
+            ),
+            ("tunnel_alerts", self.tunnel_alerts.load(Ordering::Relaxed)),
+            (
+                "queries_rate_limited",
+                self.queries_rate_limited.load(Ordering::Relaxed),
+            ),
+            ("cache_hits", self.cache_hits.load(Ordering::Relaxed)),
+            ("cache_misses", self.cache_misses.load(Ordering::Relaxed)),
+            (
+                "cache_prefetches",
+                self.cache_prefetches.load(Ordering::Relaxed),
+            ),
+            (
+                "cache_evictions",
+                self.cache_evictions.load(Ordering::Relaxed),
+            ),
+            (
+                "shared_cache_hits",
+                self.shared_cache_hits.load(Ordering::Relaxed),
+            ),
+            (
+                "shared_cache_errors",
+                self.shared_cache_errors.load(Ordering::Relaxed),
+            ),
+            ("policy_errors", self.policy_errors.load(Ordering::Relaxed)),
+            (
+                "queries_coalesced",
+                self.queries_coalesced.load(Ordering::Relaxed),
+            ),
+            (
+                "responses_truncated",
+                self.responses_truncated.load(Ordering::Relaxed),
+            ),
+            (
+                "actor_restarts",
+                self.actor_restarts.load(Ordering::Relaxed),
+            ),
+            (
+                "upstream_hedges",
+                self.upstream_hedges.load(Ordering::Relaxed),
+            ),
+            (
+                "upstream_hedge_wins",
+                self.upstream_hedge_wins.load(Ordering::Relaxed),
+            ),
+            (
+                "upstream_circuits_opened",
+                self.upstream_circuits_opened.load(Ordering::Relaxed),
+            ),
+            (
+                "upstream_circuits_closed",
+                self.upstream_circuits_closed.load(Ordering::Relaxed),
+            ),
+            (
+                "upstream_retries",
+                self.upstream_retries.load(Ordering::Relaxed),
+            ),
+            (
+                "upstream_timeouts",
+                self.upstream_timeouts.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+
+    /// Queries received by each shard, when there is more than one
+    pub fn shard_snapshot(&self) -> Vec<u64> {
+        if self.shard_queries.len() < 2 {
+            return Vec::new();
+        }
+        self.shard_queries
+            .iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// Add one to a counter
+pub fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let metrics = Metrics::new(1);
+        incr(&metrics.responses_dropped);
+        incr(&metrics.responses_dropped);
+
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.contains(&("queries", 0)));
+        assert!(snapshot.contains(&("responses_dropped", 2)));
+        assert!(metrics.shard_snapshot().is_empty());
+
+        let metrics = Metrics::new(2);
+        incr(&metrics.shard_queries[1]);
+        assert_eq!(metrics.shard_snapshot(), [0, 1]);
+    }
+}
@@ -0,0 +1,312 @@
+//! Binary-safe domain names
+//!
+//! A DNS label is up to 63 arbitrary bytes; nothing requires it to be UTF-8
+//! or even printable. Names are kept as `String`s in the master-file
+//! presentation format (RFC 1035 §5.1) so that every byte survives the trip
+//! parse → resolve → encode: a literal dot or backslash inside a label is
+//! written `\.` / `\\`, and bytes outside printable ASCII as `\DDD` (decimal).
+//! The query actor hands names to hickory as raw labels (see `name_to_labels`).
+//!
+//! For logs, `DisplayName` shows internationalized (`xn--`) names in their
+//! Unicode form as well and warns when a label mixes scripts.
+//!
+//! Busy servers see the same few names over and over. `NameInterner` hands
+//! out one shared, lowercased `Arc<str>` per name, so the in-flight query,
+//! its duplicate-suppression entry and its query log event all point at a
+//! single allocation instead of each copying the name.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use unicode_script::{Script, UnicodeScript};
+
+/// Render a label's raw bytes in presentation format
+pub fn escape_label(bytes: &[u8]) -> String {
+    let mut label = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'.' | b'\\' => {
+                label.push('\\');
+                label.push(byte as char);
+            }
+            0x21..=0x7e => label.push(byte as char),
+            _ => label.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+    label
+}
+
+/// `name` with the case of each letter chosen at random (0x20 randomization).
+/// Servers echo the question as sent, so a forged response also has to guess
+/// the case of every letter.
+pub fn randomize_case(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphabetic() && rand::random::<bool>() {
+                (c as u8 ^ 0x20) as char
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Split a presentation-format name into raw label bytes. Unescaped dots
+/// separate labels; empty labels (from a trailing dot, or the root ".") are
+/// skipped. A backslash not followed by a valid escape is taken literally.
+pub fn name_to_labels(name: &str) -> Vec<Vec<u8>> {
+    let bytes = name.as_bytes();
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => match decimal_escape(&bytes[i + 1..]) {
+                Some(byte) => {
+                    label.push(byte);
+                    i += 4;
+                }
+                None if i + 1 < bytes.len() => {
+                    label.push(bytes[i + 1]);
+                    i += 2;
+                }
+                None => {
+                    label.push(b'\\');
+                    i += 1;
+                }
+            },
+            b'.' => {
+                if !label.is_empty() {
+                    labels.push(std::mem::take(&mut label));
+                }
+                i += 1;
+            }
+            byte => {
+                label.push(byte);
+                i += 1;
+            }
+        }
+    }
+    if !label.is_empty() {
+        labels.push(label);
+    }
+    labels
+}
+
+/// The byte value of a `DDD` escape body, if `rest` starts with one
+fn decimal_escape(rest: &[u8]) -> Option<u8> {
+    let digits = rest.get(..3)?;
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let value = digits
+        .iter()
+        .fold(0u16, |acc, &d| acc * 10 + u16::from(d - b'0'));
+    u8::try_from(value).ok()
+}
+
+/// A name as shown in logs and the live query view: punycode names get their
+/// Unicode form alongside, e.g. `xn--bcher-kva.de (bücher.de)`, and labels
+/// mixing scripts (a homograph-spoofing trick such as a Cyrillic "а" in
+/// "аpple") are flagged
+pub struct DisplayName<'a>(pub &'a str);
+
+impl fmt::Display for DisplayName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let Some(unicode) = unicode_form(self.0) else {
+            return Ok(());
+        };
+        write!(f, " ({}", unicode)?;
+        if let Some(scripts) = mixed_scripts(&unicode) {
+            write!(f, ", mixed scripts: {}", scripts.join("+"))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The Unicode form of a name with punycode labels, if it has any
+fn unicode_form(name: &str) -> Option<String> {
+    let has_punycode = name.split('.').any(|label| {
+        label
+            .get(..4)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("xn--"))
+    });
+    if !has_punycode {
+        return None;
+    }
+    match idna::domain_to_unicode(name) {
+        (unicode, Ok(())) if unicode != name => Some(unicode),
+        _ => None,
+    }
+}
+
+/// The scripts used by the first label that mixes several, if any.
+/// Han, Hiragana, Katakana, Hangul and Bopomofo are legitimately written
+/// together and count as one.
+fn mixed_scripts(name: &str) -> Option<Vec<&'static str>> {
+    name.split('.').find_map(|label| {
+        let mut scripts: Vec<&'static str> = Vec::new();
+        for c in label.chars() {
+            let script = match c.script() {
+                Script::Common | Script::Inherited | Script::Unknown => continue,
+                Script::Han
+                | Script::Hiragana
+                | Script::Katakana
+                | Script::Hangul
+                | Script::Bopomofo => "CJK",
+                other => other.full_name(),
+            };
+            if !scripts.contains(&script) {
+                scripts.push(script);
+            }
+        }
+        (scripts.len() > 1).then_some(scripts)
+    })
+}
+
+/// Names a `NameInterner` holds before it starts evicting
+pub const INTERN_CAPACITY: usize = 16384;
+
+/// Shares one allocation per (lowercased) name. When the table fills up,
+/// names nothing else holds any more are dropped; if most are still in use
+/// the whole table is cleared, since the names in use stay valid regardless
+/// and the hot ones are interned again on their next query.
+#[derive(Debug)]
+pub struct NameInterner {
+    names: Mutex<HashSet<Arc<str>>>,
+    capacity: usize,
+}
+
+impl Default for NameInterner {
+    fn default() -> Self {
+        Self::new(INTERN_CAPACITY)
+    }
+}
+
+impl NameInterner {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            names: Mutex::new(HashSet::new()),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// The shared, lowercased form of a name
+    pub fn intern(&self, name: &str) -> Arc<str> {
+        let lowered;
+        let name = if name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            lowered = name.to_ascii_lowercase();
+            lowered.as_str()
+        } else {
+            name
+        };
+
+        // The table is only a cache, so a poisoned lock is still usable
+        let mut names = self.names.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(shared) = names.get(name) {
+            return Arc::clone(shared);
+        }
+        if names.len() >= self.capacity {
+            names.retain(|shared| Arc::strong_count(shared) > 1);
+            if names.len() >= self.capacity / 2 {
+                names.clear();
+            }
+        }
+        let shared: Arc<str> = Arc::from(name);
+        names.insert(Arc::clone(&shared));
+        shared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_shares_and_evicts() {
+        let interner = NameInterner::new(4);
+        let first = interner.intern("Example.COM");
+        let again = interner.intern("example.com");
+        assert_eq!(&*first, "example.com");
+        assert!(Arc::ptr_eq(&first, &again));
+
+        // Filling the table drops the names no one holds any more
+        drop(again);
+        for name in ["a.test", "b.test", "c.test"] {
+            interner.intern(name);
+        }
+        assert_eq!(interner.names.lock().unwrap().len(), 4);
+        interner.intern("d.test");
+        assert_eq!(interner.names.lock().unwrap().len(), 2);
+        assert!(Arc::ptr_eq(&first, &interner.intern("example.com")));
+    }
+
+    #[test]
+    fn test_randomize_case() {
+        let name = "abcdefghijklmnopqrstuvwxyz.abcdefghijklmnopqrstuvwxyz\\.example";
+        let randomized = randomize_case(name);
+        assert_ne!(randomized, name);
+        assert_eq!(randomized.to_ascii_lowercase(), name);
+        assert_eq!(randomize_case("123.\\065"), "123.\\065");
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label(b"_dmarc"), "_dmarc");
+        assert_eq!(escape_label(b"a.b\\c"), "a\\.b\\\\c");
+        assert_eq!(escape_label(b"sp ace\xff\x00"), "sp\\032ace\\255\\000");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let raw: Vec<Vec<u8>> = vec![
+            b"_sip".to_vec(),
+            b"_tcp".to_vec(),
+            b"a.b".to_vec(),
+            vec![0, 0xc0, b'\\', b' '],
+            b"com".to_vec(),
+        ];
+        let name = raw
+            .iter()
+            .map(|label| escape_label(label))
+            .collect::<Vec<_>>()
+            .join(".");
+        assert_eq!(name_to_labels(&name), raw);
+    }
+
+    #[test]
+    fn test_name_to_labels() {
+        assert_eq!(
+            name_to_labels("example.com."),
+            vec![b"example".to_vec(), b"com".to_vec()]
+        );
+        assert!(name_to_labels(".").is_empty());
+        assert!(name_to_labels("").is_empty());
+        // \256 is out of range, so the backslash only escapes the '2'
+        assert_eq!(name_to_labels("a\\256"), vec![b"a256".to_vec()]);
+    }
+
+    #[test]
+    fn test_display_name() {
+        assert_eq!(DisplayName("example.com").to_string(), "example.com");
+        assert_eq!(
+            DisplayName("xn--bcher-kva.de").to_string(),
+            "xn--bcher-kva.de (bücher.de)"
+        );
+        // Japanese mixes Han and Hiragana legitimately
+        assert_eq!(
+            DisplayName("xn--wgv71a119e.jp").to_string(),
+            "xn--wgv71a119e.jp (日本語.jp)"
+        );
+        // Cyrillic "а" followed by Latin "pple"
+        assert_eq!(
+            DisplayName("xn--pple-43d.com").to_string(),
+            "xn--pple-43d.com (аpple.com, mixed scripts: Cyrillic+Latin)"
+        );
+        // Invalid punycode is shown as is
+        assert_eq!(DisplayName("xn--.com").to_string(), "xn--.com");
+    }
+}
@@ -1,13 +1,100 @@
+use std::cell::Cell;
+
 use nom::{
     self,
     bytes::complete::take,
-    number::complete::{be_u16, be_u8},
-    IResult,
+    error::{ErrorKind, ParseError},
+    number::complete::{be_u16, be_u32, be_u8},
 };
 
-use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion};
+use crate::errors::PacketError;
+use crate::names::escape_label;
+use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion, DnsResourceRecord};
+use crate::registry::{Opcode, Rcode, RecordClass, RecordType};
+use crate::response_builder::encode_name;
 // use tracing::debug;
 
+/// Result of the DNS message parsers
+pub type IResult<I, O> = nom::IResult<I, O, PacketError>;
+
+/// Most labels decompressed from one message, across all of its names
+pub const MAX_LABELS: usize = 16384;
+/// Most units of work (labels, compression pointers and records) spent on
+/// one message; what bounds the CPU a crafted packet can cost
+pub const MAX_STEPS: usize = 65536;
+/// Longest RDATA accepted in a resource record
+pub const MAX_RDATA_LEN: u16 = 16384;
+/// Longest name on the wire, including length bytes (RFC 1035 §2.3.4)
+const MAX_NAME_LEN: usize = 255;
+/// Smallest question on the wire: the root name, QTYPE and QCLASS
+const MIN_QUESTION_LEN: usize = 5;
+/// Smallest resource record on the wire: the root name, TYPE, CLASS, TTL
+/// and RDLENGTH
+const MIN_RECORD_LEN: usize = 11;
+
+impl<I> ParseError<I> for PacketError {
+    fn from_error_kind(_input: I, kind: ErrorKind) -> Self {
+        PacketError::Invalid(kind)
+    }
+
+    fn append(_input: I, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+fn fail<T>(error: PacketError) -> Result<T, nom::Err<PacketError>> {
+    Err(nom::Err::Failure(error))
+}
+
+/// What is left of the work allowed for one message
+struct Budget {
+    labels: Cell<usize>,
+    steps: Cell<usize>,
+}
+
+impl Budget {
+    fn new() -> Self {
+        Budget {
+            labels: Cell::new(MAX_LABELS),
+            steps: Cell::new(MAX_STEPS),
+        }
+    }
+
+    fn step(&self) -> Result<(), nom::Err<PacketError>> {
+        let Some(left) = self.steps.get().checked_sub(1) else {
+            return fail(PacketError::TooComplex);
+        };
+        self.steps.set(left);
+        Ok(())
+    }
+
+    fn label(&self) -> Result<(), nom::Err<PacketError>> {
+        let Some(left) = self.labels.get().checked_sub(1) else {
+            return fail(PacketError::TooManyLabels(MAX_LABELS));
+        };
+        self.labels.set(left);
+        Ok(())
+    }
+}
+
+/// Reject a section count that cannot be honest: `count` entries of at least
+/// `min_len` bytes each have to fit in what is left of the message
+fn check_count(
+    section: &'static str,
+    count: u16,
+    min_len: usize,
+    remaining: &[u8],
+) -> Result<(), nom::Err<PacketError>> {
+    if count as usize * min_len > remaining.len() {
+        return fail(PacketError::CountTooLarge {
+            section,
+            count,
+            remaining: remaining.len(),
+        });
+    }
+    Ok(())
+}
+
 pub fn parse_dns_packet_header(input: &[u8]) -> IResult<&[u8], DnsPacketHeader> {
     let (input, id) = be_u16(input)?;
     // take 1 bit for qr, 4 bits for opcode, 1 bit for aa,
@@ -24,7 +111,7 @@ pub fn parse_dns_packet_header(input: &[u8]) -> IResult<&[u8], DnsPacketHeader>
         // qr (Query/Response): (flags & 0x8000) != 0
         qr: (flags & 0x8000) != 0,
         // opcode: bits 11-14
-        opcode: ((flags & 0x7800) >> 11) as u8,
+        opcode: Opcode(((flags & 0x7800) >> 11) as u8),
         // aa (Authoritative Answer): bit 10
         aa: (flags & 0x0400) != 0,
         // tc (Truncated): bit 9
@@ -36,7 +123,7 @@ pub fn parse_dns_packet_header(input: &[u8]) -> IResult<&[u8], DnsPacketHeader>
         // z (Reserved for future use): bits 4-6
         z: ((flags & 0x0070) >> 4) as u8,
         // rcode (Response Code): bits 0-3
-        rcode: (flags & 0x000F) as u8,
+        rcode: Rcode(flags & 0x000F),
         qdcount,
         ancount,
         nscount,
@@ -46,48 +133,86 @@ pub fn parse_dns_packet_header(input: &[u8]) -> IResult<&[u8], DnsPacketHeader>
     Ok((input, header))
 }
 
-/// Recursively parses a domain name, handling the DNS compression scheme.
+/// Text form of the root name
+pub const ROOT_NAME: &str = ".";
+
+/// Parses a domain name, handling the DNS compression scheme.
 /// where 'p: 'i: This constraint means lifetime 'p must outlive lifetime 'i.
 /// This ensures that the full packet reference remains valid for at least
 /// as long as the input slice reference.
-fn parse_name_recursive<'p, 'i>(
+fn parse_labels<'p, 'i>(
     full_packet: &'p [u8],
     input: &'i [u8],
+    budget: &Budget,
 ) -> IResult<&'i [u8], Vec<String>>
 where
     'p: 'i,
 {
-    let (i, length) = be_u8(input)?;
-
-    match length {
-        l if (l & 0b1100_0000) == 0b1100_0000 => {
-            let (i, next_byte) = be_u8(i)?;
-            let offset = u16::from_be_bytes([l, next_byte]) & 0x3FFF;
-            let (_, labels) = parse_name_recursive(full_packet, &full_packet[offset as usize..])?;
-            Ok((i, labels))
-        }
-        0 => Ok((i, Vec::new())),
-        l if l <= 63 => {
-            let (i, label_bytes) = take(l as usize)(i)?;
-            let label = String::from_utf8_lossy(label_bytes).to_string();
-            let (i, mut next_labels) = parse_name_recursive(full_packet, i)?;
-            let mut labels = vec![label];
-            labels.append(&mut next_labels);
-            Ok((i, labels))
+    let mut labels = Vec::new();
+    // Wire length so far, counting the root label
+    let mut name_len = 1;
+    // Where the name ends in `input`, once a pointer has been followed
+    let mut end = None;
+    let mut cursor = input;
+
+    loop {
+        budget.step()?;
+        let (i, length) = be_u8(cursor)?;
+
+        match length {
+            l if (l & 0b1100_0000) == 0b1100_0000 => {
+                let (i, next_byte) = be_u8(i)?;
+                let offset = (u16::from_be_bytes([l, next_byte]) & 0x3FFF) as usize;
+                // Pointers may only refer to earlier data (RFC 1035 §4.1.4), which
+                // also keeps a crafted packet from sending the parser in circles
+                let position = cursor.as_ptr() as usize - full_packet.as_ptr() as usize;
+                if offset >= position {
+                    return fail(PacketError::BadPointer);
+                }
+                end.get_or_insert(i);
+                cursor = &full_packet[offset..];
+            }
+            0 => return Ok((end.unwrap_or(i), labels)),
+            l if l <= 63 => {
+                let (i, label_bytes) = take(l as usize)(i)?;
+                name_len += label_bytes.len() + 1;
+                if name_len > MAX_NAME_LEN {
+                    return fail(PacketError::NameTooLong);
+                }
+                budget.label()?;
+                // Keep every byte: escape anything that isn't plain printable ASCII
+                labels.push(escape_label(label_bytes));
+                cursor = i;
+            }
+            _ => return fail(PacketError::Invalid(ErrorKind::Verify)),
         }
-        _ => Err(nom::Err::Failure(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Verify,
-        ))),
     }
 }
 
 /// Public-facing parser for a domain name.
-fn parse_domain_name<'p, 'i>(full_packet: &'p [u8], input: &'i [u8]) -> IResult<&'i [u8], String>
+pub fn parse_domain_name<'p, 'i>(
+    full_packet: &'p [u8],
+    input: &'i [u8],
+) -> IResult<&'i [u8], String>
+where
+    'p: 'i,
+{
+    parse_name(full_packet, input, &Budget::new())
+}
+
+fn parse_name<'p, 'i>(
+    full_packet: &'p [u8],
+    input: &'i [u8],
+    budget: &Budget,
+) -> IResult<&'i [u8], String>
 where
     'p: 'i,
 {
-    let (i, labels) = parse_name_recursive(full_packet, input)?;
+    let (i, labels) = parse_labels(full_packet, input, budget)?;
+    // The root name has no labels at all; spell it "." rather than ""
+    if labels.is_empty() {
+        return Ok((i, ROOT_NAME.to_string()));
+    }
     Ok((i, labels.join(".")))
 }
 
@@ -95,11 +220,12 @@ where
 fn parse_dns_question<'p, 'i>(
     full_packet: &'p [u8],
     input: &'i [u8],
+    budget: &Budget,
 ) -> IResult<&'i [u8], DnsQuestion>
 where
     'p: 'i,
 {
-    let (input, name) = parse_domain_name(full_packet, input)?;
+    let (input, name) = parse_name(full_packet, input, budget)?;
     let (input, qtype) = be_u16(input)?;
     let (input, qclass) = be_u16(input)?;
 
@@ -107,35 +233,405 @@ where
         input,
         DnsQuestion {
             name,
-            qtype,
-            qclass,
+            qtype: RecordType(qtype),
+            qclass: RecordClass(qclass),
         },
     ))
 }
 
-// Parse a complete DNS packet
+/// Parse a resource record, requires the full packet for compression. Names
+/// inside the RDATA of the types that may compress them (RFC 3597 §4) are
+/// decompressed, so the RDATA can be read without the packet it came in.
+fn parse_resource_record<'p, 'i>(
+    full_packet: &'p [u8],
+    input: &'i [u8],
+    budget: &Budget,
+) -> IResult<&'i [u8], DnsResourceRecord>
+where
+    'p: 'i,
+{
+    budget.step()?;
+    let (input, name) = parse_name(full_packet, input, budget)?;
+    let (input, rtype) = be_u16(input).map(|(input, rtype)| (input, RecordType(rtype)))?;
+    let (input, rclass) = be_u16(input).map(|(input, rclass)| (input, RecordClass(rclass)))?;
+    let (input, ttl) = be_u32(input)?;
+    let (input, rdlength) = be_u16(input)?;
+    if rdlength > MAX_RDATA_LEN {
+        return fail(PacketError::RdataTooLong(rdlength));
+    }
+    let (input, rdata) = take(rdlength as usize)(input)?;
+
+    let rdata = match rtype {
+        RecordType::NS
+        | RecordType::MD
+        | RecordType::MF
+        | RecordType::CNAME
+        | RecordType::MB
+        | RecordType::MG
+        | RecordType::MR
+        | RecordType::PTR => decompress_rdata(full_packet, rdata, 0, 1, budget)?,
+        RecordType::MINFO | RecordType::RP => decompress_rdata(full_packet, rdata, 0, 2, budget)?,
+        // A preference or subtype ahead of the name
+        RecordType::MX | RecordType::AFSDB | RecordType::RT | RecordType::KX => {
+            decompress_rdata(full_packet, rdata, 2, 1, budget)?
+        }
+        RecordType::PX => decompress_rdata(full_packet, rdata, 2, 2, budget)?,
+        // Priority, weight and port ahead of the target
+        RecordType::SRV => decompress_rdata(full_packet, rdata, 6, 1, budget)?,
+        // Order and preference, then flags, services and regexp as
+        // character strings ahead of the replacement
+        RecordType::NAPTR => {
+            let (mut rest, _) = take(4usize)(rdata)?;
+            for _ in 0..3 {
+                let (after, len) = be_u8(rest)?;
+                (rest, _) = take(len)(after)?;
+            }
+            decompress_rdata(full_packet, rdata, rdata.len() - rest.len(), 1, budget)?
+        }
+        RecordType::SOA => {
+            let (rest, mname) = parse_name(full_packet, rdata, budget)?;
+            let (rest, rname) = parse_name(full_packet, rest, budget)?;
+            // serial, refresh, retry, expire and minimum
+            let (_, counters) = take(20usize)(rest)?;
+            let mut data = encode_name(&mname);
+            data.extend(encode_name(&rname));
+            data.extend_from_slice(counters);
+            data
+        }
+        _ => rdata.to_vec(),
+    };
+
+    Ok((
+        input,
+        DnsResourceRecord::new(name, rtype, rclass, ttl, rdata),
+    ))
+}
+
+/// Copy RDATA made of `fixed` bytes followed by `names` possibly compressed
+/// names, with the names written out in full
+fn decompress_rdata(
+    full_packet: &[u8],
+    rdata: &[u8],
+    fixed: usize,
+    names: usize,
+    budget: &Budget,
+) -> Result<Vec<u8>, nom::Err<PacketError>> {
+    let (mut rest, prefix) = take(fixed)(rdata)?;
+    let mut data = prefix.to_vec();
+    for _ in 0..names {
+        let (after, name) = parse_name(full_packet, rest, budget)?;
+        data.extend(encode_name(&name));
+        rest = after;
+    }
+    data.extend_from_slice(rest);
+    Ok(data)
+}
+
+/// Parse the `count` records of one section
+fn parse_records<'a>(
+    section: &'static str,
+    count: u16,
+    full_packet: &'a [u8],
+    mut input: &'a [u8],
+    budget: &Budget,
+) -> IResult<&'a [u8], Vec<DnsResourceRecord>> {
+    check_count(section, count, MIN_RECORD_LEN, input)?;
+    let mut records = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (i, record) = parse_resource_record(full_packet, input, budget)?;
+        records.push(record);
+        input = i;
+    }
+    Ok((input, records))
+}
+
+/// Parse a complete DNS packet: the header and all four sections, whether a
+/// client's query or another server's response
 pub fn parse_dns_packet(input: &[u8]) -> IResult<&[u8], DnsPacket> {
     // Keep a reference to the start of the packet for handling compression offsets.
     let full_packet = input;
+    let budget = Budget::new();
 
     // Parse the DNS packet header
     let (mut remaining_input, header) = parse_dns_packet_header(full_packet)?;
+    check_count(
+        "question",
+        header.qdcount,
+        MIN_QUESTION_LEN,
+        remaining_input,
+    )?;
 
     // To avoid complex lifetime issues with nom's `count` combinator and older
     // versions of the library, we can simply loop and call our parser manually.
     let mut questions = Vec::with_capacity(header.qdcount as usize);
     for _ in 0..header.qdcount {
-        let (i, question) = parse_dns_question(full_packet, remaining_input)?;
+        let (i, question) = parse_dns_question(full_packet, remaining_input, &budget)?;
         questions.push(question);
         remaining_input = i;
     }
 
-    // Here we would continue parsing the answers, authorities, and additionals.
+    let (remaining_input, answers) = parse_records(
+        "answer",
+        header.ancount,
+        full_packet,
+        remaining_input,
+        &budget,
+    )?;
+    let (remaining_input, authorities) = parse_records(
+        "authority",
+        header.nscount,
+        full_packet,
+        remaining_input,
+        &budget,
+    )?;
+    let (remaining_input, additionals) = parse_records(
+        "additional",
+        header.arcount,
+        full_packet,
+        remaining_input,
+        &budget,
+    )?;
+
     let packet = DnsPacket {
         header,
         questions,
-        answers: Vec::new(), // Placeholder for answer parsing
+        answers,
+        authorities,
+        additionals,
     };
 
     Ok((remaining_input, packet))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::RecordType;
+
+    #[test]
+    fn test_parse_dns_packet_decompresses_rdata() {
+        let mut message = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        // Question: example.com A IN, at offset 12
+        message.extend(encode_name("example.com"));
+        message.extend([0, 1, 0, 1]);
+        // Answer: example.com CNAME www.example.com, both compressed
+        message.extend([0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        message.extend([3, b'w', b'w', b'w', 0xc0, 12]);
+
+        let (rest, packet) = parse_dns_packet(&message).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(packet.questions[0].qtype, RecordType::A);
+        let answer = &packet.answers[0];
+        assert_eq!(answer.name, "example.com");
+        assert_eq!(answer.rtype, RecordType::CNAME);
+        assert_eq!(answer.rdata, encode_name("www.example.com"));
+    }
+
+    #[test]
+    fn test_parse_every_section() {
+        let mut message = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 1, 0, 2];
+        // Question: _sip._udp.example.com SRV IN, at offset 12
+        message.extend(encode_name("_sip._udp.example.com"));
+        message.extend([0, 33, 0, 1]);
+        // Answer: priority 10, weight 5, port 5060, target sip.example.com
+        message.extend([0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 12]);
+        message.extend([0, 10, 0, 5, 0x13, 0xc4, 3, b's', b'i', b'p', 0xc0, 22]);
+        // Authority: example.com NS ns1.example.com
+        message.extend([0xc0, 22, 0, 2, 0, 1, 0, 0, 0, 60, 0, 6]);
+        message.extend([3, b'n', b's', b'1', 0xc0, 22]);
+        // Additional: glue for the name server, then an OPT record
+        message.extend([3, b'n', b's', b'1', 0xc0, 22, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        message.extend([192, 0, 2, 53]);
+        message.extend([0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0, 0, 0]);
+
+        let (rest, packet) = parse_dns_packet(&message).unwrap();
+        assert!(rest.is_empty());
+        let mut srv = vec![0, 10, 0, 5, 0x13, 0xc4];
+        srv.extend(encode_name("sip.example.com"));
+        assert_eq!(packet.answers[0].rdata, srv);
+        assert_eq!(packet.authorities[0].rdata, encode_name("ns1.example.com"));
+        assert_eq!(packet.additionals.len(), 2);
+        assert_eq!(packet.additionals[0].name, "ns1.example.com");
+        assert_eq!(packet.additionals[0].rdata, [192, 0, 2, 53]);
+        assert_eq!(packet.additionals[1].rtype, RecordType::OPT);
+
+        // A NAPTR replacement is decompressed past the character strings
+        let mut naptr = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        naptr.extend(encode_name("example.com"));
+        naptr.extend([0, 35, 0, 1]);
+        naptr.extend([0xc0, 12, 0, 35, 0, 1, 0, 0, 0, 60, 0, 22]);
+        naptr.extend([0, 100, 0, 10, 1, b'S', 7]);
+        naptr.extend(b"SIP+D2U");
+        naptr.extend([0, 4, b'_', b's', b'i', b'p', 0xc0, 12]);
+        let (_, packet) = parse_dns_packet(&naptr).unwrap();
+        let mut rdata = vec![0, 100, 0, 10, 1, b'S', 7];
+        rdata.extend(b"SIP+D2U");
+        rdata.push(0);
+        rdata.extend(encode_name("_sip.example.com"));
+        assert_eq!(packet.answers[0].rdata, rdata);
+        // Character strings running past the RDATA are refused
+        let len = naptr.len();
+        naptr[len - 16] = 60;
+        assert!(parse_dns_packet(&naptr).is_err());
+
+        // An additional section the message can't hold is refused
+        message[11] = 3;
+        assert!(matches!(
+            parse_error(&message),
+            PacketError::CountTooLarge {
+                section: "additional",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_unknown_types_pass_through() {
+        let mut message = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+        message.extend(encode_name("example.com"));
+        message.extend([0xff, 0, 0, 1]);
+        // TYPE65280, whose RDATA happens to look like a compression pointer
+        // (RFC 3597 §4: names in unknown RDATA aren't decompressed)
+        message.extend(encode_name("example.com"));
+        message.extend([0xff, 0, 0, 1, 0, 0, 0, 60, 0, 4, 0xc0, 12, 1, 2]);
+        // TLSA, and no RDATA at all
+        message.extend(encode_name("example.com"));
+        message.extend([0, 52, 0, 1, 0, 0, 0, 60, 0, 0]);
+
+        let (rest, packet) = parse_dns_packet(&message).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(packet.answers[0].rtype, RecordType(65280));
+        assert_eq!(packet.answers[0].rdata, [0xc0, 12, 1, 2]);
+        assert_eq!(packet.answers[1].rtype, RecordType::TLSA);
+        assert!(packet.answers[1].rdata.is_empty());
+
+        // And they are written out again byte for byte
+        let mut encoded = bytes::BytesMut::new();
+        crate::codec::encode_packet(&packet, &mut encoded).unwrap();
+        assert_eq!(encoded[..], message[..]);
+    }
+
+    #[test]
+    fn test_decode_encode_round_trip() {
+        use crate::codec::{decode_packet, encode_packet, DnsCodec};
+        use bytes::BytesMut;
+        use tokio_util::codec::Decoder;
+
+        let mut message = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 1, 0, 1];
+        message.extend(encode_name("example.com"));
+        message.extend([0, 1, 0, 1]);
+        message.extend(encode_name("example.com"));
+        message.extend([0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        message.extend(encode_name("example.com"));
+        message.extend([0, 2, 0, 1, 0, 0, 0, 60, 0, 17]);
+        message.extend(encode_name("ns1.example.com"));
+        message.extend([0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0, 0, 0]);
+
+        // Decoded straight from the slice, as the parser reads it
+        let mut packet = decode_packet(&message).unwrap().unwrap();
+        assert_eq!(packet.additionals[0].rtype, RecordType::OPT);
+        let mut encoded = BytesMut::new();
+        encode_packet(&packet, &mut encoded).unwrap();
+        assert_eq!(encoded[..], message[..]);
+
+        // The counts written are those of the sections, not the header
+        packet.header.ancount = 9;
+        packet.header.arcount = 0;
+        encoded.clear();
+        encode_packet(&packet, &mut encoded).unwrap();
+        assert_eq!(encoded[..], message[..]);
+
+        // Too short for a header is nothing yet, not an error
+        assert!(decode_packet(&message[..11]).unwrap().is_none());
+
+        // A stream decoder takes one message and leaves the next
+        let mut stream = BytesMut::from(&[&message[..], &message[..]].concat()[..]);
+        let first = DnsCodec::new().decode(&mut stream).unwrap().unwrap();
+        assert_eq!(stream[..], message[..]);
+        encoded.clear();
+        encode_packet(&first, &mut encoded).unwrap();
+        assert_eq!(encoded[..], message[..]);
+    }
+
+    #[test]
+    fn test_compression_pointers_must_point_back() {
+        // A question name pointing at itself
+        let mut message = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend([0xc0, 12, 0, 1, 0, 1]);
+        assert!(parse_dns_packet(&message).is_err());
+
+        // ...or past the end of the packet
+        message[13] = 0xff;
+        assert!(parse_dns_packet(&message).is_err());
+    }
+
+    fn parse_error(message: &[u8]) -> PacketError {
+        match parse_dns_packet(message) {
+            Err(nom::Err::Failure(e)) | Err(nom::Err::Error(e)) => e,
+            other => panic!("parsed: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_counts_must_fit_the_message() {
+        // 65535 answers claimed, with room for one at most
+        let mut message = vec![0, 1, 0x81, 0x80, 0, 0, 0xff, 0xff, 0, 0, 0, 0];
+        message.extend([0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 0]);
+        assert_eq!(
+            parse_error(&message),
+            PacketError::CountTooLarge {
+                section: "answer",
+                count: 65535,
+                remaining: 11
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        // A name of 128 one-byte labels is well over 255 bytes
+        let mut message = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend([1, b'a'].repeat(128));
+        message.extend([0, 0, 1, 0, 1]);
+        assert_eq!(parse_error(&message), PacketError::NameTooLong);
+
+        // Oversized RDATA is refused before it is read
+        let mut message = vec![0, 1, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0];
+        message.extend([0, 0, 16, 0, 1, 0, 0, 0, 60, 0x7f, 0xff]);
+        assert_eq!(parse_error(&message), PacketError::RdataTooLong(0x7fff));
+
+        // Every record repeats a long name by pointer, so a small message
+        // asks for far more labels than it carries
+        let records = 1200;
+        let mut message = vec![0, 1, 0x81, 0x80, 0, 1];
+        message.extend((records as u16).to_be_bytes());
+        message.extend([0, 0, 0, 0]);
+        message.extend([1, b'a'].repeat(60));
+        message.extend([0, 0, 1, 0, 1]);
+        for _ in 0..records {
+            message.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 0]);
+        }
+        assert_eq!(
+            parse_error(&message),
+            PacketError::TooManyLabels(MAX_LABELS)
+        );
+
+        // A chain of pointers carries no labels but still costs a step each.
+        // The chain sits in the RDATA of a record of unknown type, each
+        // pointer pointing at the one before and the first at the root name.
+        let chain: u16 = 1000;
+        let mut message = vec![0, 1, 0x81, 0x80, 0, 0, 0, 200, 0, 0, 0, 0];
+        message.extend([0, 0xff, 0, 0, 1, 0, 0, 0, 60]);
+        message.extend((2 * chain).to_be_bytes());
+        for n in 0..chain {
+            let target = if n == 0 { 12 } else { 21 + 2 * n };
+            message.extend((0xc000 | target).to_be_bytes());
+        }
+        let last = 23 + 2 * (chain - 1);
+        for _ in 0..199 {
+            message.extend((0xc000 | last).to_be_bytes());
+            message.extend([0, 1, 0, 1, 0, 0, 0, 60, 0, 0]);
+        }
+        assert_eq!(parse_error(&message), PacketError::TooComplex);
+    }
+}
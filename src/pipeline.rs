@@ -0,0 +1,669 @@
+//! The layers a question is answered through
+//!
+//! Each question of a query passes through a chain of middleware, outermost
+//! first. A layer either answers the question itself, or hands it on with
+//! `next.run(request)` and returns what the rest of the chain answers,
+//! changed or not. The built-in layers, in order:
+//!
+//! 1. logging of what was resolved, and of failures
+//! 2. the Internet class filter, which refuses every other class but CHAOS,
+//!    whose questions go straight to the server's own names
+//! 3. local records, and signed zones answered in full
+//! 4. zones served from files and local reverse zones, which have the last
+//!    word on the names in them
+//! 5. the server's own names (`--server-name`, `--ddr`)
+//! 6. the client's group (`--group`): its rewrites, and its upstreams for
+//!    questions no route claims
+//! 7. the blocklist, the group's own if it has one
+//! 8. routing (`--route`): blackholed names are answered NXDOMAIN
+//! 9. the cache, and joining a lookup of the same question already under way
+//! 10. resolution, upstream or recursive, which always answers
+//!
+//! Layers added with `DnsServerBuilder::middleware` go right after logging,
+//! in the order added, so they see every question before the server answers
+//! it and every answer before it is logged: the place for rate limiting,
+//! rewriting, or answering names of their own.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+
+use futures::future::BoxFuture;
+use tracing::{debug, error};
+
+use crate::actors::inflight_actor::MAX_INFLIGHT;
+use crate::actors::messages::{CacheKey, Cached, Joined, Resolution};
+use crate::blocklist::Blocked;
+use crate::geoip::ClientLocation;
+use crate::groups::{ClientGroup, GroupBlocking};
+use crate::handlers::query_handler::QueryActorHandle;
+use crate::local_records::{normalize_name, LocalRecord, RecordData};
+use crate::metrics;
+use crate::names::DisplayName;
+use crate::processor::QueryContext;
+use crate::protocol::{DnsQuestion, DnsResourceRecord};
+use crate::query_log::Disposition;
+use crate::registry::{Rcode, RecordClass, RecordType};
+use crate::reverse::ReverseZone;
+use crate::routing::{Route, RouteAction};
+
+/// A stage of answering a question
+pub trait Middleware: Send + Sync {
+    /// Answer `request`, or pass it on with `next.run(request)`
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer>;
+}
+
+/// A question to answer, and what it was asked with
+pub struct Request<'a> {
+    pub question: &'a DnsQuestion,
+    pub client: SocketAddr,
+    /// Whether the client desires recursion (RD)
+    pub rd: bool,
+    /// Whether the client takes DNSSEC records (the OPT record's DO bit)
+    pub dnssec_ok: bool,
+    pub(crate) location: &'a ClientLocation,
+    pub(crate) ctx: &'a QueryContext,
+    /// The `--route` the question takes, once looked up
+    route: OnceLock<Option<&'a Route>>,
+}
+
+impl<'a> Request<'a> {
+    pub(crate) fn new(
+        ctx: &'a QueryContext,
+        question: &'a DnsQuestion,
+        client: SocketAddr,
+        rd: bool,
+        dnssec_ok: bool,
+        location: &'a ClientLocation,
+    ) -> Self {
+        Request {
+            question,
+            client,
+            rd,
+            dnssec_ok,
+            location,
+            ctx,
+            route: OnceLock::new(),
+        }
+    }
+
+    /// The route tagging the question, if any
+    fn route(&self) -> Option<&'a Route> {
+        *self.route.get_or_init(|| self.tagged_route())
+    }
+
+    fn tagged_route(&self) -> Option<&'a Route> {
+        let ctx = self.ctx;
+        ctx.router
+            .route(self.client.ip(), &self.question.name, self.question.qtype)
+    }
+
+    /// Resolve the question through `route`, unless its route is settled
+    pub(crate) fn set_route(&self, route: &'a Route) {
+        let _ = self.route.set(Some(route));
+    }
+
+    /// Resolve the question through `route` when no `--route` tags it
+    pub(crate) fn default_route(&self, route: &'a Route) {
+        self.route
+            .get_or_init(|| self.tagged_route().or(Some(route)));
+    }
+
+    /// The client's group, if it is in one
+    fn group(&self) -> Option<&'a ClientGroup> {
+        self.ctx.groups.find(self.client.ip())
+    }
+
+    /// Who resolves the question: its route's upstreams, or the tenant's
+    fn resolver(&self) -> &'a QueryActorHandle {
+        match self.route().map(|route| &route.action) {
+            Some(RouteAction::Resolve(handle)) => handle,
+            _ => &self.ctx.query_handle,
+        }
+    }
+
+    /// How the blocklist answers the question, if it holds it. A group's own
+    /// blocklist stands in for the tenant's, with the tenant's allowlist
+    /// still letting names through.
+    pub(crate) fn blocked(&self) -> Option<Blocked> {
+        let question = self.question;
+        let blocklist = &self.ctx.blocklist;
+        match self.group().map(|group| &group.blocking) {
+            None | Some(GroupBlocking::Tenant) => {
+                blocklist.check(&question.name, question.qtype, question.qclass)
+            }
+            Some(GroupBlocking::Own(own)) => own
+                .check(&question.name, question.qtype, question.qclass)
+                .filter(|_| !blocklist.allowlist().allows(&question.name)),
+            Some(GroupBlocking::Off) => None,
+        }
+    }
+
+    /// The question's cache entry: routes may resolve the same name differently
+    fn cache_key(&self) -> CacheKey {
+        CacheKey {
+            name: self.ctx.names.intern(&self.question.name),
+            qtype: self.question.qtype,
+            qclass: self.question.qclass,
+            route: self.route().map(|route| Arc::clone(&route.name)),
+        }
+    }
+}
+
+/// What answering one question adds to the response
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub answers: Vec<DnsResourceRecord>,
+    pub authority: Vec<DnsResourceRecord>,
+    pub additional: Vec<DnsResourceRecord>,
+    pub rcode: Rcode,
+    pub authoritative: bool,
+    pub disposition: Disposition,
+}
+
+impl Answer {
+    pub fn new(disposition: Disposition) -> Self {
+        Answer {
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+            rcode: Rcode::NOERROR,
+            authoritative: false,
+            disposition,
+        }
+    }
+
+    pub fn with_rcode(mut self, rcode: Rcode) -> Self {
+        self.rcode = rcode;
+        self
+    }
+
+    /// A resolution's answer; failures are marked failed whatever the source
+    fn resolved(resolution: Resolution, source: Disposition) -> Self {
+        match resolution {
+            Resolution::Records(records) => {
+                let mut answer = Answer::new(source);
+                answer.answers = records;
+                answer
+            }
+            // The SOA lets clients cache the negative answer
+            Resolution::Negative { rcode, soa } => {
+                let mut answer = Answer::new(source).with_rcode(rcode);
+                answer.authority.extend(soa);
+                answer
+            }
+            Resolution::Failed(rcode) => Answer::new(Disposition::Failed).with_rcode(rcode),
+        }
+    }
+
+    /// The resolution the answer stands for, as cached
+    fn resolution(&self) -> Resolution {
+        if self.disposition == Disposition::Failed {
+            Resolution::Failed(self.rcode)
+        } else if self.rcode != Rcode::NOERROR || self.answers.is_empty() {
+            Resolution::Negative {
+                rcode: self.rcode,
+                soa: self.authority.first().cloned(),
+            }
+        } else {
+            Resolution::Records(self.answers.clone())
+        }
+    }
+}
+
+/// The rest of the chain after a layer
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    /// Answer `request` through the rest of the chain
+    pub fn run(self, request: &'a Request<'a>) -> BoxFuture<'a, Answer> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.handle(request, Next { layers }),
+            // Resolution answers everything, so only a chain cut short ends here
+            None => {
+                Box::pin(async { Answer::new(Disposition::Failed).with_rcode(Rcode::SERVFAIL) })
+            }
+        }
+    }
+}
+
+/// The chain every question of a tenant (or view) is answered through;
+/// cheap to clone
+#[derive(Clone)]
+pub struct Pipeline {
+    layers: Arc<[Arc<dyn Middleware>]>,
+}
+
+impl Pipeline {
+    /// The built-in layers, with `custom` ones after logging
+    pub fn new(custom: &[Arc<dyn Middleware>]) -> Self {
+        let mut layers: Vec<Arc<dyn Middleware>> = vec![Arc::new(Logging)];
+        layers.extend(custom.iter().cloned());
+        layers.extend([
+            Arc::new(ClassFilter) as Arc<dyn Middleware>,
+            Arc::new(LocalAnswers),
+            Arc::new(ZoneAuthority),
+            Arc::new(ServerIdentity),
+            Arc::new(Grouping),
+            Arc::new(Blocking),
+            Arc::new(Routing),
+            Arc::new(Caching),
+            Arc::new(Resolving),
+        ]);
+        Pipeline {
+            layers: layers.into(),
+        }
+    }
+
+    pub fn answer<'a>(&'a self, request: &'a Request<'a>) -> BoxFuture<'a, Answer> {
+        Next {
+            layers: &self.layers,
+        }
+        .run(request)
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new(&[])
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("layers", &self.layers.len())
+            .finish()
+    }
+}
+
+/// Logs what resolution made of a question; local answers are logged where
+/// they are made
+struct Logging;
+
+impl Middleware for Logging {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        Box::pin(async move {
+            let answer = next.run(request).await;
+            let question = request.question;
+            match answer.disposition {
+                Disposition::Failed => error!(
+                    "Could not resolve {}: Lookup failed ({})",
+                    DisplayName(&question.name),
+                    answer.rcode
+                ),
+                Disposition::Forwarded | Disposition::Cached if answer.rcode == Rcode::NOERROR => {
+                    debug!(
+                        "Resolved {} {} ({} records)",
+                        DisplayName(&question.name),
+                        question.qtype,
+                        answer.answers.len()
+                    )
+                }
+                Disposition::Forwarded | Disposition::Cached => debug!(
+                    "No records for {} ({})",
+                    DisplayName(&question.name),
+                    answer.rcode
+                ),
+                Disposition::Local | Disposition::Blocked => {}
+            }
+            answer
+        })
+    }
+}
+
+/// Only the Internet class is resolved; CHAOS questions are about the
+/// server itself, and nothing but its identity answers them
+struct ClassFilter;
+
+impl Middleware for ClassFilter {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let question = request.question;
+        if question.qclass == RecordClass::CH {
+            return ServerIdentity.handle(request, next);
+        }
+        if question.qclass != RecordClass::IN {
+            debug!(
+                "Refusing {} query for {}",
+                question.qclass,
+                DisplayName(&question.name)
+            );
+            return Box::pin(async { Answer::new(Disposition::Local).with_rcode(Rcode::REFUSED) });
+        }
+        next.run(request)
+    }
+}
+
+/// Locally defined records take precedence over upstream resolution, and
+/// signed zones are answered in full
+struct LocalAnswers;
+
+impl Middleware for LocalAnswers {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let QueryContext {
+            local_records,
+            reverse,
+            dnssec,
+            ..
+        } = request.ctx;
+        let question = request.question;
+
+        let mut local_answers =
+            local_records.lookup(&question.name, question.qtype, request.location);
+        // Reverse lookups of local addresses get the names holding them
+        if local_answers.is_empty() && question.qtype == RecordType::PTR && reverse.synthesize {
+            local_answers = local_records.synthesize_ptr(&question.name);
+        }
+        let signed_zone = dnssec.find(&question.name);
+        if !local_answers.is_empty() {
+            let mut answer = Answer::new(Disposition::Local);
+            for record in local_answers {
+                debug!(
+                    "Answered {} locally -> {}",
+                    DisplayName(&question.name),
+                    record
+                );
+                answer.answers.push(record.to_resource_record());
+                // Mail exchangers held locally come with their
+                // addresses, saving the client a lookup per exchange
+                if let RecordData::Mx { exchange, .. } = &record.data {
+                    for qtype in [RecordType::A, RecordType::AAAA] {
+                        answer.additional.extend(
+                            local_records
+                                .lookup(exchange, qtype, request.location)
+                                .iter()
+                                .map(LocalRecord::to_resource_record),
+                        );
+                    }
+                }
+            }
+            // Answers from a signed zone are authoritative, and come
+            // with their signatures when the client asks for them
+            if let Some(zone) = signed_zone {
+                answer.authoritative = true;
+                if request.dnssec_ok {
+                    let signatures = zone.sign_answers(&answer.answers);
+                    answer.answers.extend(signatures);
+                }
+            }
+            return Box::pin(async { answer });
+        }
+
+        // A signed zone is answered in full: its keys and SOA at the
+        // apex, and NODATA with a signed denial everywhere else
+        if let Some(zone) = signed_zone {
+            debug!(
+                "No local {} records for {} in signed zone {}",
+                question.qtype,
+                DisplayName(&question.name),
+                zone.zone
+            );
+            let types = local_records.types(&question.name);
+            let signed = zone.answer(&question.name, question.qtype, &types, request.dnssec_ok);
+            let mut answer = Answer::new(Disposition::Local);
+            answer.authoritative = true;
+            answer.answers = signed.answers;
+            answer.authority = signed.authority;
+            return Box::pin(async { answer });
+        }
+        next.run(request)
+    }
+}
+
+/// Zones served from files and local reverse zones have the last word on
+/// the names in them: what the local records don't answer doesn't exist,
+/// and the SOA lets clients cache that (RFC 2308). Lookups of private
+/// addresses don't go upstream.
+struct ZoneAuthority;
+
+impl Middleware for ZoneAuthority {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let QueryContext {
+            local_records,
+            reverse,
+            zones,
+            ..
+        } = request.ctx;
+        let question = request.question;
+
+        let local_zone = [
+            zones.find(&question.name).cloned(),
+            reverse.find(&question.name).map(ReverseZone::soa),
+        ]
+        .into_iter()
+        .flatten()
+        .max_by_key(|soa| soa.zone.len());
+        let Some(soa) = local_zone else {
+            return next.run(request);
+        };
+        debug!(
+            "No local {} records for {} in zone {}",
+            question.qtype,
+            DisplayName(&question.name),
+            soa.zone
+        );
+        let ttl = soa.minimum;
+        let apex = normalize_name(&question.name) == soa.zone;
+        let mut answer = Answer::new(Disposition::Local);
+        answer.authoritative = true;
+        if apex && question.qtype == RecordType::SOA {
+            answer.answers.push(soa.to_resource_record(ttl));
+        } else {
+            if !apex && !local_records.exists(&question.name) {
+                answer.rcode = Rcode::NXDOMAIN;
+            }
+            answer.authority.push(soa.to_resource_record(ttl));
+        }
+        Box::pin(async { answer })
+    }
+}
+
+/// The server's own names; a CHAOS question not about them is refused
+struct ServerIdentity;
+
+impl Middleware for ServerIdentity {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let question = request.question;
+        let identity = &request.ctx.identity;
+        let records = if question.qclass == RecordClass::CH {
+            identity.chaos(&question.name, question.qtype)
+        } else {
+            identity.answer(&question.name, question.qtype)
+        };
+        let Some(records) = records else {
+            if question.qclass == RecordClass::CH {
+                debug!(
+                    "Refusing {} query for {}",
+                    question.qclass,
+                    DisplayName(&question.name)
+                );
+                return Box::pin(async {
+                    Answer::new(Disposition::Local).with_rcode(Rcode::REFUSED)
+                });
+            }
+            return next.run(request);
+        };
+        debug!(
+            "Answered {} for the server itself ({} records)",
+            DisplayName(&question.name),
+            records.len()
+        );
+        let mut answer = Answer::new(Disposition::Local);
+        answer.answers = records;
+        Box::pin(async { answer })
+    }
+}
+
+/// A group's clients get its rewrites, and its upstreams resolve their
+/// questions unless a route claims them
+struct Grouping;
+
+impl Middleware for Grouping {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let Some(group) = request.group() else {
+            return next.run(request);
+        };
+        let question = request.question;
+        let rewrites = group
+            .rewrites
+            .lookup(&question.name, question.qtype, request.location);
+        if !rewrites.is_empty() {
+            let mut answer = Answer::new(Disposition::Local);
+            for record in rewrites {
+                debug!(
+                    "Rewrote {} for group {} -> {}",
+                    DisplayName(&question.name),
+                    group.name,
+                    record
+                );
+                answer.answers.push(record.to_resource_record());
+            }
+            return Box::pin(async { answer });
+        }
+        if let Some(route) = &group.route {
+            request.default_route(route);
+        }
+        next.run(request)
+    }
+}
+
+/// Names on the blocklist are answered NXDOMAIN or with the unspecified
+/// address instead of resolved (see `Request::blocked`)
+struct Blocking;
+
+impl Middleware for Blocking {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let Some(blocked) = request.blocked() else {
+            return next.run(request);
+        };
+        debug!("Blocked {}", DisplayName(&request.question.name));
+        metrics::incr(&request.ctx.metrics.queries_blocked);
+        let mut answer = Answer::new(Disposition::Blocked);
+        match blocked {
+            Blocked::NxDomain => answer.rcode = Rcode::NXDOMAIN,
+            Blocked::Answer(records) => answer.answers = records,
+        }
+        Box::pin(async { answer })
+    }
+}
+
+/// Tagged queries go where their route says: nowhere for a blackhole, or
+/// on to the route's own upstreams
+struct Routing;
+
+impl Middleware for Routing {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let Some(route) = request.route() else {
+            return next.run(request);
+        };
+        let name = DisplayName(&request.question.name);
+        match route.action {
+            RouteAction::Blackhole => {
+                debug!("Blackholed {} (route {})", name, route.name);
+                Box::pin(async { Answer::new(Disposition::Local).with_rcode(Rcode::NXDOMAIN) })
+            }
+            RouteAction::Resolve(_) => {
+                debug!("Routing {} to route {}", name, route.name);
+                next.run(request)
+            }
+        }
+    }
+}
+
+/// Answers still in the cache are served from there; of several concurrent
+/// queries for a question only one goes on to be resolved, and the others
+/// wait for its answer
+struct Caching;
+
+impl Middleware for Caching {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        Box::pin(async move {
+            let QueryContext {
+                metrics,
+                cache,
+                inflight,
+                ttl_limits,
+                ..
+            } = request.ctx;
+            let question = request.question;
+            let cache_key = request.cache_key();
+
+            match cache.get(cache_key.clone()).await {
+                Some(Cached {
+                    resolution,
+                    prefetch,
+                }) => {
+                    metrics::incr(&metrics.cache_hits);
+                    // A popular answer about to expire is looked up again while it
+                    // is still served, so its clients don't wait when it does
+                    if prefetch {
+                        debug!(
+                            "Prefetching {} {}",
+                            DisplayName(&question.name),
+                            question.qtype
+                        );
+                        metrics::incr(&metrics.cache_prefetches);
+                        let (handle, cache, ttl_limits) =
+                            (request.resolver().clone(), cache.clone(), *ttl_limits);
+                        let qtype = question.qtype;
+                        tokio::spawn(async move {
+                            let resolution = ttl_limits
+                                .apply(handle.resolve(Arc::clone(&cache_key.name), qtype).await);
+                            cache.insert(cache_key, resolution).await;
+                        });
+                    }
+                    Answer::resolved(resolution, Disposition::Cached)
+                }
+                // Without RD the client only gets what is already known
+                None if !request.rd => {
+                    debug!(
+                        "Not recursing for {} (RD not set)",
+                        DisplayName(&question.name)
+                    );
+                    metrics::incr(&metrics.cache_misses);
+                    Answer::new(Disposition::Local).with_rcode(Rcode::REFUSED)
+                }
+                None => {
+                    metrics::incr(&metrics.cache_misses);
+                    let shared = match inflight.join(cache_key.clone()).await {
+                        Joined::Leader => None,
+                        Joined::Follower(outcome) => tokio::time::timeout(MAX_INFLIGHT, outcome)
+                            .await
+                            .ok()
+                            .and_then(Result::ok),
+                    };
+                    if let Some(resolution) = shared {
+                        metrics::incr(&metrics.queries_coalesced);
+                        return Answer::resolved(resolution, Disposition::Forwarded);
+                    }
+                    let answer = next.run(request).await;
+                    // Cached first, so nothing arriving in between goes
+                    // upstream again
+                    let resolution = answer.resolution();
+                    cache.insert(cache_key.clone(), resolution.clone()).await;
+                    inflight.finish(cache_key, resolution).await;
+                    answer
+                }
+            }
+        })
+    }
+}
+
+/// Resolves the question upstream, or recursively, within the TTL limits
+struct Resolving;
+
+impl Middleware for Resolving {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, _next: Next<'a>) -> BoxFuture<'a, Answer> {
+        Box::pin(async move {
+            let ctx = request.ctx;
+            let name = ctx.names.intern(&request.question.name);
+            let resolution = request
+                .resolver()
+                .resolve(name, request.question.qtype)
+                .await;
+            Answer::resolved(ctx.ttl_limits.apply(resolution), Disposition::Forwarded)
+        })
+    }
+}
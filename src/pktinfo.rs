@@ -0,0 +1,226 @@
+//! Replying from the address a query was sent to
+//!
+//! A socket bound to a wildcard address (`0.0.0.0`, `::`) receives on every
+//! address of the host, but the kernel picks the source of what it sends by
+//! route, which on a host with several addresses need not be the one the
+//! client asked. Clients drop such responses. With IP_PKTINFO (and
+//! IPV6_RECVPKTINFO) each datagram comes with the address it was sent to,
+//! and handing that address back on send makes it the response's source.
+
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::ptr;
+
+use socket2::SockAddr;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+/// Room for one pktinfo control message of either family, suitably aligned
+type ControlBuffer = [u64; 8];
+
+/// Ask for the destination address of each datagram. Only wildcard sockets
+/// need it; returns whether it was turned on.
+pub fn enable(sock: &UdpSocket) -> io::Result<bool> {
+    let local = sock.local_addr()?;
+    if !local.ip().is_unspecified() {
+        return Ok(false);
+    }
+    let (level, name) = match local {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+    };
+    let on: libc::c_int = 1;
+    // SAFETY: the option value is a c_int of the size given
+    let result = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            ptr::from_ref(&on).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(true)
+}
+
+/// Receive a datagram, its source and, if pktinfo is on, its destination
+pub async fn recv_from(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    sock.async_io(Interest::READABLE, || recvmsg(sock.as_raw_fd(), buf))
+        .await
+}
+
+/// Send a datagram with `local` as its source
+pub async fn send_from(
+    sock: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    local: IpAddr,
+) -> io::Result<usize> {
+    sock.async_io(Interest::WRITABLE, || {
+        sendmsg(sock.as_raw_fd(), buf, addr, local)
+    })
+    .await
+}
+
+fn recvmsg(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    let mut control: ControlBuffer = [0; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // SAFETY: an all-zero header is valid
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+
+    // SAFETY: the header points at the buffers above and the address storage
+    // socket2 hands us, all of which outlive the call
+    let (len, source) = unsafe {
+        SockAddr::try_init(|storage, storage_len| {
+            msg.msg_name = storage.cast();
+            msg.msg_namelen = *storage_len;
+            let len = libc::recvmsg(fd, &mut msg, 0);
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            *storage_len = msg.msg_namelen;
+            Ok(len as usize)
+        })?
+    };
+    let source = source.as_socket().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "datagram from a non-IP address")
+    })?;
+
+    let mut destination = None;
+    // SAFETY: the kernel filled in the control messages the header describes
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info: libc::in_pktinfo = ptr::read_unaligned(data.cast());
+                    destination = Some(IpAddr::V4(Ipv4Addr::from(
+                        info.ipi_addr.s_addr.to_ne_bytes(),
+                    )));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info: libc::in6_pktinfo = ptr::read_unaligned(data.cast());
+                    destination = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((len, source, destination))
+}
+
+fn sendmsg(fd: RawFd, buf: &[u8], addr: SocketAddr, local: IpAddr) -> io::Result<usize> {
+    let addr = SockAddr::from(addr);
+    let mut control: ControlBuffer = [0; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr().cast_mut().cast(),
+        iov_len: buf.len(),
+    };
+    // SAFETY: an all-zero header is valid
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = addr.as_ptr().cast_mut().cast();
+    msg.msg_namelen = addr.len();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+
+    // SAFETY: the control buffer has room for either message, and the
+    // header only points at locals that outlive the call
+    let sent = unsafe {
+        match local {
+            IpAddr::V4(ip) => {
+                let info = libc::in_pktinfo {
+                    ipi_ifindex: 0,
+                    ipi_spec_dst: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(ip.octets()),
+                    },
+                    ipi_addr: libc::in_addr { s_addr: 0 },
+                };
+                write_cmsg(&mut msg, libc::IPPROTO_IP, libc::IP_PKTINFO, info);
+            }
+            IpAddr::V6(ip) => {
+                let info = libc::in6_pktinfo {
+                    ipi6_addr: libc::in6_addr {
+                        s6_addr: ip.octets(),
+                    },
+                    ipi6_ifindex: 0,
+                };
+                write_cmsg(&mut msg, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info);
+            }
+        }
+        libc::sendmsg(fd, &msg, 0)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Make `value` the header's only control message
+///
+/// # Safety
+///
+/// `msg.msg_control` must point at an aligned buffer with room for it.
+unsafe fn write_cmsg<T>(msg: &mut libc::msghdr, level: libc::c_int, kind: libc::c_int, value: T) {
+    let size = mem::size_of::<T>() as u32;
+    msg.msg_controllen = libc::CMSG_SPACE(size) as _;
+    let cmsg = libc::CMSG_FIRSTHDR(msg);
+    (*cmsg).cmsg_level = level;
+    (*cmsg).cmsg_type = kind;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+    ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast(), value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reply_leaves_from_queried_address() {
+        let server = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        assert!(enable(&server).unwrap());
+        let port = server.local_addr().unwrap().port();
+
+        // All of 127/8 is local, but replies to 127.0.0.1 would otherwise
+        // come from 127.0.0.1
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let queried: SocketAddr = ([127, 0, 0, 2], port).into();
+        client.send_to(b"query", queried).await.unwrap();
+
+        let mut buf = [0; 16];
+        let (len, source, destination) = recv_from(&server, &mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"query");
+        assert_eq!(source, client.local_addr().unwrap());
+        assert_eq!(destination, Some(queried.ip()));
+
+        send_from(&server, b"answer", source, queried.ip())
+            .await
+            .unwrap();
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"answer");
+        assert_eq!(from, queried);
+    }
+
+    #[tokio::test]
+    async fn test_only_wildcard_sockets() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(!enable(&sock).unwrap());
+    }
+}
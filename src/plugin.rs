@@ -0,0 +1,338 @@
+//! Query policies compiled to WebAssembly (`--plugin`, `wasm` feature)
+//!
+//! A plugin is a WebAssembly module (`.wasm`, or `.wat` text) deciding on
+//! questions and answers as a `--script` does (see `policy.rs`), but written
+//! in any language that compiles to WebAssembly and run sandboxed: it sees
+//! only what it is handed, and has no files, network or clock. Version 1 of
+//! the interface has the module export:
+//!
+//! - `memory`, its linear memory
+//! - `dns_abi_version() -> i32`, returning 1
+//! - `alloc(len: i32) -> i32`, the address of `len` bytes the server may
+//!   write the call's input to
+//! - `on_query(ptr: i32, len: i32) -> i64`, `on_response(ptr: i32, len: i32)
+//!   -> i64` or both, called with the address and length of their input
+//!
+//! The input is JSON: for `on_query` the question,
+//! `{"name":"www.example.com","qtype":"A","qclass":"IN","client":"192.0.2.7","rd":true}`,
+//! and for `on_response` the question and the answer,
+//! `{"query":{...},"response":{"rcode":"NOERROR","answers":["A 192.0.2.1"],"source":"forwarded"}}`.
+//! A hook returns the address of its verdict in the upper 32 bits and its
+//! length in the lower, or 0 for no verdict. The verdict is JSON too:
+//! `{"action":"block"}`, `{"action":"refuse"}`,
+//! `{"action":"answer","data":["192.0.2.1"],"ttl":60}` or, from `on_query`,
+//! `{"action":"route","name":"office"}`.
+//!
+//! The module may import `dns.log(ptr: i32, len: i32)` to write a UTF-8
+//! message to the server's log. Instances are kept and reused from one call
+//! to the next, so a plugin should free what it allocates for a call (or
+//! start its allocator over in `alloc`) before returning. Each has at most
+//! 16 MiB of memory, and each call a budget of fuel that stops a runaway
+//! loop; a call that runs out of either, or fails any other way, fails as
+//! any policy does, and its instance is thrown away for a fresh one.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::Context;
+use tracing::info;
+use wasmtime::{
+    Caller, Config, Engine, Extern, InstancePre, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc,
+};
+
+use crate::policy::{Policy, Query, Response, Verdict};
+
+/// The version of the interface plugins are written to
+const ABI_VERSION: i32 = 1;
+
+/// Fuel (roughly, WebAssembly instructions) one call may burn
+const FUEL: u64 = 10_000_000;
+
+/// Most memory one instance may take
+const MAX_MEMORY: usize = 16 << 20;
+
+/// Most instances kept between calls; as many as are called at once are made
+const IDLE_INSTANCES: usize = 16;
+
+/// What an instance's store holds
+struct Host {
+    limits: StoreLimits,
+}
+
+/// A compiled plugin
+pub struct Plugin {
+    path: PathBuf,
+    engine: Engine,
+    instance: InstancePre<Host>,
+    /// Instances ready for a call
+    idle: Mutex<Vec<Instance>>,
+    on_query: bool,
+    on_response: bool,
+}
+
+/// An instance of a plugin, with the exports a call needs
+struct Instance {
+    store: Store<Host>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_query: Option<TypedFunc<(i32, i32), i64>>,
+    on_response: Option<TypedFunc<(i32, i32), i64>>,
+}
+
+impl Plugin {
+    /// Compile the plugin at `path`, checking that it exports the interface
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::from_file(&engine, path)
+            .with_context(|| format!("could not load plugin {}", path.display()))?;
+
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap(
+            "dns",
+            "log",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> anyhow::Result<()> {
+                let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+                    anyhow::bail!("no memory export");
+                };
+                let message = read(memory.data(&caller), ptr as u32, len as u32)?;
+                info!("Plugin: {}", String::from_utf8_lossy(message));
+                Ok(())
+            },
+        )?;
+        let instance = linker
+            .instantiate_pre(&module)
+            .with_context(|| format!("plugin {}", path.display()))?;
+
+        let plugin = Plugin {
+            path: path.to_path_buf(),
+            engine,
+            on_query: module.get_export("on_query").is_some(),
+            on_response: module.get_export("on_response").is_some(),
+            instance,
+            idle: Mutex::default(),
+        };
+        plugin
+            .check()
+            .with_context(|| format!("plugin {}", plugin.path.display()))?;
+        Ok(plugin)
+    }
+
+    /// Check the exports a call needs, and the interface's version
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.on_query || self.on_response,
+            "exports neither on_query nor on_response"
+        );
+        let mut instance = self.instantiate()?;
+        let version = self
+            .instance
+            .instantiate(&mut instance.store)?
+            .get_typed_func::<(), i32>(&mut instance.store, "dns_abi_version")?
+            .call(&mut instance.store, ())?;
+        anyhow::ensure!(
+            version == ABI_VERSION,
+            "written to interface version {}, not {}",
+            version,
+            ABI_VERSION
+        );
+        Ok(())
+    }
+
+    /// A fresh instance
+    fn instantiate(&self) -> anyhow::Result<Instance> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build();
+        let mut store = Store::new(&self.engine, Host { limits });
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(FUEL)?;
+        let instance = self.instance.instantiate(&mut store)?;
+        let hook = |store: &mut Store<Host>, exported: bool, name: &str| {
+            exported
+                .then(|| instance.get_typed_func::<(i32, i32), i64>(store, name))
+                .transpose()
+        };
+        Ok(Instance {
+            memory: instance
+                .get_memory(&mut store, "memory")
+                .context("no memory export")?,
+            alloc: instance.get_typed_func::<i32, i32>(&mut store, "alloc")?,
+            on_query: hook(&mut store, self.on_query, "on_query")?,
+            on_response: hook(&mut store, self.on_response, "on_response")?,
+            store,
+        })
+    }
+
+    /// Call `hook` with `input` in an idle instance, or a fresh one, for its
+    /// verdict
+    fn call(&self, hook: &str, input: &[u8]) -> anyhow::Result<Option<Verdict>> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let mut instance = match idle {
+            Some(instance) => instance,
+            None => self.instantiate()?,
+        };
+        // An instance whose call failed may be left in any state, so only
+        // one that succeeded is kept
+        let verdict = instance.call(hook, input)?;
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < IDLE_INSTANCES {
+            idle.push(instance);
+        }
+        Ok(verdict)
+    }
+}
+
+impl Instance {
+    fn call(&mut self, hook: &str, input: &[u8]) -> anyhow::Result<Option<Verdict>> {
+        let function = match hook {
+            "on_query" => self.on_query.clone(),
+            _ => self.on_response.clone(),
+        }
+        .with_context(|| format!("no {} export", hook))?;
+        let store = &mut self.store;
+        store.set_fuel(FUEL)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = self.alloc.call(&mut *store, len)?;
+        self.memory
+            .write(&mut *store, ptr as u32 as usize, input)
+            .context("alloc returned memory out of bounds")?;
+        let verdict = function.call(&mut *store, (ptr, len))?;
+        if verdict == 0 {
+            return Ok(None);
+        }
+        let json = read(
+            self.memory.data(&*store),
+            (verdict >> 32) as u32,
+            verdict as u32,
+        )?;
+        serde_json::from_slice(json)
+            .map(Some)
+            .with_context(|| format!("invalid verdict {:?}", String::from_utf8_lossy(json)))
+    }
+}
+
+/// The `len` bytes at `ptr` in a plugin's memory
+fn read(memory: &[u8], ptr: u32, len: u32) -> anyhow::Result<&[u8]> {
+    let start = ptr as usize;
+    memory
+        .get(start..start + len as usize)
+        .context("pointer out of bounds")
+}
+
+impl fmt::Display for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Plugin {}", self.path.display())
+    }
+}
+
+impl Policy for Plugin {
+    fn on_query(&self, query: &Query) -> anyhow::Result<Option<Verdict>> {
+        if !self.on_query {
+            return Ok(None);
+        }
+        self.call("on_query", &serde_json::to_vec(query)?)
+    }
+
+    fn on_response(&self, query: &Query, response: &Response) -> anyhow::Result<Option<Verdict>> {
+        if !self.on_response {
+            return Ok(None);
+        }
+        let input = serde_json::json!({ "query": query, "response": response });
+        self.call("on_response", &serde_json::to_vec(&input)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decides on the first letter of the name: `b` is blocked, `r`
+    /// refused, `x` gets a verdict that isn't one and `l` loops forever
+    const PLUGIN: &str = r#"
+        (module
+          (import "dns" "log" (func $log (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"action\":\"block\"}")
+          (data (i32.const 32) "{\"action\":\"refuse\"}")
+          (data (i32.const 64) "nonsense")
+          (func (export "dns_abi_version") (result i32) (i32.const 1))
+          ;; One call at a time, so every input can go in the same place
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 1024))
+          (func (export "on_query") (param $ptr i32) (param $len i32) (result i64)
+            (local $first i32)
+            (call $log (local.get $ptr) (local.get $len))
+            ;; The name's first letter, after {"name":"
+            (local.set $first (i32.load8_u offset=9 (local.get $ptr)))
+            (if (i32.eq (local.get $first) (i32.const 98))
+              (then (return (i64.const 18))))
+            (if (i32.eq (local.get $first) (i32.const 114))
+              (then (return (i64.or (i64.shl (i64.const 32) (i64.const 32)) (i64.const 19)))))
+            (if (i32.eq (local.get $first) (i32.const 120))
+              (then (return (i64.or (i64.shl (i64.const 64) (i64.const 32)) (i64.const 8)))))
+            (if (i32.eq (local.get $first) (i32.const 108))
+              (then (loop $forever (br $forever))))
+            (i64.const 0)))
+    "#;
+
+    fn query(name: &str) -> Query {
+        Query {
+            name: name.to_string(),
+            qtype: "A".to_string(),
+            qclass: "IN".to_string(),
+            client: "192.0.2.7".to_string(),
+            rd: true,
+        }
+    }
+
+    #[test]
+    fn test_plugin_verdicts() {
+        let path = std::env::temp_dir().join(format!("dns-plugin-{}.wat", std::process::id()));
+        std::fs::write(&path, PLUGIN).unwrap();
+        let plugin = Plugin::load(&path).unwrap();
+
+        assert_eq!(plugin.on_query(&query("ads.example")).unwrap(), None);
+        assert_eq!(
+            plugin.on_query(&query("banners.example")).unwrap(),
+            Some(Verdict::Block)
+        );
+        assert_eq!(
+            plugin.on_query(&query("rogue.example")).unwrap(),
+            Some(Verdict::Refuse)
+        );
+        assert!(plugin.on_query(&query("x.example")).is_err());
+        assert!(plugin.on_query(&query("loop.example")).is_err());
+        // The instance of a failed call is replaced, and the others reused
+        for _ in 0..1000 {
+            assert_eq!(
+                plugin.on_query(&query("banners.example")).unwrap(),
+                Some(Verdict::Block)
+            );
+        }
+        assert_eq!(plugin.idle.lock().unwrap().len(), 1);
+        // Not exported
+        let response = Response {
+            rcode: "NOERROR".to_string(),
+            answers: Vec::new(),
+            source: "forwarded",
+        };
+        assert_eq!(
+            plugin
+                .on_response(&query("banners.example"), &response)
+                .unwrap(),
+            None
+        );
+
+        // A module that isn't a plugin is refused at load
+        std::fs::write(&path, "(module (memory (export \"memory\") 1))").unwrap();
+        assert!(Plugin::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+//! Policies deciding on questions and answers, from a `--script` or a
+//! `--plugin`
+//!
+//! A policy is asked twice about every question it sees: before the question
+//! is answered, with the question, and after, with the answer the server
+//! would give. Each time it may return a verdict or nothing, which lets the
+//! question go on as it would. Both kinds of policy see the same question
+//! and answer and give the same verdicts (see `script.rs` and `plugin.rs`
+//! for how). A policy that fails is logged and counted as `policy_errors`,
+//! and taken to have returned nothing. Policies are called on tokio's
+//! blocking threads, so one that runs long holds up only the question it is
+//! deciding on.
+
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::local_records::{LocalRecord, DEFAULT_LOCAL_TTL};
+use crate::metrics;
+use crate::names::DisplayName;
+use crate::pipeline::{Answer, Middleware, Next, Request};
+use crate::query_log::{answer_summary, Disposition};
+use crate::registry::Rcode;
+
+/// A question as policies see it
+#[derive(Debug, Clone, Serialize)]
+pub struct Query {
+    /// Lower-cased, without the trailing dot
+    pub name: String,
+    pub qtype: String,
+    pub qclass: String,
+    /// The client's address, IPv4 clients of a dual-stack socket as IPv4
+    pub client: String,
+    pub rd: bool,
+}
+
+impl Query {
+    fn new(request: &Request<'_>) -> Self {
+        let question = request.question;
+        Query {
+            name: question.name.to_lowercase(),
+            qtype: question.qtype.to_string(),
+            qclass: question.qclass.to_string(),
+            client: request.client.ip().to_canonical().to_string(),
+            rd: request.rd,
+        }
+    }
+}
+
+/// An answer as policies see it
+#[derive(Debug, Clone, Serialize)]
+pub struct Response {
+    pub rcode: String,
+    /// Each record as its type and data, `A 192.0.2.1` say
+    pub answers: Vec<String>,
+    /// Where the answer came from: `local`, `forwarded`, `cached`, `blocked`
+    /// or `failed`
+    pub source: &'static str,
+}
+
+impl Response {
+    fn new(answer: &Answer) -> Self {
+        Response {
+            rcode: answer.rcode.to_string(),
+            answers: answer.answers.iter().map(answer_summary).collect(),
+            source: answer.disposition.name(),
+        }
+    }
+}
+
+/// What a policy decided about a question
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Verdict {
+    /// Answer NXDOMAIN, counted as blocked
+    Block,
+    /// Answer REFUSED
+    Refuse,
+    /// Answer with records of the question's type holding this data,
+    /// written as for `--record`
+    Answer {
+        data: Vec<String>,
+        #[serde(default)]
+        ttl: Option<u32>,
+    },
+    /// Resolve through the upstreams of the route of this name; before the
+    /// question is answered only
+    Route { name: String },
+}
+
+/// A script or plugin, named for the log by its `Display`
+pub trait Policy: fmt::Display + Send + Sync {
+    /// The verdict on a question before it is answered
+    fn on_query(&self, query: &Query) -> anyhow::Result<Option<Verdict>>;
+
+    /// The verdict on the answer the server would give
+    fn on_response(&self, query: &Query, response: &Response) -> anyhow::Result<Option<Verdict>>;
+}
+
+/// The middleware layer asking a policy about each question
+pub struct PolicyLayer(pub Arc<dyn Policy>);
+
+impl PolicyLayer {
+    /// Call `hook` on the policy from a blocking thread
+    async fn ask<F>(&self, hook: F) -> anyhow::Result<Option<Verdict>>
+    where
+        F: FnOnce(&dyn Policy) -> anyhow::Result<Option<Verdict>> + Send + 'static,
+    {
+        let policy = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || hook(&*policy)).await?
+    }
+
+    /// The verdict, with failures logged and counted as none
+    fn verdict(
+        &self,
+        request: &Request<'_>,
+        hook: &str,
+        verdict: anyhow::Result<Option<Verdict>>,
+    ) -> Option<Verdict> {
+        verdict.unwrap_or_else(|e| {
+            warn!(
+                "{} {} failed for {}: {:#}",
+                self.0,
+                hook,
+                DisplayName(&request.question.name),
+                e
+            );
+            metrics::incr(&request.ctx.metrics.policy_errors);
+            None
+        })
+    }
+
+    /// The answer a verdict gives; none when it gives none, as when its data
+    /// doesn't make records
+    fn answer(
+        &self,
+        request: &Request<'_>,
+        verdict: Verdict,
+        source: Disposition,
+    ) -> Option<Answer> {
+        let question = request.question;
+        let name = DisplayName(&question.name);
+        match verdict {
+            Verdict::Block => {
+                debug!("{} blocked {}", self.0, name);
+                metrics::incr(&request.ctx.metrics.queries_blocked);
+                Some(Answer::new(Disposition::Blocked).with_rcode(Rcode::NXDOMAIN))
+            }
+            Verdict::Refuse => {
+                debug!("{} refused {}", self.0, name);
+                Some(Answer::new(Disposition::Local).with_rcode(Rcode::REFUSED))
+            }
+            Verdict::Answer { data, ttl } => {
+                let ttl = ttl.unwrap_or(DEFAULT_LOCAL_TTL);
+                let mut answer = Answer::new(source);
+                for data in data {
+                    let record = format!("{} {} {} {}", question.name, question.qtype, data, ttl);
+                    match record.parse::<LocalRecord>() {
+                        Ok(record) => answer.answers.push(record.to_resource_record()),
+                        Err(e) => {
+                            warn!(
+                                "{} answer {:?} for {} is invalid: {}",
+                                self.0, data, name, e
+                            );
+                            metrics::incr(&request.ctx.metrics.policy_errors);
+                            return None;
+                        }
+                    }
+                }
+                debug!(
+                    "{} answered {} ({} records)",
+                    self.0,
+                    name,
+                    answer.answers.len()
+                );
+                Some(answer)
+            }
+            Verdict::Route { name } => {
+                warn!(
+                    "{} can only route before a question is answered, not {}",
+                    self.0, name
+                );
+                metrics::incr(&request.ctx.metrics.policy_errors);
+                None
+            }
+        }
+    }
+}
+
+impl Middleware for PolicyLayer {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        Box::pin(async move {
+            let query = Query::new(request);
+            let asked = query.clone();
+            let verdict = self.ask(move |policy| policy.on_query(&asked)).await;
+            match self.verdict(request, "on_query", verdict) {
+                Some(Verdict::Route { name }) => match request.ctx.router.named(&name) {
+                    Some(route) => request.set_route(route),
+                    None => {
+                        warn!("{} routed to unknown route {}", self.0, name);
+                        metrics::incr(&request.ctx.metrics.policy_errors);
+                    }
+                },
+                Some(verdict) => {
+                    if let Some(answer) = self.answer(request, verdict, Disposition::Local) {
+                        return answer;
+                    }
+                }
+                None => {}
+            }
+
+            let answer = next.run(request).await;
+            let response = Response::new(&answer);
+            let verdict = self
+                .ask(move |policy| policy.on_response(&query, &response))
+                .await;
+            match self.verdict(request, "on_response", verdict) {
+                Some(verdict) => self
+                    .answer(request, verdict, answer.disposition)
+                    .unwrap_or(answer),
+                None => answer,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verdicts_from_json() {
+        let verdict = |json: &str| serde_json::from_str::<Verdict>(json);
+        assert_eq!(verdict(r#"{"action":"block"}"#).unwrap(), Verdict::Block);
+        assert_eq!(
+            verdict(r#"{"action":"answer","data":["192.0.2.1"]}"#).unwrap(),
+            Verdict::Answer {
+                data: vec!["192.0.2.1".to_string()],
+                ttl: None
+            }
+        );
+        assert_eq!(
+            verdict(r#"{"action":"route","name":"office"}"#).unwrap(),
+            Verdict::Route {
+                name: "office".to_string()
+            }
+        );
+        assert!(verdict(r#"{"action":"allow"}"#).is_err());
+        assert!(verdict(r#"{"action":"answer","data":"192.0.2.1"}"#).is_err());
+    }
+}
@@ -1,33 +1,221 @@
 use bytes::BytesMut;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::net::UdpSocket;
-use tokio_util::codec::{Decoder, Encoder};
+use futures::future::join_all;
+use std::time::{Duration, Instant, SystemTime};
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio::sync::mpsc;
 use tracing::{debug, error, info};
 
+use crate::acl::{ClientAcl, DenyAction};
+use crate::actors::messages::{Duplicate, QueryKey};
+use crate::blocklist::PublishedBlocklist;
+use crate::dnssec::SignedZones;
+use crate::edns::{Edns, EDNS_VERSION, MIN_UDP_PAYLOAD_SIZE, UDP_PAYLOAD_SIZE};
+use crate::geoip::{ClientLocation, Locator};
+use crate::groups::ClientGroups;
+use crate::handlers::cache_handler::CacheHandle;
+use crate::handlers::dedup_handler::DedupHandle;
+use crate::handlers::inflight_handler::InflightHandle;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::hostname::HostnamePolicy;
+use crate::identity::Identity;
+use crate::metrics::{self, Metrics};
+use crate::names::NameInterner;
+use crate::parsers::{parse_dns_packet, parse_dns_packet_header};
+use crate::pipeline::{Pipeline, Request};
+use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion};
+use crate::proxy::Proxy;
+use crate::query_log::{Disposition, QueryEvent, QueryLog};
+use crate::quota::ClientQuotas;
+use crate::registry::{Opcode, Rcode};
 use crate::response_builder::DnsResponseBuilder;
-use crate::{codec::DnsCodec, handlers::query_handler::QueryActorHandle};
+use crate::reverse::ReverseLookups;
+use crate::routing::Router;
+use crate::ttl::TtlLimits;
+use crate::udp::UdpSender;
+use crate::update::Primaries;
+use crate::zone_file::LocalZones;
+use crate::{codec, handlers::query_handler::QueryActorHandle};
+
+/// How long a query is remembered for duplicate suppression. Stub resolvers
+/// typically retransmit after one or two seconds.
+pub const DUPLICATE_WINDOW: Duration = Duration::from_secs(2);
+
+/// The header template every response is built from
+static RESPONSES: DnsResponseBuilder = DnsResponseBuilder::new();
+
+/// Handles to the shared subsystems every query task needs; cheap to clone
+#[derive(Clone, Debug)]
+pub struct QueryContext {
+    pub query_handle: QueryActorHandle,
+    pub local_records: LocalRecordsHandle,
+    pub query_log: QueryLog,
+    pub metrics: Arc<Metrics>,
+    pub dedup: DedupHandle,
+    /// Upstream answers kept until their TTLs run out, shared by the shards
+    pub cache: CacheHandle,
+    /// Upstream lookups under way, which concurrent queries for the same
+    /// question join; shared by the shards
+    pub inflight: InflightHandle,
+    /// Shares one copy of each hot name between the structures holding it
+    pub names: Arc<NameInterner>,
+    pub hostname_policy: HostnamePolicy,
+    /// The range upstream records' TTLs are kept in (`--min-ttl`, `--max-ttl`)
+    pub ttl_limits: TtlLimits,
+    /// Locates clients for location-tagged local records
+    pub locator: Arc<Locator>,
+    /// Who may query the tenant
+    pub acl: Arc<ClientAcl>,
+    /// How fast each client may query the tenant (`--client-quota`)
+    pub quotas: Option<Arc<ClientQuotas>>,
+    /// Which of the tenant's receive sockets the query came in on
+    pub shard: usize,
+    /// Relays queries upstream untouched instead of answering them (`--transparent`)
+    pub proxy: Option<Arc<Proxy>>,
+    /// Sends tagged queries to upstreams of their own (`--route`)
+    pub router: Arc<Router>,
+    /// Names answered NXDOMAIN or 0.0.0.0 instead of resolved (`--blocklist`),
+    /// and the exceptions (`--allow`)
+    pub blocklist: Arc<PublishedBlocklist>,
+    /// The server's own hostname and DDR records (`--server-name`, `--ddr`)
+    pub identity: Arc<Identity>,
+    /// Where dynamic updates are forwarded, by zone
+    pub primaries: Arc<Primaries>,
+    /// Zones served signed (`--dnssec-key`)
+    pub dnssec: Arc<SignedZones>,
+    /// Reverse zones answered locally, and PTR synthesis (`--reverse-zone`,
+    /// `--synthesize-ptr`)
+    pub reverse: Arc<ReverseLookups>,
+    /// Zones served from files, answered in full (`--zone-file`)
+    pub zones: Arc<LocalZones>,
+    /// Clients with policies of their own (`--group`)
+    pub groups: Arc<ClientGroups>,
+    /// The layers each question is answered through
+    pub pipeline: Pipeline,
+}
+
+/// Where a query's response goes: back over UDP, or down the TCP connection
+/// the query came in on; cheap to clone
+#[derive(Clone, Debug)]
+pub enum Responder {
+    Udp(UdpSender),
+    /// The connection's writer, which frames the responses in turn
+    Tcp(mpsc::Sender<Vec<u8>>),
+}
+
+impl Responder {
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            Responder::Udp(sock) => sock.send_to(buf, addr).await,
+            Responder::Tcp(connection) => match connection.send(buf.to_vec()).await {
+                Ok(()) => Ok(buf.len()),
+                Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+            },
+        }
+    }
+
+    /// The largest response the client takes: anything a TCP message holds,
+    /// or over UDP its EDNS payload size up to our own, and 512 bytes
+    /// without EDNS
+    fn limit(&self, edns: Option<&Edns>) -> u16 {
+        match (self, edns) {
+            (Responder::Tcp(_), _) => u16::MAX,
+            (Responder::Udp(_), Some(edns)) => edns.udp_payload_size.min(UDP_PAYLOAD_SIZE),
+            (Responder::Udp(_), None) => MIN_UDP_PAYLOAD_SIZE,
+        }
+    }
+}
 
 // Process DNS query in an asynchronous manner
 pub async fn process_dns_query(
     packet_data: Vec<u8>,
     addr: SocketAddr,
-    query_handle: QueryActorHandle,
-    sock: Arc<UdpSocket>,
+    ctx: QueryContext,
+    sock: Responder,
 ) {
-    let mut buf = [0; 1024];
-    // Create a BytesMut from the received data
-    let mut bytes_mut = BytesMut::from(&packet_data[..]);
+    let QueryContext {
+        query_log,
+        metrics,
+        dedup,
+        names,
+        hostname_policy,
+        locator,
+        acl,
+        quotas,
+        shard,
+        proxy,
+        primaries,
+        groups,
+        ..
+    } = &ctx;
+    let started = Instant::now();
 
-    debug!("Received {} bytes from {}", packet_data.len(), addr);
+    // Responses aimed at us (reflection noise or attack traffic) are never
+    // answered; check the QR bit before spending any effort parsing them
+    if packet_data.len() > 2 && packet_data[2] & 0x80 != 0 {
+        debug!("Dropping response packet (QR=1) from {}", addr);
+        metrics::incr(&metrics.responses_dropped);
+        return;
+    }
 
-    // Use the codec to decode the DNS packet
+    // Under --deny-action drop, clients the tenant doesn't serve get no
+    // answer at all
+    if acl.drops(addr.ip()) {
+        debug!("Dropping packet from {} (not an allowed client)", addr);
+        metrics::incr(&metrics.queries_dropped);
+        return;
+    }
+
+    // Clients over their quota are turned away unparsed, but for the header
+    // a REFUSED response is sent under
+    if let Some(quotas) = quotas.as_ref().filter(|quotas| !quotas.admit(addr.ip())) {
+        metrics::incr(&metrics.queries_over_quota);
+        debug!("Turning away packet from {} (over its quota)", addr);
+        if quotas.action == DenyAction::Refuse {
+            if let Ok((_, header)) = parse_dns_packet_header(&packet_data) {
+                send_header_only(&sock, addr, header, Rcode::REFUSED, acl.permits(addr.ip())).await;
+            }
+        }
+        return;
+    }
 
-    // Create a new DNS codec instance.
-    let mut codec = DnsCodec::new();
+    debug!("Received {} bytes from {}", packet_data.len(), addr);
 
-    // Use the codec to decode the DNS packet
-    match codec.decode(&mut bytes_mut) {
+    // Decode the DNS packet straight from the datagram
+    match codec::decode_packet(&packet_data) {
         Ok(Some(packet)) => {
+            metrics::incr(&metrics.queries);
+            if let Some(counter) = metrics.shard_queries.get(*shard) {
+                metrics::incr(counter);
+            }
+
+            // Retransmissions are answered by the original resolution
+            let dedup_key = packet.questions.first().map(|question| QueryKey {
+                client: addr,
+                id: packet.header.id,
+                name: names.intern(&question.name),
+                qtype: question.qtype,
+            });
+            if let Some(key) = &dedup_key {
+                match dedup.begin(key.clone()) {
+                    Duplicate::New => {}
+                    Duplicate::InFlight => {
+                        debug!("Duplicate query {} from {} still in flight", key.id, addr);
+                        metrics::incr(&metrics.duplicates_suppressed);
+                        return;
+                    }
+                    Duplicate::Answered(response) => {
+                        debug!(
+                            "Re-sending response to duplicate query {} from {}",
+                            key.id, addr
+                        );
+                        metrics::incr(&metrics.duplicates_suppressed);
+                        if let Err(e) = sock.send_to(&response, addr).await {
+                            error!("Failed to re-send DNS response to {}: {}", addr, e);
+                        }
+                        return;
+                    }
+                }
+            }
             debug!(
                 "Successfully decoded DNS packet from {}: {:?}",
                 addr, packet.header
@@ -37,26 +225,12 @@ pub async fn process_dns_query(
                 target: "dns_server::packet_details",
                 packet_id = packet.header.id,
                 query_response = if packet.header.qr { "Response" } else { "Query" },
-                opcode = packet.header.opcode, // This can be mapped to a string if needed
-                // opcode = match packet.header.opcode {
-                //     0 => "QUERY",
-                //     1 => "IQUERY",
-                //     2 => "STATUS",
-                //     _ => "RESERVED"
-                // },
+                opcode = %packet.header.opcode,
                 authoritative = packet.header.aa,
                 truncated = packet.header.tc,
                 recursion_desired = packet.header.rd,
                 recursion_available = packet.header.ra,
-                response_code = match packet.header.rcode {
-                    0 => "NOERROR",
-                    1 => "FORMERR",
-                    2 => "SERVFAIL",
-                    3 => "NXDOMAIN",
-                    4 => "NOTIMP",
-                    5 => "REFUSED",
-                    _ => "UNKNOWN"
-                },
+                response_code = %packet.header.rcode,
                 question_count = packet.header.qdcount,
                 answer_count = packet.header.ancount,
                 authority_count = packet.header.nscount,
@@ -64,6 +238,106 @@ pub async fn process_dns_query(
                 "DNS packet header parsed successfully"
             );
 
+            // In transparent mode an allowed client's query goes upstream as it came
+            // in, unless the blocklist holds one of its questions, and an update
+            // goes to its zone's primary (RFC 2136 §6)
+            let update_primary = match packet.questions.first() {
+                // The zone section takes the place of the question
+                Some(zone) if packet.header.opcode == Opcode::UPDATE => {
+                    primaries.primary_for(&zone.name)
+                }
+                _ => None,
+            };
+            let relay = match update_primary {
+                _ if !acl.permits(addr.ip()) => None,
+                Some(primary) => {
+                    debug!(
+                        "Forwarding update {} from {} to primary {}",
+                        packet.header.id, addr, primary
+                    );
+                    Some(Arc::new(Proxy::new(vec![primary.into()])))
+                }
+                // A blocked query is answered here, like any other
+                None if blocks_any(&ctx, &packet, addr) => None,
+                None => proxy.clone(),
+            };
+            if let Some(relay) = relay {
+                // A complete answer taken over TCP upstream must still fit what
+                // the client takes
+                let limit = match (&sock, Edns::from_packet(&packet)) {
+                    (Responder::Tcp(_), _) => u16::MAX,
+                    (Responder::Udp(_), Ok(Some(edns))) => edns.udp_payload_size,
+                    (Responder::Udp(_), _) => MIN_UDP_PAYLOAD_SIZE,
+                };
+                let relayed = match relay.forward(&packet_data, limit.into()).await {
+                    Ok(response) => Some((response, Disposition::Forwarded)),
+                    Err(e) => {
+                        error!(
+                            "Could not relay query {} from {}: {}",
+                            packet.header.id, addr, e
+                        );
+                        let mut servfail = RESPONSES
+                            .build_custom_response(&packet)
+                            .with_qr(true)
+                            .build();
+                        // Set after building, which answers other opcodes NOTIMP
+                        servfail.header.rcode = Rcode::SERVFAIL;
+                        let mut response_buf = BytesMut::new();
+                        match codec::encode_packet(&servfail, &mut response_buf) {
+                            Ok(()) => Some((response_buf.to_vec(), Disposition::Failed)),
+                            Err(e) => {
+                                error!("Failed to encode DNS response for {}: {}", addr, e);
+                                None
+                            }
+                        }
+                    }
+                };
+                let Some((response, disposition)) = relayed else {
+                    // Nothing was sent, so a retransmission is relayed afresh
+                    if let Some(key) = &dedup_key {
+                        dedup.forget(key);
+                    }
+                    return;
+                };
+
+                let logged = dedup_key
+                    .as_ref()
+                    .filter(|_| groups.logs(addr.ip(), disposition));
+                if let Some(key) = logged {
+                    let (rcode, answers) = match parse_dns_packet_header(&response) {
+                        Ok((_, header)) => (header.rcode, header.ancount.into()),
+                        Err(_) => (Rcode::SERVFAIL, 0),
+                    };
+                    // The answers are only parsed out for the query log
+                    let records = if query_log.is_active() {
+                        parse_dns_packet(&response)
+                            .map(|(_, packet)| packet.answers)
+                            .unwrap_or_default()
+                    } else {
+                        Vec::new()
+                    };
+                    query_log.publish(QueryEvent {
+                        time: SystemTime::now(),
+                        client: addr,
+                        name: Arc::clone(&key.name),
+                        qtype: key.qtype,
+                        rcode,
+                        answers,
+                        records,
+                        latency: started.elapsed(),
+                        disposition,
+                    });
+                }
+                match sock.send_to(&response, addr).await {
+                    Ok(len) => debug!("Relayed DNS response ({} bytes) to {}", len, addr),
+                    Err(e) => error!("Failed to send DNS response to {}: {}", addr, e),
+                }
+                if let Some(key) = dedup_key {
+                    dedup.complete(key, response);
+                }
+                return;
+            }
+
             // Create a DNS response packet
             // let response_packet = create_dns_response(packet);
 
@@ -78,10 +352,8 @@ pub async fn process_dns_query(
              */
             // let mut response_builder = DnsResponseBuilder::new().build_custom_response(&packet);
 
-            // Create a new builder for each request (thread-safe)
-            let mut dns_response_builder = DnsResponseBuilder::new();
-
-            let response_builder_fluent = dns_response_builder
+            // Every request starts from the shared template
+            let response_builder_fluent = RESPONSES
                 .build_custom_response(&packet)
                 // leave Packet Identifier (ID) intact
                 .with_qr(true) // Set QR bit to true for response
@@ -89,8 +361,8 @@ pub async fn process_dns_query(
                 .with_authoritative(false) // Set AA bit to false (not authoritative)
                 // Leave TC bit as is (not truncated)
                 // Leave RD bit as is (recursion desired)
-                .with_recursion_available(false)
-                // Set RA bit to false (recursion not available)
+                // Set RA bit when the client may recurse through us
+                .with_recursion_available(acl.permits(addr.ip()))
                 .with_z(0); // Reserved bits set to 0
                             // .with_rcode(0) // NOERROR
                             // NOTE: rcode is 0 (no error) if OPCODE is 0 (standard query) else 4 (not implemented)
@@ -101,62 +373,251 @@ pub async fn process_dns_query(
             // and add them to the response packet
             // debug!("Processing {} questions", packet.questions.len());
             let mut response_builder_chain = response_builder_fluent;
+            // Where the answers came from; a failure anywhere marks the whole packet
+            let mut disposition = Disposition::Local;
 
-            for question in packet.questions.iter() {
-                // `resolve` now returns an Option<Vec<IpAddr>>
-                if let Some(ip_addrs) = query_handle.resolve(question.name.clone()).await {
-                    if ip_addrs.is_empty() {
-                        error!("Could not resolve {}: No IPs found", &question.name);
-                    } else {
-                        // Iterate over all returned IP addresses and add them to the response
-                        for ip_addr in ip_addrs {
-                            info!("Resolved {} -> {}", &question.name, ip_addr);
-                            response_builder_chain = response_builder_chain.with_an_answer(
-                                &question.name,
-                                ip_addr, // This is already an IpAddr
-                                60,
-                            );
-                        }
-                    }
-                } else {
-                    error!("Could not resolve {}: Lookup failed", &question.name);
-                    // Optionally, set the RCODE to NXDOMAIN or similar
+            // A client that sent an OPT record gets one back (RFC 6891). A
+            // resolver's client subnet locates the client it asks for, and is
+            // echoed with the answer holding for all of it (RFC 7871)
+            let edns = Edns::from_packet(&packet);
+            let client_subnet = match &edns {
+                Ok(Some(edns)) if locator.is_active() => edns.client_subnet(),
+                _ => None,
+            };
+            if let Ok(Some(edns)) = &edns {
+                let mut reply = edns.reply();
+                reply
+                    .options
+                    .extend(client_subnet.map(|subnet| subnet.reply(subnet.source_prefix)));
+                response_builder_chain = response_builder_chain.with_edns(reply);
+            }
+            let dnssec_ok = matches!(&edns, Ok(Some(edns)) if edns.dnssec_ok);
+
+            // Clients the tenant doesn't serve get nothing but REFUSED, and
+            // under the strict policy a packet with an invalid name is refused as a whole
+            let invalid_name = packet
+                .questions
+                .iter()
+                .find(|question| !hostname_policy.accepts(&question.name));
+            let questions: &[DnsQuestion] = match (invalid_name, &edns) {
+                _ if !acl.permits(addr.ip()) => {
+                    info!("Refusing query from {} (not an allowed client)", addr);
+                    metrics::incr(&metrics.queries_refused);
+                    response_builder_chain = response_builder_chain.with_rcode(Rcode::REFUSED);
+                    &[]
+                }
+                // Other opcodes aren't resolved at all; the response is NOTIMP
+                _ if packet.header.opcode != Opcode::QUERY => {
+                    debug!(
+                        "Not implemented: opcode {} from {}",
+                        packet.header.opcode, addr
+                    );
+                    &[]
+                }
+                (_, Err(e)) => {
+                    info!("Rejecting query from {}: {}", addr, e);
+                    response_builder_chain = response_builder_chain.with_rcode(Rcode::FORMERR);
+                    &[]
+                }
+                // Later EDNS versions get an OPT record of the version spoken
+                (_, Ok(Some(edns))) if edns.version > EDNS_VERSION => {
+                    debug!("Unsupported EDNS version {} from {}", edns.version, addr);
+                    response_builder_chain = response_builder_chain.with_rcode(Rcode::BADVERS);
+                    &[]
+                }
+                (Some(invalid), _) => {
+                    info!(
+                        "Rejecting invalid query name {:?} from {}",
+                        invalid.name, addr
+                    );
+                    response_builder_chain = response_builder_chain.with_rcode(Rcode::FORMERR);
+                    &[]
+                }
+                (None, _) => &packet.questions,
+            };
+
+            // Only looked up once there is something to answer
+            let client_location = if questions.is_empty() {
+                ClientLocation::default()
+            } else {
+                let located = client_subnet
+                    .filter(|subnet| subnet.source_prefix > 0)
+                    .map_or(addr.ip(), |subnet| subnet.addr);
+                locator.locate(located)
+            };
+
+            // The questions are answered concurrently, so one waiting on a slow
+            // upstream doesn't hold up the others, and added in question order
+            let requests: Vec<Request> = questions
+                .iter()
+                .map(|question| {
+                    Request::new(
+                        &ctx,
+                        question,
+                        addr,
+                        packet.header.rd,
+                        dnssec_ok,
+                        &client_location,
+                    )
+                })
+                .collect();
+            let answered =
+                join_all(requests.iter().map(|request| ctx.pipeline.answer(request))).await;
+            let mut rcode = Rcode::NOERROR;
+            for (question, answer) in questions.iter().zip(answered) {
+                // Answers added below are grouped under this question
+                response_builder_chain = response_builder_chain.with_question(
+                    &question.name,
+                    question.qtype,
+                    question.qclass,
+                );
+                for record in answer.answers {
+                    response_builder_chain = response_builder_chain.with_answer(record);
+                }
+                for record in answer.authority {
+                    response_builder_chain = response_builder_chain.with_authority(record);
+                }
+                for record in answer.additional {
+                    response_builder_chain = response_builder_chain.with_additional(record);
+                }
+                if answer.authoritative {
+                    response_builder_chain = response_builder_chain.with_authoritative(true);
+                }
+                // The first of the worst outcomes answers for the packet
+                if severity(answer.rcode) > severity(rcode) {
+                    rcode = answer.rcode;
+                }
+                // A failure anywhere marks the whole packet
+                if answer.disposition == Disposition::Failed || disposition == Disposition::Local {
+                    disposition = answer.disposition;
                 }
             }
+            if !questions.is_empty() {
+                response_builder_chain = response_builder_chain.with_rcode(rcode);
+            }
 
             let response_packet = response_builder_chain.build();
-            // Other examples (commented out):
-            // Direct domain response: response_builder.build_domain_response("example.com", packet.header.id);
-            // Multiple domains: response_builder.build_multi_domain_response(&["google.com", "github.com"], packet.header.id);
-            // Different record types: .with_aaaa_record("ipv6.google.com"), .with_cname_record("www.example.com"), etc.
-
-            // Encode the response packet
-            let mut response_buf = BytesMut::new();
-            match codec.encode(response_packet, &mut response_buf) {
-                Ok(()) => {
-                    let response_len = sock
-                        .send_to(&response_buf, addr)
-                        .await
-                        .expect("Failed to send DNS response");
-                    info!("Sent DNS response ({} bytes) to {}", response_len, addr);
+
+            // A group may log only its clients' failures, or nothing
+            let logged = dedup_key
+                .as_ref()
+                .filter(|_| groups.logs(addr.ip(), disposition));
+            if let Some(key) = logged {
+                query_log.publish(QueryEvent {
+                    time: SystemTime::now(),
+                    client: addr,
+                    name: Arc::clone(&key.name),
+                    qtype: key.qtype,
+                    rcode: response_packet.header.rcode,
+                    answers: response_packet.answers.len(),
+                    records: if query_log.is_active() {
+                        response_packet.answers.clone()
+                    } else {
+                        Vec::new()
+                    },
+                    latency: started.elapsed(),
+                    disposition,
+                });
+            }
+
+            // Encode the response packet, cut to what the client takes
+            let limit = sock.limit(edns.as_ref().ok().and_then(Option::as_ref));
+            let mut response_buf = BytesMut::with_capacity(limit.into());
+            match codec::encode_truncated(response_packet, limit.into(), &mut response_buf) {
+                Ok(truncated) => {
+                    if truncated {
+                        debug!("Truncated the response to {} to {} bytes", addr, limit);
+                        metrics::incr(&metrics.responses_truncated);
+                    }
+                    match sock.send_to(&response_buf, addr).await {
+                        Ok(len) => debug!("Sent DNS response ({} bytes) to {}", len, addr),
+                        Err(e) => error!("Failed to send DNS response to {}: {}", addr, e),
+                    }
+                    if let Some(key) = dedup_key {
+                        dedup.complete(key, response_buf.to_vec());
+                    }
                 }
                 Err(e) => {
                     error!("Failed to encode DNS response for {}: {}", addr, e);
-                    // Fallback to echoing original data
-                    let response_len = sock
-                        .send_to(&packet_data[..], addr)
-                        .await
-                        .expect("Failed to send DNS response");
-                    info!("Fallback: echoed {} bytes back to {}", response_len, addr);
+                    if let Some(key) = &dedup_key {
+                        dedup.forget(key);
+                    }
+                    send_header_only(
+                        &sock,
+                        addr,
+                        packet.header,
+                        Rcode::SERVFAIL,
+                        acl.permits(addr.ip()),
+                    )
+                    .await;
                 }
             }
         }
+        // Without a header there is no ID to answer under
         Ok(None) => {
-            info!("Incomplete packet received from {}, ignoring", addr);
+            debug!("Dropping {}-byte packet from {}", packet_data.len(), addr);
         }
         Err(e) => {
-            error!("Failed to decode DNS packet from {}: {}", addr, e);
-            // Continue processing other packets even if one fails
+            info!("Malformed query from {}: {}", addr, e);
+            metrics::incr(&metrics.queries_malformed);
+            if let Ok((_, header)) = parse_dns_packet_header(&packet_data) {
+                send_header_only(&sock, addr, header, Rcode::FORMERR, acl.permits(addr.ip())).await;
+            }
         }
     }
 }
+
+/// Whether the blocklist holds one of a query's questions, so that it is
+/// answered here instead of relayed
+fn blocks_any(ctx: &QueryContext, packet: &DnsPacket, client: SocketAddr) -> bool {
+    let location = ClientLocation::default();
+    packet.header.opcode == Opcode::QUERY
+        && packet.questions.iter().any(|question| {
+            Request::new(ctx, question, client, packet.header.rd, false, &location)
+                .blocked()
+                .is_some()
+        })
+}
+
+/// How bad a question's outcome is: failures and refusals outweigh negative
+/// answers, which outweigh answers
+fn severity(rcode: Rcode) -> u8 {
+    match rcode {
+        Rcode::NOERROR => 0,
+        Rcode::NXDOMAIN => 1,
+        _ => 2,
+    }
+}
+
+/// Answer a query that can't be answered in full with its header alone: the
+/// ID, opcode and RD echoed, no sections and `rcode`, and RA as for any
+/// other response to the client
+async fn send_header_only(
+    sock: &Responder,
+    addr: SocketAddr,
+    header: DnsPacketHeader,
+    rcode: Rcode,
+    recursion_available: bool,
+) {
+    let query = DnsPacket {
+        header,
+        questions: Vec::new(),
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+    };
+    let mut response = RESPONSES
+        .build_custom_response(&query)
+        .with_recursion_available(recursion_available)
+        .build();
+    // Set after building, which answers other opcodes NOTIMP
+    response.header.rcode = rcode;
+    let mut response_buf = BytesMut::new();
+    if let Err(e) = codec::encode_packet(&response, &mut response_buf) {
+        error!("Failed to encode DNS response for {}: {}", addr, e);
+        return;
+    }
+    if let Err(e) = sock.send_to(&response_buf, addr).await {
+        error!("Failed to send DNS response to {}: {}", addr, e);
+    }
+}
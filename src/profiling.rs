@@ -0,0 +1,134 @@
+//! On-demand profiles of a running server
+//!
+//! Opt-in at build time: `--features profiling` adds CPU profiles taken with
+//! a sampling profiler, `--features heap-profiling` also switches the
+//! allocator to jemalloc with allocation sampling on. The control socket's
+//! `profile` command writes them to a file on the server's host; builds
+//! without the features answer with an error instead.
+
+use std::path::PathBuf;
+
+use crate::errors::ProfileError;
+
+/// Longest CPU profile the control socket will take
+pub const MAX_CPU_SECONDS: u64 = 300;
+
+/// What to profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Sample the CPU for a number of seconds
+    Cpu { seconds: u64 },
+    /// The sampled allocations currently live
+    Heap,
+}
+
+/// Take a profile and write it to `path`, returning a description of what
+/// was written. CPU profiles are flamegraphs when the path ends in `.svg`
+/// and pprof protobuf otherwise; heap profiles are jemalloc's heap format,
+/// which `jeprof` and `pprof` both read.
+pub async fn write_profile(profile: Profile, path: PathBuf) -> Result<String, ProfileError> {
+    tokio::task::spawn_blocking(move || match profile {
+        Profile::Cpu { seconds } => cpu::write(seconds, &path),
+        Profile::Heap => heap::write(&path),
+    })
+    .await
+    .map_err(|e| ProfileError::Profiler(e.to_string()))?
+}
+
+#[cfg(all(unix, feature = "profiling"))]
+mod cpu {
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use pprof::protos::Message;
+
+    use crate::errors::ProfileError;
+
+    /// Samples per second
+    const FREQUENCY: i32 = 99;
+
+    fn is_svg(path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("svg"))
+    }
+
+    pub fn write(seconds: u64, path: &Path) -> Result<String, ProfileError> {
+        let profiler = |e: pprof::Error| ProfileError::Profiler(e.to_string());
+
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(profiler)?;
+        std::thread::sleep(Duration::from_secs(seconds));
+        let report = guard.report().build().map_err(profiler)?;
+        drop(guard);
+
+        let mut file = File::create(path)?;
+        if is_svg(path) {
+            report.flamegraph(&mut file).map_err(profiler)?;
+            Ok(format!("{}s CPU flamegraph", seconds))
+        } else {
+            let profile = report.pprof().map_err(profiler)?;
+            file.write_all(&profile.encode_to_vec())?;
+            Ok(format!("{}s CPU profile (pprof)", seconds))
+        }
+    }
+}
+
+#[cfg(not(all(unix, feature = "profiling")))]
+mod cpu {
+    use std::path::Path;
+
+    use crate::errors::ProfileError;
+
+    pub fn write(_seconds: u64, _path: &Path) -> Result<String, ProfileError> {
+        Err(ProfileError::Unavailable("profiling"))
+    }
+}
+
+#[cfg(all(unix, feature = "heap-profiling"))]
+mod heap {
+    use std::ffi::{c_char, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use crate::errors::ProfileError;
+
+    #[global_allocator]
+    static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+    /// Sample an allocation every 512 KiB on average, from startup on; cheap
+    /// enough to leave on in production
+    #[allow(non_upper_case_globals)]
+    #[export_name = "_rjem_malloc_conf"]
+    pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
+
+    pub fn write(path: &Path) -> Result<String, ProfileError> {
+        if path.is_dir() {
+            return Err(ProfileError::Profiler(format!(
+                "{} is a directory",
+                path.display()
+            )));
+        }
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| ProfileError::Profiler("path contains a NUL byte".to_string()))?;
+        // SAFETY: prof.dump takes a NUL-terminated file name, which outlives the call
+        unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", path.as_ptr() as *const c_char) }
+            .map_err(|e| ProfileError::Profiler(e.to_string()))?;
+        Ok("heap profile (jemalloc)".to_string())
+    }
+}
+
+#[cfg(not(all(unix, feature = "heap-profiling")))]
+mod heap {
+    use std::path::Path;
+
+    use crate::errors::ProfileError;
+
+    pub fn write(_path: &Path) -> Result<String, ProfileError> {
+        Err(ProfileError::Unavailable("heap-profiling"))
+    }
+}
@@ -0,0 +1,416 @@
+//! Transparent proxying (`--transparent`)
+//!
+//! Instead of being answered, a client's query is sent upstream as it came
+//! in, with only the ID replaced, and the upstream's response is relayed back
+//! with the client's ID put back. Everything else passes through untouched:
+//! flags, EDNS options (the DO bit among them) and every section, additional
+//! records included. The server still logs, counts and filters the queries,
+//! which makes it a bump in the wire. `tcp://` and `tls://` upstreams are
+//! asked over TCP and TLS; DNS over HTTPS upstreams can't be relayed to.
+//! A UDP upstream's truncated response is followed up over TCP, and the
+//! complete answer relayed when it fits in the client's UDP payload size.
+//!
+//! A response is only taken from the upstream asked, under the ID it was
+//! asked with and with the query's question exactly as sent, letter case
+//! included; anything else is dropped as forged.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hickory_resolver::proto::rustls::client_config;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+use crate::tcp::{read_message, write_message};
+use crate::udp_client::UdpClient;
+use crate::upstream::{upstream_tcp_stream, upstream_udp_socket, Transport, Upstream};
+
+/// How long each upstream gets to answer before the next one is tried
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Relays queries to a tenant's upstreams, in order
+pub struct Proxy {
+    upstreams: Vec<Upstream>,
+    tls: TlsConnector,
+    /// Sends to UDP upstreams reached by the default route
+    udp: UdpClient,
+}
+
+impl std::fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Proxy")
+            .field("upstreams", &self.upstreams)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Proxy {
+    pub fn new(upstreams: Vec<Upstream>) -> Self {
+        Proxy {
+            upstreams,
+            tls: TlsConnector::from(Arc::new(client_config())),
+            udp: UdpClient::new(),
+        }
+    }
+
+    /// Send `query` upstream under a fresh ID and return the response under
+    /// the query's own ID. A truncated UDP response is asked for again over
+    /// TCP, and the complete one returned if it fits in `limit` bytes, what
+    /// the client takes over UDP.
+    pub async fn forward(&self, query: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        if query.len() < 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "query shorter than a header",
+            ));
+        }
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no upstream to forward to");
+        for upstream in &self.upstreams {
+            match tokio::time::timeout(UPSTREAM_TIMEOUT, self.exchange(query, upstream)).await {
+                Ok(Ok(response)) if is_truncated(&response) => {
+                    return Ok(self.complete(query, upstream, response, limit).await)
+                }
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(e)) => {
+                    debug!("Upstream {} failed: {}", upstream, e);
+                    last_error = e;
+                }
+                Err(_) => {
+                    debug!("Upstream {} timed out", upstream);
+                    last_error = io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("{} did not answer in time", upstream),
+                    );
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// The complete answer to a query truncated over UDP, asked of the same
+    /// upstream over TCP. The truncated response stays if that fails or the
+    /// answer is too large for the client anyway, which can then ask over
+    /// TCP itself.
+    async fn complete(
+        &self,
+        query: &[u8],
+        upstream: &Upstream,
+        truncated: Vec<u8>,
+        limit: usize,
+    ) -> Vec<u8> {
+        if upstream.transport != Transport::Udp {
+            return truncated;
+        }
+        let upstream = Upstream {
+            transport: Transport::Tcp,
+            ..upstream.clone()
+        };
+        match tokio::time::timeout(UPSTREAM_TIMEOUT, self.exchange(query, &upstream)).await {
+            Ok(Ok(response)) if response.len() <= limit => {
+                debug!(
+                    "Took the truncated answer from {} over TCP ({} bytes)",
+                    upstream,
+                    response.len()
+                );
+                response
+            }
+            Ok(Ok(response)) => {
+                debug!(
+                    "The {}-byte answer from {} is too large for the client",
+                    response.len(),
+                    upstream
+                );
+                truncated
+            }
+            Ok(Err(e)) => {
+                debug!("Upstream {} failed over TCP: {}", upstream, e);
+                truncated
+            }
+            Err(_) => {
+                debug!("Upstream {} timed out over TCP", upstream);
+                truncated
+            }
+        }
+    }
+
+    /// One query and its response, over a connection of its own
+    async fn exchange(&self, query: &[u8], upstream: &Upstream) -> io::Result<Vec<u8>> {
+        match &upstream.transport {
+            Transport::Udp if upstream.via.is_none() => {
+                self.udp.exchange(query, upstream.addr).await
+            }
+            // Sources and interfaces of their own need sockets of their own
+            Transport::Udp => exchange_udp(query, upstream).await,
+            Transport::Tcp => {
+                let stream = upstream_tcp_stream(upstream).await?;
+                exchange_stream(query, stream, upstream).await
+            }
+            Transport::Tls { server_name } => {
+                let name = ServerName::try_from(server_name.clone())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let stream = upstream_tcp_stream(upstream).await?;
+                let stream = self.tls.connect(name, stream).await?;
+                exchange_stream(query, stream, upstream).await
+            }
+            Transport::Https { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "DNS over HTTPS upstreams can't be relayed to",
+            )),
+        }
+    }
+}
+
+/// One query and its response over UDP, from a socket of its own so the
+/// source port is as unpredictable as the ID
+pub async fn exchange_udp(query: &[u8], upstream: &Upstream) -> io::Result<Vec<u8>> {
+    let sock = upstream_udp_socket(upstream)?;
+    // Only the upstream's datagrams are delivered to a connected socket
+    sock.connect(upstream.addr).await?;
+
+    let id: u16 = rand::random();
+    let mut packet = query.to_vec();
+    packet[..2].copy_from_slice(&id.to_be_bytes());
+    sock.send(&packet).await?;
+
+    let mut buf = vec![0; 65535];
+    loop {
+        let len = sock.recv(&mut buf).await?;
+        // Anything else is a stale or forged answer; keep waiting. Only the
+        // upstream's own address gets through the connected socket.
+        if len >= 12
+            && buf[..2] == id.to_be_bytes()
+            && buf[2] & 0x80 != 0
+            && answers_question(query, &buf[..len])
+        {
+            buf.truncate(len);
+            buf[..2].copy_from_slice(&query[..2]);
+            return Ok(buf);
+        }
+        debug!(
+            "Ignoring unexpected {}-byte datagram from {}",
+            len, upstream
+        );
+    }
+}
+
+/// One query and its response over a TCP or TLS connection
+pub async fn exchange_stream(
+    query: &[u8],
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    upstream: &Upstream,
+) -> io::Result<Vec<u8>> {
+    let id: u16 = rand::random();
+    let mut packet = query.to_vec();
+    packet[..2].copy_from_slice(&id.to_be_bytes());
+    write_message(&mut stream, &packet).await?;
+
+    let mut buf = read_message(&mut stream).await?;
+    if buf.len() < 12
+        || buf[..2] != id.to_be_bytes()
+        || buf[2] & 0x80 == 0
+        || !answers_question(query, &buf)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected {}-byte response from {}", buf.len(), upstream),
+        ));
+    }
+    buf[..2].copy_from_slice(&query[..2]);
+    Ok(buf)
+}
+
+/// Whether a response has its TC flag set
+fn is_truncated(response: &[u8]) -> bool {
+    response.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+/// Whether a response carries the question of the query it matched by ID,
+/// byte for byte, so names keep the exact case they were sent in (0x20
+/// randomization, draft-vixie-dnsext-dns0x20). Error responses may leave the
+/// question out.
+pub fn answers_question(query: &[u8], response: &[u8]) -> bool {
+    let Some(question) = question_section(query) else {
+        return false;
+    };
+    if response.len() < 12 {
+        return false;
+    }
+    if response[4..6] == [0, 0] && response[3] & 0x0f != 0 {
+        return true;
+    }
+    response[4..6] == query[4..6] && response.get(12..12 + question.len()) == Some(question)
+}
+
+/// The question section of a message
+fn question_section(packet: &[u8]) -> Option<&[u8]> {
+    let count = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]);
+    let mut offset = 12;
+    for _ in 0..count {
+        loop {
+            match *packet.get(offset)? as usize {
+                0 => {
+                    offset += 1;
+                    break;
+                }
+                // A compression pointer ends the name
+                len if len & 0xc0 == 0xc0 => {
+                    offset += 2;
+                    break;
+                }
+                len => offset += 1 + len,
+            }
+        }
+        offset += 4;
+    }
+    packet.get(12..offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_forward_rewrites_only_the_id() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Proxy::new(vec![upstream.local_addr().unwrap().into()]);
+
+        // A query with an OPT record setting the DO bit
+        let query: Vec<u8> = [
+            &[0xbe, 0xef, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 1][..],
+            &[3, b'c', b'o', b'm', 0, 0, 2, 0, 1],
+            &[0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 0],
+        ]
+        .concat();
+        let echo = async {
+            let mut buf = [0; 512];
+            let (len, client) = upstream.recv_from(&mut buf).await.unwrap();
+            let received = buf[..len].to_vec();
+            // A decoy with the wrong ID comes first, then the answer
+            let mut response = received.clone();
+            response[2] |= 0x80;
+            let mut decoy = response.clone();
+            decoy[0] ^= 0xff;
+            upstream.send_to(&decoy, client).await.unwrap();
+            upstream.send_to(&response, client).await.unwrap();
+            received
+        };
+
+        let (response, received) = tokio::join!(proxy.forward(&query, 512), echo);
+        let response = response.unwrap();
+        assert_eq!(received[2..], query[2..]);
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(response[2], query[2] | 0x80);
+        assert_eq!(response[3..], query[3..]);
+    }
+
+    #[test]
+    fn test_answers_question() {
+        let query = [
+            &[0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0][..],
+            &[3, b'w', b'W', b'w', 2, b'i', b'O', 0, 0, 1, 0, 1],
+        ]
+        .concat();
+        let mut response = query.clone();
+        response[2] |= 0x80;
+        response.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        response[7] = 1;
+        assert!(answers_question(&query, &response));
+
+        // The name in another case, another type, or no question at all
+        let mut recased = response.clone();
+        recased[14] = b'w';
+        assert!(!answers_question(&query, &recased));
+        let mut retyped = response.clone();
+        retyped[21] = 28;
+        assert!(!answers_question(&query, &retyped));
+        let mut bare = response[..12].to_vec();
+        bare[5] = 0;
+        assert!(!answers_question(&query, &bare));
+
+        // ...which is fine for an error
+        bare[3] |= 0x01;
+        assert!(answers_question(&query, &bare));
+        assert!(!answers_question(&query, &response[..20]));
+    }
+
+    #[tokio::test]
+    async fn test_forward_over_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream: Upstream = format!("tcp://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let proxy = Proxy::new(vec![upstream]);
+
+        let query = [
+            0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1,
+        ];
+        let echo = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let received = read_message(&mut stream).await.unwrap();
+            let mut response = received.clone();
+            response[2] |= 0x80;
+            write_message(&mut stream, &response).await.unwrap();
+            received
+        };
+
+        let (response, received) = tokio::join!(proxy.forward(&query, 512), echo);
+        let response = response.unwrap();
+        assert_eq!(received[2..], query[2..]);
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(response[2], query[2] | 0x80);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answers_are_completed_over_tcp() {
+        // Truncates over UDP, then gives the answer with TXT records over TCP
+        async fn upstream(udp: &UdpSocket, tcp: &tokio::net::TcpListener, answers: u8) {
+            let mut buf = [0; 512];
+            let (len, client) = udp.recv_from(&mut buf).await.unwrap();
+            buf[2] |= 0x82;
+            udp.send_to(&buf[..len], client).await.unwrap();
+
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let mut response = read_message(&mut stream).await.unwrap();
+            response[2] |= 0x80;
+            response[7] = answers;
+            for _ in 0..answers {
+                response.extend([0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 101, 100]);
+                response.extend([b'x'; 100]);
+            }
+            write_message(&mut stream, &response).await.unwrap();
+        }
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let proxy = Proxy::new(vec![addr.into()]);
+        let query = [
+            &[0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0][..],
+            &[3, b'c', b'o', b'm', 0, 0, 16, 0, 1],
+        ]
+        .concat();
+
+        let (response, ()) = tokio::join!(proxy.forward(&query, 512), upstream(&udp, &tcp, 2));
+        let response = response.unwrap();
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(response[2] & 0x02, 0);
+        assert_eq!(response[7], 2);
+
+        // An answer too large for the client is left truncated
+        let (response, ()) = tokio::join!(proxy.forward(&query, 512), upstream(&udp, &tcp, 5));
+        let response = response.unwrap();
+        assert_ne!(response[2] & 0x02, 0);
+        assert_eq!(response[7], 0);
+    }
+
+    #[tokio::test]
+    async fn test_forward_without_upstreams_fails() {
+        let proxy = Proxy::new(Vec::new());
+        assert!(proxy.forward(&[0; 12], 512).await.is_err());
+        assert!(proxy.forward(&[0; 4], 512).await.is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! The query log in SQLite (`--query-log-db`, `sqlite` feature)
+//!
+//! Every query the default tenant logs is also stored as a row of the
+//! `queries` table of an SQLite database, so the log can be searched and
+//! summed up with SQL and no server of its own:
+//!
+//! ```text
+//! CREATE TABLE queries (
+//!     id INTEGER PRIMARY KEY,
+//!     time INTEGER NOT NULL,       -- milliseconds since the Unix epoch
+//!     client TEXT NOT NULL,        -- the client's address, without the port
+//!     name TEXT NOT NULL,
+//!     qtype TEXT NOT NULL,
+//!     rcode TEXT NOT NULL,
+//!     answers INTEGER NOT NULL,
+//!     latency_ms REAL NOT NULL,
+//!     disposition TEXT NOT NULL    -- local, forwarded, cached, blocked or failed
+//! );
+//! ```
+//!
+//! The database is fed like the other query logs, through a queue that drops
+//! events rather than hold up answers. The writer task gathers what arrives
+//! within a second into one transaction, run on the blocking pool. Queries
+//! older than `--query-log-retention` days are deleted when the server starts
+//! and every hour after.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, warn};
+
+use crate::query_log::QueryEvent;
+
+/// Most events stored in one transaction
+const INSERT_BATCH: usize = 1024;
+
+/// How long the writer waits for more events before storing a batch
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+/// How often old queries are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS queries (
+        id INTEGER PRIMARY KEY,
+        time INTEGER NOT NULL,
+        client TEXT NOT NULL,
+        name TEXT NOT NULL,
+        qtype TEXT NOT NULL,
+        rcode TEXT NOT NULL,
+        answers INTEGER NOT NULL,
+        latency_ms REAL NOT NULL,
+        disposition TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS queries_time ON queries (time);
+";
+
+/// An open query log database
+pub struct QueryDb {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+impl QueryDb {
+    /// Open the database at `path`, creating it and its table as needed
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let open = || -> rusqlite::Result<Connection> {
+            let connection = Connection::open(path)?;
+            // Readers don't block the writer, and commits don't wait for
+            // the disk: a crash loses at most the last few batches
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.pragma_update(None, "synchronous", "NORMAL")?;
+            connection.execute_batch(SCHEMA)?;
+            Ok(connection)
+        };
+        let connection =
+            open().with_context(|| format!("could not open query log {}", path.display()))?;
+        Ok(QueryDb {
+            path: path.to_path_buf(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Store `events` in one transaction
+    fn insert(&self, events: &[Arc<QueryEvent>]) -> rusqlite::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT INTO queries (time, client, name, qtype, rcode, answers, latency_ms, disposition)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for event in events {
+                insert.execute(params![
+                    millis(event.time),
+                    event.client.ip().to_canonical().to_string(),
+                    &*event.name,
+                    event.qtype.to_string(),
+                    event.rcode.to_string(),
+                    event.answers,
+                    event.latency.as_secs_f64() * 1000.0,
+                    event.disposition.name(),
+                ])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Delete the queries made before `cutoff`, returning how many there were
+    fn prune(&self, cutoff: SystemTime) -> rusqlite::Result<usize> {
+        self.connection()
+            .execute("DELETE FROM queries WHERE time < ?1", [millis(cutoff)])
+    }
+}
+
+/// Milliseconds since the Unix epoch
+fn millis(time: SystemTime) -> i64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    i64::try_from(since_epoch.as_millis()).unwrap_or(i64::MAX)
+}
+
+/// The database's writer task: what arrives within `FLUSH_DELAY` of an event
+/// is stored along with it
+pub async fn store_events(mut events: mpsc::Receiver<Arc<QueryEvent>>, db: Arc<QueryDb>) {
+    let mut batch = Vec::with_capacity(INSERT_BATCH);
+    while events.recv_many(&mut batch, INSERT_BATCH).await > 0 {
+        let deadline = Instant::now() + FLUSH_DELAY;
+        while batch.len() < INSERT_BATCH {
+            let room = INSERT_BATCH - batch.len();
+            match timeout_at(deadline, events.recv_many(&mut batch, room)).await {
+                Ok(received) if received > 0 => {}
+                _ => break,
+            }
+        }
+
+        let stored = tokio::task::spawn_blocking({
+            let db = Arc::clone(&db);
+            move || {
+                let stored = db.insert(&batch);
+                batch.clear();
+                (batch, stored)
+            }
+        })
+        .await;
+        batch = match stored {
+            Ok((emptied, Ok(()))) => emptied,
+            Ok((emptied, Err(e))) => {
+                warn!("Could not store queries in {}: {}", db.path.display(), e);
+                emptied
+            }
+            Err(e) => {
+                warn!("Could not store queries in {}: {}", db.path.display(), e);
+                Vec::with_capacity(INSERT_BATCH)
+            }
+        };
+    }
+}
+
+/// Delete the queries older than `retention` now and then every hour
+pub async fn prune_events(db: Arc<QueryDb>, retention: Duration) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let cutoff = SystemTime::now() - retention;
+        let pruned = tokio::task::spawn_blocking({
+            let db = Arc::clone(&db);
+            move || db.prune(cutoff)
+        })
+        .await;
+        match pruned {
+            Ok(Ok(deleted)) => debug!("Deleted {} old queries from {}", deleted, db.path.display()),
+            Ok(Err(e)) => warn!("Could not prune {}: {}", db.path.display(), e),
+            Err(e) => warn!("Could not prune {}: {}", db.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query_log::Disposition;
+    use crate::registry::{Rcode, RecordType};
+
+    fn event(name: &str, age: Duration) -> Arc<QueryEvent> {
+        Arc::new(QueryEvent {
+            time: SystemTime::now() - age,
+            client: "[::ffff:192.168.1.20]:53311".parse().unwrap(),
+            name: name.into(),
+            qtype: RecordType::AAAA,
+            rcode: Rcode::NXDOMAIN,
+            answers: 0,
+            records: Vec::new(),
+            latency: Duration::from_micros(12_400),
+            disposition: Disposition::Blocked,
+        })
+    }
+
+    #[test]
+    fn test_store_and_prune() {
+        let path = std::env::temp_dir().join(format!("dns-queries-{}.db", std::process::id()));
+        let db = QueryDb::open(&path).unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        db.insert(&[
+            event("ads.example", Duration::ZERO),
+            event("old.example", 8 * day),
+        ])
+        .unwrap();
+
+        let (row, latency) = db
+            .connection()
+            .query_row(
+                "SELECT client, qtype, rcode, disposition, latency_ms FROM queries
+                 WHERE name = 'ads.example'",
+                [],
+                |row| {
+                    let columns: rusqlite::Result<Vec<String>> =
+                        (0..4).map(|column| row.get(column)).collect();
+                    Ok((columns?, row.get::<_, f64>(4)?))
+                },
+            )
+            .unwrap();
+        assert_eq!(row, ["192.168.1.20", "AAAA", "NXDOMAIN", "blocked"]);
+        assert!((latency - 12.4).abs() < 1e-9);
+
+        assert_eq!(db.prune(SystemTime::now() - 7 * day).unwrap(), 1);
+        let left: i64 = db
+            .connection()
+            .query_row("SELECT COUNT(*) FROM queries", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(left, 1);
+
+        // Reopening keeps what's there
+        drop(db);
+        assert!(QueryDb::open(&path).is_ok());
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
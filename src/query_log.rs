@@ -0,0 +1,527 @@
+//! Per-query events
+//!
+//! The processor publishes one `QueryEvent` per answered packet on a broadcast
+//! channel. Consumers (`tail` on the control socket) subscribe independently;
+//! a slow consumer only loses events itself and never holds up query
+//! processing.
+//!
+//! Query logs (`--pretty-query-log`, `--query-log-file`) are written by a
+//! dedicated task per log, fed through a bounded queue and writing whatever
+//! has queued up in one go. When a log can't keep up, new events are dropped
+//! and counted rather than waited for, so logging never slows down answers.
+//! Events are rendered by the writer task too, as the pretty line or, under
+//! `--query-log-format json`, as one JSON object per line:
+//!
+//! ```text
+//! {"time":"2026-10-16T13:04:05.120Z","client":"192.168.1.20","port":53311,"name":"example.com","type":"A","rcode":"NOERROR","answers":["A 93.184.215.14"],"latency_ms":12.4,"disposition":"forwarded"}
+//! ```
+
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use tracing::warn;
+
+use crate::local_records::RecordData;
+use crate::names::DisplayName;
+use crate::protocol::DnsResourceRecord;
+use crate::registry::{Rcode, RecordType};
+
+/// How many events a subscriber may fall behind before it starts losing them
+const QUERY_LOG_CAPACITY: usize = 1024;
+
+/// How many events may wait for a log writer before new ones are dropped
+const WRITER_QUEUE: usize = 4096;
+
+/// Most events a log writer formats into a single write
+const WRITE_BATCH: usize = 256;
+
+/// Days the query log database keeps queries for (`--query-log-retention`)
+pub const DEFAULT_RETENTION_DAYS: u64 = 7;
+
+// ANSI escape sequences used by the pretty renderer
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+
+/// Where the answer to a query came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// Answered from the local records table
+    Local,
+    /// Resolved through an upstream resolver
+    Forwarded,
+    /// Answered from the cache of upstream answers
+    Cached,
+    /// Blocked by the blocklist
+    Blocked,
+    /// Resolution failed
+    Failed,
+}
+
+impl Disposition {
+    fn marker(&self) -> &'static str {
+        match self {
+            Disposition::Local => "local",
+            Disposition::Forwarded => "fwd",
+            Disposition::Cached => "cache",
+            Disposition::Blocked => "block",
+            Disposition::Failed => "fail",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Disposition::Local => "local",
+            Disposition::Forwarded => "forwarded",
+            Disposition::Cached => "cached",
+            Disposition::Blocked => "blocked",
+            Disposition::Failed => "failed",
+        }
+    }
+}
+
+/// The format of `--query-log-file`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum QueryLogFormat {
+    /// The pretty line, uncolored
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// How a log renders each event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStyle {
+    Pretty { color: bool },
+    Json,
+}
+
+/// A single answered query
+#[derive(Debug, Clone)]
+pub struct QueryEvent {
+    pub time: SystemTime,
+    pub client: SocketAddr,
+    /// Lowercased question name, interned
+    pub name: Arc<str>,
+    pub qtype: RecordType,
+    pub rcode: Rcode,
+    pub answers: usize,
+    /// The answer records, kept only while something consumes events
+    pub records: Vec<DnsResourceRecord>,
+    pub latency: Duration,
+    pub disposition: Disposition,
+}
+
+/// Called with every event as it is published (see `DnsServerBuilder::on_query`)
+pub type QueryHook = Arc<dyn Fn(&QueryEvent) + Send + Sync>;
+
+/// Publisher side of the query event stream; cheap to clone
+#[derive(Clone)]
+pub struct QueryLog {
+    sender: broadcast::Sender<Arc<QueryEvent>>,
+    writers: Arc<[LogWriter]>,
+    hooks: Arc<[QueryHook]>,
+}
+
+impl fmt::Debug for QueryLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueryLog")
+            .field("sender", &self.sender)
+            .field("writers", &self.writers)
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+/// The queue of a log's writer task
+#[derive(Debug, Clone)]
+struct LogWriter {
+    queue: mpsc::Sender<Arc<QueryEvent>>,
+    // Events dropped because the queue was full
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueryLog {
+    pub fn new() -> Self {
+        Self::with_writers(Vec::new())
+    }
+
+    /// An event stream that is also written, one line per event, to each of
+    /// `sinks` in its style
+    pub fn with_writers(sinks: Vec<(Box<dyn AsyncWrite + Send + Unpin>, LogStyle)>) -> Self {
+        let (sender, _) = broadcast::channel(QUERY_LOG_CAPACITY);
+        let writers = sinks
+            .into_iter()
+            .map(|(sink, style)| {
+                let (queue, events) = mpsc::channel(WRITER_QUEUE);
+                let dropped = Arc::new(AtomicU64::new(0));
+                tokio::spawn(write_events(events, sink, style, Arc::clone(&dropped)));
+                LogWriter { queue, dropped }
+            })
+            .collect();
+        Self {
+            sender,
+            writers,
+            hooks: Arc::new([]),
+        }
+    }
+
+    /// The same stream, also queued for a writer task of the caller's as it
+    /// is for the logs: `writer` makes the task from the receiving end
+    pub fn with_writer<W, F>(self, writer: W) -> Self
+    where
+        W: FnOnce(mpsc::Receiver<Arc<QueryEvent>>) -> F,
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let (queue, events) = mpsc::channel(WRITER_QUEUE);
+        tokio::spawn(writer(events));
+        let mut writers = self.writers.to_vec();
+        writers.push(LogWriter {
+            queue,
+            dropped: Arc::default(),
+        });
+        Self {
+            writers: writers.into(),
+            ..self
+        }
+    }
+
+    /// The same stream, also passed to each of `hooks`
+    pub fn with_hooks(self, hooks: &[QueryHook]) -> Self {
+        Self {
+            hooks: hooks.into(),
+            ..self
+        }
+    }
+
+    /// Whether any log or subscriber takes events, so that the details only
+    /// they need can be left out otherwise
+    pub fn is_active(&self) -> bool {
+        !self.writers.is_empty() || !self.hooks.is_empty() || self.sender.receiver_count() > 0
+    }
+
+    /// Publish an event. Never waits: a log that is behind loses the event.
+    pub fn publish(&self, event: QueryEvent) {
+        if !self.is_active() {
+            return;
+        }
+        for hook in self.hooks.iter() {
+            hook(&event);
+        }
+        let event = Arc::new(event);
+        for writer in self.writers.iter() {
+            if writer.queue.try_send(Arc::clone(&event)).is_err() {
+                writer.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(event);
+        }
+    }
+
+    /// Events the query logs have dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.writers
+            .iter()
+            .map(|writer| writer.dropped.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Receive every event published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<QueryEvent>> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for QueryLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A log's writer task: whatever has queued up is rendered and written at
+/// once, along with a note of any events dropped since the last write
+async fn write_events(
+    mut events: mpsc::Receiver<Arc<QueryEvent>>,
+    mut sink: Box<dyn AsyncWrite + Send + Unpin>,
+    style: LogStyle,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut batch = Vec::with_capacity(WRITE_BATCH);
+    let mut reported = 0;
+
+    while events.recv_many(&mut batch, WRITE_BATCH).await > 0 {
+        let mut output = String::new();
+        let total = dropped.load(Ordering::Relaxed);
+        if total > reported {
+            let not_logged = total - reported;
+            let _ = match style {
+                LogStyle::Pretty { .. } => writeln!(
+                    output,
+                    "... {} queries not logged (output too slow)",
+                    not_logged
+                ),
+                LogStyle::Json => writeln!(output, "{}", json!({ "not_logged": not_logged })),
+            };
+            reported = total;
+        }
+        for event in batch.drain(..) {
+            match style {
+                LogStyle::Pretty { color } => output.push_str(&render_pretty(&event, color)),
+                LogStyle::Json => output.push_str(&render_json(&event)),
+            }
+            output.push('\n');
+        }
+
+        let written = match sink.write_all(output.as_bytes()).await {
+            Ok(()) => sink.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("Query log stopped: {}", e);
+            return;
+        }
+    }
+}
+
+/// Render an event as one human-friendly line, e.g.
+/// `13:04:05.120 192.168.1.20:53311  example.com A  NOERROR  2 ans  12.4ms fwd`
+pub fn render_pretty(event: &QueryEvent, color: bool) -> String {
+    let paint = |code: &'static str| if color { code } else { "" };
+    let reset = paint(RESET);
+
+    let rcode_color = match event.rcode {
+        Rcode::NOERROR => GREEN,
+        Rcode::NXDOMAIN => YELLOW,
+        _ => RED,
+    };
+    let marker_color = match event.disposition {
+        Disposition::Local => CYAN,
+        Disposition::Forwarded | Disposition::Cached => DIM,
+        Disposition::Blocked => YELLOW,
+        Disposition::Failed => RED,
+    };
+
+    let mut line = String::new();
+    let _ = write!(
+        line,
+        "{}{}{} {:<21} {}{}{} {:<5} {}{:<8}{} {:>2} ans {:>7.1}ms {}{}{}",
+        paint(DIM),
+        format_time(event.time),
+        reset,
+        event.client,
+        paint(BOLD),
+        DisplayName(&event.name),
+        reset,
+        event.qtype,
+        paint(rcode_color),
+        event.rcode,
+        reset,
+        event.answers,
+        event.latency.as_secs_f64() * 1000.0,
+        paint(marker_color),
+        event.disposition.marker(),
+        reset,
+    );
+    line
+}
+
+/// An event as written to a JSON query log
+#[derive(Serialize)]
+struct JsonEvent<'a> {
+    time: String,
+    client: String,
+    port: u16,
+    name: &'a str,
+    #[serde(rename = "type")]
+    qtype: String,
+    rcode: String,
+    answers: Vec<String>,
+    latency_ms: f64,
+    disposition: &'static str,
+}
+
+/// Render an event as a JSON object on one line
+pub fn render_json(event: &QueryEvent) -> String {
+    let line = JsonEvent {
+        time: format_timestamp(event.time),
+        client: event.client.ip().to_canonical().to_string(),
+        port: event.client.port(),
+        name: &event.name,
+        qtype: event.qtype.to_string(),
+        rcode: event.rcode.to_string(),
+        answers: event.records.iter().map(answer_summary).collect(),
+        latency_ms: (event.latency.as_secs_f64() * 10_000.0).round() / 10.0,
+        disposition: event.disposition.name(),
+    };
+    serde_json::to_string(&line).unwrap_or_default()
+}
+
+/// A record's type and data, e.g. `A 192.0.2.1`, with data of types not
+/// known here in the RFC 3597 generic form (`\# 2 0102`)
+pub fn answer_summary(record: &DnsResourceRecord) -> String {
+    match RecordData::from_rdata(record.rtype, &record.rdata) {
+        Ok(data) => format!("{} {}", record.rtype, data),
+        Err(_) => {
+            let hex: String = record.rdata.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{} \\# {} {}", record.rtype, record.rdata.len(), hex)
+        }
+    }
+}
+
+/// Wall-clock time (UTC) in RFC 3339 form with milliseconds, e.g.
+/// `2026-10-16T13:04:05.120Z`
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let days = since_epoch.as_secs() / 86_400;
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{}Z", year, month, day, format_time(time))
+}
+
+/// Wall-clock time of day (UTC) as HH:MM:SS.mmm
+pub fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() % 86_400;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> QueryEvent {
+        QueryEvent {
+            time: UNIX_EPOCH + Duration::from_millis(3_723_456),
+            client: "192.168.1.20:53311".parse().unwrap(),
+            name: Arc::from("example.com"),
+            qtype: RecordType::AAAA,
+            rcode: Rcode::NXDOMAIN,
+            answers: 0,
+            records: Vec::new(),
+            latency: Duration::from_micros(12_400),
+            disposition: Disposition::Forwarded,
+        }
+    }
+
+    #[test]
+    fn test_render_plain() {
+        let line = render_pretty(&event(), false);
+        assert!(line.starts_with("01:02:03.456 192.168.1.20:53311"));
+        assert!(line.contains("example.com AAAA"));
+        assert!(line.contains("NXDOMAIN"));
+        assert!(line.contains("12.4ms fwd"));
+        assert!(!line.contains('\x1b'));
+    }
+
+    #[test]
+    fn test_render_color() {
+        let line = render_pretty(&event(), true);
+        assert!(line.contains(&format!("{}NXDOMAIN", YELLOW)));
+    }
+
+    #[test]
+    fn test_render_json() {
+        let mut event = event();
+        event.time = UNIX_EPOCH + Duration::from_millis(1_792_155_845_120);
+        event.rcode = Rcode::NOERROR;
+        event.records = vec![
+            DnsResourceRecord::new(
+                "example.com".into(),
+                RecordType::AAAA,
+                crate::registry::RecordClass::IN,
+                300,
+                "2001:db8::1"
+                    .parse::<std::net::Ipv6Addr>()
+                    .unwrap()
+                    .octets()
+                    .to_vec(),
+            ),
+            DnsResourceRecord::new(
+                "example.com".into(),
+                RecordType::SRV,
+                crate::registry::RecordClass::IN,
+                300,
+                vec![0, 1],
+            ),
+        ];
+        let line: serde_json::Value = serde_json::from_str(&render_json(&event)).unwrap();
+        assert_eq!(
+            line,
+            json!({
+                "time": "2026-10-16T13:04:05.120Z",
+                "client": "192.168.1.20",
+                "port": 53311,
+                "name": "example.com",
+                "type": "AAAA",
+                "rcode": "NOERROR",
+                "answers": ["AAAA 2001:db8::1", "SRV \\# 2 0001"],
+                "latency_ms": 12.4,
+                "disposition": "forwarded",
+            })
+        );
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_reaches_subscribers() {
+        let log = QueryLog::new();
+        // Publishing without subscribers is a no-op
+        log.publish(event());
+
+        let mut receiver = log.subscribe();
+        log.publish(event());
+        let received = receiver.recv().await.unwrap();
+        assert_eq!(&*received.name, "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_writer_drops_instead_of_waiting() {
+        let (sink, mut output) = tokio::io::duplex(64 * 1024);
+        let log = QueryLog::with_writers(vec![(Box::new(sink), LogStyle::Pretty { color: false })]);
+        // The writer task hasn't run yet, so the queue fills up
+        for _ in 0..WRITER_QUEUE + 10 {
+            log.publish(event());
+        }
+        assert_eq!(log.dropped(), 10);
+
+        let mut first = vec![0; 128];
+        let len = tokio::io::AsyncReadExt::read(&mut output, &mut first)
+            .await
+            .unwrap();
+        let written = String::from_utf8_lossy(&first[..len]);
+        assert!(written.starts_with("... 10 queries not logged"));
+        assert!(written.contains("\n01:02:03.456"));
+    }
+}
@@ -0,0 +1,313 @@
+//! Per-client query quotas (`--client-quota`)
+//!
+//! Each client address gets a token bucket: `rate` queries a second, with
+//! bursts of up to `burst` queries. A packet from a client whose bucket is
+//! empty is turned away before it is parsed: answered REFUSED, from its
+//! header alone, or with `--quota-action drop` not at all. Quotas are kept
+//! per tenant, across its shards and views.
+//!
+//! The tenant tracks 65 536 clients at most. A new client beyond that,
+//! which only a flood from spoofed sources would bring, takes the place of
+//! the client that queried longest ago, so clients that keep querying keep
+//! their buckets and every client is counted. `stats` shows the clients of
+//! the last ten minutes, and counts the packets turned away as
+//! `queries_over_quota` and shows each client's queries and packets over
+//! quota, the busiest first.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::acl::DenyAction;
+
+/// How long a client is shown after its last query
+const IDLE: Duration = Duration::from_secs(10 * 60);
+
+/// Locks the clients are spread over, so shards rarely wait on each other
+const LOCKS: usize = 16;
+
+/// Most clients tracked
+const MAX_CLIENTS: usize = 65_536;
+
+/// Most clients `stats` shows
+const MAX_SHOWN: usize = 100;
+
+/// A query rate and the burst above it a client may send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// Queries a second
+    pub rate: u32,
+    /// Queries sent at once a full bucket takes
+    pub burst: u32,
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.burst == self.rate {
+            write!(f, "{}", self.rate)
+        } else {
+            write!(f, "{}/{}", self.rate, self.burst)
+        }
+    }
+}
+
+/// `<rate>` or `<rate>/<burst>`; the burst is the rate unless given, and no
+/// less than it
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rate, burst) = match s.split_once('/') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let rate = rate
+            .parse()
+            .ok()
+            .filter(|&rate| rate > 0)
+            .ok_or_else(|| format!("invalid quota rate '{}' (queries a second)", rate))?;
+        let burst = match burst {
+            Some(burst) => burst
+                .parse()
+                .ok()
+                .filter(|&burst| burst >= rate)
+                .ok_or_else(|| format!("invalid quota burst '{}' (at least the rate)", burst))?,
+            None => rate,
+        };
+        Ok(Quota { rate, burst })
+    }
+}
+
+/// Tokens for queries, refilled at a steady rate up to a burst
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket holding `burst` tokens
+    pub(crate) fn full(burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            tokens: f64::from(burst),
+            refilled: now,
+        }
+    }
+
+    /// Take a token if there is one, after refilling `rate` a second
+    pub(crate) fn take(&mut self, rate: u32, burst: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(rate)).min(f64::from(burst));
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A client's counters since it was last forgotten
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientCounts {
+    /// Packets received
+    pub queries: u64,
+    /// Packets turned away
+    pub over_quota: u64,
+}
+
+#[derive(Debug)]
+struct Client {
+    bucket: TokenBucket,
+    counts: ClientCounts,
+}
+
+/// The clients behind one lock
+#[derive(Debug, Default)]
+struct Clients {
+    buckets: HashMap<IpAddr, Client>,
+    /// The clients by when they last queried, the stalest first
+    by_age: BTreeSet<(Instant, IpAddr)>,
+}
+
+/// The quota every client of a tenant gets, and their buckets
+#[derive(Debug)]
+pub struct ClientQuotas {
+    quota: Quota,
+    /// What a client over its quota gets
+    pub action: DenyAction,
+    hasher: RandomState,
+    clients: Box<[Mutex<Clients>]>,
+}
+
+impl ClientQuotas {
+    pub fn new(quota: Quota, action: DenyAction) -> Self {
+        ClientQuotas {
+            quota,
+            action,
+            hasher: RandomState::new(),
+            clients: (0..LOCKS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    /// Whether a packet from `ip` is within its quota, taking a token if so
+    pub fn admit(&self, ip: IpAddr) -> bool {
+        self.admit_at(ip.to_canonical(), Instant::now())
+    }
+
+    fn admit_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let lock = &self.clients[self.hasher.hash_one(ip) as usize % LOCKS];
+        let mut clients = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let Clients { buckets, by_age } = &mut *clients;
+        match buckets.get(&ip) {
+            Some(client) => {
+                by_age.remove(&(client.bucket.refilled, ip));
+            }
+            // A full table makes room by forgetting the stalest client
+            None if buckets.len() >= MAX_CLIENTS / LOCKS => {
+                if let Some((_, stalest)) = by_age.pop_first() {
+                    buckets.remove(&stalest);
+                }
+            }
+            None => {}
+        }
+        let Quota { rate, burst } = self.quota;
+        let client = buckets.entry(ip).or_insert_with(|| Client {
+            bucket: TokenBucket::full(burst, now),
+            counts: ClientCounts::default(),
+        });
+        client.counts.queries += 1;
+        let admitted = client.bucket.take(rate, burst, now);
+        if !admitted {
+            client.counts.over_quota += 1;
+        }
+        by_age.insert((client.bucket.refilled, ip));
+        admitted
+    }
+
+    /// The counters of the clients that queried in the last ten minutes,
+    /// the busiest first, `MAX_SHOWN` at most
+    pub fn snapshot(&self) -> Vec<(IpAddr, ClientCounts)> {
+        self.snapshot_at(Instant::now())
+    }
+
+    fn snapshot_at(&self, now: Instant) -> Vec<(IpAddr, ClientCounts)> {
+        let mut snapshot: Vec<(IpAddr, ClientCounts)> = Vec::new();
+        for lock in self.clients.iter() {
+            let clients = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            snapshot.extend(
+                clients
+                    .buckets
+                    .iter()
+                    .filter(|(_, client)| {
+                        now.saturating_duration_since(client.bucket.refilled) < IDLE
+                    })
+                    .map(|(ip, client)| (*ip, client.counts)),
+            );
+        }
+        snapshot.sort_by(|a, b| b.1.queries.cmp(&a.1.queries).then(a.0.cmp(&b.0)));
+        snapshot.truncate(MAX_SHOWN);
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_quota() {
+        assert_eq!(
+            "200/500".parse::<Quota>(),
+            Ok(Quota {
+                rate: 200,
+                burst: 500
+            })
+        );
+        let quota: Quota = "50".parse().unwrap();
+        assert_eq!(quota.burst, 50);
+        assert_eq!(quota.to_string(), "50");
+        assert_eq!("200/500".parse::<Quota>().unwrap().to_string(), "200/500");
+        assert!("0".parse::<Quota>().is_err());
+        assert!("200/100".parse::<Quota>().is_err());
+        assert!("fast".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn test_client_quotas() {
+        let quotas = ClientQuotas::new("2/4".parse().unwrap(), DenyAction::Refuse);
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "192.0.2.8".parse().unwrap();
+        let now = Instant::now();
+
+        // The burst, then nothing until the bucket refills
+        let admitted: Vec<bool> = (0..6).map(|_| quotas.admit_at(client, now)).collect();
+        assert_eq!(admitted, [true, true, true, true, false, false]);
+        assert!(quotas.admit_at(other, now));
+
+        let later = now + Duration::from_millis(500);
+        assert!(quotas.admit_at(client, later));
+        assert!(!quotas.admit_at(client, later));
+
+        assert_eq!(
+            quotas.snapshot_at(later),
+            [
+                (
+                    client,
+                    ClientCounts {
+                        queries: 8,
+                        over_quota: 3
+                    }
+                ),
+                (
+                    other,
+                    ClientCounts {
+                        queries: 1,
+                        over_quota: 0
+                    }
+                ),
+            ]
+        );
+        // Idle clients are left out
+        assert!(quotas.snapshot_at(later + IDLE).is_empty());
+    }
+
+    #[test]
+    fn test_full_table() {
+        let quotas = ClientQuotas::new("1".parse().unwrap(), DenyAction::Refuse);
+        let busy: IpAddr = "192.0.2.7".parse().unwrap();
+        let first: IpAddr = Ipv4Addr::from(0x0a00_0000).into();
+        let now = Instant::now();
+        assert!(quotas.admit_at(busy, now));
+
+        // A flood of new sources, each counted and given its own bucket,
+        // while the busy client keeps its empty one
+        for source in 0..2 * MAX_CLIENTS as u32 {
+            let time = now + Duration::from_micros(source.into());
+            let ip = Ipv4Addr::from(0x0a00_0000 + source).into();
+            assert!(quotas.admit_at(ip, time));
+            if source % 100 == 0 {
+                assert!(!quotas.admit_at(busy, time));
+            }
+        }
+        let tracked = |ip| {
+            quotas
+                .clients
+                .iter()
+                .any(|lock| lock.lock().unwrap().buckets.contains_key(&ip))
+        };
+        assert!(tracked(busy));
+        assert!(!tracked(first));
+        for lock in quotas.clients.iter() {
+            let clients = lock.lock().unwrap();
+            assert!(clients.buckets.len() <= MAX_CLIENTS / LOCKS);
+            assert_eq!(clients.buckets.len(), clients.by_age.len());
+        }
+    }
+}
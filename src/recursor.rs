@@ -0,0 +1,767 @@
+//! Iterative resolution from the root (`--recursive`)
+//!
+//! Instead of being forwarded to upstream resolvers, names are resolved the
+//! way a recursive resolver does it: the root servers are asked first, and
+//! each server asked either answers or refers to the servers of a zone closer
+//! to the name, with their addresses as glue when it has them. Name servers
+//! without glue are resolved the same way first. A CNAME chain is taken
+//! from an answer as far as it stays within the answering server's zone;
+//! a target outside it is resolved from the top again, whatever records
+//! for it came along, and the whole chain goes into the answer. Referrals are
+//! remembered for their NS TTL, so most lookups start at a zone cut below the
+//! root. Servers are asked over UDP without EDNS, and over TCP when the
+//! answer is truncated. Glue is only taken for name servers within the zone
+//! of the server that gave it. Query names go out with their letters in
+//! random case (0x20), and a response counts only if it comes from the server
+//! asked and echoes the ID and question exactly, case included.
+//! `--no-case-randomization` sends names as they are, for servers that don't
+//! echo the case.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use tracing::debug;
+
+use crate::actors::cache_actor::MAX_CACHE_TTL;
+use crate::actors::messages::Resolution;
+use crate::codec;
+use crate::errors::RecursionError;
+use crate::local_records::{is_in_zone, normalize_name};
+use crate::names::randomize_case;
+use crate::parsers::{parse_dns_packet, parse_domain_name};
+use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion, DnsResourceRecord};
+use crate::proxy::{exchange_stream, UPSTREAM_TIMEOUT};
+use crate::registry::{Opcode, Rcode, RecordClass, RecordType};
+use crate::response_builder::SoaRecord;
+use crate::udp_client::UdpClient;
+use crate::upstream::{upstream_tcp_stream, Transport, Upstream};
+
+/// The root servers' IPv4 addresses, a through m
+pub const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Referrals followed for one name before giving up
+const MAX_REFERRALS: usize = 16;
+/// CNAMEs followed for one question
+const MAX_CNAMES: usize = 8;
+/// How deeply lookups of name servers without glue may nest
+const MAX_DEPTH: usize = 4;
+/// Servers of a zone tried before the lookup fails
+const MAX_SERVERS_TRIED: usize = 3;
+/// Name servers without glue looked up per referral
+const MAX_GLUELESS: usize = 2;
+/// Most zone cuts remembered
+const MAX_DELEGATIONS: usize = 10_000;
+
+/// What a server said about a name
+enum Step {
+    /// Records for the name, possibly starting with a CNAME chain, from a
+    /// server of `zone`
+    Answer {
+        records: Vec<DnsResourceRecord>,
+        zone: String,
+    },
+    /// NXDOMAIN, or no records of the type (NODATA)
+    Negative {
+        rcode: Rcode,
+        soa: Option<DnsResourceRecord>,
+    },
+}
+
+/// The servers of a zone, learned from a referral
+#[derive(Debug)]
+struct Delegation {
+    servers: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+/// Resolves names from the root servers down
+#[derive(Debug)]
+pub struct Recursor {
+    roots: Vec<SocketAddr>,
+    /// The port name servers learned from referrals are asked on
+    port: u16,
+    delegations: Mutex<HashMap<String, Delegation>>,
+    /// Query names are sent in random case
+    case_randomization: bool,
+    /// Random IDs and source ports for the queries
+    client: UdpClient,
+}
+
+impl Default for Recursor {
+    fn default() -> Self {
+        let roots = ROOT_HINTS
+            .iter()
+            .map(|&ip| SocketAddr::new(ip.into(), 53))
+            .collect();
+        Self::new(roots, 53)
+    }
+}
+
+impl Recursor {
+    pub fn new(roots: Vec<SocketAddr>, port: u16) -> Self {
+        Self {
+            roots,
+            port,
+            delegations: Mutex::default(),
+            case_randomization: true,
+            client: UdpClient::new(),
+        }
+    }
+
+    /// Send query names as they are instead of in random case
+    pub fn with_case_randomization(mut self, enabled: bool) -> Self {
+        self.case_randomization = enabled;
+        self
+    }
+
+    /// Resolve `name` to its records of `qtype`, or explain why there are none
+    pub async fn resolve(&self, name: &str, qtype: RecordType) -> Resolution {
+        match self.resolve_chain(name, qtype, 0).await {
+            Ok(resolution) => resolution,
+            Err(e) => {
+                debug!("Could not resolve {} {}: {}", name, qtype, e);
+                Resolution::Failed(Rcode::SERVFAIL)
+            }
+        }
+    }
+
+    /// Resolve a name, following CNAMEs from the top wherever they lead
+    async fn resolve_chain(
+        &self,
+        name: &str,
+        qtype: RecordType,
+        depth: usize,
+    ) -> Result<Resolution, RecursionError> {
+        let mut chain = Vec::new();
+        let mut current = normalize_name(name);
+        for _ in 0..=MAX_CNAMES {
+            let (records, zone) = match self.iterate(&current, qtype, depth).await? {
+                Step::Answer { records, zone } => (records, zone),
+                Step::Negative { rcode, soa } => return Ok(Resolution::Negative { rcode, soa }),
+            };
+            let (followed, target) = follow_chain(&current, qtype, records, &zone);
+            chain.extend(followed);
+            match target {
+                None => return Ok(Resolution::Records(chain)),
+                Some(target) => current = target,
+            }
+        }
+        Err(RecursionError::TooManyReferrals(name.to_string()))
+    }
+
+    /// Ask the servers of the closest known zone about `name`, following
+    /// their referrals down until one answers
+    async fn iterate(
+        &self,
+        name: &str,
+        qtype: RecordType,
+        depth: usize,
+    ) -> Result<Step, RecursionError> {
+        let (mut zone, mut servers) = self.closest_delegation(name);
+        for _ in 0..MAX_REFERRALS {
+            let response = self.ask_any(&servers, name, qtype).await?;
+            if response.header.rcode == Rcode::NXDOMAIN {
+                return Ok(Step::Negative {
+                    rcode: Rcode::NXDOMAIN,
+                    soa: negative_soa(&response),
+                });
+            }
+            if !response.answers.is_empty() {
+                return Ok(Step::Answer {
+                    records: response.answers,
+                    zone,
+                });
+            }
+            let Some(referral) = referral(&response, name, &zone) else {
+                // Nothing of the type, or a server that won't say more
+                return Ok(Step::Negative {
+                    rcode: Rcode::NOERROR,
+                    soa: negative_soa(&response),
+                });
+            };
+
+            debug!(
+                "Referred to {} ({} name servers) for {}",
+                display_zone(&referral.zone),
+                referral.names.len(),
+                name
+            );
+            let mut addresses = referral.glue.clone();
+            if addresses.is_empty() && depth < MAX_DEPTH {
+                for ns in referral.names.iter().take(MAX_GLUELESS) {
+                    addresses.extend(self.addresses_of(ns, depth + 1).await);
+                    if !addresses.is_empty() {
+                        break;
+                    }
+                }
+            }
+            if addresses.is_empty() {
+                return Err(RecursionError::NoAddresses(display_zone(&referral.zone)));
+            }
+            servers = addresses
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, self.port))
+                .collect();
+            self.remember(&referral.zone, &servers, referral.ttl);
+            zone = referral.zone;
+        }
+        Err(RecursionError::TooManyReferrals(name.to_string()))
+    }
+
+    /// The IPv4 addresses of a name server without glue
+    async fn addresses_of(&self, ns: &str, depth: usize) -> Vec<IpAddr> {
+        match Box::pin(self.resolve_chain(ns, RecordType::A, depth)).await {
+            Ok(Resolution::Records(records)) => records
+                .iter()
+                .filter_map(|record| address(record, RecordType::A))
+                .collect(),
+            Ok(_) => Vec::new(),
+            Err(e) => {
+                debug!("Could not look up name server {}: {}", ns, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The first usable response from a zone's servers, starting from a
+    /// random one so the load is spread
+    async fn ask_any(
+        &self,
+        servers: &[SocketAddr],
+        name: &str,
+        qtype: RecordType,
+    ) -> Result<DnsPacket, RecursionError> {
+        let qname = if self.case_randomization {
+            randomize_case(name)
+        } else {
+            name.to_string()
+        };
+        let mut query = BytesMut::new();
+        codec::encode_packet(&query_packet(&qname, qtype), &mut query)
+            .map_err(|e| RecursionError::Malformed(e.to_string()))?;
+
+        let start = rand::random::<u32>() as usize % servers.len().max(1);
+        let mut last_error = RecursionError::NoAddresses(name.to_string());
+        for &server in servers
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(servers.len().min(MAX_SERVERS_TRIED))
+        {
+            match ask(&self.client, server, &query).await {
+                Ok(mut response)
+                    if matches!(response.header.rcode, Rcode::NOERROR | Rcode::NXDOMAIN) =>
+                {
+                    // Records compressed against the question carry its random case
+                    for record in response
+                        .answers
+                        .iter_mut()
+                        .chain(&mut response.authorities)
+                        .filter(|record| record.name == qname)
+                    {
+                        record.name = name.to_string();
+                    }
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    debug!("{} answered {} for {}", server, response.header.rcode, name);
+                    last_error = RecursionError::Rcode(response.header.rcode);
+                }
+                Err(e) => {
+                    debug!("{} failed for {}: {}", server, name, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// The servers of the deepest remembered zone `name` is in, or the roots
+    fn closest_delegation(&self, name: &str) -> (String, Vec<SocketAddr>) {
+        let now = Instant::now();
+        let delegations = self
+            .delegations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut suffix = name;
+        while !suffix.is_empty() {
+            if let Some(delegation) = delegations.get(suffix) {
+                if delegation.expires > now {
+                    return (suffix.to_string(), delegation.servers.clone());
+                }
+            }
+            suffix = suffix.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        (String::new(), self.roots.clone())
+    }
+
+    fn remember(&self, zone: &str, servers: &[SocketAddr], ttl: u32) {
+        let now = Instant::now();
+        let mut delegations = self
+            .delegations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if delegations.len() >= MAX_DELEGATIONS {
+            delegations.retain(|_, delegation| delegation.expires > now);
+            if delegations.len() >= MAX_DELEGATIONS {
+                return;
+            }
+        }
+        delegations.insert(
+            zone.to_string(),
+            Delegation {
+                servers: servers.to_vec(),
+                expires: now + Duration::from_secs(ttl.min(MAX_CACHE_TTL).into()),
+            },
+        );
+    }
+}
+
+/// Send a query to one server, over TCP again when the UDP answer is truncated
+async fn ask(
+    client: &UdpClient,
+    server: SocketAddr,
+    query: &[u8],
+) -> Result<DnsPacket, RecursionError> {
+    let upstream = Upstream::from(server);
+    let response = tokio::time::timeout(UPSTREAM_TIMEOUT, client.exchange(query, server))
+        .await
+        .map_err(|_| timed_out(server))??;
+    let mut response = parse(&response)?;
+    if response.header.tc {
+        let upstream = Upstream {
+            transport: Transport::Tcp,
+            ..upstream
+        };
+        let response_bytes = tokio::time::timeout(UPSTREAM_TIMEOUT, async {
+            let stream = upstream_tcp_stream(&upstream).await?;
+            exchange_stream(query, stream, &upstream).await
+        })
+        .await
+        .map_err(|_| timed_out(server))??;
+        response = parse(&response_bytes)?;
+    }
+    Ok(response)
+}
+
+fn timed_out(server: SocketAddr) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("{} did not answer in time", server),
+    )
+}
+
+fn parse(response: &[u8]) -> Result<DnsPacket, RecursionError> {
+    parse_dns_packet(response)
+        .map(|(_, packet)| packet)
+        .map_err(|e| RecursionError::Malformed(e.to_string()))
+}
+
+/// A non-recursive query for `name`
+fn query_packet(name: &str, qtype: RecordType) -> DnsPacket {
+    DnsPacket {
+        header: DnsPacketHeader {
+            id: 0,
+            qr: false,
+            opcode: Opcode::QUERY,
+            aa: false,
+            tc: false,
+            rd: false,
+            ra: false,
+            z: 0,
+            rcode: Rcode::NOERROR,
+            qdcount: 1,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        },
+        questions: vec![DnsQuestion {
+            name: name.to_string(),
+            qtype,
+            qclass: RecordClass::IN,
+        }],
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+    }
+}
+
+/// The records of an answer from a server of `zone` that lead from `name`
+/// to the records asked for, and the name to resolve next when the chain
+/// leaves the answer or the zone. The server has no say over names outside
+/// its zone, so records for them are never taken.
+fn follow_chain(
+    name: &str,
+    qtype: RecordType,
+    records: Vec<DnsResourceRecord>,
+    zone: &str,
+) -> (Vec<DnsResourceRecord>, Option<String>) {
+    let mut followed = Vec::new();
+    let mut current = name.to_string();
+    for _ in 0..=MAX_CNAMES {
+        if !is_below(&current, zone) {
+            break;
+        }
+        let owned = |record: &DnsResourceRecord| normalize_name(&record.name) == current;
+        let answers: Vec<DnsResourceRecord> = records
+            .iter()
+            .filter(|record| owned(record) && record.rtype == qtype)
+            .cloned()
+            .collect();
+        if !answers.is_empty() {
+            followed.extend(answers);
+            return (followed, None);
+        }
+        let Some(cname) = records
+            .iter()
+            .find(|record| owned(record) && record.rtype == RecordType::CNAME)
+        else {
+            break;
+        };
+        let Ok((_, target)) = parse_domain_name(&cname.rdata, &cname.rdata) else {
+            break;
+        };
+        followed.push(cname.clone());
+        current = normalize_name(&target);
+    }
+    // A chain that ends in nothing is NODATA for the name it ends at
+    let next = (current != name).then_some(current);
+    (followed, next)
+}
+
+/// A referral to the servers of a zone closer to the name asked about
+struct Referral {
+    zone: String,
+    names: Vec<String>,
+    glue: Vec<IpAddr>,
+    ttl: u32,
+}
+
+/// The referral in a response, if it has one leading below `zone`
+fn referral(response: &DnsPacket, name: &str, zone: &str) -> Option<Referral> {
+    let ns_records: Vec<&DnsResourceRecord> = response
+        .authorities
+        .iter()
+        .filter(|record| record.rtype == RecordType::NS)
+        .collect();
+    let child = normalize_name(&ns_records.first()?.name);
+    // Only a zone between the one asked and the name is progress
+    if child == zone || !is_below(&child, zone) || !is_below(name, &child) {
+        return None;
+    }
+    let ns_records: Vec<&DnsResourceRecord> = ns_records
+        .into_iter()
+        .filter(|record| normalize_name(&record.name) == child)
+        .collect();
+    let names: Vec<String> = ns_records
+        .iter()
+        .filter_map(|record| parse_domain_name(&record.rdata, &record.rdata).ok())
+        .map(|(_, target)| normalize_name(&target))
+        .collect();
+    // Glue for names outside the zone of the server that sent it isn't
+    // trusted; IPv6 glue is used when there is no IPv4
+    let glue_for = |rtype| -> Vec<IpAddr> {
+        response
+            .additionals
+            .iter()
+            .filter(|record| {
+                let owner = normalize_name(&record.name);
+                names.contains(&owner) && is_below(&owner, zone)
+            })
+            .filter_map(|record| address(record, rtype))
+            .collect()
+    };
+    let mut glue = glue_for(RecordType::A);
+    if glue.is_empty() {
+        glue = glue_for(RecordType::AAAA);
+    }
+    Some(Referral {
+        zone: child,
+        ttl: ns_records.iter().map(|record| record.ttl).min()?,
+        names,
+        glue,
+    })
+}
+
+/// The SOA of a negative response, with its TTL set to the negative caching
+/// TTL (RFC 2308)
+fn negative_soa(response: &DnsPacket) -> Option<DnsResourceRecord> {
+    let record = response
+        .authorities
+        .iter()
+        .find(|record| record.rtype == RecordType::SOA)?;
+    let soa = SoaRecord::from_resource_record(record)?;
+    Some(DnsResourceRecord {
+        ttl: record.ttl.min(soa.minimum),
+        ..record.clone()
+    })
+}
+
+/// The address in an A or AAAA record
+fn address(record: &DnsResourceRecord, rtype: RecordType) -> Option<IpAddr> {
+    if record.rtype != rtype {
+        return None;
+    }
+    match rtype {
+        RecordType::A => <[u8; 4]>::try_from(&record.rdata[..])
+            .ok()
+            .map(|octets| Ipv4Addr::from(octets).into()),
+        RecordType::AAAA => <[u8; 16]>::try_from(&record.rdata[..])
+            .ok()
+            .map(|octets| Ipv6Addr::from(octets).into()),
+        _ => None,
+    }
+}
+
+/// Whether a normalized name is `zone` or below it; every name is below the
+/// root
+fn is_below(name: &str, zone: &str) -> bool {
+    zone.is_empty() || is_in_zone(name, zone)
+}
+
+fn display_zone(zone: &str) -> String {
+    if zone.is_empty() {
+        ".".to_string()
+    } else {
+        zone.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response_builder::encode_name;
+    use tokio::net::UdpSocket;
+
+    fn record(name: &str, rtype: RecordType, ttl: u32, rdata: Vec<u8>) -> DnsResourceRecord {
+        DnsResourceRecord::new(name.to_string(), rtype, RecordClass::IN, ttl, rdata)
+    }
+
+    /// A name server answering from `respond`, which gets each query and
+    /// fills in the response
+    async fn name_server(
+        addr: SocketAddr,
+        respond: fn(&DnsQuestion, &mut DnsPacket),
+    ) -> SocketAddr {
+        let sock = UdpSocket::bind(addr).await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, client) = sock.recv_from(&mut buf).await.unwrap();
+                let (_, mut packet) = parse_dns_packet(&buf[..len]).unwrap();
+                assert!(!packet.header.rd);
+                packet.header.qr = true;
+                // Names come in random case
+                let mut question = packet.questions[0].clone();
+                question.name.make_ascii_lowercase();
+                respond(&question, &mut packet);
+                let mut response = BytesMut::new();
+                codec::encode_packet(&packet, &mut response).unwrap();
+                sock.send_to(&response, client).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    /// The root refers example.com to ns1.example.com with glue, and
+    /// example.net to ns.example.com without
+    fn root(question: &DnsQuestion, response: &mut DnsPacket) {
+        let ns = |zone: &str| record(zone, RecordType::NS, 3600, encode_name("ns1.example.com"));
+        if question.name.ends_with("example.com") {
+            response.authorities.push(ns("example.com"));
+            let glue = record("ns1.example.com", RecordType::A, 3600, vec![127, 0, 0, 3]);
+            response.additionals.push(glue);
+        } else if question.name.ends_with("example.net") {
+            response.authorities.push(ns("example.net"));
+        } else {
+            response.header.rcode = Rcode::NXDOMAIN;
+        }
+    }
+
+    /// The example.com and example.net zones
+    fn authority(question: &DnsQuestion, response: &mut DnsPacket) {
+        response.header.aa = true;
+        let soa = SoaRecord {
+            zone: "example.com".to_string(),
+            mname: "ns1.example.com".to_string(),
+            rname: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 60,
+        };
+        match (question.name.as_str(), question.qtype) {
+            ("ns1.example.com", RecordType::A) => response.answers.push(record(
+                "ns1.example.com",
+                RecordType::A,
+                300,
+                vec![127, 0, 0, 3],
+            )),
+            // Along with an address for the target that isn't the
+            // server's to give
+            ("www.example.com", _) => {
+                response.answers.push(record(
+                    "www.example.com",
+                    RecordType::CNAME,
+                    300,
+                    encode_name("web.example.net"),
+                ));
+                response.answers.push(record(
+                    "web.example.net",
+                    RecordType::A,
+                    300,
+                    vec![203, 0, 113, 66],
+                ));
+            }
+            ("alias.example.com", _) => {
+                response.answers.push(record(
+                    "alias.example.com",
+                    RecordType::CNAME,
+                    300,
+                    encode_name("host.example.com"),
+                ));
+                response.answers.push(record(
+                    "host.example.com",
+                    RecordType::A,
+                    300,
+                    vec![192, 0, 2, 7],
+                ));
+            }
+            ("web.example.net", RecordType::A) => response.answers.push(record(
+                "web.example.net",
+                RecordType::A,
+                120,
+                vec![192, 0, 2, 1],
+            )),
+            ("web.example.net", _) => {}
+            _ => {
+                response.header.rcode = Rcode::NXDOMAIN;
+                response.authorities.push(soa.to_resource_record(3600));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_iteratively() {
+        // Both servers on one port, as glue carries no port
+        let root = name_server("127.0.0.2:0".parse().unwrap(), root).await;
+        let authority_addr = SocketAddr::new([127, 0, 0, 3].into(), root.port());
+        name_server(authority_addr, authority).await;
+        let recursor = Recursor::new(vec![root], root.port());
+
+        // Through the glue, then from the top again for the CNAME target
+        // outside the zone, whose name server has no glue
+        let Resolution::Records(records) = recursor.resolve("WWW.example.com", RecordType::A).await
+        else {
+            panic!("expected records");
+        };
+        let answers: Vec<(&str, RecordType)> = records
+            .iter()
+            .map(|record| (record.name.as_str(), record.rtype))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                ("www.example.com", RecordType::CNAME),
+                ("web.example.net", RecordType::A)
+            ]
+        );
+        assert_eq!(records[1].rdata, [192, 0, 2, 1]);
+
+        // A chain within the zone is taken from the one answer
+        let Resolution::Records(records) =
+            recursor.resolve("alias.example.com", RecordType::A).await
+        else {
+            panic!("expected records");
+        };
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].name, "host.example.com");
+        assert_eq!(records[1].rdata, [192, 0, 2, 7]);
+        assert_eq!(
+            recursor.closest_delegation("a.b.example.com").0,
+            "example.com"
+        );
+
+        assert!(matches!(
+            recursor.resolve("missing.example.com", RecordType::A).await,
+            Resolution::Negative { rcode: Rcode::NXDOMAIN, soa: Some(soa) } if soa.ttl == 60
+        ));
+        assert!(matches!(
+            recursor.resolve("web.example.net", RecordType::AAAA).await,
+            Resolution::Negative {
+                rcode: Rcode::NOERROR,
+                soa: None
+            }
+        ));
+        assert!(matches!(
+            recursor.resolve("example.org", RecordType::A).await,
+            Resolution::Negative {
+                rcode: Rcode::NXDOMAIN,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_follow_chain() {
+        let cname =
+            |name: &str, target: &str| record(name, RecordType::CNAME, 300, encode_name(target));
+        let a = |name: &str| record(name, RecordType::A, 300, vec![192, 0, 2, 1]);
+
+        // Within the zone, to the end
+        let records = vec![
+            cname("www.example.com", "web.example.com"),
+            cname("web.example.com", "host.web.example.com"),
+            a("host.web.example.com"),
+        ];
+        let (followed, next) =
+            follow_chain("www.example.com", RecordType::A, records, "example.com");
+        assert_eq!(followed.len(), 3);
+        assert_eq!(next, None);
+
+        // Up to the target outside the zone, whatever came with it
+        let records = vec![
+            cname("www.example.com", "web.example.com"),
+            cname("web.example.com", "cdn.example.net"),
+            a("cdn.example.net"),
+        ];
+        let (followed, next) = follow_chain(
+            "www.example.com",
+            RecordType::A,
+            records.clone(),
+            "example.com",
+        );
+        assert_eq!(followed.len(), 2);
+        assert_eq!(next.as_deref(), Some("cdn.example.net"));
+        // The root's servers speak for every name
+        let (followed, next) = follow_chain("www.example.com", RecordType::A, records, "");
+        assert_eq!(followed.len(), 3);
+        assert_eq!(next, None);
+
+        // A chain leading nowhere is resolved from its last name
+        let records = vec![cname("www.example.com", "web.example.com")];
+        let (followed, next) =
+            follow_chain("www.example.com", RecordType::A, records, "example.com");
+        assert_eq!(followed.len(), 1);
+        assert_eq!(next.as_deref(), Some("web.example.com"));
+    }
+}
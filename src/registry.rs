@@ -0,0 +1,312 @@
+//! IANA DNS parameters
+//!
+//! Record types, classes, response codes and opcodes as thin wrappers around
+//! their wire values, named after the IANA "Domain Name System (DNS)
+//! Parameters" registries. Each shows as its mnemonic, or in the generic
+//! form (`TYPE65280`, `CLASS32`, `RCODE3841`, `OPCODE9`; RFC 3597 style) when
+//! it has none, and parses back from either, ignoring case. With serde they
+//! are written as text and read from text or a bare number.
+//!
+//! The values are plain data: any number is a valid `RecordType`, known or
+//! not, so unknown types pass through the server untouched.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::errors::RegistryError;
+
+macro_rules! registry {
+    (
+        $(#[$meta:meta])*
+        $name:ident($repr:ty), $kind:literal, $generic:literal {
+            $($(#[$item_meta:meta])* $item:ident = $value:literal => $mnemonic:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(pub $repr);
+
+        #[allow(dead_code)]
+        impl $name {
+            $($(#[$item_meta])* pub const $item: $name = $name($value);)*
+
+            /// Every entry of the registry, in numeric order
+            pub const ALL: &'static [$name] = &[$($name::$item),*];
+
+            /// The registered mnemonic, if there is one
+            pub fn mnemonic(self) -> Option<&'static str> {
+                match self.0 {
+                    $($value => Some($mnemonic),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl From<$repr> for $name {
+            fn from(value: $repr) -> Self {
+                $name(value)
+            }
+        }
+
+        impl From<$name> for $repr {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                // Padding applies, so these line up in tables and logs
+                match self.mnemonic() {
+                    Some(mnemonic) => f.pad(mnemonic),
+                    None => f.pad(&format!(concat!($generic, "{}"), self.0)),
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = RegistryError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let unknown = || RegistryError::Unknown {
+                    kind: $kind,
+                    value: s.to_string(),
+                };
+                let upper = s.trim().to_ascii_uppercase();
+                $(if upper == $mnemonic {
+                    return Ok($name::$item);
+                })*
+                let number = upper.strip_prefix($generic).unwrap_or(&upper);
+                number.parse().map($name).map_err(|_| unknown())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct ParameterVisitor;
+
+                impl Visitor<'_> for ParameterVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        write!(f, concat!("a DNS ", $kind, " mnemonic or number"))
+                    }
+
+                    fn visit_str<E: de::Error>(self, s: &str) -> Result<$name, E> {
+                        s.parse().map_err(E::custom)
+                    }
+
+                    fn visit_u64<E: de::Error>(self, n: u64) -> Result<$name, E> {
+                        <$repr>::try_from(n)
+                            .map($name)
+                            .map_err(|_| E::custom(format!(concat!($kind, " {} out of range"), n)))
+                    }
+                }
+
+                deserializer.deserialize_any(ParameterVisitor)
+            }
+        }
+    };
+}
+
+registry! {
+    /// A resource record type (the TYPE and QTYPE fields)
+    RecordType(u16), "record type", "TYPE" {
+        A = 1 => "A",
+        NS = 2 => "NS",
+        MD = 3 => "MD",
+        MF = 4 => "MF",
+        CNAME = 5 => "CNAME",
+        SOA = 6 => "SOA",
+        MB = 7 => "MB",
+        MG = 8 => "MG",
+        MR = 9 => "MR",
+        NULL = 10 => "NULL",
+        WKS = 11 => "WKS",
+        PTR = 12 => "PTR",
+        HINFO = 13 => "HINFO",
+        MINFO = 14 => "MINFO",
+        MX = 15 => "MX",
+        TXT = 16 => "TXT",
+        RP = 17 => "RP",
+        AFSDB = 18 => "AFSDB",
+        X25 = 19 => "X25",
+        ISDN = 20 => "ISDN",
+        RT = 21 => "RT",
+        NSAP = 22 => "NSAP",
+        NSAP_PTR = 23 => "NSAP-PTR",
+        SIG = 24 => "SIG",
+        KEY = 25 => "KEY",
+        PX = 26 => "PX",
+        GPOS = 27 => "GPOS",
+        AAAA = 28 => "AAAA",
+        LOC = 29 => "LOC",
+        NXT = 30 => "NXT",
+        EID = 31 => "EID",
+        NIMLOC = 32 => "NIMLOC",
+        SRV = 33 => "SRV",
+        ATMA = 34 => "ATMA",
+        NAPTR = 35 => "NAPTR",
+        KX = 36 => "KX",
+        CERT = 37 => "CERT",
+        A6 = 38 => "A6",
+        DNAME = 39 => "DNAME",
+        SINK = 40 => "SINK",
+        OPT = 41 => "OPT",
+        APL = 42 => "APL",
+        DS = 43 => "DS",
+        SSHFP = 44 => "SSHFP",
+        IPSECKEY = 45 => "IPSECKEY",
+        RRSIG = 46 => "RRSIG",
+        NSEC = 47 => "NSEC",
+        DNSKEY = 48 => "DNSKEY",
+        DHCID = 49 => "DHCID",
+        NSEC3 = 50 => "NSEC3",
+        NSEC3PARAM = 51 => "NSEC3PARAM",
+        TLSA = 52 => "TLSA",
+        SMIMEA = 53 => "SMIMEA",
+        HIP = 55 => "HIP",
+        NINFO = 56 => "NINFO",
+        RKEY = 57 => "RKEY",
+        TALINK = 58 => "TALINK",
+        CDS = 59 => "CDS",
+        CDNSKEY = 60 => "CDNSKEY",
+        OPENPGPKEY = 61 => "OPENPGPKEY",
+        CSYNC = 62 => "CSYNC",
+        ZONEMD = 63 => "ZONEMD",
+        SVCB = 64 => "SVCB",
+        HTTPS = 65 => "HTTPS",
+        DSYNC = 66 => "DSYNC",
+        HHIT = 67 => "HHIT",
+        BRID = 68 => "BRID",
+        SPF = 99 => "SPF",
+        UINFO = 100 => "UINFO",
+        UID = 101 => "UID",
+        GID = 102 => "GID",
+        UNSPEC = 103 => "UNSPEC",
+        NID = 104 => "NID",
+        L32 = 105 => "L32",
+        L64 = 106 => "L64",
+        LP = 107 => "LP",
+        EUI48 = 108 => "EUI48",
+        EUI64 = 109 => "EUI64",
+        NXNAME = 128 => "NXNAME",
+        TKEY = 249 => "TKEY",
+        TSIG = 250 => "TSIG",
+        IXFR = 251 => "IXFR",
+        /// Zone transfer (query type only)
+        AXFR = 252 => "AXFR",
+        MAILB = 253 => "MAILB",
+        MAILA = 254 => "MAILA",
+        ANY = 255 => "ANY",
+        URI = 256 => "URI",
+        CAA = 257 => "CAA",
+        AVC = 258 => "AVC",
+        DOA = 259 => "DOA",
+        AMTRELAY = 260 => "AMTRELAY",
+        RESINFO = 261 => "RESINFO",
+        WALLET = 262 => "WALLET",
+        CLA = 263 => "CLA",
+        IPN = 264 => "IPN",
+        TA = 32768 => "TA",
+        DLV = 32769 => "DLV",
+    }
+}
+
+registry! {
+    /// A resource record class (the CLASS and QCLASS fields)
+    RecordClass(u16), "class", "CLASS" {
+        IN = 1 => "IN",
+        CH = 3 => "CH",
+        HS = 4 => "HS",
+        NONE = 254 => "NONE",
+        ANY = 255 => "ANY",
+    }
+}
+
+registry! {
+    /// A response code. The header carries the low four bits; the rest
+    /// only exist in EDNS and TSIG.
+    Rcode(u16), "response code", "RCODE" {
+        NOERROR = 0 => "NOERROR",
+        FORMERR = 1 => "FORMERR",
+        SERVFAIL = 2 => "SERVFAIL",
+        NXDOMAIN = 3 => "NXDOMAIN",
+        NOTIMP = 4 => "NOTIMP",
+        REFUSED = 5 => "REFUSED",
+        YXDOMAIN = 6 => "YXDOMAIN",
+        YXRRSET = 7 => "YXRRSET",
+        NXRRSET = 8 => "NXRRSET",
+        NOTAUTH = 9 => "NOTAUTH",
+        NOTZONE = 10 => "NOTZONE",
+        DSOTYPENI = 11 => "DSOTYPENI",
+        /// Also BADSIG in TSIG
+        BADVERS = 16 => "BADVERS",
+        BADKEY = 17 => "BADKEY",
+        BADTIME = 18 => "BADTIME",
+        BADMODE = 19 => "BADMODE",
+        BADNAME = 20 => "BADNAME",
+        BADALG = 21 => "BADALG",
+        BADTRUNC = 22 => "BADTRUNC",
+        BADCOOKIE = 23 => "BADCOOKIE",
+    }
+}
+
+registry! {
+    /// A header opcode
+    Opcode(u8), "opcode", "OPCODE" {
+        QUERY = 0 => "QUERY",
+        IQUERY = 1 => "IQUERY",
+        STATUS = 2 => "STATUS",
+        NOTIFY = 4 => "NOTIFY",
+        UPDATE = 5 => "UPDATE",
+        DSO = 6 => "DSO",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_round_trip() {
+        for rtype in RecordType::ALL {
+            assert_eq!(rtype.to_string().parse::<RecordType>().unwrap(), *rtype);
+        }
+        assert_eq!(RecordType::NSAP_PTR.to_string(), "NSAP-PTR");
+        assert_eq!(RecordType(65280).to_string(), "TYPE65280");
+        assert_eq!(
+            "type65280".parse::<RecordType>().unwrap(),
+            RecordType(65280)
+        );
+        assert_eq!("aaaa".parse::<RecordType>().unwrap(), RecordType::AAAA);
+        assert_eq!("28".parse::<RecordType>().unwrap(), RecordType::AAAA);
+        assert!("BOGUS".parse::<RecordType>().is_err());
+        assert!("TYPE70000".parse::<RecordType>().is_err());
+
+        assert_eq!(Rcode::NXDOMAIN.to_string(), "NXDOMAIN");
+        assert_eq!(Rcode(12).to_string(), "RCODE12");
+        assert_eq!("ch".parse::<RecordClass>().unwrap(), RecordClass::CH);
+        assert_eq!(Opcode(3).to_string(), "OPCODE3");
+    }
+
+    #[test]
+    fn test_serde() {
+        assert_eq!(serde_json::to_string(&RecordType::MX).unwrap(), r#""MX""#);
+        assert_eq!(
+            serde_json::from_str::<RecordType>(r#""txt""#).unwrap(),
+            RecordType::TXT
+        );
+        assert_eq!(serde_json::from_str::<Rcode>("3").unwrap(), Rcode::NXDOMAIN);
+        assert!(serde_json::from_str::<Opcode>("256").is_err());
+    }
+}
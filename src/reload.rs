@@ -0,0 +1,197 @@
+//! Reloading blocklists and zone files on demand
+//!
+//! `reload-blocklists` and `reload-zone <zone>` on the control socket reload
+//! one kind of data of the tenant they are sent to, and SIGHUP reloads all of
+//! it, for every tenant. Nothing is restarted: each blocklist is built on the
+//! side and swapped in whole, and each zone file replaces its zone in a single
+//! change of the local records, so queries go on being answered throughout
+//! and never see half of a reload. Whatever fails to load is logged and the
+//! data it would have replaced stays.
+
+use std::sync::Arc;
+
+use tracing::{info, warn};
+
+use crate::blocklist::PublishedBlocklist;
+use crate::errors::ZoneFileError;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::local_records::{normalize_name, ImportDiff};
+use crate::zone_file::ZoneFile;
+
+/// A tenant's reloadable data: its blocklists and its zone files
+#[derive(Debug, Clone, Default)]
+pub struct Reloader {
+    /// The tenant's blocklist and its groups' own, by what they are called
+    /// in messages
+    blocklists: Vec<(String, Arc<PublishedBlocklist>)>,
+    /// Zone files, with the local records each is loaded into
+    zone_files: Vec<(ZoneFile, LocalRecordsHandle)>,
+}
+
+impl Reloader {
+    /// Reload `blocklist` along with the others, if it has lists to reload
+    pub fn add_blocklist(&mut self, name: impl Into<String>, blocklist: &Arc<PublishedBlocklist>) {
+        if blocklist.has_sources() {
+            self.blocklists.push((name.into(), Arc::clone(blocklist)));
+        }
+    }
+
+    /// Reload `files` into `local_records`
+    pub fn add_zone_files(&mut self, files: &[ZoneFile], local_records: &LocalRecordsHandle) {
+        self.zone_files.extend(
+            files
+                .iter()
+                .map(|file| (absolute(file), local_records.clone())),
+        );
+    }
+
+    pub fn has_blocklists(&self) -> bool {
+        !self.blocklists.is_empty()
+    }
+
+    /// Reload every blocklist, each on its own, returning how many domains
+    /// each now blocks or why it was kept
+    pub async fn reload_blocklists(&self) -> Vec<(&str, anyhow::Result<usize>)> {
+        let mut results = Vec::with_capacity(self.blocklists.len());
+        for (name, blocklist) in &self.blocklists {
+            let result = blocklist.reload().await;
+            match &result {
+                Ok(domains) => info!("Reloaded {} ({} domains)", name, domains),
+                Err(e) => warn!("Keeping the current {}: {:#}", name, e),
+            }
+            results.push((name.as_str(), result));
+        }
+        results
+    }
+
+    /// Reload the files of a zone, returning what each changed or why it
+    /// was kept; none if no file holds the zone
+    pub async fn reload_zone(
+        &self,
+        zone: &str,
+    ) -> Vec<(&ZoneFile, Result<ImportDiff, ZoneFileError>)> {
+        let zone = normalize_name(zone);
+        let mut results = Vec::new();
+        for (file, local_records) in self.zone_files.iter().filter(|(file, _)| file.zone == zone) {
+            results.push((file, reload_file(file, local_records).await));
+        }
+        results
+    }
+
+    /// Reload everything, logging how it went
+    pub async fn reload_all(&self) {
+        self.reload_blocklists().await;
+        for (file, local_records) in &self.zone_files {
+            // Changes are logged as they are loaded
+            let _ = reload_file(file, local_records).await;
+        }
+    }
+}
+
+async fn reload_file(
+    file: &ZoneFile,
+    local_records: &LocalRecordsHandle,
+) -> Result<ImportDiff, ZoneFileError> {
+    let result = file.reload(local_records).await;
+    if let Err(e) = &result {
+        warn!(
+            "Zone file {} not reloaded, still serving the previous version: {}",
+            file.path.display(),
+            e
+        );
+    }
+    result
+}
+
+/// The file with its path made absolute, as the watcher has it, so a
+/// change of working directory (`--daemon`) doesn't lose it
+fn absolute(file: &ZoneFile) -> ZoneFile {
+    ZoneFile {
+        zone: file.zone.clone(),
+        path: std::path::absolute(&file.path).unwrap_or_else(|_| file.path.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocklist::{Allowlist, BlockMode, Blocklist, BlocklistSource};
+    use crate::geoip::ClientLocation;
+    use crate::registry::{RecordClass, RecordType};
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("dns-server-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let list = dir.join("ads.txt");
+        let zone = dir.join("lab.csv");
+        std::fs::write(&list, "ads.example\n").unwrap();
+        std::fs::write(&zone, "name,type,value,ttl\nnas.lab,A,10.0.0.2,300\n").unwrap();
+
+        let sources = vec![BlocklistSource::File(list.clone())];
+        let blocklist = Arc::new(
+            PublishedBlocklist::new(
+                Blocklist::load(&sources, BlockMode::Nxdomain).unwrap(),
+                Allowlist::default(),
+            )
+            .with_sources(sources),
+        );
+        let local_records = LocalRecordsHandle::new();
+        let file = ZoneFile {
+            zone: "lab".to_string(),
+            path: zone.clone(),
+        };
+        let mut reloader = Reloader::default();
+        reloader.add_blocklist("blocklist", &blocklist);
+        reloader.add_zone_files(std::slice::from_ref(&file), &local_records);
+        reloader.reload_all().await;
+
+        let blocked = |name| {
+            blocklist
+                .check(name, RecordType::A, RecordClass::IN)
+                .is_some()
+        };
+        let address = |name| {
+            local_records
+                .lookup(name, RecordType::A, &ClientLocation::default())
+                .len()
+        };
+        assert!(blocked("ads.example"));
+        assert_eq!(address("nas.lab"), 1);
+
+        std::fs::write(&list, "tracker.example\n").unwrap();
+        std::fs::write(&zone, "name,type,value,ttl\nprinter.lab,A,10.0.0.3,300\n").unwrap();
+        let results = reloader.reload_blocklists().await;
+        assert!(matches!(results[..], [("blocklist", Ok(1))]));
+        assert!(!blocked("ads.example"));
+        assert!(blocked("tracker.example"));
+
+        let results = reloader.reload_zone("Lab.").await;
+        assert!(
+            matches!(&results[..], [(_, Ok(diff))] if diff.added.len() == 1 && diff.removed.len() == 1)
+        );
+        assert_eq!(address("nas.lab"), 0);
+        assert_eq!(address("printer.lab"), 1);
+        assert!(reloader.reload_zone("example.com").await.is_empty());
+
+        // What fails to load is kept
+        std::fs::remove_file(&list).unwrap();
+        std::fs::write(
+            &zone,
+            "name,type,value,ttl\nnas.example.com,A,10.0.0.2,300\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            reloader.reload_blocklists().await[..],
+            [(_, Err(_))]
+        ));
+        assert!(blocked("tracker.example"));
+        assert!(matches!(
+            reloader.reload_zone("lab").await[..],
+            [(_, Err(_))]
+        ));
+        assert_eq!(address("printer.lab"), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,79 +1,231 @@
+use crate::edns::Edns;
+use crate::names::name_to_labels;
+use crate::parsers::parse_domain_name;
 use crate::protocol::{DnsPacket, DnsPacketHeader, DnsQuestion, DnsResourceRecord};
+use crate::registry::{Opcode, Rcode, RecordClass, RecordType};
+#[cfg(test)]
 use std::net::{IpAddr, Ipv6Addr};
 
-// DNS Record Type Constants
-pub const DNS_TYPE_A: u16 = 1; // IPv4 address
-pub const DNS_TYPE_NS: u16 = 2; // Name server
-pub const DNS_TYPE_CNAME: u16 = 5; // Canonical name
-pub const DNS_TYPE_SOA: u16 = 6; // Start of authority
-pub const DNS_TYPE_PTR: u16 = 12; // Pointer record
-pub const DNS_TYPE_MX: u16 = 15; // Mail exchange
-pub const DNS_TYPE_TXT: u16 = 16; // Text record
-pub const DNS_TYPE_AAAA: u16 = 28; // IPv6 address
+/// Encode a domain name in uncompressed label format
+pub fn encode_name(name: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(name.len() + 2);
+    for label in name_to_labels(name) {
+        data.push(label.len() as u8);
+        data.extend_from_slice(&label);
+    }
+    data.push(0);
+    data
+}
+
+/// Negative caching TTL of the zones the server answers for itself
+pub const LOCAL_NEGATIVE_TTL: u32 = 300;
+
+/// Start-of-authority data for a zone, sent in the authority section of
+/// negative answers so clients can cache them (RFC 2308)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoaRecord {
+    pub zone: String,
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+impl SoaRecord {
+    /// The SOA of a zone the server answers for itself. Nothing transfers
+    /// such a zone, so the serial only has to be plausible.
+    pub fn local(zone: &str, serial: u32) -> Self {
+        Self {
+            zone: zone.to_string(),
+            mname: zone.to_string(),
+            rname: format!("hostmaster.{}", zone),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 604800,
+            minimum: LOCAL_NEGATIVE_TTL,
+        }
+    }
+
+    /// The SOA as a resource record. For negative answers `ttl` should be the
+    /// negative caching TTL, i.e. the lesser of the SOA's own TTL and `minimum`.
+    pub fn to_resource_record(&self, ttl: u32) -> DnsResourceRecord {
+        let mut data = encode_name(&self.mname);
+        data.extend(encode_name(&self.rname));
+        for value in [
+            self.serial,
+            self.refresh,
+            self.retry,
+            self.expire,
+            self.minimum,
+        ] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        DnsResourceRecord::new(
+            self.zone.clone(),
+            RecordType::SOA,
+            RecordClass::IN,
+            ttl,
+            data,
+        )
+    }
+
+    /// Read an SOA resource record whose RDATA names are uncompressed, as
+    /// `parsers::parse_dns_packet` leaves them
+    pub fn from_resource_record(record: &DnsResourceRecord) -> Option<Self> {
+        if record.rtype != RecordType::SOA {
+            return None;
+        }
+        let (rest, mname) = parse_domain_name(&record.rdata, &record.rdata).ok()?;
+        let (rest, rname) = parse_domain_name(&record.rdata, rest).ok()?;
+        let counters: Vec<u32> = rest
+            .chunks_exact(4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        let [serial, refresh, retry, expire, minimum] = counters[..] else {
+            return None;
+        };
+        Some(Self {
+            zone: record.name.clone(),
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        })
+    }
+}
 
-// DNS Class Constants
-pub const DNS_CLASS_IN: u16 = 1; // Internet
+/// A naming authority pointer (NAPTR, RFC 3403), as used for ENUM and SIP
+/// service discovery, e.g. `100 10 "S" "SIP+D2U" "" _sip._udp.example.com`
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NaptrRecord {
+    pub name: String,
+    /// Records are processed in increasing order
+    pub order: u16,
+    /// Tie-breaker among records of the same order
+    pub preference: u16,
+    pub flags: String,
+    pub services: String,
+    pub regexp: String,
+    /// The next name to look up, "." when `regexp` gives the result
+    pub replacement: String,
+}
+
+#[cfg(test)]
+impl NaptrRecord {
+    /// The NAPTR as a resource record. The replacement is never compressed
+    /// (RFC 3403 §4.1) and the text fields are cut to the 255 bytes a
+    /// character string holds.
+    pub fn to_resource_record(&self, ttl: u32) -> DnsResourceRecord {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.order.to_be_bytes());
+        data.extend_from_slice(&self.preference.to_be_bytes());
+        for text in [&self.flags, &self.services, &self.regexp] {
+            let bytes = &text.as_bytes()[..text.len().min(255)];
+            data.push(bytes.len() as u8);
+            data.extend_from_slice(bytes);
+        }
+        data.extend(encode_name(&self.replacement));
+        DnsResourceRecord::new(
+            self.name.clone(),
+            RecordType::NAPTR,
+            RecordClass::IN,
+            ttl,
+            data,
+        )
+    }
+
+    /// Read a NAPTR resource record whose replacement is uncompressed, as
+    /// `parsers::parse_dns_packet` leaves it
+    pub fn from_resource_record(record: &DnsResourceRecord) -> Option<Self> {
+        if record.rtype != RecordType::NAPTR {
+            return None;
+        }
+        let (order, rest) = record.rdata.split_first_chunk::<2>()?;
+        let (preference, mut rest) = rest.split_first_chunk::<2>()?;
+        let mut texts = Vec::with_capacity(3);
+        for _ in 0..3 {
+            let (&len, tail) = rest.split_first()?;
+            let (text, tail) = tail.split_at_checked(len.into())?;
+            texts.push(String::from_utf8(text.to_vec()).ok()?);
+            rest = tail;
+        }
+        let (rest, replacement) = parse_domain_name(rest, rest).ok()?;
+        if !rest.is_empty() {
+            return None;
+        }
+        let [flags, services, regexp] = <[String; 3]>::try_from(texts).ok()?;
+        Some(Self {
+            name: record.name.clone(),
+            order: u16::from_be_bytes(*order),
+            preference: u16::from_be_bytes(*preference),
+            flags,
+            services,
+            regexp,
+            replacement,
+        })
+    }
+}
+
+/// A question together with the answers given for it. Once the builder moves
+/// on to the next question the group is sealed and never touched again, so
+/// answers can't drift to another question.
+#[derive(Debug, Clone)]
+struct QuestionGroup {
+    question: DnsQuestion,
+    answers: Vec<DnsResourceRecord>,
+}
+
+impl QuestionGroup {
+    fn new(name: &str, qtype: RecordType, qclass: RecordClass) -> Self {
+        Self {
+            question: DnsQuestion {
+                name: name.to_string(),
+                qtype,
+                qclass,
+            },
+            answers: Vec::new(),
+        }
+    }
+}
 
 /// Builder for creating DNS response packets efficiently
 pub struct DnsResponseBuilder {
     // Pre-allocated response header template
     response_header: DnsPacketHeader,
-    // Reusable questions vector
-    questions: Vec<DnsQuestion>,
-    // Reusable answers vector
-    answers: Vec<DnsResourceRecord>,
 }
 
 impl DnsResponseBuilder {
-    /// Create a new response builder
-    pub fn new() -> Self {
+    /// Create a new response builder. It holds nothing but the header
+    /// template, so one constant can serve every request.
+    pub const fn new() -> Self {
         Self {
             response_header: DnsPacketHeader {
                 id: 0,
-                qr: true,  // Always a response
-                opcode: 0, // QUERY
+                qr: true, // Always a response
+                opcode: Opcode::QUERY,
                 aa: false, // Not authoritative by default
                 tc: false, // Not truncated
                 rd: false, // Will be copied from query
                 ra: true,  // Recursion available
                 z: 0,      // Reserved
-                rcode: 0,  // NOERROR by default
+                rcode: Rcode::NOERROR,
                 qdcount: 0,
                 ancount: 0,
                 nscount: 0,
                 arcount: 0,
             },
-            questions: Vec::new(),
-            answers: Vec::new(),
-        }
-    }
-
-    /// Get the current number of answers (for debugging)
-    pub fn answers_count(&self) -> usize {
-        self.answers.len()
-    }
-
-    /// Clear accumulated answers (for reusing builder)
-    pub fn clear_answers(&mut self) {
-        self.answers.clear();
-    }
-
-    /// Build a response from a query packet without cloning
-    pub fn build_response(&mut self, query_packet: &DnsPacket) -> DnsPacket {
-        // Copy primitive fields (no heap allocation)
-        self.response_header.id = query_packet.header.id; // Echo the query ID
-        self.response_header.rd = query_packet.header.rd; // Copy recursion desired
-        self.response_header.qdcount = query_packet.header.qdcount;
-        self.response_header.ancount = query_packet.header.qdcount; // Answer count = question count
-
-        DnsPacket {
-            header: self.response_header,
-            questions: query_packet.questions.clone(), // Still need to clone here for ownership
-            answers: self.answers.clone(),
         }
     }
 
-    /// More efficient version that takes ownership and reuses the packet
+    // More efficient version that takes ownership and reuses the packet
     // pub fn build_response_owned(&mut self, mut query_packet: DnsPacket) -> DnsPacket {
     //     // Modify the header in place
     //     query_packet.header.qr = true;
@@ -85,38 +237,17 @@ impl DnsResponseBuilder {
     //     query_packet
     // }
 
-    /// Build response with custom settings
-    pub fn build_custom_response<'a>(
-        &'a mut self,
-        query_packet: &'a DnsPacket,
-    ) -> ResponseBuilder<'a> {
+    /// Build response with custom settings. Each call starts from the template
+    /// header with no questions or answers, so the builder can be reused.
+    pub fn build_custom_response<'a>(&self, query_packet: &'a DnsPacket) -> ResponseBuilder<'a> {
         ResponseBuilder {
-            builder: self,
-            query_packet,
-        }
-    }
-
-    /// Create a response for a specific domain query (A record)
-    pub fn build_domain_response(
-        &mut self,
-        domain: &str,
-        dns_resource_record: DnsResourceRecord,
-        query_id: u16,
-    ) -> DnsPacket {
-        self.response_header.id = query_id;
-        self.response_header.qdcount = 1;
-        self.response_header.ancount = 1;
-
-        let question = DnsQuestion {
-            name: domain.to_string(),
-            qtype: 1,  // A record
-            qclass: 1, // IN (Internet)
-        };
-
-        DnsPacket {
             header: self.response_header,
-            questions: vec![question],
-            answers: vec![dns_resource_record], // Convert to Vec<DnsResourceRecord>
+            query_packet,
+            groups: Vec::new(),
+            open: None,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: None,
         }
     }
 
@@ -142,158 +273,266 @@ impl DnsResponseBuilder {
     // }
 }
 
-/// Fluent interface for building custom responses
+/// Fluent interface for building custom responses.
+///
+/// Questions and their answers are collected as groups: `with_question` (or
+/// one of the `with_*_record` shorthands) opens a group, and the answer methods
+/// add to the open group. An answer given before any question opens a group
+/// for its own name and type. Counts in the header are derived from the
+/// groups when the packet is built.
 pub struct ResponseBuilder<'a> {
-    builder: &'a mut DnsResponseBuilder,
+    header: DnsPacketHeader,
     query_packet: &'a DnsPacket,
+    // Sealed groups, in question order
+    groups: Vec<QuestionGroup>,
+    // The group answers are currently added to
+    open: Option<QuestionGroup>,
+    // Authority section (e.g. the zone SOA on negative answers)
+    authorities: Vec<DnsResourceRecord>,
+    // Additional section (e.g. addresses of MX targets), ahead of the OPT record
+    additionals: Vec<DnsResourceRecord>,
+    // The OPT record to answer with, when the query had one
+    edns: Option<Edns>,
 }
 
 impl<'a> ResponseBuilder<'a> {
-    // /// Get the current number of answers (for debugging)
-    // pub fn answers_count(&self) -> usize {
-    //     self.builder.answers.len()
-    // }
-
     /// Set response code
-    pub fn with_rcode(self, rcode: u8) -> Self {
-        self.builder.response_header.rcode = rcode;
+    pub fn with_rcode(mut self, rcode: Rcode) -> Self {
+        self.header.rcode = rcode;
+        self
+    }
+
+    /// Answer with an OPT record. Its extended RCODE is filled in from the
+    /// response code when the packet is built.
+    pub fn with_edns(mut self, edns: Edns) -> Self {
+        self.edns = Some(edns);
         self
     }
 
     /// Set qr (query/response) flag
-    pub fn with_qr(self, qr: bool) -> Self {
-        self.builder.response_header.qr = qr;
+    pub fn with_qr(mut self, qr: bool) -> Self {
+        self.header.qr = qr;
         self
     }
 
     /// Set reserved bits (z)
-    pub fn with_z(self, z: u8) -> Self {
-        self.builder.response_header.z = z;
+    pub fn with_z(mut self, z: u8) -> Self {
+        self.header.z = z;
         self
     }
 
     /// Set authoritative flag
-    pub fn with_authoritative(self, aa: bool) -> Self {
-        self.builder.response_header.aa = aa;
+    pub fn with_authoritative(mut self, aa: bool) -> Self {
+        self.header.aa = aa;
         self
     }
 
     /// Set recursion available flag
-    pub fn with_recursion_available(self, ra: bool) -> Self {
-        self.builder.response_header.ra = ra;
+    pub fn with_recursion_available(mut self, ra: bool) -> Self {
+        self.header.ra = ra;
         self
     }
 
-    /// Add a custom question to the response
-    pub fn with_question(self, domain: &str, qtype: u16, qclass: u16) -> Self {
-        let question = DnsQuestion {
-            name: domain.to_string(),
-            qtype,
-            qclass,
-        };
-        self.builder.questions.clear();
-        self.builder.questions.push(question);
-        self.builder.response_header.qdcount = 1;
-        self.builder.response_header.ancount = 1; // Assume we'll provide an answer
+    /// Add a question to the response; answers added after it belong to it
+    pub fn with_question(mut self, domain: &str, qtype: RecordType, qclass: RecordClass) -> Self {
+        self.seal();
+        self.open = Some(QuestionGroup::new(domain, qtype, qclass));
         self
     }
 
+    /// Add a pre-built resource record to the answers of the open question
+    pub fn with_answer(mut self, record: DnsResourceRecord) -> Self {
+        self.open
+            .get_or_insert_with(|| QuestionGroup::new(&record.name, record.rtype, record.rclass))
+            .answers
+            .push(record);
+        self
+    }
+
+    /// Add a record to the authority section
+    pub fn with_authority(mut self, record: DnsResourceRecord) -> Self {
+        self.authorities.push(record);
+        self
+    }
+
+    /// Add a record to the additional section, once
+    pub fn with_additional(mut self, record: DnsResourceRecord) -> Self {
+        let duplicate = self.additionals.iter().any(|added| {
+            added.name == record.name && added.rtype == record.rtype && added.rdata == record.rdata
+        });
+        if !duplicate {
+            self.additionals.push(record);
+        }
+        self
+    }
+
+    /// Close the open group, if any
+    fn seal(&mut self) {
+        if let Some(group) = self.open.take() {
+            self.groups.push(group);
+        }
+    }
+
+    /// Build the final response. Without any questions added, the query's
+    /// questions are echoed back unanswered.
+    pub fn build(mut self) -> DnsPacket {
+        self.seal();
+
+        // Copy the query ID to the response
+        self.header.id = self.query_packet.header.id;
+        self.header.rd = self.query_packet.header.rd;
+        self.header.opcode = self.query_packet.header.opcode;
+
+        // Keep the configured rcode for a standard query, NOTIMP otherwise
+        if self.query_packet.header.opcode != Opcode::QUERY {
+            self.header.rcode = Rcode::NOTIMP;
+        }
+
+        // The groups' questions and answers are moved out, not copied
+        let (questions, answers) = if self.groups.is_empty() {
+            (self.query_packet.questions.clone(), Vec::new())
+        } else {
+            let mut questions = Vec::with_capacity(self.groups.len());
+            let mut answers =
+                Vec::with_capacity(self.groups.iter().map(|group| group.answers.len()).sum());
+            for group in self.groups {
+                questions.push(group.question);
+                answers.extend(group.answers);
+            }
+            (questions, answers)
+        };
+        self.header.qdcount = questions.len() as u16;
+        self.header.ancount = answers.len() as u16;
+        self.header.nscount = self.authorities.len() as u16;
+
+        // The header holds the low 4 bits of the response code, the OPT
+        // record the rest
+        let mut additionals = self.additionals;
+        additionals.extend(self.edns.map(|edns| {
+            Edns {
+                extended_rcode: (self.header.rcode.0 >> 4) as u8,
+                ..edns
+            }
+            .to_record()
+        }));
+        self.header.arcount = additionals.len() as u16;
+
+        let built_packet = DnsPacket {
+            header: self.header,
+            questions,
+            answers,
+            authorities: self.authorities,
+            additionals,
+        };
+
+        tracing::debug!(
+            "DNS Response built with custom settings: {:?}",
+            built_packet.header
+        );
+
+        built_packet
+    }
+}
+
+// The server builds every response through `build_custom_response` from the
+// records it has; the helpers below only build packets for tests
+#[cfg(test)]
+impl DnsResponseBuilder {
+    /// Build a response from a query packet: the query's questions, no answers
+    pub fn build_response(&self, query_packet: &DnsPacket) -> DnsPacket {
+        let mut header = self.response_header;
+        header.id = query_packet.header.id; // Echo the query ID
+        header.rd = query_packet.header.rd; // Copy recursion desired
+        header.qdcount = query_packet.questions.len() as u16;
+
+        DnsPacket {
+            header,
+            questions: query_packet.questions.clone(), // Still need to clone here for ownership
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+impl ResponseBuilder<'_> {
     /// Add an A record question (IPv4 address lookup)
     pub fn with_a_record(self, domain: &str) -> Self {
-        self.with_question(domain, DNS_TYPE_A, DNS_CLASS_IN)
+        self.with_question(domain, RecordType::A, RecordClass::IN)
     }
 
     /// Add an AAAA record question (IPv6 address lookup)
     pub fn with_aaaa_record(self, domain: &str) -> Self {
-        self.with_question(domain, DNS_TYPE_AAAA, DNS_CLASS_IN)
+        self.with_question(domain, RecordType::AAAA, RecordClass::IN)
     }
 
     /// Add a CNAME record question (canonical name lookup)
     pub fn with_cname_record(self, domain: &str) -> Self {
-        self.with_question(domain, DNS_TYPE_CNAME, DNS_CLASS_IN)
+        self.with_question(domain, RecordType::CNAME, RecordClass::IN)
     }
 
     /// Add an MX record question (mail exchange lookup)
     pub fn with_mx_record(self, domain: &str) -> Self {
-        self.with_question(domain, DNS_TYPE_MX, DNS_CLASS_IN)
+        self.with_question(domain, RecordType::MX, RecordClass::IN)
     }
 
     /// Add a TXT record question (text record lookup)
     pub fn with_txt_record(self, domain: &str) -> Self {
-        self.with_question(domain, DNS_TYPE_TXT, DNS_CLASS_IN)
+        self.with_question(domain, RecordType::TXT, RecordClass::IN)
     }
 
-    /// Add an A record answer (IPv4 address) - automatically adds the corresponding question
-    pub fn with_an_answer(self, domain: &str, ip: IpAddr, ttl: u32) -> Self {
-        // First add the question (copied from with_a_record)
-        let question = DnsQuestion {
-            name: domain.to_string(),
-            qtype: DNS_TYPE_A,
-            qclass: DNS_CLASS_IN,
-        };
-        self.builder.questions.clear();
-        self.builder.questions.push(question);
-        self.builder.response_header.qdcount = 1;
+    /// Add an SRV record question (service location lookup)
+    pub fn with_srv_record(self, domain: &str) -> Self {
+        self.with_question(domain, RecordType::SRV, RecordClass::IN)
+    }
 
+    /// Add a NAPTR record question (ENUM and SIP service discovery)
+    pub fn with_naptr_record(self, domain: &str) -> Self {
+        self.with_question(domain, RecordType::NAPTR, RecordClass::IN)
+    }
+
+    /// Add an A record answer (IPv4 address)
+    pub fn with_an_answer(self, domain: &str, ip: IpAddr, ttl: u32) -> Self {
         let answer: DnsResourceRecord = DnsResourceRecord::new(
             domain.to_string(),
-            DNS_TYPE_A,
-            DNS_CLASS_IN,
+            RecordType::A,
+            RecordClass::IN,
             ttl,
-            match ip { // this is the resolved IP address from the query
+            match ip {
+                // this is the resolved IP address from the query
                 IpAddr::V4(ipv4) => ipv4.octets().to_vec(),
                 IpAddr::V6(ipv6) => ipv6.octets().to_vec(),
             },
         );
-
-        self.builder.answers.push(answer);
-        self.builder.response_header.ancount = self.builder.answers.len() as u16;
-        self
+        self.with_answer(answer)
     }
 
-    /// Add an AAAA record answer (IPv6 address) - automatically adds the corresponding question
+    /// Add an AAAA record answer (IPv6 address)
     pub fn with_aaaa_answer(self, domain: &str, ip: Ipv6Addr, ttl: u32) -> Self {
-        // First add the question (copied from with_aaaa_record)
-        let question = DnsQuestion {
-            name: domain.to_string(),
-            qtype: DNS_TYPE_AAAA,
-            qclass: DNS_CLASS_IN,
-        };
-        self.builder.questions.clear();
-        self.builder.questions.push(question);
-        self.builder.response_header.qdcount = 1;
-
         let answer: DnsResourceRecord = DnsResourceRecord::new(
             domain.to_string(),
-            DNS_TYPE_AAAA,
-            DNS_CLASS_IN,
+            RecordType::AAAA,
+            RecordClass::IN,
             ttl,
             ip.octets().to_vec(),
         );
-
-        self.builder.answers.push(answer);
-        self.builder.response_header.ancount = self.builder.answers.len() as u16;
-        self
+        self.with_answer(answer)
     }
 
     /// Add a CNAME record answer (canonical name)
     pub fn with_cname_answer(self, domain: &str, cname: &str, ttl: u32) -> Self {
         // For CNAME, we need to encode the domain name in DNS format
-        let mut data = Vec::new();
-        for label in cname.split('.') {
-            if !label.is_empty() {
-                data.push(label.len() as u8);
-                data.extend_from_slice(label.as_bytes());
-            }
-        }
-        data.push(0); // Null terminator
-
-        let answer =
-            DnsResourceRecord::new(domain.to_string(), DNS_TYPE_CNAME, DNS_CLASS_IN, ttl, data);
+        let data = encode_name(cname);
 
-        self.builder.answers.push(answer);
-        self.builder.response_header.ancount = self.builder.answers.len() as u16;
-        self
+        let answer = DnsResourceRecord::new(
+            domain.to_string(),
+            RecordType::CNAME,
+            RecordClass::IN,
+            ttl,
+            data,
+        );
+        self.with_answer(answer)
     }
 
     /// Add a TXT record answer (text record)
@@ -303,12 +542,14 @@ impl<'a> ResponseBuilder<'a> {
         data.push(text.len() as u8);
         data.extend_from_slice(text.as_bytes());
 
-        let answer =
-            DnsResourceRecord::new(domain.to_string(), DNS_TYPE_TXT, DNS_CLASS_IN, ttl, data);
-
-        self.builder.answers.push(answer);
-        self.builder.response_header.ancount = self.builder.answers.len() as u16;
-        self
+        let answer = DnsResourceRecord::new(
+            domain.to_string(),
+            RecordType::TXT,
+            RecordClass::IN,
+            ttl,
+            data,
+        );
+        self.with_answer(answer)
     }
 
     /// Add an MX record answer (mail exchange)
@@ -319,53 +560,50 @@ impl<'a> ResponseBuilder<'a> {
         data.extend_from_slice(&priority.to_be_bytes());
 
         // Encode the exchange domain name
-        for label in exchange.split('.') {
-            if !label.is_empty() {
-                data.push(label.len() as u8);
-                data.extend_from_slice(label.as_bytes());
-            }
-        }
-        data.push(0); // Null terminator
+        data.extend(encode_name(exchange));
 
-        let answer =
-            DnsResourceRecord::new(domain.to_string(), DNS_TYPE_MX, DNS_CLASS_IN, ttl, data);
+        let answer = DnsResourceRecord::new(
+            domain.to_string(),
+            RecordType::MX,
+            RecordClass::IN,
+            ttl,
+            data,
+        );
+        self.with_answer(answer)
+    }
 
-        self.builder.answers.push(answer);
-        self.builder.response_header.ancount = self.builder.answers.len() as u16;
-        self
+    /// Add an SRV record answer (service location, RFC 2782), e.g. for
+    /// `_sip._udp.example.com`
+    pub fn with_srv_answer(
+        self,
+        domain: &str,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: &str,
+        ttl: u32,
+    ) -> Self {
+        // SRV record format: 2-byte priority, weight and port + target name,
+        // which is never compressed
+        let mut data = Vec::with_capacity(target.len() + 8);
+        data.extend_from_slice(&priority.to_be_bytes());
+        data.extend_from_slice(&weight.to_be_bytes());
+        data.extend_from_slice(&port.to_be_bytes());
+        data.extend(encode_name(target));
+
+        let answer = DnsResourceRecord::new(
+            domain.to_string(),
+            RecordType::SRV,
+            RecordClass::IN,
+            ttl,
+            data,
+        );
+        self.with_answer(answer)
     }
 
-    /// Build the final response
-    pub fn build(self) -> DnsPacket {
-        if !self.builder.questions.is_empty() {
-            // Use custom questions if they were added
-            // Copy the query ID to the response
-            self.builder.response_header.id = self.query_packet.header.id;
-            self.builder.response_header.rd = self.query_packet.header.rd;
-            self.builder.response_header.opcode = self.query_packet.header.opcode;
-
-            // Set rcode: 0 (NOERROR) for standard query, 4 (NOTIMP) otherwise
-            self.builder.response_header.rcode = match self.query_packet.header.opcode {
-                0 => 0,
-                _ => 4,
-            };
-
-            let built_packet = DnsPacket {
-                header: self.builder.response_header,
-                questions: self.builder.questions.clone(),
-                answers: self.builder.answers.clone(),
-            };
-
-            tracing::debug!(
-                "DNS Response built with custom settings: {:?}",
-                built_packet.header
-            );
-
-            built_packet
-        } else {
-            // Fall back to original query questions
-            self.builder.build_response(self.query_packet)
-        }
+    /// Add a NAPTR record answer, owned by the record's own name
+    pub fn with_naptr_answer(self, naptr: &NaptrRecord, ttl: u32) -> Self {
+        self.with_answer(naptr.to_resource_record(ttl))
     }
 }
 
@@ -381,27 +619,33 @@ mod tests {
 
     #[test]
     fn test_response_builder() {
-        let mut builder = DnsResponseBuilder::new();
+        let builder = DnsResponseBuilder::new();
 
         // Create a mock query
         let query = DnsPacket {
             header: DnsPacketHeader {
                 id: 1234,
                 qr: false,
-                opcode: 0,
+                opcode: Opcode::QUERY,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: false,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NOERROR,
                 qdcount: 1,
                 ancount: 0,
                 nscount: 0,
                 arcount: 0,
             },
-            questions: vec![],
+            questions: vec![DnsQuestion {
+                name: "example.com".to_string(),
+                qtype: RecordType::A,
+                qclass: RecordClass::IN,
+            }],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         let response = builder.build_response(&query);
@@ -409,24 +653,26 @@ mod tests {
         assert_eq!(response.header.id, 1234);
         assert!(response.header.qr);
         assert!(response.header.ra);
-        assert_eq!(response.header.ancount, 1);
+        // Counts reflect what the packet actually carries
+        assert_eq!(response.header.qdcount, 1);
+        assert_eq!(response.header.ancount, 0);
     }
 
     #[test]
     fn test_fluent_builder() {
-        let mut builder = DnsResponseBuilder::new();
+        let builder = DnsResponseBuilder::new();
 
         let query = DnsPacket {
             header: DnsPacketHeader {
                 id: 5678,
                 qr: false,
-                opcode: 0,
+                opcode: Opcode::QUERY,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: false,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NOERROR,
                 qdcount: 1,
                 ancount: 0,
                 nscount: 0,
@@ -434,21 +680,23 @@ mod tests {
             },
             questions: vec![],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         let response = builder
             .build_custom_response(&query)
-            .with_rcode(3) // NXDOMAIN
+            .with_rcode(Rcode::NXDOMAIN)
             .with_authoritative(true)
             .build();
 
-        assert_eq!(response.header.rcode, 3);
+        assert_eq!(response.header.rcode, Rcode::NXDOMAIN);
         assert!(response.header.aa);
     }
 
     // #[test]
     // fn test_domain_response() {
-    //     let mut builder = DnsResponseBuilder::new();
+    //     let builder = DnsResponseBuilder::new();
 
     //     let response = builder.build_domain_response("google.com", 1234);
 
@@ -458,13 +706,13 @@ mod tests {
     //     assert_eq!(response.header.ancount, 1);
     //     assert_eq!(response.questions.len(), 1);
     //     assert_eq!(response.questions[0].name, "google.com");
-    //     assert_eq!(response.questions[0].qtype, DNS_TYPE_A);
-    //     assert_eq!(response.questions[0].qclass, DNS_CLASS_IN);
+    //     assert_eq!(response.questions[0].qtype, RecordType::A);
+    //     assert_eq!(response.questions[0].qclass, RecordClass::IN);
     // }
 
     // #[test]
     // fn test_multi_domain_response() {
-    //     let mut builder = DnsResponseBuilder::new();
+    //     let builder = DnsResponseBuilder::new();
 
     //     let domains = ["google.com", "facebook.com", "github.com"];
     //     let response = builder.build_multi_domain_response(&domains, 5678);
@@ -476,26 +724,26 @@ mod tests {
 
     //     for (i, domain) in domains.iter().enumerate() {
     //         assert_eq!(response.questions[i].name, *domain);
-    //         assert_eq!(response.questions[i].qtype, DNS_TYPE_A);
-    //         assert_eq!(response.questions[i].qclass, DNS_CLASS_IN);
+    //         assert_eq!(response.questions[i].qtype, RecordType::A);
+    //         assert_eq!(response.questions[i].qclass, RecordClass::IN);
     //     }
     // }
 
     #[test]
     fn test_fluent_builder_with_custom_domain() {
-        let mut builder = DnsResponseBuilder::new();
+        let builder = DnsResponseBuilder::new();
 
         let query = DnsPacket {
             header: DnsPacketHeader {
                 id: 9999,
                 qr: false,
-                opcode: 0,
+                opcode: Opcode::QUERY,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: false,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NOERROR,
                 qdcount: 1,
                 ancount: 0,
                 nscount: 0,
@@ -503,38 +751,40 @@ mod tests {
             },
             questions: vec![],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         let response = builder
             .build_custom_response(&query)
             .with_a_record("example.com")
             .with_authoritative(true)
-            .with_rcode(0)
+            .with_rcode(Rcode::NOERROR)
             .build();
 
         assert_eq!(response.header.id, 9999);
         assert!(response.header.aa);
-        assert_eq!(response.header.rcode, 0);
+        assert_eq!(response.header.rcode, Rcode::NOERROR);
         assert_eq!(response.questions.len(), 1);
         assert_eq!(response.questions[0].name, "example.com");
-        assert_eq!(response.questions[0].qtype, DNS_TYPE_A);
+        assert_eq!(response.questions[0].qtype, RecordType::A);
     }
 
     #[test]
     fn test_different_record_types() {
-        let mut builder = DnsResponseBuilder::new();
+        let builder = DnsResponseBuilder::new();
 
         let query = DnsPacket {
             header: DnsPacketHeader {
                 id: 1111,
                 qr: false,
-                opcode: 0,
+                opcode: Opcode::QUERY,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: false,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NOERROR,
                 qdcount: 1,
                 ancount: 0,
                 nscount: 0,
@@ -542,6 +792,8 @@ mod tests {
             },
             questions: vec![],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         // Test AAAA record
@@ -550,7 +802,7 @@ mod tests {
             .with_aaaa_record("ipv6.google.com")
             .build();
 
-        assert_eq!(response.questions[0].qtype, DNS_TYPE_AAAA);
+        assert_eq!(response.questions[0].qtype, RecordType::AAAA);
 
         // Test CNAME record
         let response = builder
@@ -558,7 +810,7 @@ mod tests {
             .with_cname_record("www.example.com")
             .build();
 
-        assert_eq!(response.questions[0].qtype, DNS_TYPE_CNAME);
+        assert_eq!(response.questions[0].qtype, RecordType::CNAME);
 
         // Test MX record
         let response = builder
@@ -566,7 +818,7 @@ mod tests {
             .with_mx_record("mail.example.com")
             .build();
 
-        assert_eq!(response.questions[0].qtype, DNS_TYPE_MX);
+        assert_eq!(response.questions[0].qtype, RecordType::MX);
 
         // Test TXT record
         // let response = builder
@@ -580,7 +832,15 @@ mod tests {
             .with_txt_record("verification.example.com")
             .build();
 
-        assert_eq!(response.questions[0].qtype, DNS_TYPE_TXT);
+        assert_eq!(response.questions[0].qtype, RecordType::TXT);
+
+        // Test SRV record
+        let response = builder
+            .build_custom_response(&query)
+            .with_srv_record("_sip._udp.example.com")
+            .build();
+
+        assert_eq!(response.questions[0].qtype, RecordType::SRV);
     }
 
     #[test]
@@ -591,13 +851,13 @@ mod tests {
             header: DnsPacketHeader {
                 id: 2222,
                 qr: false,
-                opcode: 0,
+                opcode: Opcode::QUERY,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: false,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NOERROR,
                 qdcount: 1,
                 ancount: 0,
                 nscount: 0,
@@ -605,10 +865,12 @@ mod tests {
             },
             questions: vec![],
             answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
 
         // Test A record answer
-        let mut builder1 = DnsResponseBuilder::new();
+        let builder1 = DnsResponseBuilder::new();
         let response = builder1
             .build_custom_response(&query)
             .with_a_record("example.com")
@@ -621,13 +883,13 @@ mod tests {
 
         assert_eq!(response.answers.len(), 1);
         assert_eq!(response.answers[0].name, "example.com");
-        assert_eq!(response.answers[0].rtype, DNS_TYPE_A);
+        assert_eq!(response.answers[0].rtype, RecordType::A);
         assert_eq!(response.answers[0].ttl, 300);
         assert_eq!(response.answers[0].rdata, vec![192, 168, 1, 1]);
         assert_eq!(response.header.ancount, 1);
 
         // Test AAAA record answer
-        let mut builder2 = DnsResponseBuilder::new();
+        let builder2 = DnsResponseBuilder::new();
         let response = builder2
             .build_custom_response(&query)
             .with_aaaa_answer(
@@ -638,11 +900,11 @@ mod tests {
             .build();
 
         assert_eq!(response.answers.len(), 1);
-        assert_eq!(response.answers[0].rtype, DNS_TYPE_AAAA);
+        assert_eq!(response.answers[0].rtype, RecordType::AAAA);
         assert_eq!(response.answers[0].ttl, 600);
 
         // Test CNAME record answer
-        let mut builder3 = DnsResponseBuilder::new();
+        let builder3 = DnsResponseBuilder::new();
         let response = builder3
             .build_custom_response(&query)
             .with_cname_record("www.example.com")
@@ -650,11 +912,11 @@ mod tests {
             .build();
 
         assert_eq!(response.answers.len(), 1);
-        assert_eq!(response.answers[0].rtype, DNS_TYPE_CNAME);
+        assert_eq!(response.answers[0].rtype, RecordType::CNAME);
         assert_eq!(response.answers[0].ttl, 1800);
 
         // Test TXT record answer
-        let mut builder4 = DnsResponseBuilder::new();
+        let builder4 = DnsResponseBuilder::new();
         let response = builder4
             .build_custom_response(&query)
             .with_txt_record("verification.example.com")
@@ -666,11 +928,11 @@ mod tests {
             .build();
 
         assert_eq!(response.answers.len(), 1);
-        assert_eq!(response.answers[0].rtype, DNS_TYPE_TXT);
+        assert_eq!(response.answers[0].rtype, RecordType::TXT);
         assert_eq!(response.answers[0].ttl, 3600);
 
         // Test MX record answer
-        let mut builder5 = DnsResponseBuilder::new();
+        let builder5 = DnsResponseBuilder::new();
         let response = builder5
             .build_custom_response(&query)
             .with_mx_record("example.com")
@@ -678,10 +940,340 @@ mod tests {
             .build();
 
         assert_eq!(response.answers.len(), 1);
-        assert_eq!(response.answers[0].rtype, DNS_TYPE_MX);
+        assert_eq!(response.answers[0].rtype, RecordType::MX);
         assert_eq!(response.answers[0].ttl, 7200);
         // First two bytes should be priority (10 in big-endian)
         assert_eq!(response.answers[0].rdata[0], 0);
         assert_eq!(response.answers[0].rdata[1], 10);
+
+        // Test SRV record answer
+        let builder6 = DnsResponseBuilder::new();
+        let response = builder6
+            .build_custom_response(&query)
+            .with_srv_record("_sip._udp.example.com")
+            .with_srv_answer("_sip._udp.example.com", 10, 5, 5060, "sip.example.com", 900)
+            .build();
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rtype, RecordType::SRV);
+        assert_eq!(response.answers[0].ttl, 900);
+        // Priority, weight and port, then the target
+        let mut rdata = vec![0, 10, 0, 5, 0x13, 0xc4];
+        rdata.extend(encode_name("sip.example.com"));
+        assert_eq!(response.answers[0].rdata, rdata);
+
+        // The answer survives encoding and parsing
+        let mut encoded = bytes::BytesMut::new();
+        crate::codec::encode_packet(&response, &mut encoded).unwrap();
+        let (_, parsed) = crate::parsers::parse_dns_packet(&encoded).unwrap();
+        assert_eq!(parsed.answers[0].rtype, RecordType::SRV);
+        assert_eq!(parsed.answers[0].rdata, rdata);
+
+        // Test NAPTR record answer (ENUM: a number mapped to a SIP URI)
+        let naptr = NaptrRecord {
+            name: "4.3.2.1.5.5.5.0.0.8.1.e164.arpa".to_string(),
+            order: 100,
+            preference: 10,
+            flags: "u".to_string(),
+            services: "E2U+sip".to_string(),
+            regexp: "!^.*$!sip:info@example.com!".to_string(),
+            replacement: ".".to_string(),
+        };
+        let response = DnsResponseBuilder::new()
+            .build_custom_response(&query)
+            .with_naptr_record(&naptr.name)
+            .with_naptr_answer(&naptr, 3600)
+            .build();
+
+        assert_eq!(response.questions[0].qtype, RecordType::NAPTR);
+        assert_eq!(response.answers[0].rtype, RecordType::NAPTR);
+        // Order and preference, three character strings, then the root
+        let rdata = &response.answers[0].rdata;
+        assert_eq!(rdata[..6], [0, 100, 0, 10, 1, b'u']);
+        assert_eq!(rdata.len(), 4 + 2 + 8 + 28 + 1);
+
+        let mut encoded = bytes::BytesMut::new();
+        crate::codec::encode_packet(&response, &mut encoded).unwrap();
+        let (_, parsed) = crate::parsers::parse_dns_packet(&encoded).unwrap();
+        assert_eq!(
+            NaptrRecord::from_resource_record(&parsed.answers[0]),
+            Some(naptr)
+        );
+        let mut cut = parsed.answers[0].clone();
+        cut.rdata.truncate(10);
+        assert_eq!(NaptrRecord::from_resource_record(&cut), None);
+    }
+
+    #[test]
+    fn test_negative_answer_with_soa() {
+        let query = DnsPacket {
+            header: DnsPacketHeader {
+                id: 3333,
+                qr: false,
+                opcode: Opcode::QUERY,
+                aa: false,
+                tc: false,
+                rd: true,
+                ra: false,
+                z: 0,
+                rcode: Rcode::NOERROR,
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+
+        let soa = SoaRecord {
+            zone: "example.com".to_string(),
+            mname: "ns1.example.com".to_string(),
+            rname: "hostmaster.example.com".to_string(),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        };
+
+        let builder = DnsResponseBuilder::new();
+        let response = builder
+            .build_custom_response(&query)
+            .with_a_record("missing.example.com")
+            .with_rcode(Rcode::NXDOMAIN)
+            .with_authority(soa.to_resource_record(300))
+            .build();
+
+        // The configured rcode survives build() for a standard query
+        assert_eq!(response.header.rcode, Rcode::NXDOMAIN);
+        assert_eq!(response.header.nscount, 1);
+        assert_eq!(response.authorities.len(), 1);
+
+        let record = &response.authorities[0];
+        assert_eq!(record.rtype, RecordType::SOA);
+        assert_eq!(record.ttl, 300);
+        // mname (17) + rname (24) + five 32-bit fields
+        assert_eq!(record.rdata.len(), 17 + 24 + 20);
+        assert_eq!(&record.rdata[..4], &[3, b'n', b's', b'1']);
+    }
+
+    #[test]
+    fn test_additional_records() {
+        use crate::edns::Edns;
+        use std::net::Ipv4Addr;
+
+        let query = DnsPacket {
+            header: DnsPacketHeader {
+                id: 5555,
+                qr: false,
+                opcode: Opcode::QUERY,
+                aa: false,
+                tc: false,
+                rd: true,
+                ra: false,
+                z: 0,
+                rcode: Rcode::NOERROR,
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 1,
+            },
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        let glue = DnsResourceRecord::new(
+            "mail.example.com".to_string(),
+            RecordType::A,
+            RecordClass::IN,
+            300,
+            Ipv4Addr::new(192, 0, 2, 25).octets().to_vec(),
+        );
+
+        let builder = DnsResponseBuilder::new();
+        let response = builder
+            .build_custom_response(&query)
+            .with_mx_record("example.com")
+            .with_mx_answer("example.com", 10, "mail.example.com", 300)
+            .with_mx_answer("example.com", 20, "mail.example.com", 300)
+            .with_additional(glue.clone())
+            .with_additional(glue)
+            .with_edns(Edns {
+                udp_payload_size: 1232,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: false,
+                options: Vec::new(),
+            })
+            .build();
+
+        // The glue once, then the OPT record, which stays last
+        assert_eq!(response.header.arcount, 2);
+        let types: Vec<RecordType> = response.additionals.iter().map(|r| r.rtype).collect();
+        assert_eq!(types, [RecordType::A, RecordType::OPT]);
+
+        let mut encoded = bytes::BytesMut::new();
+        crate::codec::encode_packet(&response, &mut encoded).unwrap();
+        let (_, parsed) = crate::parsers::parse_dns_packet(&encoded).unwrap();
+        assert_eq!(parsed.header.arcount, 2);
+        assert_eq!(parsed.additionals[0].name, "mail.example.com");
+        assert_eq!(parsed.additionals[0].rdata, [192, 0, 2, 25]);
+    }
+
+    #[test]
+    fn test_answers_stay_with_their_question() {
+        use std::net::Ipv4Addr;
+
+        let query = DnsPacket {
+            header: DnsPacketHeader {
+                id: 4444,
+                qr: false,
+                opcode: Opcode::QUERY,
+                aa: false,
+                tc: false,
+                rd: true,
+                ra: false,
+                z: 0,
+                rcode: Rcode::NOERROR,
+                qdcount: 3,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+
+        let builder = DnsResponseBuilder::new();
+        let response = builder
+            .build_custom_response(&query)
+            .with_a_record("one.example.com")
+            .with_an_answer("one.example.com", Ipv4Addr::new(192, 0, 2, 1).into(), 60)
+            .with_an_answer("one.example.com", Ipv4Addr::new(192, 0, 2, 2).into(), 60)
+            .with_mx_record("unanswered.example.com")
+            .with_aaaa_record("two.example.com")
+            .with_aaaa_answer("two.example.com", Ipv6Addr::LOCALHOST, 60)
+            .build();
+
+        let names: Vec<&str> = response.questions.iter().map(|q| q.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "one.example.com",
+                "unanswered.example.com",
+                "two.example.com"
+            ]
+        );
+        assert_eq!(response.header.qdcount, 3);
+        assert_eq!(response.header.ancount, 3);
+        let answers: Vec<(&str, RecordType)> = response
+            .answers
+            .iter()
+            .map(|a| (a.name.as_str(), a.rtype))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                ("one.example.com", RecordType::A),
+                ("one.example.com", RecordType::A),
+                ("two.example.com", RecordType::AAAA),
+            ]
+        );
+
+        // A second response from the same builder starts empty
+        let response = builder
+            .build_custom_response(&query)
+            .with_rcode(Rcode::SERVFAIL)
+            .build();
+        assert!(response.questions.is_empty());
+        assert_eq!(response.header.ancount, 0);
+        assert_eq!(response.header.rcode, Rcode::SERVFAIL);
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        use crate::codec::encode_packet;
+        use crate::parsers::parse_dns_packet;
+        use bytes::BytesMut;
+        use std::net::Ipv4Addr;
+
+        // One builder, made at compile time, serves every response
+        const RESPONSES: DnsResponseBuilder = DnsResponseBuilder::new();
+
+        let query = DnsPacket {
+            header: DnsPacketHeader {
+                id: 7777,
+                qr: false,
+                opcode: Opcode::QUERY,
+                aa: false,
+                tc: false,
+                rd: true,
+                ra: false,
+                z: 0,
+                rcode: Rcode::NOERROR,
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![DnsQuestion {
+                name: "example.com".to_string(),
+                qtype: RecordType::A,
+                qclass: RecordClass::IN,
+            }],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        let round_trip = |response: DnsPacket| {
+            let mut encoded = BytesMut::new();
+            encode_packet(&response, &mut encoded).unwrap();
+            let (rest, decoded) = parse_dns_packet(&encoded).unwrap();
+            assert!(rest.is_empty());
+            // Decoded, it encodes to the same bytes again
+            let mut again = BytesMut::new();
+            encode_packet(&decoded, &mut again).unwrap();
+            assert_eq!(again, encoded);
+            decoded
+        };
+        let questions = |packet: &DnsPacket| -> Vec<(String, RecordType, RecordClass)> {
+            packet
+                .questions
+                .iter()
+                .map(|q| (q.name.clone(), q.qtype, q.qclass))
+                .collect()
+        };
+
+        // Without answers the query's questions are echoed
+        let response = round_trip(
+            RESPONSES
+                .build_custom_response(&query)
+                .with_qr(true)
+                .with_rcode(Rcode::NXDOMAIN)
+                .build(),
+        );
+        assert_eq!(response.header.id, 7777);
+        assert_eq!(questions(&response), questions(&query));
+        assert!(response.answers.is_empty());
+
+        // With them, each question comes back with its answers
+        let response = round_trip(
+            RESPONSES
+                .build_custom_response(&query)
+                .with_qr(true)
+                .with_a_record("example.com")
+                .with_an_answer("example.com", Ipv4Addr::new(192, 0, 2, 1).into(), 60)
+                .with_an_answer("example.com", Ipv4Addr::new(192, 0, 2, 2).into(), 60)
+                .build(),
+        );
+        assert_eq!(questions(&response), questions(&query));
+        assert_eq!(response.header.ancount, 2);
+        assert_eq!(response.answers[1].rdata, [192, 0, 2, 2]);
     }
 }
@@ -0,0 +1,323 @@
+//! Upstream timeouts and retries (`--upstream-policy`)
+//!
+//! Each upstream lookup is bounded by a query timeout, and each TCP, TLS or
+//! HTTPS connection to an upstream by a connect timeout, so an upstream that
+//! hangs fails the lookup instead of holding the client. A failed lookup is
+//! tried again, after a backoff that doubles with every attempt, until the
+//! attempts run out; which failures are worth another attempt is up to the
+//! policy: timeouts (no answer in time, or no connection at all), SERVFAIL
+//! answers, both or neither.
+//!
+//! A policy is given as comma-separated `key=value` settings. One without an
+//! `upstream` sets the defaults for every upstream; one with an `upstream`
+//! applies to that upstream alone, over the defaults. Settings left out keep
+//! their value, and of two policies for the same upstreams the later wins:
+//!
+//! ```text
+//! --upstream-policy timeout=2000,attempts=3,backoff=50,retry-on=timeout+servfail
+//! --upstream-policy upstream=10.0.0.1:53,timeout=500,connect-timeout=200,retry-on=none
+//! ```
+//!
+//! Times are in milliseconds. `stats` counts the attempts made again as
+//! `upstream_retries`, and the attempts that timed out as `upstream_timeouts`.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::proto::ProtoErrorKind;
+use hickory_resolver::ResolveError;
+
+use crate::upstream::Upstream;
+
+/// How an upstream is asked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How long an attempt may take
+    pub query_timeout: Duration,
+    /// How long a connection to the upstream may take
+    pub connect_timeout: Duration,
+    /// Attempts per lookup, the first included
+    pub attempts: usize,
+    /// The pause before the second attempt, doubled before each after it
+    pub backoff: Duration,
+    /// Failures worth another attempt
+    pub retry_on: RetryOn,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            query_timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+            attempts: 2,
+            backoff: Duration::from_millis(100),
+            retry_on: RetryOn {
+                timeout: true,
+                servfail: false,
+            },
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The policy for `upstream`: the defaults, then the policies for every
+    /// upstream, then those for this one, each in the order given
+    pub fn for_upstream(policies: &[UpstreamPolicy], upstream: &Upstream) -> Self {
+        let mut policy = RetryPolicy::default();
+        for spec in policies.iter().filter(|spec| spec.upstream.is_none()) {
+            spec.apply(&mut policy);
+        }
+        for spec in policies
+            .iter()
+            .filter(|spec| spec.upstream == Some(upstream.addr))
+        {
+            spec.apply(&mut policy);
+        }
+        policy
+    }
+
+    /// Whether a failed attempt is worth another
+    pub fn retries(&self, failure: Failure) -> bool {
+        match failure {
+            Failure::Timeout => self.retry_on.timeout,
+            Failure::ServFail => self.retry_on.servfail,
+            Failure::Other => false,
+        }
+    }
+
+    /// The pause before attempt `attempt`, counting from 0
+    pub fn backoff_before(&self, attempt: usize) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16) as u32;
+        self.backoff.saturating_mul(1 << doublings)
+    }
+}
+
+/// Which failures are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryOn {
+    pub timeout: bool,
+    pub servfail: bool,
+}
+
+impl fmt::Display for RetryOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.timeout, self.servfail) {
+            (true, true) => write!(f, "timeout+servfail"),
+            (true, false) => write!(f, "timeout"),
+            (false, true) => write!(f, "servfail"),
+            (false, false) => write!(f, "none"),
+        }
+    }
+}
+
+/// Parses `none`, or `timeout` and `servfail` joined by `+`
+impl FromStr for RetryOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut retry_on = RetryOn {
+            timeout: false,
+            servfail: false,
+        };
+        if s == "none" {
+            return Ok(retry_on);
+        }
+        for failure in s.split('+') {
+            match failure {
+                "timeout" => retry_on.timeout = true,
+                "servfail" => retry_on.servfail = true,
+                _ => {
+                    return Err(format!(
+                        "invalid retry-on '{}' (timeout, servfail or none)",
+                        failure
+                    ))
+                }
+            }
+        }
+        Ok(retry_on)
+    }
+}
+
+/// Why an attempt failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// No answer in time, or no connection to ask over
+    Timeout,
+    /// The upstream answered SERVFAIL
+    ServFail,
+    /// Anything else, a REFUSED say
+    Other,
+}
+
+impl Failure {
+    /// The failure behind a lookup's error, or none for NXDOMAIN and NODATA,
+    /// which are answers
+    pub fn of(error: &ResolveError) -> Option<Failure> {
+        let Some(error) = error.proto() else {
+            return Some(Failure::Other);
+        };
+        match error.kind() {
+            ProtoErrorKind::NoRecordsFound {
+                response_code: ResponseCode::NXDomain | ResponseCode::NoError,
+                ..
+            } => None,
+            ProtoErrorKind::NoRecordsFound {
+                response_code: ResponseCode::ServFail,
+                ..
+            } => Some(Failure::ServFail),
+            ProtoErrorKind::Timeout | ProtoErrorKind::Io(_) | ProtoErrorKind::NoConnections => {
+                Some(Failure::Timeout)
+            }
+            _ => Some(Failure::Other),
+        }
+    }
+}
+
+/// A policy as given on the command line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpstreamPolicy {
+    /// The upstream it is for; every upstream when none
+    pub upstream: Option<SocketAddr>,
+    pub query_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub attempts: Option<usize>,
+    pub backoff: Option<Duration>,
+    pub retry_on: Option<RetryOn>,
+}
+
+impl UpstreamPolicy {
+    /// Override `policy` with the settings given
+    fn apply(&self, policy: &mut RetryPolicy) {
+        policy.query_timeout = self.query_timeout.unwrap_or(policy.query_timeout);
+        policy.connect_timeout = self.connect_timeout.unwrap_or(policy.connect_timeout);
+        policy.attempts = self.attempts.unwrap_or(policy.attempts);
+        policy.backoff = self.backoff.unwrap_or(policy.backoff);
+        policy.retry_on = self.retry_on.unwrap_or(policy.retry_on);
+    }
+}
+
+impl fmt::Display for UpstreamPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut settings = Vec::new();
+        if let Some(upstream) = self.upstream {
+            settings.push(format!("upstream={}", upstream));
+        }
+        if let Some(timeout) = self.query_timeout {
+            settings.push(format!("timeout={}", timeout.as_millis()));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            settings.push(format!("connect-timeout={}", timeout.as_millis()));
+        }
+        if let Some(attempts) = self.attempts {
+            settings.push(format!("attempts={}", attempts));
+        }
+        if let Some(backoff) = self.backoff {
+            settings.push(format!("backoff={}", backoff.as_millis()));
+        }
+        if let Some(retry_on) = self.retry_on {
+            settings.push(format!("retry-on={}", retry_on));
+        }
+        write!(f, "{}", settings.join(","))
+    }
+}
+
+/// Parses comma-separated `key=value` settings, all optional but at least
+/// one needed: `upstream`, `timeout`, `connect-timeout`, `attempts`,
+/// `backoff` and `retry-on`
+impl FromStr for UpstreamPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let millis = |key: &str, value: &str| {
+            value
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| format!("invalid {} '{}' (milliseconds)", key, value))
+        };
+        let mut policy = UpstreamPolicy::default();
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got '{}'", setting))?;
+            match key {
+                "upstream" => {
+                    policy.upstream = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid upstream '{}' (<ip>:<port>)", value))?,
+                    )
+                }
+                "timeout" => policy.query_timeout = Some(millis(key, value)?),
+                "connect-timeout" => policy.connect_timeout = Some(millis(key, value)?),
+                "attempts" => {
+                    policy.attempts = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&attempts| attempts >= 1)
+                            .ok_or_else(|| format!("invalid attempts '{}' (1 or more)", value))?,
+                    )
+                }
+                "backoff" => policy.backoff = Some(millis(key, value)?),
+                "retry-on" => policy.retry_on = Some(value.parse()?),
+                _ => return Err(format!("unknown upstream policy setting '{}'", key)),
+            }
+        }
+        if policy.query_timeout == Some(Duration::ZERO)
+            || policy.connect_timeout == Some(Duration::ZERO)
+        {
+            return Err("timeouts must be longer than 0 ms".to_string());
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_policies() {
+        let policies: Vec<UpstreamPolicy> = [
+            "upstream=10.0.0.1:53,timeout=500,retry-on=none",
+            "timeout=2000,attempts=3,backoff=50,retry-on=timeout+servfail",
+        ]
+        .iter()
+        .map(|spec| spec.parse().unwrap())
+        .collect();
+        for policy in &policies {
+            assert_eq!(
+                policy.to_string().parse::<UpstreamPolicy>().unwrap(),
+                *policy
+            );
+        }
+
+        // The upstream's own settings win over the defaults, given before or not
+        let local: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let policy = RetryPolicy::for_upstream(&policies, &local.into());
+        assert_eq!(policy.query_timeout, Duration::from_millis(500));
+        assert_eq!(policy.attempts, 3);
+        assert!(!policy.retries(Failure::Timeout));
+
+        let public: SocketAddr = "9.9.9.9:53".parse().unwrap();
+        let policy = RetryPolicy::for_upstream(&policies, &public.into());
+        assert_eq!(policy.query_timeout, Duration::from_secs(2));
+        assert_eq!(policy.connect_timeout, Duration::from_secs(5));
+        assert!(policy.retries(Failure::ServFail));
+        assert!(!policy.retries(Failure::Other));
+        assert_eq!(policy.backoff_before(1), Duration::from_millis(50));
+        assert_eq!(policy.backoff_before(2), Duration::from_millis(100));
+
+        assert!("".parse::<UpstreamPolicy>().is_err());
+        assert!("timeout=2s".parse::<UpstreamPolicy>().is_err());
+        assert!("timeout=0".parse::<UpstreamPolicy>().is_err());
+        assert!("attempts=0".parse::<UpstreamPolicy>().is_err());
+        assert!("retry-on=refused".parse::<UpstreamPolicy>().is_err());
+        assert!("upstream=resolver.example:53"
+            .parse::<UpstreamPolicy>()
+            .is_err());
+    }
+}
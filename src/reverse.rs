@@ -0,0 +1,378 @@
+//! Classless reverse delegation (RFC 2317)
+//!
+//! Reverse zones are delegated on octet boundaries, so a block smaller than a
+//! /24 (say 192.168.1.64/26) can't get its own `1.168.192.in-addr.arpa`. The
+//! owner of the /24 instead publishes a CNAME per address pointing into a
+//! sub-zone named after the block, and the holder of the block serves the PTR
+//! records there:
+//!
+//! ```text
+//! 65.1.168.192.in-addr.arpa     CNAME  65.64/26.1.168.192.in-addr.arpa
+//! 65.64/26.1.168.192.in-addr.arpa PTR  host.example.com
+//! ```
+//!
+//! Both halves are ordinary local records; this module generates the names.
+//!
+//! It also maps addresses to their reverse names and back, for PTR records
+//! synthesized from the A and AAAA records of the local table
+//! (`--synthesize-ptr`), and holds the reverse zones the server answers for
+//! itself (`--reverse-zone`): a PTR query in one that nothing local answers
+//! gets an authoritative NXDOMAIN instead of going upstream, so lookups of
+//! private addresses don't leak out (RFC 6303). Other reverse names are
+//! forwarded like any name.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::errors::LocalRecordError;
+use crate::local_records::{is_in_zone, normalize_name, LocalRecord, RecordData};
+use crate::response_builder::SoaRecord;
+
+/// The reverse name of an address: `10.1.168.192.in-addr.arpa`, or 32
+/// nibbles under `ip6.arpa`
+pub fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::with_capacity(72);
+            for nibble in nibbles(addr).iter().rev() {
+                name.push(char::from_digit((*nibble).into(), 16).expect("a nibble"));
+                name.push('.');
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// The address a reverse name stands for; None for names that are not a
+/// full address under `in-addr.arpa` or `ip6.arpa`
+pub fn parse_reverse_name(name: &str) -> Option<IpAddr> {
+    let name = normalize_name(name);
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = [0; 4];
+        let mut labels = labels.split('.');
+        for octet in octets.iter_mut().rev() {
+            let label = labels.next()?;
+            // One spelling per address: no leading zeros
+            if label.len() > 1 && label.starts_with('0') {
+                return None;
+            }
+            *octet = label.parse().ok()?;
+        }
+        return labels
+            .next()
+            .is_none()
+            .then(|| Ipv4Addr::from(octets).into());
+    }
+
+    let labels = name.strip_suffix(".ip6.arpa")?;
+    let mut addr = 0u128;
+    let mut count = 0;
+    for label in labels.split('.').rev() {
+        let &[digit] = label.as_bytes() else {
+            return None;
+        };
+        addr = addr << 4 | u128::from(char::from(digit).to_digit(16)?);
+        count += 1;
+    }
+    (count == 32).then(|| Ipv6Addr::from(addr).into())
+}
+
+/// The 32 nibbles of an IPv6 address, most significant first
+fn nibbles(addr: Ipv6Addr) -> [u8; 32] {
+    let mut nibbles = [0; 32];
+    for (i, byte) in addr.octets().into_iter().enumerate() {
+        nibbles[2 * i] = byte >> 4;
+        nibbles[2 * i + 1] = byte & 0x0f;
+    }
+    nibbles
+}
+
+/// A reverse zone answered locally, for a network on an octet (IPv4) or
+/// nibble (IPv6) boundary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReverseZone {
+    network: IpAddr,
+    prefix_len: u8,
+    /// `1.168.192.in-addr.arpa` for 192.168.1.0/24
+    pub zone: String,
+}
+
+impl ReverseZone {
+    /// Whether `name` is the zone or a name in it
+    pub fn contains(&self, name: &str) -> bool {
+        is_in_zone(&normalize_name(name), &self.zone)
+    }
+
+    /// The SOA negative answers in the zone carry
+    pub fn soa(&self) -> SoaRecord {
+        SoaRecord::local(&self.zone, 1)
+    }
+}
+
+impl fmt::Display for ReverseZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Parses `<network>/<prefix length>`: a multiple of 8 up to /24 for IPv4,
+/// of 4 up to /124 for IPv6, with the host bits zero
+impl FromStr for ReverseZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid reverse zone '{}', expected a network on an octet (IPv4) or nibble (IPv6) boundary, e.g. 192.168.1.0/24",
+                s
+            )
+        };
+
+        let (network, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+        let labels: Vec<String> = match network {
+            IpAddr::V4(addr) => {
+                if !prefix_len.is_multiple_of(8) || !(8..=24).contains(&prefix_len) {
+                    return Err(invalid());
+                }
+                let octets = addr.octets();
+                let kept = usize::from(prefix_len / 8);
+                if octets[kept..].iter().any(|&octet| octet != 0) {
+                    return Err(invalid());
+                }
+                octets[..kept].iter().rev().map(u8::to_string).collect()
+            }
+            IpAddr::V6(addr) => {
+                if !prefix_len.is_multiple_of(4) || !(4..=124).contains(&prefix_len) {
+                    return Err(invalid());
+                }
+                let nibbles = nibbles(addr);
+                let kept = usize::from(prefix_len / 4);
+                if nibbles[kept..].iter().any(|&nibble| nibble != 0) {
+                    return Err(invalid());
+                }
+                nibbles[..kept]
+                    .iter()
+                    .rev()
+                    .map(|nibble| format!("{:x}", nibble))
+                    .collect()
+            }
+        };
+        let suffix = if network.is_ipv4() {
+            "in-addr.arpa"
+        } else {
+            "ip6.arpa"
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+            zone: format!("{}.{}", labels.join("."), suffix),
+        })
+    }
+}
+
+/// The reverse zones answered locally, and whether PTR records are
+/// synthesized from local addresses
+#[derive(Debug, Default)]
+pub struct ReverseLookups {
+    pub zones: Vec<ReverseZone>,
+    pub synthesize: bool,
+}
+
+impl ReverseLookups {
+    /// The most specific local reverse zone holding `name`
+    pub fn find(&self, name: &str) -> Option<&ReverseZone> {
+        self.zones
+            .iter()
+            .filter(|zone| zone.contains(name))
+            .max_by_key(|zone| zone.zone.len())
+    }
+}
+
+/// An IPv4 block smaller than a /24, aligned on its prefix length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClasslessBlock {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl ClasslessBlock {
+    /// Name of the sub-zone holding the block's PTR records, e.g.
+    /// `64/26.1.168.192.in-addr.arpa`
+    pub fn zone_name(&self) -> String {
+        let [a, b, c, d] = self.network.octets();
+        format!("{}/{}.{}.{}.{}.in-addr.arpa", d, self.prefix_len, c, b, a)
+    }
+
+    /// The addresses in the block, network and broadcast included
+    pub fn addresses(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let start = u32::from(self.network);
+        let size = 1u32 << (32 - self.prefix_len);
+        (start..start + size).map(Ipv4Addr::from)
+    }
+
+    /// Where the PTR record for `addr` lives inside the block's sub-zone
+    pub fn ptr_name(&self, addr: Ipv4Addr) -> String {
+        format!("{}.{}", addr.octets()[3], self.zone_name())
+    }
+
+    /// The CNAMEs the parent /24 publishes to delegate the block, one per address
+    pub fn delegation_cnames(&self, ttl: u32) -> Vec<LocalRecord> {
+        self.addresses()
+            .map(|addr| {
+                LocalRecord::new(
+                    &reverse_name(addr.into()),
+                    ttl,
+                    RecordData::Cname(self.ptr_name(addr)),
+                )
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for ClasslessBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Parses `<network>/<prefix length>` with a prefix length of 25 to 31
+impl FromStr for ClasslessBlock {
+    type Err = LocalRecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || LocalRecordError::InvalidBlock(s.to_string());
+
+        let (network, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let network: Ipv4Addr = network.parse().map_err(|_| invalid())?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+        if !(25..=31).contains(&prefix_len) {
+            return Err(invalid());
+        }
+
+        // Host bits must be zero
+        let host_mask = (1u32 << (32 - prefix_len)) - 1;
+        if u32::from(network) & host_mask != 0 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse_names() {
+        for addr in ["192.168.1.10", "10.0.0.1", "2001:db8::567:89ab", "::1"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(parse_reverse_name(&reverse_name(addr)), Some(addr));
+        }
+        assert_eq!(
+            reverse_name("192.168.1.10".parse().unwrap()),
+            "10.1.168.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name("2001:db8::567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+        assert_eq!(
+            parse_reverse_name("10.1.168.192.IN-ADDR.ARPA."),
+            Some("192.168.1.10".parse().unwrap())
+        );
+
+        for name in [
+            "1.168.192.in-addr.arpa",
+            "256.1.168.192.in-addr.arpa",
+            "010.1.168.192.in-addr.arpa",
+            "1.10.1.168.192.in-addr.arpa",
+            "65.64/26.1.168.192.in-addr.arpa",
+            "8.b.d.0.1.0.0.2.ip6.arpa",
+            "host.example.com",
+        ] {
+            assert_eq!(parse_reverse_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_reverse_zones() {
+        let lan: ReverseZone = "192.168.1.0/24".parse().unwrap();
+        assert_eq!(lan.zone, "1.168.192.in-addr.arpa");
+        assert!(lan.contains("10.1.168.192.in-addr.arpa"));
+        assert!(!lan.contains("10.2.168.192.in-addr.arpa"));
+        let ula: ReverseZone = "fd00::/8".parse().unwrap();
+        assert_eq!(ula.zone, "d.f.ip6.arpa");
+        assert_eq!(ula.to_string().parse::<ReverseZone>().unwrap(), ula);
+
+        let private: ReverseZone = "192.168.0.0/16".parse().unwrap();
+        let lookups = ReverseLookups {
+            zones: vec![private, lan],
+            synthesize: false,
+        };
+        assert_eq!(
+            lookups.find("10.1.168.192.in-addr.arpa").unwrap().zone,
+            "1.168.192.in-addr.arpa"
+        );
+        assert_eq!(
+            lookups.find("10.2.168.192.in-addr.arpa").unwrap().zone,
+            "168.192.in-addr.arpa"
+        );
+        assert!(lookups.find("1.0.0.10.in-addr.arpa").is_none());
+
+        for zone in [
+            "192.168.1.0/25",
+            "192.168.1.0/32",
+            "192.168.1.0/16",
+            "fd00::/7",
+            "10.0.0.0",
+        ] {
+            assert!(zone.parse::<ReverseZone>().is_err(), "{}", zone);
+        }
+    }
+
+    #[test]
+    fn test_delegation_cnames() {
+        let block: ClasslessBlock = "192.168.1.64/26".parse().unwrap();
+        assert_eq!(block.zone_name(), "64/26.1.168.192.in-addr.arpa");
+
+        let cnames = block.delegation_cnames(3600);
+        assert_eq!(cnames.len(), 64);
+        assert_eq!(
+            cnames[1].to_string(),
+            "65.1.168.192.in-addr.arpa CNAME 65.64/26.1.168.192.in-addr.arpa 3600"
+        );
+        assert_eq!(cnames[63].name, "127.1.168.192.in-addr.arpa");
+    }
+
+    #[test]
+    fn test_invalid_blocks() {
+        for block in [
+            "192.168.1.0/24",
+            "192.168.1.0/32",
+            "192.168.1.65/26",
+            "192.168.1.0",
+            "::1/120",
+        ] {
+            assert!(
+                matches!(
+                    block.parse::<ClasslessBlock>(),
+                    Err(LocalRecordError::InvalidBlock(_))
+                ),
+                "{} should be rejected",
+                block
+            );
+        }
+    }
+}
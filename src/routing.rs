@@ -0,0 +1,376 @@
+//! Tag-based upstream routing (`--route`)
+//!
+//! A route tags the queries it matches and says where they go: a set of
+//! upstreams of its own, or nowhere (`blackhole`, answered NXDOMAIN). A route
+//! matches by client subnet, query name (a domain and everything under it,
+//! given inline or as list files) and query type; every criterion given must
+//! match, and any of a criterion's values will do. Routes are tried in the
+//! order given and the first match wins; queries matching none go to the
+//! tenant's usual upstreams. Local records are answered before any routing.
+//!
+//! ```text
+//! --route name=ads,domains=/etc/dns/ads.txt,to=blackhole
+//! --route name=cn,domains=/etc/dns/cn.txt,to=223.5.5.5:53,to=119.29.29.29:53,hedge=50
+//! --route name=lab-v6,client=192.168.1.0/24,qtype=AAAA,to=10.0.0.1:53@eth1
+//! ```
+//!
+//! Domain list files hold one domain per line; `#` starts a comment, and
+//! hosts-file lines (`0.0.0.0 ads.example`) are read for their name, so
+//! common block lists work as they are.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+
+use crate::errors::RegistryError;
+use crate::geoip::Subnet;
+use crate::handlers::query_handler::QueryActorHandle;
+use crate::hedge::{Hedge, HedgedResolver};
+use crate::local_records::normalize_name;
+use crate::metrics::Metrics;
+use crate::registry::RecordType;
+use crate::retry::UpstreamPolicy;
+use crate::upstream::Upstream;
+
+/// Where a route's queries go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteTarget {
+    /// Answered NXDOMAIN without asking anyone
+    Blackhole,
+    /// Resolved by these upstreams, in order
+    Upstreams(Vec<Upstream>),
+}
+
+/// A route as given on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteRule {
+    /// The tag of the queries it matches
+    pub name: String,
+    pub clients: Vec<Subnet>,
+    pub domains: Vec<String>,
+    /// Files listing more domains
+    pub domain_files: Vec<PathBuf>,
+    pub qtypes: Vec<RecordType>,
+    pub target: RouteTarget,
+    /// How the route's upstreams race, if they do
+    pub hedge: Option<Hedge>,
+}
+
+impl fmt::Display for RouteRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "name={}", self.name)?;
+        for client in &self.clients {
+            write!(f, ",client={}", client)?;
+        }
+        for domain in &self.domains {
+            write!(f, ",domain={}", domain)?;
+        }
+        for path in &self.domain_files {
+            write!(f, ",domains={}", path.display())?;
+        }
+        for qtype in &self.qtypes {
+            write!(f, ",qtype={}", qtype)?;
+        }
+        match &self.target {
+            RouteTarget::Blackhole => write!(f, ",to=blackhole"),
+            RouteTarget::Upstreams(upstreams) => {
+                for upstream in upstreams {
+                    write!(f, ",to={}", upstream)?;
+                }
+                match self.hedge {
+                    Some(hedge) => write!(f, ",hedge={}", hedge),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Parses comma-separated `key=value` settings: `name` and `to` are required;
+/// `client`, `domain`, `domains`, `qtype` and `hedge` are optional; all but
+/// `name` and `hedge` are repeatable
+impl FromStr for RouteRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut blackhole = false;
+        let mut upstreams = Vec::new();
+        let mut rule = RouteRule {
+            name: String::new(),
+            clients: Vec::new(),
+            domains: Vec::new(),
+            domain_files: Vec::new(),
+            qtypes: Vec::new(),
+            target: RouteTarget::Blackhole,
+            hedge: None,
+        };
+
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got '{}'", setting))?;
+            match key {
+                "name" => {
+                    if value.is_empty()
+                        || !value
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+                    {
+                        return Err(format!("invalid route name '{}'", value));
+                    }
+                    name = Some(value.to_string());
+                }
+                "client" => rule.clients.push(value.parse()?),
+                "domain" if !value.is_empty() => rule.domains.push(normalize_name(value)),
+                "domains" => rule.domain_files.push(PathBuf::from(value)),
+                "qtype" => rule
+                    .qtypes
+                    .push(value.parse().map_err(|e: RegistryError| e.to_string())?),
+                "to" if value == "blackhole" => blackhole = true,
+                "to" => upstreams.push(value.parse()?),
+                "hedge" => rule.hedge = Some(value.parse()?),
+                _ => return Err(format!("unknown route setting '{}'", setting)),
+            }
+        }
+
+        rule.name = name.ok_or("route needs a name=<name>")?;
+        rule.target = match (blackhole, upstreams.is_empty()) {
+            (true, true) if rule.hedge.is_some() => {
+                return Err("a route that blackholes has no upstreams to race".into())
+            }
+            (true, true) => RouteTarget::Blackhole,
+            (false, false) => RouteTarget::Upstreams(upstreams),
+            (true, false) => return Err("a route can't both blackhole and forward".into()),
+            (false, true) => return Err("route needs a to=<ip>:<port> or to=blackhole".into()),
+        };
+        Ok(rule)
+    }
+}
+
+/// What a matching route does with a query
+#[derive(Debug)]
+pub enum RouteAction {
+    Blackhole,
+    Resolve(QueryActorHandle),
+}
+
+/// A route ready to match queries
+#[derive(Debug)]
+pub struct Route {
+    pub name: Arc<str>,
+    clients: Vec<Subnet>,
+    domains: HashSet<String>,
+    qtypes: Vec<RecordType>,
+    pub action: RouteAction,
+}
+
+impl Route {
+    /// A route resolving through `handle` that matches no query itself, for
+    /// those that pick their queries (client groups)
+    pub fn resolving(name: &str, handle: QueryActorHandle) -> Route {
+        Route {
+            name: name.into(),
+            clients: Vec::new(),
+            domains: HashSet::new(),
+            qtypes: Vec::new(),
+            action: RouteAction::Resolve(handle),
+        }
+    }
+
+    fn matches(&self, client: IpAddr, name: &str, qtype: RecordType) -> bool {
+        (self.clients.is_empty() || self.clients.iter().any(|subnet| subnet.contains(client)))
+            && (self.qtypes.is_empty() || self.qtypes.contains(&qtype))
+            && (self.domains.is_empty() || in_domains(&self.domains, name))
+    }
+}
+
+/// Whether `name` or one of its parent domains is in `domains`
+pub fn in_domains(domains: &HashSet<String>, name: &str) -> bool {
+    let name = normalize_name(name);
+    let mut suffix = name.as_str();
+    loop {
+        if domains.contains(suffix) {
+            return true;
+        }
+        match suffix.split_once('.') {
+            Some((_, parent)) => suffix = parent,
+            None => return false,
+        }
+    }
+}
+
+/// A tenant's routes, in order
+#[derive(Debug, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    /// Read the routes' domain lists and start resolvers for their upstreams
+    pub fn load(
+        rules: &[RouteRule],
+        workers: usize,
+        policies: &[UpstreamPolicy],
+        case_randomization: bool,
+        metrics: &Arc<Metrics>,
+    ) -> anyhow::Result<Router> {
+        let mut routes = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let mut domains: HashSet<String> = rule.domains.iter().cloned().collect();
+            for path in &rule.domain_files {
+                read_domains(path, &mut domains).with_context(|| {
+                    format!(
+                        "could not read domains of route {} from {}",
+                        rule.name,
+                        path.display()
+                    )
+                })?;
+            }
+            let action = match &rule.target {
+                RouteTarget::Blackhole => RouteAction::Blackhole,
+                RouteTarget::Upstreams(upstreams) => RouteAction::Resolve(QueryActorHandle::new(
+                    HedgedResolver::new(
+                        upstreams,
+                        rule.hedge,
+                        policies,
+                        case_randomization,
+                        Arc::clone(metrics),
+                    ),
+                    workers,
+                    Arc::clone(metrics),
+                )),
+            };
+            routes.push(Route {
+                name: rule.name.as_str().into(),
+                clients: rule.clients.clone(),
+                domains,
+                qtypes: rule.qtypes.clone(),
+                action,
+            });
+        }
+        Ok(Router { routes })
+    }
+
+    /// The first route matching a query, if any
+    pub fn route(&self, client: IpAddr, name: &str, qtype: RecordType) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(client, name, qtype))
+    }
+
+    /// The route called `name`
+    pub fn named(&self, name: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| &*route.name == name)
+    }
+
+    /// Each route's name and how many domains it matches on
+    pub fn summary(&self) -> Vec<(&str, usize)> {
+        self.routes
+            .iter()
+            .map(|route| (&*route.name, route.domains.len()))
+            .collect()
+    }
+}
+
+/// Add the domains listed in a file
+fn read_domains(path: &Path, domains: &mut HashSet<String>) -> std::io::Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    domains.extend(parse_domains(&contents));
+    Ok(())
+}
+
+/// The domains listed in a domain list file's contents
+pub fn parse_domains(contents: &str) -> impl Iterator<Item = String> + '_ {
+    contents.lines().filter_map(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let first = fields.next()?;
+        // Hosts-file lines name the domain after an address
+        let domain = match first.parse::<IpAddr>() {
+            Ok(_) => fields.next()?,
+            Err(_) => first,
+        };
+        Some(normalize_name(domain)).filter(|domain| !domain.is_empty())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_route() {
+        let rule: RouteRule =
+            "name=lab,client=192.168.1.0/24,domain=Lab.Example.,qtype=aaaa,to=10.0.0.1:53@10.0.0.2"
+                .parse()
+                .unwrap();
+        assert_eq!(rule.domains, vec!["lab.example"]);
+        assert_eq!(rule.qtypes, vec![RecordType::AAAA]);
+        assert_eq!(
+            rule.target,
+            RouteTarget::Upstreams(vec!["10.0.0.1:53@10.0.0.2".parse().unwrap()])
+        );
+        assert_eq!(rule.to_string().parse::<RouteRule>().unwrap(), rule);
+
+        let ads: RouteRule = "name=ads,domains=/etc/ads.txt,to=blackhole"
+            .parse()
+            .unwrap();
+        assert_eq!(ads.target, RouteTarget::Blackhole);
+
+        let raced: RouteRule = "name=cn,domain=cn,to=223.5.5.5:53,to=119.29.29.29:53,hedge=30"
+            .parse()
+            .unwrap();
+        assert_eq!(raced.hedge, Some("30/2".parse().unwrap()));
+        assert_eq!(raced.to_string().parse::<RouteRule>().unwrap(), raced);
+        assert!("name=ads,to=blackhole,hedge=30"
+            .parse::<RouteRule>()
+            .is_err());
+
+        assert!("domain=example.com,to=blackhole"
+            .parse::<RouteRule>()
+            .is_err());
+        assert!("name=ads,domain=example.com".parse::<RouteRule>().is_err());
+        assert!("name=ads,to=blackhole,to=1.1.1.1:53"
+            .parse::<RouteRule>()
+            .is_err());
+        assert!("name=ads,qtype=BOGUS,to=blackhole"
+            .parse::<RouteRule>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_route_matching() {
+        let rules: Vec<RouteRule> = [
+            "name=ads,domain=ads.example,to=blackhole",
+            "name=lab,client=192.168.1.0/24,qtype=AAAA,to=blackhole",
+        ]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+        let router = Router::load(&rules, 1, &[], true, &Arc::new(Metrics::new(1))).unwrap();
+        let lan: IpAddr = "192.168.1.7".parse().unwrap();
+        let wan: IpAddr = "203.0.113.7".parse().unwrap();
+        let tag = |client, name, qtype| router.route(client, name, qtype).map(|route| &*route.name);
+
+        assert_eq!(tag(wan, "ADS.example", RecordType::A), Some("ads"));
+        assert_eq!(tag(wan, "x.y.ads.example.", RecordType::A), Some("ads"));
+        assert_eq!(tag(wan, "badads.example", RecordType::A), None);
+        assert_eq!(tag(lan, "ads.example", RecordType::AAAA), Some("ads"));
+        assert_eq!(tag(lan, "example.com", RecordType::AAAA), Some("lab"));
+        assert_eq!(tag(lan, "example.com", RecordType::A), None);
+        assert_eq!(tag(wan, "example.com", RecordType::AAAA), None);
+    }
+
+    #[test]
+    fn test_parse_domains() {
+        let list = "# ads\nads.example\n0.0.0.0 Tracker.Example # hosts style\n\n::1 localhost\n";
+        let domains: Vec<String> = parse_domains(list).collect();
+        assert_eq!(domains, vec!["ads.example", "tracker.example", "localhost"]);
+    }
+}
@@ -0,0 +1,221 @@
+//! The order of records within an answer (`--rrset-order`)
+//!
+//! A name with several A records is answered with them in the order they
+//! were added or cached, so clients that simply take the first address all
+//! go to the same one. With `--rrset-order cyclic` each response with an
+//! RRset starts it one record further along than the last response with it,
+//! and with `random` every response is shuffled. Only the records of one
+//! RRset (the same name and type) trade places; a CNAME still comes before
+//! what it points at. The cache keeps the order it was given, so any answer,
+//! local, cached or forwarded, is reordered on its way out.
+//!
+//! Where each RRset is in its rotation is kept for 16 384 RRsets at most;
+//! a share of the table that fills up is started over, which only a flood
+//! of names would bring.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Mutex, PoisonError};
+
+use clap::ValueEnum;
+use futures::future::BoxFuture;
+use rand::seq::SliceRandom;
+
+use crate::pipeline::{Answer, Middleware, Next, Request};
+use crate::protocol::DnsResourceRecord;
+use crate::registry::RecordType;
+
+/// Locks the RRsets' turns are spread over
+const LOCKS: usize = 16;
+
+/// Most RRsets whose turns are kept
+const MAX_RRSETS: usize = 16_384;
+
+/// How the records of an RRset are ordered in answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum RrsetOrder {
+    /// As added or received
+    #[default]
+    Fixed,
+    /// Rotated by one record every response
+    Cyclic,
+    /// Shuffled every response
+    Random,
+}
+
+impl fmt::Display for RrsetOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RrsetOrder::Fixed => "fixed",
+            RrsetOrder::Cyclic => "cyclic",
+            RrsetOrder::Random => "random",
+        })
+    }
+}
+
+/// The turns of the RRsets behind one lock
+type Turns = HashMap<(String, RecordType), usize>;
+
+/// Reorders the RRsets of every answer
+#[derive(Debug)]
+pub struct Reorder {
+    order: RrsetOrder,
+    hasher: RandomState,
+    /// Responses each RRset was rotated in so far, for cyclic order, by
+    /// lower-cased name and type
+    turns: Box<[Mutex<Turns>]>,
+}
+
+impl Reorder {
+    pub fn new(order: RrsetOrder) -> Self {
+        Reorder {
+            order,
+            hasher: RandomState::new(),
+            turns: (0..LOCKS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    /// The RRset's turn, counting this response
+    fn turn(&self, name: &str, rtype: RecordType) -> usize {
+        let key = (name.to_ascii_lowercase(), rtype);
+        let lock = &self.turns[self.hasher.hash_one(&key) as usize % LOCKS];
+        let mut turns = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if !turns.contains_key(&key) && turns.len() >= MAX_RRSETS / LOCKS {
+            turns.clear();
+        }
+        let turn = turns.entry(key).or_default();
+        let current = *turn;
+        *turn = current.wrapping_add(1);
+        current
+    }
+
+    /// Reorder each run of records of the same name and type
+    fn reorder(&self, records: &mut [DnsResourceRecord]) {
+        if self.order == RrsetOrder::Fixed {
+            return;
+        }
+        let mut rng = rand::rng();
+        let mut start = 0;
+        while start < records.len() {
+            let first = &records[start];
+            let len = records[start..]
+                .iter()
+                .take_while(|record| {
+                    record.rtype == first.rtype && record.name.eq_ignore_ascii_case(&first.name)
+                })
+                .count();
+            let rrset = &mut records[start..start + len];
+            match self.order {
+                // A single record has no turns to keep
+                RrsetOrder::Cyclic if len > 1 => {
+                    let turn = self.turn(&rrset[0].name, rrset[0].rtype);
+                    rrset.rotate_left(turn % len);
+                }
+                RrsetOrder::Random => rrset.shuffle(&mut rng),
+                _ => {}
+            }
+            start += len;
+        }
+    }
+}
+
+impl Middleware for Reorder {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        Box::pin(async move {
+            let mut answer = next.run(request).await;
+            self.reorder(&mut answer.answers);
+            answer
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_records::LocalRecord;
+
+    fn records(lines: &[&str]) -> Vec<DnsResourceRecord> {
+        lines
+            .iter()
+            .map(|line| line.parse::<LocalRecord>().unwrap().to_resource_record())
+            .collect()
+    }
+
+    fn addresses(records: &[DnsResourceRecord]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| format!("{}/{}", record.name, record.rdata.last().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_cyclic_order() {
+        let reorder = Reorder::new(RrsetOrder::Cyclic);
+        let mut answer = records(&[
+            "www.lan CNAME web.lan",
+            "web.lan A 10.0.0.1",
+            "web.lan A 10.0.0.2",
+            "web.lan A 10.0.0.3",
+        ]);
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            let mut copy = answer.clone();
+            reorder.reorder(&mut copy);
+            assert_eq!(copy[0].rtype, answer[0].rtype);
+            seen.push(addresses(&copy[1..]));
+        }
+        assert_eq!(
+            seen,
+            [
+                ["web.lan/1", "web.lan/2", "web.lan/3"],
+                ["web.lan/2", "web.lan/3", "web.lan/1"],
+                ["web.lan/3", "web.lan/1", "web.lan/2"],
+                ["web.lan/1", "web.lan/2", "web.lan/3"],
+            ]
+        );
+
+        // Each RRset has turns of its own
+        let mut other = records(&["db.lan A 10.0.0.4", "db.lan A 10.0.0.5"]);
+        reorder.reorder(&mut other);
+        assert_eq!(addresses(&other), ["db.lan/4", "db.lan/5"]);
+        let mut copy = answer.clone();
+        copy[1..]
+            .iter_mut()
+            .for_each(|record| record.name = "WEB.lan".to_string());
+        reorder.reorder(&mut copy);
+        assert_eq!(
+            addresses(&copy[1..]),
+            ["WEB.lan/2", "WEB.lan/3", "WEB.lan/1"]
+        );
+
+        // Fixed order leaves answers alone
+        let before = addresses(&answer);
+        Reorder::new(RrsetOrder::Fixed).reorder(&mut answer);
+        assert_eq!(addresses(&answer), before);
+    }
+
+    #[test]
+    fn test_random_order() {
+        let reorder = Reorder::new(RrsetOrder::Random);
+        let answer = records(&[
+            "a.lan A 10.0.0.1",
+            "a.lan A 10.0.0.2",
+            "a.lan A 10.0.0.3",
+            "a.lan A 10.0.0.4",
+            "b.lan A 10.0.0.5",
+        ]);
+        let mut orders = std::collections::HashSet::new();
+        for _ in 0..50 {
+            let mut copy = answer.clone();
+            reorder.reorder(&mut copy);
+            let mut sorted = addresses(&copy[..4]);
+            orders.insert(sorted.clone());
+            sorted.sort();
+            // Still the same RRset, and the next one stays behind it
+            assert_eq!(sorted, addresses(&answer[..4]));
+            assert_eq!(addresses(&copy[4..]), ["b.lan/5"]);
+        }
+        assert!(orders.len() > 1);
+    }
+}
@@ -0,0 +1,333 @@
+//! Query policies written in Rhai (`--script`)
+//!
+//! A script defines either or both of two functions, called for every
+//! question the default tenant answers:
+//!
+//! - `on_query(query)`, before the question is answered, where `query` is
+//!   `#{ name, qtype, qclass, client, rd }`
+//! - `on_response(query, response)`, with what the server would answer, where
+//!   `response` is `#{ rcode, answers, source }`, each answer written as its
+//!   type and data (`"A 192.0.2.1"`) and `source` where it came from
+//!   (`local`, `forwarded`, `cached`, `blocked` or `failed`)
+//!
+//! Each returns a verdict, or nothing to let the question go on as it would:
+//!
+//! - `block()` answers NXDOMAIN, counted as blocked
+//! - `refuse()` answers REFUSED
+//! - `answer(data)` or `answer([data, ...])`, optionally with a TTL as
+//!   `answer(data, ttl)`, answers with records of the question's type holding
+//!   `data`, written as for `--record` (`"192.0.2.1"`, `"10 mail.example.com"`)
+//! - `route(name)`, from `on_query` only, resolves the question through the
+//!   upstreams of the `--route` of that name
+//!
+//! ```text
+//! fn on_query(query) {
+//!     if query.name.ends_with(".corp.example") && !query.client.starts_with("10.") {
+//!         return refuse();
+//!     }
+//!     if query.name == "printer.lan" { return answer("192.168.1.40", 60); }
+//!     if query.name.ends_with(".internal") { return route("office"); }
+//! }
+//!
+//! fn on_response(query, response) {
+//!     for answer in response.answers {
+//!         if answer == "A 0.0.0.0" { return block(); }
+//!     }
+//! }
+//! ```
+//!
+//! The script's directory is watched and the script compiled again when it
+//! changes; one that fails to compile is logged and the last good version
+//! kept. A call that fails, or runs more than 100 000 operations, fails as
+//! any policy does (see `policy.rs`). `print` and `debug` write to the
+//! server's log.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use anyhow::Context;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, FuncArgs, ImmutableString, Map, Scope, AST};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+use crate::policy::{Policy, Query, Response, Verdict};
+
+/// Most operations one call may run, so a runaway loop can't hold a query
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// How long the script has to stay quiet after a change before it is
+/// compiled again
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// A compiled script, kept up to date with its file
+pub struct Script {
+    path: PathBuf,
+    engine: Engine,
+    ast: RwLock<Arc<AST>>,
+}
+
+impl Script {
+    /// Compile the script at `path`
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let engine = engine();
+        let path = std::path::absolute(path)?;
+        let ast = compile(&engine, &path)?;
+        Ok(Script {
+            path,
+            engine,
+            ast: RwLock::new(Arc::new(ast)),
+        })
+    }
+
+    /// Compile the script again, keeping the last good version if it fails
+    fn reload(&self) -> anyhow::Result<()> {
+        let ast = compile(&self.engine, &self.path)?;
+        *self.ast.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(ast);
+        Ok(())
+    }
+
+    fn ast(&self) -> Arc<AST> {
+        Arc::clone(&self.ast.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Call `function`, if the script defines it, for its verdict
+    fn call(&self, function: &str, args: impl FuncArgs) -> anyhow::Result<Option<Verdict>> {
+        let ast = self.ast();
+        if !ast.iter_functions().any(|f| f.name == function) {
+            return Ok(None);
+        }
+        // The top level only defines functions, so isn't run again
+        let options = CallFnOptions::new().eval_ast(false);
+        let verdict: Dynamic = self
+            .engine
+            .call_fn_with_options(options, &mut Scope::new(), &ast, function, args)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        if verdict.is_unit() {
+            return Ok(None);
+        }
+        let kind = verdict.type_name();
+        verdict
+            .try_cast::<Verdict>()
+            .map(Some)
+            .with_context(|| format!("{} returned a {}, not a verdict", function, kind))
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Script {}", self.path.display())
+    }
+}
+
+impl Policy for Script {
+    fn on_query(&self, query: &Query) -> anyhow::Result<Option<Verdict>> {
+        self.call("on_query", (query_map(query),))
+    }
+
+    fn on_response(&self, query: &Query, response: &Response) -> anyhow::Result<Option<Verdict>> {
+        self.call("on_response", (query_map(query), response_map(response)))
+    }
+}
+
+/// The question as scripts see it
+fn query_map(query: &Query) -> Map {
+    let mut map = Map::new();
+    map.insert("name".into(), query.name.clone().into());
+    map.insert("qtype".into(), query.qtype.clone().into());
+    map.insert("qclass".into(), query.qclass.clone().into());
+    map.insert("client".into(), query.client.clone().into());
+    map.insert("rd".into(), query.rd.into());
+    map
+}
+
+/// The answer as scripts see it
+fn response_map(response: &Response) -> Map {
+    let answers: Array = response
+        .answers
+        .iter()
+        .map(|answer| answer.clone().into())
+        .collect();
+    let mut map = Map::new();
+    map.insert("rcode".into(), response.rcode.clone().into());
+    map.insert("answers".into(), answers.into());
+    map.insert("source".into(), response.source.into());
+    map
+}
+
+/// An engine with the verdict functions
+fn engine() -> Engine {
+    fn data(values: Array) -> Vec<String> {
+        values.iter().map(Dynamic::to_string).collect()
+    }
+    fn ttl(ttl: i64) -> Option<u32> {
+        Some(u32::try_from(ttl).unwrap_or(0))
+    }
+
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|text| info!("Script: {}", text));
+    engine.on_debug(|text, _, position| debug!("Script ({}): {}", position, text));
+    engine
+        .register_type_with_name::<Verdict>("Verdict")
+        .register_fn("block", || Verdict::Block)
+        .register_fn("refuse", || Verdict::Refuse)
+        .register_fn("route", |name: ImmutableString| Verdict::Route {
+            name: name.to_string(),
+        })
+        .register_fn("answer", |value: ImmutableString| Verdict::Answer {
+            data: vec![value.to_string()],
+            ttl: None,
+        })
+        .register_fn("answer", |value: ImmutableString, seconds: i64| {
+            Verdict::Answer {
+                data: vec![value.to_string()],
+                ttl: ttl(seconds),
+            }
+        })
+        .register_fn("answer", |values: Array| Verdict::Answer {
+            data: data(values),
+            ttl: None,
+        })
+        .register_fn("answer", |values: Array, seconds: i64| Verdict::Answer {
+            data: data(values),
+            ttl: ttl(seconds),
+        });
+    engine
+}
+
+fn compile(engine: &Engine, path: &Path) -> anyhow::Result<AST> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read script {}", path.display()))?;
+    engine
+        .compile(source)
+        .map_err(|e| anyhow::anyhow!("script {}: {}", path.display(), e))
+}
+
+/// Compile the script again whenever it changes, in the background
+pub fn watch_script(script: Arc<Script>) -> anyhow::Result<()> {
+    // The watcher calls back from its own thread
+    let (sender, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if !matches!(event.kind, EventKind::Access(_)) {
+                for path in event.paths {
+                    let _ = sender.send(path);
+                }
+            }
+        }
+    })?;
+    // Watch the directory rather than the file: editors often save by
+    // writing a new file and renaming it over the old one
+    if let Some(directory) = script.path.parent() {
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .with_context(|| format!("could not watch {}", directory.display()))?;
+    }
+
+    tokio::spawn(async move {
+        // Dropping the watcher would stop the notifications
+        let _watcher: RecommendedWatcher = watcher;
+
+        while let Some(path) = changes.recv().await {
+            let mut changed = BTreeSet::from([path]);
+            while let Ok(Some(path)) = timeout(SETTLE_TIME, changes.recv()).await {
+                changed.insert(path);
+            }
+            if !changed.contains(&script.path) {
+                continue;
+            }
+            match script.reload() {
+                Ok(()) => info!("Script {} reloaded", script.path.display()),
+                Err(e) => warn!(
+                    "Script {} not reloaded, still running the previous version: {:#}",
+                    script.path.display(),
+                    e
+                ),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, client: &str) -> Map {
+        let mut query = Map::new();
+        query.insert("name".into(), name.into());
+        query.insert("qtype".into(), "A".into());
+        query.insert("client".into(), client.into());
+        query
+    }
+
+    #[test]
+    fn test_script_verdicts() {
+        let path = std::env::temp_dir().join(format!("dns-script-{}.rhai", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            fn on_query(query) {
+                if query.name.ends_with(".corp.example") && !query.client.starts_with("10.") {
+                    return refuse();
+                }
+                if query.name == "printer.lan" { return answer(["192.168.1.40", "192.168.1.41"], 60); }
+                if query.name.ends_with(".internal") { return route("office"); }
+                if query.name == "bad.example" { return 42; }
+                if query.name == "loop.example" { loop {} }
+            }
+            "#,
+        )
+        .unwrap();
+        let script = Script::load(&path).unwrap();
+        let verdict = |name: &str, client: &str| script.call("on_query", (query(name, client),));
+
+        assert_eq!(
+            verdict("www.corp.example", "192.0.2.7").unwrap(),
+            Some(Verdict::Refuse)
+        );
+        assert_eq!(verdict("www.corp.example", "10.0.0.7").unwrap(), None);
+        assert_eq!(
+            verdict("printer.lan", "10.0.0.7").unwrap(),
+            Some(Verdict::Answer {
+                data: vec!["192.168.1.40".to_string(), "192.168.1.41".to_string()],
+                ttl: Some(60)
+            })
+        );
+        assert_eq!(
+            verdict("git.internal", "10.0.0.7").unwrap(),
+            Some(Verdict::Route {
+                name: "office".to_string()
+            })
+        );
+        assert!(verdict("bad.example", "10.0.0.7").is_err());
+        assert!(verdict("loop.example", "10.0.0.7").is_err());
+        // Not defined at all
+        assert_eq!(
+            script
+                .call("on_response", (query("a.example", "10.0.0.7"), Map::new()))
+                .unwrap(),
+            None
+        );
+
+        // A broken edit keeps the last good version; a good one replaces it
+        std::fs::write(&path, "fn on_query(query) { block( }").unwrap();
+        assert!(script.reload().is_err());
+        assert_eq!(
+            verdict("www.corp.example", "192.0.2.7").unwrap(),
+            Some(Verdict::Refuse)
+        );
+        std::fs::write(&path, "fn on_query(query) { block() }").unwrap();
+        script.reload().unwrap();
+        assert_eq!(
+            verdict("www.corp.example", "10.0.0.7").unwrap(),
+            Some(Verdict::Block)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1546 @@
+//! The server as a library: `DnsServer`
+//!
+//! A `DnsServer` is everything the `dns-server` binary runs, configured in
+//! code instead of on the command line. The builder covers what embedders
+//! usually set, the listener, upstreams and cache, and adds query hooks,
+//! called with every answered query's `QueryEvent`:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use dns_server::{CacheConfig, DnsServer};
+//!
+//! DnsServer::builder()
+//!     .listen("127.0.0.1:5353".parse()?)
+//!     .upstream("9.9.9.9:53".parse().map_err(anyhow::Error::msg)?)
+//!     .cache(CacheConfig {
+//!         max_entries: 50_000,
+//!         ..CacheConfig::default()
+//!     })
+//!     .on_query(|event| println!("{} {} {}", event.client, event.name, event.rcode))
+//!     .build()
+//!     .run()
+//!     .await
+//! # }
+//! ```
+//!
+//! Anything else the command line can set is reached through
+//! [`DnsServerBuilder::args`], starting from [`Args`] as parsed or as
+//! defaulted by [`Args::defaults`].
+
+use std::future::Future;
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWrite;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+use crate::block_page::BlockPage;
+use crate::blocklist::{self, Allowlist, Blocklist, PublishedBlocklist};
+use crate::cache_snapshot;
+use crate::catalog;
+use crate::cli::Args;
+use crate::control::{self, ControlContext};
+use crate::control_tls::{self, ControlTls};
+use crate::dnssec::SignedZones;
+use crate::geoip::{GeoIp, Locator};
+use crate::groups::{self, ClientGroup, ClientGroups, GroupBlocking};
+use crate::handlers::cache_handler::{CacheConfig, CacheHandle};
+use crate::handlers::dedup_handler::DedupHandle;
+use crate::handlers::inflight_handler::InflightHandle;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::handlers::query_handler::QueryActorHandle;
+use crate::health;
+use crate::hedge::HedgedResolver;
+use crate::hosts;
+use crate::identity::Identity;
+use crate::local_records::LocalRecord;
+use crate::mdns::{self, MdnsProxy};
+use crate::metrics::Metrics;
+use crate::pipeline::{Middleware, Pipeline};
+#[cfg(feature = "wasm")]
+use crate::plugin::Plugin;
+use crate::policy::PolicyLayer;
+use crate::processor::{process_dns_query, QueryContext, Responder, DUPLICATE_WINDOW};
+use crate::proxy::Proxy;
+#[cfg(feature = "sqlite")]
+use crate::query_db::{self, QueryDb};
+use crate::query_log::{LogStyle, QueryEvent, QueryHook, QueryLog, QueryLogFormat};
+use crate::recursor::Recursor;
+use crate::reload::Reloader;
+use crate::reverse::ReverseLookups;
+use crate::routing::{Route, Router};
+use crate::rrset_order::{Reorder, RrsetOrder};
+use crate::script::{self, Script};
+use crate::service::{self, ReloadSignal};
+use crate::shared_cache::{self, SharedCache};
+use crate::stats::{self, QueryStats};
+use crate::tcp;
+use crate::tenant::{self, Tenant, DEFAULT_TENANT};
+use crate::tunnel::TunnelDetector;
+use crate::udp;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::udp::UdpSender;
+use crate::update::Primaries;
+use crate::upstream::{self, Transport, Upstream};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring;
+use crate::view::{self, View};
+use crate::zone_file::{self, LocalZones};
+
+/// How long a client's TCP connection is kept open without a query
+/// (RFC 7766 §6.2.3)
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many responses a TCP connection holds while they wait to be written
+const TCP_PENDING_RESPONSES: usize = 16;
+
+/// TCP connections a tenant serves at once; more wait in the listen backlog
+/// until one closes (RFC 7766 §6.2.2)
+const TCP_MAX_CONNECTIONS: usize = 256;
+
+/// Queries of one TCP connection answered at once; the connection isn't read
+/// further until one of them is answered
+const TCP_MAX_IN_FLIGHT: usize = 16;
+
+/// A configured server, ready to run
+pub struct DnsServer {
+    args: Args,
+    cache: CacheConfig,
+    hooks: Vec<QueryHook>,
+    /// Layers added to every tenant's pipeline
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+/// Configures a [`DnsServer`]; starts from the command line's defaults
+pub struct DnsServerBuilder {
+    server: DnsServer,
+}
+
+impl DnsServer {
+    pub fn builder() -> DnsServerBuilder {
+        DnsServerBuilder::default()
+    }
+
+    /// The server the command line describes
+    pub fn from_args(args: Args) -> Self {
+        DnsServer {
+            cache: args.cache_config(),
+            args,
+            hooks: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Serve until SIGINT or SIGTERM, or until a listener fails. SIGHUP
+    /// reloads the blocklists and zone files.
+    pub async fn run(self) -> anyhow::Result<()> {
+        self.run_until(service::shutdown_signal()).await
+    }
+
+    /// Serve until `shutdown` completes, or until a listener fails. Every
+    /// tenant is started before any is served, so a bad one fails the call
+    /// before a query is answered.
+    pub async fn run_until(
+        self,
+        shutdown: impl Future<Output = std::io::Result<()>>,
+    ) -> anyhow::Result<()> {
+        let args = &self.args;
+        // Client locations for GeoDNS and site steering, shared by every tenant
+        let geoip = match &args.geoip_db {
+            Some(path) => {
+                let geoip = GeoIp::open(path)
+                    .with_context(|| format!("could not open GeoIP database {}", path.display()))?;
+                info!(
+                    "Loaded GeoIP database {} ({})",
+                    path.display(),
+                    geoip.database_type()
+                );
+                Some(geoip)
+            }
+            None => None,
+        };
+        let locator = Arc::new(Locator {
+            geoip,
+            sites: args.sites.clone(),
+        });
+
+        // The TLS every control socket speaks, if any
+        let control_tls = match (
+            &args.control_tls_cert,
+            &args.control_tls_key,
+            &args.control_client_ca,
+        ) {
+            (Some(cert), Some(key), Some(client_ca)) => {
+                Some(control_tls::acceptor(cert, key, client_ca)?)
+            }
+            _ => None,
+        };
+
+        let tenants = args.all_tenants();
+        tenant::check_distinct(&tenants).map_err(anyhow::Error::msg)?;
+
+        let mut started = Vec::new();
+        for tenant in &tenants {
+            started.push(
+                start_tenant(tenant, &self, Arc::clone(&locator), control_tls.as_ref()).await?,
+            );
+        }
+
+        let mut listeners = JoinSet::new();
+        let mut snapshots = Vec::new();
+        let mut reloaders = Vec::new();
+        for tenant in started {
+            for shard in tenant.shards {
+                listeners.spawn(serve(shard, args.io_uring));
+            }
+            listeners.spawn(serve_tcp(tenant.tcp));
+            snapshots.extend(tenant.snapshot);
+            reloaders.push(tenant.reloader);
+        }
+
+        // A listener only stops on a socket error, which takes the server down
+        tokio::pin!(shutdown);
+        let mut reload_signal = ReloadSignal::new()?;
+        loop {
+            tokio::select! {
+                result = listeners.join_next() => match result {
+                    Some(result) => result??,
+                    None => break,
+                },
+                () = reload_signal.recv() => {
+                    info!("Reloading blocklists and zone files (SIGHUP)");
+                    for reloader in &reloaders {
+                        let reloader = reloader.clone();
+                        tokio::spawn(async move { reloader.reload_all().await });
+                    }
+                }
+                result = &mut shutdown => {
+                    result?;
+                    info!("Shutting down");
+                    break;
+                }
+            }
+        }
+
+        // The cache is kept for the next start
+        for (path, cache) in snapshots {
+            match cache_snapshot::save(&path, &cache).await {
+                Ok(count) => info!(
+                    "Cache snapshot written to {} ({} answers)",
+                    path.display(),
+                    count
+                ),
+                Err(e) => error!("Could not write the cache snapshot: {:#}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for DnsServerBuilder {
+    fn default() -> Self {
+        DnsServerBuilder {
+            server: DnsServer::from_args(Args::defaults()),
+        }
+    }
+}
+
+impl DnsServerBuilder {
+    /// Start from options parsed elsewhere, such as from a command line
+    pub fn args(args: Args) -> Self {
+        DnsServerBuilder {
+            server: DnsServer::from_args(args),
+        }
+    }
+
+    /// Receive queries on `addr`, over UDP and TCP
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.server.args.listen = addr;
+        self
+    }
+
+    /// Receive queries on `shards` sockets bound to the listen address; see
+    /// `--shards`
+    pub fn shards(mut self, shards: u16) -> Self {
+        self.server.args.shards = shards;
+        self
+    }
+
+    /// Serve the control socket on `addr`
+    pub fn control(mut self, addr: SocketAddr) -> Self {
+        self.server.args.control = Some(addr);
+        self
+    }
+
+    /// Forward to `upstream`, after the upstreams added before it
+    pub fn upstream(mut self, upstream: Upstream) -> Self {
+        self.server.args.resolvers.push(upstream);
+        self
+    }
+
+    /// Whether the resolvers the OS is configured with are used as well as
+    /// the upstreams given; they are unless turned off
+    pub fn system_resolvers(mut self, enabled: bool) -> Self {
+        self.server.args.no_system_resolvers = !enabled;
+        self
+    }
+
+    /// Answer `record` locally, before any forwarding
+    pub fn record(mut self, record: LocalRecord) -> Self {
+        self.server.args.records.push(record);
+        self
+    }
+
+    /// Size, evict and refresh each cache as `config` says
+    pub fn cache(mut self, config: CacheConfig) -> Self {
+        self.server.cache = config;
+        self
+    }
+
+    /// Call `hook` with every query answered, of every tenant. Hooks run on
+    /// the query's task, so they should be quick; slow work belongs on a
+    /// task of its own.
+    pub fn on_query(mut self, hook: impl Fn(&QueryEvent) + Send + Sync + 'static) -> Self {
+        self.server.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Answer every question of every tenant through `middleware` first,
+    /// after the layers added before it; see [`Middleware`]
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.server.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn build(self) -> DnsServer {
+        self.server
+    }
+}
+
+/// A tenant whose sockets are bound and whose subsystems are running
+struct TenantServer {
+    shards: Vec<Shard>,
+    tcp: TcpShard,
+    /// Where its cache is written on shutdown, with `--cache-snapshot`
+    snapshot: Option<(PathBuf, CacheHandle)>,
+    /// Its blocklists and zone files, reloaded on SIGHUP
+    reloader: Reloader,
+}
+
+/// One of a tenant's receive sockets and the pipeline behind it: its own
+/// resolver workers and duplicate tracking, so shards don't contend
+struct Shard {
+    sock: UdpSocket,
+    context: QueryContext,
+    /// The pipelines of the tenant's views, in the order they are tried
+    views: Vec<(Arc<View>, QueryContext)>,
+}
+
+/// A tenant's TCP listener, answering through the pipeline of its first shard
+struct TcpShard {
+    listener: TcpListener,
+    context: QueryContext,
+    views: Vec<(Arc<View>, QueryContext)>,
+}
+
+/// The pipeline of the first view holding the client, or the tenant's own
+fn select_view<'a>(
+    context: &'a QueryContext,
+    views: &'a [(Arc<View>, QueryContext)],
+    client: IpAddr,
+) -> &'a QueryContext {
+    views
+        .iter()
+        .find(|(view, _)| view.matches(client))
+        .map_or(context, |(_, context)| context)
+}
+
+/// A view's records and zones, and the resolver, cache and relay of the
+/// upstreams it forwards to when it has its own
+struct ViewState {
+    view: Arc<View>,
+    local_records: LocalRecordsHandle,
+    zones: Arc<LocalZones>,
+    forwarding: Option<ViewForwarding>,
+}
+
+struct ViewForwarding {
+    resolver: HedgedResolver,
+    cache: CacheHandle,
+    inflight: InflightHandle,
+    proxy: Option<Arc<Proxy>>,
+}
+
+/// Bind a tenant's listeners and start its own resolver, records, counters,
+/// health checks and control socket
+async fn start_tenant(
+    tenant: &Tenant,
+    server: &DnsServer,
+    locator: Arc<Locator>,
+    control_tls: Option<&TlsAcceptor>,
+) -> anyhow::Result<TenantServer> {
+    let args = &server.args;
+    let socks = udp::bind_shards(tenant.listen, args.shards()).with_context(|| {
+        format!(
+            "tenant {} could not listen on {}",
+            tenant.name, tenant.listen
+        )
+    })?;
+    let listener = TcpListener::bind(tenant.listen).await.with_context(|| {
+        format!(
+            "tenant {} could not listen on {} (TCP)",
+            tenant.name, tenant.listen
+        )
+    })?;
+    let token = tenant.read_token()?;
+
+    let upstreams = upstream::select_upstreams(&tenant.upstreams, args.use_system_resolvers());
+
+    // With --recursive the default tenant resolves from the root servers instead
+    let recursor = (args.recursive && tenant.name == DEFAULT_TENANT).then(|| {
+        info!("Resolving names recursively from the root servers");
+        Arc::new(Recursor::default().with_case_randomization(args.case_randomization()))
+    });
+
+    // Counters shared by every query task
+    let metrics = Arc::new(Metrics::new(socks.len()));
+
+    // Create a new resolver instance (UDP, retried over TCP when truncated).
+    let resolver = HedgedResolver::new(
+        &upstreams,
+        tenant.hedge,
+        &args.upstream_policies,
+        args.case_randomization(),
+        Arc::clone(&metrics),
+    );
+
+    // Queries tagged by --route go to upstreams of their own (default tenant only)
+    let router = if tenant.name == DEFAULT_TENANT {
+        Router::load(
+            &args.routes,
+            args.resolver_workers.into(),
+            &args.upstream_policies,
+            args.case_randomization(),
+            &metrics,
+        )?
+    } else {
+        Router::default()
+    };
+    for (name, domains) in router.summary() {
+        info!("Route {} loaded ({} domains)", name, domains);
+    }
+    let router = Arc::new(router);
+
+    // Names answered NXDOMAIN or 0.0.0.0 instead of resolved (default tenant only)
+    // Files are read now, while lists to download wait for the reloads
+    let (blocklist, allowlist) = if tenant.name == DEFAULT_TENANT {
+        let files = args.blocklists.iter().filter(|source| !source.is_remote());
+        (
+            Blocklist::load(files, args.block_mode)?,
+            Allowlist::new(&args.allowed),
+        )
+    } else {
+        Default::default()
+    };
+    if !blocklist.is_empty() {
+        info!("Blocklist loaded ({} domains)", blocklist.len());
+    }
+    let sources = if tenant.name == DEFAULT_TENANT {
+        args.blocklists.clone()
+    } else {
+        Vec::new()
+    };
+    let blocklist = Arc::new(PublishedBlocklist::new(blocklist, allowlist).with_sources(sources));
+    if tenant.name == DEFAULT_TENANT && args.blocklists.iter().any(|source| source.is_remote()) {
+        tokio::spawn(blocklist::refresh_blocklist(
+            args.blocklist_refresh_interval(),
+            Arc::clone(&blocklist),
+        ));
+    }
+
+    // Who may query the tenant, and how fast
+    let acl = Arc::new(tenant.acl());
+    let quotas = tenant.quotas().map(Arc::new);
+
+    // The server's own hostname and DDR records (default tenant only)
+    let identity = match &args.server_name {
+        Some(name) if tenant.name == DEFAULT_TENANT => {
+            let mut addresses = args.advertised.clone();
+            if addresses.is_empty() && !tenant.listen.ip().is_unspecified() {
+                addresses.push(tenant.listen.ip());
+            }
+            Identity::new(name, addresses, args.ddr.clone())
+        }
+        _ => Identity::default(),
+    };
+    let identity = Arc::new(identity);
+
+    // Records answered locally, managed through the control socket
+    let local_records_handle = LocalRecordsHandle::new();
+
+    // Records given at startup, from --record and hosts files
+    if tenant.name == DEFAULT_TENANT {
+        let mut records = args.records.clone();
+        for path in &args.hosts_files {
+            records.extend(hosts::load_hosts_file(path)?);
+        }
+        if !records.is_empty() {
+            let diff = local_records_handle.import(records, false, false).await;
+            info!(
+                "Loaded {} local records from the configuration",
+                diff.added.len()
+            );
+        }
+    }
+
+    // The .local records, answered over mDNS too (default tenant only)
+    if args.mdns && tenant.name == DEFAULT_TENANT {
+        let records = local_records_handle.clone();
+        let interfaces = args.mdns_interfaces.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mdns::run_responder(records, interfaces).await {
+                error!("mDNS responder stopped: {}", e);
+            }
+        });
+    }
+
+    // Fail over local records whose health checks fail
+    tokio::spawn(health::run_health_checks(
+        local_records_handle.clone(),
+        args.health_check_interval(),
+        args.health_check_damping,
+    ));
+
+    // Clients of the views' subnets get records of their own (default tenant only)
+    let views: &[View] = if tenant.name == DEFAULT_TENANT {
+        &args.views
+    } else {
+        &[]
+    };
+    view::check_distinct(views).map_err(anyhow::Error::msg)?;
+    let mut view_states = Vec::with_capacity(views.len());
+    for view in views {
+        let local_records = LocalRecordsHandle::new();
+        let records = view
+            .load_records()
+            .with_context(|| format!("view {}", view.name))?;
+        local_records.import(records, false, false).await;
+        tokio::spawn(health::run_health_checks(
+            local_records.clone(),
+            args.health_check_interval(),
+            args.health_check_damping,
+        ));
+        zone_file::watch_zone_files(&view.zone_files, local_records.clone())
+            .await
+            .with_context(|| format!("view {}", view.name))?;
+
+        let forwarding = if view.upstreams.is_empty() {
+            None
+        } else {
+            check_relayable(args, &view.upstreams, &format!("view {}", view.name))?;
+            Some(ViewForwarding {
+                resolver: HedgedResolver::new(
+                    &view.upstreams,
+                    view.hedge,
+                    &args.upstream_policies,
+                    args.case_randomization(),
+                    Arc::clone(&metrics),
+                ),
+                cache: CacheHandle::new(server.cache, Arc::clone(&metrics)),
+                inflight: InflightHandle::new(),
+                proxy: args
+                    .transparent
+                    .then(|| Arc::new(Proxy::new(view.upstreams.clone()))),
+            })
+        };
+        info!(
+            "View {} loaded ({} subnets, {} records, {} zone files)",
+            view.name,
+            view.clients.len(),
+            local_records.list().await.len(),
+            view.zone_files.len()
+        );
+        view_states.push(ViewState {
+            view: Arc::new(view.clone()),
+            local_records,
+            zones: Arc::new(LocalZones::new(&view.zone_files)),
+            forwarding,
+        });
+    }
+
+    // Clients with a blocklist, rewrites and upstreams of their own (default
+    // tenant only)
+    let groups = if tenant.name == DEFAULT_TENANT {
+        load_groups(args, &metrics).await?
+    } else {
+        ClientGroups::default()
+    };
+    let groups = Arc::new(groups);
+
+    // Where dynamic updates are forwarded; catalogs add their member zones
+    let primaries = Arc::new(Primaries::new(&tenant.update_forwards));
+
+    // Secondary zones provisioned from catalogs
+    for source in &tenant.catalogs {
+        tokio::spawn(catalog::run_catalog(
+            source.clone(),
+            local_records_handle.clone(),
+            Arc::clone(&primaries),
+        ));
+    }
+
+    // Zones served from files, reloaded as they change
+    zone_file::watch_zone_files(&tenant.zone_files, local_records_handle.clone())
+        .await
+        .with_context(|| format!("tenant {}", tenant.name))?;
+
+    // What reload-blocklists, reload-zone and SIGHUP reload
+    let mut reloader = Reloader::default();
+    reloader.add_blocklist("blocklist", &blocklist);
+    for (name, blocklist) in groups.blocklists() {
+        reloader.add_blocklist(format!("blocklist of group {}", name), blocklist);
+    }
+    reloader.add_zone_files(&tenant.zone_files, &local_records_handle);
+    for state in &view_states {
+        reloader.add_zone_files(&state.view.zone_files, &state.local_records);
+    }
+
+    // Zones served signed (default tenant only)
+    let dnssec = if tenant.name == DEFAULT_TENANT {
+        SignedZones::load(&args.dnssec_keys)?
+    } else {
+        SignedZones::default()
+    };
+    for zone in dnssec.iter() {
+        for key in &zone.keys {
+            info!(
+                "Signing {} with key {}:\n{}",
+                zone.zone,
+                key.tag,
+                key.publication(&zone.zone).trim_end()
+            );
+        }
+    }
+    let dnssec = Arc::new(dnssec);
+
+    // Reverse zones answered here, and PTR records made from local addresses
+    // (default tenant only)
+    let reverse = if tenant.name == DEFAULT_TENANT {
+        ReverseLookups {
+            zones: args.reverse_zones.clone(),
+            synthesize: args.synthesize_ptr,
+        }
+    } else {
+        ReverseLookups::default()
+    };
+    for zone in &reverse.zones {
+        info!("Answering reverse zone {} ({}) locally", zone.zone, zone);
+    }
+    let reverse = Arc::new(reverse);
+
+    // Names in the zones served from files that aren't in them don't exist
+    let zones = Arc::new(LocalZones::new(&tenant.zone_files));
+
+    // Stream of per-query events for live views, and the query logs
+    let mut logs: Vec<(Box<dyn AsyncWrite + Send + Unpin>, LogStyle)> = Vec::new();
+    if tenant.name == DEFAULT_TENANT {
+        if args.pretty_query_log {
+            logs.push((
+                Box::new(tokio::io::stdout()),
+                LogStyle::Pretty {
+                    color: std::io::stdout().is_terminal(),
+                },
+            ));
+        }
+        if let Some(path) = &args.query_log_file {
+            let style = match args.query_log_format {
+                QueryLogFormat::Text => LogStyle::Pretty { color: false },
+                QueryLogFormat::Json => LogStyle::Json,
+            };
+            let sink: Box<dyn AsyncWrite + Send + Unpin> = if path.as_os_str() == "-" {
+                Box::new(tokio::io::stdout())
+            } else {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("could not open query log {}", path.display()))?;
+                Box::new(file)
+            };
+            logs.push((sink, style));
+        }
+    }
+    let mut query_log = QueryLog::with_writers(logs).with_hooks(&server.hooks);
+    if tenant.name == DEFAULT_TENANT {
+        query_log = with_query_db(query_log, args)?;
+    }
+    // What `top` and `rates` on the control socket sum up
+    let stats = Arc::new(QueryStats::default());
+    if tenant.control.is_some() {
+        let counted = Arc::clone(&stats);
+        query_log = query_log.with_writer(|events| stats::count_events(events, counted));
+    }
+
+    // Upstream answers and the lookups under way, shared by the shards
+    let mut cache = CacheHandle::new(server.cache, Arc::clone(&metrics));
+    let inflight = InflightHandle::new();
+
+    // The cache of the last run, kept up to date on disk (default tenant only)
+    let snapshot = match &args.cache_snapshot {
+        Some(path) if tenant.name == DEFAULT_TENANT => {
+            cache_snapshot::restore(path, &cache).await;
+            tokio::spawn(cache_snapshot::run_snapshots(
+                path.clone(),
+                args.cache_snapshot_interval(),
+                cache.clone(),
+            ));
+            Some((path.clone(), cache.clone()))
+        }
+        _ => None,
+    };
+
+    // Answers shared with other instances, under the tenant's name
+    if let Some(url) = &args.shared_cache {
+        let backend = shared_cache::open_backend(url)?;
+        cache = cache.with_shared(SharedCache::new(
+            backend,
+            &tenant.name,
+            Arc::clone(&metrics),
+        ));
+    }
+
+    if let Some(control_addr) = tenant.control {
+        let tls = control_tls
+            .map(|acceptor| ControlTls::new(acceptor.clone(), &tenant.control_clients))
+            .transpose()?;
+        let ctx = ControlContext {
+            local_records: local_records_handle.clone(),
+            query_log: query_log.clone(),
+            metrics: Arc::clone(&metrics),
+            blocklist: Arc::clone(&blocklist),
+            cache: cache.clone(),
+            stats,
+            quotas: quotas.clone(),
+            token,
+            reloader: reloader.clone(),
+            tls,
+        };
+        let name = tenant.name.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control::run_control_server(control_addr, ctx).await {
+                error!("Control socket of tenant {} stopped: {}", name, e);
+            }
+        });
+    } else if token.is_some() {
+        warn!(
+            "Tenant {} has a token file but no control socket",
+            tenant.name
+        );
+    }
+
+    // With --transparent the upstreams see the clients' own packets
+    check_relayable(args, &upstreams, &format!("tenant {}", tenant.name))?;
+    let proxy = args
+        .transparent
+        .then(|| Arc::new(Proxy::new(upstreams.clone())));
+
+    // The layers every question goes through: the embedder's, then the
+    // default tenant's tunnel detection, which sees every question, its
+    // block page, outside the policies so their blocks are sent there too,
+    // its policies, and the mDNS proxy for what the policies let through
+    let mut layers = server.middleware.clone();
+    // Records are reordered in whatever answer the layers below give
+    if args.rrset_order != RrsetOrder::Fixed {
+        layers.push(Arc::new(Reorder::new(args.rrset_order)));
+    }
+    if tenant.name == DEFAULT_TENANT {
+        if args.detect_tunnels {
+            let detector = TunnelDetector::new(args.tunnel_webhook.clone(), args.tunnel_rate_limit);
+            layers.push(Arc::new(detector));
+        }
+        if !args.block_pages.is_empty() {
+            let page = BlockPage::new(&args.block_pages, args.redirect_nxdomain)?;
+            layers.push(Arc::new(page));
+        }
+        layers.extend(policies(args)?);
+        if args.mdns_proxy {
+            layers.push(Arc::new(MdnsProxy::new(args.mdns_interfaces.clone())));
+        }
+    }
+    let pipeline = Pipeline::new(&layers);
+
+    // The resolver (and its cache) is shared; each shard has its own query
+    // actors, so a busy shard doesn't queue behind another
+    let shards = socks
+        .into_iter()
+        .enumerate()
+        .map(|(index, sock)| {
+            let context = QueryContext {
+                query_handle: match &recursor {
+                    Some(recursor) => QueryActorHandle::recursive(
+                        Arc::clone(recursor),
+                        args.resolver_workers.into(),
+                        Arc::clone(&metrics),
+                    ),
+                    None => QueryActorHandle::new(
+                        resolver.clone(),
+                        args.resolver_workers.into(),
+                        Arc::clone(&metrics),
+                    ),
+                },
+                local_records: local_records_handle.clone(),
+                query_log: query_log.clone(),
+                metrics: Arc::clone(&metrics),
+                dedup: DedupHandle::new(DUPLICATE_WINDOW),
+                cache: cache.clone(),
+                inflight: inflight.clone(),
+                names: Arc::default(),
+                hostname_policy: args.hostname_policy,
+                ttl_limits: args.ttl_limits(),
+                locator: Arc::clone(&locator),
+                acl: Arc::clone(&acl),
+                quotas: quotas.clone(),
+                shard: index,
+                proxy: proxy.clone(),
+                router: Arc::clone(&router),
+                blocklist: Arc::clone(&blocklist),
+                identity: Arc::clone(&identity),
+                primaries: Arc::clone(&primaries),
+                dnssec: Arc::clone(&dnssec),
+                reverse: Arc::clone(&reverse),
+                zones: Arc::clone(&zones),
+                groups: Arc::clone(&groups),
+                pipeline: pipeline.clone(),
+            };
+            // A view answers from its own records, and forwards with its own
+            // resolver and cache when it has upstreams of its own
+            let views = view_states
+                .iter()
+                .map(|state| {
+                    let mut context = QueryContext {
+                        local_records: state.local_records.clone(),
+                        zones: Arc::clone(&state.zones),
+                        ..context.clone()
+                    };
+                    if let Some(forwarding) = &state.forwarding {
+                        context.query_handle = QueryActorHandle::new(
+                            forwarding.resolver.clone(),
+                            args.resolver_workers.into(),
+                            Arc::clone(&metrics),
+                        );
+                        context.cache = forwarding.cache.clone();
+                        context.inflight = forwarding.inflight.clone();
+                        context.proxy = forwarding.proxy.clone();
+                    }
+                    (Arc::clone(&state.view), context)
+                })
+                .collect();
+            Shard {
+                sock,
+                context,
+                views,
+            }
+        })
+        .collect::<Vec<_>>();
+    let tcp = TcpShard {
+        listener,
+        context: shards[0].context.clone(),
+        views: shards[0].views.clone(),
+    };
+
+    if shards.len() > 1 {
+        info!(
+            "DNS server listening on {} (tenant {}, {} shards)",
+            tenant.listen,
+            tenant.name,
+            shards.len()
+        );
+    } else {
+        info!(
+            "DNS server listening on {} (tenant {})",
+            tenant.listen, tenant.name
+        );
+    }
+
+    Ok(TenantServer {
+        shards,
+        tcp,
+        snapshot,
+        reloader,
+    })
+}
+
+/// With --transparent, queries can't be relayed to DNS over HTTPS upstreams
+fn check_relayable(args: &Args, upstreams: &[Upstream], owner: &str) -> anyhow::Result<()> {
+    let https = upstreams
+        .iter()
+        .find(|upstream| matches!(upstream.transport, Transport::Https { .. }));
+    if let (true, Some(upstream)) = (args.transparent, https) {
+        anyhow::bail!(
+            "--transparent can't relay to DNS over HTTPS upstream {} ({})",
+            upstream,
+            owner
+        );
+    }
+    Ok(())
+}
+
+/// The query log, also stored in the --query-log-db database if one is given
+fn with_query_db(query_log: QueryLog, args: &Args) -> anyhow::Result<QueryLog> {
+    let Some(path) = &args.query_log_db else {
+        return Ok(query_log);
+    };
+    #[cfg(not(feature = "sqlite"))]
+    anyhow::bail!(
+        "--query-log-db {} needs a build with the sqlite feature",
+        path.display()
+    );
+    #[cfg(feature = "sqlite")]
+    {
+        let db = Arc::new(QueryDb::open(path)?);
+        info!(
+            "Logging queries to {} (kept for {} days)",
+            path.display(),
+            args.query_log_retention
+        );
+        tokio::spawn(query_db::prune_events(
+            Arc::clone(&db),
+            args.query_log_retention(),
+        ));
+        Ok(query_log.with_writer(|events| query_db::store_events(events, db)))
+    }
+}
+
+/// The --group groups, their blocklists and rewrites loaded and their
+/// upstreams' resolvers started
+async fn load_groups(args: &Args, metrics: &Arc<Metrics>) -> anyhow::Result<ClientGroups> {
+    groups::check_distinct(&args.groups).map_err(anyhow::Error::msg)?;
+    let mut loaded = Vec::with_capacity(args.groups.len());
+    for group in &args.groups {
+        let subnets = group
+            .subnets(&args.client_names)
+            .map_err(anyhow::Error::msg)?;
+
+        // Files are read now, while lists to download wait for the reloads
+        let blocking = if group.unblocked {
+            GroupBlocking::Off
+        } else if group.blocklists.is_empty() {
+            GroupBlocking::Tenant
+        } else {
+            let files = group.blocklists.iter().filter(|source| !source.is_remote());
+            let blocklist = Blocklist::load(files, args.block_mode)
+                .with_context(|| format!("group {}", group.name))?;
+            let blocklist = Arc::new(
+                PublishedBlocklist::new(blocklist, Allowlist::default())
+                    .with_sources(group.blocklists.clone()),
+            );
+            if group.blocklists.iter().any(|source| source.is_remote()) {
+                tokio::spawn(blocklist::refresh_blocklist(
+                    args.blocklist_refresh_interval(),
+                    Arc::clone(&blocklist),
+                ));
+            }
+            GroupBlocking::Own(blocklist)
+        };
+
+        let rewrites = LocalRecordsHandle::new();
+        rewrites.import(group.rewrites.clone(), false, false).await;
+
+        // The group's answers are cached under the route's name
+        let route = (!group.upstreams.is_empty()).then(|| {
+            let resolver = HedgedResolver::new(
+                &group.upstreams,
+                None,
+                &args.upstream_policies,
+                args.case_randomization(),
+                Arc::clone(metrics),
+            );
+            let handle =
+                QueryActorHandle::new(resolver, args.resolver_workers.into(), Arc::clone(metrics));
+            Route::resolving(&format!("group:{}", group.name), handle)
+        });
+
+        info!(
+            "Group {} loaded ({} clients, {} blocklists, {} rewrites, {} upstreams)",
+            group.name,
+            subnets.len(),
+            group.blocklists.len(),
+            group.rewrites.len(),
+            group.upstreams.len()
+        );
+        loaded.push(ClientGroup {
+            name: group.name.as_str().into(),
+            subnets,
+            blocking,
+            rewrites,
+            route,
+            log: group.log,
+        });
+    }
+    Ok(ClientGroups::new(loaded))
+}
+
+/// The --plugin and --script policies, each a layer of its own in the order
+/// given, the script last
+fn policies(args: &Args) -> anyhow::Result<Vec<Arc<dyn Middleware>>> {
+    let mut layers: Vec<Arc<dyn Middleware>> = Vec::new();
+    #[cfg(not(feature = "wasm"))]
+    if let Some(path) = args.plugins.first() {
+        anyhow::bail!(
+            "--plugin {} needs a build with the wasm feature",
+            path.display()
+        );
+    }
+    #[cfg(feature = "wasm")]
+    for path in &args.plugins {
+        let plugin = Plugin::load(path)?;
+        info!("Running plugin {}", path.display());
+        layers.push(Arc::new(PolicyLayer(Arc::new(plugin))));
+    }
+    if let Some(path) = &args.script {
+        let script = Arc::new(Script::load(path)?);
+        script::watch_script(Arc::clone(&script))?;
+        info!("Running script {}", path.display());
+        layers.push(Arc::new(PolicyLayer(script)));
+    }
+    Ok(layers)
+}
+
+/// Answer a shard's queries until its socket fails
+async fn serve(shard: Shard, io_uring: bool) -> anyhow::Result<()> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if io_uring {
+        return serve_uring(shard).await;
+    }
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    anyhow::ensure!(
+        !io_uring,
+        "--io-uring needs a Linux build with the io-uring feature"
+    );
+
+    let Shard {
+        sock,
+        context,
+        views,
+    } = shard;
+    // On a wildcard address, answer from the address the client asked
+    udp::reply_from_destination(&sock)?;
+    let sock = Arc::new(sock);
+    let mut buf = [0; 1024]; // Buffer for incoming packets
+
+    loop {
+        let (len, addr, sock_clone) = udp::recv_from(&sock, &mut buf).await?;
+
+        let packet_data = buf[..len].to_vec();
+        // Cheap clones of the shared handles
+        let ctx = select_view(&context, &views, addr.ip()).clone();
+
+        // Spawn a new task to process the DNS query
+        tokio::spawn(async move {
+            process_dns_query(packet_data, addr, ctx, Responder::Udp(sock_clone)).await;
+        });
+    }
+}
+
+/// Answer a shard's queries from an io_uring ring until its socket fails
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn serve_uring(shard: Shard) -> anyhow::Result<()> {
+    let Shard {
+        sock,
+        context,
+        views,
+    } = shard;
+    let addr = sock.local_addr()?;
+    let (sender, mut packets) = uring::start(sock.into_std()?)?;
+    let sender = Responder::Udp(UdpSender::Uring(sender));
+
+    while let Some((packet_data, addr)) = packets.recv().await {
+        let sender = sender.clone();
+        let ctx = select_view(&context, &views, addr.ip()).clone();
+        tokio::spawn(async move {
+            process_dns_query(packet_data, addr, ctx, sender).await;
+        });
+    }
+    anyhow::bail!("io_uring listener on {} stopped", addr)
+}
+
+/// Answer a tenant's queries over TCP until its listener fails
+async fn serve_tcp(shard: TcpShard) -> anyhow::Result<()> {
+    let TcpShard {
+        listener,
+        context,
+        views,
+    } = shard;
+    let connections = Arc::new(Semaphore::new(TCP_MAX_CONNECTIONS));
+    loop {
+        let connection = Arc::clone(&connections)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Out of file descriptors, or a connection reset before it was
+            // taken; the next ones may do better
+            Err(e) => {
+                warn!("Could not accept a TCP connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let ctx = select_view(&context, &views, addr.ip()).clone();
+        tokio::spawn(async move {
+            serve_connection(stream, addr, ctx).await;
+            drop(connection);
+        });
+    }
+}
+
+/// Answer the queries of one TCP connection until the client closes it or
+/// leaves it idle. Up to `TCP_MAX_IN_FLIGHT` queries are answered
+/// concurrently and each response written as soon as it is ready, in
+/// whatever order (RFC 7766 §6.2.1.1).
+async fn serve_connection(stream: TcpStream, addr: SocketAddr, ctx: QueryContext) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (responses, mut pending) = mpsc::channel::<Vec<u8>>(TCP_PENDING_RESPONSES);
+    let writing = tokio::spawn(async move {
+        while let Some(response) = pending.recv().await {
+            if let Err(e) = tcp::write_message(&mut writer, &response).await {
+                debug!("Could not write to TCP client {}: {}", addr, e);
+                break;
+            }
+        }
+    });
+
+    let in_flight = Arc::new(Semaphore::new(TCP_MAX_IN_FLIGHT));
+    while let Ok(Ok(packet_data)) =
+        tokio::time::timeout(TCP_IDLE_TIMEOUT, tcp::read_message(&mut reader)).await
+    {
+        let query = Arc::clone(&in_flight)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let ctx = ctx.clone();
+        let responder = Responder::Tcp(responses.clone());
+        tokio::spawn(async move {
+            process_dns_query(packet_data, addr, ctx, responder).await;
+            drop(query);
+        });
+    }
+    // Queries still being answered hold the writer open until they are
+    drop(responses);
+    let _ = writing.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{Answer, Next, Request};
+    use crate::query_log::Disposition;
+    use crate::registry::{Rcode, RecordType};
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    /// Run `builder`'s server on a free port until the sender is dropped
+    async fn start(
+        builder: DnsServerBuilder,
+    ) -> (
+        SocketAddr,
+        oneshot::Sender<()>,
+        JoinHandle<anyhow::Result<()>>,
+    ) {
+        // A free port, taken again by the server
+        let listen = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = builder
+            .listen(listen)
+            .system_resolvers(false)
+            .record("nas.lan A 192.168.1.10".parse().unwrap())
+            .build();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(server.run_until(async {
+            let _ = stopped.await;
+            Ok(())
+        }));
+        (listen, stop, running)
+    }
+
+    /// The server's response to an A query for `name`, given in wire format
+    async fn ask(server: SocketAddr, name: &[u8]) -> Vec<u8> {
+        ask_type(server, name, RecordType::A).await
+    }
+
+    /// The server's response to a `qtype` query for `name`
+    async fn ask_type(server: SocketAddr, name: &[u8], qtype: RecordType) -> Vec<u8> {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(name);
+        query.extend_from_slice(&u16::from(qtype).to_be_bytes());
+        query.extend_from_slice(&[0, 1]);
+        let mut response = [0; 512];
+        // The server may not be listening yet
+        for _ in 0..50 {
+            client.send_to(&query, server).await.unwrap();
+            if let Ok(received) =
+                tokio::time::timeout(Duration::from_millis(100), client.recv(&mut response)).await
+            {
+                let response = response[..received.unwrap()].to_vec();
+                assert_eq!(&response[..2], [0x12, 0x34]);
+                return response;
+            }
+        }
+        panic!("no answer from the server");
+    }
+
+    #[tokio::test]
+    async fn test_embedded_server_answers() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        let builder = DnsServer::builder().on_query(move |event| {
+            assert_eq!(&*event.name, "nas.lan");
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let (server, stop, running) = start(builder).await;
+
+        let response = ask(server, b"\x03nas\x03lan\x00").await;
+        assert!(response.ends_with(&[192, 168, 1, 10]));
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    /// Refuses names under `.test`, and answers everything else with a TTL
+    /// of 42
+    struct Rewrite;
+
+    impl Middleware for Rewrite {
+        fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+            Box::pin(async move {
+                if request.question.name.ends_with(".test") {
+                    return Answer::new(Disposition::Local).with_rcode(Rcode::REFUSED);
+                }
+                let mut answer = next.run(request).await;
+                for record in &mut answer.answers {
+                    record.ttl = 42;
+                }
+                answer
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_wraps_the_pipeline() {
+        let (server, stop, running) = start(DnsServer::builder().middleware(Rewrite)).await;
+
+        let response = ask(server, b"\x03nas\x03lan\x00").await;
+        assert!(response.ends_with(&[0, 0, 0, 42, 0, 4, 192, 168, 1, 10]));
+        let response = ask(server, b"\x07blocked\x04test\x00").await;
+        assert_eq!(u16::from(response[3] & 0x0f), u16::from(Rcode::REFUSED));
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_page() {
+        let list = std::env::temp_dir().join(format!("dns-block-page-{}.txt", std::process::id()));
+        std::fs::write(&list, "ads.example\n").unwrap();
+        let mut args = Args::defaults();
+        args.blocklists = vec![list.display().to_string().parse().unwrap()];
+        args.block_pages = vec!["192.0.2.80".parse().unwrap()];
+        let (server, stop, running) = start(DnsServerBuilder::args(args)).await;
+        let name = b"\x03ads\x07example\x00";
+
+        let response = ask_type(server, name, RecordType::A).await;
+        assert!(response.ends_with(&[0, 0, 0, 60, 0, 4, 192, 0, 2, 80]));
+        // No IPv6 block page: no records, but no NXDOMAIN either, which
+        // would tell the client the name doesn't exist
+        for qtype in [RecordType::AAAA, RecordType::HTTPS] {
+            let response = ask_type(server, name, qtype).await;
+            assert_eq!(u16::from(response[3] & 0x0f), u16::from(Rcode::NOERROR));
+            assert_eq!(response[6..8], [0, 0]);
+        }
+        // Other types keep the blocklist's answer
+        let response = ask_type(server, name, RecordType::MX).await;
+        assert_eq!(u16::from(response[3] & 0x0f), u16::from(Rcode::NXDOMAIN));
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        std::fs::remove_file(&list).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transparent_mode_blocks() {
+        let list = std::env::temp_dir().join(format!("dns-transparent-{}.txt", std::process::id()));
+        std::fs::write(&list, "ads.example\n").unwrap();
+        let (upstream_addr, queries) = upstream().await;
+        let mut args = Args::defaults();
+        args.transparent = true;
+        args.resolvers = vec![upstream_addr.into()];
+        args.blocklists = vec![list.display().to_string().parse().unwrap()];
+        let (server, stop, running) = start(DnsServerBuilder::args(args)).await;
+
+        // Blocked names are answered here and never reach the upstream
+        let response = ask(server, b"\x03ads\x07example\x00").await;
+        assert_eq!(u16::from(response[3] & 0x0f), u16::from(Rcode::NXDOMAIN));
+        assert_eq!(queries.load(Ordering::Relaxed), 0);
+        let response = ask(server, b"\x03www\x07example\x00").await;
+        assert!(response.ends_with(&[192, 0, 2, 1]));
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        std::fs::remove_file(&list).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_groups() {
+        let list = std::env::temp_dir().join(format!("dns-group-{}.txt", std::process::id()));
+        std::fs::write(&list, "tracker.example\n").unwrap();
+        let mut args = Args::defaults();
+        args.client_names = vec!["kiosk=127.0.0.1".parse().unwrap()];
+        args.groups = vec![format!(
+            "name=kiosk,client=kiosk,blocklist={},rewrite=ads.example A 192.0.2.9",
+            list.display()
+        )
+        .parse()
+        .unwrap()];
+        let (server, stop, running) = start(DnsServerBuilder::args(args)).await;
+
+        // The group's rewrites and blocklist answer its clients
+        let response = ask(server, b"\x03ads\x07example\x00").await;
+        assert!(response.ends_with(&[0, 4, 192, 0, 2, 9]));
+        let response = ask(server, b"\x07tracker\x07example\x00").await;
+        assert_eq!(u16::from(response[3] & 0x0f), u16::from(Rcode::NXDOMAIN));
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        std::fs::remove_file(&list).unwrap();
+    }
+
+    /// Holds every question until the gate lets it through, counting them
+    struct Gate {
+        open: Arc<Semaphore>,
+        waiting: Arc<AtomicUsize>,
+    }
+
+    impl Middleware for Gate {
+        fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+            Box::pin(async move {
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+                self.open.acquire().await.unwrap().forget();
+                next.run(request).await
+            })
+        }
+    }
+
+    /// A TCP query for nas.lan under `id`
+    fn tcp_query(id: u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        query.extend_from_slice(b"\x03nas\x03lan\x00\x00\x01\x00\x01");
+        query
+    }
+
+    #[tokio::test]
+    async fn test_tcp_queries_in_flight_are_capped() {
+        let open = Arc::new(Semaphore::new(0));
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let gate = Gate {
+            open: Arc::clone(&open),
+            waiting: Arc::clone(&waiting),
+        };
+        let (server, stop, running) = start(DnsServer::builder().middleware(gate)).await;
+        // Answered once the server is up
+        open.add_permits(1);
+        ask(server, b"\x03nas\x03lan\x00").await;
+        waiting.store(0, Ordering::Relaxed);
+
+        let queries = TCP_MAX_IN_FLIGHT + 4;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        for id in 0..queries {
+            tcp::write_message(&mut stream, &tcp_query(id as u16))
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(waiting.load(Ordering::Relaxed), TCP_MAX_IN_FLIGHT);
+
+        // The rest are read as the first are answered
+        open.add_permits(queries);
+        for _ in 0..queries {
+            let response = tcp::read_message(&mut stream).await.unwrap();
+            assert!(response.ends_with(&[192, 168, 1, 10]));
+        }
+        assert_eq!(waiting.load(Ordering::Relaxed), queries);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_questions_are_answered_concurrently() {
+        let open = Arc::new(Semaphore::new(0));
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let gate = Gate {
+            open: Arc::clone(&open),
+            waiting: Arc::clone(&waiting),
+        };
+        let builder = DnsServer::builder()
+            .middleware(gate)
+            .record("printer.lan A 192.168.1.20".parse().unwrap());
+        let (server, stop, running) = start(builder).await;
+        open.add_permits(1);
+        ask(server, b"\x03nas\x03lan\x00").await;
+        waiting.store(0, Ordering::Relaxed);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut query = vec![0x56, 0x78, 0x01, 0x00, 0, 2, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x03nas\x03lan\x00\x00\x01\x00\x01");
+        query.extend_from_slice(b"\x07printer\x03lan\x00\x00\x01\x00\x01");
+        client.send_to(&query, server).await.unwrap();
+        // Both questions are being answered at once, not one after the other
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(waiting.load(Ordering::Relaxed), 2);
+
+        open.add_permits(2);
+        let mut response = [0; 512];
+        let len = client.recv(&mut response).await.unwrap();
+        let (_, packet) = crate::parsers::parse_dns_packet(&response[..len]).unwrap();
+        // Their answers still come in the order asked
+        let answers: Vec<_> = packet.answers.iter().map(|a| a.rdata.clone()).collect();
+        assert_eq!(answers, [[192, 168, 1, 10], [192, 168, 1, 20]]);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connections_are_capped() {
+        let (server, stop, running) = start(DnsServer::builder()).await;
+        ask(server, b"\x03nas\x03lan\x00").await;
+
+        let mut connections = Vec::new();
+        for _ in 0..TCP_MAX_CONNECTIONS {
+            connections.push(TcpStream::connect(server).await.unwrap());
+        }
+        // One more waits until another closes
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        tcp::write_message(&mut stream, &tcp_query(1))
+            .await
+            .unwrap();
+        let waited =
+            tokio::time::timeout(Duration::from_millis(200), tcp::read_message(&mut stream)).await;
+        assert!(waited.is_err());
+        connections.pop();
+        let response = tcp::read_message(&mut stream).await.unwrap();
+        assert!(response.ends_with(&[192, 168, 1, 10]));
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    /// An upstream answering every query with 192.0.2.1 for 60 seconds, and
+    /// how many queries it got, not counting the health probes for the root
+    async fn upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, client) = upstream.recv_from(&mut buf).await.unwrap();
+                if buf[12] != 0 {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                let mut response = buf[..len].to_vec();
+                response[2] = 0x81;
+                response[3] = 0x80;
+                response[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+                let question_end = 12 + response[12..].iter().position(|&b| b == 0).unwrap() + 5;
+                response.truncate(question_end);
+                response
+                    .extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+                let _ = upstream.send_to(&response, client).await;
+            }
+        });
+        (addr, queries)
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_asks_the_upstream() {
+        let (upstream_addr, queries) = upstream().await;
+        let mut args = Args::defaults();
+        args.resolvers = vec![upstream_addr.into()];
+        args.prefetch_hits = 1;
+        args.prefetch_window = 60;
+        let (server, stop, running) = start(DnsServerBuilder::args(args)).await;
+        let name = b"\x03www\x07example\x00";
+
+        let response = ask(server, name).await;
+        assert!(response.ends_with(&[0, 0, 0, 60, 0, 4, 192, 0, 2, 1]));
+        // A hit a second later is served counted down and refreshed
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let response = ask(server, name).await;
+        assert!(response.ends_with(&[0, 0, 0, 59, 0, 4, 192, 0, 2, 1]));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // The upstream was asked again and its full TTL is cached
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+        let response = ask(server, name).await;
+        assert!(response.ends_with(&[0, 0, 0, 60, 0, 4, 192, 0, 2, 1]));
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answer_retried_over_tcp() {
+        let mut builder = DnsServer::builder();
+        for host in 1..=40 {
+            builder = builder.record(format!("big.lan A 10.0.0.{}", host).parse().unwrap());
+        }
+        let (server, stop, running) = start(builder).await;
+
+        // 40 addresses don't fit the 512 bytes a client without EDNS takes
+        let response = ask(server, b"\x03big\x03lan\x00").await;
+        assert_eq!(response[2] & 0x02, 0x02);
+        assert!(u16::from_be_bytes([response[6], response[7]]) < 40);
+
+        // The same query over TCP gets them all
+        let mut query = vec![0x56, 0x78, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x03big\x03lan\x00\x00\x01\x00\x01");
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        tcp::write_message(&mut stream, &query).await.unwrap();
+        let response = tcp::read_message(&mut stream).await.unwrap();
+        assert_eq!(&response[..2], [0x56, 0x78]);
+        assert_eq!(response[2] & 0x02, 0);
+        assert_eq!(response[6..8], [0, 40]);
+        assert!(response.ends_with(&[0, 4, 10, 0, 0, 40]));
+
+        // The connection stays open for more
+        query[1] = 0x79;
+        tcp::write_message(&mut stream, &query).await.unwrap();
+        let response = tcp::read_message(&mut stream).await.unwrap();
+        assert_eq!(&response[..2], [0x56, 0x79]);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! Running as a system service
+//!
+//! Supports classic daemonization (`--daemon`) and generating systemd/launchd
+//! service definitions that run the server in the foreground under a supervisor,
+//! and tells it when it is being asked to stop.
+
+use std::path::Path;
+
+use clap::ValueEnum;
+
+/// Service managers we can generate definitions for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ServiceKind {
+    /// A systemd unit file (Linux)
+    Systemd,
+    /// A launchd property list (macOS)
+    Launchd,
+}
+
+/// launchd job label, also used as the plist file name
+const LAUNCHD_LABEL: &str = "io.devfire.dns-server";
+
+/// Render a service definition that starts `exe` with the given server flags
+pub fn generate_service(kind: ServiceKind, exe: &Path, flags: &[String]) -> String {
+    match kind {
+        ServiceKind::Systemd => systemd_unit(exe, flags),
+        ServiceKind::Launchd => launchd_plist(exe, flags),
+    }
+}
+
+fn systemd_unit(exe: &Path, flags: &[String]) -> String {
+    let mut exec_start = systemd_quote(&exe.display().to_string());
+    for flag in flags {
+        exec_start.push(' ');
+        exec_start.push_str(&systemd_quote(flag));
+    }
+
+    format!(
+        "\
+[Unit]
+Description=Rust DNS server
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=simple
+ExecStart={exec_start}
+Restart=on-failure
+RestartSec=2
+DynamicUser=yes
+AmbientCapabilities=CAP_NET_BIND_SERVICE
+NoNewPrivileges=yes
+
+[Install]
+WantedBy=multi-user.target
+"
+    )
+}
+
+fn launchd_plist(exe: &Path, flags: &[String]) -> String {
+    let mut arguments = format!(
+        "        <string>{}</string>\n",
+        xml_escape(&exe.display().to_string())
+    );
+    for flag in flags {
+        arguments.push_str(&format!("        <string>{}</string>\n", xml_escape(flag)));
+    }
+
+    format!(
+        "\
+<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">
+<plist version=\"1.0\">
+<dict>
+    <key>Label</key>
+    <string>{LAUNCHD_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>/usr/local/var/log/dns-server.log</string>
+    <key>StandardErrorPath</key>
+    <string>/usr/local/var/log/dns-server.log</string>
+</dict>
+</plist>
+"
+    )
+}
+
+/// Quote an ExecStart argument if it contains characters systemd would split on
+fn systemd_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '$' | '%' | ';'))
+    {
+        return arg.to_string();
+    }
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "$$")
+        .replace('%', "%%");
+    format!("\"{}\"", escaped)
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Detach from the terminal: fork, write the pidfile, redirect stdout/stderr
+/// to `log_file` (or /dev/null) and change to `/`.
+///
+/// This must be called before the async runtime is started.
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&Path>, log_file: Option<&Path>) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use daemonize::{Daemonize, Stdio};
+
+    let mut daemon = Daemonize::new().working_directory("/");
+
+    if let Some(pid_file) = pid_file {
+        daemon = daemon.pid_file(pid_file);
+    }
+
+    if let Some(log_file) = log_file {
+        let open = || {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file)
+                .with_context(|| format!("could not open log file {}", log_file.display()))
+        };
+        daemon = daemon
+            .stdout(Stdio::from(open()?))
+            .stderr(Stdio::from(open()?));
+    }
+
+    daemon.start().context("could not daemonize")?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_pid_file: Option<&Path>, _log_file: Option<&Path>) -> anyhow::Result<()> {
+    anyhow::bail!("--daemon is only supported on Unix; run the server as a service instead")
+}
+
+/// Wait until the server is asked to stop: Ctrl-C (SIGINT), or SIGTERM from
+/// a service manager on Unix
+pub async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// SIGHUP, which asks a daemon to reload on Unix. There is no such signal
+/// elsewhere, so none ever arrives.
+pub struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl ReloadSignal {
+    /// Start listening for the signal
+    pub fn new() -> std::io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            Ok(Self {
+                hangup: signal(SignalKind::hangup())?,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(Self {})
+    }
+
+    /// Wait for the next signal
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if self.hangup.recv().await.is_some() {
+            return;
+        }
+        std::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags() -> Vec<String> {
+        vec![
+            "--resolver".to_string(),
+            "1.1.1.1:53".to_string(),
+            "--control".to_string(),
+            "127.0.0.1:2054".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_systemd_unit_exec_start() {
+        let unit = generate_service(
+            ServiceKind::Systemd,
+            Path::new("/opt/dns tools/dns-server"),
+            &flags(),
+        );
+        assert!(unit.contains(
+            "ExecStart=\"/opt/dns tools/dns-server\" --resolver 1.1.1.1:53 --control 127.0.0.1:2054\n"
+        ));
+        assert!(unit.contains("WantedBy=multi-user.target"));
+    }
+
+    #[test]
+    fn test_launchd_plist_arguments() {
+        let plist = generate_service(
+            ServiceKind::Launchd,
+            Path::new("/usr/local/bin/dns-server"),
+            &flags(),
+        );
+        assert!(plist.contains("<string>/usr/local/bin/dns-server</string>"));
+        assert!(plist.contains("        <string>1.1.1.1:53</string>\n"));
+        assert!(plist.contains(LAUNCHD_LABEL));
+    }
+}
@@ -0,0 +1,392 @@
+//! A cache shared between instances (`--shared-cache`)
+//!
+//! Several servers behind anycast or a load balancer each keep a cache of
+//! their own, so a name one of them has just resolved is still a miss on
+//! the others. With a shared cache every answer a tenant caches, NXDOMAIN
+//! and NODATA included, is also written to a store the instances share, and
+//! a local miss is looked up there before going upstream. An answer found
+//! there is kept in the local cache too, with its TTLs counted down by the
+//! time it spent in the shared one.
+//!
+//! The store is reached through [`CacheBackend`]; Redis (`redis://host:port/db`)
+//! is the one implemented, in builds with the `redis` feature. The local
+//! cache stays in charge: a store that fails or doesn't answer within
+//! 200 ms is left alone for ten seconds, during which the server answers
+//! from its local cache alone. `stats` counts answers found in the store as
+//! `shared_cache_hits` and failed requests to it as `shared_cache_errors`.
+//!
+//! Entries are keyed by tenant, so tenants sharing a store don't share
+//! answers; views with upstreams of their own keep a local cache only.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::actors::cache_actor::cache_ttl;
+use crate::actors::messages::{CacheKey, Resolution};
+use crate::cache_snapshot::{unix_now, Answer};
+use crate::metrics::{self, Metrics};
+
+/// How long a request to the store may take
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long a store that failed is left alone
+const RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// A key-value store with expiry that cached answers can be kept in
+pub trait CacheBackend: Send + Sync + fmt::Debug {
+    /// The value stored under `key`, if it hasn't expired
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<Option<Vec<u8>>>>;
+
+    /// Store `value` under `key` for `ttl`
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// An answer as kept in the store
+#[derive(Debug, Serialize, Deserialize)]
+struct Stored {
+    /// When it was stored, in seconds since the Unix epoch
+    saved: u64,
+    #[serde(flatten)]
+    answer: Answer,
+}
+
+/// A tenant's view of a shared store
+#[derive(Debug)]
+pub struct SharedCache {
+    backend: Arc<dyn CacheBackend>,
+    /// Prefix of the tenant's keys
+    namespace: String,
+    metrics: Arc<Metrics>,
+    /// Until when the store is left alone after failing
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl SharedCache {
+    pub fn new(backend: Arc<dyn CacheBackend>, tenant: &str, metrics: Arc<Metrics>) -> Self {
+        SharedCache {
+            backend,
+            namespace: format!("dns-server:{}", tenant),
+            metrics,
+            down_until: Mutex::new(None),
+        }
+    }
+
+    /// The store's key for a question
+    fn key(&self, key: &CacheKey) -> String {
+        let mut stored = format!(
+            "{}:{}:{}:{}",
+            self.namespace, key.qclass, key.qtype, key.name
+        );
+        if let Some(route) = &key.route {
+            stored.push('@');
+            stored.push_str(route);
+        }
+        stored
+    }
+
+    /// The answer the store holds for a question, with its TTLs counted
+    /// down; none when it holds none or can't be asked
+    pub async fn get(&self, key: &CacheKey) -> Option<Resolution> {
+        if !self.available() {
+            return None;
+        }
+        let stored_key = self.key(key);
+        let value = match tokio::time::timeout(REQUEST_TIMEOUT, self.backend.get(&stored_key)).await
+        {
+            Ok(Ok(value)) => value,
+            Ok(Err(e)) => {
+                self.failed(e);
+                return None;
+            }
+            Err(_) => {
+                self.failed(anyhow::anyhow!("timed out"));
+                return None;
+            }
+        };
+        self.recovered();
+        let value = value?;
+        let stored: Stored = match serde_json::from_slice(&value) {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!(
+                    "Ignoring unreadable shared cache entry {}: {}",
+                    stored_key, e
+                );
+                return None;
+            }
+        };
+        let elapsed = unix_now().saturating_sub(stored.saved);
+        let resolution = stored.answer.restore(elapsed).ok()??;
+        metrics::incr(&self.metrics.shared_cache_hits);
+        Some(resolution)
+    }
+
+    /// Keep an answer in the store for as long as the local cache would
+    pub async fn put(&self, key: &CacheKey, resolution: &Resolution) {
+        if !self.available() {
+            return;
+        }
+        let (Some(ttl), Some(answer)) = (cache_ttl(resolution), Answer::new(resolution)) else {
+            return;
+        };
+        let stored = Stored {
+            saved: unix_now(),
+            answer,
+        };
+        let Ok(value) = serde_json::to_vec(&stored) else {
+            return;
+        };
+        let ttl = Duration::from_secs(ttl.into());
+        let stored_key = self.key(key);
+        let set = self.backend.set(&stored_key, value, ttl);
+        match tokio::time::timeout(REQUEST_TIMEOUT, set).await {
+            Ok(Ok(())) => self.recovered(),
+            Ok(Err(e)) => self.failed(e),
+            Err(_) => self.failed(anyhow::anyhow!("timed out")),
+        }
+    }
+
+    fn down_until(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether the store may be asked: it hasn't failed lately
+    fn available(&self) -> bool {
+        !matches!(*self.down_until(), Some(until) if Instant::now() < until)
+    }
+
+    /// Leave the store alone for a while after a failed request
+    fn failed(&self, error: anyhow::Error) {
+        metrics::incr(&self.metrics.shared_cache_errors);
+        let mut down_until = self.down_until();
+        if down_until.is_none() {
+            warn!(
+                "Shared cache unavailable ({:#}); using the local cache alone",
+                error
+            );
+        }
+        *down_until = Some(Instant::now() + RETRY_AFTER);
+    }
+
+    fn recovered(&self) {
+        if self.down_until().take().is_some() {
+            info!("Shared cache available again");
+        }
+    }
+}
+
+/// The store at `url`
+pub fn open_backend(url: &str) -> anyhow::Result<Arc<dyn CacheBackend>> {
+    match url.split_once("://") {
+        #[cfg(feature = "redis")]
+        Some(("redis", _)) => Ok(Arc::new(redis_backend::RedisBackend::open(url)?)),
+        #[cfg(not(feature = "redis"))]
+        Some(("redis", _)) => {
+            anyhow::bail!(
+                "--shared-cache {} needs a build with the redis feature",
+                url
+            )
+        }
+        _ => anyhow::bail!(
+            "unsupported shared cache {} (expected redis://<host>[:<port>][/<db>])",
+            url
+        ),
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_backend {
+    use std::time::Duration;
+
+    use futures::future::BoxFuture;
+    use redis::aio::ConnectionManager;
+    use redis::Client;
+    use tokio::sync::Mutex;
+
+    use super::CacheBackend;
+
+    /// A Redis server, connected to on first use and reconnected to by the
+    /// connection manager after that
+    pub struct RedisBackend {
+        client: Client,
+        connection: Mutex<Option<ConnectionManager>>,
+    }
+
+    impl RedisBackend {
+        pub fn open(url: &str) -> anyhow::Result<Self> {
+            Ok(RedisBackend {
+                client: Client::open(url)?,
+                connection: Mutex::new(None),
+            })
+        }
+
+        async fn connection(&self) -> redis::RedisResult<ConnectionManager> {
+            let mut connection = self.connection.lock().await;
+            if let Some(connection) = &*connection {
+                return Ok(connection.clone());
+            }
+            let connected = ConnectionManager::new(self.client.clone()).await?;
+            *connection = Some(connected.clone());
+            Ok(connected)
+        }
+    }
+
+    impl std::fmt::Debug for RedisBackend {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RedisBackend")
+                .field("server", &self.client.get_connection_info().addr)
+                .finish()
+        }
+    }
+
+    impl CacheBackend for RedisBackend {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<Option<Vec<u8>>>> {
+            Box::pin(async move {
+                let mut connection = self.connection().await?;
+                Ok(redis::cmd("GET")
+                    .arg(key)
+                    .query_async(&mut connection)
+                    .await?)
+            })
+        }
+
+        fn set<'a>(
+            &'a self,
+            key: &'a str,
+            value: Vec<u8>,
+            ttl: Duration,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async move {
+                let mut connection = self.connection().await?;
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(value)
+                    .arg("EX")
+                    .arg(ttl.as_secs().max(1))
+                    .query_async::<()>(&mut connection)
+                    .await?;
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::cache_handler::{CacheConfig, CacheHandle};
+    use crate::protocol::DnsResourceRecord;
+    use crate::registry::{RecordClass, RecordType};
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+
+    /// A store in memory; `failing` makes every request fail
+    #[derive(Debug, Default)]
+    struct MemoryBackend {
+        values: Mutex<HashMap<String, Vec<u8>>>,
+        failing: bool,
+    }
+
+    impl CacheBackend for MemoryBackend {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<Option<Vec<u8>>>> {
+            Box::pin(async move {
+                anyhow::ensure!(!self.failing, "connection refused");
+                Ok(self.values.lock().unwrap().get(key).cloned())
+            })
+        }
+
+        fn set<'a>(
+            &'a self,
+            key: &'a str,
+            value: Vec<u8>,
+            _ttl: Duration,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async move {
+                anyhow::ensure!(!self.failing, "connection refused");
+                self.values.lock().unwrap().insert(key.to_string(), value);
+                Ok(())
+            })
+        }
+    }
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey {
+            name: Arc::from(name),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+            route: None,
+        }
+    }
+
+    fn addresses() -> Resolution {
+        Resolution::Records(vec![DnsResourceRecord::new(
+            "example.com".into(),
+            RecordType::A,
+            RecordClass::IN,
+            300,
+            vec![192, 0, 2, 1],
+        )])
+    }
+
+    fn instance(backend: &Arc<MemoryBackend>, tenant: &str) -> (CacheHandle, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new(1));
+        let backend: Arc<dyn CacheBackend> = backend.clone();
+        let shared = SharedCache::new(backend, tenant, Arc::clone(&metrics));
+        let cache =
+            CacheHandle::new(CacheConfig::default(), Arc::clone(&metrics)).with_shared(shared);
+        (cache, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_instances_share_answers() {
+        let backend = Arc::new(MemoryBackend::default());
+        let (first, _) = instance(&backend, "default");
+        let (second, metrics) = instance(&backend, "default");
+        let (other_tenant, _) = instance(&backend, "acme");
+
+        first.insert(key("example.com"), addresses()).await;
+        // Written to the store in the background
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let cached = second.get(key("example.com")).await.unwrap();
+        assert!(
+            matches!(cached.resolution, Resolution::Records(ref records) if records[0].ttl <= 300)
+        );
+        assert_eq!(metrics.shared_cache_hits.load(Ordering::Relaxed), 1);
+        // ...and kept locally from then on
+        assert!(second.get(key("example.com")).await.is_some());
+        assert_eq!(metrics.shared_cache_hits.load(Ordering::Relaxed), 1);
+
+        assert!(other_tenant.get(key("example.com")).await.is_none());
+        assert!(second.get(key("example.org")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_store_is_left_alone() {
+        let backend = Arc::new(MemoryBackend {
+            failing: true,
+            ..Default::default()
+        });
+        let (cache, metrics) = instance(&backend, "default");
+
+        // The local cache carries on without it
+        cache.insert(key("example.com"), addresses()).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cache.get(key("example.com")).await.is_some());
+        assert!(cache.get(key("example.org")).await.is_none());
+        assert!(cache.get(key("example.net")).await.is_none());
+        assert_eq!(metrics.shared_cache_errors.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,364 @@
+//! Query statistics for dashboards (`top` and `rates` on the control socket)
+//!
+//! A tenant with a control socket keeps the last hour of its query log summed
+//! up in memory: per second for the last minute, for rolling query rates, and
+//! per minute for the last hour, with each minute's count of queries by name,
+//! by blocked name and by client. `top` merges the minutes into the most
+//! queried names, blocked names or clients of the hour; `rates` shows the
+//! queries per second and the share blocked over the last 10 seconds, minute,
+//! 5 minutes and hour.
+//!
+//! The statistics are fed like the query logs, through a queue that drops
+//! events rather than hold up answers, and see the queries the logs see: a
+//! group's queries kept out of the log (`log=` of `--group`) are left out.
+//! A minute counts at most 10 000 distinct names and clients of each kind;
+//! queries beyond that still count towards the rates.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc;
+
+use crate::query_log::{Disposition, QueryEvent};
+
+/// How many seconds of rates are kept
+const SECONDS: u64 = 60;
+
+/// How many minutes of counts are kept
+const MINUTES: u64 = 60;
+
+/// Most distinct names or clients of each kind a minute counts
+const MAX_KEYS: usize = 10_000;
+
+/// Most events taken from the queue at once
+const BATCH: usize = 256;
+
+/// How many entries `top` shows unless told otherwise
+pub const DEFAULT_TOP: usize = 10;
+
+/// The windows `rates` covers, in seconds
+const RATE_WINDOWS: [(&str, u64); 4] = [("10s", 10), ("1m", 60), ("5m", 300), ("1h", 3600)];
+
+/// What `top` ranks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopKind {
+    Domains,
+    Blocked,
+    Clients,
+}
+
+impl fmt::Display for TopKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TopKind::Domains => "domains",
+            TopKind::Blocked => "blocked",
+            TopKind::Clients => "clients",
+        })
+    }
+}
+
+impl FromStr for TopKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "domains" => Ok(TopKind::Domains),
+            "blocked" => Ok(TopKind::Blocked),
+            "clients" => Ok(TopKind::Clients),
+            _ => Err(format!("ranking '{}' (domains, blocked or clients)", s)),
+        }
+    }
+}
+
+/// Queries and how many of them were blocked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Counts {
+    queries: u64,
+    blocked: u64,
+}
+
+impl Counts {
+    fn add(&mut self, blocked: bool) {
+        self.queries += 1;
+        self.blocked += u64::from(blocked);
+    }
+}
+
+/// One minute's counts
+#[derive(Debug, Default)]
+struct Minute {
+    counts: Counts,
+    domains: HashMap<Arc<str>, u64>,
+    blocked: HashMap<Arc<str>, u64>,
+    clients: HashMap<IpAddr, u64>,
+}
+
+/// Count one more for `key`, unless the map is full and doesn't have it
+fn count<K: Hash + Eq>(map: &mut HashMap<K, u64>, key: K) {
+    if map.len() < MAX_KEYS {
+        *map.entry(key).or_default() += 1;
+    } else if let Some(count) = map.get_mut(&key) {
+        *count += 1;
+    }
+}
+
+/// The rings of seconds and minutes, each slot holding the second or minute
+/// it counts so that a stale one is told from a current one
+#[derive(Debug)]
+struct Window {
+    seconds: Vec<(u64, Counts)>,
+    minutes: Vec<(u64, Minute)>,
+}
+
+impl Window {
+    fn record(&mut self, event: &QueryEvent) {
+        let second = epoch_seconds(event.time);
+        let blocked = event.disposition == Disposition::Blocked;
+
+        // An event too late for its slot, which has moved on, isn't counted
+        let slot = &mut self.seconds[(second % SECONDS) as usize];
+        if slot.0 < second {
+            *slot = (second, Counts::default());
+        }
+        if slot.0 == second {
+            slot.1.add(blocked);
+        }
+
+        let minute = second / 60;
+        let slot = &mut self.minutes[(minute % MINUTES) as usize];
+        if slot.0 < minute {
+            *slot = (minute, Minute::default());
+        } else if slot.0 > minute {
+            return;
+        }
+        let counts = &mut slot.1;
+        counts.counts.add(blocked);
+        count(&mut counts.domains, Arc::clone(&event.name));
+        if blocked {
+            count(&mut counts.blocked, Arc::clone(&event.name));
+        }
+        count(&mut counts.clients, event.client.ip().to_canonical());
+    }
+
+    /// The minutes of the last hour, up to `now`
+    fn hour(&self, now: u64) -> impl Iterator<Item = &Minute> {
+        let minute = now / 60;
+        self.minutes
+            .iter()
+            .filter(move |(start, _)| *start + MINUTES > minute && *start <= minute)
+            .map(|(_, counts)| counts)
+    }
+
+    /// The counts of the `window` seconds up to `now`
+    fn counts(&self, now: u64, window: u64) -> Counts {
+        let mut total = Counts::default();
+        let mut add = |counts: &Counts| {
+            total.queries += counts.queries;
+            total.blocked += counts.blocked;
+        };
+        if window <= SECONDS {
+            self.seconds
+                .iter()
+                .filter(|(second, _)| *second + window > now && *second <= now)
+                .for_each(|(_, counts)| add(counts));
+        } else {
+            // Whole minutes, the current one included
+            let minutes = window / 60;
+            let minute = now / 60;
+            self.minutes
+                .iter()
+                .filter(|(start, _)| *start + minutes > minute && *start <= minute)
+                .for_each(|(_, counts)| add(&counts.counts));
+        }
+        total
+    }
+}
+
+/// A tenant's query statistics
+#[derive(Debug)]
+pub struct QueryStats {
+    window: Mutex<Window>,
+}
+
+impl Default for QueryStats {
+    fn default() -> Self {
+        QueryStats {
+            window: Mutex::new(Window {
+                seconds: vec![(0, Counts::default()); SECONDS as usize],
+                minutes: (0..MINUTES).map(|_| (0, Minute::default())).collect(),
+            }),
+        }
+    }
+}
+
+impl QueryStats {
+    fn window(&self) -> std::sync::MutexGuard<'_, Window> {
+        self.window.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record(&self, events: &[Arc<QueryEvent>]) {
+        let mut window = self.window();
+        for event in events {
+            window.record(event);
+        }
+    }
+
+    /// The `n` names or clients with the most queries in the last hour, most
+    /// first, with their counts
+    pub fn top(&self, kind: TopKind, n: usize) -> Vec<(String, u64)> {
+        self.top_at(kind, n, epoch_seconds(SystemTime::now()))
+    }
+
+    fn top_at(&self, kind: TopKind, n: usize, now: u64) -> Vec<(String, u64)> {
+        let window = self.window();
+        let mut totals: HashMap<String, u64> = HashMap::new();
+        for minute in window.hour(now) {
+            match kind {
+                TopKind::Domains => merge(&mut totals, &minute.domains),
+                TopKind::Blocked => merge(&mut totals, &minute.blocked),
+                TopKind::Clients => merge(&mut totals, &minute.clients),
+            }
+        }
+        let mut top: Vec<(String, u64)> = totals.into_iter().collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    /// Queries per second and the percentage blocked over each window, as
+    /// `name value` pairs
+    pub fn rates(&self) -> Vec<(String, f64)> {
+        self.rates_at(epoch_seconds(SystemTime::now()))
+    }
+
+    fn rates_at(&self, now: u64) -> Vec<(String, f64)> {
+        let window = self.window();
+        let mut rates = Vec::with_capacity(RATE_WINDOWS.len() * 2);
+        for (name, seconds) in RATE_WINDOWS {
+            let counts = window.counts(now, seconds);
+            let blocked = if counts.queries == 0 {
+                0.0
+            } else {
+                100.0 * counts.blocked as f64 / counts.queries as f64
+            };
+            rates.push((
+                format!("qps_{}", name),
+                counts.queries as f64 / seconds as f64,
+            ));
+            rates.push((format!("blocked_percent_{}", name), blocked));
+        }
+        rates
+    }
+}
+
+fn merge<K: ToString>(totals: &mut HashMap<String, u64>, counts: &HashMap<K, u64>) {
+    for (key, count) in counts {
+        *totals.entry(key.to_string()).or_default() += count;
+    }
+}
+
+fn epoch_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The statistics' writer task: counts whatever has queued up at once
+pub async fn count_events(mut events: mpsc::Receiver<Arc<QueryEvent>>, stats: Arc<QueryStats>) {
+    let mut batch = Vec::with_capacity(BATCH);
+    while events.recv_many(&mut batch, BATCH).await > 0 {
+        stats.record(&batch);
+        batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{Rcode, RecordType};
+    use std::time::Duration;
+
+    fn event(name: &str, client: &str, second: u64, disposition: Disposition) -> Arc<QueryEvent> {
+        Arc::new(QueryEvent {
+            time: UNIX_EPOCH + Duration::from_secs(second),
+            client: format!("{}:53311", client).parse().unwrap(),
+            name: name.into(),
+            qtype: RecordType::A,
+            rcode: Rcode::NOERROR,
+            answers: 1,
+            records: Vec::new(),
+            latency: Duration::from_millis(1),
+            disposition,
+        })
+    }
+
+    #[test]
+    fn test_top_and_rates() {
+        let stats = QueryStats::default();
+        let now = 1_000_000;
+        let mut events = Vec::new();
+        for _ in 0..3 {
+            events.push(event(
+                "www.example",
+                "192.0.2.1",
+                now,
+                Disposition::Forwarded,
+            ));
+        }
+        events.push(event("ads.example", "192.0.2.2", now, Disposition::Blocked));
+        events.push(event(
+            "ads.example",
+            "192.0.2.2",
+            now - 30,
+            Disposition::Blocked,
+        ));
+        // Half an hour ago, and two hours ago, too old for its slot by now
+        events.push(event(
+            "old.example",
+            "[::ffff:192.0.2.1]",
+            now - 1800,
+            Disposition::Cached,
+        ));
+        events.push(event(
+            "gone.example",
+            "192.0.2.9",
+            now - 7200,
+            Disposition::Cached,
+        ));
+        stats.record(&events);
+
+        let top = |kind, n| stats.top_at(kind, n, now);
+        assert_eq!(
+            top(TopKind::Domains, 10),
+            [
+                ("www.example".to_string(), 3),
+                ("ads.example".to_string(), 2),
+                ("old.example".to_string(), 1),
+            ]
+        );
+        assert_eq!(top(TopKind::Domains, 1).len(), 1);
+        assert_eq!(top(TopKind::Blocked, 10), [("ads.example".to_string(), 2)]);
+        assert_eq!(
+            top(TopKind::Clients, 10),
+            [("192.0.2.1".to_string(), 4), ("192.0.2.2".to_string(), 2)]
+        );
+
+        let rates: HashMap<String, f64> = stats.rates_at(now).into_iter().collect();
+        assert_eq!(rates["qps_10s"], 0.4);
+        assert_eq!(rates["blocked_percent_10s"], 25.0);
+        assert_eq!(rates["qps_1m"], 5.0 / 60.0);
+        assert_eq!(rates["blocked_percent_1m"], 40.0);
+        assert_eq!(rates["qps_1h"], 6.0 / 3600.0);
+        // Nothing at all yet
+        let rates = QueryStats::default().rates_at(now);
+        assert!(rates.iter().all(|(_, value)| *value == 0.0));
+
+        assert_eq!("blocked".parse::<TopKind>(), Ok(TopKind::Blocked));
+        assert!("names".parse::<TopKind>().is_err());
+    }
+}
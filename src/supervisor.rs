@@ -0,0 +1,77 @@
+//! Restarting actors that panic
+//!
+//! A panic inside an actor ends its task, and with it the loop serving the
+//! actor's queue. Each supervised actor runs in a task of its own that the
+//! supervisor waits on; when that task panics, a new actor is started in its
+//! place, after a short pause so an actor that fails on start doesn't spin.
+//! The query actor pools share one queue that outlives any one actor, so the
+//! replacement takes the next message where the crashed one left off; only
+//! the query being handled is lost, and its caller gets SERVFAIL. An actor
+//! that returns, because its queue closed, is done and isn't restarted.
+//! `stats` counts restarts as `actor_restarts`.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::error;
+
+use crate::metrics::{self, Metrics};
+
+/// Pause before an actor that panicked is started again
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// Run the actor `start` makes, making a new one whenever it panics
+pub fn supervise<F, Fut>(name: &'static str, metrics: Arc<Metrics>, mut start: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            match tokio::spawn(start()).await {
+                Err(e) if e.is_panic() => {
+                    error!("The {} actor panicked; restarting it", name);
+                    metrics::incr(&metrics.actor_restarts);
+                    tokio::time::sleep(RESTART_DELAY).await;
+                }
+                // Its queue closed, or the runtime is shutting down
+                _ => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_panicking_actor_is_restarted() {
+        let metrics = Arc::new(Metrics::new(1));
+        let starts = Arc::new(AtomicUsize::new(0));
+        let (done, mut finished) = mpsc::channel(1);
+
+        supervise("test", Arc::clone(&metrics), {
+            let starts = Arc::clone(&starts);
+            move || {
+                let start = starts.fetch_add(1, Ordering::Relaxed);
+                let done = done.clone();
+                async move {
+                    if start < 2 {
+                        panic!("actor failure {}", start);
+                    }
+                    let _ = done.send(()).await;
+                }
+            }
+        });
+
+        // Two panics, then an actor that runs to the end and stays ended
+        finished.recv().await.unwrap();
+        assert_eq!(metrics.actor_restarts.load(Ordering::Relaxed), 2);
+        tokio::time::sleep(RESTART_DELAY * 2).await;
+        assert_eq!(starts.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,59 @@
+//! DNS messages over TCP
+//!
+//! A stream has no datagram boundaries, so each message on it is preceded by
+//! its length as two bytes in network order (RFC 1035 §4.2.2, RFC 7766 §8).
+//! Every TCP and TLS conversation the server has, with clients, upstreams,
+//! primaries and name servers alike, frames its messages here.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Send one message, its length prefix in the same write so both go out
+/// in one segment
+pub async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &[u8],
+) -> io::Result<()> {
+    let len = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long"))?;
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await?;
+    stream.flush().await
+}
+
+/// Receive one message
+pub async fn read_message(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let len = stream.read_u16().await?;
+    let mut message = vec![0; len.into()];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_messages_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        write_message(&mut client, b"first").await.unwrap();
+        write_message(&mut client, b"").await.unwrap();
+        write_message(&mut client, &[0xab; 300]).await.unwrap();
+        drop(client);
+
+        assert_eq!(read_message(&mut server).await.unwrap(), b"first");
+        assert!(read_message(&mut server).await.unwrap().is_empty());
+        assert_eq!(read_message(&mut server).await.unwrap(), [0xab; 300]);
+        // A closed stream, or one cut off inside a message, is an error
+        assert!(read_message(&mut server).await.is_err());
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[0, 10, 1, 2, 3]).await.unwrap();
+        drop(client);
+        assert!(read_message(&mut server).await.is_err());
+        assert!(write_message(&mut server, &vec![0; 70000]).await.is_err());
+    }
+}
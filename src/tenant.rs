@@ -0,0 +1,322 @@
+//! Multi-tenant serving
+//!
+//! Each tenant is a separate server within the process: its own DNS listener,
+//! local records (its zones), upstream resolver, client access list, counters
+//! and control socket, optionally protected by an admin token or client
+//! certificates. Nothing is shared between tenants except the GeoIP database
+//! and site map, so one tenant can neither see nor change another's records
+//! or statistics.
+//!
+//! The top-level options describe the default tenant; more are added with
+//! `--tenant`, e.g.
+//!
+//! ```text
+//! --tenant name=acme,listen=0.0.0.0:5300,control=127.0.0.1:2055,upstream=9.9.9.9:53@10.1.0.1,upstream=149.112.112.112:53,hedge=50,allow=10.1.0.0/16,deny=10.1.99.0/24,token-file=/etc/dns/acme.token,catalog=catalog.acme.example@192.0.2.53,update-forward=acme.example@192.0.2.53
+//! ```
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use clap::ValueEnum;
+
+use crate::acl::{ClientAcl, DenyAction};
+use crate::catalog::CatalogSource;
+use crate::control_tls;
+use crate::geoip::Subnet;
+use crate::hedge::Hedge;
+use crate::quota::{ClientQuotas, Quota};
+use crate::update::UpdateForward;
+use crate::upstream::Upstream;
+use crate::zone_file::ZoneFile;
+
+/// Name of the tenant configured by the top-level options
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Address the default tenant listens on
+pub const DEFAULT_LISTEN_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 2053);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    pub name: String,
+    /// UDP address queries are received on
+    pub listen: SocketAddr,
+    /// Control socket address, if the tenant has one
+    pub control: Option<SocketAddr>,
+    /// Upstream resolvers; the OS configuration (or 8.8.8.8) when none are given
+    pub upstreams: Vec<Upstream>,
+    /// How the upstreams race, if they do
+    pub hedge: Option<Hedge>,
+    /// Client subnets allowed to query; everyone when empty
+    pub allow: Vec<Subnet>,
+    /// Client subnets not allowed to query, even when in an allowed one
+    pub deny: Vec<Subnet>,
+    pub deny_action: DenyAction,
+    /// Queries a second, and the burst above it, each client may send
+    pub quota: Option<Quota>,
+    /// What clients over their quota get
+    pub quota_action: DenyAction,
+    /// File holding the token control connections must present with `auth`
+    pub token_file: Option<PathBuf>,
+    /// DNS names of the client certificates let on the control socket, when
+    /// it speaks TLS; any the CA issued when empty
+    pub control_clients: Vec<String>,
+    /// Catalog zones whose member zones the tenant serves as a secondary
+    pub catalogs: Vec<CatalogSource>,
+    /// Zones the tenant serves from files on disk
+    pub zone_files: Vec<ZoneFile>,
+    /// Zones whose dynamic updates are forwarded to a primary
+    pub update_forwards: Vec<UpdateForward>,
+}
+
+impl Tenant {
+    /// Who may query the tenant
+    pub fn acl(&self) -> ClientAcl {
+        ClientAcl::new(self.allow.clone(), self.deny.clone(), self.deny_action)
+    }
+
+    /// Each client's query quota, if the tenant sets one
+    pub fn quotas(&self) -> Option<ClientQuotas> {
+        self.quota
+            .map(|quota| ClientQuotas::new(quota, self.quota_action))
+    }
+
+    /// The admin token from the tenant's token file, if it has one. Surrounding
+    /// whitespace (such as a trailing newline) is not part of the token.
+    pub fn read_token(&self) -> anyhow::Result<Option<Arc<str>>> {
+        let Some(path) = &self.token_file else {
+            return Ok(None);
+        };
+        let contents = std::fs::read_to_string(path).with_context(|| {
+            format!(
+                "could not read token file {} of tenant {}",
+                path.display(),
+                self.name
+            )
+        })?;
+        let token = contents.trim();
+        if token.is_empty() || token.contains(char::is_whitespace) {
+            anyhow::bail!(
+                "token file {} of tenant {} must hold a single token",
+                path.display(),
+                self.name
+            );
+        }
+        Ok(Some(token.into()))
+    }
+}
+
+/// Tenants can't share a name, a listener or a control socket
+pub fn check_distinct(tenants: &[Tenant]) -> Result<(), String> {
+    for (i, tenant) in tenants.iter().enumerate() {
+        for other in &tenants[..i] {
+            if tenant.name == other.name {
+                return Err(format!("more than one tenant is named {}", tenant.name));
+            }
+            if tenant.listen == other.listen {
+                return Err(format!(
+                    "tenants {} and {} both listen on {}",
+                    other.name, tenant.name, tenant.listen
+                ));
+            }
+            if let Some(control) = tenant.control.filter(|&addr| Some(addr) == other.control) {
+                return Err(format!(
+                    "tenants {} and {} both use control socket {}",
+                    other.name, tenant.name, control
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "name={},listen={}", self.name, self.listen)?;
+        if let Some(control) = self.control {
+            write!(f, ",control={}", control)?;
+        }
+        for upstream in &self.upstreams {
+            write!(f, ",upstream={}", upstream)?;
+        }
+        if let Some(hedge) = self.hedge {
+            write!(f, ",hedge={}", hedge)?;
+        }
+        for subnet in &self.allow {
+            write!(f, ",allow={}", subnet)?;
+        }
+        for subnet in &self.deny {
+            write!(f, ",deny={}", subnet)?;
+        }
+        if self.deny_action == DenyAction::Drop {
+            write!(f, ",deny-action=drop")?;
+        }
+        if let Some(quota) = self.quota {
+            write!(f, ",quota={}", quota)?;
+        }
+        if self.quota_action == DenyAction::Drop {
+            write!(f, ",quota-action=drop")?;
+        }
+        if let Some(token_file) = &self.token_file {
+            write!(f, ",token-file={}", token_file.display())?;
+        }
+        for name in &self.control_clients {
+            write!(f, ",control-client={}", name)?;
+        }
+        for catalog in &self.catalogs {
+            write!(f, ",catalog={}", catalog)?;
+        }
+        for zone_file in &self.zone_files {
+            write!(f, ",zone-file={}", zone_file)?;
+        }
+        for forward in &self.update_forwards {
+            write!(f, ",update-forward={}", forward)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses comma-separated `key=value` settings: `name` and `listen` are
+/// required; `control`, `hedge`, `token-file`, `deny-action`, `quota`,
+/// `quota-action` and (repeatable)
+/// `upstream`, `allow`, `deny`, `control-client`, `catalog`, `zone-file` and
+/// `update-forward` are optional
+impl FromStr for Tenant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut listen = None;
+        let mut tenant = Tenant {
+            name: String::new(),
+            listen: DEFAULT_LISTEN_ADDR,
+            control: None,
+            upstreams: Vec::new(),
+            hedge: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            deny_action: DenyAction::Refuse,
+            quota: None,
+            quota_action: DenyAction::Refuse,
+            token_file: None,
+            control_clients: Vec::new(),
+            catalogs: Vec::new(),
+            zone_files: Vec::new(),
+            update_forwards: Vec::new(),
+        };
+
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got '{}'", setting))?;
+            let addr = || {
+                value
+                    .parse::<SocketAddr>()
+                    .map_err(|_| format!("invalid {} address '{}'", key, value))
+            };
+            match key {
+                "name" => {
+                    if value.is_empty()
+                        || !value
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+                    {
+                        return Err(format!("invalid tenant name '{}'", value));
+                    }
+                    name = Some(value.to_string());
+                }
+                "listen" => listen = Some(addr()?),
+                "control" => tenant.control = Some(addr()?),
+                "upstream" => tenant.upstreams.push(value.parse()?),
+                "hedge" => tenant.hedge = Some(value.parse()?),
+                "allow" => tenant.allow.push(value.parse()?),
+                "deny" => tenant.deny.push(value.parse()?),
+                "deny-action" => tenant.deny_action = DenyAction::from_str(value, true)?,
+                "quota" => tenant.quota = Some(value.parse()?),
+                "quota-action" => tenant.quota_action = DenyAction::from_str(value, true)?,
+                "token-file" => tenant.token_file = Some(PathBuf::from(value)),
+                "control-client" => tenant
+                    .control_clients
+                    .push(control_tls::parse_client_name(value)?),
+                "catalog" => tenant.catalogs.push(value.parse()?),
+                "zone-file" => tenant.zone_files.push(value.parse()?),
+                "update-forward" => tenant.update_forwards.push(value.parse()?),
+                _ => return Err(format!("unknown tenant setting '{}'", key)),
+            }
+        }
+
+        tenant.name = name.ok_or("tenant needs a name=<name>")?;
+        tenant.listen = listen.ok_or("tenant needs a listen=<ip>:<port>")?;
+        Ok(tenant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tenant() {
+        let spec = "name=acme,listen=0.0.0.0:5300,control=127.0.0.1:2055,\
+                    upstream=9.9.9.9:53@10.1.0.1,upstream=[2620:fe::9]:53,hedge=50/2,allow=10.1.0.0/16,allow=192.168.0.0/24,deny=10.1.99.0/24,deny-action=drop,quota=200/500,token-file=/etc/acme.token,control-client=admin.acme.example,\
+                    catalog=catalog.acme.example@192.0.2.53,zone-file=acme.example=/etc/acme.csv,update-forward=acme.example@192.0.2.53";
+        let tenant: Tenant = spec.parse().unwrap();
+        assert_eq!(tenant.name, "acme");
+        assert_eq!(tenant.listen, "0.0.0.0:5300".parse().unwrap());
+        assert_eq!(tenant.upstreams.len(), 2);
+        assert_eq!(tenant.hedge.unwrap().racers, 2);
+        assert_eq!(tenant.allow.len(), 2);
+        assert_eq!(tenant.catalogs[0].zone, "catalog.acme.example");
+        assert_eq!(tenant.zone_files[0].zone, "acme.example");
+        assert_eq!(tenant.update_forwards[0].zone, "acme.example");
+        assert_eq!(tenant.quota.unwrap().burst, 500);
+        assert_eq!(tenant.quota_action, DenyAction::Refuse);
+        assert_eq!(tenant.control_clients, ["admin.acme.example"]);
+        assert_eq!(tenant.to_string().parse::<Tenant>().unwrap(), tenant);
+
+        let acl = tenant.acl();
+        assert!(acl.permits("10.1.2.3".parse().unwrap()));
+        assert!(!acl.permits("10.2.0.1".parse().unwrap()));
+        assert!(acl.drops("10.1.99.1".parse().unwrap()));
+
+        assert!("listen=0.0.0.0:5300".parse::<Tenant>().is_err());
+        assert!("name=acme".parse::<Tenant>().is_err());
+        assert!("name=acme,listen=0.0.0.0:5300,color=blue"
+            .parse::<Tenant>()
+            .is_err());
+        assert!("name=acme,listen=0.0.0.0:5300,control-client=not a name"
+            .parse::<Tenant>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_distinct() {
+        let acme: Tenant = "name=acme,listen=0.0.0.0:5300,control=127.0.0.1:2055"
+            .parse()
+            .unwrap();
+        let globex: Tenant = "name=globex,listen=0.0.0.0:5301".parse().unwrap();
+        assert!(check_distinct(&[acme.clone(), globex.clone()]).is_ok());
+
+        let same_listener = Tenant {
+            listen: acme.listen,
+            ..globex.clone()
+        };
+        assert!(check_distinct(&[acme.clone(), same_listener]).is_err());
+
+        let same_control = Tenant {
+            control: acme.control,
+            ..globex.clone()
+        };
+        assert!(check_distinct(&[acme.clone(), same_control]).is_err());
+
+        let same_name = Tenant {
+            name: acme.name.clone(),
+            ..globex
+        };
+        assert!(check_distinct(&[acme, same_name]).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Upstream TTL limits (`--min-ttl`, `--max-ttl`)
+//!
+//! Upstream answers keep the TTLs the authoritative servers gave them, both
+//! in responses and in the cache. The limits pull records' TTLs into a range
+//! when they are resolved, so a name with a very short TTL can be kept for
+//! longer, or one with a very long TTL rechecked sooner. Negative answers
+//! keep their SOA's negative caching TTL.
+
+use crate::actors::messages::Resolution;
+
+/// The range upstream records' TTLs are kept in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtlLimits {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Default for TtlLimits {
+    fn default() -> Self {
+        Self {
+            min: 0,
+            max: u32::MAX,
+        }
+    }
+}
+
+impl TtlLimits {
+    /// A resolution with its records' TTLs in range; the maximum wins over a
+    /// larger minimum
+    pub fn apply(&self, resolution: Resolution) -> Resolution {
+        match resolution {
+            Resolution::Records(mut records) if *self != Self::default() => {
+                for record in &mut records {
+                    record.ttl = record.ttl.max(self.min).min(self.max);
+                }
+                Resolution::Records(records)
+            }
+            resolution => resolution,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DnsResourceRecord;
+    use crate::registry::{Rcode, RecordClass, RecordType};
+
+    #[test]
+    fn test_apply() {
+        let records = |ttls: &[u32]| {
+            Resolution::Records(
+                ttls.iter()
+                    .map(|&ttl| {
+                        DnsResourceRecord::new(
+                            "example.com".to_string(),
+                            RecordType::A,
+                            RecordClass::IN,
+                            ttl,
+                            vec![192, 0, 2, 1],
+                        )
+                    })
+                    .collect(),
+            )
+        };
+        let ttls = |resolution: Resolution| match resolution {
+            Resolution::Records(records) => records.iter().map(|record| record.ttl).collect(),
+            _ => Vec::new(),
+        };
+
+        let unlimited = TtlLimits::default();
+        assert_eq!(
+            ttls(unlimited.apply(records(&[0, 30, 86400]))),
+            [0, 30, 86400]
+        );
+
+        let limits = TtlLimits { min: 60, max: 3600 };
+        assert_eq!(
+            ttls(limits.apply(records(&[0, 300, 86400]))),
+            [60, 300, 3600]
+        );
+
+        let inverted = TtlLimits { min: 600, max: 300 };
+        assert_eq!(ttls(inverted.apply(records(&[30]))), [300]);
+
+        assert!(matches!(
+            limits.apply(Resolution::Failed(Rcode::SERVFAIL)),
+            Resolution::Failed(Rcode::SERVFAIL)
+        ));
+    }
+}
@@ -0,0 +1,469 @@
+//! DNS tunneling and exfiltration detection (`--detect-tunnels`)
+//!
+//! Tunnels carry data in the names they ask for, so their queries look
+//! unlike a browser's: a stream of names under one domain that are never
+//! asked twice, long labels of random-looking characters, and TXT or NULL
+//! records to carry the way back. Each minute, the queries of every client
+//! are counted per domain (the last two labels of a name, or three under a
+//! two-letter country code with a short second level, as in `co.uk`), and a
+//! client that asked at least 20 questions under a domain is scored on four
+//! signs:
+//!
+//! - 50 or more different names under the domain
+//! - the part of the names below the domain averaging 3.5 bits of entropy
+//!   per character or more, for names where that part is 16 characters long
+//!   or longer, which at least half of them must be
+//! - labels of 40 characters or longer in at least half the names
+//! - TXT and NULL queries making up at least half the questions
+//!
+//! Two signs or more make an alert: a warning in the log and, with
+//! `--tunnel-webhook`, a JSON POST to the webhook. A client stays flagged
+//! for ten minutes after its last alert, and isn't alerted on again in that
+//! time. With `--tunnel-rate-limit`, a flagged client gets that many queries
+//! a second, whatever their names; the rest are answered REFUSED. `stats`
+//! counts the alerts as `tunnel_alerts` and the refused queries as
+//! `queries_rate_limited`.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::local_records::normalize_name;
+use crate::metrics::{self, Metrics};
+use crate::pipeline::{Answer, Middleware, Next, Request};
+use crate::query_log::Disposition;
+use crate::quota::TokenBucket;
+use crate::registry::{Rcode, RecordType};
+
+/// How long traffic is counted before it's scored afresh
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a client stays flagged after an alert
+const FLAG_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// Questions under a domain a client must ask in a window to be scored
+const MIN_QUERIES: u32 = 20;
+
+/// Different names under a domain that make a sign
+const UNIQUE_NAMES: usize = 50;
+
+/// Mean entropy per character of the names' random part that makes a sign
+const ENTROPY_BITS: f64 = 3.5;
+
+/// Shortest part below the domain whose entropy is measured
+const MIN_RANDOM_LENGTH: usize = 16;
+
+/// Shortest label counted as long
+const LONG_LABEL: usize = 40;
+
+/// Signs that make an alert
+const ALERT_SCORE: usize = 2;
+
+/// Most clients and domains counted in a window; traffic beyond that is
+/// left unscored until the next
+const MAX_TRACKED: usize = 10_000;
+
+/// Longest a webhook is given to take an alert
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An `http://` or `https://` URL alerts are posted to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook(String);
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if ["http://", "https://"]
+            .iter()
+            .any(|scheme| s.starts_with(scheme))
+        {
+            Ok(Webhook(s.to_string()))
+        } else {
+            Err(format!(
+                "webhook '{}' must be an http:// or https:// URL",
+                s
+            ))
+        }
+    }
+}
+
+impl fmt::Display for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// What a client's traffic under a domain looked like
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sign {
+    UniqueNames,
+    HighEntropy,
+    LongLabels,
+    TxtNull,
+}
+
+impl fmt::Display for Sign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Sign::UniqueNames => "unique names",
+            Sign::HighEntropy => "high entropy",
+            Sign::LongLabels => "long labels",
+            Sign::TxtNull => "TXT/NULL volume",
+        })
+    }
+}
+
+/// An alert, as logged and posted to the webhook
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub client: IpAddr,
+    pub domain: String,
+    /// Questions under the domain in the window
+    pub queries: u32,
+    /// Different names among them, counted up to 50
+    pub unique_names: usize,
+    pub signs: Vec<Sign>,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let signs: Vec<String> = self.signs.iter().map(Sign::to_string).collect();
+        write!(
+            f,
+            "client {} under {}: {} queries, {} names ({})",
+            self.client,
+            self.domain,
+            self.queries,
+            self.unique_names,
+            signs.join(", ")
+        )
+    }
+}
+
+/// A client's questions under one domain in the current window
+#[derive(Debug, Default)]
+struct Traffic {
+    queries: u32,
+    /// Hashes of the names asked, up to `UNIQUE_NAMES`
+    names: HashSet<u64>,
+    /// Names whose part below the domain is long enough to measure
+    measured: u32,
+    /// Their summed entropy per character
+    entropy: f64,
+    /// Names with a long label
+    long: u32,
+    /// TXT and NULL questions
+    txt_null: u32,
+}
+
+impl Traffic {
+    fn signs(&self) -> Vec<Sign> {
+        let half = |count: u32| count * 2 >= self.queries;
+        let mut signs = Vec::new();
+        if self.names.len() >= UNIQUE_NAMES {
+            signs.push(Sign::UniqueNames);
+        }
+        if half(self.measured) && self.entropy / f64::from(self.measured) >= ENTROPY_BITS {
+            signs.push(Sign::HighEntropy);
+        }
+        if half(self.long) {
+            signs.push(Sign::LongLabels);
+        }
+        if half(self.txt_null) {
+            signs.push(Sign::TxtNull);
+        }
+        signs
+    }
+}
+
+/// A flagged client
+#[derive(Debug)]
+struct Flag {
+    until: Instant,
+    /// Its queries under `--tunnel-rate-limit`, with a second's worth of
+    /// burst
+    bucket: Option<TokenBucket>,
+}
+
+#[derive(Debug)]
+struct State {
+    window: Instant,
+    traffic: HashMap<(IpAddr, String), Traffic>,
+    flagged: HashMap<IpAddr, Flag>,
+}
+
+/// What became of a question
+#[derive(Debug, Default, PartialEq)]
+struct Verdict {
+    alert: Option<Alert>,
+    limited: bool,
+}
+
+/// The middleware layer scoring clients' questions
+#[derive(Debug)]
+pub struct TunnelDetector {
+    webhook: Option<Webhook>,
+    /// Queries a second for flagged clients, if they are limited
+    rate_limit: Option<u32>,
+    hasher: RandomState,
+    state: Mutex<State>,
+}
+
+impl TunnelDetector {
+    pub fn new(webhook: Option<Webhook>, rate_limit: Option<u32>) -> Self {
+        TunnelDetector {
+            webhook,
+            rate_limit,
+            hasher: RandomState::new(),
+            state: Mutex::new(State {
+                window: Instant::now(),
+                traffic: HashMap::new(),
+                flagged: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Count a question of `client`'s, scoring its traffic under the
+    /// question's domain
+    fn observe(&self, client: IpAddr, name: &str, qtype: RecordType, now: Instant) -> Verdict {
+        let name = normalize_name(name);
+        let (subdomain, domain) = split_domain(&name);
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if now.saturating_duration_since(state.window) >= WINDOW {
+            state.window = now;
+            state.traffic.clear();
+            state.flagged.retain(|_, flag| flag.until > now);
+        }
+
+        let mut verdict = Verdict::default();
+        if let Some(flag) = state
+            .flagged
+            .get_mut(&client)
+            .filter(|flag| flag.until > now)
+        {
+            if let (Some(bucket), Some(rate)) = (&mut flag.bucket, self.rate_limit) {
+                verdict.limited = !bucket.take(rate, rate, now);
+            }
+        }
+
+        let key = (client, domain.to_string());
+        if state.traffic.len() >= MAX_TRACKED && !state.traffic.contains_key(&key) {
+            return verdict;
+        }
+        let traffic = state.traffic.entry(key).or_default();
+        traffic.queries += 1;
+        if traffic.names.len() < UNIQUE_NAMES {
+            traffic.names.insert(self.hasher.hash_one(&name));
+        }
+        let random: String = subdomain.chars().filter(|c| *c != '.').collect();
+        if random.len() >= MIN_RANDOM_LENGTH {
+            traffic.measured += 1;
+            traffic.entropy += entropy(&random);
+        }
+        if subdomain.split('.').any(|label| label.len() >= LONG_LABEL) {
+            traffic.long += 1;
+        }
+        if matches!(qtype, RecordType::TXT | RecordType::NULL) {
+            traffic.txt_null += 1;
+        }
+        if traffic.queries < MIN_QUERIES {
+            return verdict;
+        }
+        let signs = traffic.signs();
+        if signs.len() < ALERT_SCORE {
+            return verdict;
+        }
+        let alert = Alert {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            client,
+            domain: domain.to_string(),
+            queries: traffic.queries,
+            unique_names: traffic.names.len(),
+            signs,
+        };
+
+        // Flagged clients are alerted on again once their flag runs out
+        let until = now + FLAG_DURATION;
+        match state.flagged.get_mut(&client) {
+            Some(flag) if flag.until > now => flag.until = until,
+            _ => {
+                let bucket = self.rate_limit.map(|rate| TokenBucket::full(rate, now));
+                state.flagged.insert(client, Flag { until, bucket });
+                verdict.alert = Some(alert);
+            }
+        }
+        verdict
+    }
+
+    /// Log an alert and post it to the webhook
+    fn raise(&self, alert: Alert, metrics: &Metrics) {
+        warn!("Possible DNS tunnel: {}", alert);
+        metrics::incr(&metrics.tunnel_alerts);
+        if let Some(webhook) = self.webhook.clone() {
+            tokio::task::spawn_blocking(move || {
+                let agent = ureq::AgentBuilder::new().timeout(WEBHOOK_TIMEOUT).build();
+                let body = serde_json::to_string(&alert).unwrap_or_default();
+                let request = agent
+                    .post(&webhook.0)
+                    .set("Content-Type", "application/json");
+                match request.send_string(&body) {
+                    Ok(_) => debug!("Posted the alert on {} to {}", alert.client, webhook),
+                    Err(e) => warn!("Could not post the alert to {}: {}", webhook, e),
+                }
+            });
+        }
+    }
+}
+
+impl Middleware for TunnelDetector {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> BoxFuture<'a, Answer> {
+        let client = request.client.ip().to_canonical();
+        let question = request.question;
+        let verdict = self.observe(client, &question.name, question.qtype, Instant::now());
+        if let Some(alert) = verdict.alert {
+            self.raise(alert, &request.ctx.metrics);
+        }
+        if verdict.limited {
+            metrics::incr(&request.ctx.metrics.queries_rate_limited);
+            return Box::pin(async { Answer::new(Disposition::Local).with_rcode(Rcode::REFUSED) });
+        }
+        next.run(request)
+    }
+}
+
+/// A normalized name's labels below its domain, and the domain
+fn split_domain(name: &str) -> (&str, &str) {
+    let labels: Vec<&str> = name.rsplitn(4, '.').collect();
+    // co.uk, com.au and the like take a label more
+    let count = match labels[..] {
+        [tld, second, _, ..] if tld.len() == 2 && second.len() <= 3 => 3,
+        _ => 2,
+    };
+    if labels.len() <= count {
+        return ("", name);
+    }
+    let domain_len: usize = labels[..count]
+        .iter()
+        .map(|label| label.len() + 1)
+        .sum::<usize>()
+        - 1;
+    let split = name.len() - domain_len;
+    (&name[..split - 1], &name[split..])
+}
+
+/// Shannon entropy per character, in bits
+fn entropy(s: &str) -> f64 {
+    let mut counts = [0u32; 256];
+    for byte in s.bytes() {
+        counts[usize::from(byte)] += 1;
+    }
+    let len = s.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = f64::from(*count) / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 7));
+
+    #[test]
+    fn test_split_domain() {
+        assert_eq!(split_domain("www.example.com"), ("www", "example.com"));
+        assert_eq!(split_domain("a.b.example.com"), ("a.b", "example.com"));
+        assert_eq!(split_domain("example.com"), ("", "example.com"));
+        assert_eq!(split_domain("com"), ("", "com"));
+        assert_eq!(split_domain("www.bbc.co.uk"), ("www", "bbc.co.uk"));
+        assert_eq!(split_domain("bbc.co.uk"), ("", "bbc.co.uk"));
+        assert_eq!(split_domain("x.news.google.de"), ("x.news", "google.de"));
+        assert!(entropy("aaaa") == 0.0);
+        assert!((entropy("abcd") - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tunnel_alert_and_rate_limit() {
+        let detector = TunnelDetector::new(None, Some(2));
+        let now = Instant::now();
+
+        // A busy but ordinary client: the same few names, over and over
+        for i in 0..100 {
+            let name = format!("www{}.example.com", i % 3);
+            assert_eq!(
+                detector.observe(CLIENT, &name, RecordType::A, now),
+                Verdict::default()
+            );
+        }
+
+        // Random base32-looking names carrying TXT answers back
+        let mut alerts = Vec::new();
+        let mut limited = 0;
+        for i in 0..60u64 {
+            let chunk: String = (0..48)
+                .map(|j| {
+                    let n = (i * 7919 + j * 104_729) ^ (i * j * 31);
+                    b"abcdefghijklmnopqrstuvwxyz234567"[(n % 32) as usize] as char
+                })
+                .collect();
+            let name = format!("{}.{}.t.tunnel.example", i, chunk);
+            let verdict = detector.observe(CLIENT, &name, RecordType::TXT, now);
+            alerts.extend(verdict.alert);
+            limited += u32::from(verdict.limited);
+        }
+        assert_eq!(alerts.len(), 1);
+        let alert = &alerts[0];
+        assert_eq!(alert.domain, "tunnel.example");
+        assert_eq!(alert.queries, MIN_QUERIES);
+        assert!(alert.signs.contains(&Sign::LongLabels));
+        assert!(alert.signs.contains(&Sign::TxtNull));
+        assert!(alert.signs.contains(&Sign::HighEntropy));
+        // Two queries a second once flagged, at a standstill
+        assert_eq!(limited, 60 - MIN_QUERIES - 2);
+
+        // A second later, two more; other clients are let through
+        let later = now + Duration::from_secs(1);
+        let verdicts: Vec<bool> = (0..3)
+            .map(|_| {
+                detector
+                    .observe(CLIENT, "www.example.com", RecordType::A, later)
+                    .limited
+            })
+            .collect();
+        assert_eq!(verdicts, [false, false, true]);
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 8));
+        assert!(
+            !detector
+                .observe(other, "www.example.com", RecordType::A, later)
+                .limited
+        );
+
+        // The flag runs out
+        let free = now + FLAG_DURATION + WINDOW;
+        assert!(
+            !detector
+                .observe(CLIENT, "www.example.com", RecordType::A, free)
+                .limited
+        );
+    }
+}
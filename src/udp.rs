@@ -0,0 +1,123 @@
+//! A tenant's UDP sockets: binding them and sending responses
+
+use std::io;
+use std::net::{self, IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+
+#[cfg(target_os = "linux")]
+use crate::pktinfo;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::UringSender;
+
+/// The sending half of a tenant's listener; cheap to clone
+#[derive(Clone, Debug)]
+pub enum UdpSender {
+    /// The tokio socket the queries came in on
+    Tokio(Arc<UdpSocket>),
+    /// The tokio socket, sending from the address the query was sent to
+    #[cfg(target_os = "linux")]
+    TokioFrom(Arc<UdpSocket>, IpAddr),
+    /// The io_uring ring serving the socket (`--io-uring`)
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(UringSender),
+}
+
+impl UdpSender {
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            UdpSender::Tokio(sock) => sock.send_to(buf, addr).await,
+            #[cfg(target_os = "linux")]
+            UdpSender::TokioFrom(sock, local) => pktinfo::send_from(sock, buf, addr, *local).await,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            UdpSender::Uring(ring) => ring.send_to(buf.to_vec(), addr).await,
+        }
+    }
+}
+
+/// Have responses leave from the address each query was sent to, which only
+/// matters on wildcard sockets (Linux only). Returns whether it does.
+pub fn reply_from_destination(sock: &UdpSocket) -> io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    return pktinfo::enable(sock);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = sock;
+        Ok(false)
+    }
+}
+
+/// Receive a query, and where it came from and was sent to. The destination
+/// is only known once `reply_from_destination` is on.
+pub async fn recv_from(
+    sock: &Arc<UdpSocket>,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, UdpSender)> {
+    #[cfg(target_os = "linux")]
+    {
+        let (len, addr, local) = pktinfo::recv_from(sock, buf).await?;
+        let sender = match local {
+            Some(local) => UdpSender::TokioFrom(Arc::clone(sock), local),
+            None => UdpSender::Tokio(Arc::clone(sock)),
+        };
+        Ok((len, addr, sender))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (len, addr) = sock.recv_from(buf).await?;
+        Ok((len, addr, UdpSender::Tokio(Arc::clone(sock))))
+    }
+}
+
+/// Bind a tenant's receive sockets. More than one share the address through
+/// SO_REUSEPORT, and the kernel spreads clients across them by address.
+pub fn bind_shards(addr: SocketAddr, shards: usize) -> io::Result<Vec<UdpSocket>> {
+    if shards <= 1 {
+        let sock = net::UdpSocket::bind(addr)?;
+        sock.set_nonblocking(true)?;
+        return Ok(vec![UdpSocket::from_std(sock)?]);
+    }
+
+    let mut socks = Vec::with_capacity(shards);
+    // With port 0 the first socket picks the port the others share
+    let mut addr = addr;
+    for _ in 0..shards {
+        let sock = bind_reuse_port(addr)?;
+        addr = sock.local_addr()?;
+        socks.push(UdpSocket::from_std(sock)?);
+    }
+    Ok(socks)
+}
+
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_reuse_port(true)?;
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(_addr: SocketAddr) -> io::Result<net::UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "more than one shard needs SO_REUSEPORT, which is Unix only",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_shards() {
+        let socks = bind_shards("127.0.0.1:0".parse().unwrap(), 3).unwrap();
+        assert_eq!(socks.len(), 3);
+        let addr = socks[0].local_addr().unwrap();
+        assert!(socks.iter().all(|sock| sock.local_addr().unwrap() == addr));
+    }
+}
@@ -0,0 +1,295 @@
+//! The UDP client for the server's own upstream queries
+//!
+//! Queries the server sends itself (recursion, and transparent relaying to
+//! UDP upstreams) go through a pool of sockets instead of hickory's. Every
+//! query gets a transaction ID from a cryptographically secure generator
+//! and a socket picked at random from the pool, and sockets are replaced by
+//! freshly bound ones after a few hundred queries, so the source port keeps
+//! moving too. Each query waits in a pending table under its ID and server;
+//! a response is only handed over when it arrives on the query's socket, from
+//! the server asked, under the query's ID and with its question echoed
+//! exactly. Anything else is dropped as stale or forged, and the query keeps
+//! waiting for the real answer.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::proxy::{answers_question, UPSTREAM_TIMEOUT};
+use crate::upstream::{upstream_udp_socket, Upstream};
+
+/// Sockets kept per address family
+const POOL_SIZE: usize = 16;
+/// Queries sent from a socket before it is replaced by one on a new port
+const SOCKET_QUERIES: usize = 256;
+
+/// A pooled socket and how many queries it has sent
+#[derive(Debug)]
+struct Slot {
+    sock: Arc<UdpSocket>,
+    queries: usize,
+}
+
+/// A query waiting for its response
+#[derive(Debug)]
+struct Pending {
+    /// The query as sent, for matching the response's question
+    query: Vec<u8>,
+    /// The local port it was sent from
+    port: u16,
+    respond_to: oneshot::Sender<Vec<u8>>,
+}
+
+/// Outstanding queries by ID and server
+type PendingTable = Mutex<HashMap<(u16, SocketAddr), Pending>>;
+
+/// Sends queries from a rotating pool of sockets and matches the responses
+#[derive(Debug, Default)]
+pub struct UdpClient {
+    /// IPv4 and IPv6 sockets, bound as they are first needed
+    pools: [Mutex<Vec<Slot>>; 2],
+    pending: Arc<PendingTable>,
+}
+
+impl UdpClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `query` to `server` under a fresh ID and return the response
+    /// under the query's own ID. Waits until a matching response arrives, so
+    /// callers bound it with a timeout.
+    pub async fn exchange(&self, query: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+        if query.len() < 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "query shorter than a header",
+            ));
+        }
+        let sock = self.socket(server)?;
+        let port = sock.local_addr()?.port();
+
+        let (send, recv) = oneshot::channel();
+        let mut packet = query.to_vec();
+        let id = {
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            loop {
+                // ThreadRng is a CSPRNG reseeded from the operating system
+                let id: u16 = rand::random();
+                if let Entry::Vacant(entry) = pending.entry((id, server)) {
+                    packet[..2].copy_from_slice(&id.to_be_bytes());
+                    entry.insert(Pending {
+                        query: packet.clone(),
+                        port,
+                        respond_to: send,
+                    });
+                    break id;
+                }
+            }
+        };
+        // Answered or not, the query leaves the table when the exchange ends
+        let _pending = PendingEntry {
+            table: &self.pending,
+            key: (id, server),
+        };
+
+        sock.send_to(&packet, server).await?;
+        let mut response = recv.await.map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "the socket stopped receiving")
+        })?;
+        response[..2].copy_from_slice(&query[..2]);
+        Ok(response)
+    }
+
+    /// A socket of the pool for the server's address family
+    fn socket(&self, server: SocketAddr) -> io::Result<Arc<UdpSocket>> {
+        let mut pool = self.pools[usize::from(server.is_ipv6())]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let index = if pool.len() < POOL_SIZE {
+            pool.push(self.bind(server)?);
+            pool.len() - 1
+        } else {
+            rand::random::<u32>() as usize % POOL_SIZE
+        };
+        if pool[index].queries >= SOCKET_QUERIES {
+            pool[index] = self.bind(server)?;
+        }
+        let slot = &mut pool[index];
+        slot.queries += 1;
+        Ok(Arc::clone(&slot.sock))
+    }
+
+    /// A socket on a new ephemeral port, with a task receiving its responses
+    fn bind(&self, server: SocketAddr) -> io::Result<Slot> {
+        let sock = Arc::new(upstream_udp_socket(&Upstream::from(server))?);
+        tokio::spawn(receive(Arc::clone(&sock), Arc::clone(&self.pending)));
+        Ok(Slot { sock, queries: 0 })
+    }
+}
+
+/// Removes a query from the pending table when dropped
+struct PendingEntry<'a> {
+    table: &'a PendingTable,
+    key: (u16, SocketAddr),
+}
+
+impl Drop for PendingEntry<'_> {
+    fn drop(&mut self) {
+        self.table
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+    }
+}
+
+/// Hand the responses arriving on `sock` to the queries they answer, until
+/// the socket has left the pool and no query is using it
+async fn receive(sock: Arc<UdpSocket>, pending: Arc<PendingTable>) {
+    let Ok(port) = sock.local_addr().map(|addr| addr.port()) else {
+        return;
+    };
+    let mut buf = vec![0; 65535];
+    loop {
+        let (len, from) =
+            match tokio::time::timeout(UPSTREAM_TIMEOUT, sock.recv_from(&mut buf)).await {
+                Ok(Ok(received)) => received,
+                // e.g. an ICMP port unreachable for an earlier query
+                Ok(Err(e)) => {
+                    debug!("Upstream socket on port {}: {}", port, e);
+                    continue;
+                }
+                Err(_) if Arc::strong_count(&sock) == 1 => return,
+                Err(_) => continue,
+            };
+        let response = &buf[..len];
+        if len < 12 || response[2] & 0x80 == 0 {
+            debug!("Ignoring {}-byte non-response from {}", len, from);
+            continue;
+        }
+
+        let id = u16::from_be_bytes([response[0], response[1]]);
+        let mut pending = pending.lock().unwrap_or_else(PoisonError::into_inner);
+        match pending.entry((id, from)) {
+            Entry::Occupied(entry)
+                if entry.get().port == port && answers_question(&entry.get().query, response) =>
+            {
+                let _ = entry.remove().respond_to.send(response.to_vec());
+            }
+            _ => debug!(
+                "Dropping unmatched {}-byte response from {} on port {}",
+                len, from, port
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn query(name: &[u8]) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.push(name.len() as u8);
+        query.extend(name);
+        query.extend([0, 0, 1, 0, 1]);
+        query
+    }
+
+    #[tokio::test]
+    async fn test_exchange_matches_responses() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = UdpClient::new();
+
+        let (first, second) = (query(b"First"), query(b"second"));
+        let answer = async {
+            let mut buf = [0; 512];
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let (len, from) = server.recv_from(&mut buf).await.unwrap();
+                let mut response = buf[..len].to_vec();
+                response[2] |= 0x80;
+                received.push((response, from));
+            }
+            // Decoys first: the question in another case, then an unknown ID
+            for (response, from) in &received {
+                let mut recased = response.clone();
+                recased[13] ^= 0x20;
+                server.send_to(&recased, from).await.unwrap();
+                let mut unknown = response.clone();
+                unknown[0] ^= 0xff;
+                server.send_to(&unknown, from).await.unwrap();
+            }
+            // Then the real answers, the other way round
+            for (response, from) in received.iter().rev() {
+                server.send_to(response, from).await.unwrap();
+            }
+            received
+        };
+
+        let (a, b, received) = tokio::join!(
+            client.exchange(&first, server_addr),
+            client.exchange(&second, server_addr),
+            answer
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a[..2], first[..2]);
+        assert_eq!(a[3..], first[3..]);
+        assert_eq!(b[3..], second[3..]);
+        assert_ne!(received[0].0[..2], received[1].0[..2]);
+        assert!(client.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queries_rotate_ports() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = UdpClient::new();
+
+        let answer = async {
+            let mut ports = HashSet::new();
+            let mut buf = [0; 512];
+            for _ in 0..POOL_SIZE {
+                let (len, from) = server.recv_from(&mut buf).await.unwrap();
+                ports.insert(from.port());
+                buf[2] |= 0x80;
+                server.send_to(&buf[..len], from).await.unwrap();
+            }
+            ports
+        };
+        let ask = async {
+            for _ in 0..POOL_SIZE {
+                client
+                    .exchange(&query(b"example"), server_addr)
+                    .await
+                    .unwrap();
+            }
+        };
+        let (ports, ()) = tokio::join!(answer, ask);
+        assert_eq!(ports.len(), POOL_SIZE);
+
+        // Sockets that have sent their share are replaced as they come up
+        let ports = |client: &UdpClient| -> HashSet<u16> {
+            client.pools[0]
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|slot| slot.sock.local_addr().unwrap().port())
+                .collect()
+        };
+        let before = ports(&client);
+        for slot in client.pools[0].lock().unwrap().iter_mut() {
+            slot.queries = SOCKET_QUERIES;
+        }
+        client.socket(server_addr).unwrap();
+        assert_eq!(ports(&client).difference(&before).count(), 1);
+    }
+}
@@ -0,0 +1,144 @@
+//! Forwarding dynamic updates to the primary (RFC 2136 §6)
+//!
+//! A secondary or forwarder can't apply an UPDATE itself, but it can pass it
+//! on: the message goes to the zone's primary as it came in, under a fresh
+//! ID, and the primary's response is relayed back to the client. Since TSIG
+//! signs the original ID separately, signed updates survive the trip. DHCP
+//! servers registering leases through DDNS depend on this when they only
+//! know a secondary.
+//!
+//! Primaries are configured per zone (`--update-forward <zone>@<primary>`),
+//! and the member zones of a catalog forward to the catalog's primary. An
+//! update is forwarded to the primary of its zone or the closest enclosing
+//! one; updates for other zones are answered NOTIMP as before. When the
+//! primary can't be reached the client gets SERVFAIL.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{PoisonError, RwLock};
+
+use crate::local_records::normalize_name;
+
+/// A zone whose updates go to a primary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateForward {
+    pub zone: String,
+    pub primary: SocketAddr,
+}
+
+impl fmt::Display for UpdateForward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.zone, self.primary)
+    }
+}
+
+/// Parses `<zone>@<ip>[:<port>]`; the port defaults to 53
+impl FromStr for UpdateForward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected <zone>@<ip>[:<port>], got '{}'", s);
+
+        let (zone, primary) = s.split_once('@').ok_or_else(invalid)?;
+        let zone = normalize_name(zone);
+        if zone.is_empty() || zone.split('.').any(str::is_empty) {
+            return Err(invalid());
+        }
+        let primary = match primary.parse::<SocketAddr>() {
+            Ok(primary) => primary,
+            Err(_) => SocketAddr::new(primary.parse::<IpAddr>().map_err(|_| invalid())?, 53),
+        };
+        Ok(Self { zone, primary })
+    }
+}
+
+/// The primaries a tenant's updates are forwarded to, by zone
+#[derive(Debug, Default)]
+pub struct Primaries {
+    /// From the command line; these win over catalog members
+    configured: BTreeMap<String, SocketAddr>,
+    /// Catalog member zones, kept current by the catalogs
+    learned: RwLock<BTreeMap<String, SocketAddr>>,
+}
+
+impl Primaries {
+    pub fn new(forwards: &[UpdateForward]) -> Self {
+        Primaries {
+            configured: forwards
+                .iter()
+                .map(|forward| (forward.zone.clone(), forward.primary))
+                .collect(),
+            learned: RwLock::default(),
+        }
+    }
+
+    /// Forward a catalog member zone's updates to its primary
+    pub fn learn(&self, zone: &str, primary: SocketAddr) {
+        self.learned
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(normalize_name(zone), primary);
+    }
+
+    /// Stop forwarding a zone dropped from its catalog
+    pub fn forget(&self, zone: &str) {
+        self.learned
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&normalize_name(zone));
+    }
+
+    /// The primary for `zone` or the closest zone enclosing it
+    pub fn primary_for(&self, zone: &str) -> Option<SocketAddr> {
+        let zone = normalize_name(zone);
+        let learned = self.learned.read().unwrap_or_else(PoisonError::into_inner);
+        let mut suffix = zone.as_str();
+        loop {
+            if let Some(primary) = self.configured.get(suffix).or_else(|| learned.get(suffix)) {
+                return Some(*primary);
+            }
+            suffix = suffix.split_once('.')?.1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_update_forward() {
+        let forward: UpdateForward = "Lan.@192.168.1.1".parse().unwrap();
+        assert_eq!(forward.zone, "lan");
+        assert_eq!(forward.primary, "192.168.1.1:53".parse().unwrap());
+        assert_eq!(
+            forward.to_string().parse::<UpdateForward>().unwrap(),
+            forward
+        );
+
+        assert!("lan".parse::<UpdateForward>().is_err());
+        assert!("@192.168.1.1".parse::<UpdateForward>().is_err());
+        assert!("lan@router".parse::<UpdateForward>().is_err());
+    }
+
+    #[test]
+    fn test_primary_for() {
+        let primaries = Primaries::new(&["lan@10.0.0.1:53".parse().unwrap()]);
+        let configured: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let learned: SocketAddr = "10.0.0.2:53".parse().unwrap();
+
+        assert_eq!(primaries.primary_for("LAN."), Some(configured));
+        assert_eq!(primaries.primary_for("office.lan"), Some(configured));
+        assert_eq!(primaries.primary_for("example.com"), None);
+
+        primaries.learn("office.lan", learned);
+        primaries.learn("lan", learned);
+        assert_eq!(primaries.primary_for("office.lan"), Some(learned));
+        assert_eq!(primaries.primary_for("lan"), Some(configured));
+
+        primaries.forget("office.lan");
+        assert_eq!(primaries.primary_for("office.lan"), Some(configured));
+    }
+}
@@ -0,0 +1,665 @@
+//! Upstream resolver selection
+//!
+//! When no resolver is given on the command line, the upstreams are taken from
+//! the operating system's resolver configuration (`/etc/resolv.conf` on Unix
+//! and macOS, the network adapter settings on Windows).
+//!
+//! Every upstream is reachable over both UDP and TCP: queries go out over UDP,
+//! and an answer that comes back truncated (TC set) is retried over TCP so the
+//! client gets the full record set instead of a partial one. An upstream given
+//! as `tcp://<ip>:<port>` is only ever asked over TCP, for networks that drop
+//! or mangle DNS over UDP.
+//!
+//! Queries can also be kept from eavesdroppers: `tls://1.1.1.1:853#cloudflare-dns.com`
+//! is DNS over TLS (RFC 7858) and `https://1.1.1.1:443/dns-query#cloudflare-dns.com`
+//! DNS over HTTPS (RFC 8484). The name after `#` is the one the upstream's
+//! certificate is checked against, using the Mozilla root certificates; the
+//! HTTPS path defaults to `/dns-query`.
+//!
+//! On multi-homed hosts and split-tunnel VPNs the default route may be the
+//! wrong way to an upstream, so each one can be reached from a given source
+//! address or through a given interface: `10.8.0.1:53@tun0`,
+//! `1.1.1.1:53@192.168.1.5`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hickory_resolver::config::{NameServerConfig, ResolverConfig};
+use hickory_resolver::name_server::GenericConnector;
+use hickory_resolver::proto::runtime::iocompat::AsyncIoTokioAsStd;
+use hickory_resolver::proto::runtime::{
+    RuntimeProvider, TokioHandle, TokioRuntimeProvider, TokioTime,
+};
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::{ResolveError, Resolver};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::{info, warn};
+
+use crate::retry::RetryPolicy;
+
+/// Used when no upstream is configured and none can be discovered: Google's public DNS
+pub const FALLBACK_RESOLVER: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53);
+
+/// An upstream resolver, how it is asked, and where queries to it are sent from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+    /// The local end of the conversation; the routing table decides when none
+    pub via: Option<Via>,
+}
+
+/// How queries reach an upstream
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// UDP, with TCP for truncated answers
+    #[default]
+    Udp,
+    /// TCP only
+    Tcp,
+    /// DNS over TLS; the certificate must be valid for `server_name`
+    Tls { server_name: String },
+    /// DNS over HTTPS, posted to `endpoint`
+    Https {
+        server_name: String,
+        endpoint: String,
+    },
+}
+
+/// Where DNS over HTTPS queries are sent unless the upstream says otherwise
+pub const DEFAULT_HTTP_ENDPOINT: &str = "/dns-query";
+
+impl Transport {
+    /// The protocols the resolver asks the upstream over, in order
+    fn protocols(&self) -> &'static [Protocol] {
+        match self {
+            Transport::Udp => &[Protocol::Udp, Protocol::Tcp],
+            Transport::Tcp => &[Protocol::Tcp],
+            Transport::Tls { .. } => &[Protocol::Tls],
+            Transport::Https { .. } => &[Protocol::Https],
+        }
+    }
+
+    /// The name the upstream's certificate is checked against
+    pub fn server_name(&self) -> Option<&str> {
+        match self {
+            Transport::Tls { server_name } | Transport::Https { server_name, .. } => {
+                Some(server_name)
+            }
+            Transport::Udp | Transport::Tcp => None,
+        }
+    }
+
+    fn http_endpoint(&self) -> Option<&str> {
+        match self {
+            Transport::Https { endpoint, .. } => Some(endpoint),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Via {
+    /// A local address; the routing table still picks the interface
+    Source(IpAddr),
+    /// A network interface, whatever the routing table says (Linux only)
+    Interface(String),
+}
+
+impl Upstream {
+    /// The address to bind outgoing sockets to
+    pub fn local_addr(&self) -> SocketAddr {
+        match (&self.via, self.addr) {
+            (Some(Via::Source(ip)), _) => SocketAddr::new(*ip, 0),
+            (_, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            (_, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        }
+    }
+
+    /// The interface outgoing sockets are tied to, if any
+    pub fn interface(&self) -> Option<&str> {
+        match &self.via {
+            Some(Via::Interface(name)) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Upstream {
+    fn from(addr: SocketAddr) -> Self {
+        Upstream {
+            addr,
+            transport: Transport::Udp,
+            via: None,
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.transport {
+            Transport::Udp => write!(f, "{}", self.addr)?,
+            Transport::Tcp => write!(f, "tcp://{}", self.addr)?,
+            Transport::Tls { server_name } => write!(f, "tls://{}#{}", self.addr, server_name)?,
+            Transport::Https {
+                server_name,
+                endpoint,
+            } => write!(f, "https://{}{}#{}", self.addr, endpoint, server_name)?,
+        }
+        match &self.via {
+            Some(Via::Source(ip)) => write!(f, "@{}", ip),
+            Some(Via::Interface(name)) => write!(f, "@{}", name),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses `<ip>:<port>`, optionally prefixed by `udp://` or `tcp://` and
+/// followed by `@<source ip>` or `@<interface>`. Encrypted upstreams name
+/// their certificate's server: `tls://<ip>:<port>#<name>` and
+/// `https://<ip>:<port>[/<path>]#<name>`.
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid upstream '{}', expected [udp://|tcp://]<ip>:<port>, \
+                 tls://<ip>:<port>#<name> or https://<ip>:<port>[/<path>]#<name>, \
+                 followed by [@<source ip>|@<interface>]",
+                s
+            )
+        };
+        let (scheme, rest) = s.split_once("://").unwrap_or(("udp", s));
+        let (rest, via) = match rest.split_once('@') {
+            Some((rest, via)) => (rest, Some(via)),
+            None => (rest, None),
+        };
+        let (rest, server_name) = match rest.split_once('#') {
+            Some((rest, name)) if !name.is_empty() => (rest, Some(name.to_ascii_lowercase())),
+            Some(_) => return Err(invalid()),
+            None => (rest, None),
+        };
+        let (addr, endpoint) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], Some(&rest[slash..])),
+            None => (rest, None),
+        };
+        let addr: SocketAddr = addr.parse().map_err(|_| invalid())?;
+
+        let transport = match (scheme, server_name, endpoint) {
+            ("udp", None, None) => Transport::Udp,
+            ("tcp", None, None) => Transport::Tcp,
+            ("tls", Some(server_name), None) => Transport::Tls { server_name },
+            ("https", Some(server_name), endpoint) => Transport::Https {
+                server_name,
+                endpoint: endpoint.unwrap_or(DEFAULT_HTTP_ENDPOINT).to_string(),
+            },
+            ("tls" | "https", None, _) => {
+                return Err(format!(
+                    "upstream '{}' needs the name on its certificate, as #<name>",
+                    s
+                ));
+            }
+            ("udp" | "tcp" | "tls", _, _) => return Err(invalid()),
+            (scheme, _, _) => {
+                return Err(format!(
+                    "upstream '{}': unknown transport '{}', expected udp, tcp, tls or https",
+                    s, scheme
+                ));
+            }
+        };
+        let via = match via {
+            None => None,
+            Some(via) => match via.parse::<IpAddr>() {
+                Ok(source) if source.is_ipv4() != addr.is_ipv4() => {
+                    return Err(format!(
+                        "source address {} can't reach upstream {}",
+                        source, addr
+                    ));
+                }
+                Ok(source) => Some(Via::Source(source)),
+                Err(_) => Some(Via::Interface(parse_interface(via)?)),
+            },
+        };
+        Ok(Upstream {
+            addr,
+            transport,
+            via,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn parse_interface(name: &str) -> Result<String, String> {
+    // IFNAMSIZ, less the terminating NUL
+    if name.is_empty() || name.len() > 15 || name.contains(['/', ' ', '\0']) {
+        return Err(format!("invalid interface name '{}'", name));
+    }
+    Ok(name.to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn parse_interface(name: &str) -> Result<String, String> {
+    Err(format!(
+        "'{}' is not an IP address; sending through a given interface is Linux only",
+        name
+    ))
+}
+
+/// Pick the upstream resolvers to forward to.
+///
+/// Explicit resolvers always win. Otherwise the OS configuration is used,
+/// unless `use_system` is false, in which case the fallback resolver is used.
+pub fn select_upstreams(explicit: &[Upstream], use_system: bool) -> Vec<Upstream> {
+    if !explicit.is_empty() {
+        return explicit.to_vec();
+    }
+
+    if use_system {
+        match system_resolvers() {
+            Ok(addrs) if !addrs.is_empty() => {
+                info!(
+                    "Using upstream resolvers from the system configuration: {:?}",
+                    addrs
+                );
+                return addrs.into_iter().map(Upstream::from).collect();
+            }
+            Ok(_) => warn!("System resolver configuration lists no name servers"),
+            Err(e) => warn!("Could not read the system resolver configuration: {}", e),
+        }
+    }
+
+    info!("Using fallback upstream resolver {}", FALLBACK_RESOLVER);
+    vec![FALLBACK_RESOLVER.into()]
+}
+
+/// Resolver configuration forwarding to `upstreams`, each over UDP with TCP as
+/// the fallback for truncated answers, or over TCP, TLS or HTTPS alone
+pub fn resolver_config(upstreams: &[Upstream]) -> ResolverConfig {
+    let mut config = ResolverConfig::new();
+    // hickory only retries over TCP if the pool holds TCP connections to the same servers
+    for protocol in [Protocol::Udp, Protocol::Tcp, Protocol::Tls, Protocol::Https] {
+        for upstream in upstreams {
+            if !upstream.transport.protocols().contains(&protocol) {
+                continue;
+            }
+            config.add_name_server(NameServerConfig {
+                socket_addr: upstream.addr,
+                protocol,
+                tls_dns_name: upstream.transport.server_name().map(str::to_string),
+                http_endpoint: upstream.transport.http_endpoint().map(str::to_string),
+                trust_negative_responses: true,
+                bind_addr: match upstream.via {
+                    Some(Via::Source(_)) => Some(upstream.local_addr()),
+                    _ => None,
+                },
+            });
+        }
+    }
+    config
+}
+
+/// A resolver forwarding to `upstreams`. With `case_randomization` query
+/// names go out over UDP with their letters in random case, and responses
+/// that don't echo the case are rejected; hickory already only takes
+/// responses from the upstream asked, under the query's ID and question.
+/// Requests and connections are bounded by the timeouts of `policy`; its
+/// retries are left to the caller, so each lookup is a single attempt.
+/// Answers are cached by the server, not by the resolver, so every lookup
+/// (prefetches included) asks an upstream.
+pub fn resolver(
+    upstreams: &[Upstream],
+    case_randomization: bool,
+    policy: &RetryPolicy,
+) -> Resolver<UpstreamConnector> {
+    let mut builder = Resolver::builder_with_config(
+        resolver_config(upstreams),
+        connector(upstreams, policy.connect_timeout),
+    );
+    let options = builder.options_mut();
+    options.case_randomization = case_randomization;
+    options.timeout = policy.query_timeout;
+    options.attempts = 1;
+    options.cache_size = 0;
+    builder.build()
+}
+
+/// Connections to the upstreams, for the resolver
+pub type UpstreamConnector = GenericConnector<UpstreamRuntime>;
+
+/// A connector whose sockets to interface-bound upstreams are tied to their
+/// interface, and whose connections give up after `connect_timeout`. Source
+/// addresses need no help: the resolver binds to them itself.
+pub fn connector(upstreams: &[Upstream], connect_timeout: Duration) -> UpstreamConnector {
+    let interfaces = upstreams
+        .iter()
+        .filter_map(|upstream| Some((upstream.addr, upstream.interface()?.to_string())))
+        .collect();
+    GenericConnector::new(UpstreamRuntime {
+        tokio: TokioRuntimeProvider::new(),
+        interfaces: Arc::new(interfaces),
+        connect_timeout,
+    })
+}
+
+/// tokio, with sockets tied to an interface for the upstreams that have one
+#[derive(Clone)]
+pub struct UpstreamRuntime {
+    tokio: TokioRuntimeProvider,
+    interfaces: Arc<HashMap<SocketAddr, String>>,
+    /// Used instead of the resolver's request timeout
+    connect_timeout: Duration,
+}
+
+impl RuntimeProvider for UpstreamRuntime {
+    type Handle = TokioHandle;
+    type Timer = TokioTime;
+    type Udp = UdpSocket;
+    type Tcp = AsyncIoTokioAsStd<TcpStream>;
+
+    fn create_handle(&self) -> Self::Handle {
+        self.tokio.create_handle()
+    }
+
+    fn connect_tcp(
+        &self,
+        server_addr: SocketAddr,
+        bind_addr: Option<SocketAddr>,
+        _timeout: Option<Duration>,
+    ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Tcp>>>> {
+        let connect_timeout = self.connect_timeout;
+        let Some(interface) = self.interfaces.get(&server_addr).cloned() else {
+            return self
+                .tokio
+                .connect_tcp(server_addr, bind_addr, Some(connect_timeout));
+        };
+        Box::pin(async move {
+            let connect = connect_tcp_through(server_addr, &interface);
+            match tokio::time::timeout(connect_timeout, connect).await {
+                Ok(stream) => stream.map(AsyncIoTokioAsStd),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connection to {}@{} timed out", server_addr, interface),
+                )),
+            }
+        })
+    }
+
+    fn bind_udp(
+        &self,
+        local_addr: SocketAddr,
+        server_addr: SocketAddr,
+    ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Udp>>>> {
+        let interface = self.interfaces.get(&server_addr).cloned();
+        Box::pin(async move { bind_udp(local_addr, interface.as_deref()) })
+    }
+}
+
+/// A UDP socket for talking to `upstream`, bound to its source address or interface
+pub fn upstream_udp_socket(upstream: &Upstream) -> io::Result<UdpSocket> {
+    bind_udp(upstream.local_addr(), upstream.interface())
+}
+
+/// A TCP connection to `upstream`, from its source address or through its interface
+pub async fn upstream_tcp_stream(upstream: &Upstream) -> io::Result<TcpStream> {
+    if let Some(interface) = upstream.interface() {
+        return connect_tcp_through(upstream.addr, interface).await;
+    }
+    let sock = match upstream.addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+    if let Some(Via::Source(_)) = upstream.via {
+        sock.bind(upstream.local_addr())?;
+    }
+    sock.set_nodelay(true)?;
+    sock.connect(upstream.addr).await
+}
+
+fn bind_udp(local_addr: SocketAddr, interface: Option<&str>) -> io::Result<UdpSocket> {
+    let sock = match interface {
+        Some(interface) => bind_udp_through(local_addr, interface)?,
+        None => std::net::UdpSocket::bind(local_addr)?,
+    };
+    sock.set_nonblocking(true)?;
+    UdpSocket::from_std(sock)
+}
+
+#[cfg(target_os = "linux")]
+fn device_socket(
+    addr: SocketAddr,
+    interface: &str,
+    kind: socket2::Type,
+) -> io::Result<socket2::Socket> {
+    let sock = socket2::Socket::new(socket2::Domain::for_address(addr), kind, None)?;
+    sock.bind_device(Some(interface.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("interface {}: {}", interface, e)))?;
+    Ok(sock)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_udp_through(local_addr: SocketAddr, interface: &str) -> io::Result<std::net::UdpSocket> {
+    let sock = device_socket(local_addr, interface, socket2::Type::DGRAM)?;
+    sock.bind(&local_addr.into())?;
+    Ok(sock.into())
+}
+
+#[cfg(target_os = "linux")]
+async fn connect_tcp_through(server_addr: SocketAddr, interface: &str) -> io::Result<TcpStream> {
+    let sock = device_socket(server_addr, interface, socket2::Type::STREAM)?;
+    sock.set_nonblocking(true)?;
+    let sock = tokio::net::TcpSocket::from_std_stream(sock.into());
+    sock.set_nodelay(true)?;
+    sock.connect(server_addr).await
+}
+
+// Interfaces are refused when upstreams are parsed; these are never reached
+
+#[cfg(not(target_os = "linux"))]
+fn bind_udp_through(_local_addr: SocketAddr, interface: &str) -> io::Result<std::net::UdpSocket> {
+    Err(through_unsupported(interface))
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn connect_tcp_through(_server_addr: SocketAddr, interface: &str) -> io::Result<TcpStream> {
+    Err(through_unsupported(interface))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn through_unsupported(interface: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot send through interface {}: Linux only", interface),
+    )
+}
+
+/// Name servers configured in the operating system
+pub fn system_resolvers() -> Result<Vec<SocketAddr>, ResolveError> {
+    let (config, _opts) = read_system_conf()?;
+    Ok(resolvers_from_config(&config))
+}
+
+/// Unique, usable name server addresses from a resolver configuration.
+/// hickory lists every server once per protocol, hence the deduplication.
+fn resolvers_from_config(config: &ResolverConfig) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for name_server in config.name_servers() {
+        let addr = name_server.socket_addr;
+        if addr.ip().is_unspecified() || addrs.contains(&addr) {
+            continue;
+        }
+        addrs.push(addr);
+    }
+    addrs
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use hickory_resolver::system_conf::parse_resolv_conf;
+
+    #[test]
+    fn test_resolvers_from_resolv_conf() {
+        let (config, _) = parse_resolv_conf(
+            "# generated\nnameserver 192.168.1.1\nnameserver 2001:db8::53\nnameserver 192.168.1.1\nsearch lan\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            resolvers_from_config(&config),
+            vec![
+                "192.168.1.1:53".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::53]:53".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explicit_resolver_wins() {
+        let explicit: Vec<Upstream> = vec!["1.1.1.1:53".parse().unwrap()];
+        assert_eq!(select_upstreams(&explicit, true), explicit);
+        assert_eq!(select_upstreams(&[], false), vec![FALLBACK_RESOLVER.into()]);
+    }
+
+    #[test]
+    fn test_resolver_config_has_tcp_fallback() {
+        let upstreams: Vec<Upstream> = vec!["1.1.1.1:53".parse().unwrap()];
+        let config = resolver_config(&upstreams);
+
+        let protocols: Vec<Protocol> = config
+            .name_servers()
+            .iter()
+            .map(|name_server| name_server.protocol)
+            .collect();
+        assert_eq!(protocols, vec![Protocol::Udp, Protocol::Tcp]);
+        assert_eq!(resolvers_from_config(&config), vec![upstreams[0].addr]);
+    }
+
+    #[test]
+    fn test_tcp_only_upstream() {
+        let upstreams: Vec<Upstream> = vec![
+            "tcp://1.1.1.1:53".parse().unwrap(),
+            "udp://9.9.9.9:53".parse().unwrap(),
+        ];
+        assert_eq!(upstreams[0].transport, Transport::Tcp);
+        assert_eq!(upstreams[0].to_string(), "tcp://1.1.1.1:53");
+        assert_eq!(upstreams[1], "9.9.9.9:53".parse().unwrap());
+
+        let config = resolver_config(&upstreams);
+        let servers: Vec<(SocketAddr, Protocol)> = config
+            .name_servers()
+            .iter()
+            .map(|name_server| (name_server.socket_addr, name_server.protocol))
+            .collect();
+        assert_eq!(
+            servers,
+            vec![
+                (upstreams[1].addr, Protocol::Udp),
+                (upstreams[0].addr, Protocol::Tcp),
+                (upstreams[1].addr, Protocol::Tcp),
+            ]
+        );
+
+        assert!("quic://1.1.1.1:853".parse::<Upstream>().is_err());
+        assert!("tcp://1.1.1.1:53#one.one.one.one"
+            .parse::<Upstream>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_encrypted_upstreams() {
+        let tls: Upstream = "tls://1.1.1.1:853#Cloudflare-DNS.com".parse().unwrap();
+        assert_eq!(
+            tls.transport,
+            Transport::Tls {
+                server_name: "cloudflare-dns.com".into()
+            }
+        );
+        let https: Upstream = "https://[2620:fe::fe]:443#dns.quad9.net@2001:db8::5"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            https.to_string(),
+            "https://[2620:fe::fe]:443/dns-query#dns.quad9.net@2001:db8::5"
+        );
+        for upstream in [&tls, &https] {
+            assert_eq!(&upstream.to_string().parse::<Upstream>().unwrap(), upstream);
+        }
+
+        let config = resolver_config(&[tls, https]);
+        let servers: Vec<(Protocol, Option<&str>, Option<&str>)> = config
+            .name_servers()
+            .iter()
+            .map(|name_server| {
+                (
+                    name_server.protocol,
+                    name_server.tls_dns_name.as_deref(),
+                    name_server.http_endpoint.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            servers,
+            vec![
+                (Protocol::Tls, Some("cloudflare-dns.com"), None),
+                (Protocol::Https, Some("dns.quad9.net"), Some("/dns-query")),
+            ]
+        );
+
+        // The certificate's name can't be left out
+        assert!("tls://1.1.1.1:853".parse::<Upstream>().is_err());
+        assert!("https://1.1.1.1:443/dns-query".parse::<Upstream>().is_err());
+        assert!("tls://1.1.1.1:853#".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+        let plain: Upstream = "9.9.9.9:53".parse().unwrap();
+        assert_eq!(plain.via, None);
+        assert_eq!(plain.local_addr(), "0.0.0.0:0".parse().unwrap());
+
+        let sourced: Upstream = "[2620:fe::9]:53@2001:db8::5".parse().unwrap();
+        assert_eq!(
+            sourced.via,
+            Some(Via::Source("2001:db8::5".parse().unwrap()))
+        );
+        assert_eq!(sourced.local_addr(), "[2001:db8::5]:0".parse().unwrap());
+        assert_eq!(sourced.to_string().parse::<Upstream>().unwrap(), sourced);
+
+        // The source address is used for both protocols
+        let config = resolver_config(std::slice::from_ref(&sourced));
+        assert!(config
+            .name_servers()
+            .iter()
+            .all(|name_server| name_server.bind_addr == Some(sourced.local_addr())));
+
+        assert!("9.9.9.9:53@2001:db8::5".parse::<Upstream>().is_err());
+        assert!("9.9.9.9@10.0.0.1".parse::<Upstream>().is_err());
+        assert!("9.9.9.9:53@".parse::<Upstream>().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_upstream_interface() {
+        let tunneled: Upstream = "10.8.0.1:53@tun0".parse().unwrap();
+        assert_eq!(tunneled.interface(), Some("tun0"));
+        assert_eq!(tunneled.to_string(), "10.8.0.1:53@tun0");
+        assert!(resolver_config(&[tunneled])
+            .name_servers()
+            .iter()
+            .all(|name_server| name_server.bind_addr.is_none()));
+        assert!("10.8.0.1:53@an-interface-name".parse::<Upstream>().is_err());
+
+        let tunneled: Upstream = "tcp://10.8.0.1:53@tun0".parse().unwrap();
+        assert_eq!(tunneled.to_string(), "tcp://10.8.0.1:53@tun0");
+    }
+}
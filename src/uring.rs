@@ -0,0 +1,425 @@
+//! io_uring UDP I/O (Linux, `io-uring` feature)
+//!
+//! With `--io-uring` a tenant's socket is served by a ring on a dedicated
+//! thread instead of tokio's reactor. A batch of receives is kept queued in
+//! the kernel and responses queued by the query tasks are submitted together
+//! with the receives being re-armed, so a busy listener makes about one
+//! system call per batch of packets rather than one per packet. Received
+//! packets are handed to the runtime over a channel and processed as usual.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::thread;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn};
+
+/// Receives kept queued in the kernel
+const RECV_BATCH: usize = 64;
+
+/// Submission queue size: every queued receive, the wake-up read and room
+/// for a batch of responses
+const RING_ENTRIES: u32 = 256;
+
+/// Largest query accepted, as with the standard listener
+const BUFFER_SIZE: usize = 1024;
+
+// What a completion belongs to, in the top bits of its user data
+const KIND_RECV: u64 = 1 << 62;
+const KIND_SEND: u64 = 2 << 62;
+const KIND_WAKE: u64 = 3 << 62;
+const KIND_MASK: u64 = 3 << 62;
+
+/// A received packet and its sender
+pub type Packet = (Vec<u8>, SocketAddr);
+
+/// Queues responses on a ring; cheap to clone
+#[derive(Clone, Debug)]
+pub struct UringSender {
+    requests: std_mpsc::Sender<SendRequest>,
+    // Written to when a response is queued, to wake the ring's thread
+    wake: Arc<OwnedFd>,
+}
+
+#[derive(Debug)]
+struct SendRequest {
+    data: Vec<u8>,
+    addr: SocketAddr,
+    done: oneshot::Sender<io::Result<usize>>,
+}
+
+impl UringSender {
+    pub async fn send_to(&self, data: Vec<u8>, addr: SocketAddr) -> io::Result<usize> {
+        let (done, result) = oneshot::channel();
+        self.requests
+            .send(SendRequest { data, addr, done })
+            .map_err(|_| stopped())?;
+        signal(self.wake.as_raw_fd())?;
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the io_uring listener stopped")
+}
+
+/// Bump an eventfd's counter
+fn signal(fd: RawFd) -> io::Result<()> {
+    let one: u64 = 1;
+    // SAFETY: writes the 8 bytes of a live u64
+    let written = unsafe { libc::write(fd, ptr::from_ref(&one).cast(), 8) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Serve a socket from a ring on its own thread, returning the sending half
+/// and the received packets
+pub fn start(sock: UdpSocket) -> io::Result<(UringSender, mpsc::Receiver<Packet>)> {
+    // The ring waits for packets; the socket must not fail with EAGAIN instead
+    sock.set_nonblocking(false)?;
+    let ring = IoUring::new(RING_ENTRIES)?;
+    // SAFETY: eventfd returns a new descriptor, which we own, or -1
+    let wake = match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) } {
+        -1 => return Err(io::Error::last_os_error()),
+        fd => Arc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
+    };
+    let (requests, queued) = std_mpsc::channel();
+    let (packets, received) = mpsc::channel(RECV_BATCH * 4);
+
+    let thread_wake = Arc::clone(&wake);
+    thread::Builder::new()
+        .name("io-uring".to_string())
+        .spawn(move || {
+            let mut worker = Worker {
+                ring,
+                sock,
+                wake: thread_wake,
+                wake_count: Box::new(0),
+                queued,
+                slots: Vec::new(),
+                sends: HashMap::new(),
+                next_send: 0,
+            };
+            if let Err(e) = worker.run(&packets) {
+                error!("io_uring listener stopped: {}", e);
+            }
+            // The kernel may still be cancelling operations that point into
+            // the worker's buffers, so they are never freed
+            mem::forget(worker);
+        })?;
+
+    Ok((UringSender { requests, wake }, received))
+}
+
+/// A queued receive. Boxed, so the pointers between its fields stay valid.
+struct RecvSlot {
+    buf: [u8; BUFFER_SIZE],
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+impl RecvSlot {
+    fn new() -> Box<Self> {
+        // SAFETY: all-zero buffers, addresses and headers are valid
+        let mut slot: Box<Self> = Box::new(unsafe { mem::zeroed() });
+        slot.iov.iov_base = slot.buf.as_mut_ptr().cast();
+        slot.iov.iov_len = BUFFER_SIZE;
+        slot.msg.msg_name = ptr::from_mut(&mut slot.addr).cast();
+        slot.msg.msg_iov = ptr::from_mut(&mut slot.iov);
+        slot.msg.msg_iovlen = 1;
+        slot
+    }
+}
+
+/// A response in flight, boxed like `RecvSlot`
+struct SendOp {
+    // Pointed to by `iov`
+    _data: Vec<u8>,
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+    done: oneshot::Sender<io::Result<usize>>,
+}
+
+impl SendOp {
+    fn new(request: SendRequest) -> Box<Self> {
+        let SendRequest {
+            mut data,
+            addr,
+            done,
+        } = request;
+        let (addr, addr_len) = to_sockaddr(addr);
+        let iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        let mut op = Box::new(Self {
+            _data: data,
+            addr,
+            iov,
+            // SAFETY: an all-zero header is valid
+            msg: unsafe { mem::zeroed() },
+            done,
+        });
+        op.msg.msg_name = ptr::from_mut(&mut op.addr).cast();
+        op.msg.msg_namelen = addr_len;
+        op.msg.msg_iov = ptr::from_mut(&mut op.iov);
+        op.msg.msg_iovlen = 1;
+        op
+    }
+}
+
+/// The ring's thread
+struct Worker {
+    ring: IoUring,
+    sock: UdpSocket,
+    wake: Arc<OwnedFd>,
+    // Target of the queued read on `wake`
+    wake_count: Box<u64>,
+    queued: std_mpsc::Receiver<SendRequest>,
+    // Boxed so they stay put while the kernel holds pointers into them
+    #[allow(clippy::vec_box)]
+    slots: Vec<Box<RecvSlot>>,
+    sends: HashMap<u64, Box<SendOp>>,
+    next_send: u64,
+}
+
+impl Worker {
+    /// Pass packets on until the socket fails or nobody is listening
+    fn run(&mut self, packets: &mpsc::Sender<Packet>) -> io::Result<()> {
+        for index in 0..RECV_BATCH {
+            self.slots.push(RecvSlot::new());
+            self.queue_recv(index)?;
+        }
+        self.queue_wake()?;
+
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            let completions: Vec<(u64, i32)> = self
+                .ring
+                .completion()
+                .map(|entry| (entry.user_data(), entry.result()))
+                .collect();
+
+            for (user_data, result) in completions {
+                let key = user_data & !KIND_MASK;
+                match user_data & KIND_MASK {
+                    KIND_RECV => {
+                        if result < 0 {
+                            // Errors like an ICMP unreachable leave the
+                            // socket usable, so only fatal ones stop it
+                            let e = io::Error::from_raw_os_error(-result);
+                            if is_fatal(-result) {
+                                return Err(e);
+                            }
+                            warn!("io_uring receive failed: {}", e);
+                            self.queue_recv(key as usize)?;
+                            continue;
+                        }
+                        let slot = &self.slots[key as usize];
+                        if let Some(addr) = from_sockaddr(&slot.addr) {
+                            let packet = slot.buf[..result as usize].to_vec();
+                            if packets.blocking_send((packet, addr)).is_err() {
+                                return Ok(());
+                            }
+                        }
+                        self.queue_recv(key as usize)?;
+                    }
+                    KIND_SEND => {
+                        if let Some(op) = self.sends.remove(&key) {
+                            let _ = op.done.send(if result < 0 {
+                                Err(io::Error::from_raw_os_error(-result))
+                            } else {
+                                Ok(result as usize)
+                            });
+                        }
+                    }
+                    _ => {
+                        while let Ok(request) = self.queued.try_recv() {
+                            self.queue_send(request)?;
+                        }
+                        self.queue_wake()?;
+                    }
+                }
+            }
+        }
+    }
+
+    fn queue_recv(&mut self, index: usize) -> io::Result<()> {
+        let slot = &mut self.slots[index];
+        // The kernel overwrites these on every receive
+        slot.msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        slot.msg.msg_flags = 0;
+        let entry = opcode::RecvMsg::new(types::Fd(self.sock.as_raw_fd()), &mut slot.msg)
+            .build()
+            .user_data(KIND_RECV | index as u64);
+        self.push(entry)
+    }
+
+    fn queue_send(&mut self, request: SendRequest) -> io::Result<()> {
+        let op = SendOp::new(request);
+        let key = self.next_send;
+        self.next_send = (self.next_send + 1) & !KIND_MASK;
+        let entry = opcode::SendMsg::new(types::Fd(self.sock.as_raw_fd()), &op.msg)
+            .build()
+            .user_data(KIND_SEND | key);
+        self.sends.insert(key, op);
+        self.push(entry)
+    }
+
+    fn queue_wake(&mut self) -> io::Result<()> {
+        let entry = opcode::Read::new(
+            types::Fd(self.wake.as_raw_fd()),
+            ptr::from_mut(&mut *self.wake_count).cast(),
+            8,
+        )
+        .build()
+        .user_data(KIND_WAKE);
+        self.push(entry)
+    }
+
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        // SAFETY: every entry points into a boxed buffer owned by the worker,
+        // which outlives the operation
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.ring.submit()?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a receive error means the socket itself is unusable
+fn is_fatal(errno: i32) -> bool {
+    matches!(
+        errno,
+        libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EFAULT | libc::EOPNOTSUPP
+    )
+}
+
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: an all-zero address is valid
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: sockaddr_storage is large and aligned enough for any address
+            unsafe { ptr::write(ptr::from_mut(&mut storage).cast(), sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: as above
+            unsafe { ptr::write(ptr::from_mut(&mut storage).cast(), sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says this is a sockaddr_in
+            let sin: &libc::sockaddr_in = unsafe { &*ptr::from_ref(storage).cast() };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says this is a sockaddr_in6
+            let sin6: &libc::sockaddr_in6 = unsafe { &*ptr::from_ref(storage).cast() };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sockaddr_round_trip() {
+        for addr in ["192.0.2.1:53", "[2001:db8::1]:5353"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let (storage, _) = to_sockaddr(addr);
+            assert_eq!(from_sockaddr(&storage), Some(addr));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = sock.local_addr().unwrap();
+        let (sender, mut packets) = start(sock).unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", server).await.unwrap();
+        let (packet, from) = packets.recv().await.unwrap();
+        assert_eq!(packet, b"ping");
+        assert_eq!(from, client.local_addr().unwrap());
+
+        assert_eq!(sender.send_to(b"pong".to_vec(), from).await.unwrap(), 4);
+        let mut buf = [0; 16];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_receive_error_is_survived() {
+        // A socket connected to a port nobody listens on fails its next
+        // receive with ECONNREFUSED once the port answers unreachable
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        drop(peer);
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.connect(peer_addr).unwrap();
+        let server = sock.local_addr().unwrap();
+        let (sender, mut packets) = start(sock).unwrap();
+        sender.send_to(b"ping".to_vec(), peer_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Packets are still received afterwards
+        let peer = tokio::net::UdpSocket::bind(peer_addr).await.unwrap();
+        peer.send_to(b"pong", server).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(1), packets.recv());
+        let (packet, from) = received.await.unwrap().unwrap();
+        assert_eq!(packet, b"pong");
+        assert_eq!(from, peer_addr);
+    }
+}
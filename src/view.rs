@@ -0,0 +1,196 @@
+//! Split-horizon views (`--view`)
+//!
+//! A view answers the clients in its subnets from records and zones of its
+//! own, so the same name can resolve to a private address inside a network
+//! and to a public one outside it. Views are tried in the order given and the
+//! first whose subnets hold the client wins; clients matching none get the
+//! tenant's own records. A view with upstreams of its own forwards to them,
+//! with a cache of its own; one without shares the tenant's resolver. Routes,
+//! the blocklist, access lists and counters are the tenant's for every view.
+//!
+//! ```text
+//! --view name=internal,client=10.0.0.0/8,client=192.168.0.0/16,zone-file=example.com=/etc/dns/internal.csv,upstream=192.168.1.1:53,upstream=192.168.1.2:53,hedge=20
+//! --view name=lab,client=172.16.5.0/24,record=nas.example.com A 172.16.5.10,hosts=/etc/dns/lab.hosts
+//! ```
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::geoip::Subnet;
+use crate::hedge::Hedge;
+use crate::hosts;
+use crate::local_records::LocalRecord;
+use crate::upstream::Upstream;
+use crate::zone_file::ZoneFile;
+
+/// A view as given on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct View {
+    pub name: String,
+    /// Client subnets the view answers
+    pub clients: Vec<Subnet>,
+    /// Upstream resolvers; the tenant's when none are given
+    pub upstreams: Vec<Upstream>,
+    /// How the view's upstreams race, if they do
+    pub hedge: Option<Hedge>,
+    /// Records given inline
+    pub records: Vec<LocalRecord>,
+    /// Hosts files whose names the view answers
+    pub hosts_files: Vec<PathBuf>,
+    /// Zones the view serves from files on disk
+    pub zone_files: Vec<ZoneFile>,
+}
+
+impl View {
+    /// Whether the view answers `client`
+    pub fn matches(&self, client: IpAddr) -> bool {
+        self.clients.iter().any(|subnet| subnet.contains(client))
+    }
+
+    /// The view's inline records and those of its hosts files
+    pub fn load_records(&self) -> anyhow::Result<Vec<LocalRecord>> {
+        let mut records = self.records.clone();
+        for path in &self.hosts_files {
+            records.extend(hosts::load_hosts_file(path)?);
+        }
+        Ok(records)
+    }
+}
+
+/// Views can't share a name
+pub fn check_distinct(views: &[View]) -> Result<(), String> {
+    for (i, view) in views.iter().enumerate() {
+        if views[..i].iter().any(|other| other.name == view.name) {
+            return Err(format!("more than one view is named {}", view.name));
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for View {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "name={}", self.name)?;
+        for client in &self.clients {
+            write!(f, ",client={}", client)?;
+        }
+        for upstream in &self.upstreams {
+            write!(f, ",upstream={}", upstream)?;
+        }
+        if let Some(hedge) = self.hedge {
+            write!(f, ",hedge={}", hedge)?;
+        }
+        for record in &self.records {
+            write!(f, ",record={}", record)?;
+        }
+        for path in &self.hosts_files {
+            write!(f, ",hosts={}", path.display())?;
+        }
+        for zone_file in &self.zone_files {
+            write!(f, ",zone-file={}", zone_file)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses comma-separated `key=value` settings: `name` and `client` are
+/// required; `upstream`, `hedge`, `record`, `hosts` and `zone-file` are
+/// optional; all but `name` and `hedge` are repeatable
+impl FromStr for View {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut view = View {
+            name: String::new(),
+            clients: Vec::new(),
+            upstreams: Vec::new(),
+            hedge: None,
+            records: Vec::new(),
+            hosts_files: Vec::new(),
+            zone_files: Vec::new(),
+        };
+
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got '{}'", setting))?;
+            match key {
+                "name" => {
+                    if value.is_empty()
+                        || !value
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+                    {
+                        return Err(format!("invalid view name '{}'", value));
+                    }
+                    name = Some(value.to_string());
+                }
+                "client" => view.clients.push(value.parse()?),
+                "upstream" => view.upstreams.push(value.parse()?),
+                "hedge" => view.hedge = Some(value.parse()?),
+                "record" => view.records.push(
+                    value
+                        .parse()
+                        .map_err(|e| format!("invalid record '{}': {}", value, e))?,
+                ),
+                "hosts" => view.hosts_files.push(PathBuf::from(value)),
+                "zone-file" => view.zone_files.push(value.parse()?),
+                _ => return Err(format!("unknown view setting '{}'", key)),
+            }
+        }
+
+        view.name = name.ok_or("view needs a name=<name>")?;
+        if view.clients.is_empty() {
+            return Err(format!("view {} needs a client=<cidr>", view.name));
+        }
+        Ok(view)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_view() {
+        let spec =
+            "name=internal,client=10.0.0.0/8,client=fd00::/8,upstream=192.168.1.1:53,hedge=20/2,\
+                    record=nas.example.com A 10.0.0.10,hosts=/etc/dns/internal.hosts,\
+                    zone-file=example.com=/etc/dns/internal.csv";
+        let view: View = spec.parse().unwrap();
+        assert_eq!(view.name, "internal");
+        assert_eq!(view.clients.len(), 2);
+        assert_eq!(view.upstreams.len(), 1);
+        assert_eq!(view.hedge, Some("20".parse().unwrap()));
+        assert_eq!(view.records[0].name, "nas.example.com");
+        assert_eq!(view.zone_files[0].zone, "example.com");
+        assert_eq!(view.to_string().parse::<View>().unwrap(), view);
+
+        assert!(view.matches("10.1.2.3".parse().unwrap()));
+        assert!(view.matches("fd12::1".parse().unwrap()));
+        assert!(!view.matches("192.0.2.1".parse().unwrap()));
+
+        assert!("client=10.0.0.0/8".parse::<View>().is_err());
+        assert!("name=internal".parse::<View>().is_err());
+        assert!("name=internal,client=10.0.0.0/8,record=nas A"
+            .parse::<View>()
+            .is_err());
+        assert!("name=internal,client=10.0.0.0/8,color=blue"
+            .parse::<View>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_distinct() {
+        let internal: View = "name=internal,client=10.0.0.0/8".parse().unwrap();
+        let lab: View = "name=lab,client=172.16.0.0/12".parse().unwrap();
+        assert!(check_distinct(&[internal.clone(), lab.clone()]).is_ok());
+        let renamed = View {
+            name: lab.name.clone(),
+            ..internal
+        };
+        assert!(check_distinct(&[lab, renamed]).is_err());
+    }
+}
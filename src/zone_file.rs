@@ -0,0 +1,268 @@
+//! Zone files loaded from disk and reloaded when they change
+//!
+//! `--zone-file <zone>=<path>` serves the records in a JSON or CSV file (the
+//! same formats as `records import`, picked by the file's extension) as the
+//! whole of `<zone>`: every local record at or below the zone's name is
+//! replaced by the file's records. The file's directory is watched, so an
+//! edit takes effect as soon as it is saved, whether the editor writes the
+//! file in place or replaces it. A file that fails to parse, or holds a
+//! record outside its zone, is logged and ignored; the last good version
+//! keeps being served. Every reload that changes something is a new version
+//! in the history (`versions`), so a bad edit can be rolled back.
+//!
+//! Being the whole of the zone, the file also says what isn't in it: a query
+//! in the zone that its records don't answer gets an authoritative NXDOMAIN
+//! or NODATA with the zone's SOA (RFC 2308) instead of going upstream.
+
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::errors::ZoneFileError;
+use crate::handlers::local_records_handler::LocalRecordsHandle;
+use crate::local_records::{
+    import_records, is_in_zone, normalize_name, ImportDiff, LocalRecord, RecordFormat,
+};
+use crate::response_builder::SoaRecord;
+
+/// How long a file has to stay quiet after a change before it is reloaded,
+/// so a save that takes several writes is loaded once, complete
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// A zone and the file holding its records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneFile {
+    pub zone: String,
+    pub path: PathBuf,
+}
+
+impl ZoneFile {
+    /// Read and validate the file's records
+    pub fn load(&self) -> Result<Vec<LocalRecord>, ZoneFileError> {
+        let input = std::fs::read_to_string(&self.path)?;
+        let records = import_records(&input, RecordFormat::for_path(&self.path))?;
+        if let Some(outside) = records
+            .iter()
+            .find(|record| !is_in_zone(&record.name, &self.zone))
+        {
+            return Err(ZoneFileError::OutOfZone(outside.name.clone()));
+        }
+        Ok(records)
+    }
+
+    /// Load the file into the local records, replacing the zone's records in
+    /// one change, and return what changed
+    pub async fn reload(
+        &self,
+        local_records: &LocalRecordsHandle,
+    ) -> Result<ImportDiff, ZoneFileError> {
+        let records = self.load()?;
+        let diff = local_records
+            .replace_zone(
+                self.zone.clone(),
+                records,
+                format!("reload of {} from {}", self.zone, self.path.display()),
+            )
+            .await;
+        if diff.is_changed() {
+            info!(
+                "Loaded {} from {}: {} added, {} removed, {} unchanged",
+                self.zone,
+                self.path.display(),
+                diff.added.len(),
+                diff.removed.len(),
+                diff.unchanged
+            );
+        }
+        Ok(diff)
+    }
+}
+
+impl fmt::Display for ZoneFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.zone, self.path.display())
+    }
+}
+
+/// Parses `<zone>=<path>`
+impl FromStr for ZoneFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected <zone>=<path>, got '{}'", s);
+
+        let (zone, path) = s.split_once('=').ok_or_else(invalid)?;
+        let zone = normalize_name(zone);
+        if zone.is_empty() || zone.split('.').any(str::is_empty) || path.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            zone,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// The zones served from files, answered in full
+#[derive(Debug, Default)]
+pub struct LocalZones {
+    zones: Vec<SoaRecord>,
+}
+
+impl LocalZones {
+    pub fn new(zone_files: &[ZoneFile]) -> Self {
+        let mut zones: Vec<SoaRecord> = Vec::new();
+        for file in zone_files {
+            if !zones.iter().any(|soa| soa.zone == file.zone) {
+                zones.push(SoaRecord::local(&file.zone, 1));
+            }
+        }
+        Self { zones }
+    }
+
+    /// The SOA of the most specific zone holding `name`
+    pub fn find(&self, name: &str) -> Option<&SoaRecord> {
+        let name = normalize_name(name);
+        self.zones
+            .iter()
+            .filter(|soa| is_in_zone(&name, &soa.zone))
+            .max_by_key(|soa| soa.zone.len())
+    }
+}
+
+/// Load every zone file, failing if one can't be loaded, then keep them
+/// loaded as they change, in the background
+pub async fn watch_zone_files(
+    files: &[ZoneFile],
+    local_records: LocalRecordsHandle,
+) -> anyhow::Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    // Changes are reported with absolute paths
+    let files = files
+        .iter()
+        .map(|file| {
+            Ok(ZoneFile {
+                zone: file.zone.clone(),
+                path: std::path::absolute(&file.path)?,
+            })
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    for file in &files {
+        file.reload(&local_records)
+            .await
+            .with_context(|| format!("could not load zone file {}", file.path.display()))?;
+    }
+
+    // The watcher calls back from its own thread
+    let (sender, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if !matches!(event.kind, EventKind::Access(_)) {
+                for path in event.paths {
+                    let _ = sender.send(path);
+                }
+            }
+        }
+    })?;
+    // Watch directories rather than files: editors often save by writing a
+    // new file and renaming it over the old one
+    let directories: HashSet<&Path> = files.iter().filter_map(|file| file.path.parent()).collect();
+    for directory in directories {
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .with_context(|| format!("could not watch {}", directory.display()))?;
+    }
+
+    tokio::spawn(async move {
+        // Dropping the watcher would stop the notifications
+        let _watcher: RecommendedWatcher = watcher;
+
+        while let Some(path) = changes.recv().await {
+            let mut changed = BTreeSet::from([path]);
+            while let Ok(Some(path)) = timeout(SETTLE_TIME, changes.recv()).await {
+                changed.insert(path);
+            }
+
+            for file in files.iter().filter(|file| changed.contains(&file.path)) {
+                if let Err(e) = file.reload(&local_records).await {
+                    warn!(
+                        "Zone file {} not reloaded, still serving the previous version: {}",
+                        file.path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_zone_file() {
+        let file: ZoneFile = "Example.com.=/etc/dns/example.com.csv".parse().unwrap();
+        assert_eq!(file.zone, "example.com");
+        assert_eq!(file.path, PathBuf::from("/etc/dns/example.com.csv"));
+        assert_eq!(file.to_string().parse::<ZoneFile>().unwrap(), file);
+
+        assert!("example.com".parse::<ZoneFile>().is_err());
+        assert!("example.com=".parse::<ZoneFile>().is_err());
+        assert!("=/etc/dns/zone.json".parse::<ZoneFile>().is_err());
+    }
+
+    #[test]
+    fn test_local_zones() {
+        let files: Vec<ZoneFile> = ["example.com=/a.csv", "lab.example.com=/b.csv", "lan=/c.csv"]
+            .iter()
+            .map(|file| file.parse().unwrap())
+            .collect();
+        let zones = LocalZones::new(&files);
+        assert_eq!(zones.find("www.example.com.").unwrap().zone, "example.com");
+        assert_eq!(
+            zones.find("nas.Lab.example.com").unwrap().zone,
+            "lab.example.com"
+        );
+        assert_eq!(zones.find("lan").unwrap().mname, "lan");
+        assert!(zones.find("example.org").is_none());
+        assert!(zones.find("notexample.com").is_none());
+    }
+
+    #[test]
+    fn test_load_checks_zone() {
+        let path = std::env::temp_dir().join(format!("zone-file-{}.csv", std::process::id()));
+        let file = ZoneFile {
+            zone: "example.com".to_string(),
+            path: path.clone(),
+        };
+
+        std::fs::write(
+            &path,
+            "name,type,value\nexample.com,A,192.0.2.1\nwww.example.com,A,192.0.2.2\n",
+        )
+        .unwrap();
+        assert_eq!(file.load().unwrap().len(), 2);
+
+        std::fs::write(&path, "name,type,value\nwww.example.org,A,192.0.2.2\n").unwrap();
+        assert!(
+            matches!(file.load(), Err(ZoneFileError::OutOfZone(name)) if name == "www.example.org")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(file.load(), Err(ZoneFileError::Io(_))));
+    }
+}